@@ -9,7 +9,7 @@ use std::io;
 //use std::io::Write;
 use std::sync::Arc;
 use tokio::sync::{mpsc, Mutex};
-use util::{Conn, Error};
+use util::Conn;
 
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Client, Method, Request, Response, Server, StatusCode};
@@ -17,6 +17,7 @@ use rand::{thread_rng, Rng};
 use std::time::Duration;
 use webrtc_ice::agent::agent_config::AgentConfig;
 use webrtc_ice::agent::Agent;
+use webrtc_ice::errors::IceError;
 
 #[macro_use]
 extern crate lazy_static;
@@ -86,7 +87,7 @@ async fn remote_handler(req: Request<Body>) -> Result<Response<Body>, hyper::Err
 //      cargo run --color=always --package webrtc-ice --example ping_pong -- --controlling
 
 #[tokio::main]
-async fn main() -> Result<(), Error> {
+async fn main() -> Result<(), IceError> {
     /*env_logger::Builder::new()
     .format(|buf, record| {
         writeln!(
@@ -224,11 +225,11 @@ async fn main() -> Result<(), Error> {
         .body(Body::from(format!("{}:{}", local_ufrag, local_pwd)))
     {
         Ok(req) => req,
-        Err(err) => return Err(Error::new(format!("{}", err))),
+        Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err).into()),
     };
     let resp = match client.request(req).await {
         Ok(resp) => resp,
-        Err(err) => return Err(Error::new(format!("{}", err))),
+        Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err).into()),
     };
     println!("Response from remoteAuth: {}", resp.status());
 
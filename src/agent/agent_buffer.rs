@@ -0,0 +1,123 @@
+use super::*;
+use crate::errors::*;
+
+use bytes::Bytes;
+use std::collections::VecDeque;
+use tokio::sync::Notify;
+
+/// The size each packet takes in the buffer besides its data, as the length prefix of a
+/// `util::Buffer`, so that `AgentConfig::receive_buffer_size` holds as many packets as before.
+const PACKET_OVERHEAD: usize = 2;
+
+/// A packet received on a component, with the address and the remote candidate it comes from.
+pub(crate) struct ReceivedPacket {
+    pub(crate) data: Bytes,
+    pub(crate) remote: SocketAddr,
+    pub(crate) remote_candidate: Arc<dyn Candidate + Send + Sync>,
+}
+
+#[derive(Default)]
+struct PacketQueue {
+    packets: VecDeque<ReceivedPacket>,
+    size: usize,
+    closed: bool,
+}
+
+/// The packets received on a component until they are read. They are kept as the slices of
+/// the receive buffers of the candidates they were received in, so that they are only copied
+/// into the buffer of the reader.
+pub(crate) struct PacketBuffer {
+    queue: std::sync::Mutex<PacketQueue>,
+    readable: Notify,
+    limit_size: usize,
+}
+
+impl PacketBuffer {
+    pub(crate) fn new(limit_size: usize) -> Self {
+        Self {
+            queue: std::sync::Mutex::new(PacketQueue::default()),
+            readable: Notify::new(),
+            limit_size,
+        }
+    }
+
+    /// Queues a packet. When it doesn't fit, the oldest packets are dropped to make room for it
+    /// if `drop_oldest` is set, and their number is returned. Fails with
+    /// `IceError::ReceiveBufferFull` when the packet is dropped instead.
+    pub(crate) fn push(
+        &self,
+        packet: ReceivedPacket,
+        drop_oldest: bool,
+    ) -> Result<usize, IceError> {
+        let size = packet.data.len() + PACKET_OVERHEAD;
+        let mut queue = self.queue.lock().unwrap();
+        if queue.closed {
+            return Err(IceError::Closed);
+        }
+        if size > self.limit_size {
+            return Err(IceError::ReceiveBufferFull);
+        }
+
+        let mut dropped = 0;
+        while queue.size + size > self.limit_size {
+            if !drop_oldest {
+                return Err(IceError::ReceiveBufferFull);
+            }
+            if let Some(oldest) = queue.packets.pop_front() {
+                queue.size -= oldest.data.len() + PACKET_OVERHEAD;
+                dropped += 1;
+            }
+        }
+
+        queue.size += size;
+        queue.packets.push_back(packet);
+        drop(queue);
+        self.readable.notify_one();
+
+        Ok(dropped)
+    }
+
+    /// Waits for the next packet. Returns None once the buffer is closed.
+    pub(crate) async fn pop(&self) -> Option<ReceivedPacket> {
+        loop {
+            // Created before the queue is checked, so that a packet pushed in between wakes it
+            let readable = self.readable.notified();
+            {
+                let mut queue = self.queue.lock().unwrap();
+                if queue.closed {
+                    return None;
+                }
+                if let Some(packet) = queue.packets.pop_front() {
+                    queue.size -= packet.data.len() + PACKET_OVERHEAD;
+                    return Some(packet);
+                }
+            }
+            readable.await;
+        }
+    }
+
+    /// Puts back a packet which was popped but could not be read at the front of the queue, so
+    /// that it is the next one popped.
+    pub(crate) fn push_front(&self, packet: ReceivedPacket) {
+        {
+            let mut queue = self.queue.lock().unwrap();
+            if queue.closed {
+                return;
+            }
+            queue.size += packet.data.len() + PACKET_OVERHEAD;
+            queue.packets.push_front(packet);
+        }
+        self.readable.notify_one();
+    }
+
+    /// Drops the packets and wakes up the readers, which get no more packets.
+    pub(crate) fn close(&self) {
+        {
+            let mut queue = self.queue.lock().unwrap();
+            queue.closed = true;
+            queue.packets.clear();
+            queue.size = 0;
+        }
+        self.readable.notify_waiters();
+    }
+}
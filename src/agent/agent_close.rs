@@ -0,0 +1,114 @@
+use super::*;
+
+use tokio::time::timeout_at;
+
+/// Describes a resource of the agent that failed to shut down cleanly.
+#[derive(Debug, Clone)]
+pub struct CloseFailure {
+    /// The resource that failed to shut down, e.g. a local candidate or the mDNS connection.
+    pub resource: String,
+
+    /// Why the resource failed to shut down, `IceError::Timeout` if it was still shutting down
+    /// when the deadline elapsed.
+    pub error: IceError,
+}
+
+/// Reports what failed to shut down cleanly when closing the agent.
+#[derive(Debug, Default, Clone)]
+pub struct CloseReport {
+    pub failures: Vec<CloseFailure>,
+}
+
+impl CloseReport {
+    /// Returns true if every resource of the agent was shut down before the deadline.
+    #[must_use]
+    pub fn is_clean(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    fn add_failure(&mut self, resource: String, error: IceError) {
+        self.failures.push(CloseFailure { resource, error });
+    }
+}
+
+impl Agent {
+    /// Cleans up the Agent, giving up on what hasn't shut down once `timeout` has elapsed.
+    ///
+    /// Pending connectivity checks are dropped, and the local candidates are closed
+    /// concurrently, which stops their receive loops and closes their TURN clients. The returned
+    /// report lists the resources that failed to shut down or were still shutting down at the
+    /// deadline; those are left to finish in the background.
+    pub async fn close_with_timeout(&self, timeout: Duration) -> Result<CloseReport, IceError> {
+        let deadline = Instant::now() + timeout;
+        let mut report = CloseReport::default();
+
+        if let Some(gather_candidate_cancel) = &self.gather_candidate_cancel {
+            gather_candidate_cancel();
+        }
+
+        // A handler stuck while holding the agent lock would otherwise hang the close
+        let (local_ufrag, local_candidates) =
+            if let Ok(mut ai) = timeout_at(deadline, self.agent_internal.lock()).await {
+                let local_candidates = ai.close().await?;
+                (ai.local_ufrag.clone(), local_candidates)
+            } else {
+                report.add_failure("agent".to_owned(), IceError::Timeout("while closing"));
+                return Ok(report);
+            };
+
+        let closing: Vec<_> = local_candidates
+            .into_iter()
+            .map(|c| (c.to_string(), tokio::spawn(async move { c.close().await })))
+            .collect();
+        for (resource, handle) in closing {
+            match timeout_at(deadline, handle).await {
+                Ok(Ok(Ok(()))) => {}
+                Ok(Ok(Err(err))) => report.add_failure(resource, err),
+                Ok(Err(err)) => report.add_failure(resource, IceError::Task(err.to_string())),
+                Err(_) => report.add_failure(resource, IceError::Timeout("while closing")),
+            }
+        }
+
+        if let Some(udp_mux) = &self.udp_mux {
+            if timeout_at(deadline, udp_mux.remove_conn_by_ufrag(&local_ufrag))
+                .await
+                .is_err()
+            {
+                report.add_failure("UDP mux".to_owned(), IceError::Timeout("while closing"));
+            }
+        }
+        if let Some(udp_mux_srflx) = &self.udp_mux_srflx {
+            if timeout_at(deadline, udp_mux_srflx.remove_conn_by_ufrag(&local_ufrag))
+                .await
+                .is_err()
+            {
+                report.add_failure(
+                    "UDP srflx mux".to_owned(),
+                    IceError::Timeout("while closing"),
+                );
+            }
+        }
+        if let Some(tcp_mux) = &self.tcp_mux {
+            if timeout_at(deadline, tcp_mux.remove_conn_by_ufrag(&local_ufrag))
+                .await
+                .is_err()
+            {
+                report.add_failure("TCP mux".to_owned(), IceError::Timeout("while closing"));
+            }
+        }
+
+        if let Some(mdns_conn) = &self.mdns_conn {
+            match timeout_at(deadline, mdns_conn.close()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => {
+                    report.add_failure("mDNS conn".to_owned(), err.into());
+                }
+                Err(_) => {
+                    report.add_failure("mDNS conn".to_owned(), IceError::Timeout("while closing"))
+                }
+            }
+        }
+
+        Ok(report)
+    }
+}
@@ -1,26 +1,90 @@
 use super::*;
 use crate::errors::*;
+use crate::ice_options::IceOptions;
 use crate::mdns::*;
 use crate::network_type::*;
+use crate::priority::CandidatePreferences;
+use crate::tcp_mux::*;
+use crate::udp_mux::*;
 use crate::url::*;
 
 use util::vnet::net::*;
-use util::Error;
 
+use std::net::IpAddr;
 use std::time::Duration;
 
+/// An extra address to advertise for a local IP, see `AgentConfig::additional_addresses`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdditionalAddress {
+    /// The address to advertise.
+    pub ip: IpAddr,
+
+    /// The local IP the address stands for, it must be the address of an interface.
+    pub local_ip: IpAddr,
+
+    /// `CandidateType::Host` rewrites the address of the host candidates of `local_ip`, while
+    /// `CandidateType::ServerReflexive` adds a srflx candidate bound to `local_ip`, which is its
+    /// related address. Other types are rejected.
+    pub candidate_type: CandidateType,
+}
+
+/// Checks that the additional addresses have a supported type and match the interfaces of net.
+pub(crate) async fn validate_additional_addresses(
+    addresses: &[AdditionalAddress],
+    mdns_mode: MulticastDnsMode,
+    net: &Arc<Net>,
+) -> Result<(), IceError> {
+    if addresses.is_empty() {
+        return Ok(());
+    }
+
+    let interfaces = net.get_interfaces().await;
+    for a in addresses {
+        match a.candidate_type {
+            CandidateType::Host if mdns_mode == MulticastDnsMode::QueryAndGather => {
+                return Err(IceError::MulticastDnsWithNat1To1IpMapping);
+            }
+            CandidateType::Host | CandidateType::ServerReflexive => {}
+            _ => return Err(IceError::InvalidAdditionalAddress),
+        }
+
+        let has_local_ip = interfaces
+            .iter()
+            .any(|iface| iface.addrs().iter().any(|ipnet| ipnet.addr() == a.local_ip));
+        if a.ip.is_ipv4() != a.local_ip.is_ipv4() || !has_local_ip {
+            return Err(IceError::InvalidAdditionalAddress);
+        }
+    }
+
+    Ok(())
+}
+
 /// The interval at which the agent performs candidate checks in the connecting phase.
 pub(crate) const DEFAULT_CHECK_INTERVAL: Duration = Duration::from_millis(200);
 
+/// The pacing interval Ta of the STUN transactions, see
+/// [RFC 8445 Section 14.2](https://tools.ietf.org/html/rfc8445#section-14.2).
+pub(crate) const DEFAULT_PACING_INTERVAL: Duration = Duration::from_millis(50);
+
 /// The interval used to keep candidates alive.
 pub(crate) const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(2);
 
+/// The interval at which consent to send is refreshed on the selected pair, see
+/// [RFC 7675](https://tools.ietf.org/html/rfc7675).
+pub(crate) const DEFAULT_CONSENT_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The default time without a consent response before the selected pair is considered failed.
+pub(crate) const DEFAULT_CONSENT_TIMEOUT: Duration = Duration::from_secs(30);
+
 /// The default time till an Agent transitions disconnected.
 pub(crate) const DEFAULT_DISCONNECTED_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// The default time till an Agent transitions to failed after disconnected.
 pub(crate) const DEFAULT_FAILED_TIMEOUT: Duration = Duration::from_secs(25);
 
+/// The default time `Agent::close` waits for the resources of the agent to shut down.
+pub(crate) const DEFAULT_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// Wait time before nominating a host candidate.
 pub(crate) const DEFAULT_HOST_ACCEPTANCE_MIN_WAIT: Duration = Duration::from_secs(0);
 
@@ -36,12 +100,57 @@ pub(crate) const DEFAULT_RELAY_ACCEPTANCE_MIN_WAIT: Duration = Duration::from_mi
 /// Max binding request before considering a pair failed.
 pub(crate) const DEFAULT_MAX_BINDING_REQUESTS: u16 = 7;
 
-/// The number of bytes that can be buffered before we start to error.
-pub(crate) const MAX_BUFFER_SIZE: usize = 1000 * 1000; // 1MB
+/// Max role switches on 487 (Role Conflict) responses before they are ignored.
+pub(crate) const DEFAULT_MAX_ROLE_SWITCHES: u16 = 3;
+
+/// The ICE options advertised by default, all of which the agent supports.
+pub(crate) const DEFAULT_ICE_OPTIONS: IceOptions = IceOptions {
+    trickle: true,
+    renomination: true,
+    ice2: true,
+};
+
+/// The lowest pacing interval Ta allowed by RFC 8445, enforced in strict mode.
+pub(crate) const MIN_PACING_INTERVAL: Duration = Duration::from_millis(5);
+
+/// The most candidate pairs in the checklists of an agent in strict mode.
+pub(crate) const MAX_CANDIDATE_PAIRS: usize = 100;
+
+/// Factor the RTO of a connectivity check is multiplied by on every retransmission.
+pub(crate) const DEFAULT_RTO_BACKOFF: u32 = 2;
+
+/// How long gathering waits for a STUN server to answer.
+pub(crate) const DEFAULT_STUN_GATHER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The lifetime asked for the TURN allocations, the default lifetime of the TURN servers.
+pub(crate) const DEFAULT_TURN_ALLOCATION_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+/// How many STUN and TURN servers are gathered from at once.
+pub(crate) const DEFAULT_GATHER_CONCURRENCY: usize = 8;
+
+/// The number of components, a single one multiplexing RTP and RTCP.
+pub(crate) const DEFAULT_COMPONENTS: u16 = 1;
+
+/// The number of bytes of the received packets which can be buffered until they are read.
+pub(crate) const DEFAULT_RECEIVE_BUFFER_SIZE: usize = 1000 * 1000; // 1MB
 
 /// Wait time before binding requests can be deleted.
 pub(crate) const MAX_BINDING_REQUEST_TIMEOUT: Duration = Duration::from_millis(4000);
 
+/// The largest DSCP, which is 6 bits long.
+pub(crate) const MAX_DSCP: u8 = 63;
+
+/// Returns the stream and component of every connection of streams having the given numbers of
+/// components, the first component of the first stream first.
+pub(crate) fn stream_components(streams: &[u16]) -> impl Iterator<Item = (u16, u16)> + '_ {
+    streams
+        .iter()
+        .enumerate()
+        .flat_map(|(stream, &components)| {
+            (1..=components).map(move |component| (stream as u16, component))
+        })
+}
+
 pub(crate) fn default_candidate_types() -> Vec<CandidateType> {
     vec![
         CandidateType::Host,
@@ -50,26 +159,163 @@ pub(crate) fn default_candidate_types() -> Vec<CandidateType> {
     ]
 }
 
+/// Represents how the controlling agent nominates the candidate pair, see
+/// [RFC 8445 Section 8.1.1](https://tools.ietf.org/html/rfc8445#section-8.1.1).
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum NominationStrategy {
+    Unspecified,
+
+    /// Means checks are sent without USE-CANDIDATE. Once the best valid pair has waited its
+    /// acceptance min wait, a check with USE-CANDIDATE is sent to nominate it.
+    Regular,
+
+    /// Means every check carries USE-CANDIDATE, and the first pair to succeed is selected.
+    /// Faster, but the selected pair may not be the best one. It is deprecated by RFC 8445, so
+    /// `Regular` is used instead when both agents advertise the `ice2` option.
+    Aggressive,
+}
+
+impl Default for NominationStrategy {
+    fn default() -> Self {
+        Self::Unspecified
+    }
+}
+
+/// Represents the role of the agent, see
+/// [RFC 8445 Section 6.1.1](https://tools.ietf.org/html/rfc8445#section-6.1.1).
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum IceRole {
+    /// Means the role is not determined yet. It is once `dial`, `accept` or `start` is called,
+    /// or when the first check of the remote agent arrives, the agent then taking the role
+    /// opposite to the one of the remote agent. Agents only start without a role when it is
+    /// asked for with `AgentConfig::role`.
+    Unspecified,
+
+    /// Means the agent nominates the candidate pairs.
+    Controlling,
+
+    /// Means the agent waits for the remote agent to nominate the candidate pairs.
+    Controlled,
+}
+
+impl Default for IceRole {
+    fn default() -> Self {
+        Self::Controlled
+    }
+}
+
+/// Represents how keepalives are sent on the selected pairs once connected, see
+/// [RFC 8445 Section 11](https://tools.ietf.org/html/rfc8445#section-11).
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum KeepaliveStrategy {
+    Unspecified,
+
+    /// Means keepalives are authenticated binding requests, which are answered by the peer.
+    /// Some middleboxes only refresh their mappings on a request/response exchange.
+    BindingRequest,
+
+    /// Means keepalives are binding indications, which are not answered. Cheaper, but only
+    /// refresh the mappings which are kept alive by outbound traffic.
+    BindingIndication,
+}
+
+impl Default for KeepaliveStrategy {
+    fn default() -> Self {
+        Self::Unspecified
+    }
+}
+
+/// Represents how closely the agent follows RFC 8445 where other implementations do not.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum CompatibilityMode {
+    Unspecified,
+
+    /// Means the agent works with the deployed implementations, e.g. browsers, and bends the
+    /// specification where it helps them connect, e.g. pruning server reflexive candidates of the
+    /// same public address or pacing checks as configured, without a lower bound.
+    Interop,
+
+    /// Means the agent follows RFC 8445 to the letter: aggressive nomination is refused, only
+    /// the candidates and pairs the specification deems redundant are pruned, the pacing
+    /// interval Ta is at least 5 milliseconds and the checklists hold at most 100 pairs, the
+    /// lowest priority ones being discarded, see
+    /// [RFC 8445 Section 6.1.2.5](https://tools.ietf.org/html/rfc8445#section-6.1.2.5).
+    Rfc8445Strict,
+}
+
+impl Default for CompatibilityMode {
+    fn default() -> Self {
+        Self::Unspecified
+    }
+}
+
+/// Represents what happens to the packets received while the receive buffer of a component is
+/// full, i.e. the application doesn't read them fast enough. The dropped packets are counted,
+/// see `AgentConn::packets_dropped`.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum BufferFullPolicy {
+    Unspecified,
+
+    /// Means the packet received is dropped, the buffered ones are kept.
+    DropNew,
+
+    /// Means the oldest buffered packets are dropped to make room for the packet received, so
+    /// that the application reads the freshest data, e.g. for real-time media.
+    DropOldest,
+
+    /// Means the packet received is dropped, and the next read fails, so that the application
+    /// knows packets were lost.
+    Error,
+}
+
+impl Default for BufferFullPolicy {
+    fn default() -> Self {
+        Self::Unspecified
+    }
+}
+
 pub type InterfaceFilterFn = Box<dyn (Fn(&str) -> bool) + Send + Sync>;
+pub type IpFilterFn = Box<dyn (Fn(IpAddr) -> bool) + Send + Sync>;
+pub type PrflxPriorityFn = Box<dyn (Fn(&(dyn Candidate + Send + Sync)) -> u32) + Send + Sync>;
+pub type CandidatePriorityFn = Box<
+    dyn (Fn(&(dyn Candidate + Send + Sync), CandidatePreferences) -> CandidatePreferences)
+        + Send
+        + Sync,
+>;
+pub type BindingRequestFilterFn =
+    Box<dyn (Fn(&Message, &(dyn Candidate + Send + Sync), SocketAddr) -> bool) + Send + Sync>;
 
 /// Collects the arguments to `ice::Agent` construction into a single structure, for
 /// future-proofness of the interface.
 #[derive(Default)]
 pub struct AgentConfig {
+    /// An identifier of the agent, e.g. the ID of the session it belongs to, which is the `agent`
+    /// field of its warnings and errors, including those of the muxes it uses, and is reported
+    /// in its stats, so that a server running many agents can tell them apart. A random one is
+    /// generated if empty, see `Agent::id`.
+    pub agent_id: String,
+
     pub urls: Vec<Url>,
 
-    /// This is optional. Leave it as 0 for the default UDP port allocation strategy.
+    /// The range of the local ports of host candidates, UDP and TCP, of the connections active
+    /// TCP candidates dial, and of the sockets reaching STUN and TURN servers, e.g. the one a
+    /// firewall lets through. A socket tries the ports of the range from a random one until one
+    /// is free, and candidates for which none is are skipped, reporting
+    /// `IceError::PortRangeExhausted` through `Agent::on_candidate_error`. Leave both as 0 for the system to
+    /// allocate every port.
     pub port_min: u16,
-    /// This is optional. Leave it as 0 for the default UDP port allocation strategy.
+    /// See `port_min`.
     pub port_max: u16,
 
     /// It is used to perform connectivity checks. The values MUST be unguessable, with at least
     /// 128 bits of random number generator output used to generate the password, and at least 24
     /// bits of output to generate the username fragment.
+    /// It must be 4 to 256 ice-chars long (ALPHA, DIGIT, "+" or "/"), and is generated if empty.
     pub local_ufrag: String,
     /// It is used to perform connectivity checks. The values MUST be unguessable, with at least
     /// 128 bits of random number generator output used to generate the password, and at least 24
     /// bits of output to generate the username fragment.
+    /// It must be 22 to 256 ice-chars long (ALPHA, DIGIT, "+" or "/"), and is generated if empty.
     pub local_pwd: String,
 
     /// Controls mDNS behavior for the ICE agent.
@@ -87,28 +333,160 @@ pub struct AgentConfig {
     pub failed_timeout: Option<Duration>,
 
     /// Determines how often should we send ICE keepalives (should be less then connectiontimeout
-    /// above) when this is nil, it defaults to 10 seconds.
+    /// above) when this is nil, it defaults to 2 seconds.
     /// A keepalive interval of 0 means we never send keepalive packets
     pub keepalive_interval: Option<Duration>,
 
+    /// Controls how keepalives are sent on the selected pairs. Defaults to
+    /// `KeepaliveStrategy::BindingRequest`.
+    pub keepalive_strategy: KeepaliveStrategy,
+
+    /// Keeps the valid pairs which are not selected alive as well, with a binding request on
+    /// each of them every `keepalive_interval`, so that their NAT bindings don't expire and
+    /// failing over to them is instant. With aggressive nomination, which would nominate them,
+    /// only the selected pairs are kept alive.
+    pub keepalive_valid_pairs: bool,
+
+    /// The number of binding requests in a row on a selected pair which may go unanswered before
+    /// the pair is marked failed and the controlling agent nominates the best other valid pair
+    /// of the component in its place, which both agents select once the nomination succeeds,
+    /// rather than waiting for the disconnected and failed timeouts. Failing over is disabled
+    /// when this is 0, the default. The controlling agent only fails over with renomination. Agent creation fails with
+    /// `IceError::InvalidFailoverAfterLostChecks` above 32, the number of checks a pair keeps
+    /// track of.
+    pub failover_after_lost_checks: u16,
+
+    /// Determines how often consent to send is refreshed on the selected pair, the actual interval
+    /// is randomized between 0.8 and 1.2 times this value. Defaults to 5 seconds when this
+    /// property is nil. A consent check interval of 0 means we never send consent requests.
+    pub consent_check_interval: Option<Duration>,
+
+    /// Defaults to 30 seconds when this property is nil.
+    /// If no consent response was received on the selected pair for this long, the pair and the
+    /// connection are marked failed. If the duration is 0, consent never expires.
+    pub consent_timeout: Option<Duration>,
+
+    /// How long the selected pairs must stay unchanged before the local candidates which are
+    /// not part of any of them, nor of the `backup_pairs` best valid pairs of each component,
+    /// are closed, releasing their sockets, receive loops and TURN allocations, e.g. on servers
+    /// hosting thousands of agents. Candidates are kept until the agent is closed when this
+    /// property is nil.
+    pub release_unused_candidates_after: Option<Duration>,
+
+    /// The number of valid pairs, besides the selected one, each component keeps the local
+    /// candidate of when unused candidates are released, so that it can fall back on them.
+    pub backup_pairs: usize,
+
     /// An optional configuration for disabling or enabling support for specific network types.
+    /// Defaults to UDP4 and UDP6 when empty. Only these network types are gathered, and remote
+    /// candidates of other network types are ignored.
     pub network_types: Vec<NetworkType>,
 
-    /// An optional configuration for disabling or enabling support for specific candidate types.
+    /// An optional configuration for disabling or enabling support for specific candidate types,
+    /// e.g. `vec![CandidateType::Relay]` for relay-only deployments. Only these candidate types
+    /// are gathered.
     pub candidate_types: Vec<CandidateType>,
 
+    /// When set, remote candidates whose type is not in `candidate_types` are ignored as well.
+    /// By default remote candidates of any type are accepted.
+    pub strict_candidate_types: bool,
+
     //LoggerFactory logging.LoggerFactory
     /// Controls how often our internal task loop runs when in the connecting state.
-    /// Only useful for testing.
-    pub check_interval: Duration,
+    /// Defaults to 200 milliseconds when this property is nil.
+    pub check_interval: Option<Duration>,
 
     /// The max amount of binding requests the agent will send over a candidate pair for validation
     /// or nomination, if after max_binding_requests the candidate is yet to answer a binding
     /// request or a nomination we set the pair as failed.
     pub max_binding_requests: Option<u16>,
 
+    /// How many times the agent switches its role on 487 (Role Conflict) responses to its
+    /// checks, regenerating its tie-breaker each time. Further role conflicts fail the pair
+    /// instead, so that two agents can't keep switching roles. Defaults to 3 when this property
+    /// is nil.
+    pub max_role_switches: Option<u16>,
+
+    /// How long a connectivity check waits for a response before being retransmitted, multiplied
+    /// by rto_backoff after every retransmission as in RFC 5389 Section 7.2.1. Once a pair has
+    /// measured its round trip time, its RTO is derived from it as in RFC 6298 instead, so that
+    /// long-haul links are not retransmitted on too early. Together with max_binding_requests,
+    /// it trades robustness on lossy links for time-to-failure.
+    /// Checks are retransmitted on every check_interval when this property is nil.
+    pub initial_rto: Option<Duration>,
+
+    /// The factor the RTO of a connectivity check is multiplied by after every retransmission,
+    /// 1 keeping it fixed. Agent creation fails with `IceError::InvalidRtoBackoff` for 0.
+    /// Defaults to 2 when this property is nil.
+    pub rto_backoff: Option<u32>,
+
+    /// The pacing interval Ta: the connectivity checks and the STUN and TURN requests of the
+    /// gathering start at most once per interval, across all candidates, so that bursts are
+    /// not dropped by NATs which rate-limit new mappings. Defaults to 50 milliseconds when this
+    /// property is nil, a pacing interval of 0 disables pacing.
+    pub pacing_interval: Option<Duration>,
+
+    /// How long gathering waits for each STUN server to answer before giving up on it, without
+    /// holding up the other servers. The servers given up on are reported to the handler set
+    /// with `Agent::on_candidate_error`. Defaults to 5 seconds when this property is nil.
+    pub stun_gather_timeout: Option<Duration>,
+
+    /// How many STUN and TURN servers are gathered from at once, across the candidate types,
+    /// streams and components, the others waiting for one of them to answer or time out. Host
+    /// candidates don't wait for the servers. Defaults to 8 when this property is nil, and is
+    /// at least 1.
+    pub gather_concurrency: Option<usize>,
+
+    /// The number of components to gather, pair and nominate, e.g. 2 for RTP and RTCP when they
+    /// are not multiplexed. The connection is only connected once every component has selected
+    /// a pair. Defaults to 1 when this property is nil, several components can't be used with a
+    /// UDP or TCP mux.
+    pub components: Option<u16>,
+
+    /// The number of components of each data stream sharing the agent, e.g. `vec![1, 1]` for
+    /// audio and video sent over separate transports as with non-bundled SDP. Each stream has a
+    /// checklist and connections of its own, see `Agent::get_stream_conn`, while the streams
+    /// share the credentials, the gathering and the pacing of the agent, and pairs of the same
+    /// foundation are unfrozen together across streams. Defaults to a single stream of
+    /// `components` components when this property is empty, it can't be set along with
+    /// `components`.
+    pub streams: Vec<u16>,
+
+    /// The role the agent starts with, controlled by default. With `IceRole::Unspecified` it is
+    /// determined by the first of `dial`, `accept`, `start` or the first check of the remote
+    /// agent, which suits offer/answer signaling where the role depends on its outcome. `dial`
+    /// and `accept` always set the role they stand for.
+    pub role: IceRole,
+
+    /// Sets the role to `IceRole::Controlling`, whatever `role` is, as before `role` was added.
     pub is_controlling: bool,
 
+    /// Sends the packets of a component on its best valid pair until a pair is selected, so that
+    /// data can flow as soon as a check succeeded both ways, see `AgentEvent::ValidCandidatePair`.
+    /// By default they are sent on the best pair which has not failed, checked or not.
+    pub send_on_valid_pair: bool,
+
+    /// The number of bytes of the received packets each component buffers until they are read.
+    /// Defaults to 1 MB when this property is nil.
+    pub receive_buffer_size: Option<usize>,
+
+    /// Controls what happens to the packets received while the receive buffer is full. Defaults
+    /// to `BufferFullPolicy::DropNew`.
+    pub buffer_full_policy: BufferFullPolicy,
+
+    /// Controls how the pair is nominated when the agent is controlling. Defaults to
+    /// `NominationStrategy::Regular`.
+    pub nomination_strategy: NominationStrategy,
+
+    /// Controls how closely the agent follows RFC 8445. Defaults to `CompatibilityMode::Interop`.
+    pub compatibility_mode: CompatibilityMode,
+
+    /// The ICE options the agent advertises, to be put in the `a=ice-options` attribute of the
+    /// session description. A feature is only used when the remote agent advertises it as well,
+    /// see `Agent::set_remote_ice_options`. Defaults to trickle, renomination and ice2 when this
+    /// property is nil.
+    pub ice_options: Option<IceOptions>,
+
     /// lite agents do not perform connectivity check and only provide host candidates.
     pub lite: bool,
 
@@ -122,8 +500,28 @@ pub struct AgentConfig {
     /// Contains a list of public IP addresses that are to be used as a host candidate or srflx
     /// candidate. This is used typically for servers that are behind 1:1 D-NAT (e.g. AWS EC2
     /// instances) and to eliminate the need of server reflexisive candidate gathering.
+    /// An entry is either a sole external IP, or "external/local" where local is a local IP
+    /// address or an interface name, e.g. "1.2.3.4/10.0.0.1" or "1.2.3.4/eth0".
     pub nat_1to1_ips: Vec<String>,
 
+    /// Extra addresses to advertise for local IPs, e.g. the elastic IP of a cloud VM. Unlike
+    /// `nat_1to1_ips`, each address picks its own candidate type. They are checked against the
+    /// interfaces of the agent when it is created.
+    pub additional_addresses: Vec<AdditionalAddress>,
+
+    /// Gathers loopback addresses as well, i.e. 127.0.0.1 and ::1, so agents running on the
+    /// same host can connect through them, e.g. in tests.
+    pub include_loopback: bool,
+
+    /// Gathers IPv6 link-local addresses as well, i.e. fe80::/10. They are only reachable through
+    /// the interface they are configured on, which is identified by their scope ID.
+    pub include_link_local: bool,
+
+    /// Gathers deprecated IPv6 addresses as well, e.g. temporary addresses whose preferred
+    /// lifetime has ended and which are about to expire. Addresses are only known to be
+    /// deprecated on Linux.
+    pub include_deprecated_ipv6: bool,
+
     /// Specify a minimum wait time before selecting host candidates.
     pub host_acceptance_min_wait: Option<Duration>,
     /// Specify a minimum wait time before selecting srflx candidates.
@@ -135,18 +533,161 @@ pub struct AgentConfig {
 
     /// Net is the our abstracted network interface for internal development purpose only
     /// (see (github.com/pion/transport/vnet)[github.com/pion/transport/vnet]).
+    ///
+    /// Interface enumeration, host sockets, STUN and TURN over UDP all go through it, so NAT
+    /// traversal can be tested without touching the host network. mDNS and TURN over TCP are not
+    /// supported by a virtual network and are disabled on it.
     pub net: Option<Arc<Net>>,
 
     /// A function that you can use in order to whitelist or blacklist the interfaces which are
-    /// used to gather ICE candidates.
+    /// used to gather ICE candidates. It is called with the interface name, e.g. "docker0", and
+    /// interfaces it returns false for are skipped before any socket is bound on them.
     pub interface_filter: Arc<Option<InterfaceFilterFn>>,
 
+    /// The names, e.g. "eth1", or the addresses of the interfaces to gather host candidates
+    /// from, most preferred first, e.g. the media NIC of a multi-homed server. The other
+    /// interfaces are skipped, after `interface_filter`. The local preference of the host
+    /// candidates follows the order of the list, so that their pairs are checked and selected
+    /// first. Every interface is gathered from when the list is empty.
+    pub interfaces: Vec<String>,
+
+    /// The network cost of interfaces by name, e.g. "wlan0", overriding the cost guessed from the
+    /// name of the interface. Host candidates signal the cost of their interface, and it breaks
+    /// ties between pairs of equal priority. See `NETWORK_COST_*`.
+    pub network_costs: HashMap<String, u16>,
+
+    /// The local preference of the host candidates of interfaces by name, e.g. 65535 for "eth0",
+    /// 30000 for "wlan0" and 10000 for "wwan0", so that the pairs of the preferred uplink are
+    /// checked and selected first on multi-homed hosts. The interfaces which are not listed get
+    /// the highest local preference, 65535. The rank of the address in `interfaces` is
+    /// subtracted from it.
+    pub interface_preferences: HashMap<String, u16>,
+
+    /// The DSCP marking the packets of components, e.g. 46, expedited forwarding, for the
+    /// component 1 of an audio stream. It is set on the socket of the local candidate of the
+    /// selected pair of the component, in every stream, and again whenever another pair is
+    /// selected. Only the UDP sockets of the host candidates and of the server reflexive ones
+    /// can be marked: relay and TCP candidates and the ones of a mux are not, which
+    /// `CandidatePair::dscp_marked` reports for the selected pair.
+    pub dscp: HashMap<u16, u8>,
+
+    /// A function that you can use in order to whitelist or blacklist the IP addresses which are
+    /// used to gather ICE candidates. It is applied to host addresses before any socket is bound
+    /// on them, and to server reflexive and relay addresses before the candidates are added.
+    pub ip_filter: Arc<Option<IpFilterFn>>,
+
+    /// A function computing the priority sent in the PRIORITY attribute of the checks from a
+    /// local candidate, which the remote agent gives to the peer reflexive candidate it may learn
+    /// from them. By default it is the priority of the local candidate itself, use
+    /// `peer_reflexive_priority` for the one of RFC 8445 Section 7.1.1, or a custom one for
+    /// stacks which weight peer reflexive candidates differently.
+    pub prflx_priority: Arc<Option<PrflxPriorityFn>>,
+
+    /// A function computing the preferences the priority of the local candidates is derived
+    /// from, which gets a candidate and the preferences of the default formula, e.g. to prefer
+    /// relay candidates to the other ones or to demote IPv6 ones. It must not call `priority` on
+    /// the candidate. The candidates created with a priority of their own, see
+    /// `CandidateBaseConfig::priority`, keep it.
+    pub candidate_priority: Arc<Option<CandidatePriorityFn>>,
+
+    /// A function which accepts or rejects the inbound binding requests which passed the
+    /// username and message integrity checks, e.g. to only accept checks from an allowlist of
+    /// source IPs or carrying an application token in a custom attribute. It is called with the
+    /// request, the local candidate it was received on and its source address, before a peer
+    /// reflexive candidate or a pair is created for it. The requests it returns false for are
+    /// dropped.
+    pub binding_request_filter: Arc<Option<BindingRequestFilterFn>>,
+
+    /// An optional resolver for the hostnames of STUN and TURN servers. When it is not set,
+    /// `TokioResolver` is used.
+    pub resolver: Option<Arc<dyn Resolver + Send + Sync>>,
+
+    /// An optional factory of the sockets candidates are gathered on and STUN and TURN servers
+    /// are reached from. When it is not set, plain tokio sockets are used, see
+    /// `TokioSocketFactory`.
+    pub socket_factory: Option<Arc<dyn SocketFactory + Send + Sync>>,
+
+    /// The buffer sizes, reuse and type of service options of the sockets of host candidates
+    /// and of the ones STUN and TURN servers and proxies are reached from. They are set on the
+    /// sockets of `socket_factory` as well, once it returns them. As its UDP sockets are bound
+    /// already, the reuse options can't be set along with a factory, the agent isn't created
+    /// otherwise.
+    pub socket_options: SocketOptions,
+
+    /// An optional dialer used to establish the TCP connections to TURN servers, e.g. through a
+    /// SOCKS5 or HTTP CONNECT proxy. The connections to `turns:` servers are secured by
+    /// `tls_dialer` once established. `Socks5Dialer` and `HttpConnectDialer` are available with
+    /// the `socks5` and `http-proxy` features.
+    pub proxy_dialer: Option<Arc<dyn ProxyDialer + Send + Sync>>,
+
+    /// An optional dialer securing the connections to `turns:` servers, with TLS over TCP and
+    /// DTLS over UDP. It is required when `urls` holds a `turns:` URL, the agent isn't created
+    /// otherwise.
+    pub tls_dialer: Option<Arc<dyn TlsDialer + Send + Sync>>,
+
+    /// An optional provider of the credentials of TURN servers, called each time a relay is
+    /// allocated. When it is set, the username and password of the TURN URLs are not used and
+    /// may be left empty.
+    pub credential_provider: Option<Arc<dyn CredentialProvider + Send + Sync>>,
+
+    /// The lifetime asked for the allocations on the TURN servers of `urls`, which the servers
+    /// may shorten, see `Candidate::relay_lifetime`. Each allocation asks for a random lifetime
+    /// up to 20% longer, so that the allocations made at once are not all refreshed at once, as
+    /// the TURN client refreshes them halfway through their lifetime. The refreshes that fail
+    /// are reported with `AgentEvent::RelayRefreshFailed`. Defaults to 10 minutes when this
+    /// property is nil. It must be at least a second, the agent isn't created otherwise.
+    pub turn_allocation_lifetime: Option<Duration>,
+
+    /// An optional provider of relayed transports allocated by the application, which relay
+    /// candidates are gathered on without the TURN client of the agent.
+    pub relay_provider: Option<Arc<dyn RelayProvider + Send + Sync>>,
+
+    /// An optional port mapper which asks the local gateway to forward a public port to a socket
+    /// bound to each local address, a server reflexive candidate being gathered on each mapped
+    /// address. `NatPmpMapper` and `PcpMapper` are available with the `port-mapping` feature.
+    pub port_mapper: Option<Arc<dyn PortMapper + Send + Sync>>,
+
+    /// An optional UDPMux which lets several agents share a single UDP socket for host
+    /// candidates. When set, host candidates are gathered on the mux instead of listening on a
+    /// port per interface.
+    pub udp_mux: Option<Arc<dyn UdpMux + Send + Sync>>,
+
+    /// An optional UDPMux used to gather server reflexive candidates. When set, the STUN binding
+    /// requests are sent from its shared socket, so the candidates report the shared local port
+    /// and their traffic is demultiplexed like the one of the host candidates.
+    pub udp_mux_srflx: Option<Arc<dyn UdpMuxSrflx + Send + Sync>>,
+
+    /// Gathers the UDP host candidates of all the local addresses of a family on a single socket
+    /// bound to the wildcard address, rather than on a socket per address, which saves sockets
+    /// and read loops on hosts with many addresses. The packets of each candidate are sent from
+    /// its own address. It is only supported on Linux and Android, and ignored elsewhere, on a
+    /// virtual network and when `udp_mux` is set.
+    pub bind_wildcard_udp: bool,
+
+    /// An optional TCPMux used to accept inbound connections for passive TCP host candidates.
+    /// TCP candidates are only gathered when it is set and `network_types` contains TCP4 or TCP6.
+    pub tcp_mux: Option<Arc<dyn TcpMux + Send + Sync>>,
+
+    /// An optional watcher of the local interfaces. When set, the host candidates of addresses
+    /// which disappear are closed along with their pairs, and host candidates are gathered for
+    /// the addresses which show up once gathering has started.
+    pub interface_watcher: Option<Arc<InterfaceWatcher>>,
+
     /// Controls if self-signed certificates are accepted when connecting to TURN servers via TLS or
     /// DTLS.
     pub insecure_skip_verify: bool,
 }
 
 impl AgentConfig {
+    /// Returns which addresses of the interfaces are gathered besides the usual ones.
+    pub(crate) const fn local_address_policy(&self) -> LocalAddressPolicy {
+        LocalAddressPolicy {
+            include_loopback: self.include_loopback,
+            include_link_local: self.include_link_local,
+            include_deprecated_ipv6: self.include_deprecated_ipv6,
+        }
+    }
+
     /// Populates an agent and falls back to defaults if fields are unset.
     pub(crate) fn init_with_defaults(&self, a: &mut AgentInternal) {
         if let Some(max_binding_requests) = self.max_binding_requests {
@@ -155,6 +696,44 @@ impl AgentConfig {
             a.max_binding_requests = DEFAULT_MAX_BINDING_REQUESTS;
         }
 
+        a.max_role_switches = self.max_role_switches.unwrap_or(DEFAULT_MAX_ROLE_SWITCHES);
+
+        if let Some(initial_rto) = self.initial_rto {
+            a.initial_rto = initial_rto;
+        } else {
+            a.initial_rto = Duration::from_secs(0);
+        }
+
+        a.rto_backoff = self.rto_backoff.unwrap_or(DEFAULT_RTO_BACKOFF);
+
+        if self.compatibility_mode == CompatibilityMode::Unspecified {
+            a.compatibility_mode = CompatibilityMode::Interop;
+        } else {
+            a.compatibility_mode = self.compatibility_mode;
+        }
+
+        let mut pacing_interval = self.pacing_interval.unwrap_or(DEFAULT_PACING_INTERVAL);
+        if a.compatibility_mode == CompatibilityMode::Rfc8445Strict {
+            pacing_interval = pacing_interval.max(MIN_PACING_INTERVAL);
+        }
+        a.pacer = Arc::new(Pacer::new(pacing_interval));
+
+        a.gather_semaphore = Arc::new(Semaphore::new(
+            self.gather_concurrency
+                .unwrap_or(DEFAULT_GATHER_CONCURRENCY)
+                .max(1),
+        ));
+
+        a.turn_allocation_lifetime = self
+            .turn_allocation_lifetime
+            .unwrap_or(DEFAULT_TURN_ALLOCATION_LIFETIME);
+
+        if let Some(stun_gather_timeout) = self.stun_gather_timeout {
+            a.stun_gather_timeout = stun_gather_timeout;
+        } else {
+            a.stun_gather_timeout = DEFAULT_STUN_GATHER_TIMEOUT;
+        }
+
         if let Some(host_acceptance_min_wait) = self.host_acceptance_min_wait {
             a.host_acceptance_min_wait = host_acceptance_min_wait;
         } else {
@@ -197,10 +776,36 @@ impl AgentConfig {
             a.keepalive_interval = DEFAULT_KEEPALIVE_INTERVAL;
         }
 
-        if self.check_interval == Duration::from_secs(0) {
-            a.check_interval = DEFAULT_CHECK_INTERVAL;
+        if let Some(consent_check_interval) = self.consent_check_interval {
+            a.consent_check_interval = consent_check_interval;
         } else {
-            a.check_interval = self.check_interval;
+            a.consent_check_interval = DEFAULT_CONSENT_CHECK_INTERVAL;
+        }
+
+        if let Some(consent_timeout) = self.consent_timeout {
+            a.consent_timeout = consent_timeout;
+        } else {
+            a.consent_timeout = DEFAULT_CONSENT_TIMEOUT;
+        }
+
+        if self.keepalive_strategy == KeepaliveStrategy::Unspecified {
+            a.keepalive_strategy = KeepaliveStrategy::BindingRequest;
+        } else {
+            a.keepalive_strategy = self.keepalive_strategy;
+        }
+
+        if self.nomination_strategy == NominationStrategy::Unspecified {
+            a.nomination_strategy = NominationStrategy::Regular;
+        } else {
+            a.nomination_strategy = self.nomination_strategy;
+        }
+
+        a.local_ice_options = self.ice_options.unwrap_or(DEFAULT_ICE_OPTIONS);
+
+        if let Some(check_interval) = self.check_interval {
+            a.check_interval = check_interval;
+        } else {
+            a.check_interval = DEFAULT_CHECK_INTERVAL;
         }
     }
 
@@ -208,13 +813,13 @@ impl AgentConfig {
         &self,
         mdns_mode: MulticastDnsMode,
         candidate_types: &[CandidateType],
-    ) -> Result<Option<ExternalIpMapper>, Error> {
+    ) -> Result<Option<ExternalIpMapper>, IceError> {
         if let Some(ext_ip_mapper) =
             ExternalIpMapper::new(self.nat_1to1_ip_candidate_type, &self.nat_1to1_ips)?
         {
             if ext_ip_mapper.candidate_type == CandidateType::Host {
                 if mdns_mode == MulticastDnsMode::QueryAndGather {
-                    return Err(ERR_MULTICAST_DNS_WITH_NAT_1TO1_IP_MAPPING.to_owned());
+                    return Err(IceError::MulticastDnsWithNat1To1IpMapping);
                 }
                 let mut candi_host_enabled = false;
                 for candi_type in candidate_types {
@@ -224,7 +829,7 @@ impl AgentConfig {
                     }
                 }
                 if !candi_host_enabled {
-                    return Err(ERR_INEFFECTIVE_NAT_1TO1_IP_MAPPING_HOST.to_owned());
+                    return Err(IceError::IneffectiveNat1To1IpMappingHost);
                 }
             } else if ext_ip_mapper.candidate_type == CandidateType::ServerReflexive {
                 let mut candi_srflx_enabled = false;
@@ -235,7 +840,7 @@ impl AgentConfig {
                     }
                 }
                 if !candi_srflx_enabled {
-                    return Err(ERR_INEFFECTIVE_NAT_1TO1_IP_MAPPING_SRFLX.to_owned());
+                    return Err(IceError::IneffectiveNat1To1IpMappingSrflx);
                 }
             }
 
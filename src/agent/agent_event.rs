@@ -0,0 +1,104 @@
+use crate::candidate::{Candidate, CandidatePair, CandidatePairState};
+use crate::errors::*;
+use crate::state::{ConnectionState, GatheringState};
+use crate::url::Url;
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The number of events buffered for each subscriber of `Agent::events`. A subscriber lagging
+/// further behind misses the oldest events.
+pub(crate) const EVENT_CHANNEL_CAPACITY: usize = 64;
+
+/// The error code of a server which couldn't be reached at all, e.g. as it didn't answer or
+/// couldn't be resolved, outside of the range of the STUN error codes as in WebRTC.
+pub const CANDIDATE_ERROR_CODE_UNREACHABLE: u16 = 701;
+
+/// A failed attempt to gather candidates, from a STUN or TURN server or on a local address,
+/// modeled on the `icecandidateerror` event of WebRTC.
+#[derive(Debug, Clone)]
+pub struct CandidateError {
+    /// The URL of the server, none when no socket could be bound for a host candidate or no
+    /// port could be mapped by `AgentConfig::port_mapper`.
+    pub url: Option<Url>,
+
+    /// The local address no socket could be bound on, for host candidates, or no port could be
+    /// mapped to.
+    pub address: Option<IpAddr>,
+
+    /// The STUN error code the server answered with, e.g. 401 or 403 when a TURN server refused
+    /// the credentials, or `CANDIDATE_ERROR_CODE_UNREACHABLE` when it couldn't be used at all,
+    /// or no socket could be bound.
+    pub error_code: u16,
+
+    /// A description of the failure, for logs and alerts.
+    pub error_text: String,
+
+    /// The error which caused the failure, e.g. `IceError::PortRangeExhausted` when no port of
+    /// the port range was free.
+    pub error: IceError,
+}
+
+/// Represents an event of the agent, as delivered by `Agent::events`. The events carry no agent
+/// id: each receiver only gets the events of the agent it was created from, whose id is
+/// `Agent::id`, so a server running many agents attributes them by the receiver they come from.
+#[derive(Clone)]
+pub enum AgentEvent {
+    /// The connection state has changed.
+    ConnectionStateChange(ConnectionState),
+
+    /// The gathering state has changed. Once it is `GatheringState::Complete`, no more local
+    /// candidates are gathered until the agent is restarted, except the host candidates of the
+    /// addresses reported later by `AgentConfig::interface_watcher`.
+    GatheringStateChange(GatheringState),
+
+    /// A new local candidate has been gathered.
+    Candidate(Arc<dyn Candidate + Send + Sync>),
+
+    /// The connectivity checks of a candidate pair moved it to another state, or the pair was
+    /// nominated. The pair is identified by the IDs of its candidates.
+    CandidatePairStateChange {
+        local_candidate_id: String,
+        remote_candidate_id: String,
+        state: CandidatePairState,
+        nominated: bool,
+    },
+
+    /// The first pair of a component has been validated, its checks having succeeded both ways,
+    /// possibly well before a pair is nominated and selected. With
+    /// `AgentConfig::send_on_valid_pair`, the connection of the component sends on the best
+    /// valid pair until one is selected, so that e.g. a DTLS handshake can start right away.
+    ValidCandidatePair(Arc<CandidatePair>),
+
+    /// The agent switched its role after a role conflict with the remote agent.
+    RoleChange { is_controlling: bool },
+
+    /// A new candidate pair has been selected. Whether its packets are marked with the DSCP of
+    /// its component is reported by `CandidatePair::dscp_marked`.
+    SelectedCandidatePairChange(Arc<CandidatePair>),
+
+    /// A pair to a peer behind the same NAT succeeded through the public mapping of the NAT, see
+    /// `CandidatePair::hairpinned`, while no pair of host candidates of its component succeeded.
+    /// Unless one succeeds later, the agents are on the same LAN but can't reach each other
+    /// directly, e.g. on a Wi-Fi network with client isolation, and their traffic takes a detour
+    /// through the NAT.
+    Hairpin(Arc<CandidatePair>),
+
+    /// A STUN or TURN server couldn't be used during gathering, no socket could be bound for a
+    /// host candidate or no port could be mapped, see `Agent::on_candidate_error`.
+    CandidateError(CandidateError),
+
+    /// The TURN allocation of a relay candidate could not be refreshed. Unless a later refresh
+    /// succeeds, the candidate stops working once the allocation expires, after `expires_in`,
+    /// and its pairs fail, so the application may want to restart ICE before then.
+    RelayRefreshFailed {
+        candidate_id: String,
+        expires_in: Option<Duration>,
+        error: IceError,
+    },
+
+    /// A non fatal error occurred in the background, e.g. a role conflict could not be resolved
+    /// (`IceError::RoleConflict`).
+    Error(IceError),
+}
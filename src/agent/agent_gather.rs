@@ -1,24 +1,50 @@
 use super::*;
+use crate::batch_conn::BatchConn;
 use crate::errors::*;
 use crate::network_type::*;
+use crate::port_mapping::{
+    PortMapper, PortMappingSockets, PORT_MAPPING_LIFETIME, PORT_MAPPING_MIN_REFRESH_INTERVAL,
+};
+use crate::proxy::ProxyDialer;
+use crate::relay_provider::RelayProvider;
+use crate::resolver::{resolve_server_addr, Resolver};
+use crate::socket_factory::{connect_tcp, SocketFactory, TcpConnector};
+use crate::stun_conn::StunConn;
+use crate::tcp_mux::active_tcp_conn::ActiveTcpConn;
+use crate::tcp_mux::simultaneous_open_tcp_conn::SimultaneousOpenTcpConn;
+use crate::tcp_mux::TcpMux;
+use crate::tls_dialer::TlsDialer;
+use crate::turn_allocation::{AllocationConn, STALE_NONCE};
+use crate::turn_credentials::CredentialProvider;
+use crate::udp_mux::{UdpMux, UdpMuxSrflx};
 use crate::url::{ProtoType, SchemeType, Url};
 use crate::util::*;
+use crate::wildcard_conn::WILDCARD_UDP_SUPPORTED;
 
-use util::{vnet::net::*, Conn, Error};
+use util::{vnet::net::*, Conn};
 
 use crate::candidate::candidate_base::CandidateBaseConfig;
 use crate::candidate::candidate_host::CandidateHostConfig;
 use crate::candidate::candidate_relay::CandidateRelayConfig;
 use crate::candidate::candidate_server_reflexive::CandidateServerReflexiveConfig;
 use crate::candidate::*;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::str::FromStr;
 use std::sync::Arc;
+use tokio::task::JoinHandle;
 use waitgroup::WaitGroup;
 
-const STUN_GATHER_TIMEOUT: Duration = Duration::from_secs(5);
+/// The port signaled for active TCP candidates.
+const TCP_ACTIVE_PORT: u16 = 9;
 
+/// The code of the 401 (Unauthorized) error responses.
+const UNAUTHORIZED: u16 = 401;
+
+#[derive(Clone)]
 pub(crate) struct GatherCandidatesInternalParams {
+    pub(crate) agent_id: String,
+    pub(crate) streams: Arc<Vec<u16>>,
     pub(crate) candidate_types: Vec<CandidateType>,
     pub(crate) urls: Vec<Url>,
     pub(crate) network_types: Vec<NetworkType>,
@@ -28,253 +54,681 @@ pub(crate) struct GatherCandidatesInternalParams {
     pub(crate) mdns_name: String,
     pub(crate) net: Arc<Net>,
     pub(crate) interface_filter: Arc<Option<InterfaceFilterFn>>,
+    pub(crate) interfaces: Arc<Vec<String>>,
+    pub(crate) network_costs: Arc<HashMap<String, u16>>,
+    pub(crate) interface_preferences: Arc<HashMap<String, u16>>,
+    pub(crate) ip_filter: Arc<Option<IpFilterFn>>,
+    pub(crate) resolver: Option<Arc<dyn Resolver + Send + Sync>>,
+    pub(crate) socket_factory: Arc<dyn SocketFactory + Send + Sync>,
+    pub(crate) proxy_dialer: Option<Arc<dyn ProxyDialer + Send + Sync>>,
+    pub(crate) tls_dialer: Option<Arc<dyn TlsDialer + Send + Sync>>,
+    pub(crate) credential_provider: Option<Arc<dyn CredentialProvider + Send + Sync>>,
+    pub(crate) relay_provider: Option<Arc<dyn RelayProvider + Send + Sync>>,
+    pub(crate) port_mapper: Option<Arc<dyn PortMapper + Send + Sync>>,
     pub(crate) ext_ip_mapper: Arc<Option<ExternalIpMapper>>,
+    pub(crate) additional_addresses: Arc<Vec<AdditionalAddress>>,
+    pub(crate) address_policy: LocalAddressPolicy,
+    pub(crate) bind_wildcard_udp: bool,
+    pub(crate) udp_mux: Option<Arc<dyn UdpMux + Send + Sync>>,
+    pub(crate) udp_mux_srflx: Option<Arc<dyn UdpMuxSrflx + Send + Sync>>,
+    pub(crate) tcp_mux: Option<Arc<dyn TcpMux + Send + Sync>>,
     pub(crate) agent_internal: Arc<Mutex<AgentInternal>>,
     pub(crate) gathering_state: Arc<AtomicU8>,
     pub(crate) chan_candidate_tx: ChanCandidateTx,
+    pub(crate) events_tx: broadcast::Sender<AgentEvent>,
+    pub(crate) gather_cancel_tx: broadcast::Sender<()>,
+}
+
+impl GatherCandidatesInternalParams {
+    fn local_params(&self, stream: u16, component: u16) -> GatherCandidatesLocalParams {
+        GatherCandidatesLocalParams {
+            agent_id: self.agent_id.clone(),
+            stream,
+            component,
+            network_types: self.network_types.clone(),
+            port_max: self.port_max,
+            port_min: self.port_min,
+            mdns_mode: self.mdns_mode,
+            mdns_name: self.mdns_name.clone(),
+            interface_filter: Arc::clone(&self.interface_filter),
+            interfaces: Arc::clone(&self.interfaces),
+            network_costs: Arc::clone(&self.network_costs),
+            interface_preferences: Arc::clone(&self.interface_preferences),
+            ip_filter: Arc::clone(&self.ip_filter),
+            ext_ip_mapper: Arc::clone(&self.ext_ip_mapper),
+            additional_addresses: Arc::clone(&self.additional_addresses),
+            address_policy: self.address_policy,
+            bind_wildcard_udp: self.bind_wildcard_udp,
+            udp_mux: self.udp_mux.clone(),
+            tcp_mux: self.tcp_mux.clone(),
+            net: Arc::clone(&self.net),
+            socket_factory: Arc::clone(&self.socket_factory),
+            agent_internal: Arc::clone(&self.agent_internal),
+        }
+    }
+}
+
+/// The tasks of a gathering, which are aborted when it is dropped, so that cancelling the
+/// gathering drops the STUN and TURN transactions in flight along with their sockets.
+#[derive(Default)]
+struct GatherTasks(Vec<JoinHandle<()>>);
+
+impl GatherTasks {
+    fn spawn(&mut self, task: impl Future<Output = ()> + Send + 'static) {
+        self.0.push(tokio::spawn(task));
+    }
+}
+
+impl Drop for GatherTasks {
+    fn drop(&mut self) {
+        for task in &self.0 {
+            task.abort();
+        }
+    }
 }
 
 struct GatherCandidatesLocalParams {
+    agent_id: String,
+    stream: u16,
+    component: u16,
     network_types: Vec<NetworkType>,
     port_max: u16,
     port_min: u16,
     mdns_mode: MulticastDnsMode,
     mdns_name: String,
     interface_filter: Arc<Option<InterfaceFilterFn>>,
+    interfaces: Arc<Vec<String>>,
+    network_costs: Arc<HashMap<String, u16>>,
+    interface_preferences: Arc<HashMap<String, u16>>,
+    ip_filter: Arc<Option<IpFilterFn>>,
     ext_ip_mapper: Arc<Option<ExternalIpMapper>>,
+    additional_addresses: Arc<Vec<AdditionalAddress>>,
+    address_policy: LocalAddressPolicy,
+    bind_wildcard_udp: bool,
+    udp_mux: Option<Arc<dyn UdpMux + Send + Sync>>,
+    tcp_mux: Option<Arc<dyn TcpMux + Send + Sync>>,
     net: Arc<Net>,
+    socket_factory: Arc<dyn SocketFactory + Send + Sync>,
     agent_internal: Arc<Mutex<AgentInternal>>,
 }
 
 struct GatherCandidatesSrflxMappedParasm {
+    agent_id: String,
+    stream: u16,
+    component: u16,
     network_types: Vec<NetworkType>,
     port_max: u16,
     port_min: u16,
+    ip_filter: Arc<Option<IpFilterFn>>,
     ext_ip_mapper: Arc<Option<ExternalIpMapper>>,
     net: Arc<Net>,
+    socket_factory: Arc<dyn SocketFactory + Send + Sync>,
     agent_internal: Arc<Mutex<AgentInternal>>,
 }
 
+pub(crate) struct GatherCandidatesPortMappedParams {
+    pub(crate) agent_id: String,
+    pub(crate) stream: u16,
+    pub(crate) component: u16,
+    pub(crate) network_types: Vec<NetworkType>,
+    pub(crate) port_max: u16,
+    pub(crate) port_min: u16,
+    pub(crate) interface_filter: Arc<Option<InterfaceFilterFn>>,
+    pub(crate) interfaces: Arc<Vec<String>>,
+    pub(crate) ip_filter: Arc<Option<IpFilterFn>>,
+    pub(crate) address_policy: LocalAddressPolicy,
+    pub(crate) port_mapper: Arc<dyn PortMapper + Send + Sync>,
+    pub(crate) net: Arc<Net>,
+    pub(crate) socket_factory: Arc<dyn SocketFactory + Send + Sync>,
+    pub(crate) agent_internal: Arc<Mutex<AgentInternal>>,
+}
+
 struct GatherCandidatesSrflxParams {
+    agent_id: String,
+    stream: u16,
+    component: u16,
     urls: Vec<Url>,
     network_types: Vec<NetworkType>,
     port_max: u16,
     port_min: u16,
+    ip_filter: Arc<Option<IpFilterFn>>,
+    resolver: Option<Arc<dyn Resolver + Send + Sync>>,
+    udp_mux_srflx: Option<Arc<dyn UdpMuxSrflx + Send + Sync>>,
     net: Arc<Net>,
+    socket_factory: Arc<dyn SocketFactory + Send + Sync>,
     agent_internal: Arc<Mutex<AgentInternal>>,
 }
 
 impl Agent {
-    pub(crate) async fn gather_candidates_internal(params: GatherCandidatesInternalParams) {
+    /// Follows the changes of the local addresses until the agent is closed.
+    pub(crate) async fn watch_interfaces(&self, mut changes: broadcast::Receiver<InterfaceChange>) {
+        let mut closed_ch_rx = {
+            let ai = self.agent_internal.lock().await;
+            match &ai.closed_ch_tx {
+                Some(closed_ch_tx) => closed_ch_tx.subscribe(),
+                None => return,
+            }
+        };
+
+        let params = self.gather_candidates_params(None);
+        tokio::spawn(async move {
+            loop {
+                let change = tokio::select! {
+                    result = changes.recv() => match result {
+                        Ok(change) => change,
+                        Err(broadcast::error::RecvError::Lagged(n)) => {
+                            warn_event!(
+                                agent = params.agent_id,
+                                missed = n;
+                                "missed interface changes"
+                            );
+                            continue;
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return,
+                    },
+                    _ = closed_ch_rx.recv() => return,
+                };
+
+                Self::handle_interface_change(&params, change).await;
+            }
+        });
+    }
+
+    /// Drops the host candidates of the removed addresses with their pairs, and gathers host
+    /// candidates for the added ones if the gathering has started. The gathering state is left
+    /// as it is, and no end-of-candidates is signaled again, the new candidates trickle in
+    /// like the ones gathered after the state went `Complete`.
+    async fn handle_interface_change(
+        params: &GatherCandidatesInternalParams,
+        change: InterfaceChange,
+    ) {
+        if !change.removed.is_empty() {
+            let mut ai = params.agent_internal.lock().await;
+            ai.remove_local_candidates_on(&change.removed).await;
+        }
+
+        if change.added.is_empty()
+            || !params.candidate_types.contains(&CandidateType::Host)
+            || params.gathering_state.load(Ordering::SeqCst) == GatheringState::New as u8
+        {
+            return;
+        }
+
+        let mut params = params.clone();
+        let (added, ip_filter) = (change.added, Arc::clone(&params.ip_filter));
+        params.ip_filter = Arc::new(Some(Box::new(move |ip: IpAddr| {
+            added.contains(&ip) && is_ip_allowed(&*ip_filter, ip)
+        })));
+        // The interfaces of the host are captured when a Net is created
+        if !params.net.is_virtual() {
+            params.net = Arc::new(Net::new(None));
+        }
+
+        let mut cancel_rx = params.gather_cancel_tx.subscribe();
+        let wg = WaitGroup::new();
+        let mut tasks = GatherTasks::default();
+        for (stream, component) in stream_components(&params.streams) {
+            let local_params = params.local_params(stream, component);
+            let w = wg.worker();
+            tasks.spawn(async move {
+                let _d = w;
+
+                Self::gather_candidates_local(local_params).await;
+            });
+        }
+
+        tokio::select! {
+            _ = wg.wait() => {}
+            _ = cancel_rx.recv() => drop(tasks),
+        }
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "gather", skip_all, fields(agent = %params.agent_id))
+    )]
+    pub(crate) async fn gather_candidates_internal(
+        params: GatherCandidatesInternalParams,
+        mut cancel_rx: broadcast::Receiver<()>,
+    ) {
         Self::set_gathering_state(
+            &params.agent_internal,
             &params.chan_candidate_tx,
+            &params.events_tx,
             &params.gathering_state,
             GatheringState::Gathering,
         )
         .await;
 
         let wg = WaitGroup::new();
-
-        for t in &params.candidate_types {
-            match t {
-                CandidateType::Host => {
-                    let local_params = GatherCandidatesLocalParams {
-                        network_types: params.network_types.clone(),
-                        port_max: params.port_max,
-                        port_min: params.port_min,
-                        mdns_mode: params.mdns_mode,
-                        mdns_name: params.mdns_name.clone(),
-                        interface_filter: Arc::clone(&params.interface_filter),
-                        ext_ip_mapper: Arc::clone(&params.ext_ip_mapper),
-                        net: Arc::clone(&params.net),
-                        agent_internal: Arc::clone(&params.agent_internal),
-                    };
-
-                    let w = wg.worker();
-                    tokio::spawn(async move {
-                        let _d = w;
-
-                        Self::gather_candidates_local(local_params).await;
-                    });
-                }
-                CandidateType::ServerReflexive => {
-                    let srflx_params = GatherCandidatesSrflxParams {
-                        urls: params.urls.clone(),
-                        network_types: params.network_types.clone(),
-                        port_max: params.port_max,
-                        port_min: params.port_min,
-                        net: Arc::clone(&params.net),
-                        agent_internal: Arc::clone(&params.agent_internal),
-                    };
-                    let w1 = wg.worker();
-                    tokio::spawn(async move {
-                        let _d = w1;
-
-                        Self::gather_candidates_srflx(srflx_params).await;
-                    });
-                    if let Some(ext_ip_mapper) = &*params.ext_ip_mapper {
-                        if ext_ip_mapper.candidate_type == CandidateType::ServerReflexive {
-                            let srflx_mapped_params = GatherCandidatesSrflxMappedParasm {
+        let mut tasks = GatherTasks::default();
+
+        // Each component of each stream gets candidates of its own, e.g. RTP and RTCP without
+        // rtcp-mux
+        for (stream, component) in stream_components(&params.streams) {
+            for t in &params.candidate_types {
+                match t {
+                    CandidateType::Host => {
+                        let local_params = params.local_params(stream, component);
+
+                        let w = wg.worker();
+                        tasks.spawn(async move {
+                            let _d = w;
+
+                            Self::gather_candidates_local(local_params).await;
+                        });
+                    }
+                    CandidateType::ServerReflexive => {
+                        let srflx_params = GatherCandidatesSrflxParams {
+                            agent_id: params.agent_id.clone(),
+                            stream,
+                            component,
+                            urls: params.urls.clone(),
+                            network_types: params.network_types.clone(),
+                            port_max: params.port_max,
+                            port_min: params.port_min,
+                            ip_filter: Arc::clone(&params.ip_filter),
+                            resolver: params.resolver.clone(),
+                            udp_mux_srflx: params.udp_mux_srflx.clone(),
+                            net: Arc::clone(&params.net),
+                            socket_factory: Arc::clone(&params.socket_factory),
+                            agent_internal: Arc::clone(&params.agent_internal),
+                        };
+                        let w1 = wg.worker();
+                        tasks.spawn(async move {
+                            let _d = w1;
+
+                            Self::gather_candidates_srflx(srflx_params).await;
+                        });
+                        if let Some(ext_ip_mapper) = &*params.ext_ip_mapper {
+                            if ext_ip_mapper.candidate_type == CandidateType::ServerReflexive {
+                                let srflx_mapped_params = GatherCandidatesSrflxMappedParasm {
+                                    agent_id: params.agent_id.clone(),
+                                    stream,
+                                    component,
+                                    network_types: params.network_types.clone(),
+                                    port_max: params.port_max,
+                                    port_min: params.port_min,
+                                    ip_filter: Arc::clone(&params.ip_filter),
+                                    ext_ip_mapper: Arc::clone(&params.ext_ip_mapper),
+                                    net: Arc::clone(&params.net),
+                                    socket_factory: Arc::clone(&params.socket_factory),
+                                    agent_internal: Arc::clone(&params.agent_internal),
+                                };
+                                let w2 = wg.worker();
+                                tasks.spawn(async move {
+                                    let _d = w2;
+
+                                    Self::gather_candidates_srflx_mapped(srflx_mapped_params).await;
+                                });
+                            }
+                        }
+                        if let Some(port_mapper) = &params.port_mapper {
+                            let port_mapped_params = GatherCandidatesPortMappedParams {
+                                agent_id: params.agent_id.clone(),
+                                stream,
+                                component,
                                 network_types: params.network_types.clone(),
                                 port_max: params.port_max,
                                 port_min: params.port_min,
-                                ext_ip_mapper: Arc::clone(&params.ext_ip_mapper),
+                                interface_filter: Arc::clone(&params.interface_filter),
+                                interfaces: Arc::clone(&params.interfaces),
+                                ip_filter: Arc::clone(&params.ip_filter),
+                                address_policy: params.address_policy,
+                                port_mapper: Arc::clone(port_mapper),
                                 net: Arc::clone(&params.net),
+                                socket_factory: Arc::clone(&params.socket_factory),
                                 agent_internal: Arc::clone(&params.agent_internal),
                             };
-                            let w2 = wg.worker();
-                            tokio::spawn(async move {
-                                let _d = w2;
+                            let w3 = wg.worker();
+                            tasks.spawn(async move {
+                                let _d = w3;
 
-                                Self::gather_candidates_srflx_mapped(srflx_mapped_params).await;
+                                Self::gather_candidates_port_mapped(port_mapped_params).await;
                             });
                         }
                     }
+                    CandidateType::Relay => {
+                        let urls = params.urls.clone();
+                        let net = Arc::clone(&params.net);
+                        let ip_filter = Arc::clone(&params.ip_filter);
+                        let resolver = params.resolver.clone();
+                        let proxy_dialer = params.proxy_dialer.clone();
+                        let tls_dialer = params.tls_dialer.clone();
+                        let credential_provider = params.credential_provider.clone();
+                        let socket_factory = Arc::clone(&params.socket_factory);
+                        let port_max = params.port_max;
+                        let port_min = params.port_min;
+                        let agent_internal = Arc::clone(&params.agent_internal);
+                        let w = wg.worker();
+                        tasks.spawn(async move {
+                            let _d = w;
+
+                            Self::gather_candidates_relay(
+                                urls,
+                                net,
+                                socket_factory,
+                                port_max,
+                                port_min,
+                                ip_filter,
+                                resolver,
+                                proxy_dialer,
+                                tls_dialer,
+                                credential_provider,
+                                agent_internal,
+                                stream,
+                                component,
+                            )
+                            .await;
+                        });
+
+                        if let Some(relay_provider) = &params.relay_provider {
+                            let relay_provider = Arc::clone(relay_provider);
+                            let ip_filter = Arc::clone(&params.ip_filter);
+                            let agent_internal = Arc::clone(&params.agent_internal);
+                            let w = wg.worker();
+                            tasks.spawn(async move {
+                                let _d = w;
+
+                                Self::gather_candidates_relay_provider(
+                                    relay_provider,
+                                    ip_filter,
+                                    agent_internal,
+                                    stream,
+                                    component,
+                                )
+                                .await;
+                            });
+                        }
+                    }
+                    _ => {}
                 }
-                CandidateType::Relay => {
-                    let urls = params.urls.clone();
-                    let net = Arc::clone(&params.net);
-                    let agent_internal = Arc::clone(&params.agent_internal);
-                    let w = wg.worker();
-                    tokio::spawn(async move {
-                        let _d = w;
-
-                        Self::gather_candidates_relay(urls, net, agent_internal).await;
-                    });
-                }
-                _ => {}
             }
         }
 
-        // Block until all STUN and TURN URLs have been gathered (or timed out)
-        wg.wait().await;
+        // Block until all STUN and TURN URLs have been gathered (or timed out), or the gathering
+        // is cancelled
+        tokio::select! {
+            _ = wg.wait() => {}
+            _ = cancel_rx.recv() => {
+                log::debug!("gathering cancelled");
+                drop(tasks);
+            }
+        }
 
         Self::set_gathering_state(
+            &params.agent_internal,
             &params.chan_candidate_tx,
+            &params.events_tx,
             &params.gathering_state,
             GatheringState::Complete,
         )
         .await;
     }
 
-    async fn set_gathering_state(
+    pub(crate) async fn set_gathering_state(
+        agent_internal: &Arc<Mutex<AgentInternal>>,
         chan_candidate_tx: &ChanCandidateTx,
+        events_tx: &broadcast::Sender<AgentEvent>,
         gathering_state: &Arc<AtomicU8>,
         new_state: GatheringState,
     ) {
-        if GatheringState::from(gathering_state.load(Ordering::SeqCst)) != new_state
-            && new_state == GatheringState::Complete
-        {
+        let old_state = GatheringState::from(gathering_state.load(Ordering::SeqCst));
+        if old_state != new_state && new_state == GatheringState::Complete {
             if let Some(tx) = chan_candidate_tx {
                 let _ = tx.send(None).await;
             }
         }
 
         gathering_state.store(new_state as u8, Ordering::SeqCst);
+
+        if old_state != new_state {
+            let _ = events_tx.send(AgentEvent::GatheringStateChange(new_state));
+
+            let mut ai = agent_internal.lock().await;
+            if let Some(on_gathering_state_change) = &mut ai.on_gathering_state_change_hdlr {
+                on_gathering_state_change(new_state).await;
+            }
+        }
     }
 
     async fn gather_candidates_local(params: GatherCandidatesLocalParams) {
+        if let Some(udp_mux) = params.udp_mux.clone() {
+            Self::gather_candidates_local_udp_mux(&params, udp_mux).await;
+        }
+        let wildcard_udp = params.bind_wildcard_udp
+            && params.udp_mux.is_none()
+            && !params.net.is_virtual()
+            && WILDCARD_UDP_SUPPORTED;
+        if wildcard_udp {
+            Self::gather_candidates_local_wildcard(&params).await;
+        }
+
         let (
+            agent_id,
+            stream,
+            component,
             network_types,
             port_max,
             port_min,
             mdns_mode,
             mdns_name,
             interface_filter,
+            interfaces,
+            network_costs,
+            interface_preferences,
+            ip_filter,
             ext_ip_mapper,
+            additional_addresses,
+            tcp_mux,
             net,
+            socket_factory,
             agent_internal,
         ) = (
+            params.agent_id,
+            params.stream,
+            params.component,
             params.network_types,
             params.port_max,
             params.port_min,
             params.mdns_mode,
             params.mdns_name,
             params.interface_filter,
+            params.interfaces,
+            params.network_costs,
+            params.interface_preferences,
+            params.ip_filter,
             params.ext_ip_mapper,
+            params.additional_addresses,
+            params.tcp_mux,
             params.net,
+            params.socket_factory,
             params.agent_internal,
         );
 
-        let ips = local_interfaces(&net, &*interface_filter, &network_types).await;
-        for ip in ips {
-            let mut mapped_ip = ip;
-
-            if mdns_mode != MulticastDnsMode::QueryAndGather && ext_ip_mapper.is_some() {
-                if let Some(ext_ip_mapper2) = &*ext_ip_mapper {
-                    if ext_ip_mapper2.candidate_type == CandidateType::Host {
-                        if let Ok(mi) = ext_ip_mapper2.find_external_ip(&ip.to_string()) {
-                            mapped_ip = mi;
-                        } else {
-                            log::warn!(
-                                "1:1 NAT mapping is enabled but no external IP is found for {}",
-                                ip
-                            );
-                        }
-                    }
-                }
+        // UDP host candidates are gathered on the UDPMux or the wildcard sockets when they are
+        // configured. Passive TCP candidates need the TCPMux to accept connections, active ones
+        // dial out on demand and simultaneous-open ones do both from a port of their own.
+        let mut networks = vec![];
+        if params.udp_mux.is_none()
+            && !wildcard_udp
+            && (network_types.is_empty() || network_types.iter().any(|t| t.is_udp()))
+        {
+            networks.push((UDP, TcpType::Unspecified));
+        }
+        if network_types.iter().any(|t| t.is_tcp()) {
+            if tcp_mux.is_some() {
+                networks.push((TCP, TcpType::Passive));
             }
+            networks.push((TCP, TcpType::Active));
+            networks.push((TCP, TcpType::SimultaneousOpen));
+        }
+        if networks.is_empty() {
+            return;
+        }
 
+        let (local_ufrag, errors) = {
+            let ai = agent_internal.lock().await;
+            (ai.local_ufrag.clone(), CandidateErrorReporter::new(&ai))
+        };
+
+        let ips = local_interfaces(
+            &net,
+            &*interface_filter,
+            &interfaces,
+            &*ip_filter,
+            &network_types,
+            params.address_policy,
+        )
+        .await;
+        let local_networks =
+            local_networks(&net, &network_costs, &interface_preferences, &interfaces).await;
+        for ip in ips {
+            let local_network = local_networks.get(&ip).copied().unwrap_or_default();
+            let mapped_ip = Self::map_host_address(
+                &agent_id,
+                ip,
+                mdns_mode,
+                &ext_ip_mapper,
+                &additional_addresses,
+            );
             let address = if mdns_mode == MulticastDnsMode::QueryAndGather {
                 mdns_name.clone()
             } else {
                 mapped_ip.to_string()
             };
 
-            //TODO: for network in networks
-            let network = UDP.to_owned();
-            {
-                /*TODO:switch network {
-                case tcp:
-                    // Handle ICE TCP passive mode
-
-                    a.log.Debugf("GetConn by ufrag: %s\n", a.localUfrag)
-                    conn, err = a.tcpMux.GetConnByUfrag(a.localUfrag)
-                    if err != nil {
-                        if !errors.Is(err, ErrTCPMuxNotInitialized) {
-                            a.log.Warnf("error getting tcp conn by ufrag: %s %s %s\n", network, ip, a.localUfrag)
+            for (network, tcp_type) in &networks {
+                let network_type = match determine_network_type(network, &ip) {
+                    Ok(network_type) => network_type,
+                    Err(_) => continue,
+                };
+                if !network_types.is_empty() && !network_types.contains(&network_type) {
+                    continue;
+                }
+
+                let mut batch_conn: Option<Arc<dyn BatchConn + Send + Sync>> = None;
+                let conn: Arc<dyn Conn + Send + Sync> = match tcp_type {
+                    TcpType::Passive => {
+                        // Handle ICE TCP passive mode
+                        let tcp_mux = match &tcp_mux {
+                            Some(tcp_mux) => tcp_mux,
+                            None => continue,
+                        };
+
+                        log::debug!("GetConn by ufrag: {}", local_ufrag);
+                        match tcp_mux.get_conn_by_ufrag(&local_ufrag, &agent_id).await {
+                            Ok(conn) => conn,
+                            Err(err) => {
+                                warn_event!(
+                                    agent = agent_id,
+                                    network = network,
+                                    ip = ip,
+                                    ufrag = local_ufrag,
+                                    error = err;
+                                    "error getting tcp conn by ufrag"
+                                );
+                                continue;
+                            }
                         }
-                        continue
                     }
-                    port = conn.LocalAddr().(*net.TCPAddr).Port
-                    tcpType = TCPTypePassive
-                    // is there a way to verify that the listen address is even
-                    // accessible from the current interface.
-                case udp:*/
-
-                let conn: Arc<dyn Conn + Send + Sync> = match listen_udp_in_port_range(
-                    &net,
-                    port_max,
-                    port_min,
-                    SocketAddr::new(ip, 0),
-                )
-                .await
-                {
-                    Ok(conn) => conn,
-                    Err(err) => {
-                        log::warn!("could not listen {} {}: {}", network, ip, err);
-                        continue;
+                    // Active TCP candidates dial the remote passive candidates when checked
+                    TcpType::Active => Arc::new(ActiveTcpConn::new(
+                        agent_id.clone(),
+                        ip,
+                        Arc::clone(&socket_factory),
+                        port_max,
+                        port_min,
+                    )),
+                    TcpType::SimultaneousOpen => {
+                        match SimultaneousOpenTcpConn::bind(
+                            agent_id.clone(),
+                            ip,
+                            Arc::clone(&socket_factory),
+                            port_max,
+                            port_min,
+                        )
+                        .await
+                        {
+                            Ok(conn) => Arc::new(conn),
+                            Err(err) => {
+                                errors.report_listen(network, ip, err);
+                                continue;
+                            }
+                        }
                     }
+                    // Sockets of the host can send batches of packets with a single syscall
+                    _ if !net.is_virtual() => match listen_batch_udp_in_port_range(
+                        &socket_factory,
+                        port_max,
+                        port_min,
+                        create_addr(network_type, ip, 0),
+                    )
+                    .await
+                    {
+                        Ok(conn) => {
+                            batch_conn =
+                                Some(Arc::clone(&conn) as Arc<dyn BatchConn + Send + Sync>);
+                            conn
+                        }
+                        Err(err) => {
+                            errors.report_listen(network, ip, err);
+                            continue;
+                        }
+                    },
+                    _ => match listen_udp_in_port_range(
+                        &net,
+                        &socket_factory,
+                        port_max,
+                        port_min,
+                        create_addr(network_type, ip, 0),
+                    )
+                    .await
+                    {
+                        Ok(conn) => conn,
+                        Err(err) => {
+                            errors.report_listen(network, ip, err);
+                            continue;
+                        }
+                    },
                 };
 
-                let port = match conn.local_addr().await {
-                    Ok(addr) => addr.port(),
-                    Err(err) => {
-                        log::warn!("could not get local addr: {}", err);
-                        continue;
+                let port = if *tcp_type == TcpType::Active {
+                    // The port of an active candidate is meaningless, RFC 6544 uses the discard
+                    // port for it
+                    TCP_ACTIVE_PORT
+                } else {
+                    match conn.local_addr().await {
+                        Ok(addr) => addr.port(),
+                        Err(err) => {
+                            warn_event!(
+                                agent = agent_id,
+                                network = network,
+                                ip = ip,
+                                error = err;
+                                "could not get local addr"
+                            );
+                            continue;
+                        }
                     }
                 };
 
                 let host_config = CandidateHostConfig {
                     base_config: CandidateBaseConfig {
-                        network: network.clone(),
-                        address,
+                        network: (*network).to_owned(),
+                        address: address.clone(),
                         port,
-                        component: COMPONENT_RTP,
+                        stream,
+                        component,
+                        network_id: local_network.id,
+                        network_cost: local_network.cost,
+                        address_rank: local_network.rank,
+                        interface_preference: local_network.preference,
                         conn: Some(conn),
+                        batch_conn,
                         ..CandidateBaseConfig::default()
                     },
-                    ..CandidateHostConfig::default()
+                    tcp_type: *tcp_type,
                 };
 
                 let candidate: Arc<dyn Candidate + Send + Sync> = match host_config
@@ -284,12 +738,13 @@ impl Agent {
                     Ok(candidate) => {
                         if mdns_mode == MulticastDnsMode::QueryAndGather {
                             if let Err(err) = candidate.set_ip(&ip).await {
-                                log::warn!(
-                                    "Failed to create host candidate: {} {} {}: {}",
-                                    network,
-                                    mapped_ip,
-                                    port,
-                                    err
+                                warn_event!(
+                                    agent = agent_id,
+                                    network = network,
+                                    ip = mapped_ip,
+                                    port = port,
+                                    error = err;
+                                    "Failed to create host candidate"
                                 );
                                 continue;
                             }
@@ -297,44 +752,430 @@ impl Agent {
                         Arc::new(candidate)
                     }
                     Err(err) => {
-                        log::warn!(
-                            "Failed to create host candidate: {} {} {}: {}",
-                            network,
-                            mapped_ip,
-                            port,
-                            err
+                        warn_event!(
+                            agent = agent_id,
+                            network = network,
+                            ip = mapped_ip,
+                            port = port,
+                            error = err;
+                            "Failed to create host candidate"
                         );
                         continue;
                     }
                 };
 
+                Self::add_gathered_candidate(&agent_id, &agent_internal, &candidate).await;
+            }
+
+            for a in additional_addresses
+                .iter()
+                .filter(|a| a.local_ip == ip && a.candidate_type == CandidateType::ServerReflexive)
+            {
+                if let Ok(network_type) = determine_network_type(UDP, &ip) {
+                    if !network_types.is_empty() && !network_types.contains(&network_type) {
+                        continue;
+                    }
+                }
+
+                Self::gather_candidate_additional_srflx(
+                    &agent_id,
+                    a,
+                    &net,
+                    &socket_factory,
+                    port_max,
+                    port_min,
+                    &agent_internal,
+                    stream,
+                    component,
+                )
+                .await;
+            }
+        }
+    }
+
+    /// Adds a srflx candidate advertising an additional address, bound to its local IP.
+    async fn gather_candidate_additional_srflx(
+        agent_id: &str,
+        a: &AdditionalAddress,
+        net: &Arc<Net>,
+        socket_factory: &Arc<dyn SocketFactory + Send + Sync>,
+        port_max: u16,
+        port_min: u16,
+        agent_internal: &Arc<Mutex<AgentInternal>>,
+        stream: u16,
+        component: u16,
+    ) {
+        let conn = match listen_udp_in_port_range(
+            net,
+            socket_factory,
+            port_max,
+            port_min,
+            SocketAddr::new(a.local_ip, 0),
+        )
+        .await
+        {
+            Ok(conn) => conn,
+            Err(err) => {
+                let errors = CandidateErrorReporter::new(&*agent_internal.lock().await);
+                errors.report_listen(UDP, a.local_ip, err);
+                return;
+            }
+        };
+
+        let laddr = match conn.local_addr().await {
+            Ok(laddr) => laddr,
+            Err(err) => {
+                warn_event!(agent = agent_id, error = err; "could not get local addr");
+                return;
+            }
+        };
+
+        let srflx_config = CandidateServerReflexiveConfig {
+            base_config: CandidateBaseConfig {
+                network: UDP.to_owned(),
+                address: a.ip.to_string(),
+                port: laddr.port(),
+                stream,
+                component,
+                conn: Some(conn),
+                ..CandidateBaseConfig::default()
+            },
+            rel_addr: laddr.ip().to_string(),
+            rel_port: laddr.port(),
+        };
+
+        let candidate: Arc<dyn Candidate + Send + Sync> = match srflx_config
+            .new_candidate_server_reflexive(Some(agent_internal.clone()))
+            .await
+        {
+            Ok(candidate) => Arc::new(candidate),
+            Err(err) => {
+                warn_event!(
+                    agent = agent_id,
+                    network = UDP,
+                    ip = a.ip,
+                    port = laddr.port(),
+                    error = err;
+                    "Failed to create server reflexive candidate"
+                );
+                return;
+            }
+        };
+
+        Self::add_gathered_candidate(&agent_id, &agent_internal, &candidate).await;
+    }
+
+    /// Gathers the UDP host candidates of all the local addresses of a family on a single socket
+    /// bound to the wildcard address, each candidate sending from its own address.
+    async fn gather_candidates_local_wildcard(params: &GatherCandidatesLocalParams) {
+        let network_types: Vec<NetworkType> = params
+            .network_types
+            .iter()
+            .copied()
+            .filter(|network_type| network_type.is_udp())
+            .collect();
+        if !params.network_types.is_empty() && network_types.is_empty() {
+            return;
+        }
+
+        let ips = local_interfaces(
+            &params.net,
+            &*params.interface_filter,
+            &params.interfaces,
+            &*params.ip_filter,
+            &network_types,
+            params.address_policy,
+        )
+        .await;
+        let local_networks = local_networks(
+            &params.net,
+            &params.network_costs,
+            &params.interface_preferences,
+            &params.interfaces,
+        )
+        .await;
+        let errors = {
+            let ai = params.agent_internal.lock().await;
+            CandidateErrorReporter::new(&ai)
+        };
+
+        // The wildcard socket of each family, by whether it is the IPv4 one
+        let mut wildcards = HashMap::new();
+        let network = UDP.to_owned();
+        for ip in ips {
+            let wildcard = if let Some(wildcard) = wildcards.get(&ip.is_ipv4()) {
+                Arc::clone(wildcard)
+            } else {
+                let laddr = if ip.is_ipv4() {
+                    SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)
+                } else {
+                    SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0)
+                };
+                match listen_wildcard_udp_in_port_range(
+                    &params.socket_factory,
+                    params.port_max,
+                    params.port_min,
+                    laddr,
+                )
+                .await
                 {
-                    let mut ai = agent_internal.lock().await;
-                    if let Err(err) = ai.add_candidate(&candidate).await {
-                        if let Err(close_err) = candidate.close().await {
-                            log::warn!("Failed to close candidate: {}", close_err);
+                    Ok(wildcard) => {
+                        wildcards.insert(ip.is_ipv4(), Arc::clone(&wildcard));
+                        wildcard
+                    }
+                    Err(err) => {
+                        errors.report_listen(&network, laddr.ip(), err);
+                        continue;
+                    }
+                }
+            };
+
+            let conn = wildcard.conn(ip);
+            let port = match conn.local_addr().await {
+                Ok(addr) => addr.port(),
+                Err(err) => {
+                    warn_event!(
+                        agent = params.agent_id,
+                        ip = ip,
+                        error = err;
+                        "could not get local addr"
+                    );
+                    continue;
+                }
+            };
+
+            let local_network = local_networks.get(&ip).copied().unwrap_or_default();
+            let mapped_ip = Self::map_host_address(
+                &params.agent_id,
+                ip,
+                params.mdns_mode,
+                &params.ext_ip_mapper,
+                &params.additional_addresses,
+            );
+            let address = if params.mdns_mode == MulticastDnsMode::QueryAndGather {
+                params.mdns_name.clone()
+            } else {
+                mapped_ip.to_string()
+            };
+
+            let host_config = CandidateHostConfig {
+                base_config: CandidateBaseConfig {
+                    network: network.clone(),
+                    address,
+                    port,
+                    stream: params.stream,
+                    component: params.component,
+                    network_id: local_network.id,
+                    network_cost: local_network.cost,
+                    address_rank: local_network.rank,
+                    interface_preference: local_network.preference,
+                    batch_conn: Some(Arc::clone(&conn) as Arc<dyn BatchConn + Send + Sync>),
+                    conn: Some(conn),
+                    ..CandidateBaseConfig::default()
+                },
+                ..CandidateHostConfig::default()
+            };
+
+            let candidate: Arc<dyn Candidate + Send + Sync> = match host_config
+                .new_candidate_host(Some(params.agent_internal.clone()))
+                .await
+            {
+                Ok(candidate) => {
+                    if params.mdns_mode == MulticastDnsMode::QueryAndGather {
+                        if let Err(err) = candidate.set_ip(&ip).await {
+                            warn_event!(
+                                agent = params.agent_id,
+                                network = network,
+                                ip = mapped_ip,
+                                port = port,
+                                error = err;
+                                "Failed to create host candidate"
+                            );
+                            continue;
                         }
-                        log::warn!(
-                            "Failed to append to localCandidates and run onCandidateHdlr: {}",
-                            err
-                        );
                     }
+                    Arc::new(candidate)
+                }
+                Err(err) => {
+                    warn_event!(
+                        agent = params.agent_id,
+                        network = network,
+                        ip = mapped_ip,
+                        port = port,
+                        error = err;
+                        "Failed to create host candidate"
+                    );
+                    continue;
                 }
+            };
+
+            Self::add_gathered_candidate(&params.agent_id, &params.agent_internal, &candidate)
+                .await;
+        }
+    }
+
+    /// Gathers host candidates which all share the connection handed out by the UDPMux for the
+    /// local ufrag, instead of listening on a port per interface.
+    async fn gather_candidates_local_udp_mux(
+        params: &GatherCandidatesLocalParams,
+        udp_mux: Arc<dyn UdpMux + Send + Sync>,
+    ) {
+        let local_ufrag = {
+            let ai = params.agent_internal.lock().await;
+            ai.local_ufrag.clone()
+        };
+
+        let conn = match udp_mux.get_conn(&local_ufrag, &params.agent_id).await {
+            Ok(conn) => conn,
+            Err(err) => {
+                warn_event!(
+                    agent = params.agent_id,
+                    ufrag = local_ufrag,
+                    error = err;
+                    "could not get udp mux conn"
+                );
+                return;
+            }
+        };
+
+        let local_addr = match conn.local_addr().await {
+            Ok(addr) => addr,
+            Err(err) => {
+                warn_event!(
+                    agent = params.agent_id,
+                    ufrag = local_ufrag,
+                    error = err;
+                    "could not get local addr"
+                );
+                return;
             }
+        };
+
+        // A mux bound to a specific address only receives on that address
+        let ips = if local_addr.ip().is_unspecified() {
+            local_interfaces(
+                &params.net,
+                &*params.interface_filter,
+                &params.interfaces,
+                &*params.ip_filter,
+                &params.network_types,
+                params.address_policy,
+            )
+            .await
+        } else if is_ip_allowed(&*params.ip_filter, local_addr.ip()) {
+            vec![local_addr.ip()]
+        } else {
+            vec![]
+        };
+
+        let local_networks = local_networks(
+            &params.net,
+            &params.network_costs,
+            &params.interface_preferences,
+            &params.interfaces,
+        )
+        .await;
+        let network = UDP.to_owned();
+        for ip in ips {
+            let local_network = local_networks.get(&ip).copied().unwrap_or_default();
+            let mapped_ip = Self::map_host_address(
+                &params.agent_id,
+                ip,
+                params.mdns_mode,
+                &params.ext_ip_mapper,
+                &params.additional_addresses,
+            );
+            // The mDNS name hides the address of the interface, as with the other host candidates
+            let address = if params.mdns_mode == MulticastDnsMode::QueryAndGather {
+                params.mdns_name.clone()
+            } else {
+                mapped_ip.to_string()
+            };
+
+            let host_config = CandidateHostConfig {
+                base_config: CandidateBaseConfig {
+                    network: network.clone(),
+                    address,
+                    port: local_addr.port(),
+                    stream: params.stream,
+                    component: params.component,
+                    network_id: local_network.id,
+                    network_cost: local_network.cost,
+                    address_rank: local_network.rank,
+                    interface_preference: local_network.preference,
+                    conn: Some(Arc::clone(&conn)),
+                    ..CandidateBaseConfig::default()
+                },
+                ..CandidateHostConfig::default()
+            };
+
+            let candidate: Arc<dyn Candidate + Send + Sync> = match host_config
+                .new_candidate_host(Some(params.agent_internal.clone()))
+                .await
+            {
+                Ok(candidate) => {
+                    if params.mdns_mode == MulticastDnsMode::QueryAndGather {
+                        if let Err(err) = candidate.set_ip(&ip).await {
+                            warn_event!(
+                                agent = params.agent_id,
+                                network = network,
+                                ip = mapped_ip,
+                                port = local_addr.port(),
+                                error = err;
+                                "Failed to create host candidate"
+                            );
+                            continue;
+                        }
+                    }
+                    Arc::new(candidate)
+                }
+                Err(err) => {
+                    warn_event!(
+                        agent = params.agent_id,
+                        network = network,
+                        ip = ip,
+                        port = local_addr.port(),
+                        error = err;
+                        "Failed to create host candidate"
+                    );
+                    continue;
+                }
+            };
+
+            Self::add_gathered_candidate(&params.agent_id, &params.agent_internal, &candidate)
+                .await;
         }
     }
 
     async fn gather_candidates_srflx_mapped(params: GatherCandidatesSrflxMappedParasm) {
-        let (network_types, port_max, port_min, ext_ip_mapper, net, agent_internal) = (
+        let (
+            agent_id,
+            stream,
+            component,
+            network_types,
+            port_max,
+            port_min,
+            ip_filter,
+            ext_ip_mapper,
+            net,
+            socket_factory,
+            agent_internal,
+        ) = (
+            params.agent_id,
+            params.stream,
+            params.component,
             params.network_types,
             params.port_max,
             params.port_min,
+            params.ip_filter,
             params.ext_ip_mapper,
             params.net,
+            params.socket_factory,
             params.agent_internal,
         );
 
         let wg = WaitGroup::new();
+        let mut tasks = GatherTasks::default();
 
         for network_type in network_types {
             if network_type.is_tcp() {
@@ -343,15 +1184,19 @@ impl Agent {
 
             let network = network_type.to_string();
             let net2 = Arc::clone(&net);
+            let socket_factory2 = Arc::clone(&socket_factory);
             let agent_internal2 = Arc::clone(&agent_internal);
+            let agent_id2 = agent_id.clone();
             let ext_ip_mapper2 = Arc::clone(&ext_ip_mapper);
+            let ip_filter2 = Arc::clone(&ip_filter);
 
             let w = wg.worker();
-            tokio::spawn(async move {
+            tasks.spawn(async move {
                 let _d = w;
 
                 let conn: Arc<dyn Conn + Send + Sync> = match listen_udp_in_port_range(
                     &net2,
+                    &socket_factory2,
                     port_max,
                     port_min,
                     if network_type.is_ipv4() {
@@ -364,7 +1209,12 @@ impl Agent {
                 {
                     Ok(conn) => conn,
                     Err(err) => {
-                        log::warn!("Failed to listen {}: {}", network, err);
+                        warn_event!(
+                            agent = agent_id2,
+                            network = network,
+                            error = err;
+                            "Failed to listen"
+                        );
                         return Ok(());
                     }
                 };
@@ -375,26 +1225,36 @@ impl Agent {
                         match ext_ip_mapper3.find_external_ip(&laddr.ip().to_string()) {
                             Ok(ip) => ip,
                             Err(err) => {
-                                log::warn!(
-                                    "1:1 NAT mapping is enabled but no external IP is found for {}: {}",
-                                    laddr,
-                                    err
+                                warn_event!(
+                                    agent = agent_id2,
+                                    local = laddr,
+                                    error = err;
+                                    "1:1 NAT mapping is enabled but no external IP is found"
                                 );
                                 return Ok(());
                             }
                         }
                     } else {
-                        log::error!("ext_ip_mapper is None in gather_candidates_srflx_mapped");
+                        error_event!(
+                            agent = agent_id2;
+                            "ext_ip_mapper is None in gather_candidates_srflx_mapped"
+                        );
                         return Ok(());
                     }
                 };
 
+                if !is_ip_allowed(&*ip_filter2, mapped_ip) {
+                    log::debug!("server reflexive address {} is filtered out", mapped_ip);
+                    return Ok(());
+                }
+
                 let srflx_config = CandidateServerReflexiveConfig {
                     base_config: CandidateBaseConfig {
                         network: network.clone(),
                         address: mapped_ip.to_string(),
                         port: laddr.port(),
-                        component: COMPONENT_RTP,
+                        stream,
+                        component,
                         conn: Some(conn),
                         ..CandidateBaseConfig::default()
                     },
@@ -408,48 +1268,267 @@ impl Agent {
                 {
                     Ok(candidate) => Arc::new(candidate),
                     Err(err) => {
-                        log::warn!(
-                            "Failed to create server reflexive candidate: {} {} {}: {}",
-                            network,
-                            mapped_ip,
-                            laddr.port(),
-                            err
+                        warn_event!(
+                            agent = agent_id2,
+                            network = network,
+                            ip = mapped_ip,
+                            port = laddr.port(),
+                            error = err;
+                            "Failed to create server reflexive candidate"
                         );
                         return Ok(());
                     }
                 };
 
-                {
-                    let mut ai = agent_internal2.lock().await;
-                    if let Err(err) = ai.add_candidate(&candidate).await {
-                        if let Err(close_err) = candidate.close().await {
-                            log::warn!("Failed to close candidate: {}", close_err);
-                        }
-                        log::warn!(
-                            "Failed to append to localCandidates and run onCandidateHdlr: {}",
-                            err
-                        );
+                Self::add_gathered_candidate(&agent_id2, &agent_internal2, &candidate).await;
+
+                Ok::<(), IceError>(())
+            });
+        }
+
+        wg.wait().await;
+    }
+
+    /// Gathers a server reflexive candidate on the port the port mapper maps to a socket bound to
+    /// each local address, and keeps the mapping alive until the candidate is closed.
+    pub(crate) async fn gather_candidates_port_mapped(params: GatherCandidatesPortMappedParams) {
+        let network_types: Vec<NetworkType> = params
+            .network_types
+            .iter()
+            .copied()
+            .filter(|network_type| network_type.is_udp())
+            .collect();
+        let ips = local_interfaces(
+            &params.net,
+            &*params.interface_filter,
+            &params.interfaces,
+            &*params.ip_filter,
+            &network_types,
+            params.address_policy,
+        )
+        .await;
+
+        let sockets =
+            PortMappingSockets::new(Arc::clone(&params.net), Arc::clone(&params.socket_factory));
+        let errors = {
+            let ai = params.agent_internal.lock().await;
+            CandidateErrorReporter::new(&ai)
+        };
+        let wg = WaitGroup::new();
+        let mut tasks = GatherTasks::default();
+
+        for ip in ips {
+            let net = Arc::clone(&params.net);
+            let socket_factory = Arc::clone(&params.socket_factory);
+            let sockets = sockets.clone();
+            let port_mapper = Arc::clone(&params.port_mapper);
+            let ip_filter = Arc::clone(&params.ip_filter);
+            let agent_internal = Arc::clone(&params.agent_internal);
+            let agent_id = params.agent_id.clone();
+            let errors = errors.clone();
+            let (stream, component, port_max, port_min) = (
+                params.stream,
+                params.component,
+                params.port_max,
+                params.port_min,
+            );
+
+            let w = wg.worker();
+            tasks.spawn(async move {
+                let _d = w;
+
+                let conn = match listen_udp_in_port_range(
+                    &net,
+                    &socket_factory,
+                    port_max,
+                    port_min,
+                    SocketAddr::new(ip, 0),
+                )
+                .await
+                {
+                    Ok(conn) => conn,
+                    Err(err) => {
+                        warn_event!(agent = agent_id, ip = ip, error = err; "Failed to listen");
+                        return Ok(());
                     }
+                };
+
+                let laddr = conn.local_addr().await?;
+                let mapping = match port_mapper
+                    .map_udp(&sockets, laddr, PORT_MAPPING_LIFETIME)
+                    .await
+                {
+                    Ok(mapping) => mapping,
+                    Err(err) => {
+                        errors.report_port_mapping(laddr, err);
+                        return Ok(());
+                    }
+                };
+
+                if !is_ip_allowed(&*ip_filter, mapping.external.ip()) {
+                    log::debug!(
+                        "server reflexive address {} is filtered out",
+                        mapping.external
+                    );
+                    Self::unmap_port(&agent_id, &*port_mapper, &sockets, laddr).await;
+                    return Ok(());
                 }
 
-                Ok::<(), Error>(())
+                let srflx_config = CandidateServerReflexiveConfig {
+                    base_config: CandidateBaseConfig {
+                        network: UDP.to_owned(),
+                        address: mapping.external.ip().to_string(),
+                        port: mapping.external.port(),
+                        stream,
+                        component,
+                        conn: Some(conn),
+                        ..CandidateBaseConfig::default()
+                    },
+                    rel_addr: laddr.ip().to_string(),
+                    rel_port: laddr.port(),
+                };
+
+                let candidate: Arc<dyn Candidate + Send + Sync> = match srflx_config
+                    .new_candidate_server_reflexive(Some(Arc::clone(&agent_internal)))
+                    .await
+                {
+                    Ok(candidate) => Arc::new(candidate),
+                    Err(err) => {
+                        warn_event!(
+                            agent = agent_id,
+                            mapped = mapping.external,
+                            local = laddr,
+                            error = err;
+                            "Failed to create server reflexive candidate"
+                        );
+                        Self::unmap_port(&agent_id, &*port_mapper, &sockets, laddr).await;
+                        return Ok(());
+                    }
+                };
+
+                Self::add_gathered_candidate(&agent_id, &agent_internal, &candidate).await;
+
+                let closed_ch_rx = {
+                    let closed_ch = candidate.get_closed_ch();
+                    let closed_ch = closed_ch.lock().await;
+                    closed_ch.as_ref().map(broadcast::Sender::subscribe)
+                };
+                if let Some(closed_ch_rx) = closed_ch_rx {
+                    tokio::spawn(Self::refresh_port_mapping(
+                        agent_id,
+                        port_mapper,
+                        sockets,
+                        laddr,
+                        mapping.lifetime,
+                        closed_ch_rx,
+                    ));
+                } else {
+                    Self::unmap_port(&agent_id, &*port_mapper, &sockets, laddr).await;
+                }
+
+                Ok::<(), IceError>(())
             });
         }
 
         wg.wait().await;
     }
 
+    /// Refreshes the mapping of `laddr` halfway through its lifetime, though not more often than
+    /// `PORT_MAPPING_MIN_REFRESH_INTERVAL`, and deletes it once the candidate it was gathered for
+    /// is closed.
+    async fn refresh_port_mapping(
+        agent_id: String,
+        port_mapper: Arc<dyn PortMapper + Send + Sync>,
+        sockets: PortMappingSockets,
+        laddr: SocketAddr,
+        mut lifetime: Duration,
+        mut closed_ch_rx: broadcast::Receiver<()>,
+    ) {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep((lifetime / 2).max(PORT_MAPPING_MIN_REFRESH_INTERVAL)) => {
+                    match port_mapper.map_udp(&sockets, laddr, PORT_MAPPING_LIFETIME).await {
+                        Ok(mapping) => lifetime = mapping.lifetime,
+                        Err(err) => {
+                            warn_event!(
+                                agent = agent_id,
+                                local = laddr,
+                                error = err;
+                                "Failed to refresh the port mapping"
+                            );
+                        }
+                    }
+                }
+                _ = closed_ch_rx.recv() => break,
+            }
+        }
+
+        Self::unmap_port(&agent_id, &*port_mapper, &sockets, laddr).await;
+    }
+
+    /// Deletes the mapping of `laddr`, which the gateway lets expire anyway if it fails.
+    async fn unmap_port(
+        agent_id: &str,
+        port_mapper: &(dyn PortMapper + Send + Sync),
+        sockets: &PortMappingSockets,
+        laddr: SocketAddr,
+    ) {
+        if let Err(err) = port_mapper.unmap_udp(sockets, laddr).await {
+            warn_event!(
+                agent = agent_id,
+                local = laddr,
+                error = err;
+                "Failed to delete the port mapping"
+            );
+        }
+    }
+
     async fn gather_candidates_srflx(params: GatherCandidatesSrflxParams) {
-        let (urls, network_types, port_max, port_min, net, agent_internal) = (
+        if let Some(udp_mux_srflx) = params.udp_mux_srflx.clone() {
+            Self::gather_candidates_srflx_udp_mux(params, udp_mux_srflx).await;
+            return;
+        }
+
+        let (
+            agent_id,
+            stream,
+            component,
+            urls,
+            network_types,
+            port_max,
+            port_min,
+            ip_filter,
+            resolver,
+            net,
+            socket_factory,
+            agent_internal,
+        ) = (
+            params.agent_id,
+            params.stream,
+            params.component,
             params.urls,
             params.network_types,
             params.port_max,
             params.port_min,
+            params.ip_filter,
+            params.resolver,
             params.net,
+            params.socket_factory,
             params.agent_internal,
         );
 
+        let (errors, stun_gather_timeout, gather_semaphore, pacer) = {
+            let ai = agent_internal.lock().await;
+            (
+                CandidateErrorReporter::new(&ai),
+                ai.stun_gather_timeout,
+                Arc::clone(&ai.gather_semaphore),
+                Arc::clone(&ai.pacer),
+            )
+        };
+
         let wg = WaitGroup::new();
+        let mut tasks = GatherTasks::default();
         for network_type in network_types {
             if network_type.is_tcp() {
                 continue;
@@ -460,55 +1539,92 @@ impl Agent {
                 let is_ipv4 = network_type.is_ipv4();
                 let url = url.clone();
                 let net2 = Arc::clone(&net);
+                let socket_factory2 = Arc::clone(&socket_factory);
+                let ip_filter2 = Arc::clone(&ip_filter);
+                let resolver2 = resolver.clone();
                 let agent_internal2 = Arc::clone(&agent_internal);
+                let agent_id2 = agent_id.clone();
+                let errors2 = errors.clone();
+                let gather_semaphore2 = Arc::clone(&gather_semaphore);
+                let pacer2 = Arc::clone(&pacer);
 
                 let w = wg.worker();
-                tokio::spawn(async move {
+                tasks.spawn(async move {
                     let _d = w;
+                    let _permit = gather_semaphore2.acquire_owned().await;
 
-                    let host_port = format!("{}:{}", url.host, url.port);
-                    let server_addr = match net2.resolve_addr(is_ipv4, &host_port).await {
-                        Ok(addr) => addr,
-                        Err(err) => {
-                            log::warn!("failed to resolve stun host: {}: {}", host_port, err);
-                            return Ok(());
-                        }
-                    };
+                    let server_addr =
+                        match resolve_server_addr(&resolver2, &net2, is_ipv4, &url.host, url.port)
+                            .await
+                        {
+                            Ok(addr) => addr,
+                            Err(err) => {
+                                errors2.report(
+                                    &url,
+                                    format!(
+                                        "failed to resolve stun host: {}:{}",
+                                        url.host, url.port
+                                    ),
+                                    err,
+                                );
+                                return Ok(());
+                            }
+                        };
 
-                    let conn: Arc<dyn Conn + Send + Sync> = match listen_udp_in_port_range(
-                        &net2,
-                        port_max,
-                        port_min,
-                        if is_ipv4 {
-                            SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), 0)
-                        } else {
-                            SocketAddr::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0).into(), 0)
-                        },
-                    )
-                    .await
-                    {
+                    let laddr = if is_ipv4 {
+                        SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), 0)
+                    } else {
+                        SocketAddr::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0).into(), 0)
+                    };
+                    // Sockets of the host can send batches and be marked with a DSCP, like the
+                    // ones of the host candidates
+                    let mut batch_conn: Option<Arc<dyn BatchConn + Send + Sync>> = None;
+                    let result = if net2.is_virtual() {
+                        listen_udp_in_port_range(&net2, &socket_factory2, port_max, port_min, laddr)
+                            .await
+                    } else {
+                        listen_batch_udp_in_port_range(&socket_factory2, port_max, port_min, laddr)
+                            .await
+                            .map(|conn| {
+                                batch_conn =
+                                    Some(Arc::clone(&conn) as Arc<dyn BatchConn + Send + Sync>);
+                                conn as Arc<dyn Conn + Send + Sync>
+                            })
+                    };
+                    let conn: Arc<dyn Conn + Send + Sync> = match result {
                         Ok(conn) => conn,
                         Err(err) => {
-                            log::warn!("Failed to listen for {}: {}", server_addr, err);
+                            errors2.report(
+                                &url,
+                                format!("Failed to listen for {}", server_addr),
+                                err,
+                            );
                             return Ok(());
                         }
                     };
 
+                    pacer2.acquire().await;
                     let xoraddr =
-                        match get_xormapped_addr(&conn, server_addr, STUN_GATHER_TIMEOUT).await {
+                        match get_xormapped_addr(&conn, server_addr, stun_gather_timeout).await {
                             Ok(xoraddr) => xoraddr,
                             Err(err) => {
-                                log::warn!(
-                                    "could not get server reflexive address {} {}: {}",
-                                    network,
-                                    url,
-                                    err
+                                errors2.report(
+                                    &url,
+                                    format!(
+                                        "could not get server reflexive address {} {}",
+                                        network, url
+                                    ),
+                                    err,
                                 );
                                 return Ok(());
                             }
                         };
 
                     let (ip, port) = (xoraddr.ip, xoraddr.port);
+                    if !is_ip_allowed(&*ip_filter2, ip) {
+                        log::debug!("server reflexive address {} is filtered out", ip);
+                        return Ok(());
+                    }
 
                     let laddr = conn.local_addr().await?;
                     let srflx_config = CandidateServerReflexiveConfig {
@@ -516,8 +1632,10 @@ impl Agent {
                             network: network.clone(),
                             address: ip.to_string(),
                             port,
-                            component: COMPONENT_RTP,
+                            stream,
+                            component,
                             conn: Some(conn),
+                            batch_conn,
                             ..CandidateBaseConfig::default()
                         },
                         rel_addr: laddr.ip().to_string(),
@@ -530,31 +1648,21 @@ impl Agent {
                     {
                         Ok(candidate) => Arc::new(candidate),
                         Err(err) => {
-                            log::warn!(
-                                "Failed to create server reflexive candidate: {} {} {}: {}",
-                                network,
-                                ip,
-                                port,
-                                err
+                            warn_event!(
+                                agent = agent_id2,
+                                network = network,
+                                ip = ip,
+                                port = port,
+                                error = err;
+                                "Failed to create server reflexive candidate"
                             );
                             return Ok(());
                         }
                     };
 
-                    {
-                        let mut ai = agent_internal2.lock().await;
-                        if let Err(err) = ai.add_candidate(&candidate).await {
-                            if let Err(close_err) = candidate.close().await {
-                                log::warn!("Failed to close candidate: {}", close_err);
-                            }
-                            log::warn!(
-                                "Failed to append to localCandidates and run onCandidateHdlr: {}",
-                                err
-                            );
-                        }
-                    }
+                    Self::add_gathered_candidate(&agent_id2, &agent_internal2, &candidate).await;
 
-                    Ok::<(), Error>(())
+                    Ok::<(), IceError>(())
                 });
             }
         }
@@ -562,116 +1670,493 @@ impl Agent {
         wg.wait().await;
     }
 
+    /// Gathers server reflexive candidates on the connection handed out by the UDPMuxSrflx for
+    /// the local ufrag, learning the mapped address of the shared socket from each STUN server.
+    async fn gather_candidates_srflx_udp_mux(
+        params: GatherCandidatesSrflxParams,
+        udp_mux_srflx: Arc<dyn UdpMuxSrflx + Send + Sync>,
+    ) {
+        let (agent_id, stream, component, urls, ip_filter, resolver, net, agent_internal) = (
+            params.agent_id,
+            params.stream,
+            params.component,
+            params.urls,
+            params.ip_filter,
+            params.resolver,
+            params.net,
+            params.agent_internal,
+        );
+
+        let (local_ufrag, errors, stun_gather_timeout, gather_semaphore, pacer) = {
+            let ai = agent_internal.lock().await;
+            (
+                ai.local_ufrag.clone(),
+                CandidateErrorReporter::new(&ai),
+                ai.stun_gather_timeout,
+                Arc::clone(&ai.gather_semaphore),
+                Arc::clone(&ai.pacer),
+            )
+        };
+
+        let conn = match udp_mux_srflx.get_conn(&local_ufrag, &agent_id).await {
+            Ok(conn) => conn,
+            Err(err) => {
+                warn_event!(
+                    agent = agent_id,
+                    ufrag = local_ufrag,
+                    error = err;
+                    "could not get udp mux conn"
+                );
+                return;
+            }
+        };
+        let laddr = match conn.local_addr().await {
+            Ok(addr) => addr,
+            Err(err) => {
+                warn_event!(
+                    agent = agent_id,
+                    ufrag = local_ufrag,
+                    error = err;
+                    "could not get local addr"
+                );
+                return;
+            }
+        };
+
+        let wg = WaitGroup::new();
+        let mut tasks = GatherTasks::default();
+        for url in urls {
+            let conn = Arc::clone(&conn);
+            let udp_mux_srflx = Arc::clone(&udp_mux_srflx);
+            let net = Arc::clone(&net);
+            let ip_filter = Arc::clone(&ip_filter);
+            let resolver = resolver.clone();
+            let agent_internal = Arc::clone(&agent_internal);
+            let agent_id = agent_id.clone();
+            let errors = errors.clone();
+            let gather_semaphore = Arc::clone(&gather_semaphore);
+            let pacer = Arc::clone(&pacer);
+
+            let w = wg.worker();
+            tasks.spawn(async move {
+                let _d = w;
+                let _permit = gather_semaphore.acquire_owned().await;
+
+                let server_addr = match resolve_server_addr(
+                    &resolver,
+                    &net,
+                    laddr.is_ipv4(),
+                    &url.host,
+                    url.port,
+                )
+                .await
+                {
+                    Ok(addr) => addr,
+                    Err(err) => {
+                        errors.report(
+                            &url,
+                            format!("failed to resolve stun host: {}:{}", url.host, url.port),
+                            err,
+                        );
+                        return;
+                    }
+                };
+
+                pacer.acquire().await;
+                let xoraddr = match udp_mux_srflx
+                    .get_xor_mapped_addr(server_addr, stun_gather_timeout)
+                    .await
+                {
+                    Ok(xoraddr) => xoraddr,
+                    Err(err) => {
+                        errors.report(
+                            &url,
+                            format!("could not get server reflexive address {} {}", UDP, url),
+                            err,
+                        );
+                        return;
+                    }
+                };
+
+                let (ip, port) = (xoraddr.ip, xoraddr.port);
+                if !is_ip_allowed(&*ip_filter, ip) {
+                    log::debug!("server reflexive address {} is filtered out", ip);
+                    return;
+                }
+
+                let srflx_config = CandidateServerReflexiveConfig {
+                    base_config: CandidateBaseConfig {
+                        network: UDP.to_owned(),
+                        address: ip.to_string(),
+                        port,
+                        stream,
+                        component,
+                        conn: Some(conn),
+                        ..CandidateBaseConfig::default()
+                    },
+                    rel_addr: laddr.ip().to_string(),
+                    rel_port: laddr.port(),
+                };
+
+                let candidate: Arc<dyn Candidate + Send + Sync> = match srflx_config
+                    .new_candidate_server_reflexive(Some(agent_internal.clone()))
+                    .await
+                {
+                    Ok(candidate) => Arc::new(candidate),
+                    Err(err) => {
+                        warn_event!(
+                            agent = agent_id,
+                            network = UDP,
+                            ip = ip,
+                            port = port,
+                            error = err;
+                            "Failed to create server reflexive candidate"
+                        );
+                        return;
+                    }
+                };
+
+                Self::add_gathered_candidate(&agent_id, &agent_internal, &candidate).await;
+            });
+        }
+
+        wg.wait().await;
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub(crate) async fn gather_candidates_relay(
         urls: Vec<Url>,
         net: Arc<Net>,
+        socket_factory: Arc<dyn SocketFactory + Send + Sync>,
+        port_max: u16,
+        port_min: u16,
+        ip_filter: Arc<Option<IpFilterFn>>,
+        resolver: Option<Arc<dyn Resolver + Send + Sync>>,
+        proxy_dialer: Option<Arc<dyn ProxyDialer + Send + Sync>>,
+        tls_dialer: Option<Arc<dyn TlsDialer + Send + Sync>>,
+        credential_provider: Option<Arc<dyn CredentialProvider + Send + Sync>>,
         agent_internal: Arc<Mutex<AgentInternal>>,
+        stream: u16,
+        component: u16,
     ) {
+        let (agent_id, errors, gather_semaphore, pacer, turn_allocation_lifetime) = {
+            let ai = agent_internal.lock().await;
+            (
+                ai.agent_id.clone(),
+                CandidateErrorReporter::new(&ai),
+                Arc::clone(&ai.gather_semaphore),
+                Arc::clone(&ai.pacer),
+                ai.turn_allocation_lifetime,
+            )
+        };
+
         let wg = WaitGroup::new();
+        let mut tasks = GatherTasks::default();
 
         for url in urls {
             if url.scheme != SchemeType::Turn && url.scheme != SchemeType::Turns {
                 continue;
             }
-            if url.username.is_empty() {
-                log::error!("Failed to gather relay candidates: {}", *ERR_USERNAME_EMPTY);
+            if credential_provider.is_none() && url.username.is_empty() {
+                errors.report(
+                    &url,
+                    "Failed to gather relay candidates".to_owned(),
+                    IceError::UsernameEmpty,
+                );
                 return;
             }
-            if url.password.is_empty() {
-                log::error!("Failed to gather relay candidates: {}", *ERR_PASSWORD_EMPTY);
+            if credential_provider.is_none() && url.password.is_empty() {
+                errors.report(
+                    &url,
+                    "Failed to gather relay candidates".to_owned(),
+                    IceError::PasswordEmpty,
+                );
                 return;
             }
 
             let network = NetworkType::Udp4.to_string();
             let net2 = Arc::clone(&net);
+            let socket_factory2 = Arc::clone(&socket_factory);
+            let ip_filter2 = Arc::clone(&ip_filter);
+            let resolver2 = resolver.clone();
+            let proxy_dialer2 = proxy_dialer.clone();
+            let tls_dialer2 = tls_dialer.clone();
+            let credential_provider2 = credential_provider.clone();
             let agent_internal2 = Arc::clone(&agent_internal);
+            let agent_id2 = agent_id.clone();
+            let errors2 = errors.clone();
+            let gather_semaphore2 = Arc::clone(&gather_semaphore);
+            let pacer2 = Arc::clone(&pacer);
 
             let w = wg.worker();
-            tokio::spawn(async move {
+            tasks.spawn(async move {
                 let _d = w;
+                let _permit = gather_semaphore2.acquire_owned().await;
 
-                let turn_server_addr = format!("{}:{}", url.host, url.port);
+                let turn_server_sock_addr =
+                    match resolve_server_addr(&resolver2, &net2, true, &url.host, url.port).await {
+                        Ok(addr) => addr,
+                        Err(err) => {
+                            errors2.report(
+                                &url,
+                                format!("failed to resolve turn host: {}:{}", url.host, url.port),
+                                err,
+                            );
+                            return Ok(());
+                        }
+                    };
+                let turn_server_addr = turn_server_sock_addr.to_string();
+
+                let mut retried = false;
+                let (client, allocation, relay_conn, rel_addr, rel_port, relay_protocol) = loop {
+                    let (loc_conn, rel_addr, rel_port, relay_protocol) = match url.proto {
+                        ProtoType::Udp => {
+                            let loc_conn = match listen_udp_in_port_range(
+                                &net2,
+                                &socket_factory2,
+                                port_max,
+                                port_min,
+                                SocketAddr::from_str("0.0.0.0:0")?,
+                            )
+                            .await
+                            {
+                                Ok(c) => c,
+                                Err(err) => {
+                                    errors2.report(
+                                        &url,
+                                        format!("Failed to listen for {}", turn_server_addr),
+                                        err,
+                                    );
+                                    return Ok(());
+                                }
+                            };
 
-                let (loc_conn, rel_addr, rel_port) =
-                    if url.proto == ProtoType::Udp && url.scheme == SchemeType::Turn {
-                        let loc_conn = match net2.bind(SocketAddr::from_str("0.0.0.0:0")?).await {
-                            Ok(c) => c,
-                            Err(err) => {
-                                log::warn!("Failed to listen due to error: {}", err);
+                            let local_addr = loc_conn.local_addr().await?;
+                            let rel_addr = local_addr.ip().to_string();
+                            let rel_port = local_addr.port();
+                            if url.scheme != SchemeType::Turns {
+                                (loc_conn, rel_addr, rel_port, UDP.to_owned())
+                            } else {
+                                // The agent is not created without a TLS dialer for turns: URLs
+                                let tls_dialer = match &tls_dialer2 {
+                                    Some(tls_dialer) => tls_dialer,
+                                    None => return Err(IceError::TlsDialerRequired),
+                                };
+                                let loc_conn =
+                                    match tls_dialer.connect_dtls(&url.host, loc_conn).await {
+                                        Ok(conn) => conn,
+                                        Err(err) => {
+                                            errors2.report(
+                                                &url,
+                                                format!(
+                                                    "Failed the DTLS handshake with {}",
+                                                    turn_server_addr
+                                                ),
+                                                err,
+                                            );
+                                            return Ok(());
+                                        }
+                                    };
+                                (loc_conn, rel_addr, rel_port, DTLS.to_owned())
+                            }
+                        }
+                        ProtoType::Tcp => {
+                            // The vnet has no TCP support, and falling back to the host network
+                            // would make tests over the vnet depend on it
+                            if net2.is_virtual() {
+                                warn_event!(
+                                    agent = agent_id2,
+                                    url = url;
+                                    "vnet does not support TURN over TCP, skipping"
+                                );
                                 return Ok(());
                             }
-                        };
 
-                        let local_addr = loc_conn.local_addr().await?;
-                        let rel_addr = local_addr.ip().to_string();
-                        let rel_port = local_addr.port();
-                        (loc_conn, rel_addr, rel_port)
-                    /*TODO: case url.proto == ProtoType::UDP && url.scheme == SchemeType::TURNS{
-                    case a.proxyDialer != nil && url.Proto == ProtoTypeTCP && (url.Scheme == SchemeTypeTURN || url.Scheme == SchemeTypeTURNS):
-                    case url.Proto == ProtoTypeTCP && url.Scheme == SchemeTypeTURN:
-                    case url.Proto == ProtoTypeTCP && url.Scheme == SchemeTypeTURNS:*/
-                    } else {
-                        log::warn!("Unable to handle URL in gather_candidates_relay {}", url);
-                        return Ok(());
+                            let result = if let Some(proxy_dialer) = &proxy_dialer2 {
+                                let connector = TcpConnector::new(
+                                    Arc::clone(&socket_factory2),
+                                    port_max,
+                                    port_min,
+                                );
+                                proxy_dialer.dial(&connector, &turn_server_addr).await
+                            } else {
+                                connect_tcp(
+                                    &socket_factory2,
+                                    port_max,
+                                    port_min,
+                                    turn_server_sock_addr,
+                                )
+                                .await
+                            };
+                            let stream = match result {
+                                Ok(stream) => stream,
+                                Err(err) => {
+                                    errors2.report(
+                                        &url,
+                                        format!("Failed to connect to {}", turn_server_addr),
+                                        err,
+                                    );
+                                    return Ok(());
+                                }
+                            };
+
+                            let local_addr = stream.local_addr()?;
+                            let rel_addr = local_addr.ip().to_string();
+                            let rel_port = local_addr.port();
+                            if url.scheme != SchemeType::Turns {
+                                let loc_conn: Arc<dyn Conn + Send + Sync> =
+                                    Arc::new(StunConn::new(stream)?);
+                                (loc_conn, rel_addr, rel_port, TCP.to_owned())
+                            } else {
+                                let tls_dialer = match &tls_dialer2 {
+                                    Some(tls_dialer) => tls_dialer,
+                                    None => return Err(IceError::TlsDialerRequired),
+                                };
+                                let remote_addr = stream.peer_addr()?;
+                                let tls_stream =
+                                    match tls_dialer.connect_tls(&url.host, stream).await {
+                                        Ok(tls_stream) => tls_stream,
+                                        Err(err) => {
+                                            errors2.report(
+                                                &url,
+                                                format!(
+                                                    "Failed the TLS handshake with {}",
+                                                    turn_server_addr
+                                                ),
+                                                err,
+                                            );
+                                            return Ok(());
+                                        }
+                                    };
+                                let loc_conn: Arc<dyn Conn + Send + Sync> = Arc::new(
+                                    StunConn::from_stream(tls_stream, local_addr, remote_addr),
+                                );
+                                (loc_conn, rel_addr, rel_port, TLS.to_owned())
+                            }
+                        }
+                        ProtoType::Unknown => {
+                            errors2.report(
+                                &url,
+                                format!("Unknown transport to {}", turn_server_addr),
+                                IceError::ProtoType,
+                            );
+                            return Ok(());
+                        }
                     };
 
-                let cfg = turn::client::ClientConfig {
-                    stun_serv_addr: String::new(),
-                    turn_serv_addr: turn_server_addr.clone(),
-                    username: url.username,
-                    password: url.password,
-                    realm: String::new(),
-                    software: String::new(),
-                    rto_in_ms: 0,
-                    conn: loc_conn,
-                    vnet: Some(Arc::clone(&net2)),
-                };
-                let client = match turn::client::Client::new(cfg).await {
-                    Ok(client) => Arc::new(client),
-                    Err(err) => {
-                        log::warn!(
-                            "Failed to build new turn.Client {} {}\n",
-                            turn_server_addr,
-                            err
-                        );
-                        return Ok(());
-                    }
-                };
-                if let Err(err) = client.listen().await {
-                    let _ = client.close().await;
-                    log::warn!(
-                        "Failed to listen on turn.Client {} {}",
-                        turn_server_addr,
-                        err
-                    );
-                    return Ok(());
-                }
+                    let (username, password) = match &credential_provider2 {
+                        Some(credential_provider) => {
+                            match credential_provider.credentials(&url).await {
+                                Ok(credentials) => credentials,
+                                Err(err) => {
+                                    errors2.report(
+                                        &url,
+                                        format!(
+                                            "Failed to get the credentials of {}",
+                                            turn_server_addr
+                                        ),
+                                        err,
+                                    );
+                                    return Ok(());
+                                }
+                            }
+                        }
+                        None => (url.username.clone(), url.password.clone()),
+                    };
 
-                let relay_conn = match client.allocate().await {
-                    Ok(conn) => conn,
-                    Err(err) => {
+                    let allocation = Arc::new(AllocationConn::new(
+                        loc_conn,
+                        password.clone(),
+                        turn_allocation_lifetime,
+                    ));
+                    let cfg = turn::client::ClientConfig {
+                        stun_serv_addr: String::new(),
+                        turn_serv_addr: turn_server_addr.clone(),
+                        username,
+                        password,
+                        realm: String::new(),
+                        software: String::new(),
+                        rto_in_ms: 0,
+                        conn: Arc::clone(&allocation) as Arc<dyn Conn + Send + Sync>,
+                        vnet: Some(Arc::clone(&net2)),
+                    };
+                    let client = match turn::client::Client::new(cfg).await {
+                        Ok(client) => Arc::new(client),
+                        Err(err) => {
+                            errors2.report(
+                                &url,
+                                format!("Failed to build new turn.Client {}", turn_server_addr),
+                                IceError::from_turn(err),
+                            );
+                            return Ok(());
+                        }
+                    };
+                    if let Err(err) = client.listen().await {
                         let _ = client.close().await;
-                        log::warn!(
-                            "Failed to allocate on turn.Client {} {}",
-                            turn_server_addr,
-                            err
+                        errors2.report(
+                            &url,
+                            format!("Failed to listen on turn.Client {}", turn_server_addr),
+                            IceError::from_turn(err),
                         );
                         return Ok(());
                     }
+
+                    pacer2.acquire().await;
+                    match client.allocate().await {
+                        Ok(conn) => {
+                            break (client, allocation, conn, rel_addr, rel_port, relay_protocol)
+                        }
+                        Err(err) => {
+                            let _ = client.close().await;
+                            let err = IceError::from_turn(err);
+                            // Credentials fetched anew may have expired by the time they are used,
+                            // and the nonce may have gone stale between the two requests
+                            let retry = match stun_error_code(&err) {
+                                Some(UNAUTHORIZED) => credential_provider2.is_some(),
+                                Some(STALE_NONCE) => true,
+                                _ => false,
+                            };
+                            if retry && !retried {
+                                log::debug!(
+                                    "allocation on {} was refused with {}, trying again",
+                                    turn_server_addr,
+                                    err
+                                );
+                                retried = true;
+                                continue;
+                            }
+
+                            errors2.report(
+                                &url,
+                                format!("Failed to allocate on turn.Client {}", turn_server_addr),
+                                err,
+                            );
+                            return Ok(());
+                        }
+                    }
                 };
 
                 let raddr = relay_conn.local_addr().await?;
+                if !is_ip_allowed(&*ip_filter2, raddr.ip()) {
+                    allocation.close().await;
+                    let _ = client.close().await;
+                    log::debug!("relay address {} is filtered out", raddr);
+                    return Ok(());
+                }
+
                 let relay_config = CandidateRelayConfig {
                     base_config: CandidateBaseConfig {
                         network: network.clone(),
                         address: raddr.ip().to_string(),
                         port: raddr.port(),
-                        component: COMPONENT_RTP,
+                        stream,
+                        component,
                         conn: Some(Arc::new(relay_conn)),
                         ..CandidateBaseConfig::default()
                     },
                     rel_addr,
                     rel_port,
+                    relay_protocol,
                     relay_client: Some(Arc::clone(&client)),
                 };
 
@@ -679,36 +2164,267 @@ impl Agent {
                     .new_candidate_relay(Some(agent_internal2.clone()))
                     .await
                 {
-                    Ok(candidate) => Arc::new(candidate),
+                    Ok(mut candidate) => {
+                        candidate.relay_allocation = Some(Arc::clone(&allocation));
+                        Arc::new(candidate)
+                    }
                     Err(err) => {
+                        allocation.close().await;
                         let _ = client.close().await;
-                        log::warn!(
-                            "Failed to create relay candidate: {} {}: {}",
-                            network,
-                            raddr,
-                            err
+                        warn_event!(
+                            agent = agent_id2,
+                            network = network,
+                            relayed = raddr,
+                            error = err;
+                            "Failed to create relay candidate"
                         );
                         return Ok(());
                     }
                 };
 
-                {
-                    let mut ai = agent_internal2.lock().await;
-                    if let Err(err) = ai.add_candidate(&candidate).await {
-                        if let Err(close_err) = candidate.close().await {
-                            log::warn!("Failed to close candidate: {}", close_err);
-                        }
-                        log::warn!(
-                            "Failed to append to localCandidates and run onCandidateHdlr: {}",
-                            err
-                        );
-                    }
+                if !Self::add_gathered_candidate(&agent_id2, &agent_internal2, &candidate).await {
+                    return Ok(());
                 }
+                let events_tx = {
+                    let ai = agent_internal2.lock().await;
+                    ai.events_tx.clone()
+                };
 
-                Ok::<(), Error>(())
+                // Watches the refreshes until the candidate is closed, outliving gathering
+                let candidate_id = candidate.id();
+                tokio::spawn(allocation.watch_refreshes(move |error, expires_in| {
+                    let _ = events_tx.send(AgentEvent::RelayRefreshFailed {
+                        candidate_id: candidate_id.clone(),
+                        expires_in,
+                        error,
+                    });
+                }));
+
+                Ok::<(), IceError>(())
             });
         }
 
         wg.wait().await;
     }
+
+    /// Gathers a relay candidate on each of the relayed transports of the relay provider.
+    pub(crate) async fn gather_candidates_relay_provider(
+        relay_provider: Arc<dyn RelayProvider + Send + Sync>,
+        ip_filter: Arc<Option<IpFilterFn>>,
+        agent_internal: Arc<Mutex<AgentInternal>>,
+        stream: u16,
+        component: u16,
+    ) {
+        let agent_id = agent_internal.lock().await.agent_id.clone();
+        let relays = match relay_provider.relays(stream, component).await {
+            Ok(relays) => relays,
+            Err(err) => {
+                warn_event!(
+                    agent = agent_id,
+                    error = err;
+                    "Failed to get relays from the relay provider"
+                );
+                return;
+            }
+        };
+
+        for relay in relays {
+            let raddr = match relay.conn.local_addr().await {
+                Ok(raddr) => raddr,
+                Err(err) => {
+                    warn_event!(
+                        agent = agent_id,
+                        error = err;
+                        "Failed to get the relayed address of a relay"
+                    );
+                    continue;
+                }
+            };
+            if !is_ip_allowed(&*ip_filter, raddr.ip()) {
+                log::debug!("relay address {} is filtered out", raddr);
+                continue;
+            }
+
+            let relay_config = CandidateRelayConfig {
+                base_config: CandidateBaseConfig {
+                    network: UDP.to_owned(),
+                    address: raddr.ip().to_string(),
+                    port: raddr.port(),
+                    stream,
+                    component,
+                    conn: Some(relay.conn),
+                    ..CandidateBaseConfig::default()
+                },
+                rel_addr: relay.rel_addr.ip().to_string(),
+                rel_port: relay.rel_addr.port(),
+                relay_protocol: relay.relay_protocol,
+                relay_client: None,
+            };
+
+            let candidate: Arc<dyn Candidate + Send + Sync> = match relay_config
+                .new_candidate_relay(Some(Arc::clone(&agent_internal)))
+                .await
+            {
+                Ok(candidate) => Arc::new(candidate),
+                Err(err) => {
+                    warn_event!(
+                        agent = agent_id,
+                        relayed = raddr,
+                        error = err;
+                        "Failed to create relay candidate"
+                    );
+                    continue;
+                }
+            };
+
+            Self::add_gathered_candidate(&agent_id, &agent_internal, &candidate).await;
+        }
+    }
+
+    /// Adds a gathered candidate to the local candidates of the agent, closing it if it can't
+    /// be added. Returns whether it was added.
+    async fn add_gathered_candidate(
+        agent_id: &str,
+        agent_internal: &Arc<Mutex<AgentInternal>>,
+        candidate: &Arc<dyn Candidate + Send + Sync>,
+    ) -> bool {
+        let mut ai = agent_internal.lock().await;
+        if let Err(err) = ai.add_candidate(candidate).await {
+            if let Err(close_err) = candidate.close().await {
+                warn_event!(
+                    agent = agent_id,
+                    candidate = candidate,
+                    error = close_err;
+                    "Failed to close candidate"
+                );
+            }
+            warn_event!(
+                agent = agent_id,
+                candidate = candidate,
+                error = err;
+                "Failed to append to localCandidates and run onCandidateHdlr"
+            );
+            return false;
+        }
+        true
+    }
+
+    /// Returns the address advertised by the host candidates of the local address `ip`: its
+    /// additional host address if there is one, else its 1:1 NAT mapping unless mDNS hides it.
+    fn map_host_address(
+        agent_id: &str,
+        ip: IpAddr,
+        mdns_mode: MulticastDnsMode,
+        ext_ip_mapper: &Option<ExternalIpMapper>,
+        additional_addresses: &[AdditionalAddress],
+    ) -> IpAddr {
+        // An additional host address takes precedence over 1:1 NAT mapping
+        if let Some(a) = additional_addresses
+            .iter()
+            .find(|a| a.local_ip == ip && a.candidate_type == CandidateType::Host)
+        {
+            return a.ip;
+        }
+
+        if mdns_mode != MulticastDnsMode::QueryAndGather {
+            if let Some(ext_ip_mapper) = ext_ip_mapper {
+                if ext_ip_mapper.candidate_type == CandidateType::Host {
+                    if let Ok(mi) = ext_ip_mapper.find_external_ip(&ip.to_string()) {
+                        return mi;
+                    }
+                    warn_event!(
+                        agent = agent_id,
+                        ip = ip;
+                        "1:1 NAT mapping is enabled but no external IP is found"
+                    );
+                }
+            }
+        }
+
+        ip
+    }
+}
+
+/// Reports the errors which prevented a STUN or TURN server from being used during gathering,
+/// through `Agent::events` and the handler set with `Agent::on_candidate_error`.
+#[derive(Clone)]
+struct CandidateErrorReporter {
+    agent_id: String,
+    events_tx: broadcast::Sender<AgentEvent>,
+    chan_candidate_error_tx: Option<mpsc::UnboundedSender<CandidateError>>,
+}
+
+impl CandidateErrorReporter {
+    fn new(ai: &AgentInternal) -> Self {
+        Self {
+            agent_id: ai.agent_id.clone(),
+            events_tx: ai.events_tx.clone(),
+            chan_candidate_error_tx: ai.chan_candidate_error_tx.clone(),
+        }
+    }
+
+    fn report(&self, url: &Url, context: String, err: IceError) {
+        warn_event!(agent = self.agent_id, url = url, error = err; "{}", context);
+        self.send(CandidateError {
+            url: Some(url.clone()),
+            address: None,
+            error_code: stun_error_code(&err).unwrap_or(CANDIDATE_ERROR_CODE_UNREACHABLE),
+            error_text: format!("{}: {}", context, err),
+            error: err,
+        });
+    }
+
+    /// Reports that no socket could be bound for a host candidate on `ip`, e.g. as the port
+    /// range is exhausted, which the application may want to widen.
+    fn report_listen(&self, network: &str, ip: IpAddr, err: IceError) {
+        warn_event!(
+            agent = self.agent_id,
+            network = network,
+            ip = ip,
+            error = err;
+            "could not listen"
+        );
+        self.send(CandidateError {
+            url: None,
+            address: Some(ip),
+            error_code: CANDIDATE_ERROR_CODE_UNREACHABLE,
+            error_text: format!("could not listen {} {}: {}", network, ip, err),
+            error: err,
+        });
+    }
+
+    /// Reports that the port mapper could not map a port to `laddr`, e.g. as the gateway
+    /// supports neither NAT-PMP nor PCP.
+    fn report_port_mapping(&self, laddr: SocketAddr, err: IceError) {
+        warn_event!(
+            agent = self.agent_id,
+            local = laddr,
+            error = err;
+            "Failed to map a port"
+        );
+        self.send(CandidateError {
+            url: None,
+            address: Some(laddr.ip()),
+            error_code: CANDIDATE_ERROR_CODE_UNREACHABLE,
+            error_text: format!("could not map a port to {}: {}", laddr, err),
+            error: err,
+        });
+    }
+
+    fn send(&self, candidate_error: CandidateError) {
+        let _ = self
+            .events_tx
+            .send(AgentEvent::CandidateError(candidate_error.clone()));
+        if let Some(chan_candidate_error_tx) = &self.chan_candidate_error_tx {
+            let _ = chan_candidate_error_tx.send(candidate_error);
+        }
+    }
+}
+
+/// Returns the STUN error code of an error response of a TURN server.
+pub(crate) const fn stun_error_code(err: &IceError) -> Option<u16> {
+    match err {
+        IceError::TurnErrorResponse { code, .. } => Some(*code),
+        _ => None,
+    }
 }
@@ -1,13 +1,24 @@
+use super::agent_gather::stun_error_code;
 use super::agent_vnet_test::*;
 use super::*;
+use crate::port_mapping::{PortMapper, PortMapping, PortMappingSockets};
+use crate::proxy::ProxyDialer;
+use crate::relay_provider::{Relay, RelayProvider};
+use crate::socket_factory::TcpConnector;
+use crate::tls_dialer::{TlsDialer, TlsStream};
+use crate::turn_credentials::CredentialProvider;
+use crate::udp_mux::{UdpMuxDefault, UdpMuxParams};
 use crate::util::*;
 
+use async_trait::async_trait;
 use ipnet::IpNet;
+use std::net::IpAddr;
 use std::str::FromStr;
+use stun::{error_code::*, textattrs::*};
 use util::vnet::*;
 
 #[tokio::test]
-async fn test_vnet_gather_no_local_ip_address() -> Result<(), Error> {
+async fn test_vnet_gather_no_local_ip_address() -> Result<(), IceError> {
     let vnet = Arc::new(net::Net::new(Some(net::NetConfig::default())));
 
     let a = Agent::new(AgentConfig {
@@ -16,7 +27,15 @@ async fn test_vnet_gather_no_local_ip_address() -> Result<(), Error> {
     })
     .await?;
 
-    let local_ips = local_interfaces(&vnet, &a.interface_filter, &[NetworkType::Udp4]).await;
+    let local_ips = local_interfaces(
+        &vnet,
+        &a.interface_filter,
+        &[],
+        &a.ip_filter,
+        &[NetworkType::Udp4],
+        LocalAddressPolicy::default(),
+    )
+    .await;
     assert!(local_ips.is_empty(), "should return no local IP");
 
     a.close().await?;
@@ -25,7 +44,7 @@ async fn test_vnet_gather_no_local_ip_address() -> Result<(), Error> {
 }
 
 #[tokio::test]
-async fn test_vnet_gather_dynamic_ip_address() -> Result<(), Error> {
+async fn test_vnet_gather_dynamic_ip_address() -> Result<(), IceError> {
     let cider = "1.2.3.0/24";
     let ipnet = IpNet::from_str(cider)?;
 
@@ -42,7 +61,15 @@ async fn test_vnet_gather_dynamic_ip_address() -> Result<(), Error> {
     })
     .await?;
 
-    let local_ips = local_interfaces(&nw, &a.interface_filter, &[NetworkType::Udp4]).await;
+    let local_ips = local_interfaces(
+        &nw,
+        &a.interface_filter,
+        &[],
+        &a.ip_filter,
+        &[NetworkType::Udp4],
+        LocalAddressPolicy::default(),
+    )
+    .await;
     assert!(!local_ips.is_empty(), "should have one local IP");
 
     for ip in &local_ips {
@@ -60,7 +87,7 @@ async fn test_vnet_gather_dynamic_ip_address() -> Result<(), Error> {
 }
 
 #[tokio::test]
-async fn test_vnet_gather_listen_udp() -> Result<(), Error> {
+async fn test_vnet_gather_listen_udp() -> Result<(), IceError> {
     let cider = "1.2.3.0/24";
     let r = Arc::new(Mutex::new(router::Router::new(router::RouterConfig {
         cidr: cider.to_owned(),
@@ -75,20 +102,30 @@ async fn test_vnet_gather_listen_udp() -> Result<(), Error> {
     })
     .await?;
 
-    let local_ips = local_interfaces(&nw, &a.interface_filter, &[NetworkType::Udp4]).await;
+    let local_ips = local_interfaces(
+        &nw,
+        &a.interface_filter,
+        &[],
+        &a.ip_filter,
+        &[NetworkType::Udp4],
+        LocalAddressPolicy::default(),
+    )
+    .await;
     assert!(!local_ips.is_empty(), "should have one local IP");
 
     let ip = local_ips[0];
 
-    let _ = listen_udp_in_port_range(&nw, 0, 0, SocketAddr::new(ip, 0)).await?;
+    let _ = listen_udp_in_port_range(&nw, &a.socket_factory, 0, 0, SocketAddr::new(ip, 0)).await?;
 
-    let result = listen_udp_in_port_range(&nw, 4999, 5000, SocketAddr::new(ip, 0)).await;
+    let result =
+        listen_udp_in_port_range(&nw, &a.socket_factory, 4999, 5000, SocketAddr::new(ip, 0)).await;
     assert!(
         result.is_err(),
         "listenUDP with invalid port range did not return ErrPort"
     );
 
-    let conn = listen_udp_in_port_range(&nw, 5000, 5000, SocketAddr::new(ip, 0)).await?;
+    let conn = listen_udp_in_port_range(&nw, &a.socket_factory, 5000, 5000, SocketAddr::new(ip, 0))
+        .await?;
     let port = conn.local_addr().await?.port();
     assert_eq!(
         port, 5000,
@@ -96,13 +133,20 @@ async fn test_vnet_gather_listen_udp() -> Result<(), Error> {
         port
     );
 
+    let result =
+        listen_udp_in_port_range(&nw, &a.socket_factory, 5000, 5000, SocketAddr::new(ip, 0)).await;
+    assert!(
+        matches!(result, Err(IceError::PortRangeExhausted)),
+        "listenUDP in a range of busy ports should fail"
+    );
+
     a.close().await?;
 
     Ok(())
 }
 
 #[tokio::test]
-async fn test_vnet_gather_with_nat_1to1_as_host_candidates() -> Result<(), Error> {
+async fn test_vnet_gather_with_nat_1to1_as_host_candidates() -> Result<(), IceError> {
     let external_ip0 = "1.2.3.4";
     let external_ip1 = "1.2.3.5";
     let local_ip0 = "10.0.0.1";
@@ -226,7 +270,7 @@ async fn test_vnet_gather_with_nat_1to1_as_host_candidates() -> Result<(), Error
 }
 
 #[tokio::test]
-async fn test_vnet_gather_with_nat_1to1_as_srflx_candidates() -> Result<(), Error> {
+async fn test_vnet_gather_with_nat_1to1_as_srflx_candidates() -> Result<(), IceError> {
     let wan = Arc::new(Mutex::new(router::Router::new(router::RouterConfig {
         cidr: "1.2.3.0/24".to_owned(),
         ..Default::default()
@@ -312,7 +356,103 @@ async fn test_vnet_gather_with_nat_1to1_as_srflx_candidates() -> Result<(), Erro
 }
 
 #[tokio::test]
-async fn test_vnet_gather_with_interface_filter() -> Result<(), Error> {
+async fn test_vnet_gather_reports_each_candidate() -> Result<(), IceError> {
+    let lan = Arc::new(Mutex::new(router::Router::new(router::RouterConfig {
+        cidr: "10.0.0.0/24".to_owned(),
+        ..Default::default()
+    })?));
+    let nw = Arc::new(net::Net::new(Some(net::NetConfig {
+        static_ips: vec!["10.0.0.1".to_owned(), "10.0.0.2".to_owned()],
+        ..Default::default()
+    })));
+    connect_net2router(&nw, &lan).await?;
+
+    let a = Agent::new(AgentConfig {
+        network_types: vec![NetworkType::Udp4],
+        net: Some(Arc::clone(&nw)),
+        ..Default::default()
+    })
+    .await?;
+
+    // Candidates are handed over one by one, None signals the end of gathering
+    let (candidate_tx, mut candidate_rx) = mpsc::channel(8);
+    a.on_candidate(Box::new(
+        move |c: Option<Arc<dyn Candidate + Send + Sync>>| {
+            let candidate_tx_clone = candidate_tx.clone();
+            Box::pin(async move {
+                let _ = candidate_tx_clone.send(c.map(|c| c.address())).await;
+            })
+        },
+    ))
+    .await;
+
+    a.gather_candidates().await?;
+
+    let mut addresses = vec![];
+    while let Some(Some(address)) = candidate_rx.recv().await {
+        addresses.push(address);
+    }
+    addresses.sort();
+    assert_eq!(addresses, vec!["10.0.0.1", "10.0.0.2"], "should match");
+
+    let candidates = a.get_local_candidates().await?;
+    assert_eq!(candidates.len(), 2, "every candidate should be reported");
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_vnet_gather_reports_gathering_state() -> Result<(), IceError> {
+    let lan = Arc::new(Mutex::new(router::Router::new(router::RouterConfig {
+        cidr: "10.0.0.0/24".to_owned(),
+        ..Default::default()
+    })?));
+    let nw = Arc::new(net::Net::new(Some(net::NetConfig {
+        static_ips: vec!["10.0.0.1".to_owned()],
+        ..Default::default()
+    })));
+    connect_net2router(&nw, &lan).await?;
+
+    let a = Agent::new(AgentConfig {
+        network_types: vec![NetworkType::Udp4],
+        net: Some(Arc::clone(&nw)),
+        ..Default::default()
+    })
+    .await?;
+    assert_eq!(a.gathering_state(), GatheringState::New);
+
+    let (state_tx, mut state_rx) = mpsc::channel(8);
+    a.on_gathering_state_change(Box::new(move |s: GatheringState| {
+        let state_tx_clone = state_tx.clone();
+        Box::pin(async move {
+            let _ = state_tx_clone.send(s).await;
+        })
+    }))
+    .await;
+    a.on_candidate(Box::new(|_: Option<Arc<dyn Candidate + Send + Sync>>| {
+        Box::pin(async {})
+    }))
+    .await;
+
+    a.gather_candidates().await?;
+    assert_eq!(state_rx.recv().await, Some(GatheringState::Gathering));
+    assert_eq!(state_rx.recv().await, Some(GatheringState::Complete));
+    assert_eq!(a.gathering_state(), GatheringState::Complete);
+
+    // Restarting takes the gathering back to its initial state
+    a.restart(String::new(), String::new()).await?;
+    assert_eq!(state_rx.recv().await, Some(GatheringState::New));
+    assert_eq!(a.gathering_state(), GatheringState::New);
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_vnet_gather_with_interface_filter() -> Result<(), IceError> {
     let r = Arc::new(Mutex::new(router::Router::new(router::RouterConfig {
         cidr: "1.2.3.0/24".to_owned(),
         ..Default::default()
@@ -332,7 +472,15 @@ async fn test_vnet_gather_with_interface_filter() -> Result<(), Error> {
         })
         .await?;
 
-        let local_ips = local_interfaces(&nw, &a.interface_filter, &[NetworkType::Udp4]).await;
+        let local_ips = local_interfaces(
+            &nw,
+            &a.interface_filter,
+            &[],
+            &a.ip_filter,
+            &[NetworkType::Udp4],
+            LocalAddressPolicy::default(),
+        )
+        .await;
         assert!(
             local_ips.is_empty(),
             "InterfaceFilter should have excluded everything"
@@ -352,7 +500,15 @@ async fn test_vnet_gather_with_interface_filter() -> Result<(), Error> {
         })
         .await?;
 
-        let local_ips = local_interfaces(&nw, &a.interface_filter, &[NetworkType::Udp4]).await;
+        let local_ips = local_interfaces(
+            &nw,
+            &a.interface_filter,
+            &[],
+            &a.ip_filter,
+            &[NetworkType::Udp4],
+            LocalAddressPolicy::default(),
+        )
+        .await;
         assert_eq!(
             local_ips.len(),
             1,
@@ -366,7 +522,76 @@ async fn test_vnet_gather_with_interface_filter() -> Result<(), Error> {
 }
 
 #[tokio::test]
-async fn test_vnet_gather_turn_connection_leak() -> Result<(), Error> {
+async fn test_vnet_gather_with_ip_filter() -> Result<(), IceError> {
+    let r = Arc::new(Mutex::new(router::Router::new(router::RouterConfig {
+        cidr: "1.2.3.0/24".to_owned(),
+        ..Default::default()
+    })?));
+    let nw = Arc::new(net::Net::new(Some(net::NetConfig::default())));
+    connect_net2router(&nw, &r).await?;
+
+    //"IPFilter should exclude the IP"
+    {
+        let a = Agent::new(AgentConfig {
+            net: Some(Arc::clone(&nw)),
+            ip_filter: Arc::new(Some(Box::new(|ip: IpAddr| -> bool {
+                ip != IpAddr::from_str("1.2.3.1").unwrap()
+            }))),
+            ..Default::default()
+        })
+        .await?;
+
+        let local_ips = local_interfaces(
+            &nw,
+            &a.interface_filter,
+            &[],
+            &a.ip_filter,
+            &[NetworkType::Udp4],
+            LocalAddressPolicy::default(),
+        )
+        .await;
+        assert!(
+            local_ips.is_empty(),
+            "IPFilter should have excluded everything"
+        );
+
+        a.close().await?;
+    }
+
+    //"IPFilter should not exclude the IP"
+    {
+        let a = Agent::new(AgentConfig {
+            net: Some(Arc::clone(&nw)),
+            ip_filter: Arc::new(Some(Box::new(|ip: IpAddr| -> bool {
+                ip == IpAddr::from_str("1.2.3.1").unwrap()
+            }))),
+            ..Default::default()
+        })
+        .await?;
+
+        let local_ips = local_interfaces(
+            &nw,
+            &a.interface_filter,
+            &[],
+            &a.ip_filter,
+            &[NetworkType::Udp4],
+            LocalAddressPolicy::default(),
+        )
+        .await;
+        assert_eq!(
+            local_ips.len(),
+            1,
+            "IPFilter should not have excluded everything"
+        );
+
+        a.close().await?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_vnet_gather_turn_connection_leak() -> Result<(), IceError> {
     let turn_server_url = Url {
         scheme: SchemeType::Turn,
         host: VNET_STUN_SERVER_IP.to_owned(),
@@ -401,7 +626,17 @@ async fn test_vnet_gather_turn_connection_leak() -> Result<(), Error> {
         Agent::gather_candidates_relay(
             vec![turn_server_url.clone()],
             Arc::clone(&v.net0),
+            Arc::clone(&a_agent.socket_factory),
+            0,
+            0,
+            Arc::clone(&a_agent.ip_filter),
+            None,
+            None,
+            None,
+            None,
             agent_internal,
+            0,
+            COMPONENT_RTP,
         )
         .await;
     }
@@ -412,3 +647,1206 @@ async fn test_vnet_gather_turn_connection_leak() -> Result<(), Error> {
 
     Ok(())
 }
+
+/// Hands out the credentials of the test TURN server, counting the times it is asked.
+#[derive(Default)]
+struct CountingCredentialProvider {
+    calls: AtomicUsize,
+}
+
+#[async_trait]
+impl CredentialProvider for CountingCredentialProvider {
+    async fn credentials(&self, _url: &Url) -> Result<(String, String), IceError> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        Ok(("user".to_owned(), "pass".to_owned()))
+    }
+}
+
+#[tokio::test]
+async fn test_vnet_gather_relay_with_credential_provider() -> Result<(), IceError> {
+    let v = build_vnet(nat::NatType::default(), nat::NatType::default()).await?;
+
+    let credential_provider = Arc::new(CountingCredentialProvider::default());
+    let a = Agent::new(AgentConfig {
+        network_types: supported_network_types(),
+        multicast_dns_mode: MulticastDnsMode::Disabled,
+        net: Some(Arc::clone(&v.net0)),
+        ..Default::default()
+    })
+    .await?;
+
+    // The URL has no credentials, they are fetched when the relay is allocated
+    let turn_server_url = Url {
+        scheme: SchemeType::Turn,
+        host: VNET_STUN_SERVER_IP.to_owned(),
+        port: VNET_STUN_SERVER_PORT,
+        username: String::new(),
+        password: String::new(),
+        proto: ProtoType::Udp,
+    };
+    Agent::gather_candidates_relay(
+        vec![turn_server_url],
+        Arc::clone(&v.net0),
+        Arc::clone(&a.socket_factory),
+        0,
+        0,
+        Arc::clone(&a.ip_filter),
+        None,
+        None,
+        None,
+        Some(credential_provider.clone()),
+        Arc::clone(&a.agent_internal),
+        0,
+        COMPONENT_RTP,
+    )
+    .await;
+
+    assert_eq!(credential_provider.calls.load(Ordering::SeqCst), 1);
+    let local_candidates = a.get_local_candidates().await?;
+    assert_eq!(local_candidates.len(), 1);
+    assert_eq!(local_candidates[0].candidate_type(), CandidateType::Relay);
+
+    a.close().await?;
+    v.close().await?;
+
+    Ok(())
+}
+
+/// Spawns a TURN server whose nonces are always stale: it answers the allocate requests without
+/// credentials with a 401 and a nonce, and the authenticated ones with a 438, which it counts.
+async fn spawn_stale_nonce_turn_server() -> Result<(SocketAddr, Arc<AtomicUsize>), IceError> {
+    let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+    let server_addr = server.local_addr()?;
+    let stale = Arc::new(AtomicUsize::new(0));
+    let stale2 = Arc::clone(&stale);
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 1500];
+        while let Ok((n, src_addr)) = server.recv_from(&mut buf).await {
+            let mut request = Message::new();
+            request.raw = buf[..n].to_vec();
+            if request.decode().is_err() || request.typ.method != METHOD_ALLOCATE {
+                continue;
+            }
+
+            let mut response = Message::new();
+            let result = if request.contains(ATTR_NONCE) {
+                stale2.fetch_add(1, Ordering::SeqCst);
+                response.build(&[
+                    Box::new(request.transaction_id),
+                    Box::new(MessageType::new(METHOD_ALLOCATE, CLASS_ERROR_RESPONSE)),
+                    Box::new(CODE_STALE_NONCE),
+                    Box::new(Nonce::new(ATTR_NONCE, "fresh".to_owned())),
+                ])
+            } else {
+                response.build(&[
+                    Box::new(request.transaction_id),
+                    Box::new(MessageType::new(METHOD_ALLOCATE, CLASS_ERROR_RESPONSE)),
+                    Box::new(CODE_UNAUTHORIZED),
+                    Box::new(Realm::new(ATTR_REALM, "webrtc.rs".to_owned())),
+                    Box::new(Nonce::new(ATTR_NONCE, "nonce".to_owned())),
+                ])
+            };
+            if result.is_ok() {
+                let _ = server.send_to(&response.raw, src_addr).await;
+            }
+        }
+    });
+
+    Ok((server_addr, stale))
+}
+
+#[tokio::test]
+async fn test_gather_relay_retries_stale_nonce() -> Result<(), IceError> {
+    let (server_addr, stale) = spawn_stale_nonce_turn_server().await?;
+
+    let a = Agent::new(AgentConfig {
+        network_types: vec![NetworkType::Udp4],
+        multicast_dns_mode: MulticastDnsMode::Disabled,
+        ..Default::default()
+    })
+    .await?;
+    let mut events = a.events();
+
+    let turn_server_url = Url {
+        scheme: SchemeType::Turn,
+        host: server_addr.ip().to_string(),
+        port: server_addr.port(),
+        username: "user".to_owned(),
+        password: "pass".to_owned(),
+        proto: ProtoType::Udp,
+    };
+    Agent::gather_candidates_relay(
+        vec![turn_server_url],
+        Arc::clone(&a.net),
+        Arc::clone(&a.socket_factory),
+        0,
+        0,
+        Arc::clone(&a.ip_filter),
+        None,
+        None,
+        None,
+        None,
+        Arc::clone(&a.agent_internal),
+        0,
+        COMPONENT_RTP,
+    )
+    .await;
+
+    // The allocation is tried again once with a new nonce, then given up on
+    assert_eq!(stale.load(Ordering::SeqCst), 2);
+    let mut errors = vec![];
+    while let Ok(event) = events.try_recv() {
+        if let AgentEvent::CandidateError(err) = event {
+            errors.push(err);
+        }
+    }
+    assert_eq!(errors.len(), 1, "the failure should be reported once");
+    assert_eq!(errors[0].error_code, 438);
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_vnet_gather_relay_refreshes_allocation() -> Result<(), IceError> {
+    let v = build_vnet(nat::NatType::default(), nat::NatType::default()).await?;
+
+    let lifetime = Duration::from_secs(2);
+    let a = Agent::new(AgentConfig {
+        network_types: supported_network_types(),
+        multicast_dns_mode: MulticastDnsMode::Disabled,
+        net: Some(Arc::clone(&v.net0)),
+        turn_allocation_lifetime: Some(lifetime),
+        ..Default::default()
+    })
+    .await?;
+    let mut events = a.events();
+
+    let turn_server_url = Url {
+        scheme: SchemeType::Turn,
+        host: VNET_STUN_SERVER_IP.to_owned(),
+        port: VNET_STUN_SERVER_PORT,
+        username: "user".to_owned(),
+        password: "pass".to_owned(),
+        proto: ProtoType::Udp,
+    };
+    Agent::gather_candidates_relay(
+        vec![turn_server_url],
+        Arc::clone(&v.net0),
+        Arc::clone(&a.socket_factory),
+        0,
+        0,
+        Arc::clone(&a.ip_filter),
+        None,
+        None,
+        None,
+        None,
+        Arc::clone(&a.agent_internal),
+        0,
+        COMPONENT_RTP,
+    )
+    .await;
+
+    let local_candidates = a.get_local_candidates().await?;
+    assert_eq!(local_candidates.len(), 1);
+    let relay = Arc::clone(&local_candidates[0]);
+    assert_eq!(relay.candidate_type(), CandidateType::Relay);
+    let relay_lifetime = relay
+        .relay_lifetime()
+        .expect("the allocation should be granted");
+    assert!(
+        relay_lifetime <= lifetime.mul_f64(1.2),
+        "{:?}",
+        relay_lifetime
+    );
+
+    // Outlives the first lifetime, the allocation being refreshed in between by the client
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    assert!(relay.relay_lifetime().is_some());
+
+    // Without the server, the allocation expires as the next refresh goes unanswered
+    v.server.close()?;
+    let (candidate_id, error) = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let Ok(AgentEvent::RelayRefreshFailed {
+                candidate_id,
+                error,
+                ..
+            }) = events.recv().await
+            {
+                return (candidate_id, error);
+            }
+        }
+    })
+    .await
+    .expect("the failed refresh should be reported");
+    assert_eq!(candidate_id, relay.id());
+    assert!(matches!(error, IceError::Timeout(_)), "{}", error);
+
+    a.close().await?;
+    let mut w = v.wan.lock().await;
+    w.stop().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_turn_allocation_lifetime_of_zero_is_rejected() {
+    let result = Agent::new(AgentConfig {
+        turn_allocation_lifetime: Some(Duration::from_secs(0)),
+        ..Default::default()
+    })
+    .await;
+    assert!(matches!(
+        result,
+        Err(IceError::InvalidTurnAllocationLifetime)
+    ));
+}
+
+/// Hands out a UDP socket of the host as a relayed transport.
+struct LoopbackRelayProvider;
+
+#[async_trait]
+impl RelayProvider for LoopbackRelayProvider {
+    async fn relays(&self, _stream: u16, _component: u16) -> Result<Vec<Relay>, IceError> {
+        Ok(vec![Relay {
+            conn: Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await?),
+            rel_addr: "192.168.0.1:3478".parse()?,
+            relay_protocol: UDP.to_owned(),
+        }])
+    }
+}
+
+#[tokio::test]
+async fn test_gather_relay_provider() -> Result<(), IceError> {
+    let a = Agent::new(AgentConfig::default()).await?;
+
+    Agent::gather_candidates_relay_provider(
+        Arc::new(LoopbackRelayProvider),
+        Arc::clone(&a.ip_filter),
+        Arc::clone(&a.agent_internal),
+        0,
+        COMPONENT_RTP,
+    )
+    .await;
+
+    let local_candidates = a.get_local_candidates().await?;
+    assert_eq!(local_candidates.len(), 1);
+    let relay = &local_candidates[0];
+    assert_eq!(relay.candidate_type(), CandidateType::Relay);
+    assert_eq!(relay.address(), "127.0.0.1");
+    assert_eq!(
+        relay.related_address(),
+        Some(CandidateRelatedAddress {
+            address: "192.168.0.1".to_owned(),
+            port: 3478,
+        })
+    );
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_vnet_agent_stays_off_host_network() -> Result<(), IceError> {
+    let v = build_vnet(nat::NatType::default(), nat::NatType::default()).await?;
+
+    // mDNS would bind the host network, so it is not opened on a vnet
+    let a = Agent::new(AgentConfig {
+        network_types: supported_network_types(),
+        multicast_dns_mode: MulticastDnsMode::QueryOnly,
+        net: Some(Arc::clone(&v.net0)),
+        ..Default::default()
+    })
+    .await?;
+    assert!(a.mdns_conn.is_none(), "mDNS should not be opened on a vnet");
+
+    // TURN over TCP would connect over the host network, so it is skipped on a vnet
+    let turn_tcp_url = Url {
+        scheme: SchemeType::Turn,
+        host: VNET_STUN_SERVER_IP.to_owned(),
+        port: VNET_STUN_SERVER_PORT,
+        username: "user".to_owned(),
+        password: "pass".to_owned(),
+        proto: ProtoType::Tcp,
+    };
+    Agent::gather_candidates_relay(
+        vec![turn_tcp_url],
+        Arc::clone(&v.net0),
+        Arc::clone(&a.socket_factory),
+        0,
+        0,
+        Arc::clone(&a.ip_filter),
+        None,
+        None,
+        None,
+        None,
+        Arc::clone(&a.agent_internal),
+        0,
+        COMPONENT_RTP,
+    )
+    .await;
+    assert!(
+        a.get_local_candidates().await?.is_empty(),
+        "no relay candidate should be gathered"
+    );
+
+    a.close().await?;
+    v.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_gather_stun_timeout_per_server() -> Result<(), IceError> {
+    let silent0 = SilentStunServer::new().await?;
+    let silent1 = SilentStunServer::new().await?;
+    let mut ports = vec![silent0.port()?, silent1.port()?];
+
+    let a = Agent::new(AgentConfig {
+        network_types: vec![NetworkType::Udp4],
+        candidate_types: vec![CandidateType::ServerReflexive],
+        urls: vec![silent0.url()?, silent1.url()?],
+        stun_gather_timeout: Some(Duration::from_millis(200)),
+        ..Default::default()
+    })
+    .await?;
+
+    let (errors_tx, mut errors_rx) = mpsc::unbounded_channel();
+    a.on_candidate_error(Box::new(move |err: CandidateError| {
+        assert_eq!(err.error_code, CANDIDATE_ERROR_CODE_UNREACHABLE);
+        let _ = errors_tx.send(err.url.expect("the server should be reported").port);
+        Box::pin(async {})
+    }))
+    .await;
+
+    let mut events = a.events();
+    let start = Instant::now();
+    a.gather_candidates().await?;
+    wait_for_gathering_complete(&mut events).await;
+    assert!(
+        start.elapsed() < Duration::from_secs(2),
+        "the servers should time out on their own, concurrently"
+    );
+    assert!(a.get_local_candidates().await?.is_empty());
+
+    let mut failed = vec![];
+    for _ in 0..ports.len() {
+        let port = tokio::time::timeout(Duration::from_secs(1), errors_rx.recv())
+            .await
+            .expect("every server should be reported");
+        failed.extend(port);
+    }
+    failed.sort_unstable();
+    ports.sort_unstable();
+    assert_eq!(failed, ports, "should report each server");
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_gather_reports_listen_errors() -> Result<(), IceError> {
+    // The only port of the range is taken
+    let busy = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+    let port = busy.local_addr()?.port();
+
+    let a = Agent::new(AgentConfig {
+        network_types: vec![NetworkType::Udp4],
+        candidate_types: vec![CandidateType::Host],
+        include_loopback: true,
+        ip_filter: Arc::new(Some(Box::new(|ip: IpAddr| ip.is_loopback()))),
+        port_min: port,
+        port_max: port,
+        ..Default::default()
+    })
+    .await?;
+
+    let (errors_tx, mut errors_rx) = mpsc::unbounded_channel();
+    a.on_candidate_error(Box::new(move |err: CandidateError| {
+        let _ = errors_tx.send(err);
+        Box::pin(async {})
+    }))
+    .await;
+
+    let mut events = a.events();
+    a.gather_candidates().await?;
+    let reported = wait_for_gathering_complete(&mut events)
+        .await
+        .into_iter()
+        .any(|event| matches!(event, AgentEvent::CandidateError(_)));
+    assert!(reported, "the error should be an event as well");
+
+    let err = tokio::time::timeout(Duration::from_secs(1), errors_rx.recv())
+        .await
+        .expect("the bind failure should be reported")
+        .unwrap();
+    assert!(err.url.is_none());
+    assert_eq!(err.address, Some(IpAddr::from([127, 0, 0, 1])));
+    assert_eq!(err.error_code, CANDIDATE_ERROR_CODE_UNREACHABLE);
+    assert!(matches!(err.error, IceError::PortRangeExhausted));
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cancel_gathering() -> Result<(), IceError> {
+    let silent = SilentStunServer::new().await?;
+
+    let a = Agent::new(AgentConfig {
+        network_types: vec![NetworkType::Udp4],
+        candidate_types: vec![CandidateType::ServerReflexive],
+        urls: vec![silent.url()?],
+        stun_gather_timeout: Some(Duration::from_secs(30)),
+        ..Default::default()
+    })
+    .await?;
+
+    let mut events = a.events();
+    a.gather_candidates().await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(a.gathering_state(), GatheringState::Gathering);
+
+    a.cancel_gathering();
+    tokio::time::timeout(
+        Duration::from_secs(1),
+        wait_for_gathering_complete(&mut events),
+    )
+    .await
+    .expect("gathering should complete once cancelled");
+    assert!(a.get_local_candidates().await?.is_empty());
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[test]
+fn test_stun_error_code() {
+    let tests = vec![
+        (
+            IceError::from_turn(util::Error::new(
+                "Allocate error response (error 401: Unauthorized)".to_owned(),
+            )),
+            Some(401),
+        ),
+        (
+            IceError::from_turn(util::Error::new(
+                "Allocate error response (error 403: Forbidden)".to_owned(),
+            )),
+            Some(403),
+        ),
+        (
+            IceError::from_turn(util::Error::new("transaction timed out".to_owned())),
+            None,
+        ),
+        (IceError::UsernameEmpty, None),
+    ];
+
+    for (err, expected) in tests {
+        assert_eq!(stun_error_code(&err), expected, "{}", err);
+    }
+
+    match IceError::from_turn(util::Error::new(
+        "Allocate error response (error 401: Unauthorized)".to_owned(),
+    )) {
+        IceError::TurnErrorResponse { code, reason } => {
+            assert_eq!((code, reason.as_str()), (401, "Unauthorized"));
+        }
+        err => panic!("unexpected error {}", err),
+    }
+}
+
+#[tokio::test]
+async fn test_gather_concurrency() -> Result<(), IceError> {
+    // Gathered from one at a time
+    let silent0 = SilentStunServer::new().await?;
+    let silent1 = SilentStunServer::new().await?;
+
+    let timeout = Duration::from_millis(200);
+    let a = Agent::new(AgentConfig {
+        network_types: vec![NetworkType::Udp4],
+        candidate_types: vec![CandidateType::ServerReflexive],
+        urls: vec![silent0.url()?, silent1.url()?],
+        stun_gather_timeout: Some(timeout),
+        gather_concurrency: Some(1),
+        ..Default::default()
+    })
+    .await?;
+
+    let mut events = a.events();
+    let start = Instant::now();
+    a.gather_candidates().await?;
+    wait_for_gathering_complete(&mut events).await;
+    assert!(
+        start.elapsed() >= timeout * 2,
+        "the second server should wait for the first one to time out"
+    );
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_gather_events() -> Result<(), IceError> {
+    let v = build_vnet(nat::NatType::default(), nat::NatType::default()).await?;
+
+    // A TURN server without credentials can't be used, which is reported as a candidate error
+    let a = Agent::new(AgentConfig {
+        network_types: supported_network_types(),
+        candidate_types: vec![CandidateType::Host, CandidateType::Relay],
+        urls: vec![Url {
+            scheme: SchemeType::Turn,
+            host: VNET_STUN_SERVER_IP.to_owned(),
+            port: VNET_STUN_SERVER_PORT,
+            username: String::new(),
+            password: String::new(),
+            proto: ProtoType::Udp,
+        }],
+        net: Some(Arc::clone(&v.net0)),
+        ..Default::default()
+    })
+    .await?;
+
+    // Subscribing to the events is enough to gather without an on_candidate handler
+    let mut events = a.events();
+    a.gather_candidates().await?;
+
+    let (mut gathering, mut candidates, mut errors) = (false, 0, vec![]);
+    for event in wait_for_gathering_complete(&mut events).await {
+        match event {
+            AgentEvent::GatheringStateChange(GatheringState::Gathering) => gathering = true,
+            AgentEvent::Candidate(_) => candidates += 1,
+            AgentEvent::CandidateError(err) => errors.push(err.error),
+            _ => {}
+        }
+    }
+
+    assert!(gathering, "the gathering should have started");
+    assert_eq!(candidates, a.get_local_candidates().await?.len());
+    assert!(candidates > 0, "host candidates should be gathered");
+    assert!(matches!(errors.as_slice(), [IceError::UsernameEmpty]));
+
+    a.close().await?;
+    match events.recv().await {
+        Ok(AgentEvent::ConnectionStateChange(ConnectionState::Closed)) => {}
+        _ => panic!("closing should be reported"),
+    }
+
+    v.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_additional_addresses() -> Result<(), IceError> {
+    let v = build_vnet(nat::NatType::default(), nat::NatType::default()).await?;
+    let local_ip = IpAddr::from_str(VNET_LOCAL_IPA)?;
+
+    for (ip, local_ip, candidate_type) in [
+        ("27.1.1.2", "10.9.9.9", CandidateType::Host),
+        ("::1", VNET_LOCAL_IPA, CandidateType::Host),
+        ("27.1.1.2", VNET_LOCAL_IPA, CandidateType::Relay),
+    ] {
+        let result = Agent::new(AgentConfig {
+            additional_addresses: vec![AdditionalAddress {
+                ip: IpAddr::from_str(ip)?,
+                local_ip: IpAddr::from_str(local_ip)?,
+                candidate_type,
+            }],
+            net: Some(Arc::clone(&v.net0)),
+            ..Default::default()
+        })
+        .await;
+        if let Err(err) = result {
+            assert!(matches!(err, IceError::InvalidAdditionalAddress));
+        } else {
+            panic!("expected error for {} {} {}", ip, local_ip, candidate_type);
+        }
+    }
+
+    let a = Agent::new(AgentConfig {
+        network_types: vec![NetworkType::Udp4],
+        candidate_types: vec![CandidateType::Host],
+        additional_addresses: vec![
+            AdditionalAddress {
+                ip: IpAddr::from_str("27.1.1.2")?,
+                local_ip,
+                candidate_type: CandidateType::Host,
+            },
+            AdditionalAddress {
+                ip: IpAddr::from_str("27.1.1.3")?,
+                local_ip,
+                candidate_type: CandidateType::ServerReflexive,
+            },
+        ],
+        net: Some(Arc::clone(&v.net0)),
+        ..Default::default()
+    })
+    .await?;
+
+    let mut events = a.events();
+    a.gather_candidates().await?;
+    wait_for_gathering_complete(&mut events).await;
+
+    let candidates = a.get_local_candidates().await?;
+    assert_eq!(candidates.len(), 2);
+    let host = candidates
+        .iter()
+        .find(|c| c.candidate_type() == CandidateType::Host)
+        .expect("a host candidate should be gathered");
+    assert_eq!(host.address(), "27.1.1.2", "the host address is rewritten");
+    let srflx = candidates
+        .iter()
+        .find(|c| c.candidate_type() == CandidateType::ServerReflexive)
+        .expect("a srflx candidate should be added");
+    assert_eq!(srflx.address(), "27.1.1.3");
+    let related_address = srflx.related_address().unwrap();
+    assert_eq!(related_address.address, VNET_LOCAL_IPA);
+    assert_eq!(related_address.port, srflx.port());
+
+    a.close().await?;
+    v.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_gather_srflx_udp_mux() -> Result<(), IceError> {
+    let v = build_vnet(nat::NatType::default(), nat::NatType::default()).await?;
+
+    let conn = v
+        .net0
+        .bind(SocketAddr::from_str(&format!("{}:0", VNET_LOCAL_IPA))?)
+        .await?;
+    let mux_addr = conn.local_addr().await?;
+    let udp_mux = UdpMuxDefault::new(UdpMuxParams { conn });
+
+    let a = Agent::new(AgentConfig {
+        network_types: vec![NetworkType::Udp4],
+        candidate_types: vec![CandidateType::ServerReflexive],
+        urls: vec![Url {
+            scheme: SchemeType::Stun,
+            host: VNET_STUN_SERVER_IP.to_owned(),
+            port: VNET_STUN_SERVER_PORT,
+            username: String::new(),
+            password: String::new(),
+            proto: ProtoType::Udp,
+        }],
+        udp_mux_srflx: Some(Arc::clone(&udp_mux) as Arc<dyn UdpMuxSrflx + Send + Sync>),
+        net: Some(Arc::clone(&v.net0)),
+        ..Default::default()
+    })
+    .await?;
+
+    let mut events = a.events();
+    a.gather_candidates().await?;
+    for event in wait_for_gathering_complete(&mut events).await {
+        if let AgentEvent::CandidateError(err) = event {
+            panic!("gathering failed: {}", err.error);
+        }
+    }
+
+    let candidates = a.get_local_candidates().await?;
+    assert_eq!(candidates.len(), 1);
+    let srflx = &candidates[0];
+    assert_eq!(srflx.candidate_type(), CandidateType::ServerReflexive);
+    assert_eq!(srflx.address(), VNET_GLOBAL_IPA);
+    let related_address = srflx.related_address().unwrap();
+    assert_eq!(related_address.address, VNET_LOCAL_IPA);
+    assert_eq!(
+        related_address.port,
+        mux_addr.port(),
+        "should report the shared port"
+    );
+
+    a.close().await?;
+    udp_mux.close().await?;
+    v.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_gather_udp_mux_with_mdns() -> Result<(), IceError> {
+    let v = build_vnet(nat::NatType::default(), nat::NatType::default()).await?;
+
+    let conn = v
+        .net0
+        .bind(SocketAddr::from_str(&format!("{}:0", VNET_LOCAL_IPA))?)
+        .await?;
+    let udp_mux = UdpMuxDefault::new(UdpMuxParams { conn });
+
+    let a = Agent::new(AgentConfig {
+        network_types: vec![NetworkType::Udp4],
+        candidate_types: vec![CandidateType::Host],
+        multicast_dns_mode: MulticastDnsMode::QueryAndGather,
+        udp_mux: Some(Arc::clone(&udp_mux) as Arc<dyn UdpMux + Send + Sync>),
+        net: Some(Arc::clone(&v.net0)),
+        ..Default::default()
+    })
+    .await?;
+
+    let mut events = a.events();
+    a.gather_candidates().await?;
+    wait_for_gathering_complete(&mut events).await;
+
+    let candidates = a.get_local_candidates().await?;
+    assert_eq!(candidates.len(), 1);
+    assert_eq!(
+        candidates[0].address(),
+        a.mdns_name,
+        "the address of the interface should be hidden"
+    );
+
+    a.close().await?;
+    udp_mux.close().await?;
+    v.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_gather_follows_interface_changes() -> Result<(), IceError> {
+    let nw = Arc::new(net::Net::new(Some(net::NetConfig::default())));
+    let interface_watcher =
+        InterfaceWatcher::new(Some(Arc::clone(&nw)), Duration::from_millis(50)).await;
+
+    let a = Agent::new(AgentConfig {
+        network_types: vec![NetworkType::Udp4],
+        candidate_types: vec![CandidateType::Host],
+        interface_watcher: Some(Arc::clone(&interface_watcher)),
+        net: Some(Arc::clone(&nw)),
+        ..Default::default()
+    })
+    .await?;
+
+    // Without an address there is nothing to gather
+    let mut events = a.events();
+    a.gather_candidates().await?;
+    wait_for_gathering_complete(&mut events).await;
+    assert!(a.get_local_candidates().await?.is_empty());
+
+    let r = Arc::new(Mutex::new(router::Router::new(router::RouterConfig {
+        cidr: "1.2.3.0/24".to_owned(),
+        ..Default::default()
+    })?));
+    connect_net2router(&nw, &r).await?;
+
+    let candidate = loop {
+        match tokio::time::timeout(Duration::from_secs(5), events.recv()).await {
+            Ok(Ok(AgentEvent::Candidate(c))) => break c,
+            Ok(Ok(AgentEvent::GatheringStateChange(state))) => {
+                panic!("the gathering state should be left as it is, got {}", state)
+            }
+            Ok(Ok(_)) => {}
+            _ => panic!("the new address should be gathered"),
+        }
+    };
+    assert_eq!(candidate.candidate_type(), CandidateType::Host);
+    let ip = IpAddr::from_str(&candidate.address())?;
+    assert!(IpNet::from_str("1.2.3.0/24")?.contains(&ip));
+
+    // Neither the gathering state nor the end of candidates is signaled again
+    while let Ok(event) = tokio::time::timeout(Duration::from_millis(200), events.recv()).await {
+        if let Ok(AgentEvent::GatheringStateChange(state)) = event {
+            panic!("the gathering state should be left as it is, got {}", state);
+        }
+    }
+    assert_eq!(a.gathering_state(), GatheringState::Complete);
+
+    // vnet addresses can't be unassigned, the removal is reported the way the watcher does
+    let (changes_tx, changes_rx) = broadcast::channel(1);
+    a.watch_interfaces(changes_rx).await;
+    let _ = changes_tx.send(InterfaceChange {
+        added: vec![],
+        removed: vec![ip],
+    });
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !a.get_local_candidates().await?.is_empty() {
+        assert!(
+            Instant::now() < deadline,
+            "the candidate of a removed address should be dropped"
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    a.close().await?;
+    interface_watcher.close().await;
+
+    Ok(())
+}
+
+/// Maps each local address to the same port of a fixed public address, unless the gateway
+/// refuses, and counts the mappings deleted.
+#[derive(Default)]
+struct FixedPortMapper {
+    refused: bool,
+    unmapped: AtomicUsize,
+}
+
+#[async_trait]
+impl PortMapper for FixedPortMapper {
+    async fn map_udp(
+        &self,
+        _sockets: &PortMappingSockets,
+        local: SocketAddr,
+        lifetime: Duration,
+    ) -> Result<PortMapping, IceError> {
+        if self.refused {
+            return Err(IceError::PortMappingRefused(2));
+        }
+        Ok(PortMapping {
+            external: SocketAddr::new("203.0.113.7".parse()?, local.port()),
+            lifetime,
+        })
+    }
+
+    async fn unmap_udp(
+        &self,
+        _sockets: &PortMappingSockets,
+        _local: SocketAddr,
+    ) -> Result<(), IceError> {
+        self.unmapped.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_vnet_gather_port_mapped() -> Result<(), IceError> {
+    let lan = Arc::new(Mutex::new(router::Router::new(router::RouterConfig {
+        cidr: "10.0.0.0/24".to_owned(),
+        ..Default::default()
+    })?));
+    let nw = Arc::new(net::Net::new(Some(net::NetConfig {
+        static_ips: vec!["10.0.0.1".to_owned()],
+        ..Default::default()
+    })));
+    connect_net2router(&nw, &lan).await?;
+
+    let port_mapper = Arc::new(FixedPortMapper::default());
+    let a = Agent::new(AgentConfig {
+        network_types: vec![NetworkType::Udp4],
+        candidate_types: vec![CandidateType::ServerReflexive],
+        port_mapper: Some(Arc::clone(&port_mapper) as Arc<dyn PortMapper + Send + Sync>),
+        net: Some(Arc::clone(&nw)),
+        ..Default::default()
+    })
+    .await?;
+
+    let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
+    let done_tx = Arc::new(Mutex::new(Some(done_tx)));
+    a.on_candidate(Box::new(
+        move |c: Option<Arc<dyn Candidate + Send + Sync>>| {
+            let done_tx_clone = Arc::clone(&done_tx);
+            Box::pin(async move {
+                if c.is_none() {
+                    done_tx_clone.lock().await.take();
+                }
+            })
+        },
+    ))
+    .await;
+
+    a.gather_candidates().await?;
+    let _ = done_rx.recv().await;
+
+    let local_candidates = a.get_local_candidates().await?;
+    assert_eq!(local_candidates.len(), 1);
+    let srflx = &local_candidates[0];
+    assert_eq!(srflx.candidate_type(), CandidateType::ServerReflexive);
+    assert_eq!(srflx.address(), "203.0.113.7");
+    let related_address = srflx.related_address().unwrap();
+    assert_eq!(related_address.address, "10.0.0.1");
+    assert_eq!(related_address.port, srflx.port());
+
+    // The mapping is deleted along with its candidate
+    a.close().await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(port_mapper.unmapped.load(Ordering::SeqCst), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_vnet_gather_port_mapping_refused() -> Result<(), IceError> {
+    let lan = Arc::new(Mutex::new(router::Router::new(router::RouterConfig {
+        cidr: "10.0.0.0/24".to_owned(),
+        ..Default::default()
+    })?));
+    let nw = Arc::new(net::Net::new(Some(net::NetConfig {
+        static_ips: vec!["10.0.0.1".to_owned()],
+        ..Default::default()
+    })));
+    connect_net2router(&nw, &lan).await?;
+
+    let a = Agent::new(AgentConfig {
+        network_types: vec![NetworkType::Udp4],
+        candidate_types: vec![CandidateType::ServerReflexive],
+        port_mapper: Some(Arc::new(FixedPortMapper {
+            refused: true,
+            ..Default::default()
+        })),
+        net: Some(Arc::clone(&nw)),
+        ..Default::default()
+    })
+    .await?;
+
+    let mut events = a.events();
+    a.gather_candidates().await?;
+
+    let mut errors = vec![];
+    for event in wait_for_gathering_complete(&mut events).await {
+        if let AgentEvent::CandidateError(err) = event {
+            errors.push(err);
+        }
+    }
+    assert_eq!(errors.len(), 1, "the refused mapping should be reported");
+    assert!(errors[0].url.is_none());
+    assert_eq!(errors[0].address, Some("10.0.0.1".parse()?));
+    assert!(matches!(errors[0].error, IceError::PortMappingRefused(2)));
+    assert!(a.get_local_candidates().await?.is_empty());
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[tokio::test]
+async fn test_gather_wildcard_udp() -> Result<(), IceError> {
+    let a = Agent::new(AgentConfig {
+        network_types: vec![NetworkType::Udp4],
+        candidate_types: vec![CandidateType::Host],
+        bind_wildcard_udp: true,
+        ..Default::default()
+    })
+    .await?;
+
+    let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
+    let done_tx = Arc::new(Mutex::new(Some(done_tx)));
+    a.on_candidate(Box::new(
+        move |c: Option<Arc<dyn Candidate + Send + Sync>>| {
+            let done_tx_clone = Arc::clone(&done_tx);
+            Box::pin(async move {
+                if c.is_none() {
+                    done_tx_clone.lock().await.take();
+                }
+            })
+        },
+    ))
+    .await;
+
+    a.gather_candidates().await?;
+    let _ = done_rx.recv().await;
+
+    // The candidates of every address share the port of the wildcard socket, and report their
+    // own address as the local address of their connection
+    let local_candidates = a.get_local_candidates().await?;
+    for c in &local_candidates {
+        assert_eq!(c.port(), local_candidates[0].port());
+        let local_addr = c.get_conn().unwrap().local_addr().await?;
+        assert_eq!(local_addr.ip().to_string(), c.address());
+    }
+
+    a.close().await?;
+
+    Ok(())
+}
+
+/// Leaves the connections to TURN servers as they are, counting the handshakes it is asked for.
+#[derive(Default)]
+struct PassthroughTlsDialer {
+    handshakes: AtomicUsize,
+}
+
+#[async_trait]
+impl TlsDialer for PassthroughTlsDialer {
+    async fn connect_tls(
+        &self,
+        _server_name: &str,
+        stream: tokio::net::TcpStream,
+    ) -> Result<Box<dyn TlsStream>, IceError> {
+        self.handshakes.fetch_add(1, Ordering::SeqCst);
+        Ok(Box::new(stream))
+    }
+
+    async fn connect_dtls(
+        &self,
+        _server_name: &str,
+        conn: Arc<dyn Conn + Send + Sync>,
+    ) -> Result<Arc<dyn Conn + Send + Sync>, IceError> {
+        self.handshakes.fetch_add(1, Ordering::SeqCst);
+        Ok(conn)
+    }
+}
+
+#[tokio::test]
+async fn test_vnet_gather_relay_turns() -> Result<(), IceError> {
+    let turns_url = Url {
+        scheme: SchemeType::Turns,
+        host: VNET_STUN_SERVER_IP.to_owned(),
+        port: VNET_STUN_SERVER_PORT,
+        username: "user".to_owned(),
+        password: "pass".to_owned(),
+        proto: ProtoType::Udp,
+    };
+
+    // turns: URLs can't be used without a TLS dialer
+    let result = Agent::new(AgentConfig {
+        urls: vec![turns_url.clone()],
+        ..Default::default()
+    })
+    .await;
+    assert!(matches!(result, Err(IceError::TlsDialerRequired)));
+
+    let v = build_vnet(nat::NatType::default(), nat::NatType::default()).await?;
+    let tls_dialer = Arc::new(PassthroughTlsDialer::default());
+    let a = Agent::new(AgentConfig {
+        network_types: supported_network_types(),
+        multicast_dns_mode: MulticastDnsMode::Disabled,
+        net: Some(Arc::clone(&v.net0)),
+        ..Default::default()
+    })
+    .await?;
+
+    Agent::gather_candidates_relay(
+        vec![turns_url],
+        Arc::clone(&v.net0),
+        Arc::clone(&a.socket_factory),
+        0,
+        0,
+        Arc::clone(&a.ip_filter),
+        None,
+        None,
+        Some(tls_dialer.clone()),
+        None,
+        Arc::clone(&a.agent_internal),
+        0,
+        COMPONENT_RTP,
+    )
+    .await;
+
+    assert_eq!(tls_dialer.handshakes.load(Ordering::SeqCst), 1);
+    let local_candidates = a.get_local_candidates().await?;
+    assert_eq!(local_candidates.len(), 1);
+    assert_eq!(local_candidates[0].candidate_type(), CandidateType::Relay);
+    assert_eq!(local_candidates[0].relay_protocol(), DTLS);
+
+    a.close().await?;
+    v.close().await?;
+
+    Ok(())
+}
+
+/// Connects straight to the requested address, recording it.
+#[derive(Default)]
+struct RecordingProxyDialer {
+    dialed: std::sync::Mutex<Vec<String>>,
+}
+
+#[async_trait]
+impl ProxyDialer for RecordingProxyDialer {
+    async fn dial(
+        &self,
+        connector: &TcpConnector,
+        addr: &str,
+    ) -> Result<tokio::net::TcpStream, IceError> {
+        self.dialed.lock().unwrap().push(addr.to_owned());
+        connector.connect(addr).await
+    }
+}
+
+/// Fails every handshake, recording the names of the servers.
+#[derive(Default)]
+struct RejectingTlsDialer {
+    server_names: std::sync::Mutex<Vec<String>>,
+}
+
+#[async_trait]
+impl TlsDialer for RejectingTlsDialer {
+    async fn connect_tls(
+        &self,
+        server_name: &str,
+        _stream: tokio::net::TcpStream,
+    ) -> Result<Box<dyn TlsStream>, IceError> {
+        self.server_names
+            .lock()
+            .unwrap()
+            .push(server_name.to_owned());
+        Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "bad certificate").into())
+    }
+
+    async fn connect_dtls(
+        &self,
+        server_name: &str,
+        _conn: Arc<dyn Conn + Send + Sync>,
+    ) -> Result<Arc<dyn Conn + Send + Sync>, IceError> {
+        self.server_names
+            .lock()
+            .unwrap()
+            .push(server_name.to_owned());
+        Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "bad certificate").into())
+    }
+}
+
+#[tokio::test]
+async fn test_gather_relay_turns_through_proxy() -> Result<(), IceError> {
+    // The TURN server accepts the connection, the TLS handshake fails before anything is sent
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let turn_server_addr = listener.local_addr()?;
+    tokio::spawn(async move { while listener.accept().await.is_ok() {} });
+
+    let proxy_dialer = Arc::new(RecordingProxyDialer::default());
+    let tls_dialer = Arc::new(RejectingTlsDialer::default());
+    let a = Agent::new(AgentConfig {
+        network_types: vec![NetworkType::Udp4],
+        candidate_types: vec![CandidateType::Relay],
+        urls: vec![Url {
+            scheme: SchemeType::Turns,
+            host: "127.0.0.1".to_owned(),
+            port: turn_server_addr.port(),
+            username: "user".to_owned(),
+            password: "pass".to_owned(),
+            proto: ProtoType::Tcp,
+        }],
+        proxy_dialer: Some(proxy_dialer.clone()),
+        tls_dialer: Some(tls_dialer.clone()),
+        ..Default::default()
+    })
+    .await?;
+
+    let (errors_tx, mut errors_rx) = mpsc::unbounded_channel();
+    a.on_candidate_error(Box::new(move |err: CandidateError| {
+        let _ = errors_tx.send(err);
+        Box::pin(async {})
+    }))
+    .await;
+
+    let mut events = a.events();
+    a.gather_candidates().await?;
+    wait_for_gathering_complete(&mut events).await;
+
+    assert_eq!(
+        *proxy_dialer.dialed.lock().unwrap(),
+        vec![turn_server_addr.to_string()],
+        "the TLS connection should go through the proxy"
+    );
+    assert_eq!(*tls_dialer.server_names.lock().unwrap(), vec!["127.0.0.1"]);
+    let err = tokio::time::timeout(Duration::from_secs(1), errors_rx.recv())
+        .await
+        .expect("the failed handshake should be reported")
+        .expect("the error channel should be open");
+    assert_eq!(err.error_code, CANDIDATE_ERROR_CODE_UNREACHABLE);
+    assert!(a.get_local_candidates().await?.is_empty());
+
+    a.close().await?;
+
+    Ok(())
+}
@@ -1,9 +1,17 @@
+use super::agent_pacer::Pacer;
 use super::agent_transport::*;
 use super::*;
 use crate::candidate::candidate_base::{CandidateBase, CandidateBaseConfig};
 use crate::candidate::candidate_peer_reflexive::CandidatePeerReflexiveConfig;
+use crate::control::{AttrControlled, AttrControlling};
+use crate::metrics;
+use crate::priority::{CandidatePreferences, PriorityAttr};
 use crate::util::*;
 
+use rand::Rng;
+use std::net::IpAddr;
+use stun::error_code::*;
+
 pub type ChanCandidateTx = Option<Arc<mpsc::Sender<Option<Arc<dyn Candidate + Send + Sync>>>>>;
 
 pub struct AgentInternal {
@@ -16,28 +24,72 @@ pub struct AgentInternal {
     pub(crate) done_rx: Option<mpsc::Receiver<()>>,
 
     pub(crate) chan_candidate_tx: ChanCandidateTx,
-    pub(crate) chan_candidate_pair_tx: Option<mpsc::Sender<()>>,
-    pub(crate) chan_state_tx: Option<mpsc::Sender<ConnectionState>>,
+    pub(crate) chan_candidate_pair_tx: Option<mpsc::Sender<Arc<CandidatePair>>>,
+    pub(crate) chan_state_tx: Option<mpsc::UnboundedSender<ConnectionState>>,
+    pub(crate) chan_candidate_error_tx: Option<mpsc::UnboundedSender<CandidateError>>,
+    // The authenticated binding requests, handed over to the task of the handler set with
+    // `Agent::on_binding_request`, if any
+    pub(crate) chan_binding_request_tx: Option<mpsc::UnboundedSender<InboundBindingRequest>>,
 
     pub(crate) on_connection_state_change_hdlr: Option<OnConnectionStateChangeHdlrFn>,
     pub(crate) on_selected_candidate_pair_change_hdlr: Option<OnSelectedCandidatePairChangeHdlrFn>,
+    pub(crate) on_gathering_state_change_hdlr: Option<OnGatheringStateChangeHdlrFn>,
     pub(crate) on_candidate_hdlr: Option<OnCandidateHdlrFn>,
+    pub(crate) on_candidate_error_hdlr: Option<OnCandidateErrorHdlrFn>,
+
+    pub(crate) events_tx: broadcast::Sender<AgentEvent>,
 
     // force candidate to be contacted immediately (instead of waiting for task ticker)
     pub(crate) force_candidate_contact_tx: mpsc::Sender<bool>,
     pub(crate) force_candidate_contact_rx: Option<mpsc::Receiver<bool>>,
+
+    // The packets received by the candidates, handled by the inbound loop
+    pub(crate) inbound_tx: mpsc::Sender<InboundPacket>,
+    // Bumped when candidates or pairs are removed, the inbound loop then forgets its routes
+    pub(crate) routes_generation: Arc<AtomicU64>,
     pub(crate) tie_breaker: u64,
 
     pub(crate) is_controlling: bool,
+    // Whether the role was fixed by the config, by starting the checks or by a check of the
+    // remote agent, otherwise is_controlling is provisional
+    pub(crate) role_determined: bool,
+    pub(crate) nomination_strategy: NominationStrategy,
+    pub(crate) compatibility_mode: CompatibilityMode,
+    pub(crate) local_ice_options: IceOptions,
+    pub(crate) remote_ice_options: Option<IceOptions>,
     pub(crate) lite: bool,
+    pub(crate) prflx_priority: Arc<Option<PrflxPriorityFn>>,
+    pub(crate) candidate_priority: Arc<Option<CandidatePriorityFn>>,
+    pub(crate) binding_request_filter: Arc<Option<BindingRequestFilterFn>>,
     pub(crate) start_time: Instant,
-    pub(crate) nominated_pair: Option<Arc<CandidatePair>>,
+    // The pair being nominated by the controlling agent, for each component of each stream
+    pub(crate) nominated_pairs: HashMap<(u16, u16), Arc<CandidatePair>>,
+    // The DSCP of the packets of each component, set on the local candidate of its selected pair
+    pub(crate) dscp: HashMap<u16, u8>,
+    // When a pair was last selected, the local candidates outside the selected and backup pairs
+    // being released once it is older than release_unused_candidates_after
+    pub(crate) pair_selected_at: Instant,
+    pub(crate) release_unused_candidates_after: Option<Duration>,
+    pub(crate) backup_pairs: usize,
 
     pub(crate) connection_state: ConnectionState,
 
     pub(crate) started_ch_tx: Option<broadcast::Sender<()>>,
 
+    // Dropped on close to abort background work (e.g. mDNS queries) tied to the agent
+    pub(crate) closed_ch_tx: Option<broadcast::Sender<()>>,
+
     pub(crate) max_binding_requests: u16,
+    // How many times the role may still be switched on 487 responses, and has been
+    pub(crate) max_role_switches: u16,
+    pub(crate) role_switches: u16,
+    pub(crate) initial_rto: Duration,
+    pub(crate) rto_backoff: u32,
+    pub(crate) pacer: Arc<Pacer>,
+    pub(crate) stun_gather_timeout: Duration,
+    pub(crate) turn_allocation_lifetime: Duration,
+    // Bounds the number of STUN and TURN servers gathered from at once
+    pub(crate) gather_semaphore: Arc<Semaphore>,
 
     pub(crate) host_acceptance_min_wait: Duration,
     pub(crate) srflx_acceptance_min_wait: Duration,
@@ -55,10 +107,33 @@ pub struct AgentInternal {
     // How often should we send keepalive packets?
     // 0 means never
     pub(crate) keepalive_interval: Duration,
+    pub(crate) keepalive_strategy: KeepaliveStrategy,
+    pub(crate) keepalive_valid_pairs: bool,
+    // Selected pairs with this many unanswered requests in a row are replaced, 0 means never
+    pub(crate) failover_after_lost_checks: u16,
+
+    // How often should we refresh consent on the selected pair?
+    // 0 means never
+    pub(crate) consent_check_interval: Duration,
+
+    // How long can the selected pair go without a consent response before it fails
+    pub(crate) consent_timeout: Duration,
+
+    // When the selected pair was selected, when consent was last requested on it, and the
+    // randomized wait before the next consent request
+    pub(crate) consent_granted_at: Instant,
+    pub(crate) consent_requested_at: Instant,
+    pub(crate) next_consent_interval: Duration,
+
+    // When connectivity checks were paused, if they are, and how long they have been paused for
+    // since the checking timer of the connectivity checks loop was last pushed back
+    pub(crate) checks_paused_at: Option<Instant>,
+    pub(crate) checks_paused_for: Duration,
 
     // How often should we run our internal taskLoop to check for state changes when connecting
     pub(crate) check_interval: Duration,
 
+    pub(crate) agent_id: String,
     pub(crate) local_ufrag: String,
     pub(crate) local_pwd: String,
     pub(crate) local_candidates: HashMap<NetworkType, Vec<Arc<dyn Candidate + Send + Sync>>>,
@@ -67,12 +142,18 @@ pub struct AgentInternal {
     pub(crate) remote_pwd: String,
     pub(crate) remote_candidates: HashMap<NetworkType, Vec<Arc<dyn Candidate + Send + Sync>>>,
 
+    // Trickle ICE bookkeeping shared with the Agent
+    pub(crate) pending_remote_candidates: Arc<AtomicUsize>,
+    pub(crate) remote_end_of_candidates: Arc<AtomicBool>,
+
     // LRU of outbound Binding request Transaction IDs
     pub(crate) pending_binding_requests: Vec<BindingRequest>,
 
     pub(crate) insecure_skip_verify: bool,
 
-    pub(crate) agent_conn: Arc<AgentConn>,
+    // The connection of each component of each stream, the first one carrying COMPONENT_RTP
+    // of the first stream
+    pub(crate) agent_conns: Vec<Arc<AgentConn>>,
 }
 
 //TODO: remove unsafe
@@ -86,9 +167,9 @@ impl AgentInternal {
         is_controlling: bool,
         remote_ufrag: String,
         remote_pwd: String,
-    ) -> Result<(), Error> {
+    ) -> Result<(), IceError> {
         if self.started_ch_tx.is_none() {
-            return Err(ERR_MULTIPLE_START.to_owned());
+            return Err(IceError::MultipleStart);
         }
 
         log::debug!(
@@ -97,8 +178,13 @@ impl AgentInternal {
             remote_ufrag,
             remote_pwd
         );
-        self.set_remote_credentials(remote_ufrag, remote_pwd)?;
+        // The credentials may have been set already, or come later with set_remote_credentials
+        if !remote_ufrag.is_empty() || !remote_pwd.is_empty() {
+            self.set_remote_credentials(remote_ufrag, remote_pwd)
+                .await?;
+        }
         self.is_controlling = is_controlling;
+        self.role_determined = true;
         self.start();
         self.started_ch_tx.take();
 
@@ -118,6 +204,12 @@ impl AgentInternal {
         checking_duration: &mut Instant,
     ) {
         let mut ai = agent_internal.lock().await;
+        if ai.checks_paused_at.is_some() {
+            return;
+        }
+        // The time checks were paused for doesn't count towards the checking timeout
+        *checking_duration += std::mem::take(&mut ai.checks_paused_for);
+
         if ai.connection_state == ConnectionState::Failed {
             // The connection is currently failed so don't send any checks
             // In the future it may be restarted though
@@ -130,9 +222,11 @@ impl AgentInternal {
                 *checking_duration = Instant::now();
             }
 
-            // We have been in checking longer then Disconnect+Failed timeout, set the connection to Failed
+            // We have been in checking longer then Disconnect+Failed timeout, or the remote has
+            // finished trickling and every pair failed, set the connection to Failed
             if Instant::now().duration_since(*checking_duration)
                 > ai.disconnected_timeout + ai.failed_timeout
+                || ai.is_checklist_failed().await
             {
                 ai.update_connection_state(ConnectionState::Failed).await;
                 *last_connection_state = ai.connection_state;
@@ -141,6 +235,9 @@ impl AgentInternal {
         }
 
         ai.contact_candidates().await;
+        if ai.connection_state == ConnectionState::Connected {
+            ai.release_unused_candidates().await;
+        }
 
         *last_connection_state = ai.connection_state;
     }
@@ -149,8 +246,15 @@ impl AgentInternal {
         const ZERO_DURATION: Duration = Duration::from_secs(0);
         let mut last_connection_state = ConnectionState::Unspecified;
         let mut checking_duration = Instant::now();
-        let (check_interval, keepalive_interval, disconnected_timeout, failed_timeout) = (
+        let (
+            check_interval,
+            pacing_interval,
+            keepalive_interval,
+            disconnected_timeout,
+            failed_timeout,
+        ) = (
             self.check_interval,
+            self.pacer.interval(),
             self.keepalive_interval,
             self.disconnected_timeout,
             self.failed_timeout,
@@ -161,7 +265,9 @@ impl AgentInternal {
         {
             tokio::spawn(async move {
                 loop {
-                    let mut interval = DEFAULT_CHECK_INTERVAL;
+                    // Configured intervals above the keepalive default would otherwise be cut
+                    // down to the default check interval, so start from the larger one
+                    let mut interval = DEFAULT_KEEPALIVE_INTERVAL;
 
                     let mut update_interval = |x: Duration| {
                         if x != ZERO_DURATION && (interval == ZERO_DURATION || interval > x) {
@@ -171,8 +277,10 @@ impl AgentInternal {
 
                     match last_connection_state {
                         ConnectionState::New | ConnectionState::Checking => {
-                            // While connecting, check candidates more frequently
+                            // While connecting, check candidates more frequently, and at least
+                            // on every Ta to start the paced checks
                             update_interval(check_interval);
+                            update_interval(pacing_interval);
                         }
                         ConnectionState::Connected | ConnectionState::Disconnected => {
                             update_interval(keepalive_interval);
@@ -202,6 +310,10 @@ impl AgentInternal {
         }
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(agent = %self.agent_id, state = %new_state))
+    )]
     pub(crate) async fn update_connection_state(&mut self, new_state: ConnectionState) {
         if self.connection_state != new_state {
             // Connection has gone to failed, release all gathered candidates
@@ -211,42 +323,237 @@ impl AgentInternal {
 
             log::info!("Setting new connection state: {}", new_state);
             self.connection_state = new_state;
+            metrics::connection_state_changed(new_state);
 
             // Call handler after finishing current task since we may be holding the agent lock
-            // and the handler may also require it
+            // and the handler may also require it. The queue is unbounded so that the
+            // transitions are never dropped nor reordered while waiting for the handler.
             if let Some(chan_state_tx) = &self.chan_state_tx {
-                let _ = chan_state_tx.send(new_state).await;
+                let _ = chan_state_tx.send(new_state);
             }
+            let _ = self
+                .events_tx
+                .send(AgentEvent::ConnectionStateChange(new_state));
         }
     }
 
+    /// Moves the pair `p` to `state`, reporting the transition to the subscribers of the
+    /// events of the agent.
+    pub(crate) fn set_pair_state(&self, p: &Arc<CandidatePair>, state: CandidatePairState) {
+        if p.state.swap(state as u8, Ordering::SeqCst) != state as u8 {
+            self.report_pair_state(p);
+            if state == CandidatePairState::Succeeded && is_host_pair(p) {
+                if let Some(agent_conn) = self.agent_conn(p.stream(), p.component()) {
+                    agent_conn.host_pair_succeeded.store(true, Ordering::SeqCst);
+                }
+            }
+            if state == CandidatePairState::Succeeded && self.is_hairpin(p) {
+                log::info!(
+                    "agent {}: Candidate pair {} succeeded through NAT hairpinning",
+                    self.agent_id,
+                    p
+                );
+                p.hairpinned.store(true, Ordering::SeqCst);
+                let _ = self.events_tx.send(AgentEvent::Hairpin(Arc::clone(p)));
+            }
+            if state == CandidatePairState::Succeeded {
+                self.report_valid_pair(p);
+            }
+        }
+    }
+
+    /// Reports `p` to the subscribers of the events of the agent if it is the first pair of its
+    /// component whose checks succeeded both ways, i.e. which succeeded and was checked by the
+    /// remote agent.
+    pub(crate) fn report_valid_pair(&self, p: &Arc<CandidatePair>) {
+        if p.state() != CandidatePairState::Succeeded
+            || p.requests_received.load(Ordering::SeqCst) == 0
+        {
+            return;
+        }
+
+        if let Some(agent_conn) = self.agent_conn(p.stream(), p.component()) {
+            if !agent_conn.validated.swap(true, Ordering::SeqCst) {
+                log::debug!("agent {}: Candidate pair {} is valid", self.agent_id, p);
+                let _ = self
+                    .events_tx
+                    .send(AgentEvent::ValidCandidatePair(Arc::clone(p)));
+            }
+        }
+    }
+
+    /// Returns true if the remote candidate of `p` is a server reflexive candidate of one of the
+    /// public addresses of the agent, so that its checks went through the NAT both agents are
+    /// behind and back, while no pair of host candidates of its component succeeded.
+    fn is_hairpin(&self, p: &CandidatePair) -> bool {
+        if p.remote.candidate_type() != CandidateType::ServerReflexive {
+            return false;
+        }
+        if self
+            .agent_conn(p.stream(), p.component())
+            .map_or(false, |agent_conn| {
+                agent_conn.host_pair_succeeded.load(Ordering::SeqCst)
+            })
+        {
+            return false;
+        }
+
+        self.local_candidates
+            .get(&p.remote.network_type())
+            .map_or(false, |cands| {
+                cands.iter().any(|c| {
+                    c.candidate_type() == CandidateType::ServerReflexive
+                        && c.address() == p.remote.address()
+                })
+            })
+    }
+
+    /// Marks the pair `p` as nominated, reporting it like a state transition.
+    pub(crate) fn set_pair_nominated(&self, p: &CandidatePair) {
+        if !p.nominated.swap(true, Ordering::SeqCst) {
+            self.report_pair_state(p);
+        }
+    }
+
+    fn report_pair_state(&self, p: &CandidatePair) {
+        log::trace!("Candidate pair {} is now {}", p, p.state());
+        let _ = self.events_tx.send(AgentEvent::CandidatePairStateChange {
+            local_candidate_id: p.local.id(),
+            remote_candidate_id: p.remote.id(),
+            state: p.state(),
+            nominated: p.nominated(),
+        });
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(
+            agent = %self.agent_id,
+            local = ?p.as_ref().map(|p| p.local.id()),
+            remote = ?p.as_ref().map(|p| p.remote.id()),
+        ))
+    )]
     pub(crate) async fn set_selected_pair(&mut self, p: Option<Arc<CandidatePair>>) {
         log::trace!("Set selected candidate pair: {:?}", p);
 
         if let Some(p) = p {
-            p.nominated.store(true, Ordering::SeqCst);
+            let agent_conn = match self.agent_conn(p.stream(), p.component()) {
+                Some(agent_conn) => Arc::clone(agent_conn),
+                None => {
+                    warn_event!(
+                        agent = self.agent_id,
+                        pair = p;
+                        "Discarded selected pair of an unknown component"
+                    );
+                    return;
+                }
+            };
+
+            self.set_pair_nominated(&p);
+            self.pair_selected_at = Instant::now();
+            self.consent_granted_at = Instant::now();
+            self.consent_requested_at = Instant::now();
+            self.next_consent_interval = self.randomized_consent_interval();
             {
-                let mut selected_pair = self.agent_conn.selected_pair.lock().await;
-                *selected_pair = Some(p);
+                let mut selected_pair = agent_conn.selected_pair.lock().await;
+                *selected_pair = Some(Arc::clone(&p));
             }
+            self.mark_selected_pair(&p);
 
-            self.update_connection_state(ConnectionState::Connected)
-                .await;
+            // The agent is only connected once every component has selected a pair
+            let connected = self.is_every_component_selected().await;
+            if connected {
+                self.update_connection_state(ConnectionState::Connected)
+                    .await;
+            }
 
-            // Notify when the selected pair changes
+            // Notify when the selected pair changes, handing over the pair itself so the handler
+            // sees every change even if the pair is replaced again before it runs
+            let _ = self
+                .events_tx
+                .send(AgentEvent::SelectedCandidatePairChange(Arc::clone(&p)));
             if let Some(chan_candidate_pair_tx) = &self.chan_candidate_pair_tx {
-                let _ = chan_candidate_pair_tx.send(()).await;
+                let _ = chan_candidate_pair_tx.send(p).await;
             }
 
             // Signal connected
-            self.on_connected_tx.take();
+            if connected {
+                self.on_connected_tx.take();
+            }
         } else {
-            let mut selected_pair = self.agent_conn.selected_pair.lock().await;
-            *selected_pair = None;
+            for agent_conn in &self.agent_conns {
+                let mut selected_pair = agent_conn.selected_pair.lock().await;
+                *selected_pair = None;
+            }
+        }
+    }
+
+    /// Marks the packets sent on the selected pair `p` with the DSCP of its component, if any,
+    /// and records whether it could, see `CandidatePair::dscp_marked`.
+    pub(crate) fn mark_selected_pair(&self, p: &CandidatePair) {
+        if let Some(&dscp) = self.dscp.get(&p.component()) {
+            let result = p.local.set_tos(dscp << 2);
+            p.dscp_marked.store(result.is_ok(), Ordering::SeqCst);
+            if let Err(err) = result {
+                warn_event!(
+                    agent = self.agent_id,
+                    pair = p,
+                    dscp = dscp,
+                    error = err;
+                    "Failed to mark the packets with DSCP"
+                );
+            }
+        }
+    }
+
+    /// Returns the connection of `component` of `stream`, or none if the agent doesn't have that
+    /// component.
+    pub(crate) fn agent_conn(&self, stream: u16, component: u16) -> Option<&Arc<AgentConn>> {
+        self.agent_conns
+            .iter()
+            .find(|agent_conn| agent_conn.key() == (stream, component))
+    }
+
+    /// Returns the selected pair of `component` of `stream`, or none if it has not selected a
+    /// pair yet.
+    pub(crate) async fn get_selected_pair(
+        &self,
+        stream: u16,
+        component: u16,
+    ) -> Option<Arc<CandidatePair>> {
+        match self.agent_conn(stream, component) {
+            Some(agent_conn) => agent_conn.get_selected_pair().await,
+            None => None,
+        }
+    }
+
+    /// Returns the selected pairs of the components which have selected one.
+    pub(crate) async fn get_selected_pairs(&self) -> Vec<Arc<CandidatePair>> {
+        let mut pairs = vec![];
+        for agent_conn in &self.agent_conns {
+            if let Some(p) = agent_conn.get_selected_pair().await {
+                pairs.push(p);
+            }
         }
+        pairs
+    }
+
+    /// Returns true once every component has selected a pair.
+    pub(crate) async fn is_every_component_selected(&self) -> bool {
+        for agent_conn in &self.agent_conns {
+            if agent_conn.get_selected_pair().await.is_none() {
+                return false;
+            }
+        }
+        true
     }
 
     pub(crate) async fn ping_all_candidates(&mut self) {
+        if self.remote_ufrag.is_empty() {
+            log::trace!("remote credentials are not known yet, not pinging candidates");
+            return;
+        }
+
         log::trace!("pinging all candidates");
 
         let mut pairs: Vec<(
@@ -254,27 +561,44 @@ impl AgentInternal {
             Arc<dyn Candidate + Send + Sync>,
         )> = vec![];
 
-        {
-            let mut checklist = self.agent_conn.checklist.lock().await;
+        // Without pacing every check due is sent at once, otherwise a single one is started on
+        // each Ta, see https://tools.ietf.org/html/rfc8445#section-6.1.4.2
+        let paced = self.pacer.interval() != Duration::from_secs(0);
+        let mut may_start = self.pacer.is_ready();
+
+        self.unfreeze_pairs().await;
+
+        for agent_conn in &self.agent_conns {
+            // Components which have selected or are nominating a pair are done with checks
+            if self.nominated_pairs.contains_key(&agent_conn.key())
+                || agent_conn.get_selected_pair().await.is_some()
+            {
+                continue;
+            }
+
+            let mut checklist = agent_conn.checklist.lock().await;
             if checklist.is_empty() {
-                log::warn!(
+                warn_event!(
+                    agent = self.agent_id,
+                    component = agent_conn.component;
                     "pingAllCandidates called with no candidate pairs. Connection is not possible yet."
                 );
             }
             for p in &mut *checklist {
                 let p_state = p.state.load(Ordering::SeqCst);
-                if p_state == CandidatePairState::Waiting as u8 {
-                    p.state
-                        .store(CandidatePairState::InProgress as u8, Ordering::SeqCst);
-                } else if p_state != CandidatePairState::InProgress as u8 {
+                if p_state != CandidatePairState::Waiting as u8
+                    && (p_state != CandidatePairState::InProgress as u8
+                        || !p.is_retransmission_due(self.initial_rto, self.rto_backoff))
+                {
                     continue;
                 }
 
                 if p.binding_request_count.load(Ordering::SeqCst) > self.max_binding_requests {
                     log::trace!("max requests reached for pair {}, marking it as failed", p);
-                    p.state
-                        .store(CandidatePairState::Failed as u8, Ordering::SeqCst);
-                } else {
+                    self.set_pair_state(&p, CandidatePairState::Failed);
+                } else if may_start {
+                    may_start = !paced;
+                    self.set_pair_state(&p, CandidatePairState::InProgress);
                     p.binding_request_count.fetch_add(1, Ordering::SeqCst);
                     let local = p.local.clone();
                     let remote = p.remote.clone();
@@ -293,9 +617,117 @@ impl AgentInternal {
         local: Arc<dyn Candidate + Send + Sync>,
         remote: Arc<dyn Candidate + Send + Sync>,
     ) {
-        let p = Arc::new(CandidatePair::new(local, remote, self.is_controlling));
-        let mut checklist = self.agent_conn.checklist.lock().await;
-        checklist.push(p);
+        // The TURN server drops what the peer sends to a relay candidate until it has a
+        // permission for the address of the peer, which the TURN client only creates on its first
+        // send. It is created right away instead, so that the first check of the peer isn't lost.
+        if local.candidate_type() == CandidateType::Relay {
+            create_permission(&self.agent_id, &local, remote.addr().await);
+        }
+
+        if let Some(agent_conn) = self.agent_conn(local.stream(), local.component()) {
+            let p = Arc::new(CandidatePair::new(local, remote, self.is_controlling));
+            let mut checklist = agent_conn.checklist.lock().await;
+
+            // Pairs checking the same remote candidate from the same base are redundant, only the
+            // one with the higher priority is kept, see RFC 8445 section 6.1.2.4. A pair whose
+            // checks have started is kept anyway, its result would be lost otherwise.
+            if let Some(i) = checklist.iter().position(|q| is_redundant_pair(q, &p)) {
+                if checklist[i].priority() >= p.priority() || !is_prunable_pair(&checklist[i]) {
+                    log::trace!("Pruned pair {} redundant with {}", p, checklist[i]);
+                    return;
+                }
+                log::trace!("Pruned pair {} redundant with {}", checklist[i], p);
+                checklist.remove(i);
+                self.invalidate_routes();
+            }
+
+            checklist.push(p);
+            drop(checklist);
+            if self.compatibility_mode == CompatibilityMode::Rfc8445Strict {
+                self.limit_candidate_pairs().await;
+            }
+            self.unfreeze_pairs().await;
+        }
+    }
+
+    /// Discards the lowest priority pairs which have not been checked yet while the checklists
+    /// hold more than `MAX_CANDIDATE_PAIRS`, see RFC 8445 section 6.1.2.5.
+    async fn limit_candidate_pairs(&self) {
+        loop {
+            let mut count = 0;
+            let mut lowest: Option<(&Arc<AgentConn>, Arc<CandidatePair>)> = None;
+            for agent_conn in &self.agent_conns {
+                let checklist = agent_conn.checklist.lock().await;
+                count += checklist.len();
+                for p in &*checklist {
+                    let state = p.state.load(Ordering::SeqCst);
+                    if (state == CandidatePairState::Frozen as u8
+                        || state == CandidatePairState::Waiting as u8)
+                        && lowest
+                            .as_ref()
+                            .map_or(true, |(_, q)| p.priority() < q.priority())
+                    {
+                        lowest = Some((agent_conn, Arc::clone(p)));
+                    }
+                }
+            }
+            if count <= MAX_CANDIDATE_PAIRS {
+                return;
+            }
+
+            match lowest {
+                Some((agent_conn, p)) => {
+                    log::trace!("Discarded pair {} over the limit of the checklists", p);
+                    let mut checklist = agent_conn.checklist.lock().await;
+                    checklist.retain(|q| !Arc::ptr_eq(q, &p));
+                    self.invalidate_routes();
+                }
+                None => return,
+            }
+        }
+    }
+
+    /// Moves the frozen pairs which may be checked to waiting, following the Frozen algorithm
+    /// of RFC 8445 section 6.1.2.6: for each foundation, the pair of the first stream, the lowest
+    /// component and the highest priority is unfrozen unless a pair of that foundation is already waiting or
+    /// in progress, and every pair of a foundation which has succeeded is unfrozen. Checking a
+    /// single pair per foundation at a time saves the checks which would fail alike, e.g. on
+    /// multi-homed hosts.
+    pub(crate) async fn unfreeze_pairs(&self) {
+        let mut pairs = vec![];
+        for agent_conn in &self.agent_conns {
+            let checklist = agent_conn.checklist.lock().await;
+            pairs.extend(checklist.iter().cloned());
+        }
+        pairs.sort_by(|a, b| {
+            (a.stream(), a.component())
+                .cmp(&(b.stream(), b.component()))
+                .then_with(|| b.priority().cmp(&a.priority()))
+        });
+
+        let mut active = HashSet::new();
+        let mut succeeded = HashSet::new();
+        for p in &pairs {
+            match p.state() {
+                CandidatePairState::Waiting | CandidatePairState::InProgress => {
+                    active.insert(p.foundation());
+                }
+                CandidatePairState::Succeeded => {
+                    succeeded.insert(p.foundation());
+                }
+                _ => {}
+            }
+        }
+
+        for p in pairs {
+            if p.state() != CandidatePairState::Frozen {
+                continue;
+            }
+            let foundation = p.foundation();
+            if succeeded.contains(&foundation) || active.insert(foundation) {
+                self.set_pair_state(&p, CandidatePairState::Waiting);
+            }
+        }
     }
 
     pub(crate) async fn find_pair(
@@ -303,7 +735,11 @@ impl AgentInternal {
         local: &Arc<dyn Candidate + Send + Sync>,
         remote: &Arc<dyn Candidate + Send + Sync>,
     ) -> Option<Arc<CandidatePair>> {
-        let checklist = self.agent_conn.checklist.lock().await;
+        let checklist = self
+            .agent_conn(local.stream(), local.component())?
+            .checklist
+            .lock()
+            .await;
         for p in &*checklist {
             if p.local.equal(&**local) && p.remote.equal(&**remote) {
                 return Some(p.clone());
@@ -312,24 +748,20 @@ impl AgentInternal {
         None
     }
 
-    /// Checks if the selected pair is (still) valid.
+    /// Checks if the selected pairs are (still) valid, i.e. every component has selected a pair.
+    /// The connection state follows the component which has not received anything for the
+    /// longest time.
     /// Note: the caller should hold the agent lock.
     pub(crate) async fn validate_selected_pair(&mut self) -> bool {
-        let (valid, disconnected_time) = {
-            let selected_pair = self.agent_conn.selected_pair.lock().await;
-            (*selected_pair).as_ref().map_or_else(
-                || (false, Duration::from_secs(0)),
-                |selected_pair| {
-                    let disconnected_time = match SystemTime::now()
-                        .duration_since(selected_pair.remote.last_received())
-                    {
-                        Ok(d) => d,
-                        Err(_) => Duration::from_secs(0),
-                    };
-                    (true, disconnected_time)
-                },
-            )
-        };
+        let valid = self.is_every_component_selected().await;
+        let mut disconnected_time = Duration::from_secs(0);
+        for selected_pair in self.get_selected_pairs().await {
+            let d = match SystemTime::now().duration_since(selected_pair.remote.last_received()) {
+                Ok(d) => d,
+                Err(_) => Duration::from_secs(0),
+            };
+            disconnected_time = std::cmp::max(disconnected_time, d);
+        }
 
         if valid {
             // Only allow transitions to failed if a.failedTimeout is non-zero
@@ -356,23 +788,12 @@ impl AgentInternal {
         valid
     }
 
-    /// Sends STUN Binding Indications to the selected pair.
+    /// Sends STUN Binding Indications to the selected pairs.
     /// if no packet has been sent on that pair in the last keepaliveInterval.
     /// Note: the caller should hold the agent lock.
     pub(crate) async fn check_keepalive(&mut self) {
-        let (local, remote) = {
-            let selected_pair = self.agent_conn.selected_pair.lock().await;
-            (*selected_pair)
-                .as_ref()
-                .map_or((None, None), |selected_pair| {
-                    (
-                        Some(selected_pair.local.clone()),
-                        Some(selected_pair.remote.clone()),
-                    )
-                })
-        };
-
-        if let (Some(local), Some(remote)) = (local, remote) {
+        for selected_pair in self.get_selected_pairs().await {
+            let (local, remote) = (&selected_pair.local, &selected_pair.remote);
             let last_sent = match SystemTime::now().duration_since(local.last_sent()) {
                 Ok(d) => d,
                 Err(_) => Duration::from_secs(0),
@@ -387,14 +808,198 @@ impl AgentInternal {
                 && ((last_sent > self.keepalive_interval)
                     || (last_received > self.keepalive_interval))
             {
-                // we use binding request instead of indication to support refresh consent schemas
-                // see https://tools.ietf.org/html/rfc7675
-                self.ping_candidate(&local, &remote).await;
+                match self.keepalive_strategy {
+                    KeepaliveStrategy::BindingIndication => {
+                        self.send_binding_indication(local, remote).await;
+                    }
+                    _ => self.ping_candidate(local, remote).await,
+                }
             }
         }
+
+        if self.keepalive_valid_pairs
+            && self.keepalive_interval != Duration::from_secs(0)
+            && !self.nominates_aggressively()
+        {
+            self.check_valid_pairs_keepalive().await;
+        }
     }
 
-    fn request_connectivity_check(&self) {
+    /// Sends a binding request on the valid pairs which are not selected, if none has been sent
+    /// on the pair in the last keepaliveInterval.
+    async fn check_valid_pairs_keepalive(&mut self) {
+        let mut pairs = vec![];
+        for agent_conn in &self.agent_conns {
+            let selected_pair = agent_conn.get_selected_pair().await;
+            let checklist = agent_conn.checklist.lock().await;
+            pairs.extend(
+                checklist
+                    .iter()
+                    .filter(|p| {
+                        p.state() == CandidatePairState::Succeeded
+                            && selected_pair.as_ref() != Some(*p)
+                            && p.is_keepalive_due(self.keepalive_interval)
+                    })
+                    .cloned(),
+            );
+        }
+
+        for p in pairs {
+            log::trace!("agent {}: keepalive of valid pair {}", self.agent_id, p);
+            self.ping_candidate(&p.local, &p.remote).await;
+        }
+    }
+
+    /// Sends a binding indication, which needs no authentication nor response, see
+    /// https://tools.ietf.org/html/rfc8445#section-11.
+    async fn send_binding_indication(
+        &self,
+        local: &Arc<dyn Candidate + Send + Sync>,
+        remote: &Arc<dyn Candidate + Send + Sync>,
+    ) {
+        let mut msg = Message::new();
+        if let Err(err) = msg.build(&[
+            Box::new(MessageType::new(METHOD_BINDING, CLASS_INDICATION)),
+            Box::new(TransactionId::new()),
+            Box::new(FINGERPRINT),
+        ]) {
+            error_event!(agent = self.agent_id, error = err; "failed to build binding indication");
+            return;
+        }
+
+        log::trace!(
+            "agent {}: keepalive indication: {} to {}",
+            self.agent_id,
+            local,
+            remote
+        );
+        self.send_stun(&msg, local, remote).await;
+    }
+
+    /// Refreshes consent to send on the selected pairs, and fails the pair and the connection
+    /// once consent has expired on any of them.
+    ///
+    /// reference: (IETF ref-7675)[https://tools.ietf.org/html/rfc7675#section-5.1].
+    /// Note: the caller should hold the agent lock.
+    pub(crate) async fn check_consent(&mut self) {
+        let selected_pairs = self.get_selected_pairs().await;
+        if selected_pairs.is_empty() {
+            return;
+        }
+
+        if self.consent_timeout != Duration::from_secs(0) {
+            // Consent is granted on selection and on every response received on the pair since
+            let since_selected = Instant::now().duration_since(self.consent_granted_at);
+            for p in &selected_pairs {
+                let since_consent = match elapsed_since(&p.last_response_received) {
+                    Some(d) => std::cmp::min(d, since_selected),
+                    None => since_selected,
+                };
+
+                if since_consent > self.consent_timeout {
+                    log::info!("consent expired for selected pair {}", p);
+                    self.set_pair_state(&p, CandidatePairState::Failed);
+                    self.update_connection_state(ConnectionState::Failed).await;
+                    return;
+                }
+            }
+        }
+
+        if self.consent_check_interval != Duration::from_secs(0)
+            && Instant::now().duration_since(self.consent_requested_at)
+                >= self.next_consent_interval
+        {
+            self.consent_requested_at = Instant::now();
+            self.next_consent_interval = self.randomized_consent_interval();
+            for p in &selected_pairs {
+                self.ping_candidate(&p.local, &p.remote).await;
+            }
+        }
+    }
+
+    /// Returns the consent check interval randomized by +/-20%, so consent requests of several
+    /// agents do not synchronize.
+    fn randomized_consent_interval(&self) -> Duration {
+        self.consent_check_interval
+            .mul_f64(rand::thread_rng().gen_range(0.8..1.2))
+    }
+
+    /// Returns the ICE options supported by both agents, once those of the remote agent are known.
+    pub(crate) fn negotiated_ice_options(&self) -> Option<IceOptions> {
+        self.remote_ice_options
+            .map(|remote| self.local_ice_options.intersect(&remote))
+    }
+
+    /// Returns true when every check of the controlling agent carries USE-CANDIDATE. Aggressive
+    /// nomination is deprecated by RFC 8445, so it is not used with agents which implement it.
+    pub(crate) fn nominates_aggressively(&self) -> bool {
+        self.nomination_strategy == NominationStrategy::Aggressive
+            && !self.negotiated_ice_options().map_or(false, |o| o.ice2)
+    }
+
+    /// Returns true when the remote side signaled end-of-candidates, or does not trickle them,
+    /// every trickled candidate has been added and all pairs in the checklist of a component
+    /// have failed, as that component can't connect anymore.
+    pub(crate) async fn is_checklist_failed(&self) -> bool {
+        let remote_trickles = self.negotiated_ice_options().map_or(true, |o| o.trickle);
+        if (remote_trickles && !self.remote_end_of_candidates.load(Ordering::SeqCst))
+            || self.pending_remote_candidates.load(Ordering::SeqCst) != 0
+        {
+            return false;
+        }
+
+        for agent_conn in &self.agent_conns {
+            let checklist = agent_conn.checklist.lock().await;
+            if !checklist.is_empty()
+                && checklist
+                    .iter()
+                    .all(|p| p.state.load(Ordering::SeqCst) == CandidatePairState::Failed as u8)
+            {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Stops sending checks, keepalives and consent requests until `resume_checks` is called.
+    pub(crate) fn pause_checks(&mut self) {
+        if self.checks_paused_at.is_none() {
+            log::debug!("agent {}: pausing connectivity checks", self.agent_id);
+            self.checks_paused_at = Some(Instant::now());
+        }
+    }
+
+    /// Resumes the checks stopped by `pause_checks`, pushing the timers which would otherwise
+    /// have expired for lack of traffic back by the time they were paused for.
+    pub(crate) async fn resume_checks(&mut self) {
+        let paused = match self.checks_paused_at.take() {
+            Some(paused_at) => paused_at.elapsed(),
+            None => return,
+        };
+        log::debug!(
+            "agent {}: resuming connectivity checks paused for {:?}",
+            self.agent_id,
+            paused
+        );
+
+        self.checks_paused_for += paused;
+        self.consent_granted_at += paused;
+        self.consent_requested_at += paused;
+        for p in self.get_selected_pairs().await {
+            if let Ok(mut last_response_received) = p.last_response_received.lock() {
+                if let Some(last_response_received) = &mut *last_response_received {
+                    *last_response_received += paused;
+                }
+            }
+            // Nothing answered while paused, so the remote candidate is given a full
+            // disconnected timeout to be heard from again
+            p.remote.seen(false);
+        }
+
+        self.request_connectivity_check();
+    }
+
+    pub(crate) fn request_connectivity_check(&self) {
         let _ = self.force_candidate_contact_tx.try_send(true);
     }
 
@@ -422,21 +1027,37 @@ impl AgentInternal {
         }
 
         for cand in local_cands {
-            self.add_pair(cand, c.clone()).await;
+            if can_pair(&*cand, &**c) {
+                self.add_pair(cand, c.clone()).await;
+            }
         }
 
         self.request_connectivity_check();
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(
+            agent = %self.agent_id,
+            candidate = %c.id(),
+            typ = %c.candidate_type(),
+        ))
+    )]
     pub(crate) async fn add_candidate(
         &mut self,
         c: &Arc<dyn Candidate + Send + Sync>,
-    ) -> Result<(), Error> {
+    ) -> Result<(), IceError> {
+        if let Some(candidate_priority) = &*self.candidate_priority {
+            let preferences = CandidatePreferences::from_priority(c.priority());
+            let preferences = candidate_priority(&**c, preferences);
+            c.set_priority(preferences.priority(c.component()));
+        }
+
         let initialized_ch = self
             .started_ch_tx
             .as_ref()
             .map(tokio::sync::broadcast::Sender::subscribe);
-        self.start_candidate(c, initialized_ch).await;
+        let started = self.start_candidate(c, initialized_ch, false).await;
 
         let network_type = c.network_type();
 
@@ -444,7 +1065,11 @@ impl AgentInternal {
             for cand in cands {
                 if cand.equal(&**c) {
                     if let Err(err) = c.close().await {
-                        log::warn!("Failed to close duplicate candidate: {}", err);
+                        warn_event!(
+                            agent = self.agent_id,
+                            error = err;
+                            "Failed to close duplicate candidate"
+                        );
                     }
                     //TODO: why return?
                     return Ok(());
@@ -452,6 +1077,55 @@ impl AgentInternal {
             }
         }
 
+        // A candidate with the same transport address and base as another one is redundant, only
+        // the one with the higher priority is kept, see RFC 8445 section 5.1.3. So is a server
+        // reflexive candidate mapped to the same public address as another one from another
+        // socket, unless the agent is strict.
+        let strict = self.compatibility_mode == CompatibilityMode::Rfc8445Strict;
+        let redundant = self.local_candidates.get(&network_type).and_then(|cands| {
+            cands
+                .iter()
+                .find(|cand| {
+                    is_redundant_candidate(&***cand, &**c)
+                        || (!strict && is_duplicate_server_reflexive(&***cand, &**c))
+                })
+                .cloned()
+        });
+        if let Some(redundant) = redundant {
+            let mut in_use = false;
+            if let Some(agent_conn) = self.agent_conn(redundant.stream(), redundant.component()) {
+                in_use = agent_conn
+                    .checklist
+                    .lock()
+                    .await
+                    .iter()
+                    .any(|p| p.local.equal(&*redundant) && !is_prunable_pair(p));
+            }
+            if in_use || redundant.priority() >= c.priority() {
+                log::debug!("Pruned local candidate {} redundant with {}", c, redundant);
+                if let Err(err) = c.close().await {
+                    warn_event!(
+                        agent = self.agent_id,
+                        error = err;
+                        "Failed to close redundant candidate"
+                    );
+                }
+                return Ok(());
+            }
+
+            log::debug!("Pruned local candidate {} redundant with {}", redundant, c);
+            self.remove_local_candidate(&redundant).await;
+        }
+
+        // The candidate reading the connection this one shares may just have been pruned
+        if !started {
+            let initialized_ch = self
+                .started_ch_tx
+                .as_ref()
+                .map(tokio::sync::broadcast::Sender::subscribe);
+            self.start_candidate(c, initialized_ch, false).await;
+        }
+
         if let Some(cands) = self.local_candidates.get_mut(&network_type) {
             cands.push(c.clone());
         } else {
@@ -464,36 +1138,59 @@ impl AgentInternal {
         }
 
         for cand in remote_cands {
-            self.add_pair(c.clone(), cand).await;
+            if can_pair(&**c, &*cand) {
+                self.add_pair(c.clone(), cand).await;
+            }
         }
 
         self.request_connectivity_check();
         if let Some(chan_candidate_tx) = &self.chan_candidate_tx {
             let _ = chan_candidate_tx.send(Some(c.clone())).await;
         }
+        let _ = self.events_tx.send(AgentEvent::Candidate(c.clone()));
+        metrics::candidate_gathered(c.candidate_type());
 
         Ok(())
     }
 
-    pub(crate) async fn close(&mut self) -> Result<(), Error> {
+    /// Closes the agent and hands over its local candidates, so they can be closed without
+    /// holding the agent lock.
+    pub(crate) async fn close(
+        &mut self,
+    ) -> Result<Vec<Arc<dyn Candidate + Send + Sync>>, IceError> {
         if self.done_tx.is_none() {
-            return Err(ERR_CLOSED.to_owned());
+            return Err(IceError::Closed);
         }
-        self.delete_all_candidates().await;
+        let local_candidates = self
+            .local_candidates
+            .drain()
+            .flat_map(|(_, cs)| cs)
+            .collect();
+        // Remote candidates hold no resource
+        self.remote_candidates.clear();
+        self.invalidate_routes();
+        self.pending_binding_requests.clear();
         self.started_ch_tx.take();
 
-        self.agent_conn.buffer.close().await;
+        for agent_conn in &self.agent_conns {
+            agent_conn.buffer.close();
+        }
 
         self.update_connection_state(ConnectionState::Closed).await;
 
         self.done_tx.take();
+        self.closed_ch_tx.take();
         self.chan_candidate_tx.take();
         self.chan_candidate_pair_tx.take();
         self.chan_state_tx.take();
+        self.chan_candidate_error_tx.take();
+        self.chan_binding_request_tx.take();
 
-        self.agent_conn.done.store(true, Ordering::SeqCst);
+        for agent_conn in &self.agent_conns {
+            agent_conn.done.store(true, Ordering::SeqCst);
+        }
 
-        Ok(())
+        Ok(local_candidates)
     }
 
     /// Remove all candidates.
@@ -504,7 +1201,12 @@ impl AgentInternal {
         for cs in &mut self.local_candidates.values_mut() {
             for c in cs {
                 if let Err(err) = c.close().await {
-                    log::warn!("Failed to close candidate {}: {}", c, err);
+                    warn_event!(
+                        agent = self.agent_id,
+                        candidate = c,
+                        error = err;
+                        "Failed to close candidate"
+                    );
                 }
             }
         }
@@ -513,11 +1215,185 @@ impl AgentInternal {
         for cs in self.remote_candidates.values_mut() {
             for c in cs {
                 if let Err(err) = c.close().await {
-                    log::warn!("Failed to close candidate {}: {}", c, err);
+                    warn_event!(
+                        agent = self.agent_id,
+                        candidate = c,
+                        error = err;
+                        "Failed to close candidate"
+                    );
                 }
             }
         }
         self.remote_candidates.clear();
+        self.invalidate_routes();
+    }
+
+    /// Closes the host candidates based on one of `ips`, which are no longer assigned to a local
+    /// interface, and drops their pairs. A component whose selected pair used one of them goes
+    /// back to checking the remaining pairs.
+    pub(crate) async fn remove_local_candidates_on(&mut self, ips: &[IpAddr]) {
+        let mut removed = vec![];
+        for cands in self.local_candidates.values_mut() {
+            let mut kept = vec![];
+            for c in cands.drain(..) {
+                if c.candidate_type() == CandidateType::Host && ips.contains(&base_ip(&*c).await) {
+                    removed.push(c);
+                } else {
+                    kept.push(c);
+                }
+            }
+            *cands = kept;
+        }
+        if removed.is_empty() {
+            return;
+        }
+
+        self.invalidate_routes();
+
+        let is_removed =
+            |c: &Arc<dyn Candidate + Send + Sync>| removed.iter().any(|r| r.equal(&**c));
+        let mut reselect = false;
+        for agent_conn in &self.agent_conns {
+            {
+                let mut checklist = agent_conn.checklist.lock().await;
+                checklist.retain(|p| !is_removed(&p.local));
+            }
+
+            let mut selected_pair = agent_conn.selected_pair.lock().await;
+            if selected_pair
+                .as_ref()
+                .map_or(false, |p| is_removed(&p.local))
+            {
+                *selected_pair = None;
+                self.nominated_pairs.remove(&agent_conn.key());
+                reselect = true;
+            }
+        }
+        self.nominated_pairs.retain(|_, p| !is_removed(&p.local));
+
+        for c in &removed {
+            log::debug!("Removing local candidate {}, its address is gone", c);
+            if let Err(err) = c.close().await {
+                warn_event!(
+                    agent = self.agent_id,
+                    candidate = c,
+                    error = err;
+                    "Failed to close candidate"
+                );
+            }
+        }
+
+        if reselect
+            && (self.connection_state == ConnectionState::Connected
+                || self.connection_state == ConnectionState::Disconnected)
+        {
+            self.update_connection_state(ConnectionState::Checking)
+                .await;
+            self.request_connectivity_check();
+        }
+    }
+
+    /// Closes the local candidates which are part of neither the selected pair nor the
+    /// `backup_pairs` best valid pairs of any component, once the selected pairs have been
+    /// unchanged for `release_unused_candidates_after`. The candidates sharing their connection
+    /// with a kept one, e.g. the host candidate of a server reflexive one on a UDPMux, are kept
+    /// as well.
+    pub(crate) async fn release_unused_candidates(&mut self) {
+        match self.release_unused_candidates_after {
+            Some(after) if self.pair_selected_at.elapsed() >= after => {}
+            _ => return,
+        }
+
+        let mut used: Vec<Arc<dyn Candidate + Send + Sync>> = vec![];
+        for agent_conn in &self.agent_conns {
+            let selected_pair = match agent_conn.get_selected_pair().await {
+                Some(p) => p,
+                None => return,
+            };
+
+            let mut valid: Vec<Arc<CandidatePair>> = agent_conn
+                .checklist
+                .lock()
+                .await
+                .iter()
+                .filter(|p| p.state() == CandidatePairState::Succeeded && **p != selected_pair)
+                .cloned()
+                .collect();
+            valid.sort_by(|a, b| b.priority().cmp(&a.priority()));
+
+            used.push(Arc::clone(&selected_pair.local));
+            used.extend(
+                valid
+                    .iter()
+                    .take(self.backup_pairs)
+                    .map(|p| Arc::clone(&p.local)),
+            );
+        }
+
+        let unused: Vec<Arc<dyn Candidate + Send + Sync>> = self
+            .local_candidates
+            .values()
+            .flatten()
+            .filter(|c| {
+                !used
+                    .iter()
+                    .any(|u| u.equal(&***c) || shares_conn(&**u, &***c))
+            })
+            .cloned()
+            .collect();
+        for c in &unused {
+            log::debug!(
+                "agent {}: Releasing local candidate {}, it is not part of a selected or backup pair",
+                self.agent_id,
+                c
+            );
+            self.remove_local_candidate(c).await;
+        }
+    }
+
+    /// Makes the inbound loop forget the routes of the application data, after a candidate or a
+    /// pair they may point to was removed.
+    pub(crate) fn invalidate_routes(&self) {
+        self.routes_generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Closes a local candidate and drops its pairs.
+    pub(crate) async fn remove_local_candidate(&mut self, c: &Arc<dyn Candidate + Send + Sync>) {
+        // The first of the candidates sharing a connection reads it, the next one takes over
+        let mut heir = None;
+        if let Some(cands) = self.local_candidates.get_mut(&c.network_type()) {
+            let reads_conn = cands
+                .iter()
+                .find(|cand| shares_conn(&***cand, &**c))
+                .map_or(false, |reader| reader.equal(&**c));
+            cands.retain(|cand| !cand.equal(&**c));
+            if reads_conn {
+                heir = cands
+                    .iter()
+                    .find(|cand| shares_conn(&***cand, &**c))
+                    .cloned();
+            }
+        }
+        if let Some(agent_conn) = self.agent_conn(c.stream(), c.component()) {
+            let mut checklist = agent_conn.checklist.lock().await;
+            checklist.retain(|p| !p.local.equal(&**c));
+        }
+        self.invalidate_routes();
+        if let Err(err) = c.close().await {
+            warn_event!(
+                agent = self.agent_id,
+                candidate = c,
+                error = err;
+                "Failed to close candidate"
+            );
+        }
+        if let Some(heir) = heir {
+            let initialized_ch = self
+                .started_ch_tx
+                .as_ref()
+                .map(tokio::sync::broadcast::Sender::subscribe);
+            self.start_candidate(&heir, initialized_ch, true).await;
+        }
     }
 
     pub(crate) fn find_remote_candidate(
@@ -537,6 +1413,15 @@ impl AgentInternal {
         None
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(
+            agent = %self.agent_id,
+            transaction_id = ?m.transaction_id,
+            local = %local.id(),
+            remote = %remote.id(),
+        ))
+    )]
     pub(crate) async fn send_binding_request(
         &mut self,
         m: &Message,
@@ -545,14 +1430,23 @@ impl AgentInternal {
     ) {
         log::trace!("ping STUN from {} to {}", local, remote);
 
+        let pair = self.find_pair(local, remote).await;
+        if let Some(p) = &pair {
+            p.on_request_sent();
+        }
+        metrics::check_sent();
+
         self.invalidate_pending_binding_requests(Instant::now());
         self.pending_binding_requests.push(BindingRequest {
             timestamp: Instant::now(),
             transaction_id: m.transaction_id,
             destination: remote.addr().await,
             is_use_candidate: m.contains(ATTR_USE_CANDIDATE),
+            is_controlling: m.contains(ATTR_ICE_CONTROLLING),
+            pair,
         });
 
+        self.pacer.acquire_now();
         self.send_stun(m, local, remote).await;
     }
 
@@ -580,13 +1474,17 @@ impl AgentInternal {
         };
 
         if let Err(err) = result {
-            log::warn!(
-                "Failed to handle inbound ICE from: {} to: {} error: {}",
-                local,
-                remote,
-                err
+            warn_event!(
+                agent = self.agent_id,
+                local = local,
+                remote = remote,
+                error = err;
+                "Failed to handle inbound ICE"
             );
         } else {
+            if let Some(p) = self.find_pair(local, remote).await {
+                p.on_response_sent();
+            }
             self.send_stun(&out, local, remote).await;
         }
     }
@@ -602,6 +1500,11 @@ impl AgentInternal {
         for binding_request in self.pending_binding_requests.drain(..) {
             if filter_time.duration_since(binding_request.timestamp) < MAX_BINDING_REQUEST_TIMEOUT {
                 temp.push(binding_request);
+            } else {
+                if let Some(p) = &binding_request.pair {
+                    p.on_request_lost();
+                }
+                metrics::check_failed();
             }
         }
 
@@ -632,6 +1535,15 @@ impl AgentInternal {
     }
 
     /// Processes STUN traffic from a remote candidate.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(
+            agent = %self.agent_id,
+            transaction_id = ?m.transaction_id,
+            local = %local.id(),
+            remote = %remote,
+        ))
+    )]
     pub(crate) async fn handle_inbound(
         &mut self,
         m: &mut Message,
@@ -641,6 +1553,7 @@ impl AgentInternal {
     ) {
         if m.typ.method != METHOD_BINDING
             || !(m.typ.class == CLASS_SUCCESS_RESPONSE
+                || m.typ.class == CLASS_ERROR_RESPONSE
                 || m.typ.class == CLASS_REQUEST
                 || m.typ.class == CLASS_INDICATION)
         {
@@ -654,45 +1567,71 @@ impl AgentInternal {
             return;
         }
 
-        if self.is_controlling {
-            if m.contains(ATTR_ICE_CONTROLLING) {
-                log::debug!("inbound isControlling && a.isControlling == true");
-                return;
-            } else if m.contains(ATTR_USE_CANDIDATE) {
-                log::debug!("useCandidate && a.isControlling == true");
-                return;
-            }
-        } else if m.contains(ATTR_ICE_CONTROLLED) {
-            log::debug!("inbound isControlled && a.isControlling == false");
-            return;
-        }
-
         let mut remote_candidate = self.find_remote_candidate(local.network_type(), remote);
         if m.typ.class == CLASS_SUCCESS_RESPONSE {
             if let Err(err) = assert_inbound_message_integrity(m, self.remote_pwd.as_bytes()) {
-                log::warn!("discard message from ({}), {}", remote, err);
+                warn_event!(agent = self.agent_id, remote = remote, error = err; "discard message");
                 return;
             }
 
             if let Some(rc) = &remote_candidate {
                 self.handle_success_response(m, local, rc, remote).await;
             } else {
-                log::warn!("discard success message from ({}), no such remote", remote);
+                warn_event!(
+                    agent = self.agent_id,
+                    remote = remote;
+                    "discard success message, no such remote"
+                );
+                return;
+            }
+        } else if m.typ.class == CLASS_ERROR_RESPONSE {
+            if let Err(err) = assert_inbound_message_integrity(m, self.remote_pwd.as_bytes()) {
+                warn_event!(agent = self.agent_id, remote = remote, error = err; "discard message");
+                return;
+            }
+
+            if let Some(rc) = &remote_candidate {
+                self.handle_error_response(m, local, rc).await;
+            } else {
+                warn_event!(
+                    agent = self.agent_id,
+                    remote = remote;
+                    "discard error message, no such remote"
+                );
                 return;
             }
         } else if m.typ.class == CLASS_REQUEST {
             let username = self.local_ufrag.clone() + ":" + self.remote_ufrag.as_str();
             if let Err(err) = assert_inbound_username(m, &username) {
-                log::warn!("discard message from ({}), {}", remote, err);
+                warn_event!(agent = self.agent_id, remote = remote, error = err; "discard message");
                 return;
             } else if let Err(err) = assert_inbound_message_integrity(m, self.local_pwd.as_bytes())
             {
-                log::warn!("discard message from ({}), {}", remote, err);
+                warn_event!(agent = self.agent_id, remote = remote, error = err; "discard message");
                 return;
             }
 
+            if let Some(binding_request_filter) = &*self.binding_request_filter {
+                if !binding_request_filter(m, &**local, remote) {
+                    log::debug!(
+                        "agent {}: discard request from ({}), rejected by the filter",
+                        self.agent_id,
+                        remote
+                    );
+                    return;
+                }
+            }
+
             if remote_candidate.is_none() {
-                let (ip, port, network_type) = (remote.ip(), remote.port(), NetworkType::Udp4);
+                let (ip, port, network_type) = (remote.ip(), remote.port(), local.network_type());
+
+                // The priority of a peer-reflexive candidate is the one the remote agent put in
+                // the PRIORITY attribute of its request.
+                // https://tools.ietf.org/html/rfc8445#section-7.3.1.3
+                let mut priority = PriorityAttr::default();
+                if let Err(err) = priority.get_from(m) {
+                    log::debug!("no PRIORITY in request from ({}), {}", remote, err);
+                }
 
                 let prflx_candidate_config = CandidatePeerReflexiveConfig {
                     base_config: CandidateBaseConfig {
@@ -700,6 +1639,8 @@ impl AgentInternal {
                         address: ip.to_string(),
                         port,
                         component: local.component(),
+                        stream: local.stream(),
+                        priority: priority.0,
                         ..CandidateBaseConfig::default()
                     },
                     rel_addr: "".to_owned(),
@@ -712,7 +1653,11 @@ impl AgentInternal {
                 {
                     Ok(prflx_candidate) => remote_candidate = Some(Arc::new(prflx_candidate)),
                     Err(err) => {
-                        log::error!("Failed to create new remote prflx candidate ({})", err);
+                        error_event!(
+                            agent = self.agent_id,
+                            error = err;
+                            "Failed to create new remote prflx candidate"
+                        );
                         return;
                     }
                 };
@@ -725,8 +1670,30 @@ impl AgentInternal {
 
             log::trace!("inbound STUN (Request) from {} to {}", remote, local);
 
+            if !self.role_determined {
+                self.determine_role(m).await;
+            }
+
             if let Some(rc) = &remote_candidate {
+                if !self.resolve_role_conflict(m, local, rc).await {
+                    return;
+                }
+
+                if self.is_controlling && m.contains(ATTR_USE_CANDIDATE) {
+                    log::debug!("useCandidate && a.isControlling == true");
+                    return;
+                }
+
                 self.handle_binding_request(m, local, rc).await;
+
+                if self.chan_binding_request_tx.is_some()
+                    && self.find_pair(local, rc).await.is_some()
+                {
+                    if let Some(chan_binding_request_tx) = &self.chan_binding_request_tx {
+                        let _ =
+                            chan_binding_request_tx.send((m.clone(), Arc::clone(local), remote));
+                    }
+                }
             }
         }
 
@@ -735,34 +1702,251 @@ impl AgentInternal {
         }
     }
 
-    /// Processes non STUN traffic from a remote candidate, and returns true if it is an actual
-    /// remote candidate.
+    /// Resolves a role conflict signalled by an inbound binding request carrying the same role
+    /// attribute as ours, see https://tools.ietf.org/html/rfc8445#section-7.3.1.1. The agent with
+    /// the larger tie-breaker keeps the controlling role. Returns false if the request has been
+    /// answered with a 487 (Role Conflict) error and must not be processed any further.
+    async fn resolve_role_conflict(
+        &mut self,
+        m: &Message,
+        local: &Arc<dyn Candidate + Send + Sync>,
+        remote: &Arc<dyn Candidate + Send + Sync>,
+    ) -> bool {
+        if self.is_controlling {
+            let mut controlling = AttrControlling::default();
+            if controlling.get_from(m).is_err() {
+                return true;
+            }
+
+            if self.tie_breaker >= controlling.0 {
+                self.send_role_conflict(m, local, remote).await;
+                return false;
+            }
+            self.set_role(false).await;
+        } else {
+            let mut controlled = AttrControlled::default();
+            if controlled.get_from(m).is_err() {
+                return true;
+            }
+
+            if self.tie_breaker < controlled.0 {
+                self.send_role_conflict(m, local, remote).await;
+                return false;
+            }
+            self.set_role(true).await;
+        }
+
+        true
+    }
+
+    /// Takes the role opposite to the one of the remote agent which sent the request `m`, when
+    /// the role of the agent has not been determined yet.
+    async fn determine_role(&mut self, m: &Message) {
+        let is_controlling = if m.contains(ATTR_ICE_CONTROLLING) {
+            false
+        } else if m.contains(ATTR_ICE_CONTROLLED) {
+            true
+        } else {
+            return;
+        };
+
+        self.role_determined = true;
+        if is_controlling != self.is_controlling {
+            self.set_role(is_controlling).await;
+        }
+    }
+
+    /// Answers a binding request with a 487 (Role Conflict) error response.
+    async fn send_role_conflict(
+        &self,
+        m: &Message,
+        local: &Arc<dyn Candidate + Send + Sync>,
+        remote: &Arc<dyn Candidate + Send + Sync>,
+    ) {
+        let (out, result) = {
+            let mut out = Message::new();
+            let result = out.build(&[
+                Box::new(m.clone()),
+                Box::new(BINDING_ERROR),
+                Box::new(CODE_ROLE_CONFLICT),
+                Box::new(MessageIntegrity::new_short_term_integrity(
+                    self.local_pwd.clone(),
+                )),
+                Box::new(FINGERPRINT),
+            ]);
+            (out, result)
+        };
+
+        if let Err(err) = result {
+            warn_event!(
+                agent = self.agent_id,
+                local = local,
+                remote = remote,
+                error = err;
+                "Failed to build role conflict response"
+            );
+        } else {
+            log::debug!("role conflict, sending 487 from {} to {}", local, remote);
+            self.send_stun(&out, local, remote).await;
+        }
+    }
+
+    /// Processes an error response to one of our binding requests. On a 487 (Role Conflict) the
+    /// agent switches to the role opposite to the one the request was sent with and checks the
+    /// pair again, see https://tools.ietf.org/html/rfc8445#section-7.2.5.1.
+    async fn handle_error_response(
+        &mut self,
+        m: &Message,
+        local: &Arc<dyn Candidate + Send + Sync>,
+        remote: &Arc<dyn Candidate + Send + Sync>,
+    ) {
+        let pending_request =
+            if let Some(pending_request) = self.handle_inbound_binding_success(m.transaction_id) {
+                pending_request
+            } else {
+                warn_event!(
+                    agent = self.agent_id,
+                    remote = remote;
+                    "discard message, unknown TransactionID 0x{:?}", m.transaction_id
+                );
+                return;
+            };
+
+        let mut error_code = ErrorCodeAttribute::default();
+        if let Err(err) = error_code.get_from(m) {
+            warn_event!(
+                agent = self.agent_id,
+                remote = remote,
+                error = err;
+                "discard error response"
+            );
+            return;
+        }
+
+        if error_code.code != CODE_ROLE_CONFLICT {
+            log::debug!(
+                "inbound STUN (ErrorResponse) from {} to {}: {}",
+                remote,
+                local,
+                String::from_utf8_lossy(&error_code.reason)
+            );
+            return;
+        }
+
+        // The check is answered with the other role, unless the role was switched since it was
+        // sent, see https://tools.ietf.org/html/rfc8445#section-7.2.5.1
+        if pending_request.is_controlling == self.is_controlling {
+            if self.role_switches >= self.max_role_switches {
+                warn_event!(
+                    agent = self.agent_id,
+                    remote = remote,
+                    role_switches = self.role_switches;
+                    "role conflict after too many role switches, failing the pair"
+                );
+                if let Some(p) = self.find_pair(local, remote).await {
+                    self.set_pair_state(&p, CandidatePairState::Failed);
+                }
+                let err = IceError::RoleConflict {
+                    remote: remote.addr().await,
+                    role_switches: self.role_switches,
+                };
+                let _ = self.events_tx.send(AgentEvent::Error(err));
+                return;
+            }
+            self.role_switches += 1;
+            // A new tie-breaker, so that the agents don't keep conflicting over the same values
+            self.tie_breaker = rand::random::<u64>();
+            self.set_role(!pending_request.is_controlling).await;
+        }
+
+        if let Some(p) = self.find_pair(local, remote).await {
+            self.set_pair_state(&p, CandidatePairState::Waiting);
+        }
+        self.ping_candidate(local, remote).await;
+    }
+
+    /// Switches the role of the agent, recomputing the priority of every candidate pair.
+    async fn set_role(&mut self, is_controlling: bool) {
+        log::debug!(
+            "switching role: isControlling {} -> {}",
+            self.is_controlling,
+            is_controlling
+        );
+        self.is_controlling = is_controlling;
+        self.nominated_pairs.clear();
+        self.start();
+
+        // The checks in flight carry the former role, so they are sent again with the new one
+        let mut requeued = vec![];
+        for agent_conn in &self.agent_conns {
+            let checklist = agent_conn.checklist.lock().await;
+            for p in &*checklist {
+                p.ice_role_controlling
+                    .store(is_controlling, Ordering::SeqCst);
+                if p.state() == CandidatePairState::InProgress {
+                    requeued.push(Arc::clone(p));
+                }
+            }
+        }
+        for p in &requeued {
+            self.set_pair_state(p, CandidatePairState::Waiting);
+        }
+
+        let _ = self
+            .events_tx
+            .send(AgentEvent::RoleChange { is_controlling });
+        self.request_connectivity_check();
+    }
+
+    /// Returns the remote candidate a packet of `n` bytes received on `local` from `remote` comes
+    /// from, or `None` when it comes from no known remote candidate and must be discarded.
     pub(crate) async fn validate_non_stun_traffic(
         &self,
         local: &Arc<dyn Candidate + Send + Sync>,
         remote: SocketAddr,
-    ) -> bool {
-        self.find_remote_candidate(local.network_type(), remote)
-            .map_or(false, |remote_candidate| {
-                remote_candidate.seen(false);
-                true
-            })
+        n: usize,
+    ) -> Option<Arc<dyn Candidate + Send + Sync>> {
+        let remote_candidate = self.find_remote_candidate(local.network_type(), remote)?;
+        remote_candidate.seen(false);
+        if let Some(p) = self.find_pair(local, &remote_candidate).await {
+            p.on_packet_received(n);
+        }
+        Some(remote_candidate)
     }
 
-    /// Sets the credentials of the remote agent.
-    pub(crate) fn set_remote_credentials(
+    /// Sets the credentials of the remote agent. When they change, the checks in flight are
+    /// dropped, as their responses can't be authenticated anymore, and the pairs which are not
+    /// yet succeeded are checked again with the new credentials.
+    pub(crate) async fn set_remote_credentials(
         &mut self,
         remote_ufrag: String,
         remote_pwd: String,
-    ) -> Result<(), Error> {
+    ) -> Result<(), IceError> {
         if remote_ufrag.is_empty() {
-            return Err(ERR_REMOTE_UFRAG_EMPTY.to_owned());
+            return Err(IceError::RemoteUfragEmpty);
         } else if remote_pwd.is_empty() {
-            return Err(ERR_REMOTE_PWD_EMPTY.to_owned());
+            return Err(IceError::RemotePwdEmpty);
+        }
+
+        if remote_ufrag == self.remote_ufrag && remote_pwd == self.remote_pwd {
+            return Ok(());
         }
 
         self.remote_ufrag = remote_ufrag;
         self.remote_pwd = remote_pwd;
+        self.pending_binding_requests.clear();
+
+        for agent_conn in &self.agent_conns {
+            let checklist = agent_conn.checklist.lock().await;
+            for p in &*checklist {
+                if p.state() != CandidatePairState::Succeeded {
+                    self.set_pair_state(&p, CandidatePairState::Frozen);
+                    p.binding_request_count.store(0, Ordering::SeqCst);
+                }
+            }
+        }
+        self.request_connectivity_check();
+
         Ok(())
     }
 
@@ -777,12 +1961,16 @@ impl AgentInternal {
         }
     }
 
-    /// Runs the candidate using the provided connection.
+    /// Runs the candidate using the provided connection. Returns false when the connection is
+    /// already read for another local candidate, unless `takes_over` is set: the candidate then
+    /// takes over reading a connection whose reader was removed, whichever other candidates
+    /// share it.
     async fn start_candidate(
         &self,
         candidate: &Arc<dyn Candidate + Send + Sync>,
         initialized_ch: Option<broadcast::Receiver<()>>,
-    ) {
+        takes_over: bool,
+    ) -> bool {
         let (closed_ch_tx, closed_ch_rx) = broadcast::channel(1);
         {
             let closed_ch = candidate.get_closed_ch();
@@ -791,23 +1979,206 @@ impl AgentInternal {
         }
 
         let cand = Arc::clone(candidate);
-        if let (Some(conn), Some(ai)) = (candidate.get_conn(), candidate.get_agent()) {
+        if let Some(conn) = candidate.get_conn() {
+            // Server reflexive candidates gathered through a UDPMuxSrflx share the connection of
+            // the host candidate of the mux, which is already read by the first of them. A
+            // second loop would steal its packets.
+            if !takes_over
+                && self
+                    .local_candidates
+                    .values()
+                    .flatten()
+                    .any(|c| !c.equal(&**candidate) && shares_conn(&**c, &**candidate))
+            {
+                return false;
+            }
+
             let conn = Arc::clone(conn);
-            let addr = candidate.addr().await;
-            let agent_internal = Arc::clone(ai);
+            let inbound_tx = self.inbound_tx.clone();
+            let agent_id = self.agent_id.clone();
             tokio::spawn(async move {
                 let _ = CandidateBase::recv_loop(
+                    agent_id,
                     cand,
-                    agent_internal,
+                    inbound_tx,
                     closed_ch_rx,
                     initialized_ch,
                     conn,
-                    addr,
                 )
                 .await;
             });
+            true
         } else {
-            log::error!("Can't start due to conn is_none");
+            error_event!(agent = self.agent_id; "Can't start due to conn is_none");
+            true
+        }
+    }
+}
+
+/// Returns the transport address of the base of a local candidate, see
+/// [RFC 8445](https://tools.ietf.org/html/rfc8445#section-5.1.1.3). Server and peer reflexive
+/// candidates carry their base as related address, the other ones are their own base.
+fn base_address(c: &(dyn Candidate + Send + Sync)) -> (String, u16) {
+    match (c.candidate_type(), c.related_address()) {
+        (CandidateType::ServerReflexive | CandidateType::PeerReflexive, Some(related)) => {
+            (related.address, related.port)
+        }
+        _ => (c.address(), c.port()),
+    }
+}
+
+/// Returns true if both candidates send and receive through the same connection, e.g. a host
+/// candidate and a server reflexive candidate of a UDPMuxSrflx.
+pub(crate) fn shares_conn(
+    a: &(dyn Candidate + Send + Sync),
+    b: &(dyn Candidate + Send + Sync),
+) -> bool {
+    match (a.get_conn(), b.get_conn()) {
+        (Some(a), Some(b)) => Arc::as_ptr(a).cast::<()>() == Arc::as_ptr(b).cast::<()>(),
+        _ => false,
+    }
+}
+
+/// Returns true if both local candidates have the same transport address and base, e.g. a
+/// server reflexive candidate equal to its host candidate when there is no NAT.
+fn is_redundant_candidate(
+    a: &(dyn Candidate + Send + Sync),
+    b: &(dyn Candidate + Send + Sync),
+) -> bool {
+    a.stream() == b.stream()
+        && a.component() == b.component()
+        && a.network_type() == b.network_type()
+        && a.tcp_type() == b.tcp_type()
+        && a.address() == b.address()
+        && a.port() == b.port()
+        && base_address(a) == base_address(b)
+}
+
+/// Returns true if both candidates are server reflexive candidates of the same public transport
+/// address, e.g. learned by the sockets of several interfaces behind the same NAT. The peer can
+/// reach either one the same way. Those the NAT maps to other ports are kept, as a NAT with port
+/// dependent mappings may only let through the packets to one of them.
+fn is_duplicate_server_reflexive(
+    a: &(dyn Candidate + Send + Sync),
+    b: &(dyn Candidate + Send + Sync),
+) -> bool {
+    a.candidate_type() == CandidateType::ServerReflexive
+        && b.candidate_type() == CandidateType::ServerReflexive
+        && a.stream() == b.stream()
+        && a.component() == b.component()
+        && a.network_type() == b.network_type()
+        && a.tcp_type() == b.tcp_type()
+        && a.address() == b.address()
+        && a.port() == b.port()
+}
+
+/// Returns true if both pairs check the same remote candidate from local candidates with the
+/// same base.
+fn is_redundant_pair(a: &CandidatePair, b: &CandidatePair) -> bool {
+    a.remote.equal(&*b.remote)
+        && a.local.network_type() == b.local.network_type()
+        && a.local.tcp_type() == b.local.tcp_type()
+        && base_address(&*a.local) == base_address(&*b.local)
+}
+
+/// Returns true if the checks of a pair have not started, so that it can be pruned in favor of
+/// a redundant one without losing anything.
+fn is_prunable_pair(p: &CandidatePair) -> bool {
+    matches!(
+        p.state(),
+        CandidatePairState::Frozen | CandidatePairState::Waiting
+    ) && !p.nominated()
+}
+
+/// Returns true if both candidates of a pair are host candidates, so that it connects the
+/// agents directly.
+fn is_host_pair(p: &CandidatePair) -> bool {
+    p.local.candidate_type() == CandidateType::Host
+        && p.remote.candidate_type() == CandidateType::Host
+}
+
+/// Returns the address of the local interface a candidate is based on. Candidates sharing a
+/// socket bound to all interfaces, e.g. on a UDPMux, are based on their own address.
+async fn base_ip(c: &(dyn Candidate + Send + Sync)) -> IpAddr {
+    if let Some(conn) = c.get_conn() {
+        if let Ok(addr) = conn.local_addr().await {
+            if !addr.ip().is_unspecified() {
+                return addr.ip();
+            }
+        }
+    }
+    c.addr().await.ip()
+}
+
+/// Has the TURN client of the relay candidate `local` create a permission for the IP address of
+/// `addr`, by sending it a binding indication, which the peer ignores. The TURN client refreshes
+/// the permission from then on. That first send also has it bind a channel to `addr`, so the
+/// selected pair sends ChannelData, 4 bytes of overhead rather than the 36 of Send indications,
+/// by the time it is nominated. If the bind fails the TURN client keeps sending indications.
+pub(crate) fn create_permission(
+    agent_id: &str,
+    local: &Arc<dyn Candidate + Send + Sync>,
+    addr: SocketAddr,
+) {
+    let conn = match local.get_conn() {
+        Some(conn) => Arc::clone(conn),
+        None => return,
+    };
+
+    let mut msg = Message::new();
+    if let Err(err) = msg.build(&[
+        Box::new(MessageType::new(METHOD_BINDING, CLASS_INDICATION)),
+        Box::new(TransactionId::new()),
+        Box::new(FINGERPRINT),
+    ]) {
+        error_event!(agent = agent_id, error = err; "failed to build binding indication");
+        return;
+    }
+
+    let local = Arc::clone(local);
+    let agent_id = agent_id.to_owned();
+    tokio::spawn(async move {
+        match conn.send_to(&msg.raw, addr).await {
+            Ok(_) => log::trace!(
+                "agent {}: created permission for {} on {}",
+                agent_id,
+                addr,
+                local
+            ),
+            Err(err) => warn_event!(
+                agent = agent_id,
+                remote = addr,
+                local = local,
+                error = err;
+                "failed to create permission"
+            ),
         }
+    });
+}
+
+/// Returns false for candidates of different streams or components, and for TCP candidates which can never
+/// connect to each other, e.g. two passive ones. Following
+/// [RFC 6544](https://tools.ietf.org/html/rfc6544#section-6.2), active candidates pair with
+/// passive ones and simultaneous-open candidates only pair with each other.
+fn can_pair(local: &(dyn Candidate + Send + Sync), remote: &(dyn Candidate + Send + Sync)) -> bool {
+    if local.stream() != remote.stream() || local.component() != remote.component() {
+        return false;
+    }
+    if !local.network_type().is_tcp() {
+        return true;
     }
+
+    !matches!(
+        (local.tcp_type(), remote.tcp_type()),
+        (TcpType::Active, TcpType::Active)
+            | (TcpType::Passive, TcpType::Passive)
+            | (
+                TcpType::SimultaneousOpen,
+                TcpType::Active | TcpType::Passive
+            )
+            | (
+                TcpType::Active | TcpType::Passive,
+                TcpType::SimultaneousOpen
+            )
+    )
 }
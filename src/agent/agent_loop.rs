@@ -0,0 +1,218 @@
+use super::*;
+
+use bytes::Bytes;
+
+/// The number of packets queued for the inbound loop before the receive loops of the candidates
+/// wait for it.
+pub(crate) const INBOUND_QUEUE_SIZE: usize = 1024;
+
+/// The number of queued packets handled under a single acquisition of the agent lock, so that
+/// the API calls waiting for the lock are not starved by a burst of packets.
+const MAX_PACKETS_PER_LOCK: usize = 64;
+
+/// A packet received by a local candidate, queued for the inbound loop.
+pub(crate) enum InboundPacket {
+    /// A STUN message received by `local` from `remote`.
+    Stun {
+        local: Arc<dyn Candidate + Send + Sync>,
+        remote: SocketAddr,
+        raw: Bytes,
+    },
+
+    /// A packet of the application received by `local` from `remote`.
+    Data {
+        local: Arc<dyn Candidate + Send + Sync>,
+        remote: SocketAddr,
+        data: Bytes,
+    },
+}
+
+/// Where the application data received by a local candidate from a remote address goes, once
+/// it has been validated.
+struct Route {
+    agent_conn: Arc<AgentConn>,
+    remote_candidate: Arc<dyn Candidate + Send + Sync>,
+    pair: Arc<CandidatePair>,
+}
+
+/// The routes of the application data, owned by the inbound loop. They are forgotten as soon as
+/// `AgentInternal::routes_generation` changes, i.e. a candidate or a pair was removed.
+struct Routes {
+    agent_id: String,
+    generation: Arc<AtomicU64>,
+    seen_generation: u64,
+    routes: HashMap<(String, SocketAddr), Route>,
+}
+
+impl Routes {
+    fn new(agent_id: String, generation: Arc<AtomicU64>) -> Self {
+        Self {
+            agent_id,
+            seen_generation: generation.load(Ordering::SeqCst),
+            generation,
+            routes: HashMap::new(),
+        }
+    }
+
+    /// Delivers the application data of `packet` along a known route, without the agent lock.
+    /// Returns the packet when it has to be handled by the agent.
+    fn deliver(&mut self, packet: InboundPacket) -> Option<InboundPacket> {
+        let generation = self.generation.load(Ordering::SeqCst);
+        if generation != self.seen_generation {
+            self.routes.clear();
+            self.seen_generation = generation;
+        }
+
+        let (local, remote, data) = match &packet {
+            InboundPacket::Data {
+                local,
+                remote,
+                data,
+            } => (local, *remote, data),
+            InboundPacket::Stun { .. } => return Some(packet),
+        };
+        let route = match self.routes.get(&(local.id(), remote)) {
+            Some(route) => route,
+            None => return Some(packet),
+        };
+
+        route.remote_candidate.seen(false);
+        route.pair.on_packet_received(data.len());
+        if let Err(err) =
+            route
+                .agent_conn
+                .write_packet(data.clone(), remote, Arc::clone(&route.remote_candidate))
+        {
+            // The packets dropped while the buffer is full are counted, see
+            // `AgentConn::packets_dropped`
+            warn_event!(
+                agent = self.agent_id,
+                remote = remote,
+                error = err;
+                "failed to write packet"
+            );
+        }
+
+        None
+    }
+}
+
+impl AgentInternal {
+    /// Runs the inbound loop until the agent is closed. It is the only task handling the packets
+    /// received by the candidates, whose receive loops only queue them here, so that they don't
+    /// contend with one another for the agent lock. The state of the agent stays behind that
+    /// lock though: the application data is delivered without it once the route from its local
+    /// candidate and remote address has been validated, but the STUN messages and the first
+    /// packet of each route are handled under it, along with the API calls and the connectivity
+    /// checks. The packets queued while the lock is held are handled under the same acquisition.
+    pub(crate) async fn run_inbound_loop(
+        agent_internal: Arc<Mutex<Self>>,
+        mut inbound_rx: mpsc::Receiver<InboundPacket>,
+        mut closed_ch_rx: broadcast::Receiver<()>,
+    ) {
+        let mut routes = {
+            let ai = agent_internal.lock().await;
+            Routes::new(ai.agent_id.clone(), Arc::clone(&ai.routes_generation))
+        };
+        // The STUN messages are decoded into the same message, whose raw buffer is reused since
+        // the decoder needs to own it
+        let mut message = Message::new();
+        loop {
+            let packet = tokio::select! {
+                packet = inbound_rx.recv() => match packet {
+                    Some(packet) => packet,
+                    None => return,
+                },
+                _ = closed_ch_rx.recv() => return,
+            };
+            let packet = match routes.deliver(packet) {
+                Some(packet) => packet,
+                None => continue,
+            };
+
+            let mut ai = agent_internal.lock().await;
+            ai.handle_inbound_packet(packet, &mut message, &mut routes, &agent_internal)
+                .await;
+            for _ in 1..MAX_PACKETS_PER_LOCK {
+                let packet = match inbound_rx.try_recv() {
+                    Ok(packet) => packet,
+                    Err(_) => break,
+                };
+                if let Some(packet) = routes.deliver(packet) {
+                    ai.handle_inbound_packet(packet, &mut message, &mut routes, &agent_internal)
+                        .await;
+                }
+            }
+        }
+    }
+
+    async fn handle_inbound_packet(
+        &mut self,
+        packet: InboundPacket,
+        message: &mut Message,
+        routes: &mut Routes,
+        agent_internal: &Arc<Mutex<Self>>,
+    ) {
+        match packet {
+            InboundPacket::Stun { local, remote, raw } => {
+                message.reset();
+                message.raw.extend_from_slice(&raw);
+                if let Err(err) = message.decode() {
+                    warn_event!(
+                        agent = self.agent_id,
+                        remote = remote,
+                        local = local.addr().await,
+                        error = err;
+                        "Failed to handle decode ICE"
+                    );
+                    return;
+                }
+
+                self.handle_inbound(message, &local, remote, Arc::clone(agent_internal))
+                    .await;
+            }
+            InboundPacket::Data {
+                local,
+                remote,
+                data,
+            } => {
+                let remote_candidate = if let Some(remote_candidate) = self
+                    .validate_non_stun_traffic(&local, remote, data.len())
+                    .await
+                {
+                    remote_candidate
+                } else {
+                    warn_event!(
+                        agent = self.agent_id,
+                        remote = remote;
+                        "Discarded message, not a valid remote candidate"
+                    );
+                    return;
+                };
+                if let Some(agent_conn) = self.agent_conn(local.stream(), local.component()) {
+                    // The next packets of the pair skip the agent lock
+                    if let Some(pair) = self.find_pair(&local, &remote_candidate).await {
+                        routes.routes.insert(
+                            (local.id(), remote),
+                            Route {
+                                agent_conn: Arc::clone(agent_conn),
+                                remote_candidate: Arc::clone(&remote_candidate),
+                                pair,
+                            },
+                        );
+                    }
+                    if let Err(err) = agent_conn.write_packet(data, remote, remote_candidate) {
+                        // The packets dropped while the buffer is full are counted, see
+                        // `AgentConn::packets_dropped`
+                        warn_event!(
+                            agent = self.agent_id,
+                            remote = remote,
+                            error = err;
+                            "failed to write packet"
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+/// Paces the STUN transactions started by the agent, i.e. its connectivity checks and the
+/// binding and allocation requests of the gathering, so that a new one starts at most every
+/// Ta, see [RFC 8445 Section 14.2](https://tools.ietf.org/html/rfc8445#section-14.2).
+/// Bursts of transactions may otherwise be dropped by NATs which rate-limit new mappings.
+pub(crate) struct Pacer {
+    interval: Duration,
+    // When the next transaction may start
+    next: Mutex<Instant>,
+}
+
+impl Pacer {
+    /// Creates a pacer starting a transaction every `interval`. An interval of 0 disables
+    /// pacing.
+    pub(crate) fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Returns true if a transaction may start now.
+    pub(crate) fn is_ready(&self) -> bool {
+        Instant::now() >= *self.next.lock().unwrap()
+    }
+
+    /// Reserves the next slot for a transaction starting now, e.g. a triggered check which
+    /// can't wait, delaying the following ones.
+    pub(crate) fn acquire_now(&self) {
+        let now = Instant::now();
+        let mut next = self.next.lock().unwrap();
+        *next = std::cmp::max(*next, now) + self.interval;
+    }
+
+    /// Waits until the next slot, which is reserved for the transaction of the caller.
+    pub(crate) async fn acquire(&self) {
+        let at = {
+            let now = Instant::now();
+            let mut next = self.next.lock().unwrap();
+            let at = std::cmp::max(*next, now);
+            *next = at + self.interval;
+            at
+        };
+
+        tokio::time::sleep_until(at).await;
+    }
+
+    pub(crate) const fn interval(&self) -> Duration {
+        self.interval
+    }
+}
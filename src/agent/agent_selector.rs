@@ -1,6 +1,7 @@
 use crate::agent::agent_internal::*;
 use crate::candidate::*;
 use crate::control::*;
+use crate::errors::*;
 use crate::priority::*;
 use crate::use_candidate::*;
 
@@ -80,52 +81,211 @@ impl AgentInternal {
                     > self.relay_acceptance_min_wait.as_nanos()
             }
             CandidateType::Unspecified => {
-                log::error!(
-                    "is_nominatable invalid candidate type {}",
-                    c.candidate_type()
+                error_event!(
+                    agent = self.agent_id,
+                    candidate = c;
+                    "is_nominatable invalid candidate type {}", c.candidate_type()
                 );
                 false
             }
         }
     }
 
-    async fn nominate_pair(&mut self) {
-        if let Some(pair) = &self.nominated_pair {
-            // The controlling agent MUST include the USE-CANDIDATE attribute in
-            // order to nominate a candidate pair (Section 8.1.1).  The controlled
-            // agent MUST NOT include the USE-CANDIDATE attribute in a Binding
-            // request.
-
-            let (msg, result) = {
-                let username = self.remote_ufrag.clone() + ":" + self.local_ufrag.as_str();
-                let mut msg = Message::new();
-                let result = msg.build(&[
-                    Box::new(BINDING_REQUEST),
-                    Box::new(TransactionId::new()),
-                    Box::new(Username::new(ATTR_USERNAME, username)),
-                    Box::new(UseCandidateAttr::default()),
-                    Box::new(AttrControlling(self.tie_breaker)),
-                    Box::new(PriorityAttr(pair.local.priority())),
-                    Box::new(MessageIntegrity::new_short_term_integrity(
-                        self.remote_pwd.clone(),
-                    )),
-                    Box::new(FINGERPRINT),
-                ]);
-                (msg, result)
+    /// Returns the priority sent in the PRIORITY attribute of the checks from local, see
+    /// `AgentConfig::prflx_priority`.
+    pub(crate) fn check_priority(&self, local: &Arc<dyn Candidate + Send + Sync>) -> u32 {
+        (*self.prflx_priority)
+            .as_ref()
+            .map_or_else(|| local.priority(), |f| f(&**local))
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(
+            agent = %self.agent_id,
+            local = %pair.local.id(),
+            remote = %pair.remote.id(),
+        ))
+    )]
+    async fn nominate_pair(&mut self, pair: &Arc<CandidatePair>) {
+        // The controlling agent MUST include the USE-CANDIDATE attribute in
+        // order to nominate a candidate pair (Section 8.1.1).  The controlled
+        // agent MUST NOT include the USE-CANDIDATE attribute in a Binding
+        // request.
+
+        let (msg, result) = {
+            let username = self.remote_ufrag.clone() + ":" + self.local_ufrag.as_str();
+            let mut msg = Message::new();
+            let result = msg.build(&[
+                Box::new(BINDING_REQUEST),
+                Box::new(TransactionId::new()),
+                Box::new(Username::new(ATTR_USERNAME, username)),
+                Box::new(UseCandidateAttr::default()),
+                Box::new(AttrControlling(self.tie_breaker)),
+                Box::new(PriorityAttr(self.check_priority(&pair.local))),
+                Box::new(MessageIntegrity::new_short_term_integrity(
+                    self.remote_pwd.clone(),
+                )),
+                Box::new(FINGERPRINT),
+            ]);
+            (msg, result)
+        };
+
+        if let Err(err) = result {
+            error_event!(
+                agent = self.agent_id,
+                pair = pair,
+                error = err;
+                "Failed to build the nomination request"
+            );
+        } else {
+            log::trace!(
+                "ping STUN (nominate candidate pair from {} to {}",
+                pair.local,
+                pair.remote
+            );
+            let local = pair.local.clone();
+            let remote = pair.remote.clone();
+            self.send_binding_request(&msg, &local, &remote).await;
+        }
+    }
+
+    /// Selects the valid pair `p` in place of the one picked by priority. The controlling agent
+    /// nominates it, which needs renomination to replace a selected pair, and selects it once the
+    /// nominating check succeeds, whereas the controlled agent may only select a pair the
+    /// controlling agent nominated.
+    pub(crate) async fn select_pair_manually(
+        &mut self,
+        p: Arc<CandidatePair>,
+    ) -> Result<(), IceError> {
+        if p.state() != CandidatePairState::Succeeded {
+            return Err(IceError::CandidatePairNotSelectable(format!(
+                "pair {} is {}, not succeeded",
+                p,
+                p.state()
+            )));
+        }
+
+        let selected_pair = self.get_selected_pair(p.stream(), p.component()).await;
+        if selected_pair.as_ref() == Some(&p) {
+            return Ok(());
+        }
+
+        if self.is_controlling {
+            let renomination = self
+                .negotiated_ice_options()
+                .map_or(false, |o| o.renomination);
+            if selected_pair.is_some() && !renomination && !self.nominates_aggressively() {
+                return Err(IceError::CandidatePairNotSelectable(format!(
+                    "pair {} can't be nominated without renomination",
+                    p
+                )));
+            }
+            self.nominated_pairs
+                .insert((p.stream(), p.component()), Arc::clone(&p));
+            self.nominate_pair(&p).await;
+            return Ok(());
+        } else if !p.nominated() {
+            return Err(IceError::CandidatePairNotSelectable(format!(
+                "pair {} hasn't been nominated by the controlling agent",
+                p
+            )));
+        }
+
+        self.set_selected_pair(Some(p)).await;
+        Ok(())
+    }
+
+    /// Marks failed the selected pairs whose latest `failover_after_lost_checks` binding
+    /// requests were all lost. The controlling agent nominates the best other valid pair of
+    /// their component in their place, which needs renomination, and selects it once the
+    /// nomination succeeds, see `select_pair_manually`. The controlled agent waits for that
+    /// nomination.
+    pub(crate) async fn fail_over_dead_selected_pairs(&mut self) {
+        if self.failover_after_lost_checks == 0 {
+            return;
+        }
+
+        for selected_pair in self.get_selected_pairs().await {
+            if selected_pair.lost_checks_in_a_row() < usize::from(self.failover_after_lost_checks) {
+                continue;
+            }
+
+            let agent_conn =
+                match self.agent_conn(selected_pair.stream(), selected_pair.component()) {
+                    Some(agent_conn) => Arc::clone(agent_conn),
+                    None => continue,
+                };
+
+            if selected_pair.state() == CandidatePairState::Failed {
+                // The replacement is nominated again until its nomination succeeds
+                if self.is_controlling {
+                    if let Some(p) = self.nominated_pairs.get(&agent_conn.key()).cloned() {
+                        if p != selected_pair {
+                            self.nominate_pair(&p).await;
+                        }
+                    }
+                }
+                continue;
+            }
+
+            if !self.is_controlling {
+                log::info!(
+                    "agent {}: selected pair {} is dead, waiting for another pair to be nominated",
+                    self.agent_id,
+                    selected_pair
+                );
+                self.set_pair_state(&selected_pair, CandidatePairState::Failed);
+                continue;
+            }
+
+            let backup = {
+                let checklist = agent_conn.checklist.lock().await;
+                checklist
+                    .iter()
+                    .filter(|p| p.state() == CandidatePairState::Succeeded && **p != selected_pair)
+                    .fold(None, |best: Option<&Arc<CandidatePair>>, p| match best {
+                        Some(b) if !p.is_preferred_to(b) => Some(b),
+                        _ => Some(p),
+                    })
+                    .cloned()
+            };
+            let backup = match backup {
+                Some(backup) => backup,
+                None => {
+                    log::trace!(
+                        "agent {}: selected pair {} is dead, but no valid pair is left",
+                        self.agent_id,
+                        selected_pair
+                    );
+                    continue;
+                }
             };
 
-            if let Err(err) = result {
-                log::error!("{}", err);
-            } else {
+            // Replacing a selected pair takes renomination, see `select_pair_manually`
+            let renomination = self
+                .negotiated_ice_options()
+                .map_or(false, |o| o.renomination);
+            if !renomination && !self.nominates_aggressively() {
                 log::trace!(
-                    "ping STUN (nominate candidate pair from {} to {}",
-                    pair.local,
-                    pair.remote
+                    "agent {}: selected pair {} is dead, failing over to {} needs renomination",
+                    self.agent_id,
+                    selected_pair,
+                    backup
                 );
-                let local = pair.local.clone();
-                let remote = pair.remote.clone();
-                self.send_binding_request(&msg, &local, &remote).await;
+                continue;
             }
+
+            log::info!(
+                "agent {}: selected pair {} is dead, nominating {}",
+                self.agent_id,
+                selected_pair,
+                backup
+            );
+            self.set_pair_state(&selected_pair, CandidatePairState::Failed);
+            self.nominated_pairs
+                .insert(agent_conn.key(), Arc::clone(&backup));
+            self.nominate_pair(&backup).await;
         }
     }
 
@@ -150,6 +310,19 @@ impl AgentInternal {
         local: &Arc<dyn Candidate + Send + Sync>,
         remote: &Arc<dyn Candidate + Send + Sync>,
     ) {
+        // A check sent on a frozen pair, e.g. a triggered one, unfreezes it
+        if let Some(p) = self.find_pair(local, remote).await {
+            if p.state() == CandidatePairState::Frozen {
+                self.set_pair_state(&p, CandidatePairState::Waiting);
+            }
+        }
+
+        // Triggered checks are dropped while checks are paused, the pair waiting to be checked
+        // once they are resumed
+        if self.checks_paused_at.is_some() {
+            return;
+        }
+
         if self.is_controlling {
             ControllingSelector::ping_candidate(self, local, remote).await;
         } else {
@@ -182,6 +355,11 @@ impl AgentInternal {
         } else {
             ControlledSelector::handle_binding_request(self, m, local, remote).await;
         }
+
+        if let Some(p) = self.find_pair(local, remote).await {
+            p.on_request_received();
+            self.report_valid_pair(&p);
+        }
     }
 }
 
@@ -189,7 +367,7 @@ impl AgentInternal {
 impl ControllingSelector for AgentInternal {
     fn start(&mut self) {
         self.start_time = Instant::now();
-        self.nominated_pair = None;
+        self.nominated_pairs.clear();
     }
 
     async fn contact_candidates(&mut self) {
@@ -199,36 +377,56 @@ impl ControllingSelector for AgentInternal {
             log::trace!("now falling back to full agent");
         }
 
-        if self.agent_conn.get_selected_pair().await.is_some() {
+        if self.is_every_component_selected().await {
+            self.fail_over_dead_selected_pairs().await;
             if self.validate_selected_pair().await {
                 log::trace!("checking keepalive");
                 self.check_keepalive().await;
+                self.check_consent().await;
+            }
+            return;
+        }
+
+        // Keep the components which have selected a pair alive while the others catch up
+        self.check_keepalive().await;
+
+        if self.nominates_aggressively() {
+            // Every check nominates, the first pair to succeed gets selected
+            self.ping_all_candidates().await;
+            return;
+        }
+
+        let mut has_unnominated_component = false;
+        for agent_conn in self.agent_conns.clone() {
+            if agent_conn.get_selected_pair().await.is_some() {
+                continue;
+            }
+
+            if let Some(p) = self.nominated_pairs.get(&agent_conn.key()).cloned() {
+                self.nominate_pair(&p).await;
+                continue;
             }
-        } else if self.nominated_pair.is_some() {
-            self.nominate_pair().await;
-        } else {
-            let has_nominated_pair =
-                if let Some(p) = self.agent_conn.get_best_valid_candidate_pair().await {
-                    self.is_nominatable(&p.local).await && self.is_nominatable(&p.remote).await
-                } else {
-                    false
-                };
 
-            if has_nominated_pair {
-                if let Some(p) = self.agent_conn.get_best_valid_candidate_pair().await {
+            if let Some(p) = agent_conn.get_best_valid_candidate_pair().await {
+                if self.is_nominatable(&p.local).await && self.is_nominatable(&p.remote).await {
                     log::trace!(
                         "Nominatable pair found, nominating ({}, {})",
                         p.local.to_string(),
                         p.remote.to_string()
                     );
-                    p.nominated.store(true, Ordering::SeqCst);
-                    self.nominated_pair = Some(p);
+                    self.set_pair_nominated(&p);
+                    self.nominated_pairs
+                        .insert(agent_conn.key(), Arc::clone(&p));
+                    self.nominate_pair(&p).await;
+                    continue;
                 }
-
-                self.nominate_pair().await;
-            } else {
-                self.ping_all_candidates().await;
             }
+
+            has_unnominated_component = true;
+        }
+
+        if has_unnominated_component {
+            self.ping_all_candidates().await;
         }
     }
 
@@ -239,23 +437,34 @@ impl ControllingSelector for AgentInternal {
     ) {
         let (msg, result) = {
             let username = self.remote_ufrag.clone() + ":" + self.local_ufrag.as_str();
-            let mut msg = Message::new();
-            let result = msg.build(&[
+            let mut setters: Vec<Box<dyn Setter>> = vec![
                 Box::new(BINDING_REQUEST),
                 Box::new(TransactionId::new()),
                 Box::new(Username::new(ATTR_USERNAME, username)),
-                Box::new(AttrControlling(self.tie_breaker)),
-                Box::new(PriorityAttr(local.priority())),
-                Box::new(MessageIntegrity::new_short_term_integrity(
-                    self.remote_pwd.clone(),
-                )),
-                Box::new(FINGERPRINT),
-            ]);
+            ];
+            if self.nominates_aggressively() {
+                setters.push(Box::new(UseCandidateAttr::default()));
+            }
+            setters.push(Box::new(AttrControlling(self.tie_breaker)));
+            setters.push(Box::new(PriorityAttr(self.check_priority(local))));
+            setters.push(Box::new(MessageIntegrity::new_short_term_integrity(
+                self.remote_pwd.clone(),
+            )));
+            setters.push(Box::new(FINGERPRINT));
+
+            let mut msg = Message::new();
+            let result = msg.build(&setters);
             (msg, result)
         };
 
         if let Err(err) = result {
-            log::error!("{}", err);
+            error_event!(
+                agent = self.agent_id,
+                local = local,
+                remote = remote,
+                error = err;
+                "Failed to build the binding request"
+            );
         } else {
             self.send_binding_request(&msg, local, remote).await;
         }
@@ -283,11 +492,14 @@ impl ControllingSelector for AgentInternal {
                 remote,
                 local
             );
-            let selected_pair_is_none = self.agent_conn.get_selected_pair().await.is_none();
+            let selected_pair = self
+                .get_selected_pair(local.stream(), local.component())
+                .await;
+            let selected_pair_is_none = selected_pair.is_none();
 
             if let Some(p) = self.find_pair(local, remote).await {
-                p.state
-                    .store(CandidatePairState::Succeeded as u8, Ordering::SeqCst);
+                p.on_response_received(Instant::now().duration_since(pending_request.timestamp));
+                self.set_pair_state(&p, CandidatePairState::Succeeded);
                 log::trace!(
                     "Found valid candidate pair: {}, p.state: {}, isUseCandidate: {}, {}",
                     p,
@@ -295,18 +507,26 @@ impl ControllingSelector for AgentInternal {
                     pending_request.is_use_candidate,
                     selected_pair_is_none
                 );
-                if pending_request.is_use_candidate && selected_pair_is_none {
+                // A pair renominated in place of the selected one, see `select_pair_manually`,
+                // replaces it once its nomination succeeds
+                let renominated = selected_pair.as_ref() != Some(&p)
+                    && self.nominated_pairs.get(&(p.stream(), p.component())) == Some(&p);
+                if pending_request.is_use_candidate && (selected_pair_is_none || renominated) {
                     self.set_selected_pair(Some(Arc::clone(&p))).await;
                 }
             } else {
                 // This shouldn't happen
-                log::error!("Success response from invalid candidate pair");
+                error_event!(
+                    agent = self.agent_id,
+                    remote = remote;
+                    "Success response from invalid candidate pair"
+                );
             }
         } else {
-            log::warn!(
-                "discard message from ({}), unknown TransactionID 0x{:?}",
-                remote,
-                m.transaction_id
+            warn_event!(
+                agent = self.agent_id,
+                remote = remote;
+                "discard message, unknown TransactionID 0x{:?}", m.transaction_id
             );
         }
     }
@@ -325,14 +545,31 @@ impl ControllingSelector for AgentInternal {
                 "controllingSelector: after findPair {}, p.state: {}, {}, {}",
                 p,
                 p.state.load(Ordering::SeqCst),
-                self.nominated_pair.is_none(),
-                self.agent_conn.get_selected_pair().await.is_none()
+                !self
+                    .nominated_pairs
+                    .contains_key(&(p.stream(), p.component())),
+                self.get_selected_pair(p.stream(), p.component())
+                    .await
+                    .is_none()
             );
-            if p.state.load(Ordering::SeqCst) == CandidatePairState::Succeeded as u8
-                && self.nominated_pair.is_none()
-                && self.agent_conn.get_selected_pair().await.is_none()
+            if p.state.load(Ordering::SeqCst) != CandidatePairState::Succeeded as u8 {
+                // Triggered check, the request shows the pair is likely to work
+                // https://tools.ietf.org/html/rfc8445#section-7.3.1.4
+                self.ping_candidate(local, remote).await;
+            } else if !self.nominates_aggressively()
+                && !self
+                    .nominated_pairs
+                    .contains_key(&(p.stream(), p.component()))
+                && self
+                    .get_selected_pair(p.stream(), p.component())
+                    .await
+                    .is_none()
             {
-                if let Some(best_pair) = self.agent_conn.get_best_available_candidate_pair().await {
+                let best_pair = match self.agent_conn(p.stream(), p.component()) {
+                    Some(agent_conn) => agent_conn.get_best_available_candidate_pair().await,
+                    None => None,
+                };
+                if let Some(best_pair) = best_pair {
                     log::trace!(
                         "controllingSelector: getBestAvailableCandidatePair {}",
                         best_pair
@@ -343,8 +580,9 @@ impl ControllingSelector for AgentInternal {
                     {
                         log::trace!("The candidate ({}, {}) is the best candidate available, marking it as nominated",
                             p.local, p.remote);
-                        self.nominated_pair = Some(p);
-                        self.nominate_pair().await;
+                        self.nominated_pairs
+                            .insert((p.stream(), p.component()), Arc::clone(&p));
+                        self.nominate_pair(&p).await;
                     }
                 } else {
                     log::trace!("No best pair available");
@@ -353,6 +591,7 @@ impl ControllingSelector for AgentInternal {
         } else {
             log::trace!("controllingSelector: addPair");
             self.add_pair(local.clone(), remote.clone()).await;
+            self.ping_candidate(local, remote).await;
         }
     }
 }
@@ -365,12 +604,16 @@ impl ControlledSelector for AgentInternal {
         // A lite selector should not contact candidates
         if self.lite {
             self.validate_selected_pair().await;
-        } else if self.agent_conn.get_selected_pair().await.is_some() {
+        } else if self.is_every_component_selected().await {
+            self.fail_over_dead_selected_pairs().await;
             if self.validate_selected_pair().await {
                 log::trace!("checking keepalive");
                 self.check_keepalive().await;
+                self.check_consent().await;
             }
         } else {
+            // Keep the components which have selected a pair alive while the others catch up
+            self.check_keepalive().await;
             self.ping_all_candidates().await;
         }
     }
@@ -388,7 +631,7 @@ impl ControlledSelector for AgentInternal {
                 Box::new(TransactionId::new()),
                 Box::new(Username::new(ATTR_USERNAME, username)),
                 Box::new(AttrControlled(self.tie_breaker)),
-                Box::new(PriorityAttr(local.priority())),
+                Box::new(PriorityAttr(self.check_priority(local))),
                 Box::new(MessageIntegrity::new_short_term_integrity(
                     self.remote_pwd.clone(),
                 )),
@@ -398,7 +641,13 @@ impl ControlledSelector for AgentInternal {
         };
 
         if let Err(err) = result {
-            log::error!("{}", err);
+            error_event!(
+                agent = self.agent_id,
+                local = local,
+                remote = remote,
+                error = err;
+                "Failed to build the binding request"
+            );
         } else {
             self.send_binding_request(&msg, local, remote).await;
         }
@@ -434,18 +683,22 @@ impl ControlledSelector for AgentInternal {
             );
 
             if let Some(p) = self.find_pair(local, remote).await {
-                p.state
-                    .store(CandidatePairState::Succeeded as u8, Ordering::SeqCst);
+                p.on_response_received(Instant::now().duration_since(pending_request.timestamp));
+                self.set_pair_state(&p, CandidatePairState::Succeeded);
                 log::trace!("Found valid candidate pair: {}", p);
             } else {
                 // This shouldn't happen
-                log::error!("Success response from invalid candidate pair");
+                error_event!(
+                    agent = self.agent_id,
+                    remote = remote;
+                    "Success response from invalid candidate pair"
+                );
             }
         } else {
-            log::warn!(
-                "discard message from ({}), unknown TransactionID 0x{:?}",
-                remote,
-                m.transaction_id
+            warn_event!(
+                agent = self.agent_id,
+                remote = remote;
+                "discard message, unknown TransactionID 0x{:?}", m.transaction_id
             );
         }
     }
@@ -462,7 +715,22 @@ impl ControlledSelector for AgentInternal {
 
         if let Some(p) = self.find_pair(local, remote).await {
             let use_candidate = m.contains(ATTR_USE_CANDIDATE);
-            if use_candidate {
+            if self.lite {
+                // A lite agent never sends checks of its own, so it cannot wait for a check of
+                // the pair to succeed. It accepts the nomination of the full agent as is.
+                // https://tools.ietf.org/html/rfc8445#section-7.3.1.5
+                if use_candidate {
+                    self.set_pair_state(&p, CandidatePairState::Succeeded);
+                    if self
+                        .get_selected_pair(p.stream(), p.component())
+                        .await
+                        .is_none()
+                    {
+                        self.set_selected_pair(Some(Arc::clone(&p))).await;
+                    }
+                }
+                self.send_binding_success(m, local, remote).await;
+            } else if use_candidate {
                 // https://tools.ietf.org/html/rfc8445#section-7.3.1.5
 
                 if p.state.load(Ordering::SeqCst) == CandidatePairState::Succeeded as u8 {
@@ -470,7 +738,14 @@ impl ControlledSelector for AgentInternal {
                     // previously sent by this pair produced a successful response and
                     // generated a valid pair (Section 7.2.5.3.2).  The agent sets the
                     // nominated flag value of the valid pair to true.
-                    if self.agent_conn.get_selected_pair().await.is_none() {
+                    // With renomination, the pair nominated last is the selected one.
+                    let renomination = self
+                        .negotiated_ice_options()
+                        .map_or(false, |o| o.renomination);
+                    let selected_pair = self.get_selected_pair(p.stream(), p.component()).await;
+                    if selected_pair.is_none()
+                        || (renomination && selected_pair.map_or(false, |s| s != p))
+                    {
                         self.set_selected_pair(Some(Arc::clone(&p))).await;
                     }
                     self.send_binding_success(m, local, remote).await;
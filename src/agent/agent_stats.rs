@@ -1,15 +1,21 @@
-use crate::candidate::{CandidatePairState, CandidateType};
+use crate::candidate::{get_timestamp, CandidatePairState, CandidateType};
 
 use crate::agent::agent_internal::AgentInternal;
 use crate::network_type::NetworkType;
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 use tokio::time::Instant;
 
 /// Contains ICE candidate pair statistics.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CandidatePairStats {
     /// The timestamp associated with this struct.
+    #[cfg_attr(feature = "serde", serde(with = "crate::stats::serde_instant"))]
     pub timestamp: Instant,
 
+    /// The id of the agent, see `AgentConfig::agent_id`.
+    pub agent_id: String,
+
     /// The id of the local candidate.
     pub local_candidate_id: String,
 
@@ -23,6 +29,10 @@ pub struct CandidatePairStats {
     /// if it is the highest-priority one amongst those whose nominated flag is set.
     pub nominated: bool,
 
+    /// It is true when the checks of this pair succeeded through the public mapping of the NAT
+    /// both agents are behind, see `CandidatePair::hairpinned`.
+    pub hairpinned: bool,
+
     /// The total number of packets sent on this candidate pair.
     pub packets_sent: u32,
 
@@ -39,21 +49,26 @@ pub struct CandidatePairStats {
 
     /// The timestamp at which the last packet was sent on this particular candidate pair, excluding
     /// STUN packets.
+    #[cfg_attr(feature = "serde", serde(with = "crate::stats::serde_instant"))]
     pub last_packet_sent_timestamp: Instant,
 
     /// The timestamp at which the last packet was received on this particular candidate pair,
     /// excluding STUN packets.
+    #[cfg_attr(feature = "serde", serde(with = "crate::stats::serde_instant"))]
     pub last_packet_received_timestamp: Instant,
 
     /// The timestamp at which the first STUN request was sent on this particular candidate pair.
+    #[cfg_attr(feature = "serde", serde(with = "crate::stats::serde_instant"))]
     pub first_request_timestamp: Instant,
 
     /// The timestamp at which the last STUN request was sent on this particular candidate pair.
     /// The average interval between two consecutive connectivity checks sent can be calculated with
     /// (last_request_timestamp - first_request_timestamp) / requests_sent.
+    #[cfg_attr(feature = "serde", serde(with = "crate::stats::serde_instant"))]
     pub last_request_timestamp: Instant,
 
     /// Timestamp at which the last STUN response was received on this particular candidate pair.
+    #[cfg_attr(feature = "serde", serde(with = "crate::stats::serde_instant"))]
     pub last_response_timestamp: Instant,
 
     /// The sum of all round trip time measurements in seconds since the beginning of the session,
@@ -66,6 +81,18 @@ pub struct CandidatePairStats {
     /// including those that are sent for consent verification.
     pub current_round_trip_time: f64,
 
+    /// The round trip time in seconds, smoothed over the measurements of `current_round_trip_time`
+    /// with an exponentially weighted moving average of gain 1/8, like the SRTT of RFC 6298.
+    pub smoothed_round_trip_time: f64,
+
+    /// The variance, in seconds squared, of the round trip times of the latest 32 connectivity
+    /// check and consent requests which were answered.
+    pub round_trip_time_variance: f64,
+
+    /// The share, from 0 to 1, of the latest 32 connectivity check and consent requests which
+    /// timed out without a response.
+    pub check_loss_ratio: f64,
+
     /// It is calculated by the underlying congestion control by combining the available bitrate for
     /// all the outgoing RTP streams using this candidate pair. The bitrate measurement does not
     /// count the size of the IP or other transport layers like TCP or UDP. It is similar to the
@@ -109,6 +136,7 @@ pub struct CandidatePairStats {
     pub consent_requests_sent: u64,
 
     /// The timestamp at which the latest valid STUN binding response expired.
+    #[cfg_attr(feature = "serde", serde(with = "crate::stats::serde_instant"))]
     pub consent_expired_timestamp: Instant,
 }
 
@@ -116,6 +144,7 @@ impl Default for CandidatePairStats {
     fn default() -> Self {
         Self {
             timestamp: Instant::now(),
+            agent_id: String::new(),
             local_candidate_id: String::new(),
             remote_candidate_id: String::new(),
             state: CandidatePairState::default(),
@@ -131,6 +160,9 @@ impl Default for CandidatePairStats {
             last_response_timestamp: Instant::now(),
             total_round_trip_time: 0.0,
             current_round_trip_time: 0.0,
+            smoothed_round_trip_time: 0.0,
+            round_trip_time_variance: 0.0,
+            check_loss_ratio: 0.0,
             available_outgoing_bitrate: 0.0,
             available_incoming_bitrate: 0.0,
             circuit_breaker_trigger_count: 0,
@@ -148,10 +180,15 @@ impl Default for CandidatePairStats {
 
 /// Contains ICE candidate statistics related to the `ICETransport` objects.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CandidateStats {
     // The timestamp associated with this struct.
+    #[cfg_attr(feature = "serde", serde(with = "crate::stats::serde_instant"))]
     pub timestamp: Instant,
 
+    /// The id of the agent, see `AgentConfig::agent_id`.
+    pub agent_id: String,
+
     /// The candidate id.
     pub id: String,
 
@@ -186,6 +223,10 @@ pub struct CandidateStats {
     /// for local candidates. Valid values for the TURN url protocol is one of udp, tcp, or tls.
     pub relay_protocol: String,
 
+    /// The time left before the TURN allocation of a local relay candidate expires unless it is
+    /// refreshed, see `Candidate::relay_lifetime`.
+    pub relay_lifetime: Option<Duration>,
+
     /// It is true if the candidate has been deleted/freed. For host candidates, this means that any
     /// network resources (typically a socket) associated with the candidate have been released. For
     /// TURN candidates, this means the TURN allocation is no longer active.
@@ -198,6 +239,7 @@ impl Default for CandidateStats {
     fn default() -> Self {
         Self {
             timestamp: Instant::now(),
+            agent_id: String::new(),
             id: String::new(),
             network_type: NetworkType::default(),
             ip: String::new(),
@@ -206,6 +248,7 @@ impl Default for CandidateStats {
             priority: 0,
             url: String::new(),
             relay_protocol: String::new(),
+            relay_lifetime: None,
             deleted: false,
         }
     }
@@ -214,18 +257,45 @@ impl Default for CandidateStats {
 impl AgentInternal {
     /// Returns a list of candidate pair stats.
     pub(crate) async fn get_candidate_pairs_stats(&self) -> Vec<CandidatePairStats> {
-        let checklist = self.agent_conn.checklist.lock().await;
-        let mut res = Vec::with_capacity(checklist.len());
-        for cp in &*checklist {
-            let stat = CandidatePairStats {
-                timestamp: Instant::now(),
-                local_candidate_id: cp.local.id(),
-                remote_candidate_id: cp.remote.id(),
-                state: cp.state.load(Ordering::SeqCst).into(),
-                nominated: cp.nominated.load(Ordering::SeqCst),
-                ..CandidatePairStats::default()
-            };
-            res.push(stat);
+        let mut res = vec![];
+        for agent_conn in &self.agent_conns {
+            let checklist = agent_conn.checklist.lock().await;
+            for cp in &*checklist {
+                // The timestamps which were never set default to now, like those of the
+                // default stats
+                let now = Instant::now();
+                let stat = CandidatePairStats {
+                    timestamp: now,
+                    agent_id: self.agent_id.clone(),
+                    local_candidate_id: cp.local.id(),
+                    remote_candidate_id: cp.remote.id(),
+                    state: cp.state.load(Ordering::SeqCst).into(),
+                    nominated: cp.nominated.load(Ordering::SeqCst),
+                    hairpinned: cp.hairpinned(),
+                    packets_sent: cp.packets_sent.load(Ordering::SeqCst) as u32,
+                    packets_received: cp.packets_received.load(Ordering::SeqCst) as u32,
+                    bytes_sent: cp.bytes_sent.load(Ordering::SeqCst),
+                    bytes_received: cp.bytes_received.load(Ordering::SeqCst),
+                    last_packet_sent_timestamp: get_timestamp(&cp.last_packet_sent).unwrap_or(now),
+                    last_packet_received_timestamp: get_timestamp(&cp.last_packet_received)
+                        .unwrap_or(now),
+                    first_request_timestamp: get_timestamp(&cp.first_request_sent).unwrap_or(now),
+                    last_request_timestamp: get_timestamp(&cp.last_request_sent).unwrap_or(now),
+                    last_response_timestamp: get_timestamp(&cp.last_response_received)
+                        .unwrap_or(now),
+                    total_round_trip_time: to_secs(&cp.total_round_trip_time),
+                    current_round_trip_time: to_secs(&cp.current_round_trip_time),
+                    smoothed_round_trip_time: to_secs(&cp.smoothed_round_trip_time),
+                    round_trip_time_variance: cp.round_trip_time_variance(),
+                    check_loss_ratio: cp.check_loss_ratio(),
+                    requests_received: cp.requests_received.load(Ordering::SeqCst),
+                    requests_sent: cp.requests_sent.load(Ordering::SeqCst),
+                    responses_received: cp.responses_received.load(Ordering::SeqCst),
+                    responses_sent: cp.responses_sent.load(Ordering::SeqCst),
+                    ..CandidatePairStats::default()
+                };
+                res.push(stat);
+            }
         }
         res
     }
@@ -237,6 +307,7 @@ impl AgentInternal {
             for c in local_candidates {
                 let stat = CandidateStats {
                     timestamp: Instant::now(),
+                    agent_id: self.agent_id.clone(),
                     id: c.id(),
                     network_type: *network_type,
                     ip: c.address(),
@@ -244,7 +315,8 @@ impl AgentInternal {
                     candidate_type: c.candidate_type(),
                     priority: c.priority(),
                     // URL string
-                    relay_protocol: "udp".to_owned(),
+                    relay_protocol: c.relay_protocol(),
+                    relay_lifetime: c.relay_lifetime(),
                     // Deleted bool
                     ..CandidateStats::default()
                 };
@@ -261,6 +333,7 @@ impl AgentInternal {
             for c in remote_candidates {
                 let stat = CandidateStats {
                     timestamp: Instant::now(),
+                    agent_id: self.agent_id.clone(),
                     id: c.id(),
                     network_type: *network_type,
                     ip: c.address(),
@@ -278,3 +351,8 @@ impl AgentInternal {
         res
     }
 }
+
+/// Converts a duration stored as nanoseconds into seconds.
+fn to_secs(nanos: &AtomicU64) -> f64 {
+    Duration::from_nanos(nanos.load(Ordering::SeqCst)).as_secs_f64()
+}
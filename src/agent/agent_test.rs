@@ -6,36 +6,118 @@ use crate::candidate::candidate_peer_reflexive::*;
 use crate::candidate::candidate_relay::*;
 use crate::candidate::candidate_server_reflexive::*;
 use crate::control::AttrControlling;
-use crate::priority::PriorityAttr;
+use crate::priority::{peer_reflexive_priority, CandidatePreferences, PriorityAttr};
 use crate::use_candidate::UseCandidateAttr;
 
 use crate::agent::agent_transport_test::pipe;
+use crate::udp_mux::{UdpMuxDefault, UdpMuxParams};
 use async_trait::async_trait;
 use std::io;
 use std::net::Ipv4Addr;
 use std::ops::Sub;
 use std::str::FromStr;
+use stun::error_code::CODE_ROLE_CONFLICT;
 use stun::message::*;
 use stun::textattrs::Username;
-use util::{vnet::*, Conn, Error};
+use util::{vnet::*, Conn};
 use waitgroup::{WaitGroup, Worker};
 
+/// Returns the config of a UDP candidate of the first component, on `address` and `port`.
+fn test_base_config(address: &str, port: u16) -> CandidateBaseConfig {
+    CandidateBaseConfig {
+        network: "udp".to_owned(),
+        address: address.to_owned(),
+        port,
+        component: 1,
+        ..Default::default()
+    }
+}
+
+/// Returns a host candidate of `agent` with the given config.
+async fn new_test_host_with(
+    agent: &Agent,
+    base_config: CandidateBaseConfig,
+) -> Result<Arc<dyn Candidate + Send + Sync>, IceError> {
+    let host_config = CandidateHostConfig {
+        base_config,
+        ..Default::default()
+    };
+    Ok(Arc::new(
+        host_config
+            .new_candidate_host(Some(agent.agent_internal.clone()))
+            .await?,
+    ))
+}
+
+/// Returns a UDP host candidate of the first component of `agent`, on `address` and `port`.
+async fn new_test_host(
+    agent: &Agent,
+    address: &str,
+    port: u16,
+) -> Result<Arc<dyn Candidate + Send + Sync>, IceError> {
+    new_test_host_with(agent, test_base_config(address, port)).await
+}
+
+/// Returns a server reflexive candidate of `agent` with the given config, mapped from
+/// `rel_addr` and `rel_port`.
+async fn new_test_srflx_with(
+    agent: &Agent,
+    base_config: CandidateBaseConfig,
+    rel_addr: &str,
+    rel_port: u16,
+) -> Result<Arc<dyn Candidate + Send + Sync>, IceError> {
+    let srflx_config = CandidateServerReflexiveConfig {
+        base_config,
+        rel_addr: rel_addr.to_owned(),
+        rel_port,
+    };
+    Ok(Arc::new(
+        srflx_config
+            .new_candidate_server_reflexive(Some(agent.agent_internal.clone()))
+            .await?,
+    ))
+}
+
+/// Returns a UDP server reflexive candidate of the first component of `agent`, on `address`
+/// and `port`, mapped from `rel_addr` and `rel_port`.
+async fn new_test_srflx(
+    agent: &Agent,
+    address: &str,
+    port: u16,
+    rel_addr: &str,
+    rel_port: u16,
+) -> Result<Arc<dyn Candidate + Send + Sync>, IceError> {
+    new_test_srflx_with(agent, test_base_config(address, port), rel_addr, rel_port).await
+}
+
+/// Waits until the remote candidates added to `agent` in the background have been processed.
+async fn wait_for_remote_candidates(agent: &Agent) -> Result<(), IceError> {
+    let wait = async {
+        while agent.pending_remote_candidates.load(Ordering::SeqCst) != 0 {
+            tokio::task::yield_now().await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), wait)
+        .await
+        .map_err(|_| IceError::Timeout("waiting for the remote candidates"))
+}
+
 #[tokio::test]
-async fn test_pair_search() -> Result<(), Error> {
+async fn test_pair_search() -> Result<(), IceError> {
     let config = AgentConfig::default();
     let a = Agent::new(config).await?;
 
     {
         let ai = a.agent_internal.lock().await;
         {
-            let checklist = ai.agent_conn.checklist.lock().await;
+            let checklist = ai.agent_conns[0].checklist.lock().await;
             assert!(
                 checklist.is_empty(),
                 "TestPairSearch is only a valid test if a.validPairs is empty on construction"
             );
         }
 
-        let cp = ai.agent_conn.get_best_available_candidate_pair().await;
+        let cp = ai.agent_conns[0].get_best_available_candidate_pair().await;
         assert!(cp.is_none(), "No Candidate pairs should exist");
     }
 
@@ -44,7 +126,7 @@ async fn test_pair_search() -> Result<(), Error> {
 }
 
 #[tokio::test]
-async fn test_pair_priority() -> Result<(), Error> {
+async fn test_pair_priority() -> Result<(), IceError> {
     let a = Agent::new(AgentConfig::default()).await?;
 
     let host_config = CandidateHostConfig {
@@ -145,7 +227,7 @@ async fn test_pair_priority() -> Result<(), Error> {
                     .store(CandidatePairState::Succeeded as u8, Ordering::SeqCst);
             }
 
-            if let Some(best_pair) = ai.agent_conn.get_best_available_candidate_pair().await {
+            if let Some(best_pair) = ai.agent_conns[0].get_best_available_candidate_pair().await {
                 assert_eq!(
                     best_pair.to_string(),
                     CandidatePair {
@@ -169,7 +251,49 @@ async fn test_pair_priority() -> Result<(), Error> {
 }
 
 #[tokio::test]
-async fn test_on_selected_candidate_pair_change() -> Result<(), Error> {
+async fn test_pair_network_cost_tiebreak() -> Result<(), IceError> {
+    let a = Agent::new(AgentConfig::default()).await?;
+
+    let new_host = |address: &str, network_cost: u16| {
+        new_test_host_with(
+            &a,
+            CandidateBaseConfig {
+                network_cost,
+                ..test_base_config(address, 19216)
+            },
+        )
+    };
+
+    let cellular = new_host("192.168.1.1", NETWORK_COST_HIGH).await?;
+    let wifi = new_host("192.168.2.1", NETWORK_COST_LOW).await?;
+    let remote = new_host("1.2.3.5", NETWORK_COST_MIN).await?;
+
+    {
+        let mut ai = a.agent_internal.lock().await;
+        ai.add_pair(cellular.clone(), remote.clone()).await;
+        ai.add_pair(wifi.clone(), remote.clone()).await;
+
+        let checklist = ai.agent_conns[0].checklist.lock().await.clone();
+        assert_eq!(checklist[0].priority(), checklist[1].priority());
+        assert_eq!(checklist[0].network_cost(), u32::from(NETWORK_COST_HIGH));
+        for p in &checklist {
+            p.state
+                .store(CandidatePairState::Succeeded as u8, Ordering::SeqCst);
+        }
+
+        let best = ai.agent_conns[0].get_best_valid_candidate_pair().await;
+        assert!(
+            best.map_or(false, |p| Arc::ptr_eq(&p.local, &wifi)),
+            "the cheaper pair should win the tie"
+        );
+    }
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_on_selected_candidate_pair_change() -> Result<(), IceError> {
     let a = Agent::new(AgentConfig::default()).await?;
     let (callback_called_tx, mut callback_called_rx) = mpsc::channel::<()>(1);
     let callback_called_tx = Arc::new(Mutex::new(Some(callback_called_tx)));
@@ -231,7 +355,97 @@ async fn test_on_selected_candidate_pair_change() -> Result<(), Error> {
 }
 
 #[tokio::test]
-async fn test_handle_peer_reflexive_udp_pflx_candidate() -> Result<(), Error> {
+async fn test_candidate_pair_state_change_events() -> Result<(), IceError> {
+    let a = Agent::new(AgentConfig::default()).await?;
+    let mut events = a.events();
+
+    let host_local = new_test_host(&a, "192.168.1.1", 19216).await?;
+    let host_remote = new_test_host(&a, "1.2.3.5", 12350).await?;
+
+    let p = Arc::new(CandidatePair::new(
+        Arc::clone(&host_local),
+        Arc::clone(&host_remote),
+        false,
+    ));
+    {
+        let ai = a.agent_internal.lock().await;
+        // Only actual transitions are reported
+        ai.set_pair_state(&p, CandidatePairState::Waiting);
+        ai.set_pair_state(&p, CandidatePairState::Waiting);
+        ai.set_pair_state(&p, CandidatePairState::InProgress);
+        ai.set_pair_state(&p, CandidatePairState::Succeeded);
+        ai.set_pair_nominated(&p);
+        ai.set_pair_nominated(&p);
+    }
+
+    for (expected_state, expected_nominated) in [
+        (CandidatePairState::Waiting, false),
+        (CandidatePairState::InProgress, false),
+        (CandidatePairState::Succeeded, false),
+        (CandidatePairState::Succeeded, true),
+    ] {
+        match events.try_recv() {
+            Ok(AgentEvent::CandidatePairStateChange {
+                local_candidate_id,
+                remote_candidate_id,
+                state,
+                nominated,
+            }) => {
+                assert_eq!(local_candidate_id, host_local.id());
+                assert_eq!(remote_candidate_id, host_remote.id());
+                assert_eq!(state, expected_state);
+                assert_eq!(nominated, expected_nominated);
+            }
+            _ => panic!("expected a candidate pair state change"),
+        }
+    }
+    assert!(events.try_recv().is_err(), "no more events expected");
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unfreeze_pairs_by_foundation() -> Result<(), IceError> {
+    let a = Agent::new(AgentConfig::default()).await?;
+
+    let host_local = new_test_host(&a, "192.168.1.1", 19216).await?;
+
+    // The first two remotes share a foundation, as they only differ by their port
+    let mut remotes = vec![];
+    for (address, port) in [("1.2.3.5", 12350), ("1.2.3.5", 12351), ("1.2.3.6", 12350)] {
+        remotes.push(new_test_host(&a, address, port).await?);
+    }
+
+    let mut ai = a.agent_internal.lock().await;
+    let mut pairs = vec![];
+    for remote in &remotes {
+        ai.add_pair(Arc::clone(&host_local), Arc::clone(remote))
+            .await;
+        pairs.push(ai.find_pair(&host_local, remote).await.unwrap());
+    }
+    assert_eq!(pairs[0].foundation(), pairs[1].foundation());
+    assert_ne!(pairs[0].foundation(), pairs[2].foundation());
+
+    // A single pair of each foundation is checked at first
+    assert_eq!(pairs[0].state(), CandidatePairState::Waiting);
+    assert_eq!(pairs[1].state(), CandidatePairState::Frozen);
+    assert_eq!(pairs[2].state(), CandidatePairState::Waiting);
+
+    // A success unfreezes the pairs of its foundation
+    ai.set_pair_state(&pairs[0], CandidatePairState::Succeeded);
+    ai.unfreeze_pairs().await;
+    assert_eq!(pairs[1].state(), CandidatePairState::Waiting);
+    drop(ai);
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handle_peer_reflexive_udp_pflx_candidate() -> Result<(), IceError> {
     let a = Agent::new(AgentConfig::default()).await?;
 
     let host_config = CandidateHostConfig {
@@ -307,6 +521,18 @@ async fn test_handle_peer_reflexive_udp_pflx_candidate() -> Result<(), Error> {
             assert_eq!(c.address(), "172.17.0.3", "IP address mismatch");
 
             assert_eq!(c.port(), 999, "Port number mismatch");
+
+            assert_eq!(
+                c.priority(),
+                local.priority(),
+                "priority must be taken from the request"
+            );
+
+            // The prflx candidate is paired with the local candidate right away
+            assert!(
+                ai.find_pair(&local, c).await.is_some(),
+                "prflx candidate must be paired"
+            );
         } else {
             panic!(
                 "expected non-empty remote candidate for network type {}",
@@ -320,7 +546,7 @@ async fn test_handle_peer_reflexive_udp_pflx_candidate() -> Result<(), Error> {
 }
 
 #[tokio::test]
-async fn test_handle_peer_reflexive_unknown_remote() -> Result<(), Error> {
+async fn test_handle_peer_reflexive_unknown_remote() -> Result<(), IceError> {
     let a = Agent::new(AgentConfig::default()).await?;
 
     let mut tid = TransactionId::default();
@@ -333,6 +559,8 @@ async fn test_handle_peer_reflexive_unknown_remote() -> Result<(), Error> {
             transaction_id: tid,
             destination: SocketAddr::from_str("0.0.0.0:0")?,
             is_use_candidate: false,
+            is_controlling: false,
+            pair: None,
         }];
         ai.remote_pwd.clone()
     };
@@ -385,7 +613,7 @@ async fn test_handle_peer_reflexive_unknown_remote() -> Result<(), Error> {
 
 // Assert that Agent on startup sends message, and doesn't wait for connectivityTicker to fire
 #[tokio::test]
-async fn test_connectivity_on_startup() -> Result<(), Error> {
+async fn test_connectivity_on_startup() -> Result<(), IceError> {
     /*env_logger::Builder::new()
     .format(|buf, record| {
         writeln!(
@@ -424,7 +652,7 @@ async fn test_connectivity_on_startup() -> Result<(), Error> {
     let (b_notifier, mut b_connected) = on_connected();
 
     let keepalive_interval = Some(Duration::from_secs(3600)); //time.Hour
-    let check_interval = Duration::from_secs(3600); //time.Hour
+    let check_interval = Some(Duration::from_secs(3600)); //time.Hour
     let cfg0 = AgentConfig {
         network_types: supported_network_types(),
         multicast_dns_mode: MulticastDnsMode::Disabled,
@@ -502,7 +730,7 @@ async fn test_connectivity_on_startup() -> Result<(), Error> {
 }
 
 #[tokio::test]
-async fn test_connectivity_lite() -> Result<(), Error> {
+async fn test_connectivity_lite() -> Result<(), IceError> {
     /*env_logger::Builder::new()
     .format(|buf, record| {
         writeln!(
@@ -602,7 +830,7 @@ impl Conn for MockPacketConn {
     }
 }
 
-fn build_msg(c: MessageClass, username: String, key: String) -> Result<Message, Error> {
+fn build_msg(c: MessageClass, username: String, key: String) -> Result<Message, IceError> {
     let mut msg = Message::new();
     msg.build(&[
         Box::new(MessageType::new(METHOD_BINDING, c)),
@@ -615,7 +843,7 @@ fn build_msg(c: MessageClass, username: String, key: String) -> Result<Message,
 }
 
 #[tokio::test]
-async fn test_inbound_validity() -> Result<(), Error> {
+async fn test_inbound_validity() -> Result<(), IceError> {
     /*env_logger::Builder::new()
     .format(|buf, record| {
         writeln!(
@@ -843,21 +1071,21 @@ async fn test_inbound_validity() -> Result<(), Error> {
 }
 
 #[tokio::test]
-async fn test_invalid_agent_starts() -> Result<(), Error> {
+async fn test_invalid_agent_starts() -> Result<(), IceError> {
     let a = Agent::new(AgentConfig::default()).await?;
 
     let (_cancel_tx1, cancel_rx1) = mpsc::channel(1);
     let result = a.dial(cancel_rx1, "".to_owned(), "bar".to_owned()).await;
     assert!(result.is_err());
     if let Err(err) = result {
-        assert_eq!(err, *ERR_REMOTE_UFRAG_EMPTY);
+        assert!(matches!(err, IceError::RemoteUfragEmpty));
     }
 
     let (_cancel_tx2, cancel_rx2) = mpsc::channel(1);
     let result = a.dial(cancel_rx2, "foo".to_owned(), "".to_owned()).await;
     assert!(result.is_err());
     if let Err(err) = result {
-        assert_eq!(err, *ERR_REMOTE_PWD_EMPTY);
+        assert!(matches!(err, IceError::RemotePwdEmpty));
     }
 
     let (cancel_tx3, cancel_rx3) = mpsc::channel(1);
@@ -869,14 +1097,14 @@ async fn test_invalid_agent_starts() -> Result<(), Error> {
     let result = a.dial(cancel_rx3, "foo".to_owned(), "bar".to_owned()).await;
     assert!(result.is_err());
     if let Err(err) = result {
-        assert_eq!(err, *ERR_CANCELED_BY_CALLER);
+        assert!(matches!(err, IceError::CanceledByCaller));
     }
 
     let (_cancel_tx4, cancel_rx4) = mpsc::channel(1);
     let result = a.dial(cancel_rx4, "foo".to_owned(), "bar".to_owned()).await;
     assert!(result.is_err());
     if let Err(err) = result {
-        assert_eq!(err, *ERR_MULTIPLE_START);
+        assert!(matches!(err, IceError::MultipleStart));
     }
 
     a.close().await?;
@@ -888,7 +1116,7 @@ async fn test_invalid_agent_starts() -> Result<(), Error> {
 
 // Assert that Agent emits Connecting/Connected/Disconnected/Failed/Closed messages
 #[tokio::test]
-async fn test_connection_state_callback() -> Result<(), Error> {
+async fn test_connection_state_callback() -> Result<(), IceError> {
     /*env_logger::Builder::new()
     .format(|buf, record| {
         writeln!(
@@ -1002,13 +1230,67 @@ async fn test_connection_state_callback() -> Result<(), Error> {
 }
 
 #[tokio::test]
-async fn test_invalid_gather() -> Result<(), Error> {
+async fn test_connection_state_order() -> Result<(), IceError> {
+    let a = Arc::new(Agent::new(AgentConfig::default()).await?);
+
+    let (state_tx, mut state_rx) = mpsc::unbounded_channel();
+    a.on_connection_state_change(Box::new(move |s: ConnectionState| {
+        let _ = state_tx.send(s);
+        Box::pin(async {})
+    }))
+    .await;
+
+    a.wait_for_state(ConnectionState::New, Duration::from_secs(1))
+        .await?;
+    let result = a
+        .wait_for_state(ConnectionState::Connected, Duration::from_millis(50))
+        .await;
+    assert!(matches!(result, Err(IceError::Timeout(_))));
+
+    // Many transitions under a single acquisition of the lock are all queued for the handler
+    let transitions = [
+        ConnectionState::Checking,
+        ConnectionState::Connected,
+        ConnectionState::Disconnected,
+        ConnectionState::Checking,
+        ConnectionState::Connected,
+    ];
+    {
+        let mut ai = a.agent_internal.lock().await;
+        for s in transitions {
+            ai.update_connection_state(s).await;
+        }
+    }
+    for s in transitions {
+        assert_eq!(state_rx.recv().await, Some(s), "states should be in order");
+    }
+    a.wait_for_state(ConnectionState::Connected, Duration::from_secs(1))
+        .await?;
+
+    // Closing the agent gives up on a state that can't be reached anymore
+    let waiter = {
+        let a = Arc::clone(&a);
+        tokio::spawn(async move {
+            a.wait_for_state(ConnectionState::Failed, Duration::from_secs(5))
+                .await
+        })
+    };
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    a.close().await?;
+    assert!(matches!(waiter.await.unwrap(), Err(IceError::Closed)));
+    assert_eq!(state_rx.recv().await, Some(ConnectionState::Closed));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_invalid_gather() -> Result<(), IceError> {
     //"Gather with no OnCandidate should error"
     let a = Agent::new(AgentConfig::default()).await?;
 
     if let Err(err) = a.gather_candidates().await {
-        assert_eq!(
-            err, *ERR_NO_ON_CANDIDATE_HANDLER,
+        assert!(
+            matches!(err, IceError::NoOnCandidateHandler),
             "trickle GatherCandidates succeeded without OnCandidate"
         );
     }
@@ -1019,7 +1301,7 @@ async fn test_invalid_gather() -> Result<(), Error> {
 }
 
 #[tokio::test]
-async fn test_candidate_pair_stats() -> Result<(), Error> {
+async fn test_candidate_pair_stats() -> Result<(), IceError> {
     let a = Agent::new(AgentConfig::default()).await?;
 
     let host_local: Arc<dyn Candidate + Send + Sync> = Arc::new(
@@ -1187,7 +1469,7 @@ async fn test_candidate_pair_stats() -> Result<(), Error> {
 }
 
 #[tokio::test]
-async fn test_local_candidate_stats() -> Result<(), Error> {
+async fn test_local_candidate_stats() -> Result<(), IceError> {
     let a = Agent::new(AgentConfig::default()).await?;
 
     let host_local: Arc<dyn Candidate + Send + Sync> = Arc::new(
@@ -1280,7 +1562,7 @@ async fn test_local_candidate_stats() -> Result<(), Error> {
 }
 
 #[tokio::test]
-async fn test_remote_candidate_stats() -> Result<(), Error> {
+async fn test_remote_candidate_stats() -> Result<(), IceError> {
     let a = Agent::new(AgentConfig::default()).await?;
 
     let relay_remote: Arc<dyn Candidate + Send + Sync> = Arc::new(
@@ -1431,7 +1713,7 @@ async fn test_remote_candidate_stats() -> Result<(), Error> {
 }
 
 #[tokio::test]
-async fn test_init_ext_ip_mapping() -> Result<(), Error> {
+async fn test_init_ext_ip_mapping() -> Result<(), IceError> {
     // a.extIPMapper should be nil by default
     let a = Agent::new(AgentConfig::default()).await?;
     assert!(
@@ -1463,8 +1745,8 @@ async fn test_init_ext_ip_mapping() -> Result<(), Error> {
     })
     .await
     {
-        assert_eq!(
-            err, *ERR_INEFFECTIVE_NAT_1TO1_IP_MAPPING_HOST,
+        assert!(
+            matches!(err, IceError::IneffectiveNat1To1IpMappingHost),
             "Unexpected error: {}",
             err
         );
@@ -1482,8 +1764,8 @@ async fn test_init_ext_ip_mapping() -> Result<(), Error> {
     })
     .await
     {
-        assert_eq!(
-            err, *ERR_INEFFECTIVE_NAT_1TO1_IP_MAPPING_SRFLX,
+        assert!(
+            matches!(err, IceError::IneffectiveNat1To1IpMappingSrflx),
             "Unexpected error: {}",
             err
         );
@@ -1501,8 +1783,8 @@ async fn test_init_ext_ip_mapping() -> Result<(), Error> {
     })
     .await
     {
-        assert_eq!(
-            err, *ERR_MULTICAST_DNS_WITH_NAT_1TO1_IP_MAPPING,
+        assert!(
+            matches!(err, IceError::MulticastDnsWithNat1To1IpMapping),
             "Unexpected error: {}",
             err
         );
@@ -1518,8 +1800,8 @@ async fn test_init_ext_ip_mapping() -> Result<(), Error> {
     })
     .await
     {
-        assert_eq!(
-            err, *ERR_INVALID_NAT_1TO1_IP_MAPPING,
+        assert!(
+            matches!(err, IceError::InvalidNat1To1IpMapping),
             "Unexpected error: {}",
             err
         );
@@ -1531,7 +1813,7 @@ async fn test_init_ext_ip_mapping() -> Result<(), Error> {
 }
 
 #[tokio::test]
-async fn test_binding_request_timeout() -> Result<(), Error> {
+async fn test_binding_request_timeout() -> Result<(), IceError> {
     const EXPECTED_REMOVAL_COUNT: usize = 2;
 
     let a = Agent::new(AgentConfig::default()).await?;
@@ -1568,7 +1850,7 @@ async fn test_binding_request_timeout() -> Result<(), Error> {
 // test_agent_credentials checks if local username fragments and passwords (if set) meet RFC standard
 // and ensure it's backwards compatible with previous versions of the pion/ice
 #[tokio::test]
-async fn test_agent_credentials() -> Result<(), Error> {
+async fn test_agent_credentials() -> Result<(), IceError> {
     // Agent should not require any of the usernames and password to be set
     // If set, they should follow the default 16/128 bits random number generator strategy
 
@@ -1591,7 +1873,7 @@ async fn test_agent_credentials() -> Result<(), Error> {
     })
     .await
     {
-        assert_eq!(err, *ERR_LOCAL_UFRAG_INSUFFICIENT_BITS);
+        assert!(matches!(err, IceError::LocalUfragInsufficientBits));
     } else {
         panic!("expected error, but got ok");
     }
@@ -1602,18 +1884,50 @@ async fn test_agent_credentials() -> Result<(), Error> {
     })
     .await
     {
-        assert_eq!(err, *ERR_LOCAL_PWD_INSUFFICIENT_BITS);
+        assert!(matches!(err, IceError::LocalPwdInsufficientBits));
     } else {
         panic!("expected error, but got ok");
     }
 
+    // and the ice-char grammar of RFC 8839
+    for (local_ufrag, local_pwd, expected) in [
+        ("xxx", "", &IceError::LocalUfragInvalid),
+        ("xx x", "", &IceError::LocalUfragInvalid),
+        ("", "xxxxxxxxxxxxxxxxxxxxx", &IceError::LocalPwdInvalid),
+        ("", "xxxxxxxxxxxxxxxxxxxxx=", &IceError::LocalPwdInvalid),
+    ] {
+        if let Err(err) = Agent::new(AgentConfig {
+            local_ufrag: local_ufrag.to_owned(),
+            local_pwd: local_pwd.to_owned(),
+            ..Default::default()
+        })
+        .await
+        {
+            assert_eq!(err.to_string(), expected.to_string());
+        } else {
+            panic!("expected error, but got ok");
+        }
+    }
+
+    let a = Agent::new(AgentConfig {
+        local_ufrag: "ab+/".to_owned(),
+        local_pwd: "0123456789abcdefghij+/".to_owned(),
+        ..Default::default()
+    })
+    .await?;
+    assert_eq!(
+        a.get_local_user_credentials().await,
+        ("ab+/".to_owned(), "0123456789abcdefghij+/".to_owned())
+    );
+    a.close().await?;
+
     Ok(())
 }
 
 // Assert that Agent on Failure deletes all existing candidates
 // User can then do an ICE Restart to bring agent back
 #[tokio::test]
-async fn test_connection_state_failed_delete_all_candidates() -> Result<(), Error> {
+async fn test_connection_state_failed_delete_all_candidates() -> Result<(), IceError> {
     let one_second = Duration::from_secs(1);
     let keepalive_interval = Duration::from_secs(0);
 
@@ -1666,7 +1980,7 @@ async fn test_connection_state_failed_delete_all_candidates() -> Result<(), Erro
 
 // Assert that the ICE Agent can go directly from Connecting -> Failed on both sides
 #[tokio::test]
-async fn test_connection_state_connecting_to_failed() -> Result<(), Error> {
+async fn test_connection_state_connecting_to_failed() -> Result<(), IceError> {
     let one_second = Duration::from_secs(1);
     let keepalive_interval = Duration::from_secs(0);
 
@@ -1748,7 +2062,7 @@ async fn test_connection_state_connecting_to_failed() -> Result<(), Error> {
 }
 
 #[tokio::test]
-async fn test_agent_restart_during_gather() -> Result<(), Error> {
+async fn test_agent_restart_during_gather() -> Result<(), IceError> {
     //"Restart During Gather"
 
     let agent = Agent::new(AgentConfig::default()).await?;
@@ -1758,7 +2072,7 @@ async fn test_agent_restart_during_gather() -> Result<(), Error> {
         .store(GatheringState::Gathering as u8, Ordering::SeqCst);
 
     if let Err(err) = agent.restart("".to_owned(), "".to_owned()).await {
-        assert_eq!(err, *ERR_RESTART_WHEN_GATHERING);
+        assert!(matches!(err, IceError::RestartWhenGathering));
     } else {
         panic!("expected error, but got ok");
     }
@@ -1769,14 +2083,14 @@ async fn test_agent_restart_during_gather() -> Result<(), Error> {
 }
 
 #[tokio::test]
-async fn test_agent_restart_when_closed() -> Result<(), Error> {
+async fn test_agent_restart_when_closed() -> Result<(), IceError> {
     //"Restart When Closed"
 
     let agent = Agent::new(AgentConfig::default()).await?;
     agent.close().await?;
 
     if let Err(err) = agent.restart("".to_owned(), "".to_owned()).await {
-        assert_eq!(err, *ERR_CLOSED);
+        assert!(matches!(err, IceError::Closed));
     } else {
         panic!("expected error, but got ok");
     }
@@ -1785,7 +2099,7 @@ async fn test_agent_restart_when_closed() -> Result<(), Error> {
 }
 
 #[tokio::test]
-async fn test_agent_restart_one_side() -> Result<(), Error> {
+async fn test_agent_restart_one_side() -> Result<(), IceError> {
     let one_second = Duration::from_secs(1);
 
     //"Restart One Side"
@@ -1828,13 +2142,13 @@ async fn test_agent_restart_one_side() -> Result<(), Error> {
 }
 
 #[tokio::test]
-async fn test_agent_restart_both_side() -> Result<(), Error> {
+async fn test_agent_restart_both_side() -> Result<(), IceError> {
     let one_second = Duration::from_secs(1);
     //"Restart Both Sides"
 
     // Get all addresses of candidates concatenated
     let generate_candidate_address_strings =
-        |res: Result<Vec<Arc<dyn Candidate + Send + Sync>>, Error>| -> String {
+        |res: Result<Vec<Arc<dyn Candidate + Send + Sync>>, IceError>| -> String {
             assert!(res.is_ok());
 
             let mut out = String::new();
@@ -1908,7 +2222,7 @@ async fn test_agent_restart_both_side() -> Result<(), Error> {
 }
 
 #[tokio::test]
-async fn test_get_remote_credentials() -> Result<(), Error> {
+async fn test_get_remote_credentials() -> Result<(), IceError> {
     let a = Agent::new(AgentConfig::default()).await?;
 
     let (remote_ufrag, remote_pwd) = {
@@ -1929,7 +2243,7 @@ async fn test_get_remote_credentials() -> Result<(), Error> {
 }
 
 #[tokio::test]
-async fn test_close_in_connection_state_callback() -> Result<(), Error> {
+async fn test_close_in_connection_state_callback() -> Result<(), IceError> {
     let disconnected_duration = Duration::from_secs(1);
     let failed_duration = Duration::from_secs(1);
     let keepalive_interval = Duration::from_secs(0);
@@ -1940,7 +2254,7 @@ async fn test_close_in_connection_state_callback() -> Result<(), Error> {
         disconnected_timeout: Some(disconnected_duration),
         failed_timeout: Some(failed_duration),
         keepalive_interval: Some(keepalive_interval),
-        check_interval: Duration::from_millis(500),
+        check_interval: Some(Duration::from_millis(500)),
         ..Default::default()
     };
 
@@ -1950,7 +2264,7 @@ async fn test_close_in_connection_state_callback() -> Result<(), Error> {
         disconnected_timeout: Some(disconnected_duration),
         failed_timeout: Some(failed_duration),
         keepalive_interval: Some(keepalive_interval),
-        check_interval: Duration::from_millis(500),
+        check_interval: Some(Duration::from_millis(500)),
         ..Default::default()
     };
 
@@ -1989,7 +2303,7 @@ async fn test_close_in_connection_state_callback() -> Result<(), Error> {
 }
 
 #[tokio::test]
-async fn test_run_task_in_connection_state_callback() -> Result<(), Error> {
+async fn test_run_task_in_connection_state_callback() -> Result<(), IceError> {
     let one_second = Duration::from_secs(1);
     let keepalive_interval = Duration::from_secs(0);
 
@@ -1999,7 +2313,7 @@ async fn test_run_task_in_connection_state_callback() -> Result<(), Error> {
         disconnected_timeout: Some(one_second),
         failed_timeout: Some(one_second),
         keepalive_interval: Some(keepalive_interval),
-        check_interval: Duration::from_millis(50),
+        check_interval: Some(Duration::from_millis(50)),
         ..Default::default()
     };
 
@@ -2009,7 +2323,7 @@ async fn test_run_task_in_connection_state_callback() -> Result<(), Error> {
         disconnected_timeout: Some(one_second),
         failed_timeout: Some(one_second),
         keepalive_interval: Some(keepalive_interval),
-        check_interval: Duration::from_millis(50),
+        check_interval: Some(Duration::from_millis(50)),
         ..Default::default()
     };
 
@@ -2043,7 +2357,7 @@ async fn test_run_task_in_connection_state_callback() -> Result<(), Error> {
 }
 
 #[tokio::test]
-async fn test_run_task_in_selected_candidate_pair_change_callback() -> Result<(), Error> {
+async fn test_run_task_in_selected_candidate_pair_change_callback() -> Result<(), IceError> {
     let one_second = Duration::from_secs(1);
     let keepalive_interval = Duration::from_secs(0);
 
@@ -2053,7 +2367,7 @@ async fn test_run_task_in_selected_candidate_pair_change_callback() -> Result<()
         disconnected_timeout: Some(one_second),
         failed_timeout: Some(one_second),
         keepalive_interval: Some(keepalive_interval),
-        check_interval: Duration::from_millis(50),
+        check_interval: Some(Duration::from_millis(50)),
         ..Default::default()
     };
 
@@ -2063,7 +2377,7 @@ async fn test_run_task_in_selected_candidate_pair_change_callback() -> Result<()
         disconnected_timeout: Some(one_second),
         failed_timeout: Some(one_second),
         keepalive_interval: Some(keepalive_interval),
-        check_interval: Duration::from_millis(50),
+        check_interval: Some(Duration::from_millis(50)),
         ..Default::default()
     };
 
@@ -2113,7 +2427,7 @@ async fn test_run_task_in_selected_candidate_pair_change_callback() -> Result<()
 
 // Assert that a Lite agent goes to disconnected and failed
 #[tokio::test]
-async fn test_lite_lifecycle() -> Result<(), Error> {
+async fn test_lite_lifecycle() -> Result<(), IceError> {
     let (a_notifier, mut a_connected_rx) = on_connected();
 
     let a_agent = Arc::new(
@@ -2140,7 +2454,7 @@ async fn test_lite_lifecycle() -> Result<(), Error> {
             disconnected_timeout: Some(disconnected_duration),
             failed_timeout: Some(failed_duration),
             keepalive_interval: Some(keepalive_interval),
-            check_interval: Duration::from_millis(500),
+            check_interval: Some(Duration::from_millis(500)),
             ..Default::default()
         })
         .await?,
@@ -2187,3 +2501,2130 @@ async fn test_lite_lifecycle() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_add_remote_candidate_after_end_of_candidates() -> Result<(), IceError> {
+    let a = Agent::new(AgentConfig::default()).await?;
+
+    let remote = new_test_host(&a, "192.168.0.2", 1000).await?;
+
+    a.add_remote_candidate(&remote).await?;
+    a.add_remote_end_of_candidates();
+
+    if let Err(err) = a.add_remote_candidate(&remote).await {
+        assert!(matches!(err, IceError::RemoteCandidateAfterEndOfCandidates));
+    } else {
+        panic!("expected error, but got ok");
+    }
+
+    // Restart clears end-of-candidates
+    a.restart("".to_owned(), "".to_owned()).await?;
+    a.add_remote_candidate(&remote).await?;
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_remote_candidate_with_disabled_network_type_ignored() -> Result<(), IceError> {
+    let a = Agent::new(AgentConfig {
+        network_types: vec![NetworkType::Udp4],
+        ..Default::default()
+    })
+    .await?;
+
+    let tcp_config = CandidateHostConfig {
+        base_config: CandidateBaseConfig {
+            network: "tcp".to_owned(),
+            ..test_base_config("192.168.0.2", 1000)
+        },
+        tcp_type: TcpType::Passive,
+    };
+    let tcp_remote: Arc<dyn Candidate + Send + Sync> = Arc::new(
+        tcp_config
+            .new_candidate_host(Some(a.agent_internal.clone()))
+            .await?,
+    );
+    a.add_remote_candidate(&tcp_remote).await?;
+    a.add_remote_candidate(&new_test_host(&a, "192.168.0.2", 1000).await?)
+        .await?;
+
+    // Remote candidates are added in the background
+    wait_for_remote_candidates(&a).await?;
+
+    {
+        let ai = a.agent_internal.lock().await;
+        assert!(
+            !ai.remote_candidates.contains_key(&NetworkType::Tcp4),
+            "TCP4 candidate should have been ignored"
+        );
+        assert_eq!(
+            ai.remote_candidates
+                .get(&NetworkType::Udp4)
+                .map_or(0, |cands| cands.len()),
+            1,
+            "UDP4 candidate should have been added"
+        );
+    }
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_strict_candidate_types() -> Result<(), IceError> {
+    for strict_candidate_types in &[false, true] {
+        let a = Agent::new(AgentConfig {
+            candidate_types: vec![CandidateType::Relay],
+            strict_candidate_types: *strict_candidate_types,
+            ..Default::default()
+        })
+        .await?;
+
+        let remote = new_test_host(&a, "192.168.0.2", 1000).await?;
+        a.add_remote_candidate(&remote).await?;
+
+        // Remote candidates are added in the background
+        wait_for_remote_candidates(&a).await?;
+
+        {
+            let ai = a.agent_internal.lock().await;
+            assert_eq!(
+                ai.remote_candidates.contains_key(&NetworkType::Udp4),
+                !*strict_candidate_types,
+                "host candidate should only be ignored in strict mode"
+            );
+        }
+
+        a.close().await?;
+    }
+
+    Ok(())
+}
+
+// Assert that a lite agent accepts the nomination without sending checks of its own
+#[tokio::test]
+async fn test_lite_accepts_nomination_without_checks() -> Result<(), IceError> {
+    let a = Agent::new(AgentConfig {
+        lite: true,
+        candidate_types: vec![CandidateType::Host],
+        multicast_dns_mode: MulticastDnsMode::Disabled,
+        ..Default::default()
+    })
+    .await?;
+
+    let local = new_test_host_with(
+        &a,
+        CandidateBaseConfig {
+            conn: Some(Arc::new(MockPacketConn {})),
+            ..test_base_config("192.168.0.2", 777)
+        },
+    )
+    .await?;
+    let remote = SocketAddr::from_str("172.17.0.3:999")?;
+
+    {
+        let agent_internal = Arc::clone(&a.agent_internal);
+        let mut ai = a.agent_internal.lock().await;
+
+        let mut msg = Message::new();
+        msg.build(&[
+            Box::new(BINDING_REQUEST),
+            Box::new(TransactionId::new()),
+            Box::new(Username::new(
+                ATTR_USERNAME,
+                format!("{}:{}", ai.local_ufrag, ai.remote_ufrag),
+            )),
+            Box::new(UseCandidateAttr::default()),
+            Box::new(AttrControlling(1)),
+            Box::new(PriorityAttr(local.priority())),
+            Box::new(MessageIntegrity::new_short_term_integrity(
+                ai.local_pwd.clone(),
+            )),
+            Box::new(FINGERPRINT),
+        ])?;
+
+        ai.handle_inbound(&mut msg, &local, remote, agent_internal)
+            .await;
+
+        assert!(
+            ai.agent_conns[0].get_selected_pair().await.is_some(),
+            "nominated pair should be selected"
+        );
+        assert!(
+            ai.pending_binding_requests.is_empty(),
+            "lite agent should not send binding requests"
+        );
+    }
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_aggressive_nomination() -> Result<(), IceError> {
+    let (_, _, agent_a, agent_b) = pipe(
+        Some(AgentConfig {
+            nomination_strategy: NominationStrategy::Aggressive,
+            ..Default::default()
+        }),
+        Some(AgentConfig {
+            nomination_strategy: NominationStrategy::Aggressive,
+            ..Default::default()
+        }),
+    )
+    .await?;
+
+    {
+        let ai = agent_a.agent_internal.lock().await;
+        assert_eq!(
+            ai.nomination_strategy,
+            NominationStrategy::Aggressive,
+            "should match"
+        );
+        assert!(
+            ai.agent_conns[0].get_selected_pair().await.is_some(),
+            "a pair should be selected"
+        );
+    }
+
+    agent_a.close().await?;
+    agent_b.close().await?;
+
+    Ok(())
+}
+
+// Assert that every change of the selected pair is reported with the pair that was selected
+#[tokio::test]
+async fn test_on_selected_candidate_pair_change_reports_each_pair() -> Result<(), IceError> {
+    let a = Agent::new(AgentConfig::default()).await?;
+    let (ports_tx, mut ports_rx) = mpsc::channel::<u16>(2);
+    let ports_tx = Arc::new(ports_tx);
+    a.on_selected_candidate_pair_change(Box::new(move |_, remote| {
+        let ports_tx_clone = Arc::clone(&ports_tx);
+        let port = remote.port();
+        Box::pin(async move {
+            let _ = ports_tx_clone.send(port).await;
+        })
+    }))
+    .await;
+
+    let mut pairs = vec![];
+    for port in &[1000, 2000] {
+        let local = new_test_host(&a, "192.168.1.1", 19216).await?;
+        let remote = new_test_host(&a, "192.168.1.2", *port).await?;
+        pairs.push(Arc::new(CandidatePair::new(local, remote, false)));
+    }
+
+    {
+        let mut ai = a.agent_internal.lock().await;
+        for p in pairs {
+            ai.set_selected_pair(Some(p)).await;
+        }
+    }
+
+    assert_eq!(ports_rx.recv().await, Some(1000), "should match");
+    assert_eq!(ports_rx.recv().await, Some(2000), "should match");
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_selected_candidate_pair() -> Result<(), IceError> {
+    let a = Agent::new(AgentConfig::default()).await?;
+    assert!(
+        a.get_selected_candidate_pair().await.is_none(),
+        "no pair should be selected before connecting"
+    );
+    a.close().await?;
+
+    let (_, _, agent_a, agent_b) = pipe(None, None).await?;
+
+    for agent in &[&agent_a, &agent_b] {
+        let p = agent
+            .get_selected_candidate_pair()
+            .await
+            .expect("a pair should be selected");
+        assert!(p.nominated(), "selected pair should be nominated");
+        assert_eq!(
+            p.local().candidate_type(),
+            CandidateType::Host,
+            "should match"
+        );
+    }
+
+    let p = agent_a.get_selected_candidate_pair().await.unwrap();
+    assert_eq!(p.state(), CandidatePairState::Succeeded, "should match");
+
+    agent_a.close().await?;
+    agent_b.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rto_backoff_of_zero_is_rejected() {
+    let result = Agent::new(AgentConfig {
+        rto_backoff: Some(0),
+        ..Default::default()
+    })
+    .await;
+    assert!(matches!(result, Err(IceError::InvalidRtoBackoff)));
+}
+
+#[tokio::test]
+async fn test_dscp() -> Result<(), IceError> {
+    let result = Agent::new(AgentConfig {
+        dscp: [(COMPONENT_RTP, 64)].iter().cloned().collect(),
+        ..Default::default()
+    })
+    .await;
+    assert!(matches!(result, Err(IceError::InvalidDscp)));
+
+    let (_, _, agent_a, agent_b) = pipe(
+        Some(AgentConfig {
+            dscp: [(COMPONENT_RTP, 46)].iter().cloned().collect(),
+            ..Default::default()
+        }),
+        None,
+    )
+    .await?;
+
+    // The host candidates of the selected pairs are UDP sockets of the host
+    let selected_pair = |agent: Arc<Agent>| async move {
+        agent
+            .get_selected_candidate_pair()
+            .await
+            .expect("a pair should be selected")
+    };
+    assert!(selected_pair(Arc::clone(&agent_a)).await.dscp_marked());
+    assert!(!selected_pair(Arc::clone(&agent_b)).await.dscp_marked());
+
+    // Marking the selected pair again, or with another DSCP, is fine
+    agent_a.set_dscp(COMPONENT_RTP, 46).await?;
+    agent_b.set_dscp(COMPONENT_RTP, 34).await?;
+    assert!(matches!(
+        agent_b.set_dscp(COMPONENT_RTP, 64).await,
+        Err(IceError::InvalidDscp)
+    ));
+    assert!(selected_pair(Arc::clone(&agent_b)).await.dscp_marked());
+
+    agent_a.close().await?;
+    agent_b.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_create_permission_without_relay_candidate() -> Result<(), IceError> {
+    let a = Agent::new(AgentConfig {
+        network_types: vec![NetworkType::Udp4],
+        ..Default::default()
+    })
+    .await?;
+
+    // Nothing was gathered, let alone a relay candidate
+    assert!(matches!(
+        a.create_permission("1.2.3.4:5000".parse()?).await,
+        Err(IceError::NoRelayCandidate)
+    ));
+
+    a.close().await?;
+
+    Ok(())
+}
+
+async fn selected_pair_stats(agent: &Agent) -> CandidatePairStats {
+    let p = agent
+        .get_selected_candidate_pair()
+        .await
+        .expect("a pair should be selected");
+    agent
+        .get_candidate_pairs_stats()
+        .await
+        .into_iter()
+        .find(|s| {
+            s.local_candidate_id == p.local().id() && s.remote_candidate_id == p.remote().id()
+        })
+        .expect("selected pair should have stats")
+}
+
+#[tokio::test]
+async fn test_candidate_pair_stats_counters() -> Result<(), IceError> {
+    // agent_b dials, so it is the controlling agent.
+    let (ca, cb, agent_a, agent_b) = pipe(None, None).await?;
+
+    let msg = b"hello";
+    ca.send(msg).await?;
+    let mut buf = vec![0u8; msg.len()];
+    let n = cb.recv(&mut buf).await?;
+    assert_eq!(&buf[..n], msg, "should match");
+
+    let controlled = selected_pair_stats(&agent_a).await;
+    assert!(controlled.requests_received > 0, "should receive checks");
+    assert!(controlled.responses_sent > 0, "should answer checks");
+    assert_eq!(controlled.packets_sent, 1, "should match");
+    assert_eq!(controlled.bytes_sent, msg.len() as u64, "should match");
+
+    let controlling = selected_pair_stats(&agent_b).await;
+    assert!(controlling.requests_sent > 0, "should send checks");
+    assert!(
+        controlling.responses_received > 0,
+        "should receive responses"
+    );
+    assert!(
+        controlling.total_round_trip_time >= controlling.current_round_trip_time,
+        "total RTT should include the current RTT"
+    );
+    assert!(
+        controlling.last_request_timestamp >= controlling.first_request_timestamp,
+        "last request should not precede the first one"
+    );
+    assert_eq!(controlling.packets_received, 1, "should match");
+    assert_eq!(controlling.bytes_received, msg.len() as u64, "should match");
+
+    agent_a.close().await?;
+    agent_b.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_consent_expiry_fails_connection() -> Result<(), IceError> {
+    // Only consent expiry may fail agent_a, its disconnected and failed timeouts are disabled
+    let cfg = AgentConfig {
+        disconnected_timeout: Some(Duration::from_secs(0)),
+        failed_timeout: Some(Duration::from_secs(0)),
+        keepalive_interval: Some(Duration::from_millis(100)),
+        consent_check_interval: Some(Duration::from_millis(100)),
+        consent_timeout: Some(Duration::from_secs(1)),
+        ..Default::default()
+    };
+    let (_, _, agent_a, agent_b) = pipe(Some(cfg), None).await?;
+
+    let (is_failed_tx, mut is_failed_rx) = mpsc::channel::<()>(1);
+    let is_failed_tx = Arc::new(Mutex::new(Some(is_failed_tx)));
+    agent_a
+        .on_connection_state_change(Box::new(move |c: ConnectionState| {
+            let is_failed_tx_clone = Arc::clone(&is_failed_tx);
+            Box::pin(async move {
+                if c == ConnectionState::Failed {
+                    let mut tx = is_failed_tx_clone.lock().await;
+                    tx.take();
+                }
+            })
+        }))
+        .await;
+
+    let p = agent_a.get_selected_candidate_pair().await.unwrap();
+
+    // Consent keeps being granted while the remote answers
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert_eq!(
+        p.state(),
+        CandidatePairState::Succeeded,
+        "consent should be refreshed"
+    );
+
+    // Once the remote is gone, consent expires
+    agent_b.close().await?;
+    let _ = is_failed_rx.recv().await;
+    assert_eq!(p.state(), CandidatePairState::Failed, "should match");
+
+    agent_a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_role_conflict_resolved_by_tie_breaker() -> Result<(), IceError> {
+    let a_agent = Arc::new(Agent::new(AgentConfig::default()).await?);
+    let b_agent = Arc::new(Agent::new(AgentConfig::default()).await?);
+
+    // agent_b has the larger tie-breaker and must keep the controlling role
+    a_agent.agent_internal.lock().await.tie_breaker = 1;
+    b_agent.agent_internal.lock().await.tie_breaker = 2;
+
+    let (a_ufrag, a_pwd) = a_agent.get_local_user_credentials().await;
+    let (b_ufrag, b_pwd) = b_agent.get_local_user_credentials().await;
+
+    gather_and_exchange_candidates(&a_agent, &b_agent).await?;
+
+    // Both sides start as controlling
+    let agent_a = Arc::clone(&a_agent);
+    let a_dial = tokio::spawn(async move {
+        let (_cancel_tx, cancel_rx) = mpsc::channel(1);
+        agent_a.dial(cancel_rx, b_ufrag, b_pwd).await.map(|_| ())
+    });
+    let (_cancel_tx, cancel_rx) = mpsc::channel(1);
+    let _b_conn = tokio::time::timeout(
+        Duration::from_secs(10),
+        b_agent.dial(cancel_rx, a_ufrag, a_pwd),
+    )
+    .await
+    .expect("agent_b should connect")?;
+    tokio::time::timeout(Duration::from_secs(10), a_dial)
+        .await
+        .expect("agent_a should connect")
+        .unwrap()?;
+
+    assert!(
+        !a_agent.is_controlling().await,
+        "agent_a should be controlled"
+    );
+    assert!(
+        b_agent.is_controlling().await,
+        "agent_b should be controlling"
+    );
+
+    a_agent.close().await?;
+    b_agent.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_on_binding_request() -> Result<(), IceError> {
+    let a_agent = Arc::new(Agent::new(AgentConfig::default()).await?);
+    let b_agent = Arc::new(Agent::new(AgentConfig::default()).await?);
+
+    let requests = Arc::new(AtomicUsize::new(0));
+    let requests2 = Arc::clone(&requests);
+    a_agent
+        .on_binding_request(Box::new(
+            move |m: Message, _local: Arc<dyn Candidate + Send + Sync>, _remote: SocketAddr| {
+                if m.typ == BINDING_REQUEST {
+                    requests2.fetch_add(1, Ordering::SeqCst);
+                }
+                Box::pin(async move {})
+            },
+        ))
+        .await;
+
+    let _ = connect_with_vnet(&a_agent, &b_agent).await?;
+
+    assert!(
+        requests.load(Ordering::SeqCst) > 0,
+        "binding requests from agent_b should be reported"
+    );
+
+    a_agent.close().await?;
+    b_agent.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_on_binding_request_may_call_the_agent() -> Result<(), IceError> {
+    let a_agent = Arc::new(Agent::new(AgentConfig::default()).await?);
+    let b_agent = Arc::new(Agent::new(AgentConfig::default()).await?);
+
+    let (pairs_tx, mut pairs_rx) = mpsc::unbounded_channel();
+    let weak_agent = Arc::downgrade(&a_agent);
+    a_agent
+        .on_binding_request(Box::new(
+            move |_: Message, _local: Arc<dyn Candidate + Send + Sync>, _remote: SocketAddr| {
+                let weak_agent = weak_agent.clone();
+                let pairs_tx = pairs_tx.clone();
+                Box::pin(async move {
+                    if let Some(agent) = weak_agent.upgrade() {
+                        let _ = pairs_tx.send(agent.get_candidate_pairs().await.len());
+                    }
+                })
+            },
+        ))
+        .await;
+
+    let _ = connect_with_vnet(&a_agent, &b_agent).await?;
+
+    let pairs = tokio::time::timeout(Duration::from_secs(5), pairs_rx.recv())
+        .await
+        .expect("the handler should not block the agent");
+    assert!(
+        pairs.unwrap_or_default() > 0,
+        "the handler should see the pair of the request"
+    );
+
+    a_agent.close().await?;
+    b_agent.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_candidate_pairs() -> Result<(), IceError> {
+    let a = Agent::new(AgentConfig::default()).await?;
+    assert!(a.get_candidate_pairs().await.is_empty(), "no pair yet");
+    a.close().await?;
+
+    let (_, _, agent_a, agent_b) = pipe(None, None).await?;
+
+    let selected = agent_b.get_selected_candidate_pair().await.unwrap();
+    let pairs = agent_b.get_candidate_pairs().await;
+    let p = pairs
+        .iter()
+        .find(|p| Arc::ptr_eq(p, &selected))
+        .expect("the selected pair should be in the checklist");
+    assert_eq!(p.state(), CandidatePairState::Succeeded);
+    assert!(p.nominated(), "the controlling side nominated the pair");
+    assert_eq!(p.component(), COMPONENT_RTP);
+    assert!(p.priority() > 0);
+
+    agent_a.close().await?;
+    agent_b.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_local_and_remote_candidate_inits() -> Result<(), IceError> {
+    let a = Agent::new(AgentConfig::default()).await?;
+    assert!(a.get_local_candidate_inits().await?.is_empty());
+    assert!(a.get_remote_candidate_inits().await?.is_empty());
+    a.close().await?;
+
+    let (_, _, agent_a, agent_b) = pipe(None, None).await?;
+
+    let local = agent_a.get_local_candidate_inits().await?;
+    assert!(!local.is_empty(), "host candidates should be gathered");
+    let remote = agent_b.get_remote_candidate_inits().await?;
+    for c in &local {
+        assert!(
+            remote.iter().any(|r| r.marshal() == c.marshal()),
+            "{} should be a remote candidate of the other agent",
+            c.marshal()
+        );
+    }
+
+    // Snapshots don't follow the agent
+    agent_a.close().await?;
+    agent_b.close().await?;
+    assert_eq!(local[0].component, COMPONENT_RTP);
+    assert!(agent_a.get_local_candidate_inits().await?.is_empty());
+    assert!(agent_a.get_local_candidates().await?.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_add_local_candidate_with_conn() -> Result<(), IceError> {
+    let a = Arc::new(Agent::new(AgentConfig::default()).await?);
+    let b = Arc::new(Agent::new(AgentConfig::default()).await?);
+
+    for agent in &[&a, &b] {
+        let conn: Arc<dyn Conn + Send + Sync> =
+            Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await?);
+        let local_addr = conn.local_addr().await?;
+        let c = agent
+            .add_local_candidate_with_conn(CandidateHostConfig::default(), conn)
+            .await?;
+        assert_eq!(c.candidate_type(), CandidateType::Host);
+        assert_eq!(c.address(), "127.0.0.1");
+        assert_eq!(c.port(), local_addr.port());
+        assert_eq!(c.component(), COMPONENT_RTP);
+    }
+
+    let conn: Arc<dyn Conn + Send + Sync> =
+        Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await?);
+    let config = CandidateHostConfig {
+        base_config: CandidateBaseConfig {
+            component: COMPONENT_RTCP,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let err = a
+        .add_local_candidate_with_conn(config, conn)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, IceError::UnknownComponent), "{}", err);
+
+    // The candidates are signaled like gathered ones
+    for (from, to) in &[(&a, &b), (&b, &a)] {
+        for c in from.get_local_candidates().await? {
+            let c: Arc<dyn Candidate + Send + Sync> =
+                Arc::new(to.unmarshal_remote_candidate(c.marshal()).await?);
+            to.add_remote_candidate(&c).await?;
+        }
+    }
+
+    let (a_ufrag, a_pwd) = a.get_local_user_credentials().await;
+    let (b_ufrag, b_pwd) = b.get_local_user_credentials().await;
+    let agent_a = Arc::clone(&a);
+    let accepted = tokio::spawn(async move {
+        let (_cancel_tx, cancel_rx) = mpsc::channel(1);
+        agent_a.accept(cancel_rx, b_ufrag, b_pwd).await
+    });
+    let (_cancel_tx, cancel_rx) = mpsc::channel(1);
+    let b_conn = b.dial(cancel_rx, a_ufrag, a_pwd).await?;
+    let a_conn = accepted
+        .await
+        .map_err(|err| IceError::Task(err.to_string()))??;
+
+    b_conn.send(b"ping").await?;
+    let mut buf = vec![0u8; 1500];
+    let n = a_conn.recv(&mut buf).await?;
+    assert_eq!(&buf[..n], b"ping");
+
+    a.close().await?;
+    b.close().await?;
+    assert!(
+        a.add_local_candidate_with_conn(
+            CandidateHostConfig::default(),
+            Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await?),
+        )
+        .await
+        .is_err(),
+        "a closed agent takes no candidate"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_candidate_counters() -> Result<(), IceError> {
+    let (ca, cb, agent_a, agent_b) = pipe(None, None).await?;
+
+    let p = agent_b.get_selected_candidate_pair().await.unwrap();
+    let (sent, received) = (p.local.bytes_sent(), p.local.bytes_received());
+    assert!(p.local.stun_requests_sent() > 0, "checks were sent");
+    assert!(
+        p.local.stun_responses_received() > 0,
+        "checks were answered"
+    );
+    assert!(sent > 0 && received > 0);
+
+    let msg = b"counted";
+    cb.send(msg).await?;
+    let mut buf = vec![0u8; msg.len()];
+    ca.recv(&mut buf).await?;
+
+    assert!(p.local.bytes_sent() >= sent + msg.len() as u64);
+
+    agent_a.close().await?;
+    agent_b.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_close_with_timeout() -> Result<(), IceError> {
+    let (_, _, agent_a, agent_b) = pipe(None, None).await?;
+
+    let report = agent_a.close_with_timeout(Duration::from_secs(5)).await?;
+    assert!(report.is_clean(), "unexpected failures: {:?}", report);
+    assert!(agent_a.get_local_candidates().await?.is_empty());
+    if let Err(err) = agent_a.close_with_timeout(Duration::from_secs(5)).await {
+        assert!(matches!(err, IceError::Closed));
+    } else {
+        panic!("expected error, but got ok");
+    }
+    agent_b.close().await?;
+
+    // A stuck agent is given up on at the deadline instead of hanging the close
+    let a = Agent::new(AgentConfig::default()).await?;
+    {
+        let _ai = a.agent_internal.lock().await;
+        let report = a.close_with_timeout(Duration::from_millis(100)).await?;
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].resource, "agent");
+        assert!(matches!(report.failures[0].error, IceError::Timeout(_)));
+    }
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_late_remote_credentials() -> Result<(), IceError> {
+    let new_agent = || {
+        Agent::new(AgentConfig {
+            network_types: supported_network_types(),
+            ..Default::default()
+        })
+    };
+    let a_agent = Arc::new(new_agent().await?);
+    let b_agent = Arc::new(new_agent().await?);
+    let (a_ufrag, a_pwd) = a_agent.get_local_user_credentials().await;
+    let (b_ufrag, b_pwd) = b_agent.get_local_user_credentials().await;
+
+    // b starts with outdated credentials, as after a remote restart
+    b_agent
+        .set_remote_credentials("outdated".to_owned(), "outdatedoutdatedoutdated".to_owned())
+        .await?;
+    gather_and_exchange_candidates(&a_agent, &b_agent).await?;
+
+    let (connected_tx, mut connected_rx) = mpsc::channel(2);
+    let (_a_cancel_tx, a_cancel_rx) = mpsc::channel(1);
+    let (agent_a, a_connected_tx) = (Arc::clone(&a_agent), connected_tx.clone());
+    tokio::spawn(async move {
+        // a doesn't know the credentials of b yet
+        let a_conn = agent_a
+            .accept(a_cancel_rx, String::new(), String::new())
+            .await;
+        let _ = a_connected_tx.send(a_conn.is_ok()).await;
+    });
+    let (_b_cancel_tx, b_cancel_rx) = mpsc::channel(1);
+    let agent_b = Arc::clone(&b_agent);
+    tokio::spawn(async move {
+        let b_conn = agent_b
+            .dial(b_cancel_rx, String::new(), String::new())
+            .await;
+        let _ = connected_tx.send(b_conn.is_ok()).await;
+    });
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(
+        a_agent.get_selected_candidate_pair().await.is_none()
+            && b_agent.get_selected_candidate_pair().await.is_none(),
+        "no check should succeed without the right credentials"
+    );
+
+    a_agent.set_remote_credentials(b_ufrag, b_pwd).await?;
+    b_agent.set_remote_credentials(a_ufrag, a_pwd).await?;
+    for _ in 0..2 {
+        let connected = tokio::time::timeout(Duration::from_secs(10), connected_rx.recv()).await;
+        assert!(
+            matches!(connected, Ok(Some(true))),
+            "the agents should connect"
+        );
+    }
+
+    a_agent.close().await?;
+    b_agent.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_prflx_priority() -> Result<(), IceError> {
+    let a = Agent::new(AgentConfig::default()).await?;
+    let local = new_test_host(&a, "192.168.1.1", 19216).await?;
+
+    let prflx_priority = peer_reflexive_priority(&*local);
+    assert_eq!(
+        prflx_priority >> 24,
+        u32::from(CandidateType::PeerReflexive.preference())
+    );
+    assert_eq!(prflx_priority & 0x00ff_ffff, local.priority() & 0x00ff_ffff);
+
+    // The priority of the local candidate is sent by default
+    {
+        let ai = a.agent_internal.lock().await;
+        assert_eq!(ai.check_priority(&local), local.priority());
+    }
+    a.close().await?;
+
+    let a = Agent::new(AgentConfig {
+        prflx_priority: Arc::new(Some(Box::new(|c: &(dyn Candidate + Send + Sync)| {
+            peer_reflexive_priority(c) - 1
+        }))),
+        ..Default::default()
+    })
+    .await?;
+    {
+        let ai = a.agent_internal.lock().await;
+        assert_eq!(ai.check_priority(&local), prflx_priority - 1);
+    }
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_prune_redundant_candidates_and_pairs() -> Result<(), IceError> {
+    let a = Agent::new(AgentConfig::default()).await?;
+
+    let host_local = new_test_host(&a, "192.168.1.1", 19216).await?;
+    // Without a NAT the server reflexive address is the host address
+    let redundant_srflx = new_test_srflx(&a, "192.168.1.1", 19216, "192.168.1.1", 19216).await?;
+    let srflx_local = new_test_srflx(&a, "1.2.3.4", 12340, "192.168.1.1", 19216).await?;
+    let remote = new_test_host(&a, "1.2.3.5", 12350).await?;
+
+    {
+        let mut ai = a.agent_internal.lock().await;
+        ai.add_candidate(&host_local).await?;
+        ai.add_candidate(&redundant_srflx).await?;
+        let local_candidates = &ai.local_candidates[&NetworkType::Udp4];
+        assert_eq!(
+            local_candidates.len(),
+            1,
+            "the srflx candidate is redundant"
+        );
+        assert!(local_candidates[0].equal(&*host_local));
+
+        // Checks from the srflx candidate would leave from the same base as the host ones
+        ai.add_pair(Arc::clone(&srflx_local), Arc::clone(&remote))
+            .await;
+        ai.add_pair(Arc::clone(&host_local), Arc::clone(&remote))
+            .await;
+        ai.add_pair(Arc::clone(&srflx_local), Arc::clone(&remote))
+            .await;
+        let mut checklist = ai.agent_conns[0].checklist.lock().await;
+        assert_eq!(checklist.len(), 1, "the srflx pair is redundant");
+        assert!(checklist[0].local.equal(&*host_local));
+
+        // A pair whose checks succeeded is kept, even with a lower priority
+        checklist.clear();
+        drop(checklist);
+        ai.add_pair(Arc::clone(&srflx_local), Arc::clone(&remote))
+            .await;
+        let p = Arc::clone(&ai.agent_conns[0].checklist.lock().await[0]);
+        ai.set_pair_state(&p, CandidatePairState::Succeeded);
+        ai.add_pair(Arc::clone(&host_local), Arc::clone(&remote))
+            .await;
+        let checklist = ai.agent_conns[0].checklist.lock().await;
+        assert_eq!(checklist.len(), 1);
+        assert!(
+            checklist[0].local.equal(&*srflx_local),
+            "a succeeded pair should not be pruned"
+        );
+    }
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_prune_duplicate_server_reflexive_candidates() -> Result<(), IceError> {
+    let a = Agent::new(AgentConfig::default()).await?;
+
+    // The sockets of two interfaces mapped to the same address by a NAT, one mapped to another
+    // port of the same NAT and one behind another NAT
+    let mut srflx = vec![];
+    for (address, port, rel_addr) in &[
+        ("1.2.3.4", 12340, "192.168.1.1"),
+        ("1.2.3.4", 12340, "10.0.0.1"),
+        ("1.2.3.4", 12341, "10.0.0.2"),
+        ("5.6.7.8", 56780, "10.0.1.1"),
+    ] {
+        srflx.push(new_test_srflx(&a, address, *port, rel_addr, 19216).await?);
+    }
+
+    {
+        let mut ai = a.agent_internal.lock().await;
+        for c in &srflx {
+            ai.add_candidate(c).await?;
+        }
+        let local_candidates = &ai.local_candidates[&NetworkType::Udp4];
+        assert_eq!(
+            local_candidates.len(),
+            3,
+            "the second candidate of 1.2.3.4:12340 is a duplicate"
+        );
+        assert!(local_candidates[0].equal(&*srflx[0]));
+        assert!(local_candidates[1].equal(&*srflx[2]));
+        assert!(local_candidates[2].equal(&*srflx[3]));
+    }
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_keepalive_binding_indication() -> Result<(), IceError> {
+    // Consent is only refreshed once an hour, so that every check after selection is a keepalive
+    let cfg = AgentConfig {
+        keepalive_interval: Some(Duration::from_millis(100)),
+        keepalive_strategy: KeepaliveStrategy::BindingIndication,
+        consent_check_interval: Some(Duration::from_secs(3600)),
+        ..Default::default()
+    };
+    let (_, _, agent_a, agent_b) = pipe(Some(cfg), None).await?;
+
+    {
+        let ai = agent_a.agent_internal.lock().await;
+        assert_eq!(
+            ai.keepalive_strategy,
+            KeepaliveStrategy::BindingIndication,
+            "should match"
+        );
+    }
+
+    let requests_sent = selected_pair_stats(&agent_a).await.requests_sent;
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(
+        selected_pair_stats(&agent_a).await.requests_sent,
+        requests_sent,
+        "keepalives should not be binding requests"
+    );
+
+    // The indications are still received by the remote
+    let p = agent_b.get_selected_candidate_pair().await.unwrap();
+    let since_received = SystemTime::now()
+        .duration_since(p.remote().last_received())
+        .unwrap_or_default();
+    assert!(
+        since_received < Duration::from_millis(500),
+        "keepalives should be received, last one {:?} ago",
+        since_received
+    );
+
+    agent_a.close().await?;
+    agent_b.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pacer_spaces_transactions() -> Result<(), IceError> {
+    let interval = Duration::from_millis(50);
+    let pacer = Pacer::new(interval);
+    assert!(pacer.is_ready(), "the first transaction should not wait");
+
+    let start = Instant::now();
+    for _ in 0..3 {
+        pacer.acquire().await;
+    }
+    assert!(
+        start.elapsed() >= interval * 2,
+        "transactions should be spaced by the interval"
+    );
+
+    // A transaction which can't wait delays the following ones
+    pacer.acquire_now();
+    assert!(!pacer.is_ready(), "should wait for the next slot");
+
+    let unpaced = Pacer::new(Duration::from_secs(0));
+    unpaced.acquire_now();
+    assert!(unpaced.is_ready(), "an interval of 0 disables pacing");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_agent_id() -> Result<(), IceError> {
+    let a = Agent::new(AgentConfig {
+        agent_id: "session-1".to_owned(),
+        ..Default::default()
+    })
+    .await?;
+    assert_eq!(a.id(), "session-1");
+
+    // Agents are told apart without being given an id
+    let b = Agent::new(AgentConfig::default()).await?;
+    let c = Agent::new(AgentConfig::default()).await?;
+    assert!(!b.id().is_empty());
+    assert_ne!(b.id(), c.id());
+
+    a.close().await?;
+    b.close().await?;
+    c.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ice_options() -> Result<(), IceError> {
+    let a = Agent::new(AgentConfig {
+        nomination_strategy: NominationStrategy::Aggressive,
+        ..Default::default()
+    })
+    .await?;
+    assert_eq!(a.local_ice_options().await, DEFAULT_ICE_OPTIONS);
+    assert_eq!(a.negotiated_ice_options().await, None);
+    assert!(a.agent_internal.lock().await.nominates_aggressively());
+
+    a.set_remote_ice_options(IceOptions::unmarshal("a=ice-options:trickle ice2"))
+        .await;
+    assert_eq!(
+        a.negotiated_ice_options().await,
+        Some(IceOptions {
+            trickle: true,
+            renomination: false,
+            ice2: true,
+        })
+    );
+    assert!(
+        !a.agent_internal.lock().await.nominates_aggressively(),
+        "aggressive nomination is not used with RFC 8445 agents"
+    );
+
+    let b = Agent::new(AgentConfig {
+        ice_options: Some(IceOptions::default()),
+        ..Default::default()
+    })
+    .await?;
+    b.set_remote_ice_options(DEFAULT_ICE_OPTIONS).await;
+    assert_eq!(
+        b.negotiated_ice_options().await,
+        Some(IceOptions::default()),
+        "only the options advertised by both agents are used"
+    );
+
+    a.close().await?;
+    b.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rfc8445_strict_mode() -> Result<(), IceError> {
+    let result = Agent::new(AgentConfig {
+        compatibility_mode: CompatibilityMode::Rfc8445Strict,
+        nomination_strategy: NominationStrategy::Aggressive,
+        ..Default::default()
+    })
+    .await;
+    assert!(matches!(result, Err(IceError::AggressiveNominationStrict)));
+
+    let a = Agent::new(AgentConfig {
+        compatibility_mode: CompatibilityMode::Rfc8445Strict,
+        pacing_interval: Some(Duration::from_secs(0)),
+        ..Default::default()
+    })
+    .await?;
+
+    let local = new_test_host(&a, "192.168.1.1", 19216).await?;
+    let mut srflx = vec![];
+    for (port, rel_addr) in &[(12340, "192.168.1.1"), (12341, "10.0.0.1")] {
+        srflx.push(new_test_srflx(&a, "1.2.3.4", *port, rel_addr, 19216).await?);
+    }
+
+    {
+        let mut ai = a.agent_internal.lock().await;
+        assert_eq!(
+            ai.pacer.interval(),
+            MIN_PACING_INTERVAL,
+            "pacing can't be disabled"
+        );
+
+        for c in &srflx {
+            ai.add_candidate(c).await?;
+        }
+        assert_eq!(
+            ai.local_candidates[&NetworkType::Udp4].len(),
+            2,
+            "server reflexive candidates of the same address are not redundant"
+        );
+
+        for port in 0..=MAX_CANDIDATE_PAIRS {
+            let remote = new_test_host(&a, "1.2.3.5", 20000 + port as u16).await?;
+            ai.add_pair(Arc::clone(&local), remote).await;
+        }
+        assert_eq!(
+            ai.agent_conns[0].checklist.lock().await.len(),
+            MAX_CANDIDATE_PAIRS,
+            "the checklists are limited"
+        );
+    }
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_hairpin_detection() -> Result<(), IceError> {
+    let a = Agent::new(AgentConfig::default()).await?;
+    let mut events = a.events();
+
+    let host_local = new_test_host(&a, "192.168.1.1", 19216).await?;
+
+    // Both agents are behind the NAT of 1.2.3.4, the remote one is also behind 5.6.7.8
+    let mut srflx = vec![];
+    for (address, port, rel_addr) in &[
+        ("1.2.3.4", 12340, "192.168.1.1"),
+        ("1.2.3.4", 12341, "192.168.1.2"),
+        ("5.6.7.8", 56780, "10.0.0.2"),
+    ] {
+        srflx.push(new_test_srflx(&a, address, *port, rel_addr, 19216).await?);
+    }
+
+    {
+        let mut ai = a.agent_internal.lock().await;
+        ai.add_candidate(&host_local).await?;
+        ai.add_candidate(&srflx[0]).await?;
+
+        let hairpin = Arc::new(CandidatePair::new(
+            Arc::clone(&host_local),
+            Arc::clone(&srflx[1]),
+            true,
+        ));
+        let direct = Arc::new(CandidatePair::new(
+            Arc::clone(&host_local),
+            Arc::clone(&srflx[2]),
+            true,
+        ));
+        ai.set_pair_state(&hairpin, CandidatePairState::Succeeded);
+        ai.set_pair_state(&direct, CandidatePairState::Succeeded);
+        assert!(hairpin.hairpinned());
+        assert!(
+            !direct.hairpinned(),
+            "the remote agent is behind another NAT"
+        );
+
+        // Once the agents reach each other directly, the hairpinning doesn't matter anymore
+        let host_remote = new_test_host(&a, "192.168.1.2", 19216).await?;
+        let host = Arc::new(CandidatePair::new(
+            Arc::clone(&host_local),
+            host_remote,
+            true,
+        ));
+        ai.set_pair_state(&host, CandidatePairState::Succeeded);
+        let late_hairpin = Arc::new(CandidatePair::new(
+            Arc::clone(&host_local),
+            Arc::clone(&srflx[1]),
+            true,
+        ));
+        ai.set_pair_state(&late_hairpin, CandidatePairState::Succeeded);
+        assert!(
+            !late_hairpin.hairpinned(),
+            "a pair of host candidates succeeded"
+        );
+    }
+
+    let mut hairpins = 0;
+    while let Ok(event) = events.try_recv() {
+        if let AgentEvent::Hairpin(p) = event {
+            assert!(p.remote.equal(&*srflx[1]));
+            hairpins += 1;
+        }
+    }
+    assert_eq!(hairpins, 1, "the hairpin should be reported once");
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_candidate_priority() -> Result<(), IceError> {
+    // Relay candidates are preferred to the other ones, and IPv6 ones are demoted
+    let a = Agent::new(AgentConfig {
+        candidate_priority: Arc::new(Some(Box::new(
+            |c: &(dyn Candidate + Send + Sync), mut preferences: CandidatePreferences| {
+                if c.candidate_type() == CandidateType::Relay {
+                    preferences.type_preference = 126;
+                } else if c.network_type().is_ipv6() {
+                    preferences.local_preference /= 2;
+                }
+                preferences
+            },
+        ))),
+        ..Default::default()
+    })
+    .await?;
+
+    let relay: Arc<dyn Candidate + Send + Sync> = Arc::new(
+        CandidateRelayConfig {
+            base_config: test_base_config("1.2.3.4", 12340),
+            rel_addr: "4.3.2.1".to_owned(),
+            rel_port: 43210,
+            ..Default::default()
+        }
+        .new_candidate_relay(Some(a.agent_internal.clone()))
+        .await?,
+    );
+    let host_ipv6 = new_test_host(&a, "::1", 12341).await?;
+    // A priority set on the candidate is kept
+    let host = new_test_host_with(
+        &a,
+        CandidateBaseConfig {
+            priority: 500,
+            ..test_base_config("192.168.1.1", 12342)
+        },
+    )
+    .await?;
+
+    let default_ipv6_priority = host_ipv6.priority();
+    {
+        let mut ai = a.agent_internal.lock().await;
+        ai.add_candidate(&relay).await?;
+        ai.add_candidate(&host_ipv6).await?;
+        ai.add_candidate(&host).await?;
+    }
+
+    assert_eq!(
+        CandidatePreferences::from_priority(relay.priority()),
+        CandidatePreferences {
+            type_preference: 126,
+            local_preference: 65535,
+        }
+    );
+    assert_eq!(
+        CandidatePreferences::from_priority(host_ipv6.priority()).local_preference,
+        CandidatePreferences::from_priority(default_ipv6_priority).local_preference / 2
+    );
+    assert_eq!(host.priority(), 500);
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pause_and_resume_checks() -> Result<(), IceError> {
+    let cfg = || AgentConfig {
+        disconnected_timeout: Some(Duration::from_millis(500)),
+        failed_timeout: Some(Duration::from_millis(500)),
+        keepalive_interval: Some(Duration::from_millis(100)),
+        ..Default::default()
+    };
+    let (_, _, agent_a, agent_b) = pipe(Some(cfg()), Some(cfg())).await?;
+
+    agent_a.pause_checks().await;
+    agent_b.pause_checks().await;
+    assert!(agent_a.checks_paused().await, "should be paused");
+    let requests_sent = selected_pair_stats(&agent_a).await.requests_sent;
+
+    // Nothing is sent nor received while paused, which must neither disconnect nor fail the
+    // agents once the timeouts have elapsed
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert_eq!(
+        requests_sent,
+        selected_pair_stats(&agent_a).await.requests_sent,
+        "no keepalive should be sent while paused"
+    );
+    assert_eq!(
+        ConnectionState::Connected,
+        agent_a.agent_internal.lock().await.connection_state,
+        "should match"
+    );
+
+    agent_a.resume_checks().await;
+    agent_b.resume_checks().await;
+    assert!(!agent_a.checks_paused().await, "should be resumed");
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(
+        selected_pair_stats(&agent_a).await.requests_sent > requests_sent,
+        "keepalives should be sent once resumed"
+    );
+    assert_eq!(
+        ConnectionState::Connected,
+        agent_a.agent_internal.lock().await.connection_state,
+        "should match"
+    );
+
+    agent_a.close().await?;
+    agent_b.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_release_unused_candidates() -> Result<(), IceError> {
+    let cfg = || AgentConfig {
+        include_loopback: true,
+        keepalive_interval: Some(Duration::from_millis(100)),
+        release_unused_candidates_after: Some(Duration::from_millis(200)),
+        ..Default::default()
+    };
+    let (ca, cb, agent_a, agent_b) = pipe(Some(cfg()), Some(cfg())).await?;
+
+    tokio::time::sleep(Duration::from_millis(800)).await;
+
+    // Only the local candidate of the selected pair is left
+    let p = agent_a.get_selected_candidate_pair().await.unwrap();
+    let local_candidates = agent_a.get_local_candidates().await?;
+    assert_eq!(1, local_candidates.len(), "should match");
+    assert_eq!(
+        (p.local().address(), p.local().port()),
+        (local_candidates[0].address(), local_candidates[0].port()),
+        "should match"
+    );
+
+    let msg = b"hello";
+    ca.send(msg).await?;
+    let mut buf = vec![0u8; msg.len()];
+    let n = cb.recv(&mut buf).await?;
+    assert_eq!(&buf[..n], msg, "should match");
+
+    agent_a.close().await?;
+    agent_b.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_release_unused_candidates_keeps_shared_conns() -> Result<(), IceError> {
+    let mux_conn: Arc<dyn Conn + Send + Sync> =
+        Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await?);
+    let mux_port = mux_conn.local_addr().await?.port();
+    let udp_mux = UdpMuxDefault::new(UdpMuxParams { conn: mux_conn });
+
+    let a = Agent::new(AgentConfig {
+        network_types: vec![NetworkType::Udp4],
+        udp_mux: Some(Arc::clone(&udp_mux) as Arc<dyn UdpMux + Send + Sync>),
+        release_unused_candidates_after: Some(Duration::from_secs(0)),
+        ..Default::default()
+    })
+    .await?;
+
+    {
+        let mut ai = a.agent_internal.lock().await;
+        let conn = udp_mux.get_conn(&ai.local_ufrag, &ai.agent_id).await?;
+
+        // The host and server reflexive candidates of the mux read the same connection
+        let host = new_test_host_with(
+            &a,
+            CandidateBaseConfig {
+                conn: Some(Arc::clone(&conn)),
+                ..test_base_config("127.0.0.1", mux_port)
+            },
+        )
+        .await?;
+        let srflx = new_test_srflx_with(
+            &a,
+            CandidateBaseConfig {
+                conn: Some(conn),
+                ..test_base_config("1.2.3.4", 12340)
+            },
+            "127.0.0.1",
+            mux_port,
+        )
+        .await?;
+        let other_conn: Arc<dyn Conn + Send + Sync> =
+            Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await?);
+        let other_port = other_conn.local_addr().await?.port();
+        let other = new_test_host_with(
+            &a,
+            CandidateBaseConfig {
+                conn: Some(other_conn),
+                ..test_base_config("127.0.0.1", other_port)
+            },
+        )
+        .await?;
+        let remote = new_test_host(&a, "192.168.0.2", 1000).await?;
+
+        for c in &[&host, &srflx, &other] {
+            ai.add_candidate(c).await?;
+        }
+        let p = Arc::new(CandidatePair::new(Arc::clone(&srflx), remote, true));
+        ai.set_selected_pair(Some(p)).await;
+        ai.release_unused_candidates().await;
+
+        let local_candidates: Vec<_> = ai.local_candidates.values().flatten().collect();
+        assert_eq!(
+            2,
+            local_candidates.len(),
+            "only the other host should be released"
+        );
+        assert!(local_candidates.iter().any(|c| c.equal(&*host)));
+        assert!(local_candidates.iter().any(|c| c.equal(&*srflx)));
+    }
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dial_and_accept_connected() -> Result<(), IceError> {
+    let a_agent = Arc::new(Agent::new(AgentConfig::default()).await?);
+    let b_agent = Arc::new(Agent::new(AgentConfig::default()).await?);
+
+    let (a_ufrag, a_pwd) = a_agent.get_local_user_credentials().await;
+    let (b_ufrag, b_pwd) = b_agent.get_local_user_credentials().await;
+
+    gather_and_exchange_candidates(&a_agent, &b_agent).await?;
+
+    let agent_a = Arc::clone(&a_agent);
+    let accepted = tokio::spawn(async move { agent_a.accept_connected(b_ufrag, b_pwd).await });
+    let b_conn = tokio::time::timeout(
+        Duration::from_secs(10),
+        b_agent.dial_connected(a_ufrag, a_pwd),
+    )
+    .await
+    .expect("agent_b should connect")?;
+    let a_conn = tokio::time::timeout(Duration::from_secs(10), accepted)
+        .await
+        .expect("agent_a should connect")
+        .unwrap()?;
+
+    let msg = b"hello";
+    b_conn.send(msg).await?;
+    let mut buf = vec![0u8; msg.len()];
+    let n = a_conn.recv(&mut buf).await?;
+    assert_eq!(&buf[..n], msg, "should match");
+
+    a_agent.close().await?;
+    b_agent.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dial_connected_fails() -> Result<(), IceError> {
+    let a = Agent::new(AgentConfig {
+        disconnected_timeout: Some(Duration::from_millis(100)),
+        failed_timeout: Some(Duration::from_millis(100)),
+        ..Default::default()
+    })
+    .await?;
+
+    // No remote candidate is ever added
+    let result = tokio::time::timeout(
+        Duration::from_secs(5),
+        a.dial_connected(generate_ufrag(), generate_pwd()),
+    )
+    .await
+    .expect("dial should fail in time");
+    assert!(matches!(result, Err(IceError::ConnectionFailed)));
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_valid_candidate_pair_event() -> Result<(), IceError> {
+    let cfg = || AgentConfig {
+        send_on_valid_pair: true,
+        ..Default::default()
+    };
+    let a_agent = Arc::new(Agent::new(cfg()).await?);
+    let b_agent = Arc::new(Agent::new(cfg()).await?);
+    let mut events = b_agent.events();
+
+    let (a_ufrag, a_pwd) = a_agent.get_local_user_credentials().await;
+    let (b_ufrag, b_pwd) = b_agent.get_local_user_credentials().await;
+
+    gather_and_exchange_candidates(&a_agent, &b_agent).await?;
+
+    let agent_a = Arc::clone(&a_agent);
+    let accepted = tokio::spawn(async move { agent_a.accept_connected(b_ufrag, b_pwd).await });
+    let b_conn = tokio::time::timeout(
+        Duration::from_secs(10),
+        b_agent.dial_connected(a_ufrag, a_pwd),
+    )
+    .await
+    .expect("agent_b should connect")?;
+    let a_conn = accepted.await.unwrap()?;
+
+    // The valid pair is reported once, before the selected one
+    let mut valid_pairs = 0;
+    loop {
+        match events.try_recv() {
+            Ok(AgentEvent::ValidCandidatePair(p)) => {
+                assert_eq!(p.state(), CandidatePairState::Succeeded, "should match");
+                valid_pairs += 1;
+            }
+            Ok(AgentEvent::SelectedCandidatePairChange(_)) => {
+                assert_eq!(valid_pairs, 1, "should be reported before selection");
+            }
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+    assert_eq!(valid_pairs, 1, "should match");
+
+    let msg = b"hello";
+    b_conn.send(msg).await?;
+    let mut buf = vec![0u8; msg.len()];
+    let n = a_conn.recv(&mut buf).await?;
+    assert_eq!(&buf[..n], msg, "should match");
+
+    a_agent.close().await?;
+    b_agent.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_binding_request_filter() -> Result<(), IceError> {
+    // Only the checks from 172.17.0.3 are accepted
+    let a = Agent::new(AgentConfig {
+        binding_request_filter: Arc::new(Some(Box::new(
+            |_: &Message, _: &(dyn Candidate + Send + Sync), remote: SocketAddr| {
+                remote.ip() == Ipv4Addr::new(172, 17, 0, 3)
+            },
+        ))),
+        ..Default::default()
+    })
+    .await?;
+
+    let local = new_test_host(&a, "192.168.0.2", 777).await?;
+
+    let (username, local_pwd) = {
+        let ai = a.agent_internal.lock().await;
+        (
+            ai.local_ufrag.to_owned() + ":" + ai.remote_ufrag.as_str(),
+            ai.local_pwd.clone(),
+        )
+    };
+
+    for (remote, accepted) in [("172.17.0.4:999", false), ("172.17.0.3:999", true)].iter() {
+        let mut msg = Message::new();
+        msg.build(&[
+            Box::new(BINDING_REQUEST),
+            Box::new(TransactionId::new()),
+            Box::new(Username::new(ATTR_USERNAME, username.clone())),
+            Box::new(PriorityAttr(local.priority())),
+            Box::new(MessageIntegrity::new_short_term_integrity(
+                local_pwd.clone(),
+            )),
+            Box::new(FINGERPRINT),
+        ])?;
+
+        let agent_internal_clone = Arc::clone(&a.agent_internal);
+        let mut ai = a.agent_internal.lock().await;
+        ai.handle_inbound(
+            &mut msg,
+            &local,
+            SocketAddr::from_str(remote)?,
+            agent_internal_clone,
+        )
+        .await;
+
+        // A rejected request creates no peer reflexive candidate
+        let remote_candidates = ai
+            .remote_candidates
+            .get(&local.network_type())
+            .map_or(0, Vec::len);
+        assert_eq!(usize::from(*accepted), remote_candidates, "{}", remote);
+    }
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_role_switch_on_role_conflict_response() -> Result<(), IceError> {
+    let a = Agent::new(AgentConfig {
+        is_controlling: true,
+        max_role_switches: Some(1),
+        ..Default::default()
+    })
+    .await?;
+    let mut events = a.events();
+
+    let local = new_test_host(&a, "192.168.0.2", 777).await?;
+    let remote = new_test_host(&a, "172.17.0.3", 999).await?;
+
+    let (remote_pwd, tie_breaker) = {
+        let mut ai = a.agent_internal.lock().await;
+        ai.add_remote_candidate(&remote).await;
+        ai.add_pair(Arc::clone(&local), Arc::clone(&remote)).await;
+        (ai.remote_pwd.clone(), ai.tie_breaker)
+    };
+
+    // The first conflict switches the role, the second one, answering a check sent with the
+    // new role, fails the pair as the agent may switch only once
+    for is_controlling in [true, false].iter() {
+        let tid = TransactionId::new();
+        let mut msg = Message::new();
+        msg.build(&[
+            Box::new(BINDING_ERROR),
+            Box::new(tid),
+            Box::new(CODE_ROLE_CONFLICT),
+            Box::new(MessageIntegrity::new_short_term_integrity(
+                remote_pwd.clone(),
+            )),
+            Box::new(FINGERPRINT),
+        ])?;
+
+        let agent_internal_clone = Arc::clone(&a.agent_internal);
+        let mut ai = a.agent_internal.lock().await;
+        ai.pending_binding_requests = vec![BindingRequest {
+            timestamp: Instant::now(),
+            transaction_id: tid,
+            destination: SocketAddr::from_str("172.17.0.3:999")?,
+            is_use_candidate: false,
+            is_controlling: *is_controlling,
+            pair: None,
+        }];
+        ai.handle_inbound(
+            &mut msg,
+            &local,
+            SocketAddr::from_str("172.17.0.3:999")?,
+            agent_internal_clone,
+        )
+        .await;
+        assert!(!ai.is_controlling, "should be controlled");
+    }
+
+    {
+        let ai = a.agent_internal.lock().await;
+        assert_ne!(tie_breaker, ai.tie_breaker, "should be regenerated");
+        let p = ai.find_pair(&local, &remote).await.unwrap();
+        assert_eq!(p.state(), CandidatePairState::Failed, "should match");
+    }
+
+    let mut role_changes = vec![];
+    let mut role_conflicts = vec![];
+    while let Ok(event) = events.try_recv() {
+        match event {
+            AgentEvent::RoleChange { is_controlling } => role_changes.push(is_controlling),
+            AgentEvent::Error(IceError::RoleConflict {
+                remote,
+                role_switches,
+            }) => role_conflicts.push((remote, role_switches)),
+            _ => {}
+        }
+    }
+    assert_eq!(role_changes, vec![false], "should switch once");
+    assert_eq!(
+        role_conflicts,
+        vec![(SocketAddr::from_str("172.17.0.3:999")?, 1)],
+        "the unresolved conflict should be reported"
+    );
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_role_determined_by_first_check() -> Result<(), IceError> {
+    let a_agent = Arc::new(Agent::new(AgentConfig::default()).await?);
+    let b_agent = Arc::new(
+        Agent::new(AgentConfig {
+            role: IceRole::Unspecified,
+            ..Default::default()
+        })
+        .await?,
+    );
+    assert_eq!(
+        a_agent.role().await,
+        IceRole::Controlled,
+        "agents are controlled by default"
+    );
+    assert_eq!(b_agent.role().await, IceRole::Unspecified, "should match");
+
+    let (a_ufrag, a_pwd) = a_agent.get_local_user_credentials().await;
+    let (b_ufrag, b_pwd) = b_agent.get_local_user_credentials().await;
+
+    gather_and_exchange_candidates(&a_agent, &b_agent).await?;
+
+    // agent_b learns its role from the checks of agent_a
+    b_agent.set_remote_credentials(a_ufrag, a_pwd).await?;
+    let agent_a = Arc::clone(&a_agent);
+    let dialed = tokio::spawn(async move {
+        let (_cancel_tx, cancel_rx) = mpsc::channel(1);
+        agent_a.dial(cancel_rx, b_ufrag, b_pwd).await.map(|_| ())
+    });
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while b_agent.role().await == IceRole::Unspecified {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("agent_b should receive a check");
+    assert_eq!(b_agent.role().await, IceRole::Controlled, "should match");
+
+    let (_cancel_tx, cancel_rx) = mpsc::channel(1);
+    tokio::time::timeout(
+        Duration::from_secs(10),
+        b_agent.start(cancel_rx, String::new(), String::new()),
+    )
+    .await
+    .expect("agent_b should connect")?;
+    dialed.await.unwrap()?;
+
+    assert_eq!(a_agent.role().await, IceRole::Controlling, "should match");
+    assert_eq!(b_agent.role().await, IceRole::Controlled, "should match");
+
+    a_agent.close().await?;
+    b_agent.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_select_candidate_pair() -> Result<(), IceError> {
+    let a = Agent::new(AgentConfig::default()).await?;
+
+    let local = new_test_host(&a, "192.168.1.1", 19216).await?;
+    let first = new_test_host(&a, "1.2.3.4", 19216).await?;
+    let second = new_test_host(&a, "1.2.3.5", 19216).await?;
+
+    let second_pair = {
+        let mut ai = a.agent_internal.lock().await;
+        ai.add_pair(local.clone(), first.clone()).await;
+        ai.add_pair(local.clone(), second.clone()).await;
+        let checklist = ai.agent_conns[0].checklist.lock().await.clone();
+        ai.set_selected_pair(Some(Arc::clone(&checklist[0]))).await;
+        Arc::clone(&checklist[1])
+    };
+
+    assert!(matches!(
+        a.select_candidate_pair(&local.id(), "unknown").await,
+        Err(IceError::CandidatePairNotFound)
+    ));
+
+    // The pair must be valid
+    assert!(matches!(
+        a.select_candidate_pair(&local.id(), &second.id()).await,
+        Err(IceError::CandidatePairNotSelectable(_))
+    ));
+
+    // The controlled agent only selects pairs the controlling agent nominated
+    second_pair
+        .state
+        .store(CandidatePairState::Succeeded as u8, Ordering::SeqCst);
+    assert!(matches!(
+        a.select_candidate_pair(&local.id(), &second.id()).await,
+        Err(IceError::CandidatePairNotSelectable(_))
+    ));
+
+    second_pair.nominated.store(true, Ordering::SeqCst);
+    a.select_candidate_pair(&local.id(), &second.id()).await?;
+    let selected = a.get_selected_candidate_pair().await.unwrap();
+    assert!(Arc::ptr_eq(&selected, &second_pair), "should match");
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_select_candidate_pair_controlling() -> Result<(), IceError> {
+    let a = Agent::new(AgentConfig {
+        is_controlling: true,
+        ..Default::default()
+    })
+    .await?;
+
+    let local = new_test_host(&a, "192.168.1.1", 19216).await?;
+    let remote = new_test_host(&a, "1.2.3.4", 19216).await?;
+    let p = {
+        let mut ai = a.agent_internal.lock().await;
+        ai.add_pair(local.clone(), remote.clone()).await;
+        let p = Arc::clone(&ai.agent_conns[0].checklist.lock().await[0]);
+        p.state
+            .store(CandidatePairState::Succeeded as u8, Ordering::SeqCst);
+        p
+    };
+
+    a.select_candidate_pair(&local.id(), &remote.id()).await?;
+    assert!(
+        a.get_selected_candidate_pair().await.is_none(),
+        "the pair is selected once its nomination succeeds"
+    );
+
+    {
+        let mut ai = a.agent_internal.lock().await;
+        let (transaction_id, destination) = {
+            let request = ai
+                .pending_binding_requests
+                .last()
+                .expect("the pair should be nominated");
+            assert!(request.is_use_candidate);
+            (request.transaction_id, request.destination)
+        };
+        let mut msg = Message::new();
+        msg.build(&[Box::new(BINDING_SUCCESS), Box::new(transaction_id)])?;
+        ai.handle_success_response(&msg, &local, &remote, destination)
+            .await;
+    }
+    let selected = a.get_selected_candidate_pair().await.unwrap();
+    assert!(Arc::ptr_eq(&selected, &p), "should match");
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fail_over_dead_selected_pair() -> Result<(), IceError> {
+    let a = Agent::new(AgentConfig {
+        is_controlling: true,
+        failover_after_lost_checks: 3,
+        ..Default::default()
+    })
+    .await?;
+    a.set_remote_ice_options(DEFAULT_ICE_OPTIONS).await;
+
+    let local = new_test_host(&a, "192.168.1.1", 19216).await?;
+    let mut remotes = vec![];
+    for address in &["1.2.3.4", "1.2.3.5"] {
+        let remote = new_test_host(&a, address, 19216).await?;
+        let mut ai = a.agent_internal.lock().await;
+        ai.add_pair(local.clone(), remote.clone()).await;
+        remotes.push(remote);
+    }
+
+    let mut ai = a.agent_internal.lock().await;
+    let checklist = ai.agent_conns[0].checklist.lock().await.clone();
+    for p in &checklist {
+        p.state
+            .store(CandidatePairState::Succeeded as u8, Ordering::SeqCst);
+    }
+    let (dead, backup) = (&checklist[0], &checklist[1]);
+    ai.set_selected_pair(Some(Arc::clone(dead))).await;
+
+    // A few lost requests are tolerated
+    dead.on_request_lost();
+    dead.on_request_lost();
+    ai.fail_over_dead_selected_pairs().await;
+    let selected = ai.agent_conns[0].get_selected_pair().await.unwrap();
+    assert!(Arc::ptr_eq(&selected, dead), "should match");
+    assert_eq!(dead.state(), CandidatePairState::Succeeded, "should match");
+
+    // The backup is nominated, and only selected once the nomination succeeds
+    dead.on_request_lost();
+    ai.fail_over_dead_selected_pairs().await;
+    assert_eq!(dead.state(), CandidatePairState::Failed, "should match");
+    let selected = ai.agent_conns[0].get_selected_pair().await.unwrap();
+    assert!(Arc::ptr_eq(&selected, dead), "should match");
+
+    let (transaction_id, destination) = {
+        let request = ai
+            .pending_binding_requests
+            .last()
+            .expect("the backup should be nominated");
+        assert!(request.is_use_candidate);
+        (request.transaction_id, request.destination)
+    };
+    let mut msg = Message::new();
+    msg.build(&[Box::new(BINDING_SUCCESS), Box::new(transaction_id)])?;
+    ai.handle_success_response(&msg, &local, &remotes[1], destination)
+        .await;
+    let selected = ai.agent_conns[0].get_selected_pair().await.unwrap();
+    assert!(Arc::ptr_eq(&selected, backup), "should fail over");
+    drop(ai);
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fail_over_waits_for_nomination_when_controlled() -> Result<(), IceError> {
+    let a = Agent::new(AgentConfig {
+        failover_after_lost_checks: 1,
+        ..Default::default()
+    })
+    .await?;
+
+    let local = new_test_host(&a, "192.168.1.1", 19216).await?;
+    for address in &["1.2.3.4", "1.2.3.5"] {
+        let remote = new_test_host(&a, address, 19216).await?;
+        let mut ai = a.agent_internal.lock().await;
+        ai.add_pair(local.clone(), remote).await;
+    }
+
+    let mut ai = a.agent_internal.lock().await;
+    let checklist = ai.agent_conns[0].checklist.lock().await.clone();
+    for p in &checklist {
+        p.state
+            .store(CandidatePairState::Succeeded as u8, Ordering::SeqCst);
+    }
+    let dead = &checklist[0];
+    ai.set_selected_pair(Some(Arc::clone(dead))).await;
+
+    dead.on_request_lost();
+    ai.fail_over_dead_selected_pairs().await;
+    assert_eq!(dead.state(), CandidatePairState::Failed, "should match");
+    let selected = ai.agent_conns[0].get_selected_pair().await.unwrap();
+    assert!(
+        Arc::ptr_eq(&selected, dead),
+        "the controlling agent picks the replacement"
+    );
+    assert!(
+        ai.pending_binding_requests.is_empty(),
+        "nothing is nominated"
+    );
+    drop(ai);
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fail_over_needs_renomination_when_controlling() -> Result<(), IceError> {
+    let a = Agent::new(AgentConfig {
+        is_controlling: true,
+        failover_after_lost_checks: 1,
+        ..Default::default()
+    })
+    .await?;
+    a.set_remote_ice_options(IceOptions::unmarshal("a=ice-options:trickle ice2"))
+        .await;
+
+    let local = new_test_host(&a, "192.168.1.1", 19216).await?;
+    for address in &["1.2.3.4", "1.2.3.5"] {
+        let remote = new_test_host(&a, address, 19216).await?;
+        let mut ai = a.agent_internal.lock().await;
+        ai.add_pair(local.clone(), remote).await;
+    }
+
+    let mut ai = a.agent_internal.lock().await;
+    let checklist = ai.agent_conns[0].checklist.lock().await.clone();
+    for p in &checklist {
+        p.state
+            .store(CandidatePairState::Succeeded as u8, Ordering::SeqCst);
+    }
+    let dead = &checklist[0];
+    ai.set_selected_pair(Some(Arc::clone(dead))).await;
+
+    dead.on_request_lost();
+    ai.fail_over_dead_selected_pairs().await;
+    let selected = ai.agent_conns[0].get_selected_pair().await.unwrap();
+    assert!(Arc::ptr_eq(&selected, dead), "should not fail over");
+    assert_eq!(dead.state(), CandidatePairState::Succeeded, "should match");
+    drop(ai);
+
+    a.close().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_failover_after_too_many_lost_checks_is_rejected() {
+    let result = Agent::new(AgentConfig {
+        failover_after_lost_checks: 33,
+        ..Default::default()
+    })
+    .await;
+    assert!(matches!(
+        result,
+        Err(IceError::InvalidFailoverAfterLostChecks)
+    ));
+}
+
+/// Counts the reads started on it, which never complete.
+#[derive(Default)]
+struct CountingReadsConn {
+    reads: AtomicUsize,
+}
+
+#[async_trait]
+impl Conn for CountingReadsConn {
+    async fn connect(&self, _addr: SocketAddr) -> io::Result<()> {
+        Ok(())
+    }
+
+    async fn recv(&self, _buf: &mut [u8]) -> io::Result<usize> {
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        std::future::pending().await
+    }
+
+    async fn recv_from(&self, _buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        std::future::pending().await
+    }
+
+    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    async fn send_to(&self, buf: &[u8], _target: SocketAddr) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    async fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(SocketAddr::new(Ipv4Addr::new(192, 168, 0, 2).into(), 777))
+    }
+}
+
+#[tokio::test]
+async fn test_shared_conn_read_once() -> Result<(), IceError> {
+    let a = Agent::new(AgentConfig::default()).await?;
+
+    // A host candidate and a server reflexive candidate of the same UDPMuxSrflx socket
+    let conn = Arc::new(CountingReadsConn::default());
+    let host = new_test_host_with(
+        &a,
+        CandidateBaseConfig {
+            conn: Some(conn.clone()),
+            ..test_base_config("192.168.0.2", 777)
+        },
+    )
+    .await?;
+    let srflx = new_test_srflx_with(
+        &a,
+        CandidateBaseConfig {
+            conn: Some(conn.clone()),
+            ..test_base_config("1.2.3.4", 5678)
+        },
+        "192.168.0.2",
+        777,
+    )
+    .await?;
+
+    {
+        let mut ai = a.agent_internal.lock().await;
+        ai.add_candidate(&host).await?;
+        ai.add_candidate(&srflx).await?;
+        ai.started_ch_tx.take();
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(
+        conn.reads.load(Ordering::SeqCst),
+        1,
+        "the shared connection should be read by a single loop"
+    );
+
+    // The server reflexive candidate takes over once the host candidate is gone
+    a.agent_internal
+        .lock()
+        .await
+        .remove_local_candidate(&host)
+        .await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(conn.reads.load(Ordering::SeqCst), 2);
+    assert_eq!(a.get_local_candidates().await?.len(), 1);
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shared_conn_handed_over_between_three_candidates() -> Result<(), IceError> {
+    let a = Agent::new(AgentConfig::default()).await?;
+
+    // A host candidate and two server reflexive candidates of the same socket
+    let conn = Arc::new(CountingReadsConn::default());
+    let mut candidates = vec![
+        new_test_host_with(
+            &a,
+            CandidateBaseConfig {
+                conn: Some(conn.clone()),
+                ..test_base_config("192.168.0.2", 777)
+            },
+        )
+        .await?,
+    ];
+    for address in &["1.2.3.4", "1.2.3.5"] {
+        candidates.push(
+            new_test_srflx_with(
+                &a,
+                CandidateBaseConfig {
+                    conn: Some(conn.clone()),
+                    ..test_base_config(address, 5678)
+                },
+                "192.168.0.2",
+                777,
+            )
+            .await?,
+        );
+    }
+
+    {
+        let mut ai = a.agent_internal.lock().await;
+        for c in &candidates {
+            ai.add_candidate(c).await?;
+        }
+        ai.started_ch_tx.take();
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(conn.reads.load(Ordering::SeqCst), 1);
+
+    // Each reader hands the connection over to the next candidate, although a third one still
+    // shares it
+    for (i, c) in candidates.iter().take(2).enumerate() {
+        a.agent_internal
+            .lock()
+            .await
+            .remove_local_candidate(c)
+            .await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            conn.reads.load(Ordering::SeqCst),
+            i + 2,
+            "the connection should still be read"
+        );
+    }
+    assert_eq!(a.get_local_candidates().await?.len(), 1);
+
+    a.close().await?;
+
+    Ok(())
+}
@@ -2,25 +2,73 @@ use super::*;
 use crate::errors::*;
 
 use async_trait::async_trait;
+use bytes::Bytes;
 use std::io;
 use std::sync::atomic::{AtomicBool, Ordering};
 use util::Conn;
 
 impl Agent {
     /// Connects to the remote agent, acting as the controlling ice agent.
-    /// The method blocks until at least one ice candidate pair has successfully connected.
+    /// The method blocks until a candidate pair has been selected for every component, and
+    /// returns the connection of the first one, see `get_component_conn` for the others.
+    ///
+    /// The remote credentials may both be empty if they are, or will be, set with
+    /// `set_remote_credentials`.
     pub async fn dial(
+        &self,
+        cancel_rx: mpsc::Receiver<()>,
+        remote_ufrag: String,
+        remote_pwd: String,
+    ) -> Result<Arc<AgentConn>, IceError> {
+        self.start_with_role(cancel_rx, Some(true), remote_ufrag, remote_pwd)
+            .await
+    }
+
+    /// Connects to the remote agent, acting as the controlled ice agent.
+    /// The method blocks until a candidate pair has been selected for every component, and
+    /// returns the connection of the first one, see `get_component_conn` for the others.
+    ///
+    /// The remote credentials may both be empty if they are, or will be, set with
+    /// `set_remote_credentials`.
+    pub async fn accept(
+        &self,
+        cancel_rx: mpsc::Receiver<()>,
+        remote_ufrag: String,
+        remote_pwd: String,
+    ) -> Result<Arc<AgentConn>, IceError> {
+        self.start_with_role(cancel_rx, Some(false), remote_ufrag, remote_pwd)
+            .await
+    }
+
+    /// Connects to the remote agent with the role determined so far, see `AgentConfig::role`.
+    /// If it is still unspecified, the agent takes the controlling role, a conflict with the
+    /// role of the remote agent being resolved by their tie-breakers.
+    /// The method blocks until a candidate pair has been selected for every component, and
+    /// returns the connection of the first one, see `get_component_conn` for the others.
+    pub async fn start(
+        &self,
+        cancel_rx: mpsc::Receiver<()>,
+        remote_ufrag: String,
+        remote_pwd: String,
+    ) -> Result<Arc<AgentConn>, IceError> {
+        self.start_with_role(cancel_rx, None, remote_ufrag, remote_pwd)
+            .await
+    }
+
+    async fn start_with_role(
         &self,
         mut cancel_rx: mpsc::Receiver<()>,
+        is_controlling: Option<bool>,
         remote_ufrag: String,
         remote_pwd: String,
-    ) -> Result<Arc<impl Conn>, Error> {
+    ) -> Result<Arc<AgentConn>, IceError> {
         let (on_connected_rx, agent_conn) = {
             let agent_internal = Arc::clone(&self.agent_internal);
             let mut ai = self.agent_internal.lock().await;
-            ai.start_connectivity_checks(agent_internal, true, remote_ufrag, remote_pwd)
+            let is_controlling = is_controlling.unwrap_or(!ai.role_determined || ai.is_controlling);
+            ai.start_connectivity_checks(agent_internal, is_controlling, remote_ufrag, remote_pwd)
                 .await?;
-            (ai.on_connected_rx.take(), Arc::clone(&ai.agent_conn))
+            (ai.on_connected_rx.take(), Arc::clone(&ai.agent_conns[0]))
         };
 
         if let Some(mut on_connected_rx) = on_connected_rx {
@@ -28,35 +76,66 @@ impl Agent {
             tokio::select! {
                 _ = on_connected_rx.recv() => {},
                 _ = cancel_rx.recv() => {
-                    return Err(ERR_CANCELED_BY_CALLER.to_owned());
+                    return Err(IceError::CanceledByCaller);
                 }
             }
         }
+
         Ok(agent_conn)
     }
 
-    /// Connects to the remote agent, acting as the controlled ice agent.
-    /// The method blocks until at least one ice candidate pair has successfully connected.
-    pub async fn accept(
+    /// Connects to the remote agent as the controlling ice agent, like `dial` but without a
+    /// cancel channel: it returns `IceError::ConnectionFailed` once the agent fails to connect,
+    /// i.e. when checking lasts longer than the disconnected and failed timeouts or every pair
+    /// has failed.
+    pub async fn dial_connected(
         &self,
-        mut cancel_rx: mpsc::Receiver<()>,
         remote_ufrag: String,
         remote_pwd: String,
-    ) -> Result<Arc<impl Conn>, Error> {
+    ) -> Result<Arc<AgentConn>, IceError> {
+        self.connect(true, remote_ufrag, remote_pwd).await
+    }
+
+    /// Connects to the remote agent as the controlled ice agent, like `accept` but without a
+    /// cancel channel: it returns `IceError::ConnectionFailed` once the agent fails to connect,
+    /// i.e. when checking lasts longer than the disconnected and failed timeouts or every pair
+    /// has failed.
+    pub async fn accept_connected(
+        &self,
+        remote_ufrag: String,
+        remote_pwd: String,
+    ) -> Result<Arc<AgentConn>, IceError> {
+        self.connect(false, remote_ufrag, remote_pwd).await
+    }
+
+    async fn connect(
+        &self,
+        is_controlling: bool,
+        remote_ufrag: String,
+        remote_pwd: String,
+    ) -> Result<Arc<AgentConn>, IceError> {
+        // Subscribed before starting, so that a failure right away is not missed
+        let mut events = self.events();
         let (on_connected_rx, agent_conn) = {
             let agent_internal = Arc::clone(&self.agent_internal);
             let mut ai = self.agent_internal.lock().await;
-            ai.start_connectivity_checks(agent_internal, false, remote_ufrag, remote_pwd)
+            ai.start_connectivity_checks(agent_internal, is_controlling, remote_ufrag, remote_pwd)
                 .await?;
-            (ai.on_connected_rx.take(), Arc::clone(&ai.agent_conn))
+            (ai.on_connected_rx.take(), Arc::clone(&ai.agent_conns[0]))
         };
 
         if let Some(mut on_connected_rx) = on_connected_rx {
-            // block until pair selected
-            tokio::select! {
-                _ = on_connected_rx.recv() => {},
-                _ = cancel_rx.recv() => {
-                    return Err(ERR_CANCELED_BY_CALLER.to_owned());
+            // block until pair selected, or the agent failed
+            loop {
+                tokio::select! {
+                    _ = on_connected_rx.recv() => break,
+                    event = events.recv() => match event {
+                        Ok(AgentEvent::ConnectionStateChange(ConnectionState::Failed)) => {
+                            return Err(IceError::ConnectionFailed);
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return Err(IceError::Closed),
+                        _ => {}
+                    },
                 }
             }
         }
@@ -65,28 +144,51 @@ impl Agent {
     }
 }
 
-pub(crate) struct AgentConn {
+/// The connection of a component to the remote agent over its selected candidate pair, returned
+/// by `dial`, `accept` and `get_component_conn`. It implements `util::Conn` and, with the
+/// `tokio-io` feature, can be wrapped in an `AgentConnIo` implementing `AsyncRead` and
+/// `AsyncWrite`.
+pub struct AgentConn {
+    pub(crate) stream: u16,
+    pub(crate) component: u16,
     pub(crate) selected_pair: Mutex<Option<Arc<CandidatePair>>>,
     pub(crate) checklist: Mutex<Vec<Arc<CandidatePair>>>,
 
-    pub(crate) buffer: Buffer,
+    pub(crate) buffer: PacketBuffer,
     pub(crate) bytes_received: AtomicUsize,
     pub(crate) bytes_sent: AtomicUsize,
     pub(crate) done: AtomicBool,
+    /// Whether a valid pair of the component has been reported since the agent (re)started.
+    pub(crate) validated: AtomicBool,
+    /// Whether a pair of host candidates of the component succeeded since the agent
+    /// (re)started, after which the pairs going through NAT hairpinning are not reported.
+    pub(crate) host_pair_succeeded: AtomicBool,
+    /// Whether packets are only sent on valid pairs until a pair is selected.
+    pub(crate) send_on_valid_pair: bool,
+    pub(crate) buffer_full_policy: BufferFullPolicy,
+    pub(crate) packets_dropped: AtomicUsize,
+    /// Whether packets were dropped since the last read, with `BufferFullPolicy::Error`.
+    overflowed: AtomicBool,
 }
 
 impl AgentConn {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(stream: u16, component: u16) -> Self {
         Self {
+            stream,
+            component,
             selected_pair: Mutex::new(None),
             checklist: Mutex::new(vec![]),
             // Make sure the buffer doesn't grow indefinitely.
-            // NOTE: We actually won't get anywhere close to this limit.
-            // SRTP will constantly read from the endpoint and drop packets if it's full.
-            buffer: Buffer::new(0, MAX_BUFFER_SIZE),
+            buffer: PacketBuffer::new(DEFAULT_RECEIVE_BUFFER_SIZE),
             bytes_received: AtomicUsize::new(0),
             bytes_sent: AtomicUsize::new(0),
             done: AtomicBool::new(false),
+            validated: AtomicBool::new(false),
+            host_pair_succeeded: AtomicBool::new(false),
+            send_on_valid_pair: false,
+            buffer_full_policy: BufferFullPolicy::DropNew,
+            packets_dropped: AtomicUsize::new(0),
+            overflowed: AtomicBool::new(false),
         }
     }
     pub(crate) async fn get_selected_pair(&self) -> Option<Arc<CandidatePair>> {
@@ -104,7 +206,7 @@ impl AgentConn {
             }
 
             if let Some(b) = &mut best {
-                if b.priority() < p.priority() {
+                if p.is_preferred_to(b) {
                     *b = p;
                 }
             } else {
@@ -125,7 +227,7 @@ impl AgentConn {
             }
 
             if let Some(b) = &mut best {
-                if b.priority() < p.priority() {
+                if p.is_preferred_to(b) {
                     *b = p;
                 }
             } else {
@@ -136,6 +238,32 @@ impl AgentConn {
         best.cloned()
     }
 
+    /// Returns the pair packets are sent on until a pair is selected.
+    async fn get_unselected_send_pair(&self) -> Option<Arc<CandidatePair>> {
+        if self.send_on_valid_pair {
+            self.get_best_valid_candidate_pair().await
+        } else {
+            self.get_best_available_candidate_pair().await
+        }
+    }
+
+    /// Returns the component this connection carries, e.g. `COMPONENT_RTP`.
+    #[must_use]
+    pub const fn component(&self) -> u16 {
+        self.component
+    }
+
+    /// Returns the index of the data stream this connection carries.
+    #[must_use]
+    pub const fn stream(&self) -> u16 {
+        self.stream
+    }
+
+    /// Returns the stream and the component of this connection, identifying it in the agent.
+    pub(crate) const fn key(&self) -> (u16, u16) {
+        (self.stream, self.component)
+    }
+
     /// Returns the number of bytes sent.
     pub fn bytes_sent(&self) -> usize {
         self.bytes_sent.load(Ordering::SeqCst)
@@ -145,32 +273,182 @@ impl AgentConn {
     pub fn bytes_received(&self) -> usize {
         self.bytes_received.load(Ordering::SeqCst)
     }
-}
 
-#[async_trait]
-impl Conn for AgentConn {
-    async fn connect(&self, _addr: SocketAddr) -> io::Result<()> {
-        Err(io::Error::new(io::ErrorKind::Other, "Not applicable"))
+    /// Returns the number of packets received which were dropped because the receive buffer was
+    /// full, see `AgentConfig::buffer_full_policy`.
+    pub fn packets_dropped(&self) -> usize {
+        self.packets_dropped.load(Ordering::SeqCst)
     }
 
-    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+    /// Sends every buffer of `bufs` as a separate packet on the selected candidate pair, in a
+    /// single call, and returns the number of bytes sent. On Linux the packets of host UDP
+    /// candidates are sent with a single `sendmmsg` syscall. When sending fails after the first
+    /// packets were sent, the bytes of those are returned and the others can be sent again.
+    pub async fn send_batch(&self, bufs: &[&[u8]]) -> io::Result<usize> {
         if self.done.load(Ordering::SeqCst) {
             return Err(io::Error::new(io::ErrorKind::Other, "Conn is closed"));
         }
 
-        let n = match self.buffer.read(buf, None).await {
-            Ok(n) => n,
-            Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err.to_string())),
+        if bufs.iter().any(|buf| is_message(buf)) {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                IceError::IceWriteStunMessage,
+            ));
+        }
+
+        let result = if let Some(pair) = self.get_selected_pair().await {
+            pair.write_batch(bufs).await
+        } else if let Some(pair) = self.get_unselected_send_pair().await {
+            pair.write_batch(bufs).await
+        } else {
+            Ok(0)
         };
-        self.bytes_received.fetch_add(n, Ordering::SeqCst);
 
-        Ok(n)
+        match result {
+            Ok(n) => {
+                self.bytes_sent.fetch_add(n, Ordering::SeqCst);
+                Ok(n)
+            }
+            Err(err) => Err(err.into()),
+        }
     }
 
-    async fn recv_from(&self, _buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    /// Buffers a packet received from `remote`, the address of `remote_candidate`, until it is
+    /// read. The packet is queued as is, it is only copied once it is read.
+    pub(crate) fn write_packet(
+        &self,
+        data: Bytes,
+        remote: SocketAddr,
+        remote_candidate: Arc<dyn Candidate + Send + Sync>,
+    ) -> io::Result<()> {
+        let packet = ReceivedPacket {
+            data,
+            remote,
+            remote_candidate,
+        };
+        let drop_oldest = self.buffer_full_policy == BufferFullPolicy::DropOldest;
+        match self.buffer.push(packet, drop_oldest) {
+            Ok(dropped) => {
+                self.packets_dropped.fetch_add(dropped, Ordering::SeqCst);
+                Ok(())
+            }
+            Err(err) => {
+                if matches!(err, IceError::ReceiveBufferFull) {
+                    self.packets_dropped.fetch_add(1, Ordering::SeqCst);
+                    if self.buffer_full_policy == BufferFullPolicy::Error {
+                        self.overflowed.store(true, Ordering::SeqCst);
+                    }
+                }
+                Err(err.into())
+            }
+        }
+    }
+
+    /// Reads the next packet received, and returns its size along with the address and the
+    /// remote candidate it comes from. It may come from another pair than the selected one, e.g.
+    /// while the remote agent is still checking pairs or switches to another one.
+    ///
+    /// A packet which does not fit in `buf` is left in the queue and `IceError::BufferShort` is
+    /// returned, so that it can be read again with a larger buffer.
+    pub async fn recv_from_candidate(
+        &self,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, Arc<dyn Candidate + Send + Sync>)> {
+        let packet = self.recv_packet().await?;
+        let n = packet.data.len();
+        if n > buf.len() {
+            self.bytes_received.fetch_sub(n, Ordering::SeqCst);
+            self.buffer.push_front(packet);
+            return Err(IceError::BufferShort.into());
+        }
+        buf[..n].copy_from_slice(&packet.data);
+
+        Ok((n, packet.remote, packet.remote_candidate))
+    }
+
+    /// Reads the next packet received, without copying it.
+    pub(crate) async fn recv_packet(&self) -> io::Result<ReceivedPacket> {
+        if self.done.load(Ordering::SeqCst) {
+            return Err(io::Error::new(io::ErrorKind::Other, "Conn is closed"));
+        }
+
+        if self.overflowed.swap(false, Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Receive buffer overflowed, packets were dropped",
+            ));
+        }
+
+        match self.buffer.pop().await {
+            Some(packet) => {
+                self.bytes_received
+                    .fetch_add(packet.data.len(), Ordering::SeqCst);
+                Ok(packet)
+            }
+            None => Err(io::Error::new(io::ErrorKind::Other, "Conn is closed")),
+        }
+    }
+
+    /// Splits the connection into a receiving and a sending half, which can be moved to separate
+    /// tasks.
+    #[must_use]
+    pub fn split(self: Arc<Self>) -> (AgentConnRecvHalf, AgentConnSendHalf) {
+        (
+            AgentConnRecvHalf(Arc::clone(&self)),
+            AgentConnSendHalf(self),
+        )
+    }
+}
+
+/// The receiving half of an `AgentConn`, see `AgentConn::split`.
+pub struct AgentConnRecvHalf(Arc<AgentConn>);
+
+impl AgentConnRecvHalf {
+    /// Reads the next packet received on the selected candidate pair.
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.recv(buf).await
+    }
+
+    /// Reads the next packet received, see `AgentConn::recv_from_candidate`.
+    pub async fn recv_from_candidate(
+        &self,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, Arc<dyn Candidate + Send + Sync>)> {
+        self.0.recv_from_candidate(buf).await
+    }
+}
+
+/// The sending half of an `AgentConn`, see `AgentConn::split`.
+pub struct AgentConnSendHalf(Arc<AgentConn>);
+
+impl AgentConnSendHalf {
+    /// Sends a packet on the selected candidate pair.
+    pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(buf).await
+    }
+
+    /// Sends several packets on the selected candidate pair, see `AgentConn::send_batch`.
+    pub async fn send_batch(&self, bufs: &[&[u8]]) -> io::Result<usize> {
+        self.0.send_batch(bufs).await
+    }
+}
+
+#[async_trait]
+impl Conn for AgentConn {
+    async fn connect(&self, _addr: SocketAddr) -> io::Result<()> {
         Err(io::Error::new(io::ErrorKind::Other, "Not applicable"))
     }
 
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let (n, _, _) = self.recv_from_candidate(buf).await?;
+        Ok(n)
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (n, remote, _) = self.recv_from_candidate(buf).await?;
+        Ok((n, remote))
+    }
+
     async fn send(&self, buf: &[u8]) -> io::Result<usize> {
         if self.done.load(Ordering::SeqCst) {
             return Err(io::Error::new(io::ErrorKind::Other, "Conn is closed"));
@@ -179,13 +457,13 @@ impl Conn for AgentConn {
         if is_message(buf) {
             return Err(io::Error::new(
                 io::ErrorKind::Other,
-                ERR_ICE_WRITE_STUN_MESSAGE.to_string(),
+                IceError::IceWriteStunMessage,
             ));
         }
 
         let result = if let Some(pair) = self.get_selected_pair().await {
             pair.write(buf).await
-        } else if let Some(pair) = self.get_best_available_candidate_pair().await {
+        } else if let Some(pair) = self.get_unselected_send_pair().await {
             pair.write(buf).await
         } else {
             Ok(0)
@@ -196,7 +474,7 @@ impl Conn for AgentConn {
                 self.bytes_sent.fetch_add(buf.len(), Ordering::SeqCst);
                 Ok(n)
             }
-            Err(err) => Err(io::Error::new(io::ErrorKind::Other, err.to_string())),
+            Err(err) => Err(err.into()),
         }
     }
 
@@ -215,3 +493,95 @@ impl Conn for AgentConn {
         }
     }
 }
+
+#[cfg(feature = "tokio-io")]
+type PendingRead = Pin<Box<dyn Future<Output = io::Result<Bytes>> + Send>>;
+#[cfg(feature = "tokio-io")]
+type PendingWrite = Pin<Box<dyn Future<Output = io::Result<usize>> + Send>>;
+
+/// Adapts an `AgentConn` to `AsyncRead` and `AsyncWrite`. Each read returns at most one packet;
+/// the part of a packet that does not fit in the read buffer is returned by the next reads.
+#[cfg(feature = "tokio-io")]
+pub struct AgentConnIo {
+    conn: Arc<AgentConn>,
+    read_buf: Bytes,
+    pending_read: Option<PendingRead>,
+    pending_write: Option<PendingWrite>,
+}
+
+#[cfg(feature = "tokio-io")]
+impl AgentConnIo {
+    #[must_use]
+    pub fn new(conn: Arc<AgentConn>) -> Self {
+        Self {
+            conn,
+            read_buf: Bytes::new(),
+            pending_read: None,
+            pending_write: None,
+        }
+    }
+}
+
+#[cfg(feature = "tokio-io")]
+impl tokio::io::AsyncRead for AgentConnIo {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<io::Result<()>> {
+        if self.read_buf.is_empty() {
+            let mut pending_read = self.pending_read.take().unwrap_or_else(|| {
+                let conn = Arc::clone(&self.conn);
+                Box::pin(async move { Ok(conn.recv_packet().await?.data) })
+            });
+
+            match pending_read.as_mut().poll(cx) {
+                std::task::Poll::Ready(Ok(b)) => self.read_buf = b,
+                std::task::Poll::Ready(Err(err)) => return std::task::Poll::Ready(Err(err)),
+                std::task::Poll::Pending => {
+                    self.pending_read = Some(pending_read);
+                    return std::task::Poll::Pending;
+                }
+            }
+        }
+
+        let n = std::cmp::min(buf.remaining(), self.read_buf.len());
+        buf.put_slice(&self.read_buf.split_to(n));
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "tokio-io")]
+impl tokio::io::AsyncWrite for AgentConnIo {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<io::Result<usize>> {
+        let mut pending_write = self.pending_write.take().unwrap_or_else(|| {
+            let conn = Arc::clone(&self.conn);
+            let b = buf.to_vec();
+            Box::pin(async move { conn.send(&b).await })
+        });
+
+        let poll = pending_write.as_mut().poll(cx);
+        if poll.is_pending() {
+            self.pending_write = Some(pending_write);
+        }
+        poll
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+}
@@ -1,13 +1,16 @@
 use super::agent_vnet_test::*;
 use super::*;
+use crate::candidate::candidate_base::CandidateBase;
 
-use util::{vnet::*, Conn, Error};
+use bytes::Bytes;
+use std::net::IpAddr;
+use util::{vnet::*, Conn};
 use waitgroup::WaitGroup;
 
 pub(crate) async fn pipe(
     default_config0: Option<AgentConfig>,
     default_config1: Option<AgentConfig>,
-) -> Result<(Arc<impl Conn>, Arc<impl Conn>, Arc<Agent>, Arc<Agent>), Error> {
+) -> Result<(Arc<AgentConn>, Arc<AgentConn>, Arc<Agent>, Arc<Agent>), IceError> {
     let (a_notifier, mut a_connected) = on_connected();
     let (b_notifier, mut b_connected) = on_connected();
 
@@ -44,7 +47,7 @@ pub(crate) async fn pipe(
 }
 
 #[tokio::test]
-async fn test_remote_local_addr() -> Result<(), Error> {
+async fn test_remote_local_addr() -> Result<(), IceError> {
     // Agent0 is behind 1:1 NAT
     let nat_type0 = nat::NatType {
         mode: nat::NatMode::Nat1To1,
@@ -68,7 +71,7 @@ async fn test_remote_local_addr() -> Result<(), Error> {
 
     //"Disconnected Returns nil"
     {
-        let disconnected_conn = AgentConn::new();
+        let disconnected_conn = AgentConn::new(0, COMPONENT_RTP);
         let result = disconnected_conn.local_addr().await;
         assert!(result.is_err(), "Disconnected Returns nil");
     }
@@ -106,7 +109,7 @@ async fn test_remote_local_addr() -> Result<(), Error> {
 }
 
 #[tokio::test]
-async fn test_conn_stats() -> Result<(), Error> {
+async fn test_conn_stats() -> Result<(), IceError> {
     let (ca, cb, _, _) = pipe(None, None).await?;
     let na = ca.send(&[0u8; 10]).await?;
 
@@ -120,7 +123,7 @@ async fn test_conn_stats() -> Result<(), Error> {
         let nb = cb.recv(&mut buf).await?;
         assert_eq!(nb, 10, "bytes received don't match");
 
-        Ok::<(), Error>(())
+        Ok::<(), IceError>(())
     });
 
     wg.wait().await;
@@ -129,3 +132,356 @@ async fn test_conn_stats() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_conn_split() -> Result<(), IceError> {
+    let (ca, cb, _, _) = pipe(None, None).await?;
+    let (_, a_send) = ca.split();
+    let (b_recv, _) = cb.split();
+
+    let reader = tokio::spawn(async move {
+        let mut buf = vec![0u8; 10];
+        b_recv.recv(&mut buf).await
+    });
+
+    let na = a_send.send(&[1u8; 10]).await?;
+    assert_eq!(na, 10, "bytes sent don't match");
+
+    let nb = reader.await.unwrap()?;
+    assert_eq!(nb, 10, "bytes received don't match");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_conn_recv_from_candidate() -> Result<(), IceError> {
+    let (ca, cb, _, _) = pipe(None, None).await?;
+    let selected_pair = cb.get_selected_pair().await.unwrap();
+
+    ca.send(&[1u8; 10]).await?;
+    let mut buf = vec![0u8; 10];
+    let (n, remote, remote_candidate) = cb.recv_from_candidate(&mut buf).await?;
+    assert_eq!(n, 10, "bytes received don't match");
+    assert_eq!(remote, selected_pair.remote.addr().await);
+    assert!(
+        remote_candidate.equal(&*selected_pair.remote),
+        "the packet should come from the remote candidate of the selected pair"
+    );
+
+    // Each packet keeps its source, even when it does not fit in the buffer
+    ca.send(&[2u8; 20]).await?;
+    ca.send(&[3u8; 10]).await?;
+    assert!(cb.recv_from(&mut buf).await.is_err());
+    let (n, remote) = cb.recv_from(&mut buf).await?;
+    assert_eq!(&buf[..n], &[3u8; 10]);
+    assert_eq!(remote, selected_pair.remote.addr().await);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_conn_send_batch() -> Result<(), IceError> {
+    let (ca, cb, _, _) = pipe(None, None).await?;
+
+    let packets: Vec<Vec<u8>> = (1..=8_u8).map(|i| vec![i; usize::from(i)]).collect();
+    let bufs: Vec<&[u8]> = packets.iter().map(Vec::as_slice).collect();
+    let na = ca.send_batch(&bufs).await?;
+    assert_eq!(na, 36, "bytes sent don't match");
+    assert_eq!(ca.bytes_sent(), 36, "bytes sent don't match");
+
+    let mut buf = vec![0u8; 10];
+    for packet in &packets {
+        let nb = cb.recv(&mut buf).await?;
+        assert_eq!(
+            &buf[..nb],
+            &packet[..],
+            "packets should be received in order"
+        );
+    }
+
+    let mut stun = Message::new();
+    stun.build(&[Box::new(BINDING_REQUEST), Box::new(TransactionId::new())])?;
+    assert!(
+        ca.send_batch(&[&b"media"[..], &stun.raw[..]])
+            .await
+            .is_err(),
+        "STUN messages should not be sent"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_conn_multiple_components() -> Result<(), IceError> {
+    let result = Agent::new(AgentConfig {
+        components: Some(0),
+        ..Default::default()
+    })
+    .await;
+    assert!(
+        matches!(result, Err(IceError::InvalidComponents)),
+        "an agent needs at least one component"
+    );
+
+    let config = || AgentConfig {
+        components: Some(2),
+        ..Default::default()
+    };
+    let (_, _, a_agent, b_agent) = pipe(Some(config()), Some(config())).await?;
+
+    for agent in [&a_agent, &b_agent] {
+        let pairs = agent.get_candidate_pairs().await;
+        assert!(
+            pairs.iter().any(|p| p.component() == COMPONENT_RTCP),
+            "RTCP candidates should be paired"
+        );
+        assert!(
+            pairs
+                .iter()
+                .all(|p| p.local.component() == p.remote.component()),
+            "candidates of different components should not be paired"
+        );
+        assert!(agent.get_component_conn(3).await.is_none());
+    }
+
+    let ca = a_agent
+        .get_component_conn(COMPONENT_RTCP)
+        .await
+        .expect("a should have an RTCP conn");
+    let cb = b_agent
+        .get_component_conn(COMPONENT_RTCP)
+        .await
+        .expect("b should have an RTCP conn");
+    assert_eq!(ca.component(), COMPONENT_RTCP);
+    let selected_pair = ca.get_selected_pair().await.expect("RTCP pair selected");
+    assert_eq!(selected_pair.component(), COMPONENT_RTCP);
+
+    let reader = tokio::spawn(async move {
+        let mut buf = vec![0u8; 10];
+        cb.recv(&mut buf).await
+    });
+
+    let na = ca.send(&[2u8; 10]).await?;
+    assert_eq!(na, 10, "bytes sent don't match");
+
+    let nb = reader.await.unwrap()?;
+    assert_eq!(nb, 10, "bytes received don't match");
+
+    a_agent.close().await?;
+    b_agent.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_conn_multiple_streams() -> Result<(), IceError> {
+    let result = Agent::new(AgentConfig {
+        components: Some(1),
+        streams: vec![1, 1],
+        ..Default::default()
+    })
+    .await;
+    assert!(matches!(result, Err(IceError::ComponentsWithStreams)));
+
+    let result = Agent::new(AgentConfig {
+        streams: vec![1, 0],
+        ..Default::default()
+    })
+    .await;
+    assert!(matches!(result, Err(IceError::InvalidComponents)));
+
+    let config = || AgentConfig {
+        streams: vec![1, 1],
+        ..Default::default()
+    };
+    let (_, _, a_agent, b_agent) = pipe(Some(config()), Some(config())).await?;
+
+    for agent in [&a_agent, &b_agent] {
+        let pairs = agent.get_candidate_pairs().await;
+        assert!(
+            pairs.iter().any(|p| p.stream() == 1),
+            "the candidates of the second stream should be paired"
+        );
+        assert!(
+            pairs.iter().all(|p| p.local.stream() == p.remote.stream()),
+            "candidates of different streams should not be paired"
+        );
+        assert!(agent.get_stream_conn(2, COMPONENT_RTP).await.is_none());
+    }
+
+    let ca = a_agent
+        .get_stream_conn(1, COMPONENT_RTP)
+        .await
+        .expect("a should have a conn for the second stream");
+    let cb = b_agent
+        .get_stream_conn(1, COMPONENT_RTP)
+        .await
+        .expect("b should have a conn for the second stream");
+    assert_eq!(ca.stream(), 1);
+    let selected_pair = ca.get_selected_pair().await.expect("pair selected");
+    assert_eq!(selected_pair.stream(), 1);
+
+    let reader = tokio::spawn(async move {
+        let mut buf = vec![0u8; 10];
+        cb.recv(&mut buf).await
+    });
+
+    let na = ca.send(&[3u8; 10]).await?;
+    assert_eq!(na, 10, "bytes sent don't match");
+
+    let nb = reader.await.unwrap()?;
+    assert_eq!(nb, 10, "bytes received don't match");
+
+    a_agent.close().await?;
+    b_agent.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_conn_loopback() -> Result<(), IceError> {
+    let config = || AgentConfig {
+        include_loopback: true,
+        ip_filter: Arc::new(Some(Box::new(|ip: IpAddr| ip.is_loopback()))),
+        ..Default::default()
+    };
+    let (_, _, a_agent, b_agent) = pipe(Some(config()), Some(config())).await?;
+
+    let selected_pair = a_agent
+        .get_selected_candidate_pair()
+        .await
+        .expect("a pair should be selected");
+    assert!(selected_pair.local.addr().await.ip().is_loopback());
+    assert!(selected_pair.remote.addr().await.ip().is_loopback());
+
+    a_agent.close().await?;
+    b_agent.close().await?;
+
+    Ok(())
+}
+
+#[cfg(feature = "tokio-io")]
+#[tokio::test]
+async fn test_conn_async_read_write() -> Result<(), IceError> {
+    use crate::agent::agent_transport::AgentConnIo;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (ca, cb, _, _) = pipe(None, None).await?;
+    let mut a_io = AgentConnIo::new(ca);
+    let mut b_io = AgentConnIo::new(cb);
+
+    a_io.write_all(b"hello").await?;
+
+    // A packet larger than the read buffer is returned over several reads
+    let mut buf = [0u8; 3];
+    b_io.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"hel");
+    let n = b_io.read(&mut buf).await?;
+    assert_eq!(&buf[..n], b"lo");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_conn_buffer_full_policy() -> Result<(), IceError> {
+    let remote: SocketAddr = "1.2.3.4:5000".parse()?;
+    let candidate: Arc<dyn Candidate + Send + Sync> = Arc::new(CandidateBase::default());
+    // Each packet takes 2 more bytes for its length, so the buffer holds 2 of them
+    let new_conn = |policy| {
+        let mut conn = AgentConn::new(0, COMPONENT_RTP);
+        conn.buffer = PacketBuffer::new(10);
+        conn.buffer_full_policy = policy;
+        conn
+    };
+    let mut buf = [0u8; 8];
+
+    let conn = new_conn(BufferFullPolicy::DropNew);
+    for packet in [b"one", b"two", b"six"].iter() {
+        let _ = conn.write_packet(Bytes::from_static(*packet), remote, Arc::clone(&candidate));
+    }
+    assert_eq!(conn.packets_dropped(), 1);
+    let n = conn.recv(&mut buf).await?;
+    assert_eq!(&buf[..n], b"one", "the newest packet should be dropped");
+
+    let conn = new_conn(BufferFullPolicy::DropOldest);
+    for packet in [b"one", b"two", b"six"].iter() {
+        conn.write_packet(Bytes::from_static(*packet), remote, Arc::clone(&candidate))?;
+    }
+    assert_eq!(conn.packets_dropped(), 1);
+    let (n, addr, _) = conn.recv_from_candidate(&mut buf).await?;
+    assert_eq!(&buf[..n], b"two", "the oldest packet should be dropped");
+    assert_eq!(addr, remote);
+
+    let conn = new_conn(BufferFullPolicy::Error);
+    for packet in [b"one", b"two", b"six"].iter() {
+        let _ = conn.write_packet(Bytes::from_static(*packet), remote, Arc::clone(&candidate));
+    }
+    assert_eq!(conn.packets_dropped(), 1);
+    assert!(
+        conn.recv(&mut buf).await.is_err(),
+        "the overflow should be reported"
+    );
+    let n = conn.recv(&mut buf).await?;
+    assert_eq!(&buf[..n], b"one", "should match");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_conn_buffers_packets_without_copying() -> Result<(), IceError> {
+    let remote: SocketAddr = "1.2.3.4:5000".parse()?;
+    let candidate: Arc<dyn Candidate + Send + Sync> = Arc::new(CandidateBase::default());
+    let conn = AgentConn::new(0, COMPONENT_RTP);
+
+    let packet = Bytes::from(vec![1u8; 100]);
+    conn.write_packet(packet.clone(), remote, candidate)?;
+    let received = conn.recv_packet().await?;
+    assert_eq!(
+        received.data.as_ptr(),
+        packet.as_ptr(),
+        "the packet should be queued as is"
+    );
+    assert_eq!(received.remote, remote);
+
+    // A packet which doesn't fit in the read buffer is kept for the next read
+    let bytes_received = conn.bytes_received();
+    conn.write_packet(packet.clone(), remote, Arc::new(CandidateBase::default()))?;
+    let mut buf = [0u8; 10];
+    assert!(conn.recv(&mut buf).await.is_err());
+    let mut buf = [0u8; 100];
+    let n = conn.recv(&mut buf).await?;
+    assert_eq!(&buf[..n], &packet[..], "the packet should be read in full");
+    assert_eq!(conn.bytes_received(), bytes_received + 100);
+
+    conn.buffer.close();
+    assert!(
+        conn.recv(&mut buf).await.is_err(),
+        "the reads of a closed buffer should fail"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_conn_receives_without_agent_lock() -> Result<(), IceError> {
+    let (ca, cb, a_agent, _) = pipe(None, None).await?;
+    let mut buf = vec![0u8; 10];
+
+    // The first packet validates the route of the pair
+    cb.send(&[1u8; 10]).await?;
+    let n = tokio::time::timeout(Duration::from_secs(5), ca.recv(&mut buf))
+        .await
+        .expect("the first packet should be received")?;
+    assert_eq!(n, 10);
+
+    // The next ones don't wait for the agent lock
+    let ai = a_agent.agent_internal.lock().await;
+    cb.send(&[2u8; 10]).await?;
+    let n = tokio::time::timeout(Duration::from_secs(5), ca.recv(&mut buf))
+        .await
+        .expect("the packet should be received while the agent is locked")?;
+    assert_eq!(&buf[..n], &[2u8; 10]);
+    drop(ai);
+
+    Ok(())
+}
@@ -41,7 +41,7 @@ pub(crate) struct VNet {
 }
 
 impl VNet {
-    pub(crate) async fn close(&self) -> Result<(), Error> {
+    pub(crate) async fn close(&self) -> Result<(), IceError> {
         self.server.close()?;
         let mut w = self.wan.lock().await;
         w.stop().await?;
@@ -61,7 +61,7 @@ pub(crate) const VNET_STUN_SERVER_PORT: u16 = 3478;
 pub(crate) async fn build_simple_vnet(
     _nat_type0: nat::NatType,
     _nat_type1: nat::NatType,
-) -> Result<VNet, Error> {
+) -> Result<VNet, IceError> {
     // WAN
     let wan = Arc::new(Mutex::new(router::Router::new(router::RouterConfig {
         cidr: "0.0.0.0/0".to_owned(),
@@ -110,7 +110,7 @@ pub(crate) async fn build_simple_vnet(
 pub(crate) async fn build_vnet(
     nat_type0: nat::NatType,
     nat_type1: nat::NatType,
-) -> Result<VNet, Error> {
+) -> Result<VNet, IceError> {
     // WAN
     let wan = Arc::new(Mutex::new(router::Router::new(router::RouterConfig {
         cidr: "0.0.0.0/0".to_owned(),
@@ -199,16 +199,18 @@ impl turn::auth::AuthHandler for TestAuthHandler {
         username: &str,
         _realm: &str,
         _src_addr: SocketAddr,
-    ) -> Result<Vec<u8>, Error> {
+    ) -> Result<Vec<u8>, util::Error> {
         if let Some(pw) = self.cred_map.get(username) {
             Ok(pw.to_vec())
         } else {
-            Err(Error::new("fake error".to_owned()))
+            Err(util::Error::new("fake error".to_owned()))
         }
     }
 }
 
-pub(crate) async fn add_vnet_stun(wan_net: Arc<net::Net>) -> Result<turn::server::Server, Error> {
+pub(crate) async fn add_vnet_stun(
+    wan_net: Arc<net::Net>,
+) -> Result<turn::server::Server, IceError> {
     // Run TURN(STUN) server
     let conn = wan_net
         .bind(SocketAddr::from_str(&format!(
@@ -240,7 +242,7 @@ pub(crate) async fn add_vnet_stun(wan_net: Arc<net::Net>) -> Result<turn::server
 pub(crate) async fn connect_with_vnet(
     a_agent: &Arc<Agent>,
     b_agent: &Arc<Agent>,
-) -> Result<(Arc<impl Conn>, Arc<impl Conn>), Error> {
+) -> Result<(Arc<AgentConn>, Arc<AgentConn>), IceError> {
     // Manual signaling
     let (a_ufrag, a_pwd) = a_agent.get_local_user_credentials().await;
     let (b_ufrag, b_pwd) = b_agent.get_local_user_credentials().await;
@@ -256,7 +258,7 @@ pub(crate) async fn connect_with_vnet(
 
         let _ = accepted_tx.send(a_conn).await;
 
-        Ok::<(), Error>(())
+        Ok::<(), IceError>(())
     });
 
     let (_b_cancel_tx, b_cancel_rx) = mpsc::channel(1);
@@ -266,7 +268,7 @@ pub(crate) async fn connect_with_vnet(
     if let Some(a_conn) = accepted_rx.recv().await {
         Ok((a_conn, b_conn))
     } else {
-        Err(Error::new("no a_conn".to_owned()))
+        Err(IceError::Task("no a_conn".to_owned()))
     }
 }
 
@@ -280,7 +282,7 @@ pub(crate) async fn pipe_with_vnet(
     v: &VNet,
     a0test_config: AgentTestConfig,
     a1test_config: AgentTestConfig,
-) -> Result<(Arc<impl Conn>, Arc<impl Conn>), Error> {
+) -> Result<(Arc<AgentConn>, Arc<AgentConn>), IceError> {
     let (a_notifier, mut a_connected) = on_connected();
     let (b_notifier, mut b_connected) = on_connected();
 
@@ -331,6 +333,50 @@ pub(crate) async fn pipe_with_vnet(
     Ok((a_conn, b_conn))
 }
 
+/// Waits for the gathering to complete, and returns the events received meanwhile. Panics if it
+/// doesn't complete within 5 seconds.
+pub(crate) async fn wait_for_gathering_complete(
+    events: &mut broadcast::Receiver<AgentEvent>,
+) -> Vec<AgentEvent> {
+    let mut received = vec![];
+    loop {
+        match tokio::time::timeout(Duration::from_secs(5), events.recv()).await {
+            Ok(Ok(AgentEvent::GatheringStateChange(GatheringState::Complete))) => return received,
+            Ok(Ok(event)) => received.push(event),
+            Ok(Err(err)) => panic!("failed to receive an event: {}", err),
+            Err(_) => panic!("gathering did not complete"),
+        }
+    }
+}
+
+/// A STUN server on the loopback interface which never answers.
+pub(crate) struct SilentStunServer {
+    socket: tokio::net::UdpSocket,
+}
+
+impl SilentStunServer {
+    pub(crate) async fn new() -> Result<Self, IceError> {
+        Ok(Self {
+            socket: tokio::net::UdpSocket::bind("127.0.0.1:0").await?,
+        })
+    }
+
+    pub(crate) fn port(&self) -> Result<u16, IceError> {
+        Ok(self.socket.local_addr()?.port())
+    }
+
+    pub(crate) fn url(&self) -> Result<Url, IceError> {
+        Ok(Url {
+            scheme: SchemeType::Stun,
+            host: "127.0.0.1".to_owned(),
+            port: self.port()?,
+            username: String::new(),
+            password: String::new(),
+            proto: ProtoType::Udp,
+        })
+    }
+}
+
 pub(crate) fn on_connected() -> (OnConnectionStateChangeHdlrFn, mpsc::Receiver<()>) {
     let (done_tx, done_rx) = mpsc::channel::<()>(1);
     let done_tx = Arc::new(Mutex::new(Some(done_tx)));
@@ -349,7 +395,7 @@ pub(crate) fn on_connected() -> (OnConnectionStateChangeHdlrFn, mpsc::Receiver<(
 pub(crate) async fn gather_and_exchange_candidates(
     a_agent: &Arc<Agent>,
     b_agent: &Arc<Agent>,
-) -> Result<(), Error> {
+) -> Result<(), IceError> {
     let wg = WaitGroup::new();
 
     let w1 = Arc::new(Mutex::new(Some(wg.worker())));
@@ -390,6 +436,8 @@ pub(crate) async fn gather_and_exchange_candidates(
     for c in candidates {
         let c2: Arc<dyn Candidate + Send + Sync> =
             Arc::new(b_agent.unmarshal_remote_candidate(c.marshal()).await?);
+        // The stream is signaled along with the candidate, e.g. by its media section
+        c2.set_stream(c.stream());
         b_agent.add_remote_candidate(&c2).await?;
     }
 
@@ -397,13 +445,14 @@ pub(crate) async fn gather_and_exchange_candidates(
     for c in candidates {
         let c2: Arc<dyn Candidate + Send + Sync> =
             Arc::new(a_agent.unmarshal_remote_candidate(c.marshal()).await?);
+        c2.set_stream(c.stream());
         a_agent.add_remote_candidate(&c2).await?;
     }
 
     Ok(())
 }
 
-pub(crate) async fn start_router(router: &Arc<Mutex<router::Router>>) -> Result<(), Error> {
+pub(crate) async fn start_router(router: &Arc<Mutex<router::Router>>) -> Result<(), IceError> {
     let mut w = router.lock().await;
     w.start().await
 }
@@ -411,7 +460,7 @@ pub(crate) async fn start_router(router: &Arc<Mutex<router::Router>>) -> Result<
 pub(crate) async fn connect_net2router(
     net: &Arc<net::Net>,
     router: &Arc<Mutex<router::Router>>,
-) -> Result<(), Error> {
+) -> Result<(), IceError> {
     let nic = net.get_nic()?;
 
     {
@@ -429,7 +478,7 @@ pub(crate) async fn connect_net2router(
 pub(crate) async fn connect_router2router(
     child: &Arc<Mutex<router::Router>>,
     parent: &Arc<Mutex<router::Router>>,
-) -> Result<(), Error> {
+) -> Result<(), IceError> {
     {
         let mut w = parent.lock().await;
         w.add_router(Arc::clone(child)).await?;
@@ -444,7 +493,7 @@ pub(crate) async fn connect_router2router(
 }
 
 #[tokio::test]
-async fn test_connectivity_simple_vnet_full_cone_nats_on_both_ends() -> Result<(), Error> {
+async fn test_connectivity_simple_vnet_full_cone_nats_on_both_ends() -> Result<(), IceError> {
     /*env_logger::Builder::new()
     .format(|buf, record| {
         writeln!(
@@ -497,7 +546,7 @@ async fn test_connectivity_simple_vnet_full_cone_nats_on_both_ends() -> Result<(
 }
 
 #[tokio::test]
-async fn test_connectivity_vnet_full_cone_nats_on_both_ends() -> Result<(), Error> {
+async fn test_connectivity_vnet_full_cone_nats_on_both_ends() -> Result<(), IceError> {
     /*env_logger::Builder::new()
     .format(|buf, record| {
         writeln!(
@@ -559,7 +608,7 @@ async fn test_connectivity_vnet_full_cone_nats_on_both_ends() -> Result<(), Erro
 }
 
 #[tokio::test]
-async fn test_connectivity_vnet_symmetric_nats_on_both_ends() -> Result<(), Error> {
+async fn test_connectivity_vnet_symmetric_nats_on_both_ends() -> Result<(), IceError> {
     /*env_logger::Builder::new()
     .format(|buf, record| {
         writeln!(
@@ -621,8 +670,8 @@ async fn test_connectivity_vnet_symmetric_nats_on_both_ends() -> Result<(), Erro
 }
 
 #[tokio::test]
-async fn test_connectivity_vnet_1to1_nat_with_host_candidate_vs_symmetric_nats() -> Result<(), Error>
-{
+async fn test_connectivity_vnet_1to1_nat_with_host_candidate_vs_symmetric_nats(
+) -> Result<(), IceError> {
     /*env_logger::Builder::new()
     .format(|buf, record| {
         writeln!(
@@ -675,7 +724,7 @@ async fn test_connectivity_vnet_1to1_nat_with_host_candidate_vs_symmetric_nats()
 
 #[tokio::test]
 async fn test_connectivity_vnet_1to1_nat_with_srflx_candidate_vs_symmetric_nats(
-) -> Result<(), Error> {
+) -> Result<(), IceError> {
     /*env_logger::Builder::new()
     .format(|buf, record| {
         writeln!(
@@ -739,7 +788,7 @@ async fn block_until_state_seen(
 
 // test_disconnected_to_connected asserts that an agent can go to disconnected, and then return to connected successfully
 #[tokio::test]
-async fn test_disconnected_to_connected() -> Result<(), Error> {
+async fn test_disconnected_to_connected() -> Result<(), IceError> {
     /*env_logger::Builder::new()
     .format(|buf, record| {
         writeln!(
@@ -793,7 +842,7 @@ async fn test_disconnected_to_connected() -> Result<(), Error> {
             net: Some(Arc::clone(&net0)),
             disconnected_timeout: Some(disconnected_timeout),
             keepalive_interval: Some(keepalive_interval),
-            check_interval: keepalive_interval,
+            check_interval: Some(keepalive_interval),
             ..Default::default()
         })
         .await?,
@@ -806,7 +855,7 @@ async fn test_disconnected_to_connected() -> Result<(), Error> {
             net: Some(Arc::clone(&net1)),
             disconnected_timeout: Some(disconnected_timeout),
             keepalive_interval: Some(keepalive_interval),
-            check_interval: keepalive_interval,
+            check_interval: Some(keepalive_interval),
             ..Default::default()
         })
         .await?,
@@ -883,7 +932,7 @@ async fn test_disconnected_to_connected() -> Result<(), Error> {
 
 // Agent.Write should use the best valid pair if a selected pair is not yet available
 #[tokio::test]
-async fn test_write_use_valid_pair() -> Result<(), Error> {
+async fn test_write_use_valid_pair() -> Result<(), IceError> {
     /*env_logger::Builder::new()
     .format(|buf, record| {
         writeln!(
@@ -970,7 +1019,7 @@ async fn test_write_use_valid_pair() -> Result<(), Error> {
             let mut ai = controlling_agent_tx.agent_internal.lock().await;
             ai.start_connectivity_checks(agent_internal, true, controlled_ufrag, controlled_pwd)
                 .await?;
-            Arc::clone(&ai.agent_conn) as Arc<dyn Conn + Send + Sync>
+            Arc::clone(&ai.agent_conns[0]) as Arc<dyn Conn + Send + Sync>
         };
 
         log::debug!("controlling_agent start_connectivity_checks done...");
@@ -983,7 +1032,7 @@ async fn test_write_use_valid_pair() -> Result<(), Error> {
             tokio::time::sleep(Duration::from_millis(20)).await;
         }
 
-        Ok::<(), Error>(())
+        Ok::<(), IceError>(())
     });
 
     let controlled_agent_conn = {
@@ -991,7 +1040,7 @@ async fn test_write_use_valid_pair() -> Result<(), Error> {
         let mut ai = controlled_agent.agent_internal.lock().await;
         ai.start_connectivity_checks(agent_internal, false, controlling_ufrag, controlling_pwd)
             .await?;
-        Arc::clone(&ai.agent_conn) as Arc<dyn Conn + Send + Sync>
+        Arc::clone(&ai.agent_conns[0]) as Arc<dyn Conn + Send + Sync>
     };
 
     log::debug!("controlled_agent start_connectivity_checks done...");
@@ -7,9 +7,14 @@ mod agent_transport_test;
 #[cfg(test)]
 pub(crate) mod agent_vnet_test;
 
+pub mod agent_buffer;
+pub mod agent_close;
 pub mod agent_config;
+pub mod agent_event;
 pub mod agent_gather;
 pub mod agent_internal;
+pub mod agent_loop;
+pub mod agent_pacer;
 pub mod agent_selector;
 pub mod agent_stats;
 pub mod agent_transport;
@@ -17,37 +22,53 @@ pub mod agent_transport;
 use crate::candidate::*;
 use crate::errors::*;
 use crate::external_ip_mapper::*;
+use crate::ice_options::IceOptions;
+use crate::interface_watcher::{InterfaceChange, InterfaceWatcher};
 use crate::mdns::*;
 use crate::network_type::*;
+use crate::port_mapping::PortMapper;
+use crate::proxy::ProxyDialer;
+use crate::relay_provider::RelayProvider;
+use crate::resolver::Resolver;
+use crate::socket_factory::{
+    OptionsSocketFactory, SocketFactory, SocketOptions, TokioSocketFactory,
+};
 use crate::state::*;
+use crate::tcp_mux::TcpMux;
+use crate::tls_dialer::TlsDialer;
+use crate::turn_credentials::CredentialProvider;
+use crate::udp_mux::{UdpMux, UdpMuxSrflx};
 use crate::url::*;
+use agent_buffer::*;
 use agent_config::*;
+use agent_event::*;
 use agent_internal::*;
+use agent_loop::*;
+use agent_pacer::Pacer;
 use agent_stats::*;
 
 use mdns::conn::*;
 use stun::{agent::*, attributes::*, fingerprint::*, integrity::*, message::*, xoraddr::*};
-use util::{vnet::net::*, Buffer, Error};
+use util::vnet::net::*;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::net::{Ipv4Addr, SocketAddr};
 
 use crate::rand::*;
 
 use crate::agent::agent_gather::GatherCandidatesInternalParams;
 use crate::agent::agent_transport::AgentConn;
-use crate::candidate::candidate_base::CandidateBaseConfig;
+use crate::candidate::candidate_base::unmarshal_candidate_with_agent;
 use crate::candidate::candidate_host::CandidateHostConfig;
-use crate::candidate::candidate_peer_reflexive::CandidatePeerReflexiveConfig;
-use crate::candidate::candidate_relay::CandidateRelayConfig;
-use crate::candidate::candidate_server_reflexive::CandidateServerReflexiveConfig;
+use crate::candidate::candidate_init::CandidateInit;
 use crate::tcp_type::TcpType;
+use crate::util::LocalAddressPolicy;
 use std::future::Future;
 use std::pin::Pin;
-use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::SystemTime;
-use tokio::sync::{broadcast, mpsc, Mutex};
+use tokio::sync::{broadcast, mpsc, Mutex, Semaphore};
 use tokio::time::{Duration, Instant};
 
 #[derive(Debug, Clone)]
@@ -56,6 +77,9 @@ pub(crate) struct BindingRequest {
     pub(crate) transaction_id: TransactionId,
     pub(crate) destination: SocketAddr,
     pub(crate) is_use_candidate: bool,
+    pub(crate) is_controlling: bool,
+    /// The pair the request checks, which records whether it is answered.
+    pub(crate) pair: Option<Arc<CandidatePair>>,
 }
 
 impl Default for BindingRequest {
@@ -65,6 +89,8 @@ impl Default for BindingRequest {
             transaction_id: TransactionId::default(),
             destination: SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), 0),
             is_use_candidate: false,
+            is_controlling: false,
+            pair: None,
         }
     }
 }
@@ -74,6 +100,9 @@ pub type OnConnectionStateChangeHdlrFn = Box<
         + Send
         + Sync,
 >;
+pub type OnGatheringStateChangeHdlrFn = Box<
+    dyn (FnMut(GatheringState) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>>) + Send + Sync,
+>;
 pub type OnSelectedCandidatePairChangeHdlrFn = Box<
     dyn (FnMut(
             &(dyn Candidate + Send + Sync),
@@ -89,15 +118,44 @@ pub type OnCandidateHdlrFn = Box<
         + Send
         + Sync,
 >;
+pub type OnCandidateErrorHdlrFn = Box<
+    dyn (FnMut(CandidateError) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>>) + Send + Sync,
+>;
+pub type OnBindingRequestHdlrFn = Box<
+    dyn (FnMut(
+            Message,
+            Arc<dyn Candidate + Send + Sync>,
+            SocketAddr,
+        ) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>>)
+        + Send
+        + Sync,
+>;
+/// An authenticated inbound binding request, with the local candidate it was received on and
+/// the address it was sent from.
+pub(crate) type InboundBindingRequest = (Message, Arc<dyn Candidate + Send + Sync>, SocketAddr);
 pub type GatherCandidateCancelFn = Box<dyn Fn() + Send + Sync>;
 
 /// Represents the ICE agent.
 pub struct Agent {
     pub(crate) agent_internal: Arc<Mutex<AgentInternal>>,
 
+    pub(crate) agent_id: String,
+
     pub(crate) port_min: u16,
     pub(crate) port_max: u16,
+    pub(crate) streams: Arc<Vec<u16>>,
     pub(crate) interface_filter: Arc<Option<InterfaceFilterFn>>,
+    pub(crate) interfaces: Arc<Vec<String>>,
+    pub(crate) network_costs: Arc<HashMap<String, u16>>,
+    pub(crate) interface_preferences: Arc<HashMap<String, u16>>,
+    pub(crate) ip_filter: Arc<Option<IpFilterFn>>,
+    pub(crate) resolver: Option<Arc<dyn Resolver + Send + Sync>>,
+    pub(crate) socket_factory: Arc<dyn SocketFactory + Send + Sync>,
+    pub(crate) proxy_dialer: Option<Arc<dyn ProxyDialer + Send + Sync>>,
+    pub(crate) tls_dialer: Option<Arc<dyn TlsDialer + Send + Sync>>,
+    pub(crate) credential_provider: Option<Arc<dyn CredentialProvider + Send + Sync>>,
+    pub(crate) relay_provider: Option<Arc<dyn RelayProvider + Send + Sync>>,
+    pub(crate) port_mapper: Option<Arc<dyn PortMapper + Send + Sync>>,
     pub(crate) mdns_mode: MulticastDnsMode,
     pub(crate) mdns_name: String,
     pub(crate) mdns_conn: Option<Arc<DnsConn>>,
@@ -105,20 +163,83 @@ pub struct Agent {
 
     // 1:1 D-NAT IP address mapping
     pub(crate) ext_ip_mapper: Arc<Option<ExternalIpMapper>>,
+    pub(crate) additional_addresses: Arc<Vec<AdditionalAddress>>,
+    pub(crate) address_policy: LocalAddressPolicy,
+    pub(crate) bind_wildcard_udp: bool,
+    pub(crate) udp_mux: Option<Arc<dyn UdpMux + Send + Sync>>,
+    pub(crate) udp_mux_srflx: Option<Arc<dyn UdpMuxSrflx + Send + Sync>>,
+    pub(crate) tcp_mux: Option<Arc<dyn TcpMux + Send + Sync>>,
     pub(crate) gathering_state: Arc<AtomicU8>, //GatheringState,
     pub(crate) candidate_types: Vec<CandidateType>,
+    pub(crate) strict_candidate_types: bool,
     pub(crate) urls: Vec<Url>,
     pub(crate) network_types: Vec<NetworkType>,
 
     pub(crate) gather_candidate_cancel: Option<GatherCandidateCancelFn>,
+    pub(crate) gather_cancel_tx: broadcast::Sender<()>,
+
+    // Trickle ICE: remote candidates still being resolved/added and whether the remote side
+    // has signaled end-of-candidates.
+    pub(crate) pending_remote_candidates: Arc<AtomicUsize>,
+    pub(crate) remote_end_of_candidates: Arc<AtomicBool>,
+
+    pub(crate) events_tx: broadcast::Sender<AgentEvent>,
 }
 
 impl Agent {
     /// Creates a new Agent.
-    pub async fn new(config: AgentConfig) -> Result<Self, Error> {
+    pub async fn new(config: AgentConfig) -> Result<Self, IceError> {
         if config.port_max < config.port_min {
-            return Err(ERR_PORT.to_owned());
+            return Err(IceError::Port);
+        }
+
+        let streams = if config.streams.is_empty() {
+            vec![config.components.unwrap_or(DEFAULT_COMPONENTS)]
+        } else if config.components.is_some() {
+            return Err(IceError::ComponentsWithStreams);
+        } else {
+            config.streams.clone()
+        };
+        if streams.contains(&0) {
+            return Err(IceError::InvalidComponents);
+        }
+        if config.dscp.values().any(|&dscp| dscp > MAX_DSCP) {
+            return Err(IceError::InvalidDscp);
+        }
+        if config
+            .turn_allocation_lifetime
+            .map_or(false, |lifetime| lifetime.as_secs() == 0)
+        {
+            return Err(IceError::InvalidTurnAllocationLifetime);
+        }
+        if config.rto_backoff == Some(0) {
+            return Err(IceError::InvalidRtoBackoff);
         }
+        if usize::from(config.failover_after_lost_checks) > CHECK_WINDOW_SIZE {
+            return Err(IceError::InvalidFailoverAfterLostChecks);
+        }
+        if config.socket_factory.is_some() && config.socket_options.reuses() {
+            return Err(IceError::ReuseWithSocketFactory);
+        }
+        if config.compatibility_mode == CompatibilityMode::Rfc8445Strict
+            && config.nomination_strategy == NominationStrategy::Aggressive
+        {
+            return Err(IceError::AggressiveNominationStrict);
+        }
+        // The muxes demultiplex by ufrag only, so they can't tell the components apart
+        if stream_components(&streams).count() > 1
+            && (config.udp_mux.is_some()
+                || config.udp_mux_srflx.is_some()
+                || config.tcp_mux.is_some())
+        {
+            return Err(IceError::MuxMultipleComponents);
+        }
+
+        let agent_id = if config.agent_id.is_empty() {
+            generate_agent_id()
+        } else {
+            config.agent_id.clone()
+        };
 
         let mut mdns_name = config.multicast_dns_host_name.clone();
         if mdns_name.is_empty() {
@@ -126,7 +247,7 @@ impl Agent {
         }
 
         if !mdns_name.ends_with(".local") || mdns_name.split('.').count() != 2 {
-            return Err(ERR_INVALID_MULTICAST_DNSHOST_NAME.to_owned());
+            return Err(IceError::InvalidMulticastDnsHostName);
         }
 
         let mut mdns_mode = config.multicast_dns_mode;
@@ -134,23 +255,50 @@ impl Agent {
             mdns_mode = MulticastDnsMode::QueryOnly;
         }
 
-        let mdns_conn = match create_multicast_dns(mdns_mode, &mdns_name) {
-            Ok(c) => c,
-            Err(err) => {
-                // Opportunistic mDNS: If we can't open the connection, that's ok: we
-                // can continue without it.
-                log::warn!("Failed to initialize mDNS {}: {}", mdns_name, err);
-                None
+        // mDNS always runs on the host network, so it is left out of virtual networks to keep
+        // them hermetic.
+        let is_virtual = config.net.as_ref().map_or(false, |net| net.is_virtual());
+        let mdns_conn = if is_virtual {
+            if mdns_mode != MulticastDnsMode::Disabled {
+                warn_event!(agent = agent_id; "vnet does not support mDNS yet");
+            }
+            None
+        } else {
+            match create_multicast_dns(mdns_mode, &mdns_name) {
+                Ok(c) => c,
+                Err(err) => {
+                    // Opportunistic mDNS: If we can't open the connection, that's ok: we
+                    // can continue without it.
+                    warn_event!(
+                        agent = agent_id,
+                        name = mdns_name,
+                        error = err;
+                        "Failed to initialize mDNS"
+                    );
+                    None
+                }
             }
         };
 
-        let (chan_state_tx, chan_state_rx) = mpsc::channel(1);
+        let (chan_state_tx, chan_state_rx) = mpsc::unbounded_channel();
         let (chan_candidate_tx, chan_candidate_rx) = mpsc::channel(1);
         let (chan_candidate_pair_tx, chan_candidate_pair_rx) = mpsc::channel(1);
+        let (chan_candidate_error_tx, chan_candidate_error_rx) = mpsc::unbounded_channel();
         let (on_connected_tx, on_connected_rx) = mpsc::channel(1);
         let (done_tx, done_rx) = mpsc::channel(1);
         let (force_candidate_contact_tx, force_candidate_contact_rx) = mpsc::channel(1);
         let (started_ch_tx, _) = broadcast::channel(1);
+        let (closed_ch_tx, closed_ch_rx) = broadcast::channel(1);
+        let (gather_cancel_tx, _) = broadcast::channel(1);
+        let (inbound_tx, inbound_rx) = mpsc::channel(INBOUND_QUEUE_SIZE);
+        let (events_tx, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        let pending_remote_candidates = Arc::new(AtomicUsize::new(0));
+        let remote_end_of_candidates = Arc::new(AtomicBool::new(false));
+        let role = if config.is_controlling {
+            IceRole::Controlling
+        } else {
+            config.role
+        };
 
         let mut ai = AgentInternal {
             on_connected_tx: Some(on_connected_tx),
@@ -163,30 +311,62 @@ impl Agent {
             force_candidate_contact_tx,
             force_candidate_contact_rx: Some(force_candidate_contact_rx),
 
+            inbound_tx,
+            routes_generation: Arc::new(AtomicU64::new(0)),
+
             chan_state_tx: Some(chan_state_tx),
             chan_candidate_tx: Some(Arc::new(chan_candidate_tx)),
             chan_candidate_pair_tx: Some(chan_candidate_pair_tx),
+            chan_candidate_error_tx: Some(chan_candidate_error_tx),
+            chan_binding_request_tx: None,
 
             on_connection_state_change_hdlr: None,
             on_selected_candidate_pair_change_hdlr: None,
+            on_gathering_state_change_hdlr: None,
             on_candidate_hdlr: None,
+            on_candidate_error_hdlr: None,
+
+            events_tx: events_tx.clone(),
 
             tie_breaker: rand::random::<u64>(),
 
             lite: config.lite,
-            is_controlling: config.is_controlling,
+            is_controlling: role == IceRole::Controlling,
+            role_determined: role != IceRole::Unspecified,
+            nomination_strategy: NominationStrategy::Regular,
+            compatibility_mode: CompatibilityMode::Interop,
+            local_ice_options: DEFAULT_ICE_OPTIONS,
+            remote_ice_options: None,
+            prflx_priority: Arc::clone(&config.prflx_priority),
+            candidate_priority: Arc::clone(&config.candidate_priority),
+            binding_request_filter: Arc::clone(&config.binding_request_filter),
             start_time: Instant::now(),
-            nominated_pair: None,
+            nominated_pairs: HashMap::new(),
+            dscp: config.dscp.clone(),
+            pair_selected_at: Instant::now(),
+            release_unused_candidates_after: config.release_unused_candidates_after,
+            backup_pairs: config.backup_pairs,
 
             connection_state: ConnectionState::New,
             local_candidates: HashMap::new(),
             remote_candidates: HashMap::new(),
+            pending_remote_candidates: Arc::clone(&pending_remote_candidates),
+            remote_end_of_candidates: Arc::clone(&remote_end_of_candidates),
 
             insecure_skip_verify: config.insecure_skip_verify,
 
             started_ch_tx: Some(started_ch_tx),
+            closed_ch_tx: Some(closed_ch_tx),
 
             max_binding_requests: 0,
+            max_role_switches: 0,
+            role_switches: 0,
+            initial_rto: Duration::from_secs(0),
+            rto_backoff: DEFAULT_RTO_BACKOFF,
+            pacer: Arc::new(Pacer::new(Duration::from_secs(0))),
+            stun_gather_timeout: Duration::from_secs(0),
+            turn_allocation_lifetime: Duration::from_secs(0),
+            gather_semaphore: Arc::new(Semaphore::new(DEFAULT_GATHER_CONCURRENCY)),
 
             host_acceptance_min_wait: Duration::from_secs(0),
             srflx_acceptance_min_wait: Duration::from_secs(0),
@@ -204,10 +384,24 @@ impl Agent {
             // How often should we send keepalive packets?
             // 0 means never
             keepalive_interval: Duration::from_secs(0),
+            keepalive_strategy: KeepaliveStrategy::BindingRequest,
+            keepalive_valid_pairs: config.keepalive_valid_pairs,
+            failover_after_lost_checks: config.failover_after_lost_checks,
+
+            // How often should we refresh consent on the selected pair?
+            // 0 means never
+            consent_check_interval: Duration::from_secs(0),
+            consent_timeout: Duration::from_secs(0),
+            consent_granted_at: Instant::now(),
+            consent_requested_at: Instant::now(),
+            next_consent_interval: Duration::from_secs(0),
+            checks_paused_at: None,
+            checks_paused_for: Duration::from_secs(0),
 
             // How often should we run our internal taskLoop to check for state changes when connecting
             check_interval: Duration::from_secs(0),
 
+            agent_id: agent_id.clone(),
             local_ufrag: String::new(),
             local_pwd: String::new(),
 
@@ -217,8 +411,22 @@ impl Agent {
             // LRU of outbound Binding request Transaction IDs
             pending_binding_requests: vec![],
 
-            // AgentConn
-            agent_conn: Arc::new(AgentConn::new()),
+            // AgentConn of each component of each stream
+            agent_conns: stream_components(&streams)
+                .map(|(stream, component)| {
+                    let mut agent_conn = AgentConn::new(stream, component);
+                    agent_conn.send_on_valid_pair = config.send_on_valid_pair;
+                    agent_conn.buffer = PacketBuffer::new(
+                        config
+                            .receive_buffer_size
+                            .unwrap_or(DEFAULT_RECEIVE_BUFFER_SIZE),
+                    );
+                    if config.buffer_full_policy != BufferFullPolicy::Unspecified {
+                        agent_conn.buffer_full_policy = config.buffer_full_policy;
+                    }
+                    Arc::new(agent_conn)
+                })
+                .collect(),
         };
 
         config.init_with_defaults(&mut ai);
@@ -230,32 +438,39 @@ impl Agent {
         };
 
         if ai.lite && (candidate_types.len() != 1 || candidate_types[0] != CandidateType::Host) {
-            Self::close_multicast_conn(&mdns_conn).await;
-            return Err(ERR_LITE_USING_NON_HOST_CANDIDATES.to_owned());
+            Self::close_multicast_conn(&agent_id, &mdns_conn).await;
+            return Err(IceError::LiteUsingNonHostCandidates);
         }
 
         if !config.urls.is_empty()
             && !contains_candidate_type(CandidateType::ServerReflexive, &candidate_types)
             && !contains_candidate_type(CandidateType::Relay, &candidate_types)
         {
-            Self::close_multicast_conn(&mdns_conn).await;
-            return Err(ERR_USELESS_URLS_PROVIDED.to_owned());
+            Self::close_multicast_conn(&agent_id, &mdns_conn).await;
+            return Err(IceError::UselessUrlsProvided);
+        }
+
+        if config.tls_dialer.is_none()
+            && config
+                .urls
+                .iter()
+                .any(|url| url.scheme == SchemeType::Turns)
+        {
+            Self::close_multicast_conn(&agent_id, &mdns_conn).await;
+            return Err(IceError::TlsDialerRequired);
         }
 
-        let ext_ip_mapper = match config.init_ext_ip_mapping(mdns_mode, &candidate_types) {
+        let mut ext_ip_mapper = match config.init_ext_ip_mapping(mdns_mode, &candidate_types) {
             Ok(ext_ip_mapper) => ext_ip_mapper,
             Err(err) => {
-                Self::close_multicast_conn(&mdns_conn).await;
+                Self::close_multicast_conn(&agent_id, &mdns_conn).await;
                 return Err(err);
             }
         };
 
         let net = if let Some(net) = config.net {
             if net.is_virtual() {
-                log::warn!("vnet is enabled");
-                if mdns_mode != MulticastDnsMode::Disabled {
-                    log::warn!("vnet does not support mDNS yet");
-                }
+                warn_event!(agent = agent_id; "vnet is enabled");
             }
 
             net
@@ -263,24 +478,88 @@ impl Agent {
             Arc::new(Net::new(None))
         };
 
+        if let Some(ext_ip_mapper) = &mut ext_ip_mapper {
+            if let Err(err) = ext_ip_mapper.resolve_interfaces(&net).await {
+                Self::close_multicast_conn(&agent_id, &mdns_conn).await;
+                return Err(err);
+            }
+        }
+
+        if let Err(err) =
+            validate_additional_addresses(&config.additional_addresses, mdns_mode, &net).await
+        {
+            Self::close_multicast_conn(&agent_id, &mdns_conn).await;
+            return Err(err);
+        }
+
         let a = Self {
+            agent_id: ai.agent_id.clone(),
             port_min: config.port_min,
             port_max: config.port_max,
+            streams: Arc::new(streams),
             agent_internal: Arc::new(Mutex::new(ai)),
             interface_filter: Arc::clone(&config.interface_filter),
+            interfaces: Arc::new(config.interfaces.clone()),
+            network_costs: Arc::new(config.network_costs.clone()),
+            interface_preferences: Arc::new(config.interface_preferences.clone()),
+            ip_filter: Arc::clone(&config.ip_filter),
+            resolver: config.resolver.clone(),
+            socket_factory: match &config.socket_factory {
+                Some(socket_factory) if config.socket_options != SocketOptions::default() => {
+                    Arc::new(OptionsSocketFactory {
+                        socket_factory: Arc::clone(socket_factory),
+                        options: config.socket_options,
+                    })
+                }
+                Some(socket_factory) => Arc::clone(socket_factory),
+                None => Arc::new(TokioSocketFactory::new(config.socket_options)),
+            },
+            proxy_dialer: config.proxy_dialer.clone(),
+            tls_dialer: config.tls_dialer.clone(),
+            credential_provider: config.credential_provider.clone(),
+            relay_provider: config.relay_provider.clone(),
+            port_mapper: config.port_mapper.clone(),
             mdns_mode,
             mdns_name,
             mdns_conn,
             net,
             ext_ip_mapper: Arc::new(ext_ip_mapper),
-            gathering_state: Arc::new(AtomicU8::new(0)), //GatheringState::New,
+            additional_addresses: Arc::new(config.additional_addresses.clone()),
+            address_policy: config.local_address_policy(),
+            bind_wildcard_udp: config.bind_wildcard_udp,
+            udp_mux: config.udp_mux.clone(),
+            udp_mux_srflx: config.udp_mux_srflx.clone(),
+            tcp_mux: config.tcp_mux.clone(),
+            gathering_state: Arc::new(AtomicU8::new(GatheringState::New as u8)),
             candidate_types,
+            strict_candidate_types: config.strict_candidate_types,
             urls: config.urls.clone(),
-            network_types: config.network_types.clone(),
-
-            gather_candidate_cancel: None,
+            network_types: if config.network_types.is_empty() {
+                supported_network_types()
+            } else {
+                config.network_types.clone()
+            },
+
+            gather_candidate_cancel: {
+                let gather_cancel_tx = gather_cancel_tx.clone();
+                Some(Box::new(move || {
+                    let _ = gather_cancel_tx.send(());
+                }))
+            },
+            gather_cancel_tx,
+
+            pending_remote_candidates,
+            remote_end_of_candidates,
+
+            events_tx,
         };
 
+        tokio::spawn(AgentInternal::run_inbound_loop(
+            Arc::clone(&a.agent_internal),
+            inbound_rx,
+            closed_ch_rx,
+        ));
+
         let agent_internal = Arc::clone(&a.agent_internal);
 
         Self::start_on_connection_state_change_routine(
@@ -288,16 +567,20 @@ impl Agent {
             chan_state_rx,
             chan_candidate_rx,
             chan_candidate_pair_rx,
+            chan_candidate_error_rx,
         )
         .await;
 
         // Restart is also used to initialize the agent for the first time
         if let Err(err) = a.restart(config.local_ufrag, config.local_pwd).await {
-            Self::close_multicast_conn(&a.mdns_conn).await;
             let _ = a.close().await;
             return Err(err);
         }
 
+        if let Some(interface_watcher) = &config.interface_watcher {
+            a.watch_interfaces(interface_watcher.subscribe()).await;
+        }
+
         Ok(a)
     }
 
@@ -313,82 +596,193 @@ impl Agent {
         ai.on_selected_candidate_pair_change_hdlr = Some(f);
     }
 
+    /// Sets a handler that is fired when the gathering state changes, i.e. to gathering when
+    /// `gather_candidates` is called, to complete once every candidate was gathered and back to
+    /// new when the agent is restarted.
+    pub async fn on_gathering_state_change(&self, f: OnGatheringStateChangeHdlrFn) {
+        let mut ai = self.agent_internal.lock().await;
+        ai.on_gathering_state_change_hdlr = Some(f);
+    }
+
     /// Sets a handler that is fired when new candidates gathered. When the gathering process
     /// complete the last candidate is nil.
+    ///
+    /// Each candidate is reported as soon as it is ready, so it can be trickled to the remote
+    /// peer without waiting for the gathering to complete.
     pub async fn on_candidate(&self, f: OnCandidateHdlrFn) {
         let mut ai = self.agent_internal.lock().await;
         ai.on_candidate_hdlr = Some(f);
     }
 
+    /// Sets a handler that is fired for every failed attempt to gather candidates from a STUN or
+    /// TURN server, e.g. as it can't be resolved, doesn't answer within
+    /// `AgentConfig::stun_gather_timeout` or refuses the TURN credentials, so that applications
+    /// can alert on misconfigured servers. The other servers are still used, so the gathering
+    /// completes with the candidates of the servers which could be.
+    pub async fn on_candidate_error(&self, f: OnCandidateErrorHdlrFn) {
+        let mut ai = self.agent_internal.lock().await;
+        ai.on_candidate_error_hdlr = Some(f);
+    }
+
+    /// Sets a handler that is fired for every authenticated inbound STUN binding request on a
+    /// candidate pair, with the decoded request, the local candidate it was received on and the
+    /// address it was sent from.
+    ///
+    /// The handler runs on a task of its own, without the agent lock, so that it may call the
+    /// agent and doesn't hold up the handling of the other packets. The requests received while
+    /// it runs are queued.
+    pub async fn on_binding_request(&self, mut f: OnBindingRequestHdlrFn) {
+        let (chan_binding_request_tx, mut chan_binding_request_rx) =
+            mpsc::unbounded_channel::<InboundBindingRequest>();
+        {
+            let mut ai = self.agent_internal.lock().await;
+            if ai.done_tx.is_none() {
+                return;
+            }
+            // The task of the previous handler ends once its queued requests are handled
+            ai.chan_binding_request_tx = Some(chan_binding_request_tx);
+        }
+
+        tokio::spawn(async move {
+            while let Some((m, local, remote)) = chan_binding_request_rx.recv().await {
+                f(m, local, remote).await;
+            }
+        });
+    }
+
+    /// Returns a stream of the events of the agent, as an alternative to the handlers that fits
+    /// actor-style applications, e.g. with `tokio::select!`. Each call returns an independent
+    /// receiver that only gets the events emitted after it was created. A receiver lagging too
+    /// far behind gets `RecvError::Lagged` and misses the oldest events.
+    pub fn events(&self) -> broadcast::Receiver<AgentEvent> {
+        self.events_tx.subscribe()
+    }
+
     async fn start_on_connection_state_change_routine(
         agent_internal: Arc<Mutex<AgentInternal>>,
-        mut chan_state_rx: mpsc::Receiver<ConnectionState>,
+        mut chan_state_rx: mpsc::UnboundedReceiver<ConnectionState>,
         mut chan_candidate_rx: mpsc::Receiver<Option<Arc<dyn Candidate + Send + Sync>>>,
-        mut chan_candidate_pair_rx: mpsc::Receiver<()>,
+        mut chan_candidate_pair_rx: mpsc::Receiver<Arc<CandidatePair>>,
+        mut chan_candidate_error_rx: mpsc::UnboundedReceiver<CandidateError>,
     ) {
         let agent_internal_pair = Arc::clone(&agent_internal);
         tokio::spawn(async move {
             // CandidatePair and ConnectionState are usually changed at once.
             // Blocking one by the other one causes deadlock.
-            while chan_candidate_pair_rx.recv().await.is_some() {
+            while let Some(p) = chan_candidate_pair_rx.recv().await {
                 let mut ai = agent_internal_pair.lock().await;
-                let selected_pair = {
-                    let selected_pair = ai.agent_conn.selected_pair.lock().await;
-                    selected_pair.clone()
-                };
-
-                if let (Some(on_selected_candidate_pair_change), Some(p)) = (
-                    &mut ai.on_selected_candidate_pair_change_hdlr,
-                    &selected_pair,
-                ) {
+                if let Some(on_selected_candidate_pair_change) =
+                    &mut ai.on_selected_candidate_pair_change_hdlr
+                {
                     on_selected_candidate_pair_change(&*p.local, &*p.remote).await;
                 }
             }
         });
 
+        // The states are handed over by a single task in the order of the transitions, so
+        // that a handler never sees e.g. checking after connected.
+        let agent_internal_state = Arc::clone(&agent_internal);
+        tokio::spawn(async move {
+            while let Some(s) = chan_state_rx.recv().await {
+                let mut ai = agent_internal_state.lock().await;
+                if let Some(on_connection_state_change) = &mut ai.on_connection_state_change_hdlr {
+                    on_connection_state_change(s).await;
+                }
+            }
+        });
+
+        let agent_internal_error = Arc::clone(&agent_internal);
         tokio::spawn(async move {
+            while let Some(err) = chan_candidate_error_rx.recv().await {
+                let mut ai = agent_internal_error.lock().await;
+                if let Some(on_candidate_error) = &mut ai.on_candidate_error_hdlr {
+                    on_candidate_error(err).await;
+                }
+            }
+        });
+
+        tokio::spawn(async move {
+            while let Some(c) = chan_candidate_rx.recv().await {
+                let mut ai = agent_internal.lock().await;
+                if let Some(on_candidate) = &mut ai.on_candidate_hdlr {
+                    on_candidate(c).await;
+                }
+            }
+        });
+    }
+
+    /// Waits until the connection state is `state`, returning at once if it already is.
+    /// Fails with `IceError::Timeout` if the state is not reached within `timeout`, and with
+    /// `IceError::Closed` if the agent is closed first.
+    pub async fn wait_for_state(
+        &self,
+        state: ConnectionState,
+        timeout: Duration,
+    ) -> Result<(), IceError> {
+        // The transitions are published under the agent lock, so none is missed between the
+        // check of the current state and the subscription
+        let mut events = {
+            let ai = self.agent_internal.lock().await;
+            if ai.connection_state == state {
+                return Ok(());
+            }
+            if ai.connection_state == ConnectionState::Closed {
+                return Err(IceError::Closed);
+            }
+            self.events_tx.subscribe()
+        };
+
+        let wait = async {
             loop {
-                tokio::select! {
-                    opt_state = chan_state_rx.recv() => {
-                        let mut ai = agent_internal.lock().await;
-                        if let Some(s) = opt_state {
-                            if let Some(on_connection_state_change) = &mut ai.on_connection_state_change_hdlr{
-                                on_connection_state_change(s).await;
-                            }
-                        } else {
-                            while let Some(c) = chan_candidate_rx.recv().await {
-                                if let Some(on_candidate) = &mut ai.on_candidate_hdlr {
-                                    on_candidate(c).await;
-                                }
-                            }
-                            break;
-                        }
-                    },
-                    opt_cand = chan_candidate_rx.recv() => {
-                        let mut ai = agent_internal.lock().await;
-                        if let Some(c) = opt_cand {
-                            if let Some(on_candidate) = &mut ai.on_candidate_hdlr{
-                                on_candidate(c).await;
-                            }
-                        } else {
-                            while let Some(s) = chan_state_rx.recv().await {
-                                if let Some(on_connection_state_change) = &mut ai.on_connection_state_change_hdlr{
-                                    on_connection_state_change(s).await;
-                                }
-                            }
-                            break;
+                match events.recv().await {
+                    Ok(AgentEvent::ConnectionStateChange(s)) if s == state => return Ok(()),
+                    Ok(AgentEvent::ConnectionStateChange(ConnectionState::Closed)) => {
+                        return Err(IceError::Closed)
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        let ai = self.agent_internal.lock().await;
+                        if ai.connection_state == state {
+                            return Ok(());
                         }
                     }
+                    Err(broadcast::error::RecvError::Closed) => return Err(IceError::Closed),
                 }
             }
-        });
+        };
+
+        match tokio::time::timeout(timeout, wait).await {
+            Ok(result) => result,
+            Err(_) => Err(IceError::Timeout("waiting for the connection state")),
+        }
     }
 
     /// Adds a new remote candidate.
+    ///
+    /// Candidates may be trickled in at any time, including after connectivity checks have
+    /// started; new pairs are formed and checked right away. Once
+    /// `add_remote_end_of_candidates` has been called, further candidates are rejected until
+    /// the agent is restarted. With several streams, the candidate is paired with the local
+    /// candidates of the stream set with `Candidate::set_stream`, the first one by default.
     pub async fn add_remote_candidate(
         &self,
         c: &Arc<dyn Candidate + Send + Sync>,
-    ) -> Result<(), Error> {
+    ) -> Result<(), IceError> {
+        if self.remote_end_of_candidates.load(Ordering::SeqCst) {
+            return Err(IceError::RemoteCandidateAfterEndOfCandidates);
+        }
+
+        if self.strict_candidate_types
+            && !contains_candidate_type(c.candidate_type(), &self.candidate_types)
+        {
+            warn_event!(
+                agent = self.agent_id,
+                candidate = c;
+                "Ignoring remote candidate with excluded type {}", c.candidate_type()
+            );
+            return Ok(());
+        }
+
         // cannot check for network yet because it might not be applied
         // when mDNS hostame is used.
         if c.tcp_type() == TcpType::Active {
@@ -401,46 +795,190 @@ impl Agent {
         // If we have a mDNS Candidate lets fully resolve it before adding it locally
         if c.candidate_type() == CandidateType::Host && c.address().ends_with(".local") {
             if self.mdns_mode == MulticastDnsMode::Disabled {
-                log::warn!(
-                    "remote mDNS candidate added, but mDNS is disabled: ({})",
-                    c.address()
+                warn_event!(
+                    agent = self.agent_id,
+                    candidate = c;
+                    "remote mDNS candidate added, but mDNS is disabled"
                 );
                 return Ok(());
             }
 
             if c.candidate_type() != CandidateType::Host {
-                return Err(ERR_ADDRESS_PARSE_FAILED.to_owned());
+                return Err(IceError::AddressParseFailed);
             }
 
+            let agent_id = self.agent_id.clone();
             let agent_internal = Arc::clone(&self.agent_internal);
             let host_candidate = Arc::clone(c);
             let mdns_conn = self.mdns_conn.clone();
+            let network_types = self.network_types.clone();
+            let pending_remote_candidates = Arc::clone(&self.pending_remote_candidates);
+            pending_remote_candidates.fetch_add(1, Ordering::SeqCst);
             tokio::spawn(async move {
-                if let Some(mdns_conn) = mdns_conn {
-                    if let Ok(candidate) =
-                        Self::resolve_and_add_multicast_candidate(mdns_conn, host_candidate).await
+                let closed_ch_rx = {
+                    let ai = agent_internal.lock().await;
+                    ai.closed_ch_tx.as_ref().map(broadcast::Sender::subscribe)
+                };
+
+                if let (Some(mdns_conn), Some(closed_ch_rx)) = (mdns_conn, closed_ch_rx) {
+                    if let Ok(candidate) = Self::resolve_and_add_multicast_candidate(
+                        &agent_id,
+                        mdns_conn,
+                        host_candidate,
+                        closed_ch_rx,
+                    )
+                    .await
                     {
-                        let mut ai = agent_internal.lock().await;
-                        ai.add_remote_candidate(&candidate).await;
+                        // The network type of a mDNS candidate is only known once resolved
+                        if network_types.contains(&candidate.network_type()) {
+                            let mut ai = agent_internal.lock().await;
+                            ai.add_remote_candidate(&candidate).await;
+                        } else {
+                            warn_event!(
+                                agent = agent_id,
+                                candidate = candidate,
+                                network_type = candidate.network_type();
+                                "Ignoring remote candidate with disabled network type"
+                            );
+                        }
                     }
                 }
+                pending_remote_candidates.fetch_sub(1, Ordering::SeqCst);
             });
+        } else if !self.network_types.contains(&c.network_type()) {
+            warn_event!(
+                agent = self.agent_id,
+                candidate = c,
+                network_type = c.network_type();
+                "Ignoring remote candidate with disabled network type"
+            );
         } else {
             let agent_internal = Arc::clone(&self.agent_internal);
             let candidate = Arc::clone(c);
+            let pending_remote_candidates = Arc::clone(&self.pending_remote_candidates);
+            pending_remote_candidates.fetch_add(1, Ordering::SeqCst);
             tokio::spawn(async move {
                 let mut ai = agent_internal.lock().await;
                 ai.add_remote_candidate(&candidate).await;
+                pending_remote_candidates.fetch_sub(1, Ordering::SeqCst);
             });
         }
 
         Ok(())
     }
 
+    /// Signals that the remote agent has finished trickling candidates, e.g. when an
+    /// end-of-candidates indication is received over signaling (RFC 8838).
+    ///
+    /// Once every remote candidate added so far has been processed and all pairs in the
+    /// checklist have failed, the agent transitions to `ConnectionState::Failed` without waiting
+    /// for the failed timeout.
+    pub fn add_remote_end_of_candidates(&self) {
+        self.remote_end_of_candidates.store(true, Ordering::SeqCst);
+
+        let agent_internal = Arc::clone(&self.agent_internal);
+        tokio::spawn(async move {
+            let ai = agent_internal.lock().await;
+            ai.request_connectivity_check();
+        });
+    }
+
+    /// Temporarily stops sending connectivity checks, keepalives and consent requests, e.g. while
+    /// a mobile application is in the background, without tearing down candidates or selected
+    /// pairs. Incoming checks are still answered.
+    pub async fn pause_checks(&self) {
+        let mut ai = self.agent_internal.lock().await;
+        ai.pause_checks();
+    }
+
+    /// Resumes the checks stopped by `pause_checks`. The time they were paused for doesn't count
+    /// towards the disconnected, failed and consent timeouts.
+    pub async fn resume_checks(&self) {
+        let mut ai = self.agent_internal.lock().await;
+        ai.resume_checks().await;
+    }
+
+    /// Returns true while checks are paused by `pause_checks`.
+    pub async fn checks_paused(&self) -> bool {
+        let ai = self.agent_internal.lock().await;
+        ai.checks_paused_at.is_some()
+    }
+
+    /// Returns the ICE options the agent advertises, see `AgentConfig::ice_options`.
+    pub async fn local_ice_options(&self) -> IceOptions {
+        let ai = self.agent_internal.lock().await;
+        ai.local_ice_options
+    }
+
+    /// Sets the ICE options the remote agent advertised in the `a=ice-options` attribute of its
+    /// session description, see `IceOptions::unmarshal`. Until they are set, the agent assumes
+    /// the remote agent trickles candidates, and neither renominates nor implements RFC 8445.
+    pub async fn set_remote_ice_options(&self, options: IceOptions) {
+        let mut ai = self.agent_internal.lock().await;
+        ai.remote_ice_options = Some(options);
+        // A remote agent which does not trickle has given all its candidates already
+        ai.request_connectivity_check();
+    }
+
+    /// Returns the ICE options supported by both agents, or `None` until the options of the
+    /// remote agent are set with `set_remote_ice_options`.
+    pub async fn negotiated_ice_options(&self) -> Option<IceOptions> {
+        let ai = self.agent_internal.lock().await;
+        ai.negotiated_ice_options()
+    }
+
+    /// Adds a host candidate sending and receiving over `conn`, a transport provided by the
+    /// application, e.g. an overlay network or a test harness socket. The agent runs its receive
+    /// loop over `conn` and closes it along with the candidate, e.g. on restart.
+    ///
+    /// The address and port of the candidate default to the local address of `conn`, its
+    /// network to UDP and its component to `COMPONENT_RTP`.
+    pub async fn add_local_candidate_with_conn(
+        &self,
+        mut config: CandidateHostConfig,
+        conn: Arc<dyn util::Conn + Send + Sync>,
+    ) -> Result<Arc<dyn Candidate + Send + Sync>, IceError> {
+        let base_config = &mut config.base_config;
+        if base_config.address.is_empty() || base_config.port == 0 {
+            let local_addr = conn.local_addr().await?;
+            if base_config.address.is_empty() {
+                base_config.address = local_addr.ip().to_string();
+            }
+            if base_config.port == 0 {
+                base_config.port = local_addr.port();
+            }
+        }
+        if base_config.network.is_empty() {
+            base_config.network = UDP.to_owned();
+        }
+        if base_config.component == 0 {
+            base_config.component = COMPONENT_RTP;
+        }
+        base_config.conn = Some(conn);
+        let (stream, component) = (base_config.stream, base_config.component);
+
+        let mut ai = self.agent_internal.lock().await;
+        if ai.done_tx.is_none() {
+            return Err(IceError::Closed);
+        }
+        if ai.agent_conn(stream, component).is_none() {
+            return Err(IceError::UnknownComponent);
+        }
+
+        let candidate: Arc<dyn Candidate + Send + Sync> = Arc::new(
+            config
+                .new_candidate_host(Some(Arc::clone(&self.agent_internal)))
+                .await?,
+        );
+        ai.add_candidate(&candidate).await?;
+
+        Ok(candidate)
+    }
+
     /// Returns the local candidates.
     pub async fn get_local_candidates(
         &self,
-    ) -> Result<Vec<Arc<dyn Candidate + Send + Sync>>, Error> {
+    ) -> Result<Vec<Arc<dyn Candidate + Send + Sync>>, IceError> {
         let mut res = vec![];
 
         {
@@ -455,36 +993,204 @@ impl Agent {
         Ok(res)
     }
 
-    /// Returns the local user credentials.
+    /// Returns a snapshot of the local candidates gathered so far, as plain descriptions which
+    /// don't follow the agent. It can be called at any time, including while gathering is still
+    /// in progress.
+    pub async fn get_local_candidate_inits(&self) -> Result<Vec<CandidateInit>, IceError> {
+        let ai = self.agent_internal.lock().await;
+        Ok(ai
+            .local_candidates
+            .values()
+            .flatten()
+            .map(|c| CandidateInit::from(c.as_ref()))
+            .collect())
+    }
+
+    /// Returns a snapshot of the remote candidates added so far, peer reflexive candidates
+    /// discovered by connectivity checks included, see `get_local_candidate_inits`.
+    pub async fn get_remote_candidate_inits(&self) -> Result<Vec<CandidateInit>, IceError> {
+        let ai = self.agent_internal.lock().await;
+        Ok(ai
+            .remote_candidates
+            .values()
+            .flatten()
+            .map(|c| CandidateInit::from(c.as_ref()))
+            .collect())
+    }
+
+    /// Returns the local user credentials. They are set as soon as the agent is created, or
+    /// restarted, so they can be signaled before gathering.
     pub async fn get_local_user_credentials(&self) -> (String, String) {
         let ai = self.agent_internal.lock().await;
         (ai.local_ufrag.clone(), ai.local_pwd.clone())
     }
 
+    /// Returns the identifier of the agent, see `AgentConfig::agent_id`.
+    pub fn id(&self) -> &str {
+        &self.agent_id
+    }
+
+    /// Returns the selected pair of the first component, i.e. the path its traffic is currently
+    /// taking, or none if no pair has been selected yet.
+    pub async fn get_selected_candidate_pair(&self) -> Option<Arc<CandidatePair>> {
+        let ai = self.agent_internal.lock().await;
+        ai.agent_conns[0].get_selected_pair().await
+    }
+
+    /// Selects the pair of the local candidate `local_id` and the remote candidate `remote_id`,
+    /// overriding the choice by priority, e.g. to send through a given relay. The pair must be
+    /// valid, i.e. its check succeeded. The controlling agent nominates it, which replaces a
+    /// selected pair only with renomination, and selects it once the remote agent answers the
+    /// nomination, see `AgentEvent::SelectedCandidatePairChange`. The controlled agent selects it
+    /// right away, but only if the controlling agent nominated it.
+    pub async fn select_candidate_pair(
+        &self,
+        local_id: &str,
+        remote_id: &str,
+    ) -> Result<(), IceError> {
+        let mut ai = self.agent_internal.lock().await;
+        let mut pair = None;
+        for agent_conn in &ai.agent_conns {
+            let checklist = agent_conn.checklist.lock().await;
+            pair = checklist
+                .iter()
+                .find(|p| p.local.id() == local_id && p.remote.id() == remote_id)
+                .cloned();
+            if pair.is_some() {
+                break;
+            }
+        }
+
+        match pair {
+            Some(p) => ai.select_pair_manually(p).await,
+            None => Err(IceError::CandidatePairNotFound),
+        }
+    }
+
+    /// Sets the DSCP marking the packets of `component`, in every stream, see `AgentConfig::dscp`.
+    /// It is applied to the pairs already selected for the component right away.
+    pub async fn set_dscp(&self, component: u16, dscp: u8) -> Result<(), IceError> {
+        if dscp > MAX_DSCP {
+            return Err(IceError::InvalidDscp);
+        }
+
+        let mut ai = self.agent_internal.lock().await;
+        ai.dscp.insert(component, dscp);
+        for p in ai.get_selected_pairs().await {
+            if p.component() == component {
+                ai.mark_selected_pair(&p);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Creates a TURN permission for the IP address of `addr` on every relay candidate of its
+    /// address family. Permissions are created for the remote candidates as they are added, this
+    /// is for the peers the agent learns of otherwise, e.g. a media server known in advance.
+    pub async fn create_permission(&self, addr: SocketAddr) -> Result<(), IceError> {
+        let ai = self.agent_internal.lock().await;
+        let mut created = false;
+        for cands in ai.local_candidates.values() {
+            for cand in cands {
+                if cand.candidate_type() == CandidateType::Relay
+                    && cand.network_type().is_ipv4() == addr.is_ipv4()
+                {
+                    agent_internal::create_permission(&self.agent_id, cand, addr);
+                    created = true;
+                }
+            }
+        }
+
+        if created {
+            Ok(())
+        } else {
+            Err(IceError::NoRelayCandidate)
+        }
+    }
+
+    /// Returns the connection of `component`, e.g. `COMPONENT_RTCP`, or none if the agent was not
+    /// configured with that many components. Like the connection returned by `dial` and `accept`
+    /// for the first component, it sends over the pair selected for its component.
+    pub async fn get_component_conn(&self, component: u16) -> Option<Arc<AgentConn>> {
+        self.get_stream_conn(0, component).await
+    }
+
+    /// Returns the connection of `component` of the data stream `stream`, or none if the agent
+    /// was not configured with that stream or component, see `AgentConfig::streams`.
+    pub async fn get_stream_conn(&self, stream: u16, component: u16) -> Option<Arc<AgentConn>> {
+        let ai = self.agent_internal.lock().await;
+        ai.agent_conn(stream, component).cloned()
+    }
+
+    /// Returns true if the agent currently has the controlling role. The role given to `dial` or
+    /// `accept` may be switched to resolve a role conflict with the remote agent.
+    pub async fn is_controlling(&self) -> bool {
+        let ai = self.agent_internal.lock().await;
+        ai.is_controlling
+    }
+
+    /// Returns the role of the agent, `IceRole::Unspecified` until it is determined, see
+    /// `AgentConfig::role`.
+    pub async fn role(&self) -> IceRole {
+        let ai = self.agent_internal.lock().await;
+        if !ai.role_determined {
+            IceRole::Unspecified
+        } else if ai.is_controlling {
+            IceRole::Controlling
+        } else {
+            IceRole::Controlled
+        }
+    }
+
+    /// Returns the checklist, i.e. every candidate pair formed so far in the order they were
+    /// formed. Each pair exposes its priority, state, nominated flag and component, which helps
+    /// to understand why a pair was or wasn't selected. A pair stays frozen until no other pair
+    /// of its foundation is being checked. With several components or streams, the pairs are
+    /// grouped by stream and component.
+    pub async fn get_candidate_pairs(&self) -> Vec<Arc<CandidatePair>> {
+        let ai = self.agent_internal.lock().await;
+        let mut pairs = vec![];
+        for agent_conn in &ai.agent_conns {
+            let checklist = agent_conn.checklist.lock().await;
+            pairs.extend(checklist.iter().cloned());
+        }
+        pairs
+    }
+
     /// Returns the remote user credentials.
     pub async fn get_remote_user_credentials(&self) -> (String, String) {
         let ai = self.agent_internal.lock().await;
         (ai.remote_ufrag.clone(), ai.remote_pwd.clone())
     }
 
-    /// Cleans up the Agent.
-    pub async fn close(&self) -> Result<(), Error> {
-        if let Some(gather_candidate_cancel) = &self.gather_candidate_cancel {
-            gather_candidate_cancel();
+    /// Cleans up the Agent. It gives up on the resources that don't shut down within
+    /// `DEFAULT_CLOSE_TIMEOUT` and logs them, see `close_with_timeout` to handle them instead.
+    pub async fn close(&self) -> Result<(), IceError> {
+        let report = self.close_with_timeout(DEFAULT_CLOSE_TIMEOUT).await?;
+        for failure in &report.failures {
+            warn_event!(
+                agent = self.agent_id,
+                resource = failure.resource,
+                error = failure.error;
+                "Failed to close"
+            );
         }
 
-        let mut ai = self.agent_internal.lock().await;
-        ai.close().await
+        Ok(())
     }
 
-    /// Sets the credentials of the remote agent.
+    /// Sets the credentials of the remote agent. It may be called before or after the checks
+    /// have started, e.g. when the answer arrives late or the remote agent has restarted, and
+    /// the following checks are authenticated with the new credentials. No check is sent until
+    /// the credentials are known.
     pub async fn set_remote_credentials(
         &self,
         remote_ufrag: String,
         remote_pwd: String,
-    ) -> Result<(), Error> {
+    ) -> Result<(), IceError> {
         let mut ai = self.agent_internal.lock().await;
-        ai.set_remote_credentials(remote_ufrag, remote_pwd)
+        ai.set_remote_credentials(remote_ufrag, remote_pwd).await
     }
 
     /// Restarts the ICE Agent with the provided ufrag/pwd
@@ -492,7 +1198,7 @@ impl Agent {
     ///
     /// Restart must only be called when `GatheringState` is `GatheringStateComplete`
     /// a user must then call `GatherCandidates` explicitly to start generating new ones.
-    pub async fn restart(&self, mut ufrag: String, mut pwd: String) -> Result<(), Error> {
+    pub async fn restart(&self, mut ufrag: String, mut pwd: String) -> Result<(), IceError> {
         if ufrag.is_empty() {
             ufrag = generate_ufrag();
         }
@@ -501,24 +1207,50 @@ impl Agent {
         }
 
         if ufrag.len() * 8 < 24 {
-            return Err(ERR_LOCAL_UFRAG_INSUFFICIENT_BITS.to_owned());
+            return Err(IceError::LocalUfragInsufficientBits);
         }
         if pwd.len() * 8 < 128 {
-            return Err(ERR_LOCAL_PWD_INSUFFICIENT_BITS.to_owned());
+            return Err(IceError::LocalPwdInsufficientBits);
+        }
+        if !is_ice_chars(&ufrag, 4) {
+            return Err(IceError::LocalUfragInvalid);
+        }
+        if !is_ice_chars(&pwd, 22) {
+            return Err(IceError::LocalPwdInvalid);
         }
 
         if GatheringState::from(self.gathering_state.load(Ordering::SeqCst))
             == GatheringState::Gathering
         {
-            return Err(ERR_RESTART_WHEN_GATHERING.to_owned());
+            return Err(IceError::RestartWhenGathering);
         }
-        self.gathering_state
-            .store(GatheringState::New as u8, Ordering::SeqCst);
+        Self::set_gathering_state(
+            &self.agent_internal,
+            &None,
+            &self.events_tx,
+            &self.gathering_state,
+            GatheringState::New,
+        )
+        .await;
+        self.remote_end_of_candidates.store(false, Ordering::SeqCst);
 
         let mut ai = self.agent_internal.lock().await;
 
         if ai.done_tx.is_none() {
-            return Err(ERR_CLOSED.to_owned());
+            return Err(IceError::Closed);
+        }
+
+        // Connections muxed under the old ufrag would no longer receive any traffic
+        if !ai.local_ufrag.is_empty() {
+            if let Some(udp_mux) = &self.udp_mux {
+                udp_mux.remove_conn_by_ufrag(&ai.local_ufrag).await;
+            }
+            if let Some(udp_mux_srflx) = &self.udp_mux_srflx {
+                udp_mux_srflx.remove_conn_by_ufrag(&ai.local_ufrag).await;
+            }
+            if let Some(tcp_mux) = &self.tcp_mux {
+                tcp_mux.remove_conn_by_ufrag(&ai.local_ufrag).await;
+            }
         }
 
         // Clear all agent needed to take back to fresh state
@@ -527,10 +1259,15 @@ impl Agent {
         ai.remote_ufrag = String::new();
         ai.remote_pwd = String::new();
         ai.pending_binding_requests = vec![];
+        ai.role_switches = 0;
 
-        {
-            let mut checklist = ai.agent_conn.checklist.lock().await;
+        for agent_conn in &ai.agent_conns {
+            let mut checklist = agent_conn.checklist.lock().await;
             *checklist = vec![];
+            agent_conn.validated.store(false, Ordering::SeqCst);
+            agent_conn
+                .host_pair_succeeded
+                .store(false, Ordering::SeqCst);
         }
 
         ai.set_selected_pair(None).await;
@@ -546,16 +1283,31 @@ impl Agent {
         Ok(())
     }
 
+    /// Returns the state of the candidate gathering.
+    pub fn gathering_state(&self) -> GatheringState {
+        GatheringState::from(self.gathering_state.load(Ordering::SeqCst))
+    }
+
+    /// Aborts the gathering started by `gather_candidates`, e.g. when the user hangs up during
+    /// call setup. The STUN and TURN transactions in flight are dropped right away along with
+    /// their sockets, and the gathering state becomes `GatheringState::Complete`. The candidates
+    /// gathered so far are kept until the agent is closed.
+    pub fn cancel_gathering(&self) {
+        if let Some(gather_candidate_cancel) = &self.gather_candidate_cancel {
+            gather_candidate_cancel();
+        }
+    }
+
     /// Initiates the trickle based gathering process.
-    pub async fn gather_candidates(&self) -> Result<(), Error> {
+    pub async fn gather_candidates(&self) -> Result<(), IceError> {
         if self.gathering_state.load(Ordering::SeqCst) != GatheringState::New as u8 {
-            return Err(ERR_MULTIPLE_GATHER_ATTEMPTED.to_owned());
+            return Err(IceError::MultipleGatherAttempted);
         }
 
         let chan_candidate_tx = {
             let ai = self.agent_internal.lock().await;
-            if ai.on_candidate_hdlr.is_none() {
-                return Err(ERR_NO_ON_CANDIDATE_HANDLER.to_owned());
+            if ai.on_candidate_hdlr.is_none() && self.events_tx.receiver_count() == 0 {
+                return Err(IceError::NoOnCandidateHandler);
             }
             ai.chan_candidate_tx.clone()
         };
@@ -564,9 +1316,22 @@ impl Agent {
             gather_candidate_cancel(); // Cancel previous gathering routine
         }
 
-        //TODO: a.gatherCandidateCancel = cancel
+        let params = self.gather_candidates_params(chan_candidate_tx);
+        let cancel_rx = self.gather_cancel_tx.subscribe();
+        tokio::spawn(async move {
+            Self::gather_candidates_internal(params, cancel_rx).await;
+        });
+
+        Ok(())
+    }
 
-        let params = GatherCandidatesInternalParams {
+    fn gather_candidates_params(
+        &self,
+        chan_candidate_tx: ChanCandidateTx,
+    ) -> GatherCandidatesInternalParams {
+        GatherCandidatesInternalParams {
+            agent_id: self.agent_id.clone(),
+            streams: Arc::clone(&self.streams),
             candidate_types: self.candidate_types.clone(),
             urls: self.urls.clone(),
             network_types: self.network_types.clone(),
@@ -576,16 +1341,30 @@ impl Agent {
             mdns_name: self.mdns_name.clone(),
             net: Arc::clone(&self.net),
             interface_filter: self.interface_filter.clone(),
+            interfaces: Arc::clone(&self.interfaces),
+            network_costs: Arc::clone(&self.network_costs),
+            interface_preferences: Arc::clone(&self.interface_preferences),
+            ip_filter: self.ip_filter.clone(),
+            resolver: self.resolver.clone(),
+            socket_factory: Arc::clone(&self.socket_factory),
+            proxy_dialer: self.proxy_dialer.clone(),
+            tls_dialer: self.tls_dialer.clone(),
+            credential_provider: self.credential_provider.clone(),
+            relay_provider: self.relay_provider.clone(),
+            port_mapper: self.port_mapper.clone(),
             ext_ip_mapper: Arc::clone(&self.ext_ip_mapper),
+            additional_addresses: Arc::clone(&self.additional_addresses),
+            address_policy: self.address_policy,
+            bind_wildcard_udp: self.bind_wildcard_udp,
+            udp_mux: self.udp_mux.clone(),
+            udp_mux_srflx: self.udp_mux_srflx.clone(),
+            tcp_mux: self.tcp_mux.clone(),
             agent_internal: Arc::clone(&self.agent_internal),
             gathering_state: Arc::clone(&self.gathering_state),
             chan_candidate_tx,
-        };
-        tokio::spawn(async move {
-            Self::gather_candidates_internal(params).await;
-        });
-
-        Ok(())
+            events_tx: self.events_tx.clone(),
+            gather_cancel_tx: self.gather_cancel_tx.clone(),
+        }
     }
 
     /// Returns a list of candidate pair stats.
@@ -607,157 +1386,37 @@ impl Agent {
     }
 
     /// Creates a Remote Candidate from its string representation.
-    pub async fn unmarshal_remote_candidate(&self, raw: String) -> Result<impl Candidate, Error> {
-        let split: Vec<&str> = raw.split_whitespace().collect();
-        if split.len() < 8 {
-            return Err(Error::new(format!(
-                "{} ({})",
-                *ERR_ATTRIBUTE_TOO_SHORT_ICE_CANDIDATE,
-                split.len()
-            )));
-        }
-
-        // Foundation
-        let foundation = split[0].to_owned();
-
-        // Component
-        let component: u16 = split[1].parse()?;
-
-        // Network
-        let network = split[2].to_owned();
-
-        // Priority
-        let priority: u32 = split[3].parse()?;
-
-        // Address
-        let address = split[4].to_owned();
-
-        // Port
-        let port: u16 = split[5].parse()?;
-
-        let typ = split[7];
-
-        let mut rel_addr = String::new();
-        let mut rel_port = 0;
-        let mut tcp_type = TcpType::Unspecified;
-
-        if split.len() > 8 {
-            let split2 = &split[8..];
-
-            if split2[0] == "raddr" {
-                if split2.len() < 4 {
-                    return Err(Error::new(format!(
-                        "{}: incorrect length",
-                        *ERR_PARSE_RELATED_ADDR
-                    )));
-                }
-
-                // RelatedAddress
-                rel_addr = split2[1].to_owned();
-
-                // RelatedPort
-                rel_port = split2[3].parse()?;
-            } else if split2[0] == "tcptype" {
-                if split2.len() < 2 {
-                    return Err(Error::new(format!("{}: incorrect length", *ERR_PARSE_TYPE)));
-                }
-
-                tcp_type = TcpType::from(split2[1]);
-            }
-        }
-
-        match typ {
-            "host" => {
-                let config = CandidateHostConfig {
-                    base_config: CandidateBaseConfig {
-                        network,
-                        address,
-                        port,
-                        component,
-                        priority,
-                        foundation,
-                        ..CandidateBaseConfig::default()
-                    },
-                    tcp_type,
-                };
-                config
-                    .new_candidate_host(Some(Arc::clone(&self.agent_internal)))
-                    .await
-            }
-            "srflx" => {
-                let config = CandidateServerReflexiveConfig {
-                    base_config: CandidateBaseConfig {
-                        network,
-                        address,
-                        port,
-                        component,
-                        priority,
-                        foundation,
-                        ..CandidateBaseConfig::default()
-                    },
-                    rel_addr,
-                    rel_port,
-                };
-                config
-                    .new_candidate_server_reflexive(Some(Arc::clone(&self.agent_internal)))
-                    .await
-            }
-            "prflx" => {
-                let config = CandidatePeerReflexiveConfig {
-                    base_config: CandidateBaseConfig {
-                        network,
-                        address,
-                        port,
-                        component,
-                        priority,
-                        foundation,
-                        ..CandidateBaseConfig::default()
-                    },
-                    rel_addr,
-                    rel_port,
-                };
-
-                config
-                    .new_candidate_peer_reflexive(Some(Arc::clone(&self.agent_internal)))
-                    .await
-            }
-            "relay" => {
-                let config = CandidateRelayConfig {
-                    base_config: CandidateBaseConfig {
-                        network,
-                        address,
-                        port,
-                        component,
-                        priority,
-                        foundation,
-                        ..CandidateBaseConfig::default()
-                    },
-                    rel_addr,
-                    rel_port,
-                    ..CandidateRelayConfig::default()
-                };
-                config
-                    .new_candidate_relay(Some(Arc::clone(&self.agent_internal)))
-                    .await
-            }
-            _ => Err(Error::new(format!(
-                "{} ({})",
-                *ERR_UNKNOWN_CANDIDATE_TYPE, typ
-            ))),
-        }
+    pub async fn unmarshal_remote_candidate(
+        &self,
+        raw: String,
+    ) -> Result<impl Candidate, IceError> {
+        unmarshal_candidate_with_agent(&raw, Some(Arc::clone(&self.agent_internal))).await
     }
 
     async fn resolve_and_add_multicast_candidate(
+        agent_id: &str,
         mdns_conn: Arc<DnsConn>,
         c: Arc<dyn Candidate + Send + Sync>,
-    ) -> Result<Arc<dyn Candidate + Send + Sync>, Error> {
-        //TODO: hook up _close_query_signal_tx to Agent or Candidate's Close signal?
+        mut closed_ch_rx: broadcast::Receiver<()>,
+    ) -> Result<Arc<dyn Candidate + Send + Sync>, IceError> {
         let (_close_query_signal_tx, close_query_signal_rx) = mpsc::channel(1);
-        let src = match mdns_conn.query(&c.address(), close_query_signal_rx).await {
+
+        // Abandon the query as soon as the agent is closed
+        let result = tokio::select! {
+            result = mdns_conn.query(&c.address(), close_query_signal_rx) => result,
+            _ = closed_ch_rx.recv() => return Err(IceError::Closed),
+        };
+
+        let src = match result {
             Ok((_, src)) => src,
             Err(err) => {
-                log::warn!("Failed to discover mDNS candidate {}: {}", c.address(), err);
-                return Err(err);
+                warn_event!(
+                    agent = agent_id,
+                    candidate = c.address(),
+                    error = err;
+                    "Failed to discover mDNS candidate"
+                );
+                return Err(err.into());
             }
         };
 
@@ -766,11 +1425,19 @@ impl Agent {
         Ok(c)
     }
 
-    async fn close_multicast_conn(mdns_conn: &Option<Arc<DnsConn>>) {
+    async fn close_multicast_conn(agent_id: &str, mdns_conn: &Option<Arc<DnsConn>>) {
         if let Some(conn) = mdns_conn {
             if let Err(err) = conn.close().await {
-                log::warn!("failed to close mDNS Conn: {}", err);
+                warn_event!(agent = agent_id, error = err; "failed to close mDNS Conn");
             }
         }
     }
 }
+
+/// Tells whether s is made of min to 256 ice-chars, the grammar of ice-ufrag and ice-pwd in
+/// RFC 8839 Section 5.4.
+fn is_ice_chars(s: &str, min: usize) -> bool {
+    (min..=256).contains(&s.len())
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'+' || b == b'/')
+}
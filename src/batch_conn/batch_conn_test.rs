@@ -0,0 +1,104 @@
+use super::*;
+use crate::errors::*;
+
+use async_trait::async_trait;
+use socket2::SockRef;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::time::Duration;
+
+#[tokio::test]
+async fn test_batch_udp_conn_sends_every_packet_in_order() -> Result<(), IceError> {
+    let conn = BatchUdpConn::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let local_addr = conn.local_addr().await?;
+
+    let remote = UdpSocket::bind("127.0.0.1:0").await?;
+    let remote_addr = remote.local_addr()?;
+
+    let packets: Vec<Vec<u8>> = (0..64_u8).map(|i| vec![i; usize::from(i) + 1]).collect();
+    let bufs: Vec<&[u8]> = packets.iter().map(Vec::as_slice).collect();
+    let n = conn.send_batch_to(&bufs, remote_addr).await?;
+    assert_eq!(n, packets.len(), "should send every packet");
+
+    let mut buf = vec![0_u8; 1500];
+    for packet in &packets {
+        let (n, src_addr) =
+            tokio::time::timeout(Duration::from_secs(5), remote.recv_from(&mut buf))
+                .await
+                .expect("packet should be received")?;
+        assert_eq!(&buf[..n], &packet[..], "should match");
+        assert_eq!(src_addr, local_addr, "should match");
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_batch_udp_conn_set_tos() -> Result<(), IceError> {
+    let conn = BatchUdpConn::new(UdpSocket::bind("127.0.0.1:0").await?);
+    conn.set_tos(46 << 2)?;
+    assert_eq!(
+        SockRef::from(&conn.socket).tos_v4()?,
+        46 << 2,
+        "should match"
+    );
+
+    Ok(())
+}
+
+/// Accepts a given number of packets, then fails.
+struct FailingConn {
+    accepted: AtomicUsize,
+}
+
+#[async_trait]
+impl Conn for FailingConn {
+    async fn connect(&self, _addr: SocketAddr) -> io::Result<()> {
+        Ok(())
+    }
+
+    async fn recv(&self, _buf: &mut [u8]) -> io::Result<usize> {
+        Ok(0)
+    }
+
+    async fn recv_from(&self, _buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        Err(io::Error::new(io::ErrorKind::Other, "not readable"))
+    }
+
+    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    async fn send_to(&self, buf: &[u8], _target: SocketAddr) -> io::Result<usize> {
+        let accepted = self.accepted.load(Ordering::SeqCst);
+        if accepted == 0 {
+            return Err(io::Error::new(io::ErrorKind::Other, "buffer full"));
+        }
+        self.accepted.store(accepted - 1, Ordering::SeqCst);
+        Ok(buf.len())
+    }
+
+    async fn local_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::Error::new(io::ErrorKind::Other, "not bound"))
+    }
+}
+
+#[tokio::test]
+async fn test_send_each_to_counts_packets_sent() -> Result<(), IceError> {
+    let target = SocketAddr::from(([127, 0, 0, 1], 5000));
+    let bufs: Vec<&[u8]> = vec![b"a", b"bb", b"ccc"];
+
+    let conn = FailingConn {
+        accepted: AtomicUsize::new(2),
+    };
+    assert_eq!(
+        send_each_to(&conn, &bufs, target).await?,
+        2,
+        "only the packets sent should be counted"
+    );
+    assert!(
+        send_each_to(&conn, &bufs, target).await.is_err(),
+        "should fail when no packet is sent"
+    );
+
+    Ok(())
+}
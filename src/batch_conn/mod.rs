@@ -0,0 +1,212 @@
+#[cfg(test)]
+mod batch_conn_test;
+
+use crate::socket_factory::set_tos;
+use util::Conn;
+
+use async_trait::async_trait;
+use socket2::SockRef;
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+
+/// A connection which can send several packets to the same address in a single call, e.g.
+/// a media stack sending the packets of a whole frame at once.
+#[async_trait]
+pub trait BatchConn: Conn {
+    /// Sends every buffer of `bufs` as a separate packet to `target`, in order, and returns the
+    /// number of packets sent. It is less than the number of buffers when sending failed after
+    /// the first packets were sent, the error is only returned when none was.
+    async fn send_batch_to(&self, bufs: &[&[u8]], target: SocketAddr) -> io::Result<usize>;
+
+    /// Sets the type of service, or IPv6 traffic class, of the packets sent from now on, e.g.
+    /// to mark them with a DSCP.
+    fn set_tos(&self, tos: u8) -> io::Result<()>;
+}
+
+/// A UDP socket of the host, which sends batches with a single `sendmmsg` syscall on Linux and
+/// Android, and with a `send_to` per packet elsewhere.
+pub(crate) struct BatchUdpConn {
+    socket: UdpSocket,
+}
+
+impl BatchUdpConn {
+    pub(crate) const fn new(socket: UdpSocket) -> Self {
+        Self { socket }
+    }
+}
+
+#[async_trait]
+impl Conn for BatchUdpConn {
+    async fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+        self.socket.connect(addr).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.socket.recv(buf).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.socket.recv_from(buf).await
+    }
+
+    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.send(buf).await
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        self.socket.send_to(buf, target).await
+    }
+
+    async fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+#[async_trait]
+impl BatchConn for BatchUdpConn {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    async fn send_batch_to(&self, bufs: &[&[u8]], target: SocketAddr) -> io::Result<usize> {
+        // The kernel may send only the first packets, the next call sends the others
+        let mut sent = 0;
+        while sent < bufs.len() {
+            let result = self
+                .socket
+                .async_io(tokio::io::Interest::WRITABLE, || {
+                    mmsg::send_mmsg(&self.socket, &bufs[sent..], target)
+                })
+                .await;
+            match result {
+                Ok(n) => sent += n,
+                Err(err) if sent == 0 => return Err(err),
+                Err(_) => break,
+            }
+        }
+
+        Ok(sent)
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    async fn send_batch_to(&self, bufs: &[&[u8]], target: SocketAddr) -> io::Result<usize> {
+        send_each_to(self, bufs, target).await
+    }
+
+    fn set_tos(&self, tos: u8) -> io::Result<()> {
+        let ipv4 = self.socket.local_addr()?.is_ipv4();
+        set_tos(&SockRef::from(&self.socket), ipv4, tos)
+    }
+}
+
+/// Sends the packets of `bufs` one by one, for connections which can't send them at once, and
+/// returns the number of packets sent like `BatchConn::send_batch_to`.
+pub(crate) async fn send_each_to(
+    conn: &(dyn Conn + Send + Sync),
+    bufs: &[&[u8]],
+    target: SocketAddr,
+) -> io::Result<usize> {
+    for (sent, buf) in bufs.iter().enumerate() {
+        match conn.send_to(buf, target).await {
+            Ok(_) => {}
+            Err(err) if sent == 0 => return Err(err),
+            Err(_) => return Ok(sent),
+        }
+    }
+
+    Ok(bufs.len())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) mod mmsg {
+    use super::*;
+
+    use std::os::unix::io::AsRawFd;
+
+    /// Converts `addr` to the socket address passed to the syscalls.
+    #[allow(clippy::cast_possible_truncation)]
+    pub(crate) fn to_sockaddr(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        let len = match addr {
+            SocketAddr::V4(addr) => {
+                let sin = libc::sockaddr_in {
+                    sin_family: libc::AF_INET as libc::sa_family_t,
+                    sin_port: addr.port().to_be(),
+                    sin_addr: libc::in_addr {
+                        s_addr: u32::from_ne_bytes(addr.ip().octets()),
+                    },
+                    sin_zero: [0; 8],
+                };
+                unsafe {
+                    std::ptr::write(
+                        (&mut storage as *mut libc::sockaddr_storage).cast::<libc::sockaddr_in>(),
+                        sin,
+                    );
+                }
+                std::mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(addr) => {
+                let sin6 = libc::sockaddr_in6 {
+                    sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                    sin6_port: addr.port().to_be(),
+                    sin6_flowinfo: addr.flowinfo(),
+                    sin6_addr: libc::in6_addr {
+                        s6_addr: addr.ip().octets(),
+                    },
+                    sin6_scope_id: addr.scope_id(),
+                };
+                unsafe {
+                    std::ptr::write(
+                        (&mut storage as *mut libc::sockaddr_storage).cast::<libc::sockaddr_in6>(),
+                        sin6,
+                    );
+                }
+                std::mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+
+        (storage, len as libc::socklen_t)
+    }
+
+    /// Sends as many buffers as the kernel accepts in one `sendmmsg` call, and returns how many
+    /// were sent. Fails with `WouldBlock` when none could be sent.
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    pub(super) fn send_mmsg(
+        socket: &UdpSocket,
+        bufs: &[&[u8]],
+        target: SocketAddr,
+    ) -> io::Result<usize> {
+        let (mut addr, addr_len) = to_sockaddr(target);
+        let mut iovecs: Vec<libc::iovec> = bufs
+            .iter()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            })
+            .collect();
+        let mut msgs: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .map(|iovec| {
+                let mut msg: libc::mmsghdr = unsafe { std::mem::zeroed() };
+                msg.msg_hdr.msg_name =
+                    (&mut addr as *mut libc::sockaddr_storage).cast::<libc::c_void>();
+                msg.msg_hdr.msg_namelen = addr_len;
+                msg.msg_hdr.msg_iov = iovec;
+                msg.msg_hdr.msg_iovlen = 1;
+                msg
+            })
+            .collect();
+
+        let n = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                msgs.len() as libc::c_uint,
+                0,
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(n as usize)
+    }
+}
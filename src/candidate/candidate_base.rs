@@ -1,17 +1,25 @@
+use super::candidate_host::CandidateHostConfig;
+use super::candidate_peer_reflexive::CandidatePeerReflexiveConfig;
+use super::candidate_relay::CandidateRelayConfig;
+use super::candidate_server_reflexive::CandidateServerReflexiveConfig;
 use super::*;
+use crate::agent::agent_loop::InboundPacket;
+use crate::batch_conn::{send_each_to, BatchConn};
 use crate::errors::*;
+use crate::turn_allocation::AllocationConn;
 use crate::util::*;
 
 use stun::message::*;
 
 use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
 use crc::{Crc, CRC_32_ISCSI};
 use std::fmt;
 use std::ops::Add;
-use std::sync::atomic::{AtomicU16, AtomicU64, AtomicU8, Ordering};
+use std::sync::atomic::{AtomicU16, AtomicU32, AtomicU64, AtomicU8, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use tokio::sync::{broadcast, Mutex};
+use tokio::sync::{broadcast, mpsc, Mutex};
 
 #[derive(Default)]
 pub struct CandidateBaseConfig {
@@ -20,13 +28,29 @@ pub struct CandidateBaseConfig {
     pub address: String,
     pub port: u16,
     pub component: u16,
+    /// The index of the data stream of the candidate, see `AgentConfig::streams`.
+    pub stream: u16,
     pub priority: u32,
     pub foundation: String,
+    /// The id of the network interface of the candidate, see `Candidate::network_id`.
+    pub network_id: u16,
+    /// The cost of the network interface of the candidate, see `Candidate::network_cost`.
+    pub network_cost: u16,
+    /// How many addresses are preferred to the one of the candidate, lowering its local
+    /// preference by as much, see `AgentConfig::interfaces`.
+    pub address_rank: u16,
+    /// The local preference of the interface of the candidate, see
+    /// `AgentConfig::interface_preferences`. The highest one when it is not set.
+    pub interface_preference: Option<u16>,
+    /// The extension attributes of the candidate, in the order they are marshaled.
+    pub extensions: Vec<CandidateExtension>,
     pub conn: Option<Arc<dyn util::Conn + Send + Sync>>,
+    /// The same connection as `conn`, when it can send batches of packets.
+    pub batch_conn: Option<Arc<dyn BatchConn + Send + Sync>>,
     pub initialized_ch: Option<broadcast::Receiver<()>>,
 }
 
-pub(crate) type OnClose = fn() -> Result<(), Error>;
+pub(crate) type OnClose = fn() -> Result<(), IceError>;
 
 pub struct CandidateBase {
     pub(crate) id: String,
@@ -34,27 +58,44 @@ pub struct CandidateBase {
     pub(crate) candidate_type: CandidateType,
 
     pub(crate) component: AtomicU16,
+    pub(crate) stream: AtomicU16,
     pub(crate) address: String,
     pub(crate) port: u16,
     pub(crate) related_address: Option<CandidateRelatedAddress>,
     pub(crate) tcp_type: TcpType,
+    pub(crate) network_id: u16,
+    pub(crate) network_cost: u16,
+    pub(crate) address_rank: u16,
+    pub(crate) interface_preference: u16,
+    pub(crate) extensions: Vec<CandidateExtension>,
 
     pub(crate) resolved_addr: Mutex<SocketAddr>,
 
     pub(crate) last_sent: AtomicU64,
     pub(crate) last_received: AtomicU64,
 
+    pub(crate) bytes_sent: AtomicU64,
+    pub(crate) bytes_received: AtomicU64,
+    pub(crate) stun_requests_sent: AtomicU64,
+    pub(crate) stun_responses_received: AtomicU64,
+
     pub(crate) conn: Option<Arc<dyn util::Conn + Send + Sync>>,
+    pub(crate) batch_conn: Option<Arc<dyn BatchConn + Send + Sync>>,
     pub(crate) agent_internal: Option<Arc<Mutex<AgentInternal>>>,
     pub(crate) closed_ch: Arc<Mutex<Option<broadcast::Sender<()>>>>,
 
     pub(crate) foundation_override: String,
     pub(crate) priority_override: u32,
+    // The priority given by the priority function of the agent, 0 when there is none
+    pub(crate) agent_priority: AtomicU32,
 
     //CandidateHost
     pub(crate) network: String,
     //CandidateRelay
+    pub(crate) relay_protocol: String,
     pub(crate) relay_client: Option<Arc<turn::client::Client>>,
+    // The transport of the TURN client, which manages the lifetime of its allocation
+    pub(crate) relay_allocation: Option<Arc<AllocationConn>>,
 }
 
 impl Default for CandidateBase {
@@ -65,24 +106,39 @@ impl Default for CandidateBase {
             candidate_type: CandidateType::default(),
 
             component: AtomicU16::new(0),
+            stream: AtomicU16::new(0),
             address: String::new(),
             port: 0,
             related_address: None,
             tcp_type: TcpType::default(),
+            network_id: 0,
+            network_cost: NETWORK_COST_MIN,
+            address_rank: 0,
+            interface_preference: DEFAULT_LOCAL_PREFERENCE,
+            extensions: vec![],
 
             resolved_addr: Mutex::new(SocketAddr::new(IpAddr::from([0, 0, 0, 0]), 0)),
 
             last_sent: AtomicU64::new(0),
             last_received: AtomicU64::new(0),
 
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            stun_requests_sent: AtomicU64::new(0),
+            stun_responses_received: AtomicU64::new(0),
+
             conn: None,
+            batch_conn: None,
             agent_internal: None,
             closed_ch: Arc::new(Mutex::new(None)),
 
             foundation_override: String::new(),
             priority_override: 0,
+            agent_priority: AtomicU32::new(0),
             network: String::new(),
+            relay_protocol: String::new(),
             relay_client: None,
+            relay_allocation: None,
         }
     }
 }
@@ -144,6 +200,15 @@ impl Candidate for CandidateBase {
         self.component.store(component, Ordering::SeqCst);
     }
 
+    /// Returns the index of the data stream of the candidate.
+    fn stream(&self) -> u16 {
+        self.stream.load(Ordering::SeqCst)
+    }
+
+    fn set_stream(&self, stream: u16) {
+        self.stream.store(stream, Ordering::SeqCst);
+    }
+
     /// Returns a time indicating the last time this candidate was received.
     fn last_received(&self) -> SystemTime {
         UNIX_EPOCH.add(Duration::from_nanos(
@@ -156,6 +221,22 @@ impl Candidate for CandidateBase {
         UNIX_EPOCH.add(Duration::from_nanos(self.last_sent.load(Ordering::SeqCst)))
     }
 
+    fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::SeqCst)
+    }
+
+    fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::SeqCst)
+    }
+
+    fn stun_requests_sent(&self) -> u64 {
+        self.stun_requests_sent.load(Ordering::SeqCst)
+    }
+
+    fn stun_responses_received(&self) -> u64 {
+        self.stun_responses_received.load(Ordering::SeqCst)
+    }
+
     /// Returns candidate NetworkType.
     fn network_type(&self) -> NetworkType {
         NetworkType::from(self.network_type.load(Ordering::SeqCst))
@@ -176,6 +257,10 @@ impl Candidate for CandidateBase {
         if self.priority_override != 0 {
             return self.priority_override;
         }
+        let agent_priority = self.agent_priority.load(Ordering::SeqCst);
+        if agent_priority != 0 {
+            return agent_priority;
+        }
 
         // The local preference MUST be an integer from 0 (lowest preference) to
         // 65535 (highest preference) inclusive.  When there is only a single IP
@@ -188,6 +273,10 @@ impl Candidate for CandidateBase {
             + (256 - u32::from(self.component()))
     }
 
+    fn set_priority(&self, priority: u32) {
+        self.agent_priority.store(priority, Ordering::SeqCst);
+    }
+
     /// Returns `Option<CandidateRelatedAddress>`.
     fn related_address(&self) -> Option<CandidateRelatedAddress> {
         self.related_address.as_ref().cloned()
@@ -202,6 +291,36 @@ impl Candidate for CandidateBase {
         self.tcp_type
     }
 
+    /// Returns the transport used to reach the TURN server of a relay candidate.
+    fn relay_protocol(&self) -> String {
+        self.relay_protocol.clone()
+    }
+
+    fn relay_lifetime(&self) -> Option<Duration> {
+        self.relay_allocation
+            .as_ref()
+            .and_then(|relay_allocation| relay_allocation.expires_in())
+    }
+
+    fn network_id(&self) -> u16 {
+        self.network_id
+    }
+
+    fn network_cost(&self) -> u16 {
+        self.network_cost
+    }
+
+    fn extensions(&self) -> Vec<CandidateExtension> {
+        self.extensions.clone()
+    }
+
+    fn get_extension(&self, key: &str) -> Option<String> {
+        self.extensions
+            .iter()
+            .find(|ext| ext.key == key)
+            .map(|ext| ext.value.clone())
+    }
+
     /// Returns the string representation of the ICECandidate.
     fn marshal(&self) -> String {
         let mut val = format!(
@@ -227,6 +346,18 @@ impl Candidate for CandidateBase {
             .as_str();
         }
 
+        for ext in &self.extensions {
+            val += format!(" {} {}", ext.key, ext.value).as_str();
+        }
+
+        if self.network_id != 0 {
+            val += format!(" {} {}", EXTENSION_NETWORK_ID, self.network_id).as_str();
+        }
+
+        if self.network_cost != NETWORK_COST_MIN {
+            val += format!(" {} {}", EXTENSION_NETWORK_COST, self.network_cost).as_str();
+        }
+
         val
     }
 
@@ -236,17 +367,20 @@ impl Candidate for CandidateBase {
     }
 
     /// Stops the recvLoop.
-    async fn close(&self) -> Result<(), Error> {
+    async fn close(&self) -> Result<(), IceError> {
         {
             let mut closed_ch = self.closed_ch.lock().await;
             if closed_ch.is_none() {
-                return Err(ERR_CLOSED.to_owned());
+                return Err(IceError::Closed);
             }
             closed_ch.take();
         }
 
+        if let Some(relay_allocation) = &self.relay_allocation {
+            relay_allocation.close().await;
+        }
         if let Some(relay_client) = &self.relay_client {
-            relay_client.close().await
+            relay_client.close().await.map_err(IceError::Turn)
         } else {
             Ok(())
         }
@@ -269,7 +403,7 @@ impl Candidate for CandidateBase {
         &self,
         raw: &[u8],
         dst: &(dyn Candidate + Send + Sync),
-    ) -> Result<usize, Error> {
+    ) -> Result<usize, IceError> {
         let n = if let Some(conn) = &self.conn {
             let addr = dst.addr().await;
             conn.send_to(raw, addr).await?
@@ -277,9 +411,47 @@ impl Candidate for CandidateBase {
             0
         };
         self.seen(true);
+
+        self.bytes_sent.fetch_add(n as u64, Ordering::SeqCst);
+        if stun_message_class(raw) == Some(CLASS_REQUEST) {
+            self.stun_requests_sent.fetch_add(1, Ordering::SeqCst);
+        }
+
         Ok(n)
     }
 
+    async fn write_batch_to(
+        &self,
+        bufs: &[&[u8]],
+        dst: &(dyn Candidate + Send + Sync),
+    ) -> Result<usize, IceError> {
+        let addr = dst.addr().await;
+        let sent = if let Some(batch_conn) = &self.batch_conn {
+            batch_conn.send_batch_to(bufs, addr).await?
+        } else if let Some(conn) = &self.conn {
+            send_each_to(&**conn, bufs, addr).await?
+        } else {
+            0
+        };
+        self.seen(true);
+
+        let n: usize = bufs[..sent].iter().map(|buf| buf.len()).sum();
+        self.bytes_sent.fetch_add(n as u64, Ordering::SeqCst);
+
+        Ok(sent)
+    }
+
+    fn received(&self, raw: &[u8]) {
+        self.bytes_received
+            .fetch_add(raw.len() as u64, Ordering::SeqCst);
+        if matches!(
+            stun_message_class(raw),
+            Some(CLASS_SUCCESS_RESPONSE | CLASS_ERROR_RESPONSE)
+        ) {
+            self.stun_responses_received.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
     /// Used to compare two candidateBases.
     fn equal(&self, other: &dyn Candidate) -> bool {
         self.network_type() == other.network_type()
@@ -290,7 +462,7 @@ impl Candidate for CandidateBase {
             && self.related_address() == other.related_address()
     }
 
-    async fn set_ip(&self, ip: &IpAddr) -> Result<(), Error> {
+    async fn set_ip(&self, ip: &IpAddr) -> Result<(), IceError> {
         let network_type = determine_network_type(&self.network, ip)?;
 
         self.network_type
@@ -302,6 +474,13 @@ impl Candidate for CandidateBase {
         Ok(())
     }
 
+    fn set_tos(&self, tos: u8) -> Result<(), IceError> {
+        match &self.batch_conn {
+            Some(batch_conn) => Ok(batch_conn.set_tos(tos)?),
+            None => Err(IceError::TosUnsupported),
+        }
+    }
+
     fn get_conn(&self) -> Option<&Arc<dyn util::Conn + Send + Sync>> {
         self.conn.as_ref()
     }
@@ -365,7 +544,10 @@ impl CandidateBase {
             // other-pref is the preference for the particular IP address from which
             // the candidate was obtained.  When there is only a single IP address,
             // this value SHOULD be set to the maximum allowed value (8191).
-            let other_pref: u16 = 8191;
+            //
+            // The preference of the interface is scaled down to the 13 bits of other-pref.
+            let other_pref: u16 =
+                (self.interface_preference >> 3).saturating_sub(self.address_rank);
 
             let direction_pref: u16 = match self.candidate_type() {
                 CandidateType::Host | CandidateType::Relay => match self.tcp_type() {
@@ -387,92 +569,255 @@ impl CandidateBase {
 
             (1 << 13) * direction_pref + other_pref
         } else {
-            DEFAULT_LOCAL_PREFERENCE
+            // The preferred addresses come first, see RFC 8445 section 5.1.2.1
+            self.interface_preference.saturating_sub(self.address_rank)
         }
     }
 
     pub(crate) async fn recv_loop(
+        agent_id: String,
         candidate: Arc<dyn Candidate + Send + Sync>,
-        agent_internal: Arc<Mutex<AgentInternal>>,
+        inbound_tx: mpsc::Sender<InboundPacket>,
         mut closed_ch_rx: broadcast::Receiver<()>,
         initialized_ch: Option<broadcast::Receiver<()>>,
         conn: Arc<dyn util::Conn + Send + Sync>,
-        addr: SocketAddr,
-    ) -> Result<(), Error> {
+    ) -> Result<(), IceError> {
         if let Some(mut initialized_ch) = initialized_ch {
             tokio::select! {
                 _ = initialized_ch.recv() => {}
-                _ = closed_ch_rx.recv() => return Err(ERR_CLOSED.to_owned()),
+                _ = closed_ch_rx.recv() => return Err(IceError::Closed),
             }
         }
 
-        let mut buffer = vec![0_u8; RECEIVE_MTU];
-        let mut n;
-        let mut src_addr;
+        // Packets are split off a single buffer and handed over to the inbound loop without being
+        // copied. The application data is only copied once it is read from the `AgentConn`, and
+        // the STUN messages once they are decoded. Once the packets have been dropped, the memory
+        // of the buffer is reclaimed instead of allocating a new one.
+        let mut buffer = BytesMut::with_capacity(RECEIVE_BUFFER_PACKETS * RECEIVE_MTU);
         loop {
-            tokio::select! {
-               result = conn.recv_from(&mut buffer) => {
-                   match result {
-                       Ok((num, src)) => {
-                            n = num;
-                            src_addr = src;
-                       }
-                       Err(err) => return Err(Error::new(err.to_string())),
-                   }
-               },
-                _  = closed_ch_rx.recv() => return Err(ERR_CLOSED.to_owned()),
-            }
+            buffer.reserve(RECEIVE_MTU);
+            buffer.resize(RECEIVE_MTU, 0);
 
-            Self::handle_inbound_candidate_msg(
-                &candidate,
-                &agent_internal,
-                &buffer[..n],
-                src_addr,
-                addr,
-            )
-            .await;
+            let result = tokio::select! {
+                result = conn.recv_from(&mut buffer) => result,
+                _  = closed_ch_rx.recv() => return Err(IceError::Closed),
+            };
+            let (n, src_addr) = match result {
+                Ok(received) => received,
+                Err(err) => {
+                    // The reads of a closed candidate are expected to fail
+                    if !matches!(
+                        closed_ch_rx.try_recv(),
+                        Err(broadcast::error::TryRecvError::Closed)
+                    ) {
+                        warn_event!(
+                            agent = agent_id,
+                            candidate = candidate,
+                            error = err;
+                            "Failed to read from the candidate"
+                        );
+                    }
+                    return Err(err.into());
+                }
+            };
+            buffer.truncate(n);
+            let packet = buffer.split().freeze();
+
+            let inbound = Self::handle_inbound_candidate_msg(&candidate, packet, src_addr);
+            // The inbound loop only stops once the agent is closed
+            if inbound_tx.send(inbound).await.is_err() {
+                return Err(IceError::Closed);
+            }
         }
     }
 
-    async fn handle_inbound_candidate_msg(
+    /// Turns a packet received by the candidate into an inbound packet of the agent.
+    fn handle_inbound_candidate_msg(
         c: &Arc<dyn Candidate + Send + Sync>,
-        agent_internal: &Arc<Mutex<AgentInternal>>,
-        buf: &[u8],
+        packet: Bytes,
         src_addr: SocketAddr,
-        addr: SocketAddr,
-    ) {
-        if stun::message::is_message(buf) {
-            let mut m = Message {
-                raw: vec![],
-                ..Message::default()
-            };
-            // Explicitly copy raw buffer so Message can own the memory.
-            m.raw.extend_from_slice(buf);
-
-            if let Err(err) = m.decode() {
-                log::warn!(
-                    "Failed to handle decode ICE from {} to {}: {}",
-                    addr,
-                    src_addr,
-                    err
-                );
-            } else {
-                let agent_internal_clone = Arc::clone(agent_internal);
-                let mut ai = agent_internal.lock().await;
-                ai.handle_inbound(&mut m, c, src_addr, agent_internal_clone)
-                    .await;
+    ) -> InboundPacket {
+        c.received(&packet);
+
+        if stun::message::is_message(&packet) {
+            InboundPacket::Stun {
+                local: Arc::clone(c),
+                remote: src_addr,
+                raw: packet,
             }
         } else {
-            let ai = agent_internal.lock().await;
-            if !ai.validate_non_stun_traffic(c, src_addr).await {
-                log::warn!(
-                    "Discarded message from {}, not a valid remote candidate",
-                    c.addr().await
-                );
-            } else if let Err(err) = ai.agent_conn.buffer.write(buf).await {
-                // NOTE This will return packetio.ErrFull if the buffer ever manages to fill up.
-                log::warn!("failed to write packet: {}", err);
+            InboundPacket::Data {
+                local: Arc::clone(c),
+                remote: src_addr,
+                data: packet,
+            }
+        }
+    }
+}
+
+/// Returns the class of the STUN message in `raw`, or None if it is not a STUN message.
+fn stun_message_class(raw: &[u8]) -> Option<MessageClass> {
+    if !is_message(raw) {
+        return None;
+    }
+
+    let mut typ = MessageType::default();
+    typ.read_value(u16::from_be_bytes([raw[0], raw[1]]));
+    Some(typ.class)
+}
+
+/// Creates a candidate from its string representation, i.e. the value of an `a=candidate` SDP
+/// attribute as defined in RFC 8839 Section 5.1. The `a=` and `candidate:` prefixes are optional.
+/// Extension attributes other than `raddr`, `rport` and `tcptype` are kept in the order they
+/// appear, see `Candidate::extensions`, and the well-known ones are validated.
+pub async fn unmarshal_candidate(raw: &str) -> Result<CandidateBase, IceError> {
+    unmarshal_candidate_with_agent(raw, None).await
+}
+
+pub(crate) async fn unmarshal_candidate_with_agent(
+    raw: &str,
+    agent_internal: Option<Arc<Mutex<AgentInternal>>>,
+) -> Result<CandidateBase, IceError> {
+    let raw = raw.trim();
+    let raw = raw.strip_prefix("a=").unwrap_or(raw);
+    let raw = raw.strip_prefix("candidate:").unwrap_or(raw);
+
+    let split: Vec<&str> = raw.split_whitespace().collect();
+    if split.len() < 8 {
+        return Err(IceError::AttributeTooShortIceCandidate(split.len()));
+    }
+
+    // Foundation
+    let foundation = split[0].to_owned();
+
+    // Component
+    let component: u16 = split[1]
+        .parse()
+        .map_err(|err| IceError::ParseComponent(format!("{}: {}", split[1], err)))?;
+
+    // Network
+    let network = split[2].to_lowercase();
+
+    // Priority
+    let priority: u32 = split[3]
+        .parse()
+        .map_err(|err| IceError::ParsePriority(format!("{}: {}", split[3], err)))?;
+
+    // Address
+    let address = split[4].to_owned();
+
+    // Port
+    let port: u16 = split[5]
+        .parse()
+        .map_err(|err| IceError::ParsePort(format!("{}: {}", split[5], err)))?;
+
+    if split[6] != "typ" {
+        return Err(IceError::ParseType(format!(
+            "expected \"typ\", got \"{}\"",
+            split[6]
+        )));
+    }
+    let typ = split[7];
+
+    let mut rel_addr = String::new();
+    let mut rel_port = 0;
+    let mut tcp_type = TcpType::Unspecified;
+    let mut network_id = 0;
+    let mut network_cost = NETWORK_COST_MIN;
+    let mut extensions = vec![];
+
+    // Extension attributes come in name/value pairs.
+    let ext_pairs = &split[8..];
+    for (i, name) in ext_pairs.iter().enumerate().step_by(2) {
+        let value = ext_pairs
+            .get(i + 1)
+            .ok_or_else(|| IceError::ParseExtension(format!("{}: missing value", name)))?;
+
+        match *name {
+            "raddr" => rel_addr = (*value).to_owned(),
+            "rport" => {
+                rel_port = value
+                    .parse()
+                    .map_err(|err| IceError::ParseRelatedAddr(format!("{}: {}", value, err)))?;
+            }
+            "tcptype" => {
+                tcp_type = TcpType::from(*value);
+                if tcp_type == TcpType::Unspecified {
+                    return Err(IceError::ParseType(format!("unknown tcptype {}", value)));
+                }
             }
+            EXTENSION_NETWORK_ID => network_id = parse_extension(name, value)?,
+            EXTENSION_NETWORK_COST => network_cost = parse_extension(name, value)?,
+            _ => {
+                validate_extension(name, value)?;
+                extensions.push(CandidateExtension {
+                    key: (*name).to_owned(),
+                    value: (*value).to_owned(),
+                });
+            }
+        }
+    }
+
+    let base_config = CandidateBaseConfig {
+        network,
+        address,
+        port,
+        component,
+        priority,
+        foundation,
+        network_id,
+        network_cost,
+        extensions,
+        ..CandidateBaseConfig::default()
+    };
+
+    match typ {
+        "host" => {
+            let config = CandidateHostConfig {
+                base_config,
+                tcp_type,
+            };
+            config.new_candidate_host(agent_internal).await
+        }
+        "srflx" => {
+            let config = CandidateServerReflexiveConfig {
+                base_config,
+                rel_addr,
+                rel_port,
+            };
+            config.new_candidate_server_reflexive(agent_internal).await
         }
+        "prflx" => {
+            let config = CandidatePeerReflexiveConfig {
+                base_config,
+                rel_addr,
+                rel_port,
+            };
+            config.new_candidate_peer_reflexive(agent_internal).await
+        }
+        "relay" => {
+            let config = CandidateRelayConfig {
+                base_config,
+                rel_addr,
+                rel_port,
+                ..CandidateRelayConfig::default()
+            };
+            config.new_candidate_relay(agent_internal).await
+        }
+        _ => Err(IceError::UnknownCandidateType(typ.to_owned())),
+    }
+}
+
+/// Checks the value of the well-known extensions browsers emit, the others are kept as is.
+fn validate_extension(key: &str, value: &str) -> Result<(), IceError> {
+    if key == EXTENSION_GENERATION {
+        parse_extension::<u32>(key, value)?;
     }
+    Ok(())
+}
+
+fn parse_extension<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, IceError> {
+    value
+        .parse()
+        .map_err(|_| IceError::ParseExtension(format!("{}: {}", key, value)))
 }
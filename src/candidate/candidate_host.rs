@@ -17,7 +17,7 @@ impl CandidateHostConfig {
     pub async fn new_candidate_host(
         self,
         agent_internal: Option<Arc<Mutex<AgentInternal>>>,
-    ) -> Result<CandidateBase, Error> {
+    ) -> Result<CandidateBase, IceError> {
         let mut candidate_id = self.base_config.candidate_id;
         if candidate_id.is_empty() {
             candidate_id = generate_cand_id();
@@ -28,6 +28,15 @@ impl CandidateHostConfig {
             address: self.base_config.address.clone(),
             candidate_type: CandidateType::Host,
             component: AtomicU16::new(self.base_config.component),
+            stream: AtomicU16::new(self.base_config.stream),
+            network_id: self.base_config.network_id,
+            network_cost: self.base_config.network_cost,
+            address_rank: self.base_config.address_rank,
+            interface_preference: self
+                .base_config
+                .interface_preference
+                .unwrap_or(DEFAULT_LOCAL_PREFERENCE),
+            extensions: self.base_config.extensions,
             port: self.base_config.port,
             tcp_type: self.tcp_type,
             foundation_override: self.base_config.foundation,
@@ -35,6 +44,7 @@ impl CandidateHostConfig {
             network: self.base_config.network,
             network_type: AtomicU8::new(NetworkType::Udp4 as u8),
             conn: self.base_config.conn,
+            batch_conn: self.base_config.batch_conn,
             agent_internal,
             ..CandidateBase::default()
         };
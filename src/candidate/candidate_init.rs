@@ -0,0 +1,100 @@
+use super::*;
+
+/// A plain-data description of a candidate, suitable for sending over a signaling channel.
+/// Enabling the `serde` feature makes it (de)serializable.
+#[derive(PartialEq, Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CandidateInit {
+    pub foundation: String,
+    pub component: u16,
+    pub network_type: NetworkType,
+    pub priority: u32,
+    pub address: String,
+    pub port: u16,
+    pub candidate_type: CandidateType,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub tcp_type: TcpType,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub related_address: Option<CandidateRelatedAddress>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub network_id: u16,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub network_cost: u16,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub extensions: Vec<CandidateExtension>,
+    /// The data stream of the candidate, see `Candidate::stream`. It is not part of the
+    /// marshaled candidate.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub stream: u16,
+}
+
+impl CandidateInit {
+    /// Returns the string representation of the candidate, in the same format as
+    /// `Candidate::marshal`.
+    #[must_use]
+    pub fn marshal(&self) -> String {
+        let mut val = format!(
+            "{} {} {} {} {} {} typ {}",
+            self.foundation,
+            self.component,
+            self.network_type.network_short(),
+            self.priority,
+            self.address,
+            self.port,
+            self.candidate_type
+        );
+
+        if self.tcp_type != TcpType::Unspecified {
+            val += format!(" tcptype {}", self.tcp_type).as_str();
+        }
+
+        if let Some(related_address) = &self.related_address {
+            val += format!(
+                " raddr {} rport {}",
+                related_address.address, related_address.port,
+            )
+            .as_str();
+        }
+
+        for ext in &self.extensions {
+            val += format!(" {} {}", ext.key, ext.value).as_str();
+        }
+
+        if self.network_id != 0 {
+            val += format!(" {} {}", EXTENSION_NETWORK_ID, self.network_id).as_str();
+        }
+
+        if self.network_cost != NETWORK_COST_MIN {
+            val += format!(" {} {}", EXTENSION_NETWORK_COST, self.network_cost).as_str();
+        }
+
+        val
+    }
+
+    /// Creates a candidate that is not bound to any agent from this description.
+    pub async fn to_candidate(&self) -> Result<CandidateBase, IceError> {
+        let c = unmarshal_candidate(&self.marshal()).await?;
+        c.set_stream(self.stream);
+        Ok(c)
+    }
+}
+
+impl<C: Candidate + ?Sized> From<&C> for CandidateInit {
+    fn from(c: &C) -> Self {
+        Self {
+            foundation: c.foundation(),
+            component: c.component(),
+            network_type: c.network_type(),
+            priority: c.priority(),
+            address: c.address(),
+            port: c.port(),
+            candidate_type: c.candidate_type(),
+            tcp_type: c.tcp_type(),
+            related_address: c.related_address(),
+            network_id: c.network_id(),
+            network_cost: c.network_cost(),
+            extensions: c.extensions(),
+            stream: c.stream(),
+        }
+    }
+}
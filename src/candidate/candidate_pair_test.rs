@@ -4,9 +4,8 @@ use crate::candidate::candidate_host::CandidateHostConfig;
 use crate::candidate::candidate_peer_reflexive::CandidatePeerReflexiveConfig;
 use crate::candidate::candidate_relay::CandidateRelayConfig;
 use crate::candidate::candidate_server_reflexive::CandidateServerReflexiveConfig;
-use util::Error;
 
-pub(crate) async fn host_candidate() -> Result<CandidateBase, Error> {
+pub(crate) async fn host_candidate() -> Result<CandidateBase, IceError> {
     CandidateHostConfig {
         base_config: CandidateBaseConfig {
             network: "udp".to_owned(),
@@ -20,7 +19,7 @@ pub(crate) async fn host_candidate() -> Result<CandidateBase, Error> {
     .await
 }
 
-pub(crate) async fn prflx_candidate() -> Result<CandidateBase, Error> {
+pub(crate) async fn prflx_candidate() -> Result<CandidateBase, IceError> {
     CandidatePeerReflexiveConfig {
         base_config: CandidateBaseConfig {
             network: "udp".to_owned(),
@@ -34,7 +33,7 @@ pub(crate) async fn prflx_candidate() -> Result<CandidateBase, Error> {
     .await
 }
 
-pub(crate) async fn srflx_candidate() -> Result<CandidateBase, Error> {
+pub(crate) async fn srflx_candidate() -> Result<CandidateBase, IceError> {
     CandidateServerReflexiveConfig {
         base_config: CandidateBaseConfig {
             network: "udp".to_owned(),
@@ -48,7 +47,7 @@ pub(crate) async fn srflx_candidate() -> Result<CandidateBase, Error> {
     .await
 }
 
-pub(crate) async fn relay_candidate() -> Result<CandidateBase, Error> {
+pub(crate) async fn relay_candidate() -> Result<CandidateBase, IceError> {
     CandidateRelayConfig {
         base_config: CandidateBaseConfig {
             network: "udp".to_owned(),
@@ -63,7 +62,7 @@ pub(crate) async fn relay_candidate() -> Result<CandidateBase, Error> {
 }
 
 #[tokio::test]
-async fn test_candidate_pair_priority() -> Result<(), Error> {
+async fn test_candidate_pair_priority() -> Result<(), IceError> {
     let tests = vec![
         (
             CandidatePair::new(
@@ -144,7 +143,7 @@ async fn test_candidate_pair_priority() -> Result<(), Error> {
 }
 
 #[tokio::test]
-async fn test_candidate_pair_equality() -> Result<(), Error> {
+async fn test_candidate_pair_equality() -> Result<(), IceError> {
     let pair_a = CandidatePair::new(
         Arc::new(host_candidate().await?),
         Arc::new(srflx_candidate().await?),
@@ -160,3 +159,144 @@ async fn test_candidate_pair_equality() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[test]
+fn test_candidate_pair_retransmission_due() {
+    let rto = Duration::from_secs(1);
+    let p = CandidatePair::default();
+    assert!(
+        p.is_retransmission_due(rto, 2),
+        "the first check is always due"
+    );
+
+    p.binding_request_count.store(1, Ordering::SeqCst);
+    p.on_request_sent();
+    assert!(!p.is_retransmission_due(rto, 2));
+    assert!(
+        p.is_retransmission_due(Duration::from_secs(0), 2),
+        "without RTO, checks are retransmitted on every tick"
+    );
+
+    set_timestamp(
+        &p.last_request_sent,
+        Instant::now() - Duration::from_secs(2),
+    );
+    assert!(p.is_retransmission_due(rto, 2));
+
+    // The RTO doubles on every retransmission, so the third one waits 4 seconds
+    p.binding_request_count.store(3, Ordering::SeqCst);
+    assert!(!p.is_retransmission_due(rto, 2));
+    assert!(
+        p.is_retransmission_due(rto, 1),
+        "without backoff, the RTO stays the same"
+    );
+}
+
+#[test]
+fn test_candidate_pair_keepalive_due() {
+    let interval = Duration::from_secs(1);
+    let p = CandidatePair::default();
+    assert!(
+        p.is_keepalive_due(interval),
+        "should be due before any check"
+    );
+
+    p.on_request_sent();
+    assert!(!p.is_keepalive_due(interval));
+
+    set_timestamp(
+        &p.last_request_sent,
+        Instant::now() - Duration::from_secs(2),
+    );
+    assert!(p.is_keepalive_due(interval));
+}
+
+#[test]
+fn test_candidate_pair_lost_checks_in_a_row() {
+    let p = CandidatePair::default();
+    assert_eq!(p.lost_checks_in_a_row(), 0);
+
+    p.on_request_lost();
+    p.on_response_received(Duration::from_millis(10));
+    assert_eq!(p.lost_checks_in_a_row(), 0, "a response ends the run");
+
+    p.on_request_lost();
+    p.on_request_lost();
+    assert_eq!(p.lost_checks_in_a_row(), 2);
+}
+
+#[test]
+fn test_candidate_pair_retransmission_timeout() {
+    let rto = Duration::from_millis(500);
+    let p = CandidatePair::default();
+    assert_eq!(
+        p.retransmission_timeout(rto),
+        rto,
+        "the initial RTO is used until the round trip time is measured"
+    );
+
+    // The first measurement sets RTTVAR to half of it, so the RTO is 3 times the round trip time
+    p.on_response_received(Duration::from_millis(800));
+    assert_eq!(p.retransmission_timeout(rto), Duration::from_millis(2400));
+
+    // A steady round trip time brings the RTO down towards it
+    for _ in 0..64 {
+        p.on_response_received(Duration::from_millis(800));
+    }
+    let derived = p.retransmission_timeout(rto);
+    assert!(derived >= Duration::from_millis(800) && derived < Duration::from_millis(810));
+
+    // Short links are bounded by the minimum RTO
+    let p = CandidatePair::default();
+    p.on_response_received(Duration::from_millis(1));
+    assert_eq!(p.retransmission_timeout(rto), MIN_RTO);
+}
+
+#[test]
+fn test_candidate_pair_smoothed_round_trip_time() {
+    let p = CandidatePair::default();
+
+    // The first measurement is taken as is
+    p.on_response_received(Duration::from_millis(80));
+    assert_eq!(
+        p.smoothed_round_trip_time.load(Ordering::SeqCst),
+        Duration::from_millis(80).as_nanos() as u64
+    );
+
+    // The next ones move it by an eighth of their difference
+    p.on_response_received(Duration::from_millis(160));
+    assert_eq!(
+        p.smoothed_round_trip_time.load(Ordering::SeqCst),
+        Duration::from_millis(90).as_nanos() as u64
+    );
+    assert_eq!(
+        p.current_round_trip_time.load(Ordering::SeqCst),
+        Duration::from_millis(160).as_nanos() as u64
+    );
+    assert_eq!(
+        p.total_round_trip_time.load(Ordering::SeqCst),
+        Duration::from_millis(240).as_nanos() as u64
+    );
+}
+
+#[test]
+fn test_candidate_pair_check_window() {
+    let p = CandidatePair::default();
+    assert_eq!(p.check_loss_ratio(), 0.0);
+    assert_eq!(p.round_trip_time_variance(), 0.0);
+
+    p.on_response_received(Duration::from_millis(100));
+    p.on_response_received(Duration::from_millis(300));
+    p.on_request_lost();
+    p.on_response_received(Duration::from_millis(200));
+    assert!((p.check_loss_ratio() - 0.25).abs() < 1e-9);
+    // The mean is 200ms, so the variance is (0.1² + 0.1² + 0) / 3
+    assert!((p.round_trip_time_variance() - 0.02 / 3.0).abs() < 1e-9);
+
+    // Only the latest checks are kept
+    for _ in 0..CHECK_WINDOW_SIZE {
+        p.on_response_received(Duration::from_millis(50));
+    }
+    assert_eq!(p.check_loss_ratio(), 0.0);
+    assert!(p.round_trip_time_variance().abs() < 1e-9);
+}
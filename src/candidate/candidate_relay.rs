@@ -13,6 +13,8 @@ pub struct CandidateRelayConfig {
 
     pub rel_addr: String,
     pub rel_port: u16,
+    /// The transport used to reach the TURN server, e.g. "udp" or "tcp".
+    pub relay_protocol: String,
     pub relay_client: Option<Arc<turn::client::Client>>,
 }
 
@@ -21,7 +23,7 @@ impl CandidateRelayConfig {
     pub async fn new_candidate_relay(
         self,
         agent_internal: Option<Arc<Mutex<AgentInternal>>>,
-    ) -> Result<CandidateBase, Error> {
+    ) -> Result<CandidateBase, IceError> {
         let mut candidate_id = self.base_config.candidate_id;
         if candidate_id.is_empty() {
             candidate_id = generate_cand_id();
@@ -29,7 +31,7 @@ impl CandidateRelayConfig {
 
         let ip: IpAddr = match self.base_config.address.parse() {
             Ok(ip) => ip,
-            Err(_) => return Err(ERR_ADDRESS_PARSE_FAILED.to_owned()),
+            Err(_) => return Err(IceError::AddressParseFailed),
         };
         let network_type = determine_network_type(&self.base_config.network, &ip)?;
 
@@ -41,6 +43,10 @@ impl CandidateRelayConfig {
             port: self.base_config.port,
             resolved_addr: Mutex::new(create_addr(network_type, ip, self.base_config.port)),
             component: AtomicU16::new(self.base_config.component),
+            stream: AtomicU16::new(self.base_config.stream),
+            network_id: self.base_config.network_id,
+            network_cost: self.base_config.network_cost,
+            extensions: self.base_config.extensions,
             foundation_override: self.base_config.foundation,
             priority_override: self.base_config.priority,
             related_address: Some(CandidateRelatedAddress {
@@ -48,7 +54,9 @@ impl CandidateRelayConfig {
                 port: self.rel_port,
             }),
             conn: self.base_config.conn,
+            batch_conn: self.base_config.batch_conn,
             agent_internal,
+            relay_protocol: self.relay_protocol,
             relay_client: self.relay_client.clone(),
             ..CandidateBase::default()
         };
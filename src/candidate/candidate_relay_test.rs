@@ -4,10 +4,12 @@ use crate::agent::agent_config::AgentConfig;
 use crate::agent::agent_vnet_test::{connect_with_vnet, on_connected};
 use crate::agent::Agent;
 use crate::url::{ProtoType, SchemeType, Url};
+use std::io;
+use std::sync::atomic::AtomicUsize;
 use std::time::Duration;
 use tokio::net::UdpSocket;
 use turn::auth::AuthHandler;
-use util::Error;
+use util::Conn;
 
 pub(crate) struct OptimisticAuthHandler;
 
@@ -17,7 +19,7 @@ impl AuthHandler for OptimisticAuthHandler {
         _username: &str,
         _realm: &str,
         _src_addr: SocketAddr,
-    ) -> Result<Vec<u8>, Error> {
+    ) -> Result<Vec<u8>, util::Error> {
         Ok(turn::auth::generate_auth_key(
             "username",
             "webrtc.rs",
@@ -26,10 +28,48 @@ impl AuthHandler for OptimisticAuthHandler {
     }
 }
 
+/// The socket of a TURN server, counting the ChannelData messages it receives, whose first two
+/// bits are 01, see RFC 8656 section 12.
+struct ChannelDataCounter {
+    conn: UdpSocket,
+    channel_data: Arc<AtomicUsize>,
+}
+
+#[async_trait]
+impl Conn for ChannelDataCounter {
+    async fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+        self.conn.connect(addr).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.conn.recv(buf).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (n, addr) = self.conn.recv_from(buf).await?;
+        if n > 0 && buf[0] & 0xC0 == 0x40 {
+            self.channel_data.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok((n, addr))
+    }
+
+    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.conn.send(buf).await
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        self.conn.send_to(buf, target).await
+    }
+
+    async fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.conn.local_addr()
+    }
+}
+
 //use std::io::Write;
 
 #[tokio::test]
-async fn test_relay_only_connection() -> Result<(), Error> {
+async fn test_relay_only_connection() -> Result<(), IceError> {
     /*env_logger::Builder::new()
     .format(|buf, record| {
         writeln!(
@@ -109,3 +149,76 @@ async fn test_relay_only_connection() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_relay_pair_sends_channel_data() -> Result<(), IceError> {
+    let channel_data = Arc::new(AtomicUsize::new(0));
+    let server_listener = UdpSocket::bind("127.0.0.1:0").await?;
+    let server_port = server_listener.local_addr()?.port();
+
+    let server = turn::server::Server::new(turn::server::config::ServerConfig {
+        realm: "webrtc.rs".to_owned(),
+        auth_handler: Arc::new(Box::new(OptimisticAuthHandler {})),
+        conn_configs: vec![turn::server::config::ConnConfig {
+            conn: Arc::new(ChannelDataCounter {
+                conn: server_listener,
+                channel_data: Arc::clone(&channel_data),
+            }),
+            relay_addr_generator: Box::new(turn::relay::relay_none::RelayAddressGeneratorNone {
+                address: "127.0.0.1".to_owned(),
+                net: Arc::new(util::vnet::net::Net::new(None)),
+            }),
+        }],
+        channel_bind_timeout: Duration::from_secs(0),
+    })
+    .await?;
+
+    let relay_config = || AgentConfig {
+        network_types: supported_network_types(),
+        urls: vec![Url {
+            scheme: SchemeType::Turn,
+            host: "127.0.0.1".to_owned(),
+            username: "username".to_owned(),
+            password: "password".to_owned(),
+            port: server_port,
+            proto: ProtoType::Udp,
+        }],
+        candidate_types: vec![CandidateType::Relay],
+        ..Default::default()
+    };
+
+    let a_agent = Arc::new(Agent::new(relay_config()).await?);
+    let (a_notifier, mut a_connected) = on_connected();
+    a_agent.on_connection_state_change(a_notifier).await;
+
+    let b_agent = Arc::new(Agent::new(relay_config()).await?);
+    let (b_notifier, mut b_connected) = on_connected();
+    b_agent.on_connection_state_change(b_notifier).await;
+
+    let (a_conn, _b_conn) = connect_with_vnet(&a_agent, &b_agent).await?;
+
+    let _ = a_connected.recv().await;
+    let _ = b_connected.recv().await;
+
+    // The channels are bound by the checks, so the selected pair relays ChannelData
+    let sent = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            a_conn.send(b"hello").await?;
+            if channel_data.load(Ordering::SeqCst) > 0 {
+                return Ok::<(), IceError>(());
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await;
+    assert!(
+        matches!(sent, Ok(Ok(()))),
+        "the relay pair should send ChannelData"
+    );
+
+    a_agent.close().await?;
+    b_agent.close().await?;
+    server.close()?;
+
+    Ok(())
+}
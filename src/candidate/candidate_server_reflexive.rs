@@ -20,10 +20,10 @@ impl CandidateServerReflexiveConfig {
     pub async fn new_candidate_server_reflexive(
         self,
         agent_internal: Option<Arc<Mutex<AgentInternal>>>,
-    ) -> Result<CandidateBase, Error> {
+    ) -> Result<CandidateBase, IceError> {
         let ip: IpAddr = match self.base_config.address.parse() {
             Ok(ip) => ip,
-            Err(_) => return Err(ERR_ADDRESS_PARSE_FAILED.to_owned()),
+            Err(_) => return Err(IceError::AddressParseFailed),
         };
         let network_type = determine_network_type(&self.base_config.network, &ip)?;
 
@@ -40,6 +40,10 @@ impl CandidateServerReflexiveConfig {
             port: self.base_config.port,
             resolved_addr: Mutex::new(create_addr(network_type, ip, self.base_config.port)),
             component: AtomicU16::new(self.base_config.component),
+            stream: AtomicU16::new(self.base_config.stream),
+            network_id: self.base_config.network_id,
+            network_cost: self.base_config.network_cost,
+            extensions: self.base_config.extensions,
             foundation_override: self.base_config.foundation,
             priority_override: self.base_config.priority,
             related_address: Some(CandidateRelatedAddress {
@@ -47,6 +51,7 @@ impl CandidateServerReflexiveConfig {
                 port: self.rel_port,
             }),
             conn: self.base_config.conn,
+            batch_conn: self.base_config.batch_conn,
             agent_internal,
             ..CandidateBase::default()
         };
@@ -1,12 +1,13 @@
+use super::candidate_init::CandidateInit;
 use super::*;
+use crate::errors::*;
 
 use crate::agent::agent_config::AgentConfig;
 use crate::agent::Agent;
 use std::time::UNIX_EPOCH;
-use util::Error;
 
 #[test]
-fn test_candidate_priority() -> Result<(), Error> {
+fn test_candidate_priority() -> Result<(), IceError> {
     let tests = vec![
         (
             CandidateBase {
@@ -16,6 +17,24 @@ fn test_candidate_priority() -> Result<(), Error> {
             },
             2130706431,
         ),
+        (
+            CandidateBase {
+                candidate_type: CandidateType::Host,
+                component: AtomicU16::new(COMPONENT_RTP as u16),
+                address_rank: 1,
+                ..Default::default()
+            },
+            2130706175,
+        ),
+        (
+            CandidateBase {
+                candidate_type: CandidateType::Host,
+                component: AtomicU16::new(COMPONENT_RTP as u16),
+                interface_preference: 30000,
+                ..Default::default()
+            },
+            2121609471,
+        ),
         (
             CandidateBase {
                 candidate_type: CandidateType::Host,
@@ -26,6 +45,28 @@ fn test_candidate_priority() -> Result<(), Error> {
             },
             2128609279,
         ),
+        (
+            CandidateBase {
+                candidate_type: CandidateType::Host,
+                component: AtomicU16::new(COMPONENT_RTP as u16),
+                network_type: AtomicU8::new(NetworkType::Tcp4 as u8),
+                tcp_type: TcpType::Active,
+                address_rank: 1,
+                ..Default::default()
+            },
+            2128609023,
+        ),
+        (
+            CandidateBase {
+                candidate_type: CandidateType::Host,
+                component: AtomicU16::new(COMPONENT_RTP as u16),
+                network_type: AtomicU8::new(NetworkType::Tcp4 as u8),
+                tcp_type: TcpType::Active,
+                interface_preference: 30000,
+                ..Default::default()
+            },
+            2127472383,
+        ),
         (
             CandidateBase {
                 candidate_type: CandidateType::Host,
@@ -115,7 +156,7 @@ fn test_candidate_priority() -> Result<(), Error> {
 }
 
 #[test]
-fn test_candidate_last_sent() -> Result<(), Error> {
+fn test_candidate_last_sent() -> Result<(), IceError> {
     let candidate = CandidateBase::default();
     assert_eq!(candidate.last_sent(), UNIX_EPOCH);
 
@@ -128,7 +169,7 @@ fn test_candidate_last_sent() -> Result<(), Error> {
 }
 
 #[test]
-fn test_candidate_last_received() -> Result<(), Error> {
+fn test_candidate_last_received() -> Result<(), IceError> {
     let candidate = CandidateBase::default();
     assert_eq!(candidate.last_received(), UNIX_EPOCH);
 
@@ -141,7 +182,7 @@ fn test_candidate_last_received() -> Result<(), Error> {
 }
 
 #[test]
-fn test_candidate_foundation() -> Result<(), Error> {
+fn test_candidate_foundation() -> Result<(), IceError> {
     // All fields are the same
     assert_eq!(
         (CandidateBase {
@@ -238,7 +279,7 @@ fn test_candidate_foundation() -> Result<(), Error> {
 }
 
 #[tokio::test]
-async fn test_candidate_marshal() -> Result<(), Error> {
+async fn test_candidate_marshal() -> Result<(), IceError> {
     let tests = vec![
        (
             Some(CandidateBase{
@@ -324,6 +365,9 @@ async fn test_candidate_marshal() -> Result<(), Error> {
         (None, "4207374051 1 udp INVALID 10.0.75.1 53634 typ host"),
         (None, "4207374051 INVALID udp 2130706431 10.0.75.1 INVALID typ host"),
         (None, "4207374051 1 udp 2130706431 10.0.75.1 53634 typ INVALID"),
+        (None, "4207374051 1 udp 2130706431 10.0.75.1 53634 type host"),
+        (None, "4207374051 1 tcp 2128609279 10.0.75.1 53634 typ host tcptype INVALID"),
+        (None, "4207374051 1 udp 1685790463 191.228.238.68 53991 typ srflx raddr 192.168.0.278 rport"),
     ];
 
     let agent = Agent::new(AgentConfig::default()).await?;
@@ -348,3 +392,144 @@ async fn test_candidate_marshal() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_unmarshal_candidate() -> Result<(), IceError> {
+    // Both the SDP attribute prefix and the candidate: prefix are accepted
+    let c = unmarshal_candidate(
+        "a=candidate:647372371 1 UDP 1694498815 191.228.238.68 53991 typ srflx raddr 192.168.0.274 rport 53991",
+    )
+    .await?;
+    assert_eq!(c.candidate_type(), CandidateType::ServerReflexive);
+    assert_eq!(c.network_type(), NetworkType::Udp4);
+    assert_eq!(c.foundation(), "647372371");
+    assert_eq!(c.priority(), 1694498815);
+    assert_eq!(c.address(), "191.228.238.68");
+    assert_eq!(c.port(), 53991);
+    assert_eq!(
+        c.related_address(),
+        Some(CandidateRelatedAddress {
+            address: "192.168.0.274".to_owned(),
+            port: 53991,
+        })
+    );
+
+    // Extensions may come in any order and are preserved
+    let c = unmarshal_candidate(
+        "candidate:1052353102 1 tcp 2128609279 192.168.0.196 9 typ host generation 0 tcptype passive network-id 1",
+    )
+    .await?;
+    assert_eq!(c.candidate_type(), CandidateType::Host);
+    assert_eq!(c.network_type(), NetworkType::Tcp4);
+    assert_eq!(c.tcp_type(), TcpType::Passive);
+    assert_eq!(c.generation(), Some(0));
+    assert_eq!(c.network_id(), 1);
+    assert_eq!(c.network_cost(), NETWORK_COST_MIN);
+    assert_eq!(c.get_extension(EXTENSION_NETWORK_ID), None);
+    assert_eq!(
+        c.marshal(),
+        "1052353102 1 tcp 2128609279 192.168.0.196 9 typ host tcptype passive generation 0 network-id 1"
+    );
+
+    let c = unmarshal_candidate(
+        "848194626 1 udp 16777215 50.0.0.1 5000 typ relay rport 5001 raddr 192.168.0.1 ufrag abcd x-custom 42",
+    )
+    .await?;
+    assert_eq!(c.ufrag(), Some("abcd".to_owned()));
+    assert_eq!(c.generation(), None);
+    assert_eq!(
+        c.extensions(),
+        vec![
+            CandidateExtension {
+                key: "ufrag".to_owned(),
+                value: "abcd".to_owned(),
+            },
+            CandidateExtension {
+                key: "x-custom".to_owned(),
+                value: "42".to_owned(),
+            },
+        ]
+    );
+    assert_eq!(
+        c.marshal(),
+        "848194626 1 udp 16777215 50.0.0.1 5000 typ relay raddr 192.168.0.1 rport 5001 ufrag abcd x-custom 42"
+    );
+
+    let init = CandidateInit::from(&c);
+    assert_eq!(init.marshal(), c.marshal());
+    assert_eq!(init.to_candidate().await?.extensions(), c.extensions());
+
+    // Errors name the offending field
+    let err = unmarshal_candidate("1 1 udp 1 10.0.75.1 INVALID typ host")
+        .await
+        .unwrap_err();
+    assert!(matches!(err, IceError::ParsePort(_)), "{}", err);
+
+    let err = unmarshal_candidate("1 1 udp 1 10.0.75.1 53634 typ host generation")
+        .await
+        .unwrap_err();
+    assert!(matches!(err, IceError::ParseExtension(_)), "{}", err);
+
+    let err = unmarshal_candidate("1 1 udp 1 10.0.75.1 53634 typ host network-cost high")
+        .await
+        .unwrap_err();
+    assert!(matches!(err, IceError::ParseExtension(_)), "{}", err);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_candidate_init() -> Result<(), IceError> {
+    let marshaled =
+        "647372371 1 udp 1694498815 191.228.238.68 53991 typ srflx raddr 192.168.0.274 rport 53991";
+    let c = unmarshal_candidate(marshaled).await?;
+
+    let init = CandidateInit::from(&c);
+    assert_eq!(init.candidate_type, CandidateType::ServerReflexive);
+    assert_eq!(init.network_type, NetworkType::Udp4);
+    assert_eq!(init.marshal(), marshaled);
+
+    let actual = init.to_candidate().await?;
+    assert!(c.equal(&actual), "{} vs {}", c, actual);
+
+    Ok(())
+}
+
+#[cfg(feature = "serde")]
+#[tokio::test]
+async fn test_candidate_init_serde() -> Result<(), IceError> {
+    let init = CandidateInit {
+        foundation: "1052353102".to_owned(),
+        component: 1,
+        network_type: NetworkType::Tcp4,
+        priority: 2128609279,
+        address: "192.168.0.196".to_owned(),
+        port: 9,
+        candidate_type: CandidateType::Host,
+        tcp_type: TcpType::Passive,
+        related_address: None,
+        network_id: 0,
+        network_cost: 0,
+        extensions: vec![],
+        stream: 0,
+    };
+
+    let json = serde_json::to_string(&init).unwrap();
+    assert!(json.contains(r#""network_type":"tcp4""#), "{}", json);
+    assert!(json.contains(r#""candidate_type":"host""#), "{}", json);
+    assert!(json.contains(r#""tcp_type":"passive""#), "{}", json);
+
+    let actual: CandidateInit = serde_json::from_str(&json).unwrap();
+    assert_eq!(actual, init);
+
+    assert_eq!(
+        serde_json::to_string(&CandidateType::ServerReflexive).unwrap(),
+        r#""srflx""#
+    );
+    assert_eq!(
+        serde_json::from_str::<TcpType>(r#""so""#).unwrap(),
+        TcpType::SimultaneousOpen
+    );
+
+    Ok(())
+}
@@ -9,32 +9,61 @@ mod candidate_test;
 
 pub mod candidate_base;
 pub mod candidate_host;
+pub mod candidate_init;
 pub mod candidate_peer_reflexive;
 pub mod candidate_relay;
 pub mod candidate_server_reflexive;
 
+use crate::errors::*;
+use crate::metrics;
 use crate::network_type::*;
 use crate::tcp_type::*;
 use candidate_base::*;
 
-use util::Error;
-
 use crate::agent::agent_internal::AgentInternal;
 use async_trait::async_trait;
+use std::collections::VecDeque;
 use std::fmt;
 use std::net::{IpAddr, SocketAddr};
-use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU8, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, AtomicU8, Ordering};
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use tokio::sync::{broadcast, Mutex};
+use tokio::time::Instant;
 
 pub(crate) const RECEIVE_MTU: usize = 8192;
+/// The number of packets of `RECEIVE_MTU` bytes the receive buffer of a candidate holds.
+pub(crate) const RECEIVE_BUFFER_PACKETS: usize = 64;
 pub(crate) const DEFAULT_LOCAL_PREFERENCE: u16 = 65535;
+/// The number of the latest binding requests of a pair its check loss and round trip time
+/// variance are measured over.
+pub(crate) const CHECK_WINDOW_SIZE: usize = 32;
+/// The lowest RTO derived from the round trip time of a pair, so that a jitter spike on a short
+/// link does not trigger retransmissions right away.
+pub(crate) const MIN_RTO: Duration = Duration::from_millis(100);
+
+/// The extension attribute carrying the generation of a candidate.
+pub const EXTENSION_GENERATION: &str = "generation";
+/// The extension attribute carrying the username fragment of a candidate.
+pub const EXTENSION_UFRAG: &str = "ufrag";
+/// The extension attribute carrying the id of the network interface of a candidate.
+pub const EXTENSION_NETWORK_ID: &str = "network-id";
+/// The extension attribute carrying the cost of the network interface of a candidate.
+pub const EXTENSION_NETWORK_COST: &str = "network-cost";
+
+/// The network cost of a wired interface, and of candidates which don't signal one.
+pub const NETWORK_COST_MIN: u16 = 0;
+/// The network cost of a Wi-Fi interface.
+pub const NETWORK_COST_LOW: u16 = 10;
+/// The network cost of an interface whose type can't be told from its name, e.g. a VPN.
+pub const NETWORK_COST_UNKNOWN: u16 = 50;
+/// The network cost of a cellular interface.
+pub const NETWORK_COST_HIGH: u16 = 900;
 
 /// Indicates that the candidate is used for RTP.
-pub(crate) const COMPONENT_RTP: u16 = 1;
+pub const COMPONENT_RTP: u16 = 1;
 /// Indicates that the candidate is used for RTCP.
-pub(crate) const COMPONENT_RTCP: u16 = 0;
+pub const COMPONENT_RTCP: u16 = 2;
 
 /// Candidate represents an ICE candidate
 #[async_trait]
@@ -54,18 +83,42 @@ pub trait Candidate: fmt::Display {
     fn component(&self) -> u16;
     fn set_component(&self, c: u16);
 
+    /// The index of the data stream the candidate belongs to when several streams share the
+    /// agent, e.g. one per media section of non-bundled SDP. It is not part of the candidate
+    /// attribute, so it has to be signaled along with it.
+    fn stream(&self) -> u16;
+    fn set_stream(&self, s: u16);
+
     /// The last time this candidate received traffic
     fn last_received(&self) -> SystemTime;
 
     /// The last time this candidate sent traffic
     fn last_sent(&self) -> SystemTime;
 
+    /// The number of bytes sent from this candidate, STUN traffic included.
+    fn bytes_sent(&self) -> u64;
+
+    /// The number of bytes received on this candidate, STUN traffic included.
+    fn bytes_received(&self) -> u64;
+
+    /// The number of STUN requests sent from this candidate, e.g. connectivity checks.
+    fn stun_requests_sent(&self) -> u64;
+
+    /// The number of STUN responses, success or error, received on this candidate.
+    fn stun_responses_received(&self) -> u64;
+
     fn network_type(&self) -> NetworkType;
     fn address(&self) -> String;
     fn port(&self) -> u16;
 
     fn priority(&self) -> u32;
 
+    /// Overrides the priority of the default formula, with the one given by
+    /// `AgentConfig::candidate_priority` when the candidate is added to the agent. It is ignored
+    /// when the candidate was created with a priority of its own. The default ignores it, so
+    /// that the candidates implemented outside of the crate keep their priority.
+    fn set_priority(&self, _priority: u32) {}
+
     /// A transport address related to candidate,
     /// which is useful for diagnostics and other purposes.
     fn related_address(&self) -> Option<CandidateRelatedAddress>;
@@ -73,20 +126,70 @@ pub trait Candidate: fmt::Display {
     fn candidate_type(&self) -> CandidateType;
     fn tcp_type(&self) -> TcpType;
 
+    /// The transport used to reach the TURN server, only set for relay candidates.
+    fn relay_protocol(&self) -> String;
+
+    /// The time left before the TURN allocation of a relay candidate expires unless it is
+    /// refreshed, see `AgentConfig::turn_allocation_lifetime`. None for the other candidates,
+    /// the ones of `AgentConfig::relay_provider`, and once the allocation expired.
+    fn relay_lifetime(&self) -> Option<Duration> {
+        None
+    }
+
+    /// The id of the network interface the candidate was gathered on, the same for every
+    /// candidate of an interface. 0 when unknown.
+    fn network_id(&self) -> u16;
+
+    /// How costly sending over the network interface of the candidate is, e.g.
+    /// `NETWORK_COST_HIGH` for a cellular one. It breaks ties between pairs of equal priority.
+    fn network_cost(&self) -> u16;
+
+    /// The extension attributes of the candidate attribute, e.g. `generation`, in the order they
+    /// were received. `network-id` and `network-cost` are reported by their own accessors.
+    fn extensions(&self) -> Vec<CandidateExtension>;
+
+    /// The value of the extension attribute `key`, if the candidate has it.
+    fn get_extension(&self, key: &str) -> Option<String>;
+
+    /// The generation of the candidate, i.e. how many times the remote agent restarted ICE.
+    fn generation(&self) -> Option<u32> {
+        self.get_extension(EXTENSION_GENERATION)
+            .and_then(|value| value.parse().ok())
+    }
+
+    /// The username fragment of the agent that gathered the candidate.
+    fn ufrag(&self) -> Option<String> {
+        self.get_extension(EXTENSION_UFRAG)
+    }
+
     fn marshal(&self) -> String;
 
     async fn addr(&self) -> SocketAddr;
 
-    async fn close(&self) -> Result<(), Error>;
+    async fn close(&self) -> Result<(), IceError>;
     fn seen(&self, outbound: bool);
+    /// Updates the inbound counters with a packet received on this candidate.
+    fn received(&self, raw: &[u8]);
 
     async fn write_to(
         &self,
         raw: &[u8],
         dst: &(dyn Candidate + Send + Sync),
-    ) -> Result<usize, Error>;
+    ) -> Result<usize, IceError>;
+    /// Sends every buffer of `bufs` as a separate packet to `dst`, with a single syscall when
+    /// the connection of the candidate supports it, and returns the number of packets sent, see
+    /// `BatchConn::send_batch_to`.
+    async fn write_batch_to(
+        &self,
+        bufs: &[&[u8]],
+        dst: &(dyn Candidate + Send + Sync),
+    ) -> Result<usize, IceError>;
+    /// Sets the type of service, or IPv6 traffic class, of the packets sent from the socket of
+    /// the candidate. Fails with `IceError::TosUnsupported` when its transport is not a UDP
+    /// socket of the host, e.g. for relay and TCP candidates or the ones of a mux.
+    fn set_tos(&self, tos: u8) -> Result<(), IceError>;
     fn equal(&self, other: &dyn Candidate) -> bool;
-    async fn set_ip(&self, ip: &IpAddr) -> Result<(), Error>;
+    async fn set_ip(&self, ip: &IpAddr) -> Result<(), IceError>;
     fn get_conn(&self) -> Option<&Arc<dyn util::Conn + Send + Sync>>;
     fn get_agent(&self) -> Option<&Arc<Mutex<AgentInternal>>>;
     fn get_closed_ch(&self) -> Arc<Mutex<Option<broadcast::Sender<()>>>>;
@@ -94,10 +197,17 @@ pub trait Candidate: fmt::Display {
 
 /// Represents the type of candidate `CandidateType` enum.
 #[derive(PartialEq, Debug, Copy, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
 pub enum CandidateType {
     Unspecified,
     Host,
+    #[cfg_attr(feature = "serde", serde(rename = "srflx"))]
     ServerReflexive,
+    #[cfg_attr(feature = "serde", serde(rename = "prflx"))]
     PeerReflexive,
     Relay,
 }
@@ -157,11 +267,21 @@ pub(crate) fn contains_candidate_type(
 
 /// Convey transport addresses related to the candidate, useful for diagnostics and other purposes.
 #[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CandidateRelatedAddress {
     pub address: String,
     pub port: u16,
 }
 
+/// An extension attribute of a candidate attribute, a name/value pair following the ones this
+/// crate interprets.
+#[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CandidateExtension {
+    pub key: String,
+    pub value: String,
+}
+
 // String makes CandidateRelatedAddress printable
 impl fmt::Display for CandidateRelatedAddress {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -171,6 +291,11 @@ impl fmt::Display for CandidateRelatedAddress {
 
 /// Represent the ICE candidate pair state.
 #[derive(PartialEq, Debug, Copy, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "kebab-case")
+)]
 pub enum CandidatePairState {
     Unspecified = 0,
 
@@ -186,6 +311,10 @@ pub enum CandidatePairState {
 
     /// Means a check for this pair was already done and produced a successful result.
     Succeeded = 4,
+
+    /// Means a check has not been performed for this pair, and can't be until it is unfrozen
+    /// because a pair of the same foundation is being checked or has succeeded.
+    Frozen = 5,
 }
 
 impl From<u8> for CandidatePairState {
@@ -195,6 +324,7 @@ impl From<u8> for CandidatePairState {
             2 => Self::InProgress,
             3 => Self::Failed,
             4 => Self::Succeeded,
+            5 => Self::Frozen,
             _ => Self::Unspecified,
         }
     }
@@ -213,6 +343,7 @@ impl fmt::Display for CandidatePairState {
             Self::InProgress => "in-progress",
             Self::Failed => "failed",
             Self::Succeeded => "succeeded",
+            Self::Frozen => "frozen",
             Self::Unspecified => "unspecified",
         };
 
@@ -221,25 +352,51 @@ impl fmt::Display for CandidatePairState {
 }
 
 /// Represents a combination of a local and remote candidate.
-pub(crate) struct CandidatePair {
+pub struct CandidatePair {
     pub(crate) ice_role_controlling: AtomicBool,
     pub(crate) remote: Arc<dyn Candidate + Send + Sync>,
     pub(crate) local: Arc<dyn Candidate + Send + Sync>,
     pub(crate) binding_request_count: AtomicU16,
     pub(crate) state: AtomicU8, // convert it to CandidatePairState,
     pub(crate) nominated: AtomicBool,
+    // The checks of the pair succeeded through the public mapping of the NAT both agents are
+    // behind, see `hairpinned`.
+    pub(crate) hairpinned: AtomicBool,
+    // The packets sent on the pair are marked with the DSCP of its component, see `dscp_marked`.
+    pub(crate) dscp_marked: AtomicBool,
+
+    // Counters surfaced through `CandidatePairStats`. Timestamps are none until the first
+    // occurrence.
+    pub(crate) packets_sent: AtomicU64,
+    pub(crate) packets_received: AtomicU64,
+    pub(crate) bytes_sent: AtomicU64,
+    pub(crate) bytes_received: AtomicU64,
+    pub(crate) requests_sent: AtomicU64,
+    pub(crate) requests_received: AtomicU64,
+    pub(crate) responses_sent: AtomicU64,
+    pub(crate) responses_received: AtomicU64,
+    pub(crate) total_round_trip_time: AtomicU64,
+    pub(crate) current_round_trip_time: AtomicU64,
+    pub(crate) smoothed_round_trip_time: AtomicU64,
+    // The variation of the round trip time, RTTVAR of RFC 6298, in nanoseconds.
+    pub(crate) round_trip_time_variation: AtomicU64,
+    // The outcome of the latest binding requests, the round trip time in nanoseconds of those
+    // which were answered and none for those which were lost.
+    pub(crate) check_window: std::sync::Mutex<VecDeque<Option<u64>>>,
+    pub(crate) last_packet_sent: std::sync::Mutex<Option<Instant>>,
+    pub(crate) last_packet_received: std::sync::Mutex<Option<Instant>>,
+    pub(crate) first_request_sent: std::sync::Mutex<Option<Instant>>,
+    pub(crate) last_request_sent: std::sync::Mutex<Option<Instant>>,
+    pub(crate) last_response_received: std::sync::Mutex<Option<Instant>>,
 }
 
 impl Default for CandidatePair {
     fn default() -> Self {
-        Self {
-            ice_role_controlling: AtomicBool::new(false),
-            remote: Arc::new(CandidateBase::default()),
-            local: Arc::new(CandidateBase::default()),
-            state: AtomicU8::new(CandidatePairState::Waiting as u8),
-            binding_request_count: AtomicU16::new(0),
-            nominated: AtomicBool::new(false),
-        }
+        Self::new(
+            Arc::new(CandidateBase::default()),
+            Arc::new(CandidateBase::default()),
+            false,
+        )
     }
 }
 
@@ -287,9 +444,29 @@ impl CandidatePair {
             ice_role_controlling: AtomicBool::new(controlling),
             remote,
             local,
-            state: AtomicU8::new(CandidatePairState::Waiting as u8),
+            state: AtomicU8::new(CandidatePairState::Frozen as u8),
             binding_request_count: AtomicU16::new(0),
             nominated: AtomicBool::new(false),
+            hairpinned: AtomicBool::new(false),
+            dscp_marked: AtomicBool::new(false),
+            packets_sent: AtomicU64::new(0),
+            packets_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            requests_sent: AtomicU64::new(0),
+            requests_received: AtomicU64::new(0),
+            responses_sent: AtomicU64::new(0),
+            responses_received: AtomicU64::new(0),
+            total_round_trip_time: AtomicU64::new(0),
+            current_round_trip_time: AtomicU64::new(0),
+            smoothed_round_trip_time: AtomicU64::new(0),
+            round_trip_time_variation: AtomicU64::new(0),
+            check_window: std::sync::Mutex::new(VecDeque::with_capacity(CHECK_WINDOW_SIZE)),
+            last_packet_sent: std::sync::Mutex::new(None),
+            last_packet_received: std::sync::Mutex::new(None),
+            first_request_sent: std::sync::Mutex::new(None),
+            last_request_sent: std::sync::Mutex::new(None),
+            last_response_received: std::sync::Mutex::new(None),
         }
     }
 
@@ -312,7 +489,280 @@ impl CandidatePair {
             + if g > d { 1 } else { 0 }
     }
 
-    pub async fn write(&self, b: &[u8]) -> Result<usize, Error> {
-        self.local.write_to(b, &*self.remote).await
+    /// The cost of the networks the pair goes through, the sum of the network costs of its
+    /// candidates.
+    pub fn network_cost(&self) -> u32 {
+        u32::from(self.local.network_cost()) + u32::from(self.remote.network_cost())
+    }
+
+    /// Returns true if the pair is preferred to `other`: its priority is higher or, like
+    /// libwebrtc does, it is as high and the pair is cheaper.
+    pub(crate) fn is_preferred_to(&self, other: &Self) -> bool {
+        match self.priority().cmp(&other.priority()) {
+            std::cmp::Ordering::Equal => self.network_cost() < other.network_cost(),
+            ordering => ordering == std::cmp::Ordering::Greater,
+        }
+    }
+
+    pub async fn write(&self, b: &[u8]) -> Result<usize, IceError> {
+        let n = self.local.write_to(b, &*self.remote).await?;
+        self.packets_sent.fetch_add(1, Ordering::SeqCst);
+        self.bytes_sent.fetch_add(n as u64, Ordering::SeqCst);
+        set_timestamp(&self.last_packet_sent, Instant::now());
+        metrics::bytes_sent(n);
+        Ok(n)
+    }
+
+    /// Sends the packets of `bufs` on this pair, see `Candidate::write_batch_to`, and returns
+    /// the number of bytes sent. Only the packets actually sent are counted when sending failed
+    /// midway.
+    pub async fn write_batch(&self, bufs: &[&[u8]]) -> Result<usize, IceError> {
+        let sent = self.local.write_batch_to(bufs, &*self.remote).await?;
+        let n: usize = bufs[..sent].iter().map(|buf| buf.len()).sum();
+        self.packets_sent.fetch_add(sent as u64, Ordering::SeqCst);
+        self.bytes_sent.fetch_add(n as u64, Ordering::SeqCst);
+        set_timestamp(&self.last_packet_sent, Instant::now());
+        metrics::bytes_sent(n);
+        Ok(n)
+    }
+
+    /// Records a non-STUN packet of `n` bytes received on this pair.
+    pub(crate) fn on_packet_received(&self, n: usize) {
+        self.packets_received.fetch_add(1, Ordering::SeqCst);
+        self.bytes_received.fetch_add(n as u64, Ordering::SeqCst);
+        set_timestamp(&self.last_packet_received, Instant::now());
+        metrics::bytes_received(n);
     }
+
+    /// Records a binding request sent on this pair.
+    pub(crate) fn on_request_sent(&self) {
+        let now = Instant::now();
+        self.requests_sent.fetch_add(1, Ordering::SeqCst);
+        if let Ok(mut first_request_sent) = self.first_request_sent.lock() {
+            first_request_sent.get_or_insert(now);
+        }
+        set_timestamp(&self.last_request_sent, now);
+    }
+
+    /// Returns the RTO of the checks of the pair: `initial_rto` until a response has been
+    /// received, then SRTT + 4 * RTTVAR as in RFC 6298, but no less than `MIN_RTO`.
+    pub(crate) fn retransmission_timeout(&self, initial_rto: Duration) -> Duration {
+        let srtt = self.smoothed_round_trip_time.load(Ordering::SeqCst);
+        if srtt == 0 {
+            return initial_rto;
+        }
+
+        let rttvar = self.round_trip_time_variation.load(Ordering::SeqCst);
+        Duration::from_nanos(srtt.saturating_add(rttvar.saturating_mul(4))).max(MIN_RTO)
+    }
+
+    /// Returns true if the check of the pair should be retransmitted, i.e. the last request has
+    /// been waiting for a response for its RTO, see `retransmission_timeout`, multiplied by
+    /// `backoff` for every retransmission so far. Without `initial_rto`, it is always due.
+    pub(crate) fn is_retransmission_due(&self, initial_rto: Duration, backoff: u32) -> bool {
+        let count = self.binding_request_count.load(Ordering::SeqCst);
+        if count == 0 || initial_rto == Duration::from_secs(0) {
+            return true;
+        }
+
+        let rto = self
+            .retransmission_timeout(initial_rto)
+            .saturating_mul(backoff.saturating_pow(u32::from(count - 1).min(16)));
+        elapsed_since(&self.last_request_sent).map_or(true, |elapsed| elapsed >= rto)
+    }
+
+    /// Returns true if no binding request has been sent on the pair for `interval`.
+    pub(crate) fn is_keepalive_due(&self, interval: Duration) -> bool {
+        elapsed_since(&self.last_request_sent).map_or(true, |elapsed| elapsed > interval)
+    }
+
+    /// Records a binding request received on this pair.
+    pub(crate) fn on_request_received(&self) {
+        self.requests_received.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Records a binding success response sent on this pair.
+    pub(crate) fn on_response_sent(&self) {
+        self.responses_sent.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Records a binding success response received on this pair, `rtt` being the time elapsed
+    /// since the matching request was sent. The responses to the checks, the keepalives and the
+    /// consent checks all measure the round trip time, which is smoothed like the SRTT of
+    /// RFC 6298, with a gain of 1/8.
+    pub(crate) fn on_response_received(&self, rtt: Duration) {
+        if self.nominated() {
+            metrics::selected_pair_rtt(rtt);
+        }
+        let rtt = rtt.as_nanos() as u64;
+        self.responses_received.fetch_add(1, Ordering::SeqCst);
+        self.total_round_trip_time.fetch_add(rtt, Ordering::SeqCst);
+        self.current_round_trip_time.store(rtt, Ordering::SeqCst);
+        let srtt = self
+            .smoothed_round_trip_time
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |srtt| {
+                Some(if srtt == 0 {
+                    rtt
+                } else {
+                    srtt - srtt / 8 + rtt / 8
+                })
+            })
+            .unwrap_or_default();
+        // RTTVAR is updated from the SRTT before this measurement, with a gain of 1/4
+        let _ = self.round_trip_time_variation.fetch_update(
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+            |rttvar| {
+                Some(if srtt == 0 {
+                    rtt / 2
+                } else {
+                    rttvar - rttvar / 4 + srtt.abs_diff(rtt) / 4
+                })
+            },
+        );
+        set_timestamp(&self.last_response_received, Instant::now());
+        self.record_check(Some(rtt));
+    }
+
+    /// Records a binding request sent on this pair which has timed out without a response.
+    pub(crate) fn on_request_lost(&self) {
+        self.record_check(None);
+    }
+
+    fn record_check(&self, rtt: Option<u64>) {
+        if let Ok(mut check_window) = self.check_window.lock() {
+            if check_window.len() == CHECK_WINDOW_SIZE {
+                check_window.pop_front();
+            }
+            check_window.push_back(rtt);
+        }
+    }
+
+    /// Returns the number of the latest binding requests of the pair which were lost in a row.
+    pub(crate) fn lost_checks_in_a_row(&self) -> usize {
+        self.check_window.lock().map_or(0, |check_window| {
+            check_window
+                .iter()
+                .rev()
+                .take_while(|rtt| rtt.is_none())
+                .count()
+        })
+    }
+
+    /// Returns the share of the latest binding requests of the pair which were lost, from 0 to 1.
+    pub(crate) fn check_loss_ratio(&self) -> f64 {
+        self.check_window
+            .lock()
+            .ok()
+            .and_then(|check_window| {
+                mean(
+                    check_window
+                        .iter()
+                        .map(|rtt| if rtt.is_none() { 1.0 } else { 0.0 }),
+                )
+            })
+            .unwrap_or(0.0)
+    }
+
+    /// Returns the variance, in seconds squared, of the round trip times of the latest binding
+    /// requests of the pair which were answered.
+    pub(crate) fn round_trip_time_variance(&self) -> f64 {
+        let rtts: Vec<f64> = match self.check_window.lock() {
+            Ok(check_window) => check_window
+                .iter()
+                .flatten()
+                .map(|&rtt| Duration::from_nanos(rtt).as_secs_f64())
+                .collect(),
+            Err(_) => return 0.0,
+        };
+
+        mean(rtts.iter().copied())
+            .and_then(|mean_rtt| mean(rtts.iter().map(|rtt| (rtt - mean_rtt) * (rtt - mean_rtt))))
+            .unwrap_or(0.0)
+    }
+
+    /// Returns the local candidate of the pair.
+    #[must_use]
+    pub fn local(&self) -> Arc<dyn Candidate + Send + Sync> {
+        Arc::clone(&self.local)
+    }
+
+    /// Returns the remote candidate of the pair.
+    #[must_use]
+    pub fn remote(&self) -> Arc<dyn Candidate + Send + Sync> {
+        Arc::clone(&self.remote)
+    }
+
+    /// Returns the current state of the connectivity checks of the pair.
+    #[must_use]
+    pub fn state(&self) -> CandidatePairState {
+        CandidatePairState::from(self.state.load(Ordering::SeqCst))
+    }
+
+    /// Returns true if the pair has been nominated.
+    #[must_use]
+    pub fn nominated(&self) -> bool {
+        self.nominated.load(Ordering::SeqCst)
+    }
+
+    /// Returns true if the pair succeeded by sending to the server reflexive address of a peer
+    /// behind the same NAT as the agent, i.e. the NAT hairpins the packets back to the LAN.
+    #[must_use]
+    pub fn hairpinned(&self) -> bool {
+        self.hairpinned.load(Ordering::SeqCst)
+    }
+
+    /// Returns true if the packets sent on the pair are marked with the DSCP of its component,
+    /// see `AgentConfig::dscp`. It is only set once the pair is selected, and stays false when
+    /// its local candidate can't be marked, e.g. a relay or TCP candidate.
+    #[must_use]
+    pub fn dscp_marked(&self) -> bool {
+        self.dscp_marked.load(Ordering::SeqCst)
+    }
+
+    /// Returns the foundation of the pair, made of the foundations of its candidates. Pairs of
+    /// the same foundation are likely to succeed or fail together.
+    #[must_use]
+    pub fn foundation(&self) -> String {
+        format!("{}:{}", self.local.foundation(), self.remote.foundation())
+    }
+
+    /// Returns the data stream of the pair, which is the one of its local candidate.
+    #[must_use]
+    pub fn stream(&self) -> u16 {
+        self.local.stream()
+    }
+
+    /// Returns the component of the pair, which is the one of its local candidate.
+    #[must_use]
+    pub fn component(&self) -> u16 {
+        self.local.component()
+    }
+}
+
+/// Returns the mean of `values`, or none if there are none.
+fn mean(values: impl Iterator<Item = f64>) -> Option<f64> {
+    let (sum, count) = values.fold((0.0, 0.0), |(sum, count), value| (sum + value, count + 1.0));
+    if count > 0.0 {
+        Some(sum / count)
+    } else {
+        None
+    }
+}
+
+/// Returns the value of `timestamp`, or none if it was never set.
+pub(crate) fn get_timestamp(timestamp: &std::sync::Mutex<Option<Instant>>) -> Option<Instant> {
+    timestamp.lock().ok().and_then(|timestamp| *timestamp)
+}
+
+/// Sets `timestamp` to `at`.
+fn set_timestamp(timestamp: &std::sync::Mutex<Option<Instant>>, at: Instant) {
+    if let Ok(mut timestamp) = timestamp.lock() {
+        *timestamp = Some(at);
+    }
+}
+
+/// Returns the time elapsed since `timestamp`, or none if it was never set.
+pub(crate) fn elapsed_since(timestamp: &std::sync::Mutex<Option<Instant>>) -> Option<Duration> {
+    get_timestamp(timestamp).map(|at| Instant::now().saturating_duration_since(at))
 }
@@ -0,0 +1,152 @@
+use crate::agent::agent_config::AgentConfig;
+use crate::agent::Agent;
+use crate::candidate::candidate_base::CandidateBaseConfig;
+use crate::candidate::candidate_host::CandidateHostConfig;
+use crate::candidate::Candidate;
+use crate::errors::IceError;
+use crate::network_type::NetworkType;
+use crate::tcp_type::TcpType;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Metadata, Subscriber};
+
+/// An event, with its fields formatted by name, the message under "message".
+struct CapturedEvent {
+    level: Level,
+    fields: HashMap<String, String>,
+}
+
+/// Keeps the events emitted while it is the default subscriber.
+#[derive(Default)]
+struct CaptureSubscriber {
+    events: Arc<Mutex<Vec<CapturedEvent>>>,
+    next_span_id: AtomicU64,
+}
+
+struct FieldVisitor<'a>(&'a mut HashMap<String, String>);
+
+impl Visit for FieldVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_owned(), format!("{:?}", value));
+    }
+}
+
+impl Subscriber for CaptureSubscriber {
+    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, _span: &Attributes<'_>) -> Id {
+        Id::from_u64(self.next_span_id.fetch_add(1, Ordering::SeqCst) + 1)
+    }
+
+    fn record(&self, _span: &Id, _values: &Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = HashMap::new();
+        event.record(&mut FieldVisitor(&mut fields));
+        self.events.lock().unwrap().push(CapturedEvent {
+            level: *event.metadata().level(),
+            fields,
+        });
+    }
+
+    fn enter(&self, _span: &Id) {}
+
+    fn exit(&self, _span: &Id) {}
+}
+
+fn capture<F: FnOnce()>(f: F) -> Vec<CapturedEvent> {
+    let subscriber = CaptureSubscriber::default();
+    let events = Arc::clone(&subscriber.events);
+    tracing::subscriber::with_default(subscriber, f);
+    let mut events = events.lock().unwrap();
+    std::mem::take(&mut *events)
+}
+
+#[test]
+fn test_warn_event_has_fields() {
+    let remote: std::net::SocketAddr = "10.0.0.1:5000".parse().unwrap();
+    let events = capture(|| {
+        warn_event!(remote = remote, error = IceError::Closed; "discard message");
+    });
+
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].level, Level::WARN);
+    assert_eq!(events[0].fields["remote"], "10.0.0.1:5000");
+    assert_eq!(events[0].fields["error"], IceError::Closed.to_string());
+    assert_eq!(events[0].fields["message"], "discard message");
+}
+
+#[test]
+fn test_error_event_formats_message() {
+    let events = capture(|| {
+        error_event!(agent = 3; "invalid candidate type {}", "host");
+        error_event!("no fields");
+    });
+
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].level, Level::ERROR);
+    assert_eq!(events[0].fields["agent"], "3");
+    assert_eq!(events[0].fields["message"], "invalid candidate type host");
+    assert_eq!(events[1].level, Level::ERROR);
+    assert_eq!(events[1].fields["message"], "no fields");
+}
+
+#[tokio::test]
+async fn test_agent_warning_has_agent_and_candidate() -> Result<(), IceError> {
+    let subscriber = CaptureSubscriber::default();
+    let events = Arc::clone(&subscriber.events);
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let a = Agent::new(AgentConfig {
+        agent_id: "session-1".to_owned(),
+        network_types: vec![NetworkType::Udp4],
+        ..Default::default()
+    })
+    .await?;
+
+    let host_config = CandidateHostConfig {
+        base_config: CandidateBaseConfig {
+            network: "tcp".to_owned(),
+            address: "192.168.0.2".to_owned(),
+            port: 1000,
+            component: 1,
+            ..Default::default()
+        },
+        tcp_type: TcpType::Passive,
+    };
+    let remote: Arc<dyn Candidate + Send + Sync> = Arc::new(
+        host_config
+            .new_candidate_host(Some(a.agent_internal.clone()))
+            .await?,
+    );
+    a.add_remote_candidate(&remote).await?;
+
+    {
+        let events = events.lock().unwrap();
+        let event = events
+            .iter()
+            .find(|event| {
+                event.fields.get("message").map(String::as_str)
+                    == Some("Ignoring remote candidate with disabled network type")
+            })
+            .expect("the ignored candidate should be reported");
+        assert_eq!(event.level, Level::WARN);
+        assert_eq!(event.fields["agent"], "session-1");
+        assert_eq!(event.fields["network_type"], "tcp4");
+        assert_eq!(event.fields["candidate"], remote.to_string());
+    }
+
+    a.close().await?;
+
+    Ok(())
+}
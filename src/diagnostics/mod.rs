@@ -0,0 +1,44 @@
+//! The warnings and errors of the agents. With the `tracing` feature they are emitted as
+//! `tracing` events, whose fields, e.g. the `agent`, `candidate` or `remote` they relate to, can
+//! be filtered and indexed by the subscriber. Without it they are logged through `log`, with the
+//! fields appended to the message as `name=value`.
+
+#[cfg(all(test, feature = "tracing"))]
+mod diagnostics_test;
+
+/// Emits an event at `$tracing_level` or logs it at `$log_level`, see `warn_event` and
+/// `error_event`.
+macro_rules! ice_event {
+    ($tracing_level:ident, $log_level:ident, $($field:ident = $value:expr),+ ; $($arg:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        ::tracing::event!(::tracing::Level::$tracing_level, $($field = %$value,)+ $($arg)+);
+        #[cfg(not(feature = "tracing"))]
+        ::log::log!(
+            ::log::Level::$log_level,
+            concat!("{}", $(" ", stringify!($field), "={}",)+),
+            format_args!($($arg)+),
+            $($value,)+
+        );
+    }};
+    ($tracing_level:ident, $log_level:ident, $($arg:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        ::tracing::event!(::tracing::Level::$tracing_level, $($arg)+);
+        #[cfg(not(feature = "tracing"))]
+        ::log::log!(::log::Level::$log_level, $($arg)+);
+    }};
+}
+
+/// Emits a warning, with the `Display` values of the fields before the `;` and the message
+/// after it, e.g. `warn_event!(remote = remote, error = err; "discard message")`.
+macro_rules! warn_event {
+    ($($arg:tt)+) => {
+        ice_event!(WARN, Warn, $($arg)+)
+    };
+}
+
+/// Emits an error, with the same syntax as `warn_event`.
+macro_rules! error_event {
+    ($($arg:tt)+) => {
+        ice_event!(ERROR, Error, $($arg)+)
+    };
+}
@@ -1,130 +1,398 @@
-use util::Error;
-
-lazy_static! {
-    /// Indicates an error with Unknown info.
-    pub static ref ERR_UNKNOWN_TYPE:Error = Error::new("Unknown".to_owned());
-
+use std::io;
+use std::net::{AddrParseError, SocketAddr};
+use std::sync::Arc;
+use thiserror::Error;
+
+/// The errors of the ICE agent. The variants tell the failures apart, so that callers can
+/// handle them programmatically instead of comparing messages.
+#[derive(Debug, Clone, Error)]
+#[non_exhaustive]
+pub enum IceError {
     /// Indicates the scheme type could not be parsed.
-    pub static ref ERR_SCHEME_TYPE:Error = Error::new("unknown scheme type".to_owned());
+    #[error("unknown scheme type")]
+    SchemeType,
 
     /// Indicates query arguments are provided in a STUN URL.
-    pub static ref ERR_STUN_QUERY:Error = Error::new("queries not supported in stun address".to_owned());
+    #[error("queries not supported in stun address")]
+    StunQuery,
 
     /// Indicates an malformed query is provided.
-    pub static ref ERR_INVALID_QUERY:Error = Error::new("invalid query".to_owned());
+    #[error("invalid query")]
+    InvalidQuery,
 
     /// Indicates malformed hostname is provided.
-    pub static ref ERR_HOST:Error = Error::new("invalid hostname".to_owned());
+    #[error("invalid hostname")]
+    Host,
 
     /// Indicates malformed port is provided.
-    pub static ref ERR_PORT:Error = Error::new("invalid port number".to_owned());
+    #[error("invalid port number")]
+    Port,
+
+    /// Indicates that no port of the port range was free to bind a socket on.
+    #[error("no port is free in the port range")]
+    PortRangeExhausted,
+
+    /// Indicates a malformed URL is provided.
+    #[error("invalid url")]
+    InvalidUrl,
 
     /// Indicates local username fragment insufficient bits are provided.
     /// Have to be at least 24 bits long.
-    pub static ref ERR_LOCAL_UFRAG_INSUFFICIENT_BITS:Error = Error::new("local username fragment is less than 24 bits long".to_owned());
+    #[error("local username fragment is less than 24 bits long")]
+    LocalUfragInsufficientBits,
 
     /// Indicates local passoword insufficient bits are provided.
     /// Have to be at least 128 bits long.
-    pub static ref ERR_LOCAL_PWD_INSUFFICIENT_BITS:Error = Error::new("local password is less than 128 bits long".to_owned());
+    #[error("local password is less than 128 bits long")]
+    LocalPwdInsufficientBits,
+
+    /// Indicates local username fragment is not 4 to 256 ice-chars long, as required by RFC 8839.
+    #[error("local username fragment must be 4 to 256 ice-chars long")]
+    LocalUfragInvalid,
+
+    /// Indicates local password is not 22 to 256 ice-chars long, as required by RFC 8839.
+    #[error("local password must be 22 to 256 ice-chars long")]
+    LocalPwdInvalid,
 
     /// Indicates an unsupported transport type was provided.
-    pub static ref ERR_PROTO_TYPE:Error = Error::new("invalid transport protocol type".to_owned());
+    #[error("invalid transport protocol type")]
+    ProtoType,
 
-    /// Indicates the agent is closed.
-    pub static ref ERR_CLOSED:Error = Error::new("the agent is closed".to_owned());
+    /// Indicates a `turns:` URL while no TLS dialer is configured to secure the connection.
+    #[error("turns: URLs require a TLS dialer")]
+    TlsDialerRequired,
 
-    /// Indicates agent does not have a valid candidate pair.
-    pub static ref ERR_NO_CANDIDATE_PAIRS:Error = Error::new("no candidate pairs available".to_owned());
+    /// Indicates the agent is closed.
+    #[error("the agent is closed")]
+    Closed,
+
+    /// Indicates a role conflict with the remote agent could not be resolved, as the agent
+    /// already switched its role `role_switches` times, see `AgentConfig::max_role_switches`.
+    /// The pair checked with `remote` is failed.
+    #[error("unresolved role conflict with {remote} after {role_switches} role switches")]
+    RoleConflict {
+        remote: SocketAddr,
+        role_switches: u16,
+    },
+
+    /// Indicates a received packet was dropped because the receive buffer of its component is
+    /// full.
+    #[error("receive buffer is full")]
+    ReceiveBufferFull,
+
+    /// Indicates a received packet is larger than the buffer it is read into.
+    #[error("buffer too short")]
+    BufferShort,
+
+    /// Indicates an operation did not complete in time, e.g. a resource of the agent did not
+    /// shut down before the close deadline.
+    #[error("timed out {0}")]
+    Timeout(&'static str),
 
     /// Indicates agent connection was canceled by the caller.
-    pub static ref ERR_CANCELED_BY_CALLER:Error = Error::new("connecting canceled by caller".to_owned());
+    #[error("connecting canceled by caller")]
+    CanceledByCaller,
+
+    /// Indicates the agent failed to connect, e.g. every candidate pair failed or checking
+    /// lasted longer than the disconnected and failed timeouts.
+    #[error("the agent failed to connect")]
+    ConnectionFailed,
 
     /// Indicates agent was started twice.
-    pub static ref ERR_MULTIPLE_START:Error = Error::new("attempted to start agent twice".to_owned());
+    #[error("attempted to start agent twice")]
+    MultipleStart,
 
     /// Indicates agent was started with an empty remote ufrag.
-    pub static ref ERR_REMOTE_UFRAG_EMPTY:Error = Error::new("remote ufrag is empty".to_owned());
+    #[error("remote ufrag is empty")]
+    RemoteUfragEmpty,
 
     /// Indicates agent was started with an empty remote pwd.
-    pub static ref ERR_REMOTE_PWD_EMPTY:Error = Error::new("remote pwd is empty".to_owned());
+    #[error("remote pwd is empty")]
+    RemotePwdEmpty,
 
-    /// Indicates agent was started without on_candidate.
-    pub static ref ERR_NO_ON_CANDIDATE_HANDLER:Error = Error::new("no on_candidate provided".to_owned());
+    /// Indicates agent was started without on_candidate nor a subscriber to its events.
+    #[error("no on_candidate provided")]
+    NoOnCandidateHandler,
 
     /// Indicates GatherCandidates has been called multiple times.
-    pub static ref ERR_MULTIPLE_GATHER_ATTEMPTED:Error = Error::new("attempting to gather candidates during gathering state".to_owned());
+    #[error("attempting to gather candidates during gathering state")]
+    MultipleGatherAttempted,
+
+    /// Indicates Restart was called when Agent is in GatheringStateGathering.
+    #[error("ICE Agent can not be restarted when gathering")]
+    RestartWhenGathering,
 
     /// Indicates agent was give TURN URL with an empty Username.
-    pub static ref ERR_USERNAME_EMPTY:Error = Error::new("username is empty".to_owned());
+    #[error("username is empty")]
+    UsernameEmpty,
 
     /// Indicates agent was give TURN URL with an empty Password.
-    pub static ref ERR_PASSWORD_EMPTY:Error = Error::new("password is empty".to_owned());
+    #[error("password is empty")]
+    PasswordEmpty,
 
     /// Indicates we were unable to parse a candidate address.
-    pub static ref ERR_ADDRESS_PARSE_FAILED:Error = Error::new("failed to parse address".to_owned());
+    #[error("failed to parse address")]
+    AddressParseFailed,
 
     /// Indicates that non host candidates were selected for a lite agent.
-    pub static ref ERR_LITE_USING_NON_HOST_CANDIDATES:Error = Error::new("lite agents must only use host candidates".to_owned());
+    #[error("lite agents must only use host candidates")]
+    LiteUsingNonHostCandidates,
 
     /// Indicates that one or more URL was provided to the agent but no host candidate required them.
-    pub static ref ERR_USELESS_URLS_PROVIDED:Error = Error::new("agent does not need URL with selected candidate types".to_owned());
+    #[error("agent does not need URL with selected candidate types")]
+    UselessUrlsProvided,
 
     /// Indicates that the specified NAT1To1IPCandidateType is unsupported.
-    pub static ref ERR_UNSUPPORTED_NAT_1TO1_IP_CANDIDATE_TYPE:Error = Error::new("unsupported 1:1 NAT IP candidate type".to_owned());
+    #[error("unsupported 1:1 NAT IP candidate type")]
+    UnsupportedNat1To1IpCandidateType,
 
     /// Indicates that the given 1:1 NAT IP mapping is invalid.
-    pub static ref ERR_INVALID_NAT_1TO1_IP_MAPPING:Error = Error::new("invalid 1:1 NAT IP mapping".to_owned());
+    #[error("invalid 1:1 NAT IP mapping")]
+    InvalidNat1To1IpMapping,
 
     /// IPNotFound in NAT1To1IPMapping.
-    pub static ref ERR_EXTERNAL_MAPPED_IP_NOT_FOUND:Error = Error::new("external mapped IP not found".to_owned());
+    #[error("external mapped IP not found")]
+    ExternalMappedIpNotFound,
 
     /// Indicates that the mDNS gathering cannot be used along with 1:1 NAT IP mapping for host
     /// candidate.
-    pub static ref ERR_MULTICAST_DNS_WITH_NAT_1TO1_IP_MAPPING:Error = Error::new("mDNS gathering cannot be used with 1:1 NAT IP mapping for host candidate".to_owned());
+    #[error("mDNS gathering cannot be used with 1:1 NAT IP mapping for host candidate")]
+    MulticastDnsWithNat1To1IpMapping,
 
     /// Indicates that 1:1 NAT IP mapping for host candidate is requested, but the host candidate
     /// type is disabled.
-    pub static ref ERR_INEFFECTIVE_NAT_1TO1_IP_MAPPING_HOST:Error = Error::new("1:1 NAT IP mapping for host candidate ineffective".to_owned());
+    #[error("1:1 NAT IP mapping for host candidate ineffective")]
+    IneffectiveNat1To1IpMappingHost,
 
     /// Indicates that 1:1 NAT IP mapping for srflx candidate is requested, but the srflx candidate
     /// type is disabled.
-    pub static ref ERR_INEFFECTIVE_NAT_1TO1_IP_MAPPING_SRFLX:Error = Error::new("1:1 NAT IP mapping for srflx candidate ineffective".to_owned());
+    #[error("1:1 NAT IP mapping for srflx candidate ineffective")]
+    IneffectiveNat1To1IpMappingSrflx,
+
+    /// Indicates that an additional address is not a host or srflx one, belongs to another IP
+    /// family than its local IP, or has a local IP that no interface of the agent has.
+    #[error("invalid additional address")]
+    InvalidAdditionalAddress,
+
+    /// Indicates that the agent, or one of its streams, was configured with zero components.
+    #[error("the agent needs at least one component")]
+    InvalidComponents,
+
+    /// Indicates the reuse socket options along with a socket factory, which binds the UDP
+    /// sockets before the options could be set.
+    #[error("the reuse socket options can't be set on the sockets of a socket factory")]
+    ReuseWithSocketFactory,
+
+    /// Indicates a DSCP value which doesn't fit in its 6 bits.
+    #[error("DSCP values must be at most 63")]
+    InvalidDscp,
+
+    /// Indicates a requested TURN allocation lifetime of zero, which would delete the
+    /// allocations as soon as they are made.
+    #[error("the TURN allocation lifetime must be at least a second")]
+    InvalidTurnAllocationLifetime,
+
+    /// Indicates an RTO backoff of zero, which would have the checks retransmitted right away
+    /// once they were retransmitted a first time.
+    #[error("the RTO backoff must be at least 1")]
+    InvalidRtoBackoff,
+
+    /// Indicates more lost checks to fail over after than a candidate pair keeps track of, with
+    /// which the selected pair would never be failed over.
+    #[error("the lost checks to fail over after must be at most 32")]
+    InvalidFailoverAfterLostChecks,
+
+    /// Indicates no candidate pair of the checklist is made of the given candidates.
+    #[error("no candidate pair of the given candidates")]
+    CandidatePairNotFound,
+
+    /// Indicates a candidate pair which can't be selected, because its check hasn't succeeded
+    /// or, on the controlled side, because the controlling agent hasn't nominated it.
+    #[error("the candidate pair can't be selected: {0}")]
+    CandidatePairNotSelectable(String),
+
+    /// Indicates that the type of service of a candidate can't be set, as its transport is not
+    /// a socket of the host.
+    #[error("the transport of the candidate has no type of service")]
+    TosUnsupported,
+
+    /// Indicates that the agent has no relay candidate to create a TURN permission on.
+    #[error("no relay candidate to create a permission on")]
+    NoRelayCandidate,
+
+    /// Indicates that both the components and the streams of the agent were configured.
+    #[error("components can't be configured along with streams")]
+    ComponentsWithStreams,
+
+    /// Indicates that several components were configured along with a UDP or TCP mux, which
+    /// can't tell the components apart.
+    #[error("muxes only support a single component")]
+    MuxMultipleComponents,
+
+    /// Indicates aggressive nomination was configured along with the strict RFC 8445 mode,
+    /// which deprecates it.
+    #[error("aggressive nomination is not allowed in strict RFC 8445 mode")]
+    AggressiveNominationStrict,
+
+    /// Indicates that a local candidate was added for a stream or component the agent was not
+    /// configured with.
+    #[error("the agent has no such stream or component")]
+    UnknownComponent,
 
     /// Indicates an invalid MulticastDNSHostName.
-    pub static ref ERR_INVALID_MULTICAST_DNSHOST_NAME:Error = Error::new("invalid mDNS HostName, must end with .local and can only contain a single '.'".to_owned());
+    #[error("invalid mDNS HostName, must end with .local and can only contain a single '.'")]
+    InvalidMulticastDnsHostName,
 
-    /// Indicates Restart was called when Agent is in GatheringStateGathering.
-    pub static ref ERR_RESTART_WHEN_GATHERING:Error = Error::new("ICE Agent can not be restarted when gathering".to_owned());
-
-    /// Indicates a run operation was canceled by its individual done.
-    pub static ref ERR_RUN_CANCELED:Error = Error::new("run was canceled by done".to_owned());
+    /// Indicates the UDPMux has been closed.
+    #[error("UDPMux is closed")]
+    UdpMuxClosed,
 
-    /// Initialized Indicates TCPMux is not initialized and that invalidTCPMux is used.
-    pub static ref ERR_TCP_MUX_NOT_INITIALIZED:Error = Error::new("TCPMux is not initialized".to_owned());
+    /// Indicates the TCPMux has been closed.
+    #[error("TCPMux is closed")]
+    TcpMuxClosed,
 
     /// Indicates we already have the connection with same remote addr.
-    pub static ref ERR_TCP_REMOTE_ADDR_ALREADY_EXISTS:Error = Error::new("conn with same remote addr already exists".to_owned());
-
-    pub static ref ERR_SEND_PACKET                      :Error = Error::new("failed to send packet".to_owned());
-    pub static ref ERR_ATTRIBUTE_TOO_SHORT_ICE_CANDIDATE:Error = Error::new("attribute not long enough to be ICE candidate".to_owned());
-    pub static ref ERR_PARSE_COMPONENT                  :Error = Error::new("could not parse component".to_owned());
-    pub static ref ERR_PARSE_PRIORITY                   :Error = Error::new("could not parse priority".to_owned());
-    pub static ref ERR_PARSE_PORT                       :Error = Error::new("could not parse port".to_owned());
-    pub static ref ERR_PARSE_RELATED_ADDR               :Error = Error::new("could not parse related addresses".to_owned());
-    pub static ref ERR_PARSE_TYPE                       :Error = Error::new("could not parse type".to_owned());
-    pub static ref ERR_UNKNOWN_CANDIDATE_TYPE           :Error = Error::new("unknown candidate type".to_owned());
-    pub static ref ERR_GET_XOR_MAPPED_ADDR_RESPONSE     :Error = Error::new("failed to get XOR-MAPPED-ADDRESS response".to_owned());
-    pub static ref ERR_CONNECTION_ADDR_ALREADY_EXIST    :Error = Error::new("connection with same remote address already exists".to_owned());
-    pub static ref ERR_READING_STREAMING_PACKET         :Error = Error::new("error reading streaming packet".to_owned());
-    pub static ref ERR_WRITING                          :Error = Error::new("error writing to".to_owned());
-    pub static ref ERR_CLOSING_CONNECTION               :Error = Error::new("error closing connection".to_owned());
-    pub static ref ERR_DETERMINE_NETWORK_TYPE           :Error = Error::new("unable to determine networkType".to_owned());
-    pub static ref ERR_MISSING_PROTOCOL_SCHEME          :Error = Error::new("missing protocol scheme".to_owned());
-    pub static ref ERR_TOO_MANY_COLONS_ADDR             :Error = Error::new("too many colons in address".to_owned());
-    pub static ref ERR_READ                             :Error = Error::new("unexpected error trying to read".to_owned());
-    pub static ref ERR_UNKNOWN_ROLE                     :Error = Error::new("unknown role".to_owned());
-    pub static ref ERR_MISMATCH_USERNAME                :Error = Error::new("username mismatch".to_owned());
-    pub static ref ERR_ICE_WRITE_STUN_MESSAGE           :Error = Error::new("the ICE conn can't write STUN messages".to_owned());
-    pub static ref ERR_INVALID_URL                      :Error = Error::new("invalid url".to_owned());
-    pub static ref ERR_URL_PARSE_ERROR                  :Error = Error::new("relative URL without a base".to_owned());
+    #[error("conn with same remote addr already exists")]
+    TcpRemoteAddrAlreadyExists,
+
+    /// Indicates the hostname of a STUN or TURN server has no address of the requested family.
+    #[error("unable to resolve host: {0}")]
+    HostUnresolved(String),
+
+    /// Indicates the handshake with an outbound proxy failed.
+    #[error("proxy handshake failed: {0}")]
+    ProxyHandshake(String),
+
+    /// Indicates a remote candidate was added after the remote end-of-candidates was signaled.
+    #[error("remote candidate added after end-of-candidates")]
+    RemoteCandidateAfterEndOfCandidates,
+
+    /// Indicates a candidate attribute has less than the 8 mandatory fields.
+    #[error("attribute not long enough to be ICE candidate ({0})")]
+    AttributeTooShortIceCandidate(usize),
+
+    /// Indicates the component of a candidate attribute is malformed.
+    #[error("could not parse component {0}")]
+    ParseComponent(String),
+
+    /// Indicates the priority of a candidate attribute is malformed.
+    #[error("could not parse priority {0}")]
+    ParsePriority(String),
+
+    /// Indicates the port of a candidate attribute is malformed.
+    #[error("could not parse port {0}")]
+    ParsePort(String),
+
+    /// Indicates the related port of a candidate attribute is malformed.
+    #[error("could not parse related addresses {0}")]
+    ParseRelatedAddr(String),
+
+    /// Indicates the candidate or TCP type of a candidate attribute is malformed.
+    #[error("could not parse type: {0}")]
+    ParseType(String),
+
+    /// Indicates an extension attribute of a candidate attribute is malformed.
+    #[error("could not parse extension attribute {0}")]
+    ParseExtension(String),
+
+    /// Indicates the type of a candidate attribute is not host, srflx, prflx nor relay.
+    #[error("unknown candidate type ({0})")]
+    UnknownCandidateType(String),
+
+    /// Indicates a packet read from a TCP connection is malformed.
+    #[error("error reading streaming packet: {0}")]
+    ReadingStreamingPacket(String),
+
+    /// Indicates the network type of an address could not be determined.
+    #[error("unable to determine networkType")]
+    DetermineNetworkType,
+
+    /// Indicates the USERNAME of an inbound STUN message is not the expected one.
+    #[error("username mismatch expected({expected}) actual({actual})")]
+    MismatchUsername { expected: String, actual: String },
+
+    /// Indicates the application attempted to send a STUN message on the ICE conn.
+    #[error("the ICE conn can't write STUN messages")]
+    IceWriteStunMessage,
+
+    /// Indicates the gateway refused to map a port, with the result code of its response.
+    #[error("the gateway refused the port mapping with result code {0}")]
+    PortMappingRefused(u16),
+
+    /// Indicates a port mapping protocol which only maps IPv4 addresses was asked to map an IPv6 one.
+    #[error("the port mapping protocol doesn't map IPv6 addresses")]
+    PortMappingIpv6,
+
+    /// A background task of the agent panicked or was cancelled.
+    #[error("task failed: {0}")]
+    Task(String),
+
+    /// A STUN message could not be built, decoded or validated.
+    #[error("stun: {0}")]
+    Stun(util::Error),
+
+    /// A TURN client failed, e.g. a relay allocation timed out.
+    #[error("turn: {0}")]
+    Turn(util::Error),
+
+    /// A TURN server answered a request with an error response, e.g. 401 or 403 when it refused
+    /// the credentials, see `IceError::from_turn`.
+    #[error("turn: error response {code}: {reason}")]
+    TurnErrorResponse { code: u16, reason: String },
+
+    /// An IP address or a socket address could not be parsed.
+    #[error(transparent)]
+    AddrParse(#[from] AddrParseError),
+
+    /// A URL could not be parsed.
+    #[error(transparent)]
+    Url(#[from] url::ParseError),
+
+    /// Another error of the networking stack, e.g. of the virtual network.
+    #[error(transparent)]
+    Util(#[from] util::Error),
+
+    /// An I/O error of a socket.
+    #[error(transparent)]
+    Io(Arc<io::Error>),
+}
+
+impl IceError {
+    /// Converts an error of the TURN client. Its error responses are told apart from the other
+    /// failures, as the client only reports them in the text of its errors, e.g. "Allocate error
+    /// response (error 401: Unauthorized)".
+    pub(crate) fn from_turn(err: util::Error) -> Self {
+        let text = err.to_string();
+        let response = text
+            .find("(error ")
+            .map(|start| &text[start + "(error ".len()..])
+            .and_then(|response| {
+                let end = response.find(|c: char| !c.is_ascii_digit())?;
+                let code = response[..end].parse().ok()?;
+                let reason = response[end..]
+                    .trim_start_matches(|c| c == ':' || c == ' ')
+                    .trim_end_matches(')');
+                Some((code, reason.to_owned()))
+            });
+
+        match response {
+            Some((code, reason)) => Self::TurnErrorResponse { code, reason },
+            None => Self::Turn(err),
+        }
+    }
+}
+
+impl From<io::Error> for IceError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(Arc::new(err))
+    }
+}
+
+impl From<IceError> for io::Error {
+    fn from(err: IceError) -> Self {
+        match err {
+            IceError::Io(err) => io::Error::new(err.kind(), err.to_string()),
+            err => io::Error::new(io::ErrorKind::Other, err.to_string()),
+        }
+    }
 }
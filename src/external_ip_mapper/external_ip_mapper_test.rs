@@ -1,7 +1,7 @@
 use super::*;
 
 #[test]
-fn test_external_ip_mapper_validate_ip_string() -> Result<(), Error> {
+fn test_external_ip_mapper_validate_ip_string() -> Result<(), IceError> {
     let ip = validate_ip_string("1.2.3.4")?;
     assert!(ip.is_ipv4(), "should be true");
     assert_eq!("1.2.3.4", ip.to_string(), "should be true");
@@ -17,7 +17,7 @@ fn test_external_ip_mapper_validate_ip_string() -> Result<(), Error> {
 }
 
 #[test]
-fn test_external_ip_mapper_new_external_ip_mapper() -> Result<(), Error> {
+fn test_external_ip_mapper_new_external_ip_mapper() -> Result<(), IceError> {
     // ips being empty should succeed but mapper will still be nil
     let m = ExternalIpMapper::new(CandidateType::Unspecified, &[])?;
     assert!(m.is_none(), "should be none");
@@ -101,7 +101,7 @@ fn test_external_ip_mapper_new_external_ip_mapper() -> Result<(), Error> {
 }
 
 #[test]
-fn test_external_ip_mapper_new_external_ip_mapper_with_explicit_local_ip() -> Result<(), Error> {
+fn test_external_ip_mapper_new_external_ip_mapper_with_explicit_local_ip() -> Result<(), IceError> {
     // IPv4 with  explicit local IP, defaults to CandidateTypeHost
     let m = ExternalIpMapper::new(CandidateType::Unspecified, &["1.2.3.4/10.0.0.1".to_owned()])?
         .unwrap();
@@ -148,7 +148,7 @@ fn test_external_ip_mapper_new_external_ip_mapper_with_explicit_local_ip() -> Re
 }
 
 #[test]
-fn test_external_ip_mapper_new_external_ip_mapper_with_implicit_local_ip() -> Result<(), Error> {
+fn test_external_ip_mapper_new_external_ip_mapper_with_implicit_local_ip() -> Result<(), IceError> {
     // Mixing inpicit and explicit local IPs not allowed
     let result = ExternalIpMapper::new(
         CandidateType::Unspecified,
@@ -167,7 +167,7 @@ fn test_external_ip_mapper_new_external_ip_mapper_with_implicit_local_ip() -> Re
 }
 
 #[test]
-fn test_external_ip_mapper_find_external_ip_without_explicit_local_ip() -> Result<(), Error> {
+fn test_external_ip_mapper_find_external_ip_without_explicit_local_ip() -> Result<(), IceError> {
     // IPv4 with  explicit local IP, defaults to CandidateTypeHost
     let m = ExternalIpMapper::new(
         CandidateType::Unspecified,
@@ -193,7 +193,7 @@ fn test_external_ip_mapper_find_external_ip_without_explicit_local_ip() -> Resul
 }
 
 #[test]
-fn test_external_ip_mapper_find_external_ip_with_explicit_local_ip() -> Result<(), Error> {
+fn test_external_ip_mapper_find_external_ip_with_explicit_local_ip() -> Result<(), IceError> {
     // IPv4 with  explicit local IP, defaults to CandidateTypeHost
     let m = ExternalIpMapper::new(
         CandidateType::Unspecified,
@@ -234,7 +234,7 @@ fn test_external_ip_mapper_find_external_ip_with_explicit_local_ip() -> Result<(
 }
 
 #[test]
-fn test_external_ip_mapper_find_external_ip_with_empty_map() -> Result<(), Error> {
+fn test_external_ip_mapper_find_external_ip_with_empty_map() -> Result<(), IceError> {
     let m = ExternalIpMapper::new(CandidateType::Unspecified, &["1.2.3.4".to_owned()])?.unwrap();
 
     // attempt to find IPv6 that does not exist in the map
@@ -249,3 +249,41 @@ fn test_external_ip_mapper_find_external_ip_with_empty_map() -> Result<(), Error
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_external_ip_mapper_new_external_ip_mapper_with_interface() -> Result<(), IceError> {
+    let m = ExternalIpMapper::new(
+        CandidateType::Unspecified,
+        &["1.2.3.4/eth0".to_owned(), "2200::1/eth0".to_owned()],
+    )?
+    .unwrap();
+    assert_eq!(1, m.ipv4_mapping.iface_map.len(), "should match");
+    assert_eq!(1, m.ipv6_mapping.iface_map.len(), "should match");
+    assert_eq!(0, m.ipv4_mapping.ip_map.len(), "should match");
+
+    // Cannot assign two ext IPs for one interface
+    let result = ExternalIpMapper::new(
+        CandidateType::Unspecified,
+        &["1.2.3.4/eth0".to_owned(), "1.2.3.5/eth0".to_owned()],
+    );
+    assert!(result.is_err(), "should fail");
+
+    // Cannot mix a sole IP with an interface mapping of the same family
+    let result = ExternalIpMapper::new(
+        CandidateType::Unspecified,
+        &["1.2.3.4/eth0".to_owned(), "1.2.3.5".to_owned()],
+    );
+    assert!(result.is_err(), "should fail");
+
+    // Unknown interfaces are rejected when resolved
+    let mut m = ExternalIpMapper::new(
+        CandidateType::Unspecified,
+        &["1.2.3.4/no-such-iface0".to_owned()],
+    )?
+    .unwrap();
+    let net = Arc::new(Net::new(None));
+    let result = m.resolve_interfaces(&net).await;
+    assert!(result.is_err(), "should fail");
+
+    Ok(())
+}
@@ -4,29 +4,37 @@ mod external_ip_mapper_test;
 use crate::candidate::*;
 use crate::errors::*;
 
-use util::Error;
+use util::vnet::net::*;
 
 use std::collections::HashMap;
 use std::net::IpAddr;
+use std::sync::Arc;
 
-pub(crate) fn validate_ip_string(ip_str: &str) -> Result<IpAddr, Error> {
+pub(crate) fn validate_ip_string(ip_str: &str) -> Result<IpAddr, IceError> {
     match ip_str.parse() {
         Ok(ip) => Ok(ip),
-        Err(_) => Err(ERR_INVALID_NAT_1TO1_IP_MAPPING.to_owned()),
+        Err(_) => Err(IceError::InvalidNat1To1IpMapping),
     }
 }
 
+/// Tells whether the local part of a mapping names an interface rather than an IP address, e.g.
+/// "eth0" as opposed to "10.0.0.1" or "fe80::1".
+fn is_interface_name(s: &str) -> bool {
+    s.chars().next().map_or(false, |c| !c.is_ascii_digit()) && !s.contains(':')
+}
+
 /// Holds the mapping of local and external IP address for a particular IP family.
 #[derive(Default, PartialEq, Debug)]
 pub(crate) struct IpMapping {
     ip_sole: Option<IpAddr>, // when non-nil, this is the sole external IP for one local IP assumed
     ip_map: HashMap<String, IpAddr>, // local-to-external IP mapping (k: local, v: external)
+    iface_map: HashMap<String, IpAddr>, // interface-to-external IP mapping, resolved into ip_map
 }
 
 impl IpMapping {
-    pub(crate) fn set_sole_ip(&mut self, ip: IpAddr) -> Result<(), Error> {
-        if self.ip_sole.is_some() || !self.ip_map.is_empty() {
-            return Err(ERR_INVALID_NAT_1TO1_IP_MAPPING.to_owned());
+    pub(crate) fn set_sole_ip(&mut self, ip: IpAddr) -> Result<(), IceError> {
+        if self.ip_sole.is_some() || !self.ip_map.is_empty() || !self.iface_map.is_empty() {
+            return Err(IceError::InvalidNat1To1IpMapping);
         }
 
         self.ip_sole = Some(ip);
@@ -34,16 +42,20 @@ impl IpMapping {
         Ok(())
     }
 
-    pub(crate) fn add_ip_mapping(&mut self, loc_ip: IpAddr, ext_ip: IpAddr) -> Result<(), Error> {
+    pub(crate) fn add_ip_mapping(
+        &mut self,
+        loc_ip: IpAddr,
+        ext_ip: IpAddr,
+    ) -> Result<(), IceError> {
         if self.ip_sole.is_some() {
-            return Err(ERR_INVALID_NAT_1TO1_IP_MAPPING.to_owned());
+            return Err(IceError::InvalidNat1To1IpMapping);
         }
 
         let loc_ip_str = loc_ip.to_string();
 
         // check if dup of local IP
         if self.ip_map.contains_key(&loc_ip_str) {
-            return Err(ERR_INVALID_NAT_1TO1_IP_MAPPING.to_owned());
+            return Err(IceError::InvalidNat1To1IpMapping);
         }
 
         self.ip_map.insert(loc_ip_str, ext_ip);
@@ -51,13 +63,27 @@ impl IpMapping {
         Ok(())
     }
 
-    pub(crate) fn find_external_ip(&self, loc_ip: IpAddr) -> Result<IpAddr, Error> {
+    pub(crate) fn add_iface_mapping(
+        &mut self,
+        iface: &str,
+        ext_ip: IpAddr,
+    ) -> Result<(), IceError> {
+        if self.ip_sole.is_some() || self.iface_map.contains_key(iface) {
+            return Err(IceError::InvalidNat1To1IpMapping);
+        }
+
+        self.iface_map.insert(iface.to_owned(), ext_ip);
+
+        Ok(())
+    }
+
+    pub(crate) fn find_external_ip(&self, loc_ip: IpAddr) -> Result<IpAddr, IceError> {
         if let Some(ip_sole) = &self.ip_sole {
             return Ok(*ip_sole);
         }
 
         self.ip_map.get(&loc_ip.to_string()).map_or_else(
-            || Err(ERR_EXTERNAL_MAPPED_IP_NOT_FOUND.to_owned()),
+            || Err(IceError::ExternalMappedIpNotFound),
             |ext_ip| Ok(*ext_ip),
         )
     }
@@ -74,7 +100,7 @@ impl ExternalIpMapper {
     pub(crate) fn new(
         mut candidate_type: CandidateType,
         ips: &[String],
-    ) -> Result<Option<Self>, Error> {
+    ) -> Result<Option<Self>, IceError> {
         if ips.is_empty() {
             return Ok(None);
         }
@@ -83,7 +109,7 @@ impl ExternalIpMapper {
         } else if candidate_type != CandidateType::Host
             && candidate_type != CandidateType::ServerReflexive
         {
-            return Err(ERR_UNSUPPORTED_NAT_1TO1_IP_CANDIDATE_TYPE.to_owned());
+            return Err(IceError::UnsupportedNat1To1IpCandidateType);
         }
 
         let mut m = Self {
@@ -95,7 +121,7 @@ impl ExternalIpMapper {
         for ext_ip_str in ips {
             let ip_pair: Vec<&str> = ext_ip_str.split('/').collect();
             if ip_pair.is_empty() || ip_pair.len() > 2 {
-                return Err(ERR_INVALID_NAT_1TO1_IP_MAPPING.clone());
+                return Err(IceError::InvalidNat1To1IpMapping);
             }
 
             let ext_ip = validate_ip_string(ip_pair[0])?;
@@ -106,16 +132,25 @@ impl ExternalIpMapper {
                     m.ipv6_mapping.set_sole_ip(ext_ip)?;
                 }
             } else {
+                if is_interface_name(ip_pair[1]) {
+                    if ext_ip.is_ipv4() {
+                        m.ipv4_mapping.add_iface_mapping(ip_pair[1], ext_ip)?;
+                    } else {
+                        m.ipv6_mapping.add_iface_mapping(ip_pair[1], ext_ip)?;
+                    }
+                    continue;
+                }
+
                 let loc_ip = validate_ip_string(ip_pair[1])?;
                 if ext_ip.is_ipv4() {
                     if !loc_ip.is_ipv4() {
-                        return Err(ERR_INVALID_NAT_1TO1_IP_MAPPING.clone());
+                        return Err(IceError::InvalidNat1To1IpMapping);
                     }
 
                     m.ipv4_mapping.add_ip_mapping(loc_ip, ext_ip)?;
                 } else {
                     if loc_ip.is_ipv4() {
-                        return Err(ERR_INVALID_NAT_1TO1_IP_MAPPING.clone());
+                        return Err(IceError::InvalidNat1To1IpMapping);
                     }
 
                     m.ipv6_mapping.add_ip_mapping(loc_ip, ext_ip)?;
@@ -126,7 +161,40 @@ impl ExternalIpMapper {
         Ok(Some(m))
     }
 
-    pub(crate) fn find_external_ip(&self, local_ip_str: &str) -> Result<IpAddr, Error> {
+    /// Replaces the per-interface entries by entries for the addresses of those interfaces which
+    /// belong to the same IP family as the external IP.
+    pub(crate) async fn resolve_interfaces(&mut self, net: &Arc<Net>) -> Result<(), IceError> {
+        if self.ipv4_mapping.iface_map.is_empty() && self.ipv6_mapping.iface_map.is_empty() {
+            return Ok(());
+        }
+
+        let interfaces = net.get_interfaces().await;
+        for mapping in [&mut self.ipv4_mapping, &mut self.ipv6_mapping] {
+            for (iface_name, ext_ip) in std::mem::take(&mut mapping.iface_map) {
+                let iface = interfaces
+                    .iter()
+                    .find(|iface| iface.name() == iface_name)
+                    .ok_or(IceError::InvalidNat1To1IpMapping)?;
+
+                let mut found = false;
+                for ipnet in iface.addrs() {
+                    let loc_ip = ipnet.addr();
+                    if loc_ip.is_ipv4() == ext_ip.is_ipv4() {
+                        mapping.add_ip_mapping(loc_ip, ext_ip)?;
+                        found = true;
+                    }
+                }
+
+                if !found {
+                    return Err(IceError::InvalidNat1To1IpMapping);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn find_external_ip(&self, local_ip_str: &str) -> Result<IpAddr, IceError> {
         let loc_ip = validate_ip_string(local_ip_str)?;
 
         if loc_ip.is_ipv4() {
@@ -0,0 +1,56 @@
+use super::*;
+
+#[test]
+fn test_ice_options_marshal() {
+    let options = IceOptions {
+        trickle: true,
+        renomination: false,
+        ice2: true,
+    };
+    assert_eq!("trickle ice2", options.to_string());
+    assert_eq!("ice-options:trickle ice2", options.marshal());
+    assert_eq!("", IceOptions::default().to_string());
+}
+
+#[test]
+fn test_ice_options_unmarshal() {
+    let expected = IceOptions {
+        trickle: true,
+        renomination: true,
+        ice2: false,
+    };
+    assert_eq!(
+        expected,
+        IceOptions::unmarshal("a=ice-options:trickle renomination")
+    );
+    assert_eq!(
+        expected,
+        IceOptions::unmarshal("ice-options:renomination  trickle")
+    );
+    assert_eq!(expected, IceOptions::from("trickle renomination"));
+    assert_eq!(
+        expected,
+        IceOptions::unmarshal("trickle foo renomination"),
+        "unknown options are ignored"
+    );
+    assert_eq!(IceOptions::default(), IceOptions::unmarshal(""));
+
+    let options = IceOptions {
+        trickle: true,
+        renomination: true,
+        ice2: true,
+    };
+    assert_eq!(options, IceOptions::unmarshal(&options.marshal()));
+}
+
+#[test]
+fn test_ice_options_intersect() {
+    let local = IceOptions {
+        trickle: true,
+        renomination: true,
+        ice2: true,
+    };
+    let remote = IceOptions::unmarshal("trickle");
+    assert_eq!(remote, local.intersect(&remote));
+    assert_eq!(remote, remote.intersect(&local));
+}
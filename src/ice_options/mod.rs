@@ -0,0 +1,89 @@
+#[cfg(test)]
+mod ice_options_test;
+
+use std::fmt;
+
+/// The `trickle` option, see [RFC 8840](https://tools.ietf.org/html/rfc8840).
+pub const ICE_OPTION_TRICKLE: &str = "trickle";
+/// The `renomination` option, see
+/// [draft-thatcher-ice-renomination](https://tools.ietf.org/html/draft-thatcher-ice-renomination).
+pub const ICE_OPTION_RENOMINATION: &str = "renomination";
+/// The `ice2` option, see [RFC 8445 Section 10](https://tools.ietf.org/html/rfc8445#section-10).
+pub const ICE_OPTION_ICE2: &str = "ice2";
+
+/// The options of the `a=ice-options` attribute of a session description, see
+/// [RFC 8839 Section 5.6](https://tools.ietf.org/html/rfc8839#section-5.6).
+/// The default is the empty set, that of a peer which advertises none.
+#[derive(Default, PartialEq, Eq, Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct IceOptions {
+    /// The agent accepts candidates trickled after the session description.
+    pub trickle: bool,
+    /// The agent follows the controlling agent when it nominates another pair.
+    pub renomination: bool,
+    /// The agent implements RFC 8445 rather than RFC 5245.
+    pub ice2: bool,
+}
+
+impl IceOptions {
+    /// Returns the options supported by both `self` and `other`.
+    #[must_use]
+    pub const fn intersect(&self, other: &Self) -> Self {
+        Self {
+            trickle: self.trickle && other.trickle,
+            renomination: self.renomination && other.renomination,
+            ice2: self.ice2 && other.ice2,
+        }
+    }
+
+    /// Returns the attribute advertising the options, e.g. `ice-options:trickle ice2`, to which
+    /// `a=` is prepended in a session description. The attribute must be left out when no
+    /// option is set.
+    #[must_use]
+    pub fn marshal(&self) -> String {
+        format!("ice-options:{}", self)
+    }
+
+    /// Parses the options of an `ice-options` attribute, with or without its `a=ice-options:`
+    /// prefix. The options which are not known are ignored, as RFC 8839 requires.
+    #[must_use]
+    pub fn unmarshal(raw: &str) -> Self {
+        let raw = raw.trim();
+        let raw = raw.strip_prefix("a=").unwrap_or(raw);
+        let raw = raw.strip_prefix("ice-options:").unwrap_or(raw);
+
+        let mut options = Self::default();
+        for option in raw.split_whitespace() {
+            match option {
+                ICE_OPTION_TRICKLE => options.trickle = true,
+                ICE_OPTION_RENOMINATION => options.renomination = true,
+                ICE_OPTION_ICE2 => options.ice2 = true,
+                _ => {}
+            }
+        }
+        options
+    }
+}
+
+impl From<&str> for IceOptions {
+    fn from(raw: &str) -> Self {
+        Self::unmarshal(raw)
+    }
+}
+
+/// Formats the options as the value of an `ice-options` attribute, separated by spaces.
+impl fmt::Display for IceOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let options = [
+            (self.trickle, ICE_OPTION_TRICKLE),
+            (self.renomination, ICE_OPTION_RENOMINATION),
+            (self.ice2, ICE_OPTION_ICE2),
+        ];
+        let options: Vec<&str> = options
+            .iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, option)| *option)
+            .collect();
+        write!(f, "{}", options.join(" "))
+    }
+}
@@ -0,0 +1,56 @@
+use super::*;
+use crate::errors::*;
+
+use std::str::FromStr;
+use util::vnet::router::{Router, RouterConfig};
+
+#[test]
+fn test_interface_change_between() {
+    let a = IpAddr::from_str("10.0.0.1").unwrap();
+    let b = IpAddr::from_str("10.0.0.2").unwrap();
+    let c = IpAddr::from_str("fe80::1").unwrap();
+
+    let change = InterfaceChange::between(&[a, b], &[b, c]);
+    assert_eq!(change.added, vec![c]);
+    assert_eq!(change.removed, vec![a]);
+
+    assert!(InterfaceChange::between(&[a, b], &[b, a]).is_empty());
+}
+
+#[tokio::test]
+async fn test_interface_watcher_reports_added_address() -> Result<(), IceError> {
+    let nw = Arc::new(Net::new(Some(NetConfig::default())));
+    let w = InterfaceWatcher::new(Some(Arc::clone(&nw)), Duration::from_millis(50)).await;
+    let mut changes = w.subscribe();
+    assert_eq!(
+        w.addresses().await,
+        vec![IpAddr::from_str("127.0.0.1")?],
+        "only lo0 has an address"
+    );
+
+    // The router assigns an address to eth0
+    let r = Arc::new(Mutex::new(Router::new(RouterConfig {
+        cidr: "1.2.3.0/24".to_owned(),
+        ..Default::default()
+    })?));
+    let nic = nw.get_nic()?;
+    r.lock().await.add_net(Arc::clone(&nic)).await?;
+    nic.lock().await.set_router(Arc::clone(&r)).await?;
+
+    let change = tokio::time::timeout(Duration::from_secs(5), changes.recv())
+        .await
+        .expect("the change should be reported")
+        .expect("the watcher should be running");
+    assert_eq!(change.added.len(), 1);
+    assert!(change.added[0].to_string().starts_with("1.2.3."));
+    assert!(change.removed.is_empty());
+    assert!(w.addresses().await.contains(&change.added[0]));
+
+    w.close().await;
+    let result = tokio::time::timeout(Duration::from_secs(5), changes.recv())
+        .await
+        .expect("closing should stop the watcher");
+    assert!(result.is_err(), "no more changes should be reported");
+
+    Ok(())
+}
@@ -0,0 +1,189 @@
+#[cfg(test)]
+mod interface_watcher_test;
+
+mod os_notifier;
+
+use os_notifier::OsNotifier;
+
+use util::vnet::net::*;
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::sync::{broadcast, Mutex};
+use tokio::time::Duration;
+
+/// The default interval at which the interfaces are read again, in case a change wasn't
+/// notified by the OS.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The number of changes buffered per subscriber before the oldest ones are dropped.
+const MAX_PENDING_CHANGES: usize = 16;
+
+/// Describes how the local addresses changed between two reads of the interfaces.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct InterfaceChange {
+    /// The addresses which were assigned to an interface since the previous read.
+    pub added: Vec<IpAddr>,
+
+    /// The addresses which are no longer assigned to any interface.
+    pub removed: Vec<IpAddr>,
+}
+
+impl InterfaceChange {
+    /// Returns true if no address was added or removed.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+
+    fn between(previous: &[IpAddr], current: &[IpAddr]) -> Self {
+        Self {
+            added: current
+                .iter()
+                .filter(|ip| !previous.contains(ip))
+                .copied()
+                .collect(),
+            removed: previous
+                .iter()
+                .filter(|ip| !current.contains(ip))
+                .copied()
+                .collect(),
+        }
+    }
+}
+
+/// Watches the addresses of the local interfaces and reports their changes.
+///
+/// The interfaces are read again when the OS notifies a change, through a netlink socket on
+/// Linux and Android, a routing socket on macOS, iOS and the BSDs and IP Helper notifications on
+/// Windows, and at least every poll interval. Other platforms and virtual networks are only
+/// polled. A single
+/// watcher can be shared by several agents through `AgentConfig::interface_watcher`, and
+/// applications can subscribe to it as well.
+pub struct InterfaceWatcher {
+    addresses: Arc<Mutex<Vec<IpAddr>>>,
+    changes_tx: broadcast::Sender<InterfaceChange>,
+    closed_ch_tx: Mutex<Option<broadcast::Sender<()>>>,
+}
+
+impl InterfaceWatcher {
+    /// Starts watching the interfaces of `net`, or the ones of the host when it is none.
+    pub async fn new(net: Option<Arc<Net>>, poll_interval: Duration) -> Arc<Self> {
+        let addresses = read_addresses(&net).await;
+        let (changes_tx, _) = broadcast::channel(MAX_PENDING_CHANGES);
+        let (closed_ch_tx, closed_ch_rx) = broadcast::channel(1);
+
+        let w = Arc::new(Self {
+            addresses: Arc::new(Mutex::new(addresses)),
+            changes_tx,
+            closed_ch_tx: Mutex::new(Some(closed_ch_tx)),
+        });
+
+        // Virtual interfaces are unknown to the OS
+        let notifier = if net.as_ref().map_or(false, |net| net.is_virtual()) {
+            None
+        } else {
+            match OsNotifier::new() {
+                Ok(notifier) => Some(notifier),
+                Err(err) => {
+                    log::debug!("polling the interfaces, no change notifications: {}", err);
+                    None
+                }
+            }
+        };
+
+        let addresses = Arc::clone(&w.addresses);
+        let changes_tx = w.changes_tx.clone();
+        tokio::spawn(async move {
+            Self::watch_loop(
+                net,
+                poll_interval,
+                notifier,
+                addresses,
+                changes_tx,
+                closed_ch_rx,
+            )
+            .await;
+        });
+
+        w
+    }
+
+    /// Returns a receiver of the changes of the local addresses.
+    pub fn subscribe(&self) -> broadcast::Receiver<InterfaceChange> {
+        self.changes_tx.subscribe()
+    }
+
+    /// Returns the addresses of the local interfaces as of the last read.
+    pub async fn addresses(&self) -> Vec<IpAddr> {
+        self.addresses.lock().await.clone()
+    }
+
+    /// Stops watching the interfaces. The subscribers' receivers are closed once the watch loop
+    /// has stopped.
+    pub async fn close(&self) {
+        let mut closed_ch_tx = self.closed_ch_tx.lock().await;
+        closed_ch_tx.take();
+    }
+
+    async fn watch_loop(
+        net: Option<Arc<Net>>,
+        poll_interval: Duration,
+        mut notifier: Option<OsNotifier>,
+        addresses: Arc<Mutex<Vec<IpAddr>>>,
+        changes_tx: broadcast::Sender<InterfaceChange>,
+        mut closed_ch_rx: broadcast::Receiver<()>,
+    ) {
+        loop {
+            let notified = async {
+                match &mut notifier {
+                    Some(notifier) => notifier.changed().await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            let result = tokio::select! {
+                _ = tokio::time::sleep(poll_interval) => Ok(()),
+                result = notified => result,
+                _ = closed_ch_rx.recv() => return,
+            };
+            if let Err(err) = result {
+                warn_event!(error = err; "stopped receiving interface change notifications");
+                notifier = None;
+            }
+
+            let current = read_addresses(&net).await;
+            let change = {
+                let mut addresses = addresses.lock().await;
+                let change = InterfaceChange::between(&addresses, &current);
+                *addresses = current;
+                change
+            };
+
+            if !change.is_empty() {
+                log::debug!("local addresses changed: {:?}", change);
+                let _ = changes_tx.send(change);
+            }
+        }
+    }
+}
+
+/// Reads the addresses of the interfaces of `net`. The interfaces of the host are captured when
+/// a `Net` is created, so a new one is needed to see their changes.
+pub(crate) async fn read_addresses(net: &Option<Arc<Net>>) -> Vec<IpAddr> {
+    let interfaces = match net {
+        Some(net) if net.is_virtual() => net.get_interfaces().await,
+        _ => Net::new(None).get_interfaces().await,
+    };
+
+    let mut addresses = vec![];
+    for iface in interfaces {
+        for ipnet in iface.addrs() {
+            if !addresses.contains(&ipnet.addr()) {
+                addresses.push(ipnet.addr());
+            }
+        }
+    }
+
+    addresses
+}
@@ -0,0 +1,262 @@
+//! Notifications of the OS about changes of the local addresses, which wake up the
+//! `InterfaceWatcher` instead of waiting for its next poll: a netlink socket on Linux and
+//! Android, a routing socket on macOS, iOS and the BSDs, and `NotifyUnicastIpAddressChange` on
+//! Windows. The messages themselves aren't parsed, the watcher reads the interfaces again
+//! anyway.
+
+use std::io;
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "dragonfly"
+))]
+mod imp {
+    use super::*;
+
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use tokio::io::unix::AsyncFd;
+
+    /// The size of the buffer used to drain the notifications.
+    const RECEIVE_BUFFER_SIZE: usize = 8192;
+
+    /// A non-blocking socket which becomes readable when the local addresses change.
+    struct ChangeSocket(RawFd);
+
+    impl AsRawFd for ChangeSocket {
+        fn as_raw_fd(&self) -> RawFd {
+            self.0
+        }
+    }
+
+    impl Drop for ChangeSocket {
+        fn drop(&mut self) {
+            unsafe {
+                libc::close(self.0);
+            }
+        }
+    }
+
+    /// Subscribes to the address and link changes of the routing netlink socket.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    fn open_change_socket() -> io::Result<ChangeSocket> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = ChangeSocket(fd);
+
+        let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups =
+            (libc::RTMGRP_LINK | libc::RTMGRP_IPV4_IFADDR | libc::RTMGRP_IPV6_IFADDR) as u32;
+        let result = unsafe {
+            libc::bind(
+                fd,
+                (&addr as *const libc::sockaddr_nl).cast::<libc::sockaddr>(),
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(socket)
+    }
+
+    /// Opens a routing socket, which receives every change of the routing tables, including
+    /// the addresses added to or removed from the interfaces.
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn open_change_socket() -> io::Result<ChangeSocket> {
+        let fd = unsafe { libc::socket(libc::PF_ROUTE, libc::SOCK_RAW, libc::AF_UNSPEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(ChangeSocket(fd))
+    }
+
+    fn set_nonblocking(socket: &ChangeSocket) -> io::Result<()> {
+        unsafe {
+            let flags = libc::fcntl(socket.0, libc::F_GETFL);
+            if flags < 0 || libc::fcntl(socket.0, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) struct OsNotifier {
+        socket: AsyncFd<ChangeSocket>,
+        buffer: Vec<u8>,
+    }
+
+    impl OsNotifier {
+        pub(crate) fn new() -> io::Result<Self> {
+            let socket = open_change_socket()?;
+            set_nonblocking(&socket)?;
+
+            Ok(Self {
+                socket: AsyncFd::new(socket)?,
+                buffer: vec![0_u8; RECEIVE_BUFFER_SIZE],
+            })
+        }
+
+        /// Waits until the OS reports a change, consuming every pending notification.
+        pub(crate) async fn changed(&mut self) -> io::Result<()> {
+            let mut guard = self.socket.readable().await?;
+            loop {
+                let n = unsafe {
+                    libc::recv(
+                        guard.get_inner().0,
+                        self.buffer.as_mut_ptr().cast::<libc::c_void>(),
+                        self.buffer.len(),
+                        0,
+                    )
+                };
+                if n < 0 {
+                    let err = io::Error::last_os_error();
+                    if err.kind() == io::ErrorKind::WouldBlock {
+                        guard.clear_ready();
+                        return Ok(());
+                    }
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use super::*;
+
+    use std::ffi::c_void;
+    use tokio::sync::mpsc;
+    use windows_sys::Win32::Foundation::{HANDLE, NO_ERROR};
+    use windows_sys::Win32::NetworkManagement::IpHelper::{
+        CancelMibChangeNotify2, NotifyUnicastIpAddressChange, MIB_NOTIFICATION_TYPE,
+        MIB_UNICASTIPADDRESS_ROW,
+    };
+    use windows_sys::Win32::Networking::WinSock::AF_UNSPEC;
+
+    /// Called by the OS on one of its threads each time a unicast address is added, removed or
+    /// changed.
+    unsafe extern "system" fn on_address_change(
+        context: *const c_void,
+        _row: *const MIB_UNICASTIPADDRESS_ROW,
+        _notification_type: MIB_NOTIFICATION_TYPE,
+    ) {
+        let changes_tx = &*context.cast::<mpsc::UnboundedSender<()>>();
+        let _ = changes_tx.send(());
+    }
+
+    pub(crate) struct OsNotifier {
+        handle: HANDLE,
+        // Owned by the notifier, the OS holds a pointer to it until the notification is
+        // cancelled
+        changes_tx: *mut mpsc::UnboundedSender<()>,
+        changes_rx: mpsc::UnboundedReceiver<()>,
+    }
+
+    // The handle and the sender are only used by the OS callback and on drop
+    unsafe impl Send for OsNotifier {}
+
+    impl OsNotifier {
+        pub(crate) fn new() -> io::Result<Self> {
+            let (changes_tx, changes_rx) = mpsc::unbounded_channel();
+            let changes_tx = Box::into_raw(Box::new(changes_tx));
+
+            let mut handle: HANDLE = std::ptr::null_mut();
+            let result = unsafe {
+                NotifyUnicastIpAddressChange(
+                    AF_UNSPEC,
+                    Some(on_address_change),
+                    changes_tx.cast::<c_void>(),
+                    0,
+                    &mut handle,
+                )
+            };
+            if result != NO_ERROR {
+                drop(unsafe { Box::from_raw(changes_tx) });
+                #[allow(clippy::cast_possible_wrap)]
+                return Err(io::Error::from_raw_os_error(result as i32));
+            }
+
+            Ok(Self {
+                handle,
+                changes_tx,
+                changes_rx,
+            })
+        }
+
+        /// Waits until the OS reports a change, consuming every pending notification.
+        pub(crate) async fn changed(&mut self) -> io::Result<()> {
+            if self.changes_rx.recv().await.is_none() {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "address change notifications stopped",
+                ));
+            }
+            while self.changes_rx.try_recv().is_ok() {}
+
+            Ok(())
+        }
+    }
+
+    impl Drop for OsNotifier {
+        fn drop(&mut self) {
+            // Waits for the callbacks in progress, none is made afterwards
+            unsafe {
+                CancelMibChangeNotify2(self.handle);
+                drop(Box::from_raw(self.changes_tx));
+            }
+        }
+    }
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "dragonfly",
+    windows
+)))]
+mod imp {
+    use super::*;
+
+    /// No notifications are available on this platform, the watcher polls instead.
+    pub(crate) struct OsNotifier;
+
+    impl OsNotifier {
+        pub(crate) fn new() -> io::Result<Self> {
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                "interface change notifications are not supported on this platform",
+            ))
+        }
+
+        pub(crate) async fn changed(&mut self) -> io::Result<()> {
+            std::future::pending().await
+        }
+    }
+}
+
+pub(crate) use imp::OsNotifier;
@@ -16,20 +16,36 @@
 #![allow(dead_code)]
 
 #[macro_use]
-extern crate lazy_static;
+mod diagnostics;
 
 pub mod agent;
+pub mod batch_conn;
 pub mod candidate;
 pub mod control;
 pub mod errors;
 pub mod external_ip_mapper;
+pub mod ice_options;
+pub mod interface_watcher;
 pub mod mdns;
+pub mod metrics;
 pub mod network_type;
+pub mod port_mapping;
 pub mod priority;
+pub mod proxy;
 mod rand;
+pub mod relay_provider;
+pub mod resolver;
+pub mod socket_factory;
 pub mod state;
 pub mod stats;
+mod stun_conn;
+pub mod tcp_mux;
 pub mod tcp_type;
+pub mod tls_dialer;
+mod turn_allocation;
+pub mod turn_credentials;
+pub mod udp_mux;
 pub mod url;
 pub mod use_candidate;
 mod util;
+mod wildcard_conn;
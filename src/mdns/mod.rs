@@ -1,6 +1,8 @@
 #[cfg(test)]
 mod mdns_test;
 
+use crate::errors::*;
+
 use mdns::config::*;
 use mdns::conn::*;
 
@@ -9,7 +11,6 @@ use uuid::Uuid;
 use std::net::SocketAddr;
 use std::str::FromStr;
 use std::sync::Arc;
-use util::Error;
 
 /// Represents the different Multicast modes that ICE can run.
 #[derive(PartialEq, Debug, Copy, Clone)]
@@ -42,7 +43,7 @@ pub(crate) fn generate_multicast_dns_name() -> String {
 pub(crate) fn create_multicast_dns(
     mdns_mode: MulticastDnsMode,
     mdns_name: &str,
-) -> Result<Option<Arc<DnsConn>>, Error> {
+) -> Result<Option<Arc<DnsConn>>, IceError> {
     if mdns_mode == MulticastDnsMode::Disabled {
         return Ok(None);
     }
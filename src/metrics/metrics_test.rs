@@ -0,0 +1,136 @@
+use super::*;
+
+use ::metrics::{Counter, Gauge, Histogram, Key, KeyName, Metadata, Recorder, SharedString, Unit};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Keeps the counters and gauges registered by the agent, keyed by their name and labels.
+#[derive(Default)]
+struct TestRecorder {
+    counters: Mutex<HashMap<String, Arc<AtomicU64>>>,
+    gauges: Mutex<HashMap<String, Arc<AtomicU64>>>,
+    described: Mutex<Vec<String>>,
+}
+
+impl TestRecorder {
+    fn counter(&self, key: &str) -> u64 {
+        self.counters
+            .lock()
+            .unwrap()
+            .get(key)
+            .map_or(0, |counter| counter.load(Ordering::SeqCst))
+    }
+
+    fn gauge(&self, key: &str) -> Option<f64> {
+        self.gauges
+            .lock()
+            .unwrap()
+            .get(key)
+            .map(|gauge| f64::from_bits(gauge.load(Ordering::SeqCst)))
+    }
+}
+
+/// Formats a key as "name{label=value,...}".
+fn key_string(key: &Key) -> String {
+    let labels: Vec<String> = key
+        .labels()
+        .map(|label| format!("{}={}", label.key(), label.value()))
+        .collect();
+    format!("{}{{{}}}", key.name(), labels.join(","))
+}
+
+impl Recorder for TestRecorder {
+    fn describe_counter(&self, key: KeyName, _unit: Option<Unit>, _description: SharedString) {
+        self.described.lock().unwrap().push(key.as_str().to_owned());
+    }
+
+    fn describe_gauge(&self, key: KeyName, _unit: Option<Unit>, _description: SharedString) {
+        self.described.lock().unwrap().push(key.as_str().to_owned());
+    }
+
+    fn describe_histogram(&self, key: KeyName, _unit: Option<Unit>, _description: SharedString) {
+        self.described.lock().unwrap().push(key.as_str().to_owned());
+    }
+
+    fn register_counter(&self, key: &Key, _metadata: &Metadata<'_>) -> Counter {
+        let mut counters = self.counters.lock().unwrap();
+        Counter::from_arc(Arc::clone(counters.entry(key_string(key)).or_default()))
+    }
+
+    fn register_gauge(&self, key: &Key, _metadata: &Metadata<'_>) -> Gauge {
+        let mut gauges = self.gauges.lock().unwrap();
+        Gauge::from_arc(Arc::clone(gauges.entry(key_string(key)).or_default()))
+    }
+
+    fn register_histogram(&self, _key: &Key, _metadata: &Metadata<'_>) -> Histogram {
+        Histogram::noop()
+    }
+}
+
+#[test]
+fn test_counters_are_labelled() {
+    let recorder = TestRecorder::default();
+    ::metrics::with_local_recorder(&recorder, || {
+        candidate_gathered(CandidateType::Host);
+        candidate_gathered(CandidateType::Host);
+        candidate_gathered(CandidateType::Relay);
+        connection_state_changed(ConnectionState::Connected);
+        check_sent();
+        check_failed();
+        bytes_sent(100);
+        bytes_received(40);
+        bytes_received(2);
+    });
+
+    assert_eq!(
+        recorder.counter("ice_candidates_gathered_total{type=host}"),
+        2
+    );
+    assert_eq!(
+        recorder.counter("ice_candidates_gathered_total{type=relay}"),
+        1
+    );
+    assert_eq!(
+        recorder.counter("ice_connection_state_transitions_total{state=Connected}"),
+        1
+    );
+    assert_eq!(recorder.counter("ice_checks_sent_total{}"), 1);
+    assert_eq!(recorder.counter("ice_checks_failed_total{}"), 1);
+    assert_eq!(recorder.counter("ice_bytes_sent_total{}"), 100);
+    assert_eq!(recorder.counter("ice_bytes_received_total{}"), 42);
+}
+
+#[test]
+fn test_selected_pair_rtt_is_a_gauge_in_seconds() {
+    let recorder = TestRecorder::default();
+    ::metrics::with_local_recorder(&recorder, || {
+        selected_pair_rtt(Duration::from_millis(250));
+        selected_pair_rtt(Duration::from_millis(30));
+    });
+
+    let rtt = recorder
+        .gauge("ice_selected_pair_rtt_seconds{}")
+        .expect("the gauge should be registered");
+    assert!((rtt - 0.03).abs() < f64::EPSILON, "{}", rtt);
+}
+
+#[test]
+fn test_describe() {
+    let recorder = TestRecorder::default();
+    ::metrics::with_local_recorder(&recorder, describe);
+
+    let mut described = recorder.described.lock().unwrap().clone();
+    described.sort();
+    let mut expected = vec![
+        CANDIDATES_GATHERED,
+        CHECKS_SENT,
+        CHECKS_FAILED,
+        CONNECTION_STATE_TRANSITIONS,
+        BYTES_SENT,
+        BYTES_RECEIVED,
+        SELECTED_PAIR_RTT,
+    ];
+    expected.sort_unstable();
+    assert_eq!(described, expected);
+}
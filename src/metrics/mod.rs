@@ -0,0 +1,96 @@
+//! Records the activity of the agents through the `metrics` facade, with the `metrics` feature.
+//! The metrics are emitted to the recorder the application installs, e.g. the Prometheus exporter
+//! of `metrics-exporter-prometheus`, and are not recorded without the feature.
+
+#[cfg(all(test, feature = "metrics"))]
+mod metrics_test;
+
+use crate::candidate::CandidateType;
+use crate::state::ConnectionState;
+
+use std::time::Duration;
+
+/// The number of local candidates gathered, labelled by `type`.
+pub const CANDIDATES_GATHERED: &str = "ice_candidates_gathered_total";
+/// The number of connectivity check and consent requests sent.
+pub const CHECKS_SENT: &str = "ice_checks_sent_total";
+/// The number of connectivity check and consent requests which timed out without a response.
+pub const CHECKS_FAILED: &str = "ice_checks_failed_total";
+/// The number of transitions of the connection state, labelled by the new `state`.
+pub const CONNECTION_STATE_TRANSITIONS: &str = "ice_connection_state_transitions_total";
+/// The number of bytes of application data sent on candidate pairs.
+pub const BYTES_SENT: &str = "ice_bytes_sent_total";
+/// The number of bytes of application data received on candidate pairs.
+pub const BYTES_RECEIVED: &str = "ice_bytes_received_total";
+/// The latest round trip time, in seconds, measured on a nominated pair.
+pub const SELECTED_PAIR_RTT: &str = "ice_selected_pair_rtt_seconds";
+
+/// Describes the metrics of the agents to the installed recorder, so that exporters can publish
+/// their help text and unit. Call it once the recorder has been installed.
+#[cfg(feature = "metrics")]
+pub fn describe() {
+    use ::metrics::{describe_counter, describe_gauge, Unit};
+
+    describe_counter!(CANDIDATES_GATHERED, "Local candidates gathered, by type");
+    describe_counter!(CHECKS_SENT, "Connectivity check and consent requests sent");
+    describe_counter!(
+        CHECKS_FAILED,
+        "Connectivity check and consent requests which timed out"
+    );
+    describe_counter!(
+        CONNECTION_STATE_TRANSITIONS,
+        "Transitions of the connection state, by new state"
+    );
+    describe_counter!(BYTES_SENT, Unit::Bytes, "Application data sent");
+    describe_counter!(BYTES_RECEIVED, Unit::Bytes, "Application data received");
+    describe_gauge!(
+        SELECTED_PAIR_RTT,
+        Unit::Seconds,
+        "Latest round trip time of a nominated pair"
+    );
+}
+
+pub(crate) fn candidate_gathered(candidate_type: CandidateType) {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!(CANDIDATES_GATHERED, "type" => candidate_type.to_string()).increment(1);
+    #[cfg(not(feature = "metrics"))]
+    let _ = candidate_type;
+}
+
+pub(crate) fn check_sent() {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!(CHECKS_SENT).increment(1);
+}
+
+pub(crate) fn check_failed() {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!(CHECKS_FAILED).increment(1);
+}
+
+pub(crate) fn connection_state_changed(state: ConnectionState) {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!(CONNECTION_STATE_TRANSITIONS, "state" => state.to_string()).increment(1);
+    #[cfg(not(feature = "metrics"))]
+    let _ = state;
+}
+
+pub(crate) fn bytes_sent(n: usize) {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!(BYTES_SENT).increment(n as u64);
+    #[cfg(not(feature = "metrics"))]
+    let _ = n;
+}
+
+pub(crate) fn bytes_received(n: usize) {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!(BYTES_RECEIVED).increment(n as u64);
+    #[cfg(not(feature = "metrics"))]
+    let _ = n;
+}
+
+pub(crate) fn selected_pair_rtt(rtt: Duration) {
+    #[cfg(feature = "metrics")]
+    ::metrics::gauge!(SELECTED_PAIR_RTT).set(rtt.as_secs_f64());
+    #[cfg(not(feature = "metrics"))]
+    let _ = rtt;
+}
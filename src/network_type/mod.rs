@@ -3,13 +3,14 @@ mod network_type_test;
 
 use crate::errors::*;
 
-use util::Error;
-
 use std::fmt;
 use std::net::IpAddr;
 
 pub(crate) const UDP: &str = "udp";
 pub(crate) const TCP: &str = "tcp";
+/// The relay protocols of relay candidates allocated over `turns:` URLs.
+pub(crate) const TLS: &str = "tls";
+pub(crate) const DTLS: &str = "dtls";
 
 #[must_use]
 pub fn supported_network_types() -> Vec<NetworkType> {
@@ -23,6 +24,11 @@ pub fn supported_network_types() -> Vec<NetworkType> {
 
 /// Represents the type of network.
 #[derive(PartialEq, Debug, Copy, Clone, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
 pub enum NetworkType {
     Unspecified,
 
@@ -122,7 +128,7 @@ impl NetworkType {
 }
 
 /// Determines the type of network based on the short network string and an IP address.
-pub(crate) fn determine_network_type(network: &str, ip: &IpAddr) -> Result<NetworkType, Error> {
+pub(crate) fn determine_network_type(network: &str, ip: &IpAddr) -> Result<NetworkType, IceError> {
     let ipv4 = ip.is_ipv4();
     let net = network.to_lowercase();
     if net.starts_with(UDP) {
@@ -138,6 +144,6 @@ pub(crate) fn determine_network_type(network: &str, ip: &IpAddr) -> Result<Netwo
             Ok(NetworkType::Tcp6)
         }
     } else {
-        Err(ERR_DETERMINE_NETWORK_TYPE.to_owned())
+        Err(IceError::DetermineNetworkType)
     }
 }
@@ -0,0 +1,136 @@
+#[cfg(all(test, feature = "port-mapping"))]
+mod port_mapping_test;
+
+#[cfg(feature = "port-mapping")]
+mod nat_pmp;
+#[cfg(feature = "port-mapping")]
+mod pcp;
+
+#[cfg(feature = "port-mapping")]
+pub use nat_pmp::NatPmpMapper;
+#[cfg(feature = "port-mapping")]
+pub use pcp::PcpMapper;
+
+use crate::errors::*;
+use crate::socket_factory::{bind_udp, SocketFactory};
+
+use async_trait::async_trait;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::time::Duration;
+use util::vnet::net::Net;
+use util::Conn;
+
+/// The port of the gateway NAT-PMP and PCP requests are sent to.
+pub const PORT_MAPPING_SERVER_PORT: u16 = 5351;
+
+/// The lifetime of the mappings requested by the agent, which refreshes them halfway through.
+pub(crate) const PORT_MAPPING_LIFETIME: Duration = Duration::from_secs(7200);
+
+/// The shortest interval the agent refreshes a mapping at, however short its lifetime, so that a
+/// gateway granting a lifetime of 0 isn't asked again in a tight loop.
+pub(crate) const PORT_MAPPING_MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A port mapping created by the local gateway.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PortMapping {
+    /// The public address the gateway forwards to the local socket.
+    pub external: SocketAddr,
+
+    /// How long the gateway keeps the mapping, unless it is refreshed.
+    pub lifetime: Duration,
+}
+
+/// Binds the sockets the port mappers talk to the gateway from like the other sockets of the
+/// agent, on its virtual network when it uses one and with its socket factory otherwise, see
+/// `AgentConfig::net` and `AgentConfig::socket_factory`.
+#[derive(Clone)]
+pub struct PortMappingSockets {
+    net: Arc<Net>,
+    socket_factory: Arc<dyn SocketFactory + Send + Sync>,
+}
+
+impl PortMappingSockets {
+    /// Returns the sockets of `net`, or of `socket_factory` unless `net` is virtual.
+    #[must_use]
+    pub fn new(net: Arc<Net>, socket_factory: Arc<dyn SocketFactory + Send + Sync>) -> Self {
+        Self {
+            net,
+            socket_factory,
+        }
+    }
+
+    /// Returns a UDP socket bound to `local_ip` and any port.
+    pub async fn bind_udp(
+        &self,
+        local_ip: IpAddr,
+    ) -> Result<Arc<dyn Conn + Send + Sync>, IceError> {
+        bind_udp(
+            &self.net,
+            &self.socket_factory,
+            SocketAddr::new(local_ip, 0),
+        )
+        .await
+    }
+}
+
+/// Asks the local gateway to forward a public UDP port to a socket of the agent, e.g. with
+/// NAT-PMP or PCP. The agent gathers a server reflexive candidate on the mapped address of each
+/// of its local addresses, which peers reach even when the NAT maps STUN requests per
+/// destination. The NAT-PMP and PCP implementations come with the `port-mapping` feature.
+///
+/// UPnP IGD is not implemented by the crate, as it takes an SSDP discovery and SOAP requests over
+/// HTTP to the device it finds. Gateways which only speak it can be used by implementing the
+/// trait with an IGD client.
+#[async_trait]
+pub trait PortMapper {
+    /// Maps a public UDP port to `local`, the address a socket of the agent is bound to, for
+    /// `lifetime`, and returns the mapping granted by the gateway. The requests are sent from
+    /// `sockets`. The agent calls it again with the same address to refresh the mapping.
+    async fn map_udp(
+        &self,
+        sockets: &PortMappingSockets,
+        local: SocketAddr,
+        lifetime: Duration,
+    ) -> Result<PortMapping, IceError>;
+
+    /// Deletes the mapping of `local`, once its candidate is closed.
+    async fn unmap_udp(
+        &self,
+        sockets: &PortMappingSockets,
+        local: SocketAddr,
+    ) -> Result<(), IceError>;
+}
+
+/// Sends `request` to `gateway` from a socket of `sockets` bound to `local_ip`, and returns the
+/// first response `is_response` accepts. The request is retransmitted after 250 milliseconds,
+/// doubled after every retransmission as RFC 6886 and RFC 6887 recommend, and given up on after
+/// 4 attempts.
+#[cfg(feature = "port-mapping")]
+pub(crate) async fn transact(
+    sockets: &PortMappingSockets,
+    local_ip: IpAddr,
+    gateway: SocketAddr,
+    request: &[u8],
+    is_response: impl Fn(&[u8]) -> bool,
+) -> Result<Vec<u8>, IceError> {
+    const ATTEMPTS: u32 = 4;
+
+    let conn = sockets.bind_udp(local_ip).await?;
+    let mut timeout = Duration::from_millis(250);
+    let mut buf = [0_u8; 1100];
+    for _ in 0..ATTEMPTS {
+        conn.send_to(request, gateway).await?;
+
+        let deadline = tokio::time::Instant::now() + timeout;
+        while let Ok(result) = tokio::time::timeout_at(deadline, conn.recv_from(&mut buf)).await {
+            let (n, from) = result?;
+            if from == gateway && is_response(&buf[..n]) {
+                return Ok(buf[..n].to_vec());
+            }
+        }
+        timeout *= 2;
+    }
+
+    Err(IceError::Timeout("waiting for the port mapping response"))
+}
@@ -0,0 +1,113 @@
+use super::*;
+
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr};
+
+const VERSION: u8 = 0;
+const OPCODE_EXTERNAL_ADDRESS: u8 = 0;
+const OPCODE_MAP_UDP: u8 = 1;
+const OPCODE_RESPONSE: u8 = 128;
+
+/// Maps ports with NAT-PMP, see [RFC 6886](https://tools.ietf.org/html/rfc6886). It only maps
+/// IPv4 addresses.
+#[derive(Debug, Copy, Clone)]
+pub struct NatPmpMapper {
+    gateway: SocketAddr,
+}
+
+impl NatPmpMapper {
+    /// Returns a mapper sending its requests to `gateway`, the default gateway of the host and
+    /// `PORT_MAPPING_SERVER_PORT`, e.g. `192.168.1.1:5351`.
+    #[must_use]
+    pub const fn new(gateway: SocketAddr) -> Self {
+        Self { gateway }
+    }
+
+    /// Sends `request` and returns the response to its opcode, once its result code is checked.
+    async fn request(
+        &self,
+        sockets: &PortMappingSockets,
+        local_ip: IpAddr,
+        request: &[u8],
+        response_len: usize,
+        is_response: impl Fn(&[u8]) -> bool,
+    ) -> Result<Vec<u8>, IceError> {
+        let opcode = request[1];
+        let response = transact(sockets, local_ip, self.gateway, request, |response| {
+            response.len() >= response_len
+                && response[0] == VERSION
+                && response[1] == OPCODE_RESPONSE + opcode
+                && is_response(response)
+        })
+        .await?;
+
+        let result_code = u16::from_be_bytes([response[2], response[3]]);
+        if result_code != 0 {
+            return Err(IceError::PortMappingRefused(result_code));
+        }
+        Ok(response)
+    }
+
+    async fn map(
+        &self,
+        sockets: &PortMappingSockets,
+        local: SocketAddr,
+        external_port: u16,
+        lifetime: Duration,
+    ) -> Result<Vec<u8>, IceError> {
+        if local.is_ipv6() {
+            return Err(IceError::PortMappingIpv6);
+        }
+
+        let lifetime = u32::try_from(lifetime.as_secs()).unwrap_or(u32::MAX);
+        let mut request = vec![VERSION, OPCODE_MAP_UDP, 0, 0];
+        request.extend_from_slice(&local.port().to_be_bytes());
+        request.extend_from_slice(&external_port.to_be_bytes());
+        request.extend_from_slice(&lifetime.to_be_bytes());
+
+        self.request(sockets, local.ip(), &request, 16, |response| {
+            response[8..10] == local.port().to_be_bytes()
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl PortMapper for NatPmpMapper {
+    async fn map_udp(
+        &self,
+        sockets: &PortMappingSockets,
+        local: SocketAddr,
+        lifetime: Duration,
+    ) -> Result<PortMapping, IceError> {
+        let response = self.map(sockets, local, local.port(), lifetime).await?;
+        let external_port = u16::from_be_bytes([response[10], response[11]]);
+        let lifetime = u32::from_be_bytes([response[12], response[13], response[14], response[15]]);
+
+        // The mapping response doesn't tell the public address, which is asked separately
+        let response = self
+            .request(
+                sockets,
+                local.ip(),
+                &[VERSION, OPCODE_EXTERNAL_ADDRESS],
+                12,
+                |_| true,
+            )
+            .await?;
+        let external_ip = Ipv4Addr::new(response[8], response[9], response[10], response[11]);
+
+        Ok(PortMapping {
+            external: SocketAddr::new(external_ip.into(), external_port),
+            lifetime: Duration::from_secs(u64::from(lifetime)),
+        })
+    }
+
+    async fn unmap_udp(
+        &self,
+        sockets: &PortMappingSockets,
+        local: SocketAddr,
+    ) -> Result<(), IceError> {
+        self.map(sockets, local, 0, Duration::from_secs(0)).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+use super::*;
+
+use rand::Rng;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Mutex;
+
+const VERSION: u8 = 2;
+const OPCODE_MAP: u8 = 1;
+const RESPONSE_BIT: u8 = 0x80;
+const PROTOCOL_UDP: u8 = 17;
+const NONCE_LEN: usize = 12;
+const MAP_RESPONSE_LEN: usize = 60;
+
+/// Maps ports with PCP, see [RFC 6887](https://tools.ietf.org/html/rfc6887), the successor of
+/// NAT-PMP, which also opens pinholes in IPv6 firewalls.
+#[derive(Debug)]
+pub struct PcpMapper {
+    gateway: SocketAddr,
+    // The nonce of the mapping of each local address, which its refreshes and deletion repeat
+    nonces: Mutex<HashMap<SocketAddr, [u8; NONCE_LEN]>>,
+}
+
+impl PcpMapper {
+    /// Returns a mapper sending its requests to `gateway`, the default gateway of the host and
+    /// `PORT_MAPPING_SERVER_PORT`, e.g. `192.168.1.1:5351`.
+    #[must_use]
+    pub fn new(gateway: SocketAddr) -> Self {
+        Self {
+            gateway,
+            nonces: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn map(
+        &self,
+        sockets: &PortMappingSockets,
+        local: SocketAddr,
+        nonce: [u8; NONCE_LEN],
+        lifetime: Duration,
+    ) -> Result<Vec<u8>, IceError> {
+        // Addresses are all carried as IPv6 ones, IPv4 ones being mapped, including the external
+        // address, which is unspecified to let the gateway choose it
+        let (client_ip, suggested_ip) = match local.ip() {
+            IpAddr::V4(ip) => (ip.to_ipv6_mapped(), Ipv4Addr::UNSPECIFIED.to_ipv6_mapped()),
+            IpAddr::V6(ip) => (ip, Ipv6Addr::UNSPECIFIED),
+        };
+
+        let lifetime = u32::try_from(lifetime.as_secs()).unwrap_or(u32::MAX);
+        let mut request = vec![VERSION, OPCODE_MAP, 0, 0];
+        request.extend_from_slice(&lifetime.to_be_bytes());
+        request.extend_from_slice(&client_ip.octets());
+        request.extend_from_slice(&nonce);
+        request.extend_from_slice(&[PROTOCOL_UDP, 0, 0, 0]);
+        request.extend_from_slice(&local.port().to_be_bytes());
+        request.extend_from_slice(&local.port().to_be_bytes());
+        request.extend_from_slice(&suggested_ip.octets());
+
+        let response = transact(sockets, local.ip(), self.gateway, &request, |response| {
+            response.len() >= MAP_RESPONSE_LEN
+                && response[0] == VERSION
+                && response[1] == RESPONSE_BIT | OPCODE_MAP
+                && response[24..24 + NONCE_LEN] == nonce
+        })
+        .await?;
+
+        if response[3] != 0 {
+            return Err(IceError::PortMappingRefused(u16::from(response[3])));
+        }
+        Ok(response)
+    }
+}
+
+#[async_trait]
+impl PortMapper for PcpMapper {
+    async fn map_udp(
+        &self,
+        sockets: &PortMappingSockets,
+        local: SocketAddr,
+        lifetime: Duration,
+    ) -> Result<PortMapping, IceError> {
+        let nonce = *self
+            .nonces
+            .lock()
+            .unwrap()
+            .entry(local)
+            .or_insert_with(|| rand::thread_rng().gen());
+        let response = self.map(sockets, local, nonce, lifetime).await?;
+
+        let lifetime = u32::from_be_bytes([response[4], response[5], response[6], response[7]]);
+        let external_port = u16::from_be_bytes([response[42], response[43]]);
+        let mut octets = [0_u8; 16];
+        octets.copy_from_slice(&response[44..60]);
+        let external_ip = Ipv6Addr::from(octets);
+        let external_ip = external_ip
+            .to_ipv4_mapped()
+            .map_or(IpAddr::V6(external_ip), IpAddr::V4);
+
+        Ok(PortMapping {
+            external: SocketAddr::new(external_ip, external_port),
+            lifetime: Duration::from_secs(u64::from(lifetime)),
+        })
+    }
+
+    async fn unmap_udp(
+        &self,
+        sockets: &PortMappingSockets,
+        local: SocketAddr,
+    ) -> Result<(), IceError> {
+        let nonce = self.nonces.lock().unwrap().remove(&local);
+        if let Some(nonce) = nonce {
+            self.map(sockets, local, nonce, Duration::from_secs(0))
+                .await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,161 @@
+use super::*;
+
+use crate::socket_factory::TokioSocketFactory;
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::net::{TcpSocket, UdpSocket};
+
+/// Counts the UDP sockets it binds, which are plain tokio ones.
+#[derive(Default)]
+struct CountingSocketFactory {
+    udp: AtomicUsize,
+}
+
+#[async_trait]
+impl SocketFactory for CountingSocketFactory {
+    async fn bind_udp(&self, addr: SocketAddr) -> Result<UdpSocket, IceError> {
+        self.udp.fetch_add(1, Ordering::SeqCst);
+        TokioSocketFactory::default().bind_udp(addr).await
+    }
+
+    fn new_tcp(&self, addr: SocketAddr) -> Result<TcpSocket, IceError> {
+        TokioSocketFactory::default().new_tcp(addr)
+    }
+}
+
+fn host_sockets(socket_factory: Arc<dyn SocketFactory + Send + Sync>) -> PortMappingSockets {
+    PortMappingSockets::new(Arc::new(Net::new(None)), socket_factory)
+}
+
+// Answers the requests the gateway receives with the responses `respond` builds
+async fn fake_gateway(
+    respond: impl Fn(&[u8]) -> Vec<u8> + Send + 'static,
+) -> Result<SocketAddr, IceError> {
+    let conn = UdpSocket::bind("127.0.0.1:0").await?;
+    let gateway = conn.local_addr()?;
+    tokio::spawn(async move {
+        let mut buf = [0_u8; 1100];
+        while let Ok((n, from)) = conn.recv_from(&mut buf).await {
+            let response = respond(&buf[..n]);
+            let _ = conn.send_to(&response, from).await;
+        }
+    });
+    Ok(gateway)
+}
+
+fn nat_pmp_response(request: &[u8], result_code: u16) -> Vec<u8> {
+    let mut response = vec![0, 128 + request[1]];
+    response.extend_from_slice(&result_code.to_be_bytes());
+    response.extend_from_slice(&1_u32.to_be_bytes());
+    if request[1] == 0 {
+        response.extend_from_slice(&[203, 0, 113, 7]);
+    } else {
+        response.extend_from_slice(&request[4..6]);
+        response.extend_from_slice(&40000_u16.to_be_bytes());
+        response.extend_from_slice(&request[8..12]);
+    }
+    response
+}
+
+#[tokio::test]
+async fn test_nat_pmp_map_udp() -> Result<(), IceError> {
+    let gateway = fake_gateway(|request| nat_pmp_response(request, 0)).await?;
+    let mapper = NatPmpMapper::new(gateway);
+    let counting = Arc::new(CountingSocketFactory::default());
+    let sockets = host_sockets(counting.clone());
+
+    let local = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 5000);
+    let mapping = mapper
+        .map_udp(&sockets, local, Duration::from_secs(60))
+        .await?;
+    assert_eq!(
+        PortMapping {
+            external: "203.0.113.7:40000".parse().unwrap(),
+            lifetime: Duration::from_secs(60),
+        },
+        mapping
+    );
+    mapper.unmap_udp(&sockets, local).await?;
+    // The requests, for the mapping, the external address and the deletion, are sent from the
+    // sockets of the factory
+    assert_eq!(counting.udp.load(Ordering::SeqCst), 3);
+
+    let local = "[::1]:5000".parse().unwrap();
+    assert!(matches!(
+        mapper
+            .map_udp(&sockets, local, Duration::from_secs(60))
+            .await,
+        Err(IceError::PortMappingIpv6)
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_nat_pmp_map_udp_refused() -> Result<(), IceError> {
+    // Not Authorized/Refused
+    let gateway = fake_gateway(|request| nat_pmp_response(request, 2)).await?;
+    let mapper = NatPmpMapper::new(gateway);
+    let sockets = host_sockets(Arc::new(TokioSocketFactory::default()));
+
+    let local = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 5000);
+    assert!(matches!(
+        mapper
+            .map_udp(&sockets, local, Duration::from_secs(60))
+            .await,
+        Err(IceError::PortMappingRefused(2))
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pcp_map_udp() -> Result<(), IceError> {
+    let gateway = fake_gateway(|request| {
+        let mut response = vec![2, 0x81, 0, 0];
+        response.extend_from_slice(&request[4..8]);
+        response.extend_from_slice(&[0; 16]);
+        // The MAP opcode payload, the nonce, protocol and internal port being echoed
+        response.extend_from_slice(&request[24..42]);
+        response.extend_from_slice(&40000_u16.to_be_bytes());
+        response.extend_from_slice(&Ipv4Addr::new(203, 0, 113, 7).to_ipv6_mapped().octets());
+        response
+    })
+    .await?;
+    let mapper = PcpMapper::new(gateway);
+    let sockets = host_sockets(Arc::new(TokioSocketFactory::default()));
+
+    let local = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 5000);
+    let mapping = mapper
+        .map_udp(&sockets, local, Duration::from_secs(60))
+        .await?;
+    assert_eq!(
+        PortMapping {
+            external: "203.0.113.7:40000".parse().unwrap(),
+            lifetime: Duration::from_secs(60),
+        },
+        mapping
+    );
+    mapper.unmap_udp(&sockets, local).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_port_mapping_timeout() -> Result<(), IceError> {
+    // A gateway which doesn't speak PCP
+    let conn = UdpSocket::bind("127.0.0.1:0").await?;
+    let mapper = PcpMapper::new(conn.local_addr()?);
+    let sockets = host_sockets(Arc::new(TokioSocketFactory::default()));
+
+    let local = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 5000);
+    assert!(matches!(
+        mapper
+            .map_udp(&sockets, local, Duration::from_secs(60))
+            .await,
+        Err(IceError::Timeout(_))
+    ));
+
+    Ok(())
+}
@@ -1,6 +1,8 @@
 #[cfg(test)]
 mod priority_test;
 
+use crate::candidate::{Candidate, CandidateType};
+
 use stun::attributes::ATTR_PRIORITY;
 use stun::checks::*;
 use stun::message::*;
@@ -23,6 +25,45 @@ impl Setter for PriorityAttr {
     }
 }
 
+/// The type and local preferences the priority of a candidate is computed from, see
+/// [RFC 8445 Section 5.1.2.1](https://tools.ietf.org/html/rfc8445#section-5.1.2.1).
+#[derive(Default, PartialEq, Eq, Debug, Copy, Clone)]
+pub struct CandidatePreferences {
+    /// The preference of the type of the candidate, from 0 to 126, e.g. 126 for host candidates
+    /// and 0 for relay ones by default.
+    pub type_preference: u8,
+    /// The preference of the candidate among the ones of its type, from 0 to 65535.
+    pub local_preference: u16,
+}
+
+impl CandidatePreferences {
+    /// Returns the preferences `priority` was computed from.
+    #[allow(clippy::cast_possible_truncation)]
+    #[must_use]
+    pub const fn from_priority(priority: u32) -> Self {
+        Self {
+            type_preference: (priority >> 24) as u8,
+            local_preference: (priority >> 8) as u16,
+        }
+    }
+
+    /// Returns the priority of a candidate of `component` with these preferences.
+    #[must_use]
+    pub fn priority(&self, component: u16) -> u32 {
+        (1 << 24) * u32::from(self.type_preference)
+            + (1 << 8) * u32::from(self.local_preference)
+            + (256 - u32::from(component))
+    }
+}
+
+/// Returns the priority of `local` with the type preference of a peer reflexive candidate, i.e.
+/// the priority RFC 8445 Section 7.1.1 mandates in the PRIORITY attribute of the checks sent from
+/// `local`. It can be used as `AgentConfig::prflx_priority`.
+#[must_use]
+pub fn peer_reflexive_priority(local: &(dyn Candidate + Send + Sync)) -> u32 {
+    (u32::from(CandidateType::PeerReflexive.preference()) << 24) | (local.priority() & 0x00ff_ffff)
+}
+
 impl PriorityAttr {
     /// Decodes PRIORITY attribute from message.
     pub fn get_from(&mut self, m: &Message) -> Result<(), Error> {
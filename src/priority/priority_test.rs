@@ -38,3 +38,14 @@ fn test_priority_get_from() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[test]
+fn test_candidate_preferences() {
+    let preferences = CandidatePreferences {
+        type_preference: 100,
+        local_preference: 65535,
+    };
+    let priority = preferences.priority(1);
+    assert_eq!(priority, 1_694_498_815);
+    assert_eq!(preferences, CandidatePreferences::from_priority(priority));
+}
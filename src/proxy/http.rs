@@ -0,0 +1,88 @@
+use super::*;
+use crate::errors::*;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// The maximum size of the response header of the proxy.
+const MAX_RESPONSE_HEADER_SIZE: usize = 8192;
+
+/// Connects to TURN servers through an HTTP proxy with the CONNECT method, see RFC 7231
+/// Section 4.3.6. Basic authentication is used when credentials are given.
+#[derive(Debug, Clone)]
+pub struct HttpConnectDialer {
+    pub proxy_addr: String,
+    pub credentials: Option<(String, String)>,
+}
+
+impl HttpConnectDialer {
+    #[must_use]
+    pub fn new(proxy_addr: String, credentials: Option<(String, String)>) -> Self {
+        Self {
+            proxy_addr,
+            credentials,
+        }
+    }
+}
+
+#[async_trait]
+impl ProxyDialer for HttpConnectDialer {
+    async fn dial(&self, connector: &TcpConnector, addr: &str) -> Result<TcpStream, IceError> {
+        let mut stream = connector.connect(&self.proxy_addr).await?;
+
+        let mut req = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", addr, addr);
+        if let Some((username, password)) = &self.credentials {
+            req += &format!(
+                "Proxy-Authorization: Basic {}\r\n",
+                base64_encode(format!("{}:{}", username, password).as_bytes())
+            );
+        }
+        req += "\r\n";
+        stream.write_all(req.as_bytes()).await?;
+
+        // Read the response header byte by byte, so that nothing sent by the TURN server is
+        // consumed
+        let mut header = vec![];
+        while !header.ends_with(b"\r\n\r\n") {
+            if header.len() >= MAX_RESPONSE_HEADER_SIZE {
+                return Err(IceError::ProxyHandshake(
+                    "response header too large".to_owned(),
+                ));
+            }
+            header.push(stream.read_u8().await?);
+        }
+
+        let header = String::from_utf8_lossy(&header);
+        let status_line = header.lines().next().unwrap_or_default();
+        let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+        if !status.starts_with('2') {
+            return Err(IceError::ProxyHandshake(format!(
+                "connect to {} failed: {}",
+                addr, status_line
+            )));
+        }
+
+        Ok(stream)
+    }
+}
+
+pub(crate) fn base64_encode(input: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::with_capacity((input.len() + 2) / 3 * 4);
+    for chunk in input.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
@@ -0,0 +1,27 @@
+#[cfg(all(test, any(feature = "socks5", feature = "http-proxy")))]
+mod proxy_test;
+
+#[cfg(feature = "http-proxy")]
+mod http;
+#[cfg(feature = "socks5")]
+mod socks5;
+
+#[cfg(feature = "http-proxy")]
+pub use http::HttpConnectDialer;
+#[cfg(feature = "socks5")]
+pub use socks5::Socks5Dialer;
+
+use crate::errors::*;
+use crate::socket_factory::TcpConnector;
+
+use async_trait::async_trait;
+use tokio::net::TcpStream;
+
+/// Establishes the TCP connections to TURN servers, e.g. through an outbound proxy. The returned
+/// stream must be connected to `addr` once the proxy handshake is done. The connection to the
+/// proxy should be made with `connector`, so that it uses the socket factory and the port range
+/// of the agent.
+#[async_trait]
+pub trait ProxyDialer {
+    async fn dial(&self, connector: &TcpConnector, addr: &str) -> Result<TcpStream, IceError>;
+}
@@ -0,0 +1,194 @@
+use super::*;
+use crate::socket_factory::{SocketFactory, TokioSocketFactory};
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpSocket, UdpSocket};
+
+/// Counts the TCP sockets it creates, which are plain tokio ones.
+#[derive(Default)]
+struct CountingSocketFactory {
+    tcp: AtomicUsize,
+}
+
+#[async_trait]
+impl SocketFactory for CountingSocketFactory {
+    async fn bind_udp(&self, addr: SocketAddr) -> Result<UdpSocket, IceError> {
+        TokioSocketFactory::default().bind_udp(addr).await
+    }
+
+    fn new_tcp(&self, addr: SocketAddr) -> Result<TcpSocket, IceError> {
+        self.tcp.fetch_add(1, Ordering::SeqCst);
+        TokioSocketFactory::default().new_tcp(addr)
+    }
+}
+
+/// Returns a connector creating its sockets with a new `CountingSocketFactory`.
+fn connector() -> (Arc<CountingSocketFactory>, TcpConnector) {
+    let socket_factory = Arc::new(CountingSocketFactory::default());
+    let connector = TcpConnector::new(socket_factory.clone(), 0, 0);
+    (socket_factory, connector)
+}
+
+/// Starts a server that echoes everything it receives and returns its address.
+async fn echo_server() -> Result<String, IceError> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                let _ = tokio::io::copy(&mut r, &mut w).await;
+            });
+        }
+    });
+    Ok(addr)
+}
+
+/// Runs `handshake` on the first connection accepted on a new listener, then relays the
+/// connection to the address it returns.
+async fn proxy_server<F, Fut>(handshake: F) -> Result<String, IceError>
+where
+    F: FnOnce(TcpStream) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = (TcpStream, String)> + Send,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    tokio::spawn(async move {
+        if let Ok((stream, _)) = listener.accept().await {
+            let (mut stream, target) = handshake(stream).await;
+            if let Ok(mut upstream) = TcpStream::connect(target).await {
+                let _ = tokio::io::copy_bidirectional(&mut stream, &mut upstream).await;
+            }
+        }
+    });
+    Ok(addr)
+}
+
+async fn assert_echo(mut stream: TcpStream) -> Result<(), IceError> {
+    stream.write_all(b"ping").await?;
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"ping");
+    Ok(())
+}
+
+#[cfg(feature = "socks5")]
+#[tokio::test]
+async fn test_socks5_dialer() -> Result<(), IceError> {
+    let echo_addr = echo_server().await?;
+
+    let proxy_addr = proxy_server(|mut stream: TcpStream| async move {
+        // Method negotiation, username/password required
+        let mut greeting = [0u8; 4];
+        stream.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(greeting, [0x05, 2, 0x00, 0x02]);
+        stream.write_all(&[0x05, 0x02]).await.unwrap();
+
+        let mut auth = [0u8; 11];
+        stream.read_exact(&mut auth).await.unwrap();
+        assert_eq!(&auth, b"\x01\x04user\x04pass");
+        stream.write_all(&[0x01, 0x00]).await.unwrap();
+
+        // CONNECT to an IPv4 address
+        let mut req = [0u8; 10];
+        stream.read_exact(&mut req).await.unwrap();
+        assert_eq!(&req[..4], &[0x05, 0x01, 0x00, 0x01]);
+        let target = format!(
+            "{}.{}.{}.{}:{}",
+            req[4],
+            req[5],
+            req[6],
+            req[7],
+            u16::from_be_bytes([req[8], req[9]])
+        );
+        stream
+            .write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+
+        (stream, target)
+    })
+    .await?;
+
+    // The proxy is connected from a socket of the factory
+    let (socket_factory, connector) = connector();
+    let dialer = Socks5Dialer::new(proxy_addr, Some(("user".to_owned(), "pass".to_owned())));
+    let stream = dialer.dial(&connector, &echo_addr).await?;
+    assert_eq!(socket_factory.tcp.load(Ordering::SeqCst), 1);
+    assert_echo(stream).await
+}
+
+#[cfg(feature = "http-proxy")]
+#[tokio::test]
+async fn test_http_connect_dialer() -> Result<(), IceError> {
+    let echo_addr = echo_server().await?;
+
+    let proxy_addr = proxy_server(|mut stream: TcpStream| async move {
+        let mut header = vec![];
+        while !header.ends_with(b"\r\n\r\n") {
+            header.push(stream.read_u8().await.unwrap());
+        }
+        let header = String::from_utf8(header).unwrap();
+        assert!(
+            header.contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n"),
+            "{}",
+            header
+        );
+
+        let target = header.split_whitespace().nth(1).unwrap().to_owned();
+        stream
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await
+            .unwrap();
+
+        (stream, target)
+    })
+    .await?;
+
+    let dialer = HttpConnectDialer::new(proxy_addr, Some(("user".to_owned(), "pass".to_owned())));
+    let (socket_factory, connector) = connector();
+    let stream = dialer.dial(&connector, &echo_addr).await?;
+    assert_eq!(socket_factory.tcp.load(Ordering::SeqCst), 1);
+    assert_echo(stream).await
+}
+
+#[cfg(feature = "http-proxy")]
+#[tokio::test]
+async fn test_http_connect_dialer_rejected() -> Result<(), IceError> {
+    let proxy_addr = proxy_server(|mut stream: TcpStream| async move {
+        let mut header = vec![];
+        while !header.ends_with(b"\r\n\r\n") {
+            header.push(stream.read_u8().await.unwrap());
+        }
+        stream
+            .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+            .await
+            .unwrap();
+
+        (stream, String::new())
+    })
+    .await?;
+
+    let dialer = HttpConnectDialer::new(proxy_addr, None);
+    assert!(matches!(
+        dialer.dial(&connector().1, "127.0.0.1:3478").await,
+        Err(IceError::ProxyHandshake(_))
+    ));
+
+    Ok(())
+}
+
+#[cfg(feature = "http-proxy")]
+#[test]
+fn test_base64_encode() {
+    use super::http::base64_encode;
+
+    assert_eq!(base64_encode(b""), "");
+    assert_eq!(base64_encode(b"f"), "Zg==");
+    assert_eq!(base64_encode(b"fo"), "Zm8=");
+    assert_eq!(base64_encode(b"foo"), "Zm9v");
+    assert_eq!(base64_encode(b"user:pass"), "dXNlcjpwYXNz");
+}
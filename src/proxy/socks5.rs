@@ -0,0 +1,137 @@
+use super::*;
+use crate::errors::*;
+
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const SOCKS_VERSION: u8 = 0x05;
+const AUTH_NONE: u8 = 0x00;
+const AUTH_USERNAME_PASSWORD: u8 = 0x02;
+const USERNAME_PASSWORD_VERSION: u8 = 0x01;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN_NAME: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+const REPLY_SUCCEEDED: u8 = 0x00;
+
+/// Connects to TURN servers through a SOCKS5 proxy, see RFC 1928. Username/password
+/// authentication (RFC 1929) is used when credentials are given.
+#[derive(Debug, Clone)]
+pub struct Socks5Dialer {
+    pub proxy_addr: String,
+    pub credentials: Option<(String, String)>,
+}
+
+impl Socks5Dialer {
+    #[must_use]
+    pub fn new(proxy_addr: String, credentials: Option<(String, String)>) -> Self {
+        Self {
+            proxy_addr,
+            credentials,
+        }
+    }
+
+    async fn authenticate(&self, stream: &mut TcpStream) -> Result<(), IceError> {
+        if self.credentials.is_some() {
+            stream
+                .write_all(&[SOCKS_VERSION, 2, AUTH_NONE, AUTH_USERNAME_PASSWORD])
+                .await?;
+        } else {
+            stream.write_all(&[SOCKS_VERSION, 1, AUTH_NONE]).await?;
+        }
+
+        let mut reply = [0_u8; 2];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != SOCKS_VERSION {
+            return Err(IceError::ProxyHandshake(format!(
+                "unexpected version {}",
+                reply[0]
+            )));
+        }
+
+        match (reply[1], &self.credentials) {
+            (AUTH_NONE, _) => Ok(()),
+            (AUTH_USERNAME_PASSWORD, Some((username, password))) => {
+                let too_long = |_| IceError::ProxyHandshake("credentials too long".to_owned());
+
+                let mut req = vec![
+                    USERNAME_PASSWORD_VERSION,
+                    u8::try_from(username.len()).map_err(too_long)?,
+                ];
+                req.extend_from_slice(username.as_bytes());
+                req.push(u8::try_from(password.len()).map_err(too_long)?);
+                req.extend_from_slice(password.as_bytes());
+                stream.write_all(&req).await?;
+
+                stream.read_exact(&mut reply).await?;
+                if reply[1] == REPLY_SUCCEEDED {
+                    Ok(())
+                } else {
+                    Err(IceError::ProxyHandshake("authentication failed".to_owned()))
+                }
+            }
+            _ => Err(IceError::ProxyHandshake(
+                "no acceptable authentication method".to_owned(),
+            )),
+        }
+    }
+}
+
+#[async_trait]
+impl ProxyDialer for Socks5Dialer {
+    async fn dial(&self, connector: &TcpConnector, addr: &str) -> Result<TcpStream, IceError> {
+        let mut stream = connector.connect(&self.proxy_addr).await?;
+        self.authenticate(&mut stream).await?;
+
+        let mut req = vec![SOCKS_VERSION, CMD_CONNECT, 0];
+        match SocketAddr::from_str(addr) {
+            Ok(SocketAddr::V4(addr)) => {
+                req.push(ATYP_IPV4);
+                req.extend_from_slice(&addr.ip().octets());
+                req.extend_from_slice(&addr.port().to_be_bytes());
+            }
+            Ok(SocketAddr::V6(addr)) => {
+                req.push(ATYP_IPV6);
+                req.extend_from_slice(&addr.ip().octets());
+                req.extend_from_slice(&addr.port().to_be_bytes());
+            }
+            Err(_) => {
+                let (host, port) = addr.rsplit_once(':').ok_or(IceError::Port)?;
+                let port: u16 = port.parse().map_err(|_| IceError::Port)?;
+                req.push(ATYP_DOMAIN_NAME);
+                req.push(u8::try_from(host.len()).map_err(|_| IceError::Host)?);
+                req.extend_from_slice(host.as_bytes());
+                req.extend_from_slice(&port.to_be_bytes());
+            }
+        }
+        stream.write_all(&req).await?;
+
+        let mut reply = [0_u8; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != SOCKS_VERSION || reply[1] != REPLY_SUCCEEDED {
+            return Err(IceError::ProxyHandshake(format!(
+                "connect to {} failed with reply {}",
+                addr, reply[1]
+            )));
+        }
+
+        // Skip the address the proxy bound to
+        let bound_addr_len = match reply[3] {
+            ATYP_IPV4 => 4,
+            ATYP_IPV6 => 16,
+            ATYP_DOMAIN_NAME => usize::from(stream.read_u8().await?),
+            atyp => {
+                return Err(IceError::ProxyHandshake(format!(
+                    "unknown address type {}",
+                    atyp
+                )))
+            }
+        };
+        let mut bound_addr = vec![0_u8; bound_addr_len + 2];
+        stream.read_exact(&mut bound_addr).await?;
+
+        Ok(stream)
+    }
+}
@@ -9,6 +9,7 @@ const RUNES_CANDIDATE_ID_FOUNDATION: &[u8] =
 
 const LEN_UFRAG: usize = 16;
 const LEN_PWD: usize = 32;
+const LEN_AGENT_ID: usize = 8;
 
 pub fn generate_cand_id() -> String {
     // https://tools.ietf.org/html/rfc5245#section-15.1
@@ -56,3 +57,16 @@ pub fn generate_ufrag() -> String {
 
     rand_ufrag
 }
+
+/// Generates the identifier of an agent, which tells it apart in logs rather than authenticates
+/// anything.
+pub fn generate_agent_id() -> String {
+    let mut rng = thread_rng();
+
+    (0..LEN_AGENT_ID)
+        .map(|_| {
+            let idx = rng.gen_range(0..RUNES_ALPHA.len());
+            RUNES_ALPHA[idx] as char
+        })
+        .collect()
+}
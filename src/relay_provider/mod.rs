@@ -0,0 +1,33 @@
+use crate::errors::*;
+
+use async_trait::async_trait;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use util::Conn;
+
+/// A relayed transport allocated by the application, which the agent gathers a relay candidate
+/// on.
+pub struct Relay {
+    /// The relayed transport. Its local address is the relayed address, advertised as the
+    /// address of the candidate, and it sends to and receives from the peers through the relay,
+    /// as the connection returned by a TURN client does.
+    pub conn: Arc<dyn Conn + Send + Sync>,
+
+    /// The address the relay is reached from, advertised as the related address of the
+    /// candidate.
+    pub rel_addr: SocketAddr,
+
+    /// The transport used to reach the relay, e.g. "udp" or "tcp".
+    pub relay_protocol: String,
+}
+
+/// Provides relayed transports allocated by other means than the TURN client of the agent, e.g.
+/// by a TURN client of the application or through a proprietary relay. A relay candidate is
+/// gathered on each of them, alongside those of the TURN servers of `AgentConfig::urls`.
+#[async_trait]
+pub trait RelayProvider {
+    /// Returns the relayed transports of `component` of the data stream `stream`. They are
+    /// dropped when their candidates are closed, the provider is responsible for releasing the
+    /// relays then.
+    async fn relays(&self, stream: u16, component: u16) -> Result<Vec<Relay>, IceError>;
+}
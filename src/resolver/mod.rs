@@ -0,0 +1,69 @@
+#[cfg(test)]
+mod resolver_test;
+
+use crate::errors::*;
+
+use async_trait::async_trait;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use util::vnet::net::Net;
+
+/// Resolves the hostnames of STUN and TURN servers. Implement it to use DNS over HTTPS,
+/// split-horizon DNS or any other resolution scheme the platform resolver does not provide.
+#[async_trait]
+pub trait Resolver {
+    /// Returns the addresses of `host`, which is never an IP literal.
+    async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, IceError>;
+}
+
+/// The default resolver, which runs the platform resolver through `tokio::net::lookup_host`.
+#[derive(Default, Debug, Copy, Clone)]
+pub struct TokioResolver;
+
+#[async_trait]
+impl Resolver for TokioResolver {
+    async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, IceError> {
+        let mut ips = vec![];
+        for addr in tokio::net::lookup_host((host, 0)).await? {
+            if !ips.contains(&addr.ip()) {
+                ips.push(addr.ip());
+            }
+        }
+        Ok(ips)
+    }
+}
+
+/// Resolves the address of a STUN or TURN server to an address of the requested family. Without
+/// a custom resolver, hostnames are resolved by the virtual network when one is used, and by
+/// `TokioResolver` otherwise.
+pub(crate) async fn resolve_server_addr(
+    resolver: &Option<Arc<dyn Resolver + Send + Sync>>,
+    net: &Arc<Net>,
+    use_ipv4: bool,
+    host: &str,
+    port: u16,
+) -> Result<SocketAddr, IceError> {
+    let ips = if let Ok(ip) = IpAddr::from_str(host) {
+        vec![ip]
+    } else if let Some(resolver) = resolver {
+        resolver.resolve(host).await?
+    } else if net.is_virtual() {
+        return Ok(net
+            .resolve_addr(use_ipv4, &format!("{}:{}", host, port))
+            .await?);
+    } else {
+        TokioResolver.resolve(host).await?
+    };
+
+    ips.into_iter()
+        .find(|ip| ip.is_ipv4() == use_ipv4)
+        .map(|ip| SocketAddr::new(ip, port))
+        .ok_or_else(|| {
+            IceError::HostUnresolved(format!(
+                "{} has no {} address",
+                host,
+                if use_ipv4 { "IPv4" } else { "IPv6" }
+            ))
+        })
+}
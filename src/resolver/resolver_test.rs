@@ -0,0 +1,46 @@
+use super::*;
+
+use std::net::Ipv4Addr;
+
+struct StaticResolver(Vec<IpAddr>);
+
+#[async_trait]
+impl Resolver for StaticResolver {
+    async fn resolve(&self, _host: &str) -> Result<Vec<IpAddr>, IceError> {
+        Ok(self.0.clone())
+    }
+}
+
+#[tokio::test]
+async fn test_resolve_server_addr() -> Result<(), IceError> {
+    let net = Arc::new(Net::new(None));
+    let resolver: Option<Arc<dyn Resolver + Send + Sync>> = Some(Arc::new(StaticResolver(vec![
+        IpAddr::from_str("::1")?,
+        IpAddr::from_str("10.0.0.1")?,
+    ])));
+
+    // IP literals are never resolved
+    let addr = resolve_server_addr(&resolver, &net, true, "1.2.3.4", 3478).await?;
+    assert_eq!(
+        addr,
+        SocketAddr::new(Ipv4Addr::new(1, 2, 3, 4).into(), 3478)
+    );
+
+    // The first address of the requested family is used
+    let addr = resolve_server_addr(&resolver, &net, true, "stun.example.com", 3478).await?;
+    assert_eq!(addr, SocketAddr::from_str("10.0.0.1:3478")?);
+    let addr = resolve_server_addr(&resolver, &net, false, "stun.example.com", 3478).await?;
+    assert_eq!(addr, SocketAddr::from_str("[::1]:3478")?);
+
+    let resolver: Option<Arc<dyn Resolver + Send + Sync>> =
+        Some(Arc::new(StaticResolver(vec![IpAddr::from_str("::1")?])));
+    assert!(
+        matches!(
+            resolve_server_addr(&resolver, &net, true, "stun.example.com", 3478).await,
+            Err(IceError::HostUnresolved(_))
+        ),
+        "no IPv4 address should be an error"
+    );
+
+    Ok(())
+}
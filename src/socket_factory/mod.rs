@@ -0,0 +1,272 @@
+#[cfg(test)]
+mod socket_factory_test;
+
+use crate::errors::*;
+use crate::util::bind_in_port_range;
+
+use async_trait::async_trait;
+use socket2::{Domain, SockRef, Socket, Type};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+use util::vnet::net::Net;
+use util::Conn;
+
+/// Creates the sockets the agent gathers candidates on and reaches STUN and TURN servers from.
+/// Implement it to set platform-specific options, e.g. `SO_BINDTODEVICE`, a VRF or an Android
+/// network handle, or to hand out sockets under test control. It is not used on a virtual
+/// network, see `AgentConfig::net`, nor by the UDP and TCP muxes which own their sockets.
+#[async_trait]
+pub trait SocketFactory {
+    /// Returns a UDP socket bound to `addr`. Its port is 0 when any port will do.
+    async fn bind_udp(&self, addr: SocketAddr) -> Result<UdpSocket, IceError>;
+
+    /// Returns a UDP socket bound to `addr`, an IPv6 address, with `IPV6_V6ONLY` set before it
+    /// was bound, so that it doesn't receive the IPv4 packets of an IPv4 socket bound next to it,
+    /// see `AgentConfig::bind_wildcard_udp`. The default binds it with `bind_udp`, leaving the
+    /// option to the system default.
+    async fn bind_udp_v6_only(&self, addr: SocketAddr) -> Result<UdpSocket, IceError> {
+        self.bind_udp(addr).await
+    }
+
+    /// Returns a TCP socket of the family of `addr`, not bound yet. The agent binds it to `addr`
+    /// once it has set the options it needs, then listens or connects on it.
+    fn new_tcp(&self, addr: SocketAddr) -> Result<TcpSocket, IceError>;
+}
+
+/// Options set on the sockets of `TokioSocketFactory`. Those left to `None` or `false` keep the
+/// default of the system.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// The size of the receive buffer, `SO_RCVBUF`, in bytes. The default of the system, e.g.
+    /// 208KB on Linux, drops packets of high-bitrate media under load. The system may round it,
+    /// or cap it, e.g. to `net.core.rmem_max` on Linux.
+    pub recv_buffer_size: Option<usize>,
+
+    /// The size of the send buffer, `SO_SNDBUF`, in bytes.
+    pub send_buffer_size: Option<usize>,
+
+    /// Sets `SO_REUSEADDR`.
+    pub reuse_addr: bool,
+
+    /// Sets `SO_REUSEPORT`, on the platforms which have it.
+    pub reuse_port: bool,
+
+    /// The type of service of IPv4 packets, `IP_TOS`, or the traffic class of IPv6 ones,
+    /// `IPV6_TCLASS`. The DSCP is its 6 high bits, e.g. `46 << 2` for expedited forwarding.
+    pub tos: Option<u8>,
+}
+
+impl SocketOptions {
+    /// Sets the options on `socket`, an IPv4 one when `ipv4` is true. The reuse options only
+    /// take effect before the socket is bound.
+    fn apply(&self, socket: &SockRef<'_>, ipv4: bool) -> Result<(), IceError> {
+        if self.reuse_addr {
+            socket.set_reuse_address(true)?;
+        }
+        #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+        if self.reuse_port {
+            socket.set_reuse_port(true)?;
+        }
+
+        self.apply_bound(socket, ipv4)
+    }
+
+    /// Sets the options which still take effect once `socket` is bound, i.e. all of them but
+    /// the reuse ones.
+    fn apply_bound(&self, socket: &SockRef<'_>, ipv4: bool) -> Result<(), IceError> {
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(tos) = self.tos {
+            set_tos(socket, ipv4, tos)?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether one of the reuse options is set, which must be set before the socket is bound.
+    pub(crate) const fn reuses(&self) -> bool {
+        self.reuse_addr || self.reuse_port
+    }
+}
+
+/// Sets the type of service of the packets sent from `socket`, an IPv4 one when `ipv4` is true.
+/// The traffic class of IPv6 sockets is left as is on the platforms which can't set it.
+pub(crate) fn set_tos(socket: &SockRef<'_>, ipv4: bool, tos: u8) -> io::Result<()> {
+    if ipv4 {
+        socket.set_tos_v4(u32::from(tos))?;
+    } else {
+        #[cfg(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "netbsd",
+            target_os = "openbsd",
+        ))]
+        socket.set_tclass_v6(u32::from(tos))?;
+    }
+
+    Ok(())
+}
+
+/// The default socket factory, which creates plain tokio sockets with the given options.
+#[derive(Default, Debug, Copy, Clone)]
+pub struct TokioSocketFactory {
+    options: SocketOptions,
+}
+
+impl TokioSocketFactory {
+    /// Returns a factory setting `options` on the sockets it creates.
+    pub const fn new(options: SocketOptions) -> Self {
+        Self { options }
+    }
+}
+
+#[async_trait]
+impl SocketFactory for TokioSocketFactory {
+    async fn bind_udp(&self, addr: SocketAddr) -> Result<UdpSocket, IceError> {
+        if self.options == SocketOptions::default() {
+            return Ok(UdpSocket::bind(addr).await?);
+        }
+
+        let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, None)?;
+        self.options
+            .apply(&SockRef::from(&socket), addr.is_ipv4())?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        Ok(UdpSocket::from_std(socket.into())?)
+    }
+
+    async fn bind_udp_v6_only(&self, addr: SocketAddr) -> Result<UdpSocket, IceError> {
+        let socket = Socket::new(Domain::IPV6, Type::DGRAM, None)?;
+        socket.set_only_v6(true)?;
+        self.options.apply(&SockRef::from(&socket), false)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        Ok(UdpSocket::from_std(socket.into())?)
+    }
+
+    fn new_tcp(&self, addr: SocketAddr) -> Result<TcpSocket, IceError> {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        self.options
+            .apply(&SockRef::from(&socket), addr.is_ipv4())?;
+        Ok(socket)
+    }
+}
+
+/// Sets the socket options of the agent on the sockets of a socket factory of the application.
+/// The UDP sockets are bound by the factory already, so the reuse options are rejected by the
+/// agent along with a factory.
+pub(crate) struct OptionsSocketFactory {
+    pub(crate) socket_factory: Arc<dyn SocketFactory + Send + Sync>,
+    pub(crate) options: SocketOptions,
+}
+
+#[async_trait]
+impl SocketFactory for OptionsSocketFactory {
+    async fn bind_udp(&self, addr: SocketAddr) -> Result<UdpSocket, IceError> {
+        let socket = self.socket_factory.bind_udp(addr).await?;
+        self.options
+            .apply_bound(&SockRef::from(&socket), addr.is_ipv4())?;
+        Ok(socket)
+    }
+
+    async fn bind_udp_v6_only(&self, addr: SocketAddr) -> Result<UdpSocket, IceError> {
+        let socket = self.socket_factory.bind_udp_v6_only(addr).await?;
+        self.options.apply_bound(&SockRef::from(&socket), false)?;
+        Ok(socket)
+    }
+
+    fn new_tcp(&self, addr: SocketAddr) -> Result<TcpSocket, IceError> {
+        let socket = self.socket_factory.new_tcp(addr)?;
+        self.options
+            .apply(&SockRef::from(&socket), addr.is_ipv4())?;
+        Ok(socket)
+    }
+}
+
+/// Binds a UDP socket to `addr`, on the virtual network when one is used and with the socket
+/// factory otherwise.
+pub(crate) async fn bind_udp(
+    net: &Arc<Net>,
+    socket_factory: &Arc<dyn SocketFactory + Send + Sync>,
+    addr: SocketAddr,
+) -> Result<Arc<dyn Conn + Send + Sync>, IceError> {
+    if net.is_virtual() {
+        Ok(net.bind(addr).await?)
+    } else {
+        Ok(Arc::new(socket_factory.bind_udp(addr).await?))
+    }
+}
+
+/// Connects to `addr` from a TCP socket of the socket factory, bound to any local address and to
+/// a port of the port range.
+pub(crate) async fn connect_tcp(
+    socket_factory: &Arc<dyn SocketFactory + Send + Sync>,
+    port_max: u16,
+    port_min: u16,
+    addr: SocketAddr,
+) -> Result<TcpStream, IceError> {
+    let laddr = if addr.is_ipv4() {
+        SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)
+    } else {
+        SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0)
+    };
+    let socket = bind_in_port_range(port_max, port_min, laddr, |laddr| async move {
+        let socket = socket_factory.new_tcp(laddr)?;
+        socket.bind(laddr)?;
+        Ok(socket)
+    })
+    .await?;
+    Ok(socket.connect(addr).await?)
+}
+
+/// Connects outbound TCP connections from sockets of the socket factory, bound to a port of the
+/// port range. It is handed to `ProxyDialer::dial`, so that the connections to the proxy are
+/// made like the other ones of the agent.
+#[derive(Clone)]
+pub struct TcpConnector {
+    socket_factory: Arc<dyn SocketFactory + Send + Sync>,
+    port_max: u16,
+    port_min: u16,
+}
+
+impl TcpConnector {
+    /// Returns a connector creating its sockets with `socket_factory`. Their ports are
+    /// allocated by the system when `port_max` and `port_min` are 0.
+    #[must_use]
+    pub const fn new(
+        socket_factory: Arc<dyn SocketFactory + Send + Sync>,
+        port_max: u16,
+        port_min: u16,
+    ) -> Self {
+        Self {
+            socket_factory,
+            port_max,
+            port_min,
+        }
+    }
+
+    /// Connects to `addr`, a `host:port` string. The addresses a host name resolves to are tried
+    /// in turn.
+    pub async fn connect(&self, addr: &str) -> Result<TcpStream, IceError> {
+        let mut last_err = IceError::Host;
+        for addr in tokio::net::lookup_host(addr).await? {
+            match connect_tcp(&self.socket_factory, self.port_max, self.port_min, addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = err,
+            }
+        }
+        Err(last_err)
+    }
+}
@@ -0,0 +1,169 @@
+use super::*;
+use crate::agent::agent_config::AgentConfig;
+use crate::agent::agent_vnet_test::wait_for_gathering_complete;
+use crate::agent::Agent;
+use crate::network_type::NetworkType;
+
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::net::TcpListener;
+use util::vnet::net::NetConfig;
+
+/// Counts the sockets it creates, which are plain tokio ones.
+#[derive(Default)]
+struct CountingSocketFactory {
+    udp: AtomicUsize,
+    tcp: AtomicUsize,
+}
+
+#[async_trait]
+impl SocketFactory for CountingSocketFactory {
+    async fn bind_udp(&self, addr: SocketAddr) -> Result<UdpSocket, IceError> {
+        self.udp.fetch_add(1, Ordering::SeqCst);
+        TokioSocketFactory::default().bind_udp(addr).await
+    }
+
+    fn new_tcp(&self, addr: SocketAddr) -> Result<TcpSocket, IceError> {
+        self.tcp.fetch_add(1, Ordering::SeqCst);
+        TokioSocketFactory::default().new_tcp(addr)
+    }
+}
+
+#[tokio::test]
+async fn test_bind_udp() -> Result<(), IceError> {
+    let counting = Arc::new(CountingSocketFactory::default());
+    let socket_factory: Arc<dyn SocketFactory + Send + Sync> = counting.clone();
+
+    let net = Arc::new(Net::new(None));
+    let conn = bind_udp(&net, &socket_factory, "127.0.0.1:0".parse()?).await?;
+    assert_ne!(conn.local_addr().await?.port(), 0);
+    assert_eq!(counting.udp.load(Ordering::SeqCst), 1);
+
+    // A virtual network binds sockets of its own
+    let vnet = Arc::new(Net::new(Some(NetConfig::default())));
+    bind_udp(&vnet, &socket_factory, "127.0.0.1:0".parse()?).await?;
+    assert_eq!(counting.udp.load(Ordering::SeqCst), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_connect_tcp() -> Result<(), IceError> {
+    let counting = Arc::new(CountingSocketFactory::default());
+    let socket_factory: Arc<dyn SocketFactory + Send + Sync> = counting.clone();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let stream = connect_tcp(&socket_factory, 0, 0, listener.local_addr()?).await?;
+    assert_eq!(stream.peer_addr()?, listener.local_addr()?);
+    assert_eq!(counting.tcp.load(Ordering::SeqCst), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_socket_options() -> Result<(), IceError> {
+    let socket_factory = TokioSocketFactory::new(SocketOptions {
+        recv_buffer_size: Some(1 << 16),
+        send_buffer_size: Some(1 << 16),
+        reuse_addr: true,
+        reuse_port: false,
+        tos: Some(46 << 2),
+    });
+
+    let udp = socket_factory.bind_udp("127.0.0.1:0".parse()?).await?;
+    let tcp = socket_factory.new_tcp("127.0.0.1:0".parse()?)?;
+    for socket in &[SockRef::from(&udp), SockRef::from(&tcp)] {
+        assert!(socket.reuse_address()?);
+        assert_eq!(socket.tos_v4()?, 46 << 2);
+        // Linux doubles the size it is given, other systems may round it down
+        if cfg!(target_os = "linux") {
+            assert!(socket.recv_buffer_size()? >= 1 << 16);
+            assert!(socket.send_buffer_size()? >= 1 << 16);
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_socket_options_with_socket_factory() -> Result<(), IceError> {
+    let options = SocketOptions {
+        send_buffer_size: Some(1 << 16),
+        tos: Some(46 << 2),
+        ..Default::default()
+    };
+    let counting = Arc::new(CountingSocketFactory::default());
+    let socket_factory = OptionsSocketFactory {
+        socket_factory: counting.clone(),
+        options,
+    };
+
+    let udp = socket_factory.bind_udp("127.0.0.1:0".parse()?).await?;
+    let tcp = socket_factory.new_tcp("127.0.0.1:0".parse()?)?;
+    assert_eq!(counting.udp.load(Ordering::SeqCst), 1);
+    assert_eq!(counting.tcp.load(Ordering::SeqCst), 1);
+    for socket in &[SockRef::from(&udp), SockRef::from(&tcp)] {
+        assert_eq!(socket.tos_v4()?, 46 << 2);
+        if cfg!(target_os = "linux") {
+            assert!(socket.send_buffer_size()? >= 1 << 16);
+        }
+    }
+
+    // The UDP sockets of a factory are bound before the reuse options could be set
+    let result = Agent::new(AgentConfig {
+        socket_factory: Some(counting),
+        socket_options: SocketOptions {
+            reuse_addr: true,
+            ..options
+        },
+        ..Default::default()
+    })
+    .await;
+    assert!(matches!(result, Err(IceError::ReuseWithSocketFactory)));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_gather_with_socket_factory() -> Result<(), IceError> {
+    let counting = Arc::new(CountingSocketFactory::default());
+    let a = Agent::new(AgentConfig {
+        network_types: vec![NetworkType::Udp4, NetworkType::Tcp4],
+        include_loopback: true,
+        ip_filter: Arc::new(Some(Box::new(|ip: IpAddr| ip.is_loopback()))),
+        socket_factory: Some(counting.clone()),
+        ..Default::default()
+    })
+    .await?;
+
+    let mut events = a.events();
+    a.gather_candidates().await?;
+    wait_for_gathering_complete(&mut events).await;
+
+    assert!(!a.get_local_candidates().await?.is_empty());
+    assert!(
+        counting.udp.load(Ordering::SeqCst) > 0,
+        "the UDP host candidate is bound by the factory"
+    );
+    assert!(
+        counting.tcp.load(Ordering::SeqCst) > 0,
+        "the simultaneous-open TCP candidate is bound by the factory"
+    );
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bind_udp_v6_only() -> Result<(), IceError> {
+    let socket_factory = TokioSocketFactory::default();
+    // The host may have no IPv6
+    let socket = match socket_factory.bind_udp_v6_only("[::]:0".parse()?).await {
+        Ok(socket) => socket,
+        Err(_) => return Ok(()),
+    };
+    assert!(SockRef::from(&socket).only_v6()?);
+
+    Ok(())
+}
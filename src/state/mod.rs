@@ -53,7 +53,7 @@ impl fmt::Display for ConnectionState {
 }
 
 /// Describes the state of the candidate gathering process.
-#[derive(PartialEq, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum GatheringState {
     Unspecified,
 
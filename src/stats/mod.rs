@@ -5,8 +5,10 @@ use tokio::time::Instant;
 
 // CandidatePairStats contains ICE candidate pair statistics
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CandidatePairStats {
     // timestamp is the timestamp associated with this object.
+    #[cfg_attr(feature = "serde", serde(with = "crate::stats::serde_instant"))]
     pub timestamp: Instant,
 
     // local_candidate_id is the id of the local candidate
@@ -39,24 +41,29 @@ pub struct CandidatePairStats {
 
     // last_packet_sent_timestamp represents the timestamp at which the last packet was
     // sent on this particular candidate pair, excluding STUN packets.
+    #[cfg_attr(feature = "serde", serde(with = "crate::stats::serde_instant"))]
     pub last_packet_sent_timestamp: Instant,
 
     // last_packet_received_timestamp represents the timestamp at which the last packet
     // was received on this particular candidate pair, excluding STUN packets.
+    #[cfg_attr(feature = "serde", serde(with = "crate::stats::serde_instant"))]
     pub last_packet_received_timestamp: Instant,
 
     // first_request_timestamp represents the timestamp at which the first STUN request
     // was sent on this particular candidate pair.
+    #[cfg_attr(feature = "serde", serde(with = "crate::stats::serde_instant"))]
     pub first_request_timestamp: Instant,
 
     // last_request_timestamp represents the timestamp at which the last STUN request
     // was sent on this particular candidate pair. The average interval between two
     // consecutive connectivity checks sent can be calculated with
     // (last_request_timestamp - first_request_timestamp) / requests_sent.
+    #[cfg_attr(feature = "serde", serde(with = "crate::stats::serde_instant"))]
     pub last_request_timestamp: Instant,
 
     // last_response_timestamp represents the timestamp at which the last STUN response
     // was received on this particular candidate pair.
+    #[cfg_attr(feature = "serde", serde(with = "crate::stats::serde_instant"))]
     pub last_response_timestamp: Instant,
 
     // total_round_trip_time represents the sum of all round trip time measurements
@@ -122,13 +129,16 @@ pub struct CandidatePairStats {
 
     // consent_expired_timestamp represents the timestamp at which the latest valid
     // STUN binding response expired.
+    #[cfg_attr(feature = "serde", serde(with = "crate::stats::serde_instant"))]
     pub consent_expired_timestamp: Instant,
 }
 
 // CandidateStats contains ICE candidate statistics related to the ICETransport objects.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CandidateStats {
     // timestamp is the timestamp associated with this object.
+    #[cfg_attr(feature = "serde", serde(with = "crate::stats::serde_instant"))]
     pub timestamp: Instant,
 
     // id is the candidate id
@@ -176,3 +186,46 @@ pub struct CandidateStats {
     // Only defined for local candidates. For remote candidates, this property is not applicable.
     pub deleted: bool,
 }
+
+/// (De)serializes an `Instant` as milliseconds since `UNIX_EPOCH`, since an `Instant` means
+/// nothing outside of the process that took it.
+#[cfg(feature = "serde")]
+pub(crate) mod serde_instant {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::convert::TryFrom;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use tokio::time::Instant;
+
+    pub(crate) fn serialize<S: Serializer>(
+        instant: &Instant,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let now = Instant::now();
+        let system_now = SystemTime::now();
+        let system_time = if *instant <= now {
+            system_now.checked_sub(now - *instant)
+        } else {
+            system_now.checked_add(*instant - now)
+        }
+        .unwrap_or(UNIX_EPOCH);
+
+        let millis = system_time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        u64::try_from(millis)
+            .unwrap_or(u64::MAX)
+            .serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Instant, D::Error> {
+        let system_time = UNIX_EPOCH + Duration::from_millis(u64::deserialize(deserializer)?);
+        let now = Instant::now();
+        Ok(match SystemTime::now().duration_since(system_time) {
+            Ok(ago) => now.checked_sub(ago).unwrap_or(now),
+            Err(err) => now + err.duration(),
+        })
+    }
+}
@@ -0,0 +1,163 @@
+#[cfg(test)]
+mod stun_conn_test;
+
+use crate::errors::*;
+
+use util::Conn;
+
+use async_trait::async_trait;
+use std::io;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+/// The size of the common header of STUN messages and TURN ChannelData messages.
+const HEADER_SIZE: usize = 4;
+
+/// The size of a STUN message header.
+const STUN_HEADER_SIZE: usize = 20;
+
+/// Wraps a stream to a TURN server and splits the byte stream into STUN and ChannelData
+/// messages, so it can be handed to the TURN client like a packet connection.
+///
+/// reference: (IETF ref-5766)[https://tools.ietf.org/html/rfc5766#section-2.1].
+pub(crate) struct StunConn {
+    reader: Mutex<Box<dyn AsyncRead + Unpin + Send>>,
+    writer: Mutex<Box<dyn AsyncWrite + Unpin + Send>>,
+    local_addr: SocketAddr,
+    remote_addr: SocketAddr,
+}
+
+impl StunConn {
+    pub(crate) fn new(stream: TcpStream) -> io::Result<Self> {
+        let local_addr = stream.local_addr()?;
+        let remote_addr = stream.peer_addr()?;
+        let (reader, writer) = stream.into_split();
+
+        Ok(Self {
+            reader: Mutex::new(Box::new(reader)),
+            writer: Mutex::new(Box::new(writer)),
+            local_addr,
+            remote_addr,
+        })
+    }
+
+    /// Wraps `stream`, e.g. a TLS stream over a TCP connection from `local_addr` to
+    /// `remote_addr`.
+    pub(crate) fn from_stream<S>(stream: S, local_addr: SocketAddr, remote_addr: SocketAddr) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (reader, writer) = tokio::io::split(stream);
+
+        Self {
+            reader: Mutex::new(Box::new(reader)),
+            writer: Mutex::new(Box::new(writer)),
+            local_addr,
+            remote_addr,
+        }
+    }
+}
+
+#[async_trait]
+impl Conn for StunConn {
+    async fn connect(&self, _addr: SocketAddr) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "Not applicable"))
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut reader = self.reader.lock().await;
+        read_message(&mut *reader, buf).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let n = self.recv(buf).await?;
+        Ok((n, self.remote_addr))
+    }
+
+    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let mut writer = self.writer.lock().await;
+        write_message(&mut *writer, buf).await?;
+        Ok(buf.len())
+    }
+
+    /// The stream is connected to the TURN server already, so the target is ignored.
+    async fn send_to(&self, buf: &[u8], _target: SocketAddr) -> io::Result<usize> {
+        self.send(buf).await
+    }
+
+    async fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+/// Returns true if the message starts like a ChannelData message, whose channel numbers have
+/// the two most significant bits set to 0b01.
+fn is_channel_data(header: &[u8]) -> bool {
+    header[0] & 0xC0 == 0x40
+}
+
+/// Returns the ChannelData length padded to a multiple of 4 bytes, as required over streams.
+///
+/// reference: (IETF ref-5766)[https://tools.ietf.org/html/rfc5766#section-11.5].
+fn padded_len(length: usize) -> usize {
+    (length + 3) & !3
+}
+
+/// Reads a single STUN or ChannelData message from the stream into `buf`, without the padding
+/// of ChannelData messages.
+pub(crate) async fn read_message<R: AsyncRead + Unpin + Send + ?Sized>(
+    reader: &mut R,
+    buf: &mut [u8],
+) -> io::Result<usize> {
+    let mut header = [0_u8; HEADER_SIZE];
+    reader.read_exact(&mut header).await?;
+
+    let length = usize::from(u16::from_be_bytes([header[2], header[3]]));
+    let (message_len, remaining) = if is_channel_data(&header) {
+        (HEADER_SIZE + length, padded_len(length))
+    } else if header[0] & 0xC0 == 0 {
+        (
+            STUN_HEADER_SIZE + length,
+            STUN_HEADER_SIZE - HEADER_SIZE + length,
+        )
+    } else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            IceError::ReadingStreamingPacket("neither STUN nor ChannelData".to_owned()),
+        ));
+    };
+
+    let mut message = vec![0_u8; HEADER_SIZE + remaining];
+    message[..HEADER_SIZE].copy_from_slice(&header);
+    reader.read_exact(&mut message[HEADER_SIZE..]).await?;
+
+    if message_len > buf.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            IceError::ReadingStreamingPacket(format!(
+                "message of {} bytes exceeds buffer of {} bytes",
+                message_len,
+                buf.len()
+            )),
+        ));
+    }
+    buf[..message_len].copy_from_slice(&message[..message_len]);
+
+    Ok(message_len)
+}
+
+/// Writes a single STUN or ChannelData message to the stream, padding ChannelData messages.
+pub(crate) async fn write_message<W: AsyncWrite + Unpin + Send + ?Sized>(
+    writer: &mut W,
+    buf: &[u8],
+) -> io::Result<()> {
+    if buf.len() >= HEADER_SIZE && is_channel_data(buf) {
+        let mut padded = buf.to_vec();
+        padded.resize(HEADER_SIZE + padded_len(buf.len() - HEADER_SIZE), 0);
+        writer.write_all(&padded).await
+    } else {
+        writer.write_all(buf).await
+    }
+}
@@ -0,0 +1,52 @@
+use super::*;
+
+use stun::agent::*;
+use stun::message::*;
+use tokio::net::TcpListener;
+
+#[tokio::test]
+async fn test_stun_conn_channel_data_padding() -> Result<(), IceError> {
+    let (mut a, mut b) = tokio::io::duplex(1024);
+
+    // Channel 0x4000 carrying 5 bytes is padded to 8 bytes on the stream
+    let channel_data = [0x40, 0x00, 0x00, 0x05, 1, 2, 3, 4, 5];
+    write_message(&mut a, &channel_data).await?;
+    drop(a);
+
+    let mut raw = vec![];
+    b.read_to_end(&mut raw).await?;
+    assert_eq!(raw.len(), 12, "ChannelData should be padded");
+
+    let mut reader = &raw[..];
+    let mut buf = vec![0_u8; 1500];
+    let n = read_message(&mut reader, &mut buf).await?;
+    assert_eq!(&buf[..n], &channel_data[..], "padding should be stripped");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stun_conn_splits_messages() -> Result<(), IceError> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let server_addr = listener.local_addr()?;
+
+    let client = StunConn::new(TcpStream::connect(server_addr).await?)?;
+    let (stream, _) = listener.accept().await?;
+    let server = StunConn::new(stream)?;
+
+    let mut msg = Message::new();
+    msg.build(&[Box::new(BINDING_REQUEST), Box::new(TransactionId::new())])?;
+
+    // Two messages written back to back are read one at a time
+    client.send_to(&msg.raw, server_addr).await?;
+    client.send_to(&msg.raw, server_addr).await?;
+
+    let mut buf = vec![0_u8; 1500];
+    for _ in 0..2 {
+        let (n, addr) = server.recv_from(&mut buf).await?;
+        assert_eq!(&buf[..n], &msg.raw[..], "should match");
+        assert_eq!(addr, client.local_addr().await?, "should match");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,186 @@
+use super::*;
+
+use crate::util::bind_in_port_range;
+use std::collections::HashSet;
+
+use std::net::IpAddr;
+use tokio::time::Duration;
+
+/// The time allowed for an outbound TCP connection to be established.
+const DIAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The connection of an active TCP candidate, see
+/// [RFC 6544](https://tools.ietf.org/html/rfc6544#section-4.5).
+///
+/// An active candidate has no pre-bound socket. The first packet sent to a remote address dials
+/// it from the local IP in the background, and is written once the stream is established. Until
+/// then further packets to that address are dropped, so connectivity checks are simply retried.
+pub(crate) struct ActiveTcpConn {
+    agent_id: String,
+    local_ip: IpAddr,
+    socket_factory: Arc<dyn SocketFactory + Send + Sync>,
+    port_max: u16,
+    port_min: u16,
+    read_buffer_size: usize,
+    writers: StreamWriters,
+    dialing: Arc<Mutex<HashSet<SocketAddr>>>,
+    packets_tx: mpsc::Sender<(Vec<u8>, SocketAddr)>,
+    packets_rx: Mutex<mpsc::Receiver<(Vec<u8>, SocketAddr)>>,
+    // Dropping the sender stops the stream readers, which closes the streams
+    closed_ch_tx: broadcast::Sender<()>,
+}
+
+impl ActiveTcpConn {
+    /// Returns the conn of an active candidate dialing from `local_ip`, and from a port of the
+    /// port range, allocated by the system when `port_max` and `port_min` are 0.
+    pub(crate) fn new(
+        agent_id: String,
+        local_ip: IpAddr,
+        socket_factory: Arc<dyn SocketFactory + Send + Sync>,
+        port_max: u16,
+        port_min: u16,
+    ) -> Self {
+        let (packets_tx, packets_rx) = mpsc::channel(MAX_PENDING_PACKETS);
+        let (closed_ch_tx, _) = broadcast::channel(1);
+        Self {
+            agent_id,
+            local_ip,
+            socket_factory,
+            port_max,
+            port_min,
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            writers: Arc::new(Mutex::new(HashMap::new())),
+            dialing: Arc::new(Mutex::new(HashSet::new())),
+            packets_tx,
+            packets_rx: Mutex::new(packets_rx),
+            closed_ch_tx,
+        }
+    }
+
+    async fn dial(&self, first_packet: Vec<u8>, target: SocketAddr) {
+        {
+            let mut dialing = self.dialing.lock().await;
+            if !dialing.insert(target) {
+                return;
+            }
+        }
+
+        let agent_id = self.agent_id.clone();
+        let local_ip = self.local_ip;
+        let socket_factory = Arc::clone(&self.socket_factory);
+        let (port_max, port_min) = (self.port_max, self.port_min);
+        let read_buffer_size = self.read_buffer_size;
+        let writers = Arc::clone(&self.writers);
+        let dialing = Arc::clone(&self.dialing);
+        let packets_tx = self.packets_tx.clone();
+        let closed_ch_rx = self.closed_ch_tx.subscribe();
+        tokio::spawn(async move {
+            match Self::connect(&socket_factory, port_max, port_min, local_ip, target).await {
+                Ok(stream) => {
+                    let (reader, mut writer) = stream.into_split();
+                    if let Err(err) = write_streaming_packet(&mut writer, &first_packet).await {
+                        warn_event!(
+                            agent = agent_id,
+                            remote = target,
+                            error = err;
+                            "failed to write to active tcp conn"
+                        );
+                    } else {
+                        {
+                            let mut writers = writers.lock().await;
+                            writers.insert(target, Arc::new(Mutex::new(writer)));
+                        }
+                        spawn_stream_reader(
+                            agent_id,
+                            reader,
+                            target,
+                            read_buffer_size,
+                            packets_tx,
+                            writers,
+                            closed_ch_rx,
+                        );
+                    }
+                }
+                Err(err) => {
+                    warn_event!(
+                        agent = agent_id,
+                        remote = target,
+                        local = local_ip,
+                        error = err;
+                        "failed to dial"
+                    );
+                }
+            }
+
+            let mut dialing = dialing.lock().await;
+            dialing.remove(&target);
+        });
+    }
+
+    async fn connect(
+        socket_factory: &Arc<dyn SocketFactory + Send + Sync>,
+        port_max: u16,
+        port_min: u16,
+        local_ip: IpAddr,
+        target: SocketAddr,
+    ) -> Result<TcpStream, IceError> {
+        let laddr = SocketAddr::new(local_ip, 0);
+        let socket = bind_in_port_range(port_max, port_min, laddr, |laddr| async move {
+            let socket = socket_factory.new_tcp(laddr)?;
+            socket.bind(laddr)?;
+            Ok(socket)
+        })
+        .await?;
+
+        match tokio::time::timeout(DIAL_TIMEOUT, socket.connect(target)).await {
+            Ok(result) => Ok(result?),
+            Err(_) => Err(IceError::Timeout("dialing the remote candidate")),
+        }
+    }
+}
+
+#[async_trait]
+impl Conn for ActiveTcpConn {
+    async fn connect(&self, _addr: SocketAddr) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "Not applicable"))
+    }
+
+    async fn recv(&self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::Other, "Not applicable"))
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut packets_rx = self.packets_rx.lock().await;
+        if let Some((packet, src_addr)) = packets_rx.recv().await {
+            let n = std::cmp::min(buf.len(), packet.len());
+            buf[..n].copy_from_slice(&packet[..n]);
+            Ok((n, src_addr))
+        } else {
+            Err(io::Error::new(io::ErrorKind::Other, IceError::Closed))
+        }
+    }
+
+    async fn send(&self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::Other, "Not applicable"))
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        let writer = {
+            let writers = self.writers.lock().await;
+            writers.get(&target).cloned()
+        };
+
+        if let Some(writer) = writer {
+            let mut writer = writer.lock().await;
+            write_streaming_packet(&mut *writer, buf).await?;
+        } else {
+            self.dial(buf.to_vec(), target).await;
+        }
+
+        Ok(buf.len())
+    }
+
+    async fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(SocketAddr::new(self.local_ip, 0))
+    }
+}
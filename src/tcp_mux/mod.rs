@@ -0,0 +1,495 @@
+#[cfg(test)]
+mod tcp_mux_test;
+
+pub(crate) mod active_tcp_conn;
+pub(crate) mod simultaneous_open_tcp_conn;
+
+use crate::errors::*;
+use crate::socket_factory::SocketFactory;
+use crate::udp_mux::ufrag_from_message;
+
+use stun::message::*;
+use util::Conn;
+
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc, Mutex};
+use tokio::time::Duration;
+
+/// The default size of the buffer used to read RFC 4571 framed packets.
+pub(crate) const DEFAULT_READ_BUFFER_SIZE: usize = 8192;
+
+/// The number of packets buffered per connection before new ones are dropped.
+pub(crate) const MAX_PENDING_PACKETS: usize = 128;
+
+/// The default time an accepted stream has to deliver its first packet before it is dropped.
+pub(crate) const DEFAULT_FIRST_PACKET_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Allows ICE agents to share a single listening TCP port for passive TCP candidates, see
+/// [RFC 6544](https://tools.ietf.org/html/rfc6544).
+#[async_trait]
+pub trait TcpMux {
+    /// Stops accepting connections and closes every connection handed out.
+    async fn close(&self) -> Result<(), IceError>;
+
+    /// Returns the connection carrying the TCP streams of the agent with the given local ufrag.
+    /// The warnings of the connection name the agent by `agent_id`, see `AgentConfig::agent_id`.
+    async fn get_conn_by_ufrag(
+        &self,
+        ufrag: &str,
+        agent_id: &str,
+    ) -> Result<Arc<dyn Conn + Send + Sync>, IceError>;
+
+    /// Closes and removes the connection of the agent with the given local ufrag.
+    async fn remove_conn_by_ufrag(&self, ufrag: &str);
+}
+
+/// The parameters used to create a `TcpMuxDefault`.
+pub struct TcpMuxParams {
+    pub listener: TcpListener,
+    /// The size of the buffer used to read a single framed packet. Leave it as 0 for the default.
+    pub read_buffer_size: usize,
+    /// How long an accepted stream may take to deliver its first packet before it is dropped.
+    /// Leave it as 0 for the default.
+    pub first_packet_timeout: Duration,
+}
+
+/// An implementation of `TcpMux` which routes every accepted TCP stream to an agent by the
+/// username fragment of the first STUN message received on it.
+pub struct TcpMuxDefault {
+    local_addr: SocketAddr,
+    read_buffer_size: usize,
+    first_packet_timeout: Duration,
+    conns: Arc<Mutex<HashMap<String, Arc<TcpPacketConn>>>>,
+    closed_ch_tx: Mutex<Option<broadcast::Sender<()>>>,
+}
+
+impl TcpMuxDefault {
+    /// Creates a new `TcpMuxDefault` and starts accepting connections on the listener.
+    pub fn new(params: TcpMuxParams) -> Result<Arc<Self>, IceError> {
+        let local_addr = params.listener.local_addr()?;
+        let read_buffer_size = if params.read_buffer_size == 0 {
+            DEFAULT_READ_BUFFER_SIZE
+        } else {
+            params.read_buffer_size
+        };
+        let first_packet_timeout = if params.first_packet_timeout == Duration::from_secs(0) {
+            DEFAULT_FIRST_PACKET_TIMEOUT
+        } else {
+            params.first_packet_timeout
+        };
+
+        let (closed_ch_tx, closed_ch_rx) = broadcast::channel(1);
+
+        let m = Arc::new(Self {
+            local_addr,
+            read_buffer_size,
+            first_packet_timeout,
+            conns: Arc::new(Mutex::new(HashMap::new())),
+            closed_ch_tx: Mutex::new(Some(closed_ch_tx)),
+        });
+
+        let conns = Arc::clone(&m.conns);
+        tokio::spawn(async move {
+            Self::accept_loop(
+                params.listener,
+                read_buffer_size,
+                first_packet_timeout,
+                conns,
+                closed_ch_rx,
+            )
+            .await;
+        });
+
+        Ok(m)
+    }
+
+    /// Returns the address the mux is listening on.
+    pub const fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    async fn accept_loop(
+        listener: TcpListener,
+        read_buffer_size: usize,
+        first_packet_timeout: Duration,
+        conns: Arc<Mutex<HashMap<String, Arc<TcpPacketConn>>>>,
+        mut closed_ch_rx: broadcast::Receiver<()>,
+    ) {
+        loop {
+            let (stream, remote_addr) = tokio::select! {
+                result = listener.accept() => match result {
+                    Ok((stream, remote_addr)) => (stream, remote_addr),
+                    Err(err) => {
+                        warn_event!(error = err; "tcp mux failed to accept");
+                        return;
+                    }
+                },
+                _ = closed_ch_rx.recv() => return,
+            };
+
+            let conns = Arc::clone(&conns);
+            tokio::spawn(async move {
+                Self::handle_conn(
+                    stream,
+                    remote_addr,
+                    read_buffer_size,
+                    first_packet_timeout,
+                    conns,
+                )
+                .await;
+            });
+        }
+    }
+
+    async fn handle_conn(
+        mut stream: TcpStream,
+        remote_addr: SocketAddr,
+        read_buffer_size: usize,
+        first_packet_timeout: Duration,
+        conns: Arc<Mutex<HashMap<String, Arc<TcpPacketConn>>>>,
+    ) {
+        let mut buffer = vec![0_u8; read_buffer_size];
+        // A stream which never sends anything would otherwise hold its task forever
+        let result = tokio::time::timeout(
+            first_packet_timeout,
+            read_streaming_packet(&mut stream, &mut buffer),
+        )
+        .await;
+        let n = match result {
+            Ok(Ok(n)) => n,
+            Ok(Err(err)) => {
+                warn_event!(
+                    remote = remote_addr,
+                    error = err;
+                    "tcp mux: failed to read first packet"
+                );
+                return;
+            }
+            Err(_) => {
+                warn_event!(remote = remote_addr; "tcp mux: timed out waiting for first packet");
+                return;
+            }
+        };
+
+        if !is_message(&buffer[..n]) {
+            warn_event!(remote = remote_addr; "tcp mux: first packet is not a STUN message");
+            return;
+        }
+
+        let ufrag = if let Some(ufrag) = ufrag_from_message(&buffer[..n]) {
+            ufrag
+        } else {
+            warn_event!(remote = remote_addr; "tcp mux: first STUN message has no username");
+            return;
+        };
+
+        let packet_conn = {
+            let conns = conns.lock().await;
+            conns.get(&ufrag).cloned()
+        };
+
+        if let Some(packet_conn) = packet_conn {
+            if let Err(err) = packet_conn
+                .add_conn(stream, remote_addr, &buffer[..n])
+                .await
+            {
+                warn_event!(
+                    agent = packet_conn.agent_id,
+                    remote = remote_addr,
+                    error = err;
+                    "tcp mux: failed to add conn"
+                );
+            }
+        } else {
+            warn_event!(ufrag = ufrag, remote = remote_addr; "tcp mux: no agent with ufrag");
+        }
+    }
+}
+
+#[async_trait]
+impl TcpMux for TcpMuxDefault {
+    async fn close(&self) -> Result<(), IceError> {
+        {
+            let mut closed_ch_tx = self.closed_ch_tx.lock().await;
+            if closed_ch_tx.take().is_none() {
+                return Err(IceError::TcpMuxClosed);
+            }
+        }
+
+        let mut conns = self.conns.lock().await;
+        for (_, conn) in conns.drain() {
+            conn.close().await;
+        }
+
+        Ok(())
+    }
+
+    async fn get_conn_by_ufrag(
+        &self,
+        ufrag: &str,
+        agent_id: &str,
+    ) -> Result<Arc<dyn Conn + Send + Sync>, IceError> {
+        {
+            let closed_ch_tx = self.closed_ch_tx.lock().await;
+            if closed_ch_tx.is_none() {
+                return Err(IceError::TcpMuxClosed);
+            }
+        }
+
+        let mut conns = self.conns.lock().await;
+        if let Some(conn) = conns.get(ufrag) {
+            return Ok(Arc::clone(conn) as Arc<dyn Conn + Send + Sync>);
+        }
+
+        let conn = Arc::new(TcpPacketConn::new(
+            agent_id.to_owned(),
+            self.local_addr,
+            self.read_buffer_size,
+        ));
+        conns.insert(ufrag.to_owned(), Arc::clone(&conn));
+
+        Ok(conn)
+    }
+
+    async fn remove_conn_by_ufrag(&self, ufrag: &str) {
+        let conn = {
+            let mut conns = self.conns.lock().await;
+            conns.remove(ufrag)
+        };
+
+        if let Some(conn) = conn {
+            conn.close().await;
+        }
+    }
+}
+
+/// A packet oriented view over all TCP streams routed to a single agent. Each stream is
+/// addressed by its remote address.
+pub struct TcpPacketConn {
+    agent_id: String,
+    local_addr: SocketAddr,
+    read_buffer_size: usize,
+    writers: StreamWriters,
+    // Dropped on close, so that reads fail once the stream readers have stopped
+    packets_tx: Mutex<Option<mpsc::Sender<(Vec<u8>, SocketAddr)>>>,
+    packets_rx: Mutex<mpsc::Receiver<(Vec<u8>, SocketAddr)>>,
+    closed_ch_tx: Mutex<Option<broadcast::Sender<()>>>,
+}
+
+impl TcpPacketConn {
+    fn new(agent_id: String, local_addr: SocketAddr, read_buffer_size: usize) -> Self {
+        let (packets_tx, packets_rx) = mpsc::channel(MAX_PENDING_PACKETS);
+        let (closed_ch_tx, _) = broadcast::channel(1);
+        Self {
+            agent_id,
+            local_addr,
+            read_buffer_size,
+            writers: Arc::new(Mutex::new(HashMap::new())),
+            packets_tx: Mutex::new(Some(packets_tx)),
+            packets_rx: Mutex::new(packets_rx),
+            closed_ch_tx: Mutex::new(Some(closed_ch_tx)),
+        }
+    }
+
+    /// Takes ownership of a TCP stream whose first framed packet has already been read.
+    pub(crate) async fn add_conn(
+        &self,
+        stream: TcpStream,
+        remote_addr: SocketAddr,
+        first_packet: &[u8],
+    ) -> Result<(), IceError> {
+        let closed_ch_rx = {
+            let closed_ch_tx = self.closed_ch_tx.lock().await;
+            if let Some(closed_ch_tx) = &*closed_ch_tx {
+                closed_ch_tx.subscribe()
+            } else {
+                return Err(IceError::TcpMuxClosed);
+            }
+        };
+        let packets_tx = {
+            let packets_tx = self.packets_tx.lock().await;
+            packets_tx.clone().ok_or(IceError::TcpMuxClosed)?
+        };
+
+        let (reader, writer) = stream.into_split();
+        {
+            let mut writers = self.writers.lock().await;
+            if writers.contains_key(&remote_addr) {
+                return Err(IceError::TcpRemoteAddrAlreadyExists);
+            }
+            writers.insert(remote_addr, Arc::new(Mutex::new(writer)));
+        }
+
+        let _ = packets_tx.try_send((first_packet.to_vec(), remote_addr));
+
+        spawn_stream_reader(
+            self.agent_id.clone(),
+            reader,
+            remote_addr,
+            self.read_buffer_size,
+            packets_tx,
+            Arc::clone(&self.writers),
+            closed_ch_rx,
+        );
+
+        Ok(())
+    }
+
+    async fn close(&self) {
+        {
+            let mut closed_ch_tx = self.closed_ch_tx.lock().await;
+            closed_ch_tx.take();
+        }
+        {
+            let mut packets_tx = self.packets_tx.lock().await;
+            packets_tx.take();
+        }
+
+        let mut writers = self.writers.lock().await;
+        writers.clear();
+    }
+}
+
+#[async_trait]
+impl Conn for TcpPacketConn {
+    async fn connect(&self, _addr: SocketAddr) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "Not applicable"))
+    }
+
+    async fn recv(&self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::Other, "Not applicable"))
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut packets_rx = self.packets_rx.lock().await;
+        if let Some((packet, src_addr)) = packets_rx.recv().await {
+            let n = std::cmp::min(buf.len(), packet.len());
+            buf[..n].copy_from_slice(&packet[..n]);
+            Ok((n, src_addr))
+        } else {
+            Err(io::Error::new(io::ErrorKind::Other, IceError::TcpMuxClosed))
+        }
+    }
+
+    async fn send(&self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::Other, "Not applicable"))
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        let writer = {
+            let writers = self.writers.lock().await;
+            writers.get(&target).cloned()
+        };
+
+        if let Some(writer) = writer {
+            let mut writer = writer.lock().await;
+            write_streaming_packet(&mut *writer, buf).await?;
+            Ok(buf.len())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!("error writing to {}", target),
+            ))
+        }
+    }
+
+    async fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+/// The write halves of TCP streams, keyed by remote address.
+pub(crate) type StreamWriters = Arc<Mutex<HashMap<SocketAddr, Arc<Mutex<OwnedWriteHalf>>>>>;
+
+/// Forwards the framed packets read from a TCP stream until it fails or `closed_ch_rx` fires,
+/// then forgets the writer of the stream so it gets closed.
+pub(crate) fn spawn_stream_reader(
+    agent_id: String,
+    mut reader: OwnedReadHalf,
+    remote_addr: SocketAddr,
+    read_buffer_size: usize,
+    packets_tx: mpsc::Sender<(Vec<u8>, SocketAddr)>,
+    writers: StreamWriters,
+    mut closed_ch_rx: broadcast::Receiver<()>,
+) {
+    tokio::spawn(async move {
+        let mut buffer = vec![0_u8; read_buffer_size];
+        loop {
+            let n = tokio::select! {
+                result = read_streaming_packet(&mut reader, &mut buffer) => match result {
+                    Ok(n) => n,
+                    Err(err) => {
+                        log::debug!("closing tcp conn from {}: {}", remote_addr, err);
+                        break;
+                    }
+                },
+                _ = closed_ch_rx.recv() => break,
+            };
+
+            if packets_tx
+                .try_send((buffer[..n].to_vec(), remote_addr))
+                .is_err()
+            {
+                warn_event!(
+                    agent = agent_id,
+                    remote = remote_addr;
+                    "dropping tcp packet, buffer is full"
+                );
+            }
+        }
+
+        let mut writers = writers.lock().await;
+        writers.remove(&remote_addr);
+    });
+}
+
+/// Reads a single packet framed as described in
+/// [RFC 4571](https://tools.ietf.org/html/rfc4571#section-2): a 16-bit big-endian length
+/// followed by the packet itself.
+pub(crate) async fn read_streaming_packet<R: AsyncRead + Unpin + Send>(
+    reader: &mut R,
+    buf: &mut [u8],
+) -> Result<usize, IceError> {
+    let mut header = [0_u8; 2];
+    reader.read_exact(&mut header).await?;
+
+    let length = usize::from(u16::from_be_bytes(header));
+    if length > buf.len() {
+        return Err(IceError::ReadingStreamingPacket(format!(
+            "packet of {} bytes exceeds buffer of {} bytes",
+            length,
+            buf.len()
+        )));
+    }
+
+    reader.read_exact(&mut buf[..length]).await?;
+
+    Ok(length)
+}
+
+/// Writes a single packet framed as described in
+/// [RFC 4571](https://tools.ietf.org/html/rfc4571#section-2).
+pub(crate) async fn write_streaming_packet<W: AsyncWrite + Unpin + Send>(
+    writer: &mut W,
+    buf: &[u8],
+) -> io::Result<()> {
+    let length = u16::try_from(buf.len()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("packet of {} bytes is too large to frame", buf.len()),
+        )
+    })?;
+
+    let mut framed = Vec::with_capacity(buf.len() + 2);
+    framed.extend_from_slice(&length.to_be_bytes());
+    framed.extend_from_slice(buf);
+    writer.write_all(&framed).await
+}
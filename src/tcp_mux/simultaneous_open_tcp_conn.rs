@@ -0,0 +1,318 @@
+use super::*;
+
+use crate::util::bind_in_port_range;
+use std::collections::HashSet;
+
+use std::net::IpAddr;
+use tokio::net::TcpSocket;
+use tokio::time::{Duration, Instant};
+
+/// The time allowed for an outbound TCP connection to be established, across all attempts.
+const DIAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The delay before the first connection attempt is retried, doubled after each failure.
+const INITIAL_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The upper bound of the delay between two connection attempts.
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The backlog of the listening socket accepting the peer's connections.
+const LISTEN_BACKLOG: u32 = 1024;
+
+/// The connection of a simultaneous-open TCP candidate, see
+/// [RFC 6544](https://tools.ietf.org/html/rfc6544#section-4.5).
+///
+/// The candidate is bound to a port which both accepts the peer's connections and dials the
+/// peer from, so that the SYNs of both sides can cross and open a single connection. The first
+/// packet sent to a remote address connects to it in the background, retrying with an
+/// exponential backoff since the peer's socket may not be listening yet. Until a stream is
+/// established, in either direction, further packets to that address are dropped.
+pub(crate) struct SimultaneousOpenTcpConn {
+    agent_id: String,
+    local_addr: SocketAddr,
+    socket_factory: Arc<dyn SocketFactory + Send + Sync>,
+    read_buffer_size: usize,
+    writers: StreamWriters,
+    dialing: Arc<Mutex<HashSet<SocketAddr>>>,
+    packets_tx: mpsc::Sender<(Vec<u8>, SocketAddr)>,
+    packets_rx: Mutex<mpsc::Receiver<(Vec<u8>, SocketAddr)>>,
+    // Dropping the sender stops the accept loop and the stream readers, which closes the streams
+    closed_ch_tx: broadcast::Sender<()>,
+}
+
+impl SimultaneousOpenTcpConn {
+    /// Binds a TCP port of the port range on the local IP and starts accepting connections on
+    /// it. The port is allocated by the system when `port_max` and `port_min` are 0.
+    pub(crate) async fn bind(
+        agent_id: String,
+        local_ip: IpAddr,
+        socket_factory: Arc<dyn SocketFactory + Send + Sync>,
+        port_max: u16,
+        port_min: u16,
+    ) -> Result<Self, IceError> {
+        let laddr = SocketAddr::new(local_ip, 0);
+        let listener = bind_in_port_range(port_max, port_min, laddr, |laddr| {
+            let socket_factory = &socket_factory;
+            async move { Ok(Self::new_socket(socket_factory, laddr)?.listen(LISTEN_BACKLOG)?) }
+        })
+        .await?;
+        let local_addr = listener.local_addr()?;
+
+        let (packets_tx, packets_rx) = mpsc::channel(MAX_PENDING_PACKETS);
+        let (closed_ch_tx, _) = broadcast::channel(1);
+        let conn = Self {
+            agent_id,
+            local_addr,
+            socket_factory,
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            writers: Arc::new(Mutex::new(HashMap::new())),
+            dialing: Arc::new(Mutex::new(HashSet::new())),
+            packets_tx,
+            packets_rx: Mutex::new(packets_rx),
+            closed_ch_tx,
+        };
+        conn.spawn_accept_loop(listener);
+
+        Ok(conn)
+    }
+
+    /// Creates a socket bound to `addr` which may share its port with the listening socket.
+    fn new_socket(
+        socket_factory: &Arc<dyn SocketFactory + Send + Sync>,
+        addr: SocketAddr,
+    ) -> io::Result<TcpSocket> {
+        let socket = socket_factory.new_tcp(addr)?;
+        socket.set_reuseaddr(true)?;
+        #[cfg(unix)]
+        socket.set_reuseport(true)?;
+        socket.bind(addr)?;
+
+        Ok(socket)
+    }
+
+    fn spawn_accept_loop(&self, listener: TcpListener) {
+        let agent_id = self.agent_id.clone();
+        let read_buffer_size = self.read_buffer_size;
+        let writers = Arc::clone(&self.writers);
+        let packets_tx = self.packets_tx.clone();
+        let mut closed_ch_rx = self.closed_ch_tx.subscribe();
+        tokio::spawn(async move {
+            loop {
+                let (stream, remote_addr) = tokio::select! {
+                    result = listener.accept() => match result {
+                        Ok(accepted) => accepted,
+                        Err(err) => {
+                            warn_event!(
+                                agent = agent_id,
+                                error = err;
+                                "failed to accept simultaneous-open tcp conn"
+                            );
+                            break;
+                        }
+                    },
+                    _ = closed_ch_rx.recv() => break,
+                };
+
+                Self::add_stream(
+                    &agent_id,
+                    stream,
+                    remote_addr,
+                    read_buffer_size,
+                    &writers,
+                    &packets_tx,
+                    &closed_ch_rx,
+                )
+                .await;
+            }
+        });
+    }
+
+    /// Starts reading the stream, and writes to it unless another stream to the same remote
+    /// address was established first, e.g. when both sides' connections succeeded.
+    async fn add_stream(
+        agent_id: &str,
+        stream: TcpStream,
+        remote_addr: SocketAddr,
+        read_buffer_size: usize,
+        writers: &StreamWriters,
+        packets_tx: &mpsc::Sender<(Vec<u8>, SocketAddr)>,
+        closed_ch_rx: &broadcast::Receiver<()>,
+    ) -> Arc<Mutex<OwnedWriteHalf>> {
+        let (reader, writer) = stream.into_split();
+        let writer = {
+            let mut writers = writers.lock().await;
+            Arc::clone(
+                writers
+                    .entry(remote_addr)
+                    .or_insert_with(|| Arc::new(Mutex::new(writer))),
+            )
+        };
+        spawn_stream_reader(
+            agent_id.to_owned(),
+            reader,
+            remote_addr,
+            read_buffer_size,
+            packets_tx.clone(),
+            Arc::clone(writers),
+            closed_ch_rx.resubscribe(),
+        );
+
+        writer
+    }
+
+    async fn dial(&self, first_packet: Vec<u8>, target: SocketAddr) {
+        {
+            let mut dialing = self.dialing.lock().await;
+            if !dialing.insert(target) {
+                return;
+            }
+        }
+
+        let agent_id = self.agent_id.clone();
+        let local_addr = self.local_addr;
+        let socket_factory = Arc::clone(&self.socket_factory);
+        let read_buffer_size = self.read_buffer_size;
+        let writers = Arc::clone(&self.writers);
+        let dialing = Arc::clone(&self.dialing);
+        let packets_tx = self.packets_tx.clone();
+        let mut closed_ch_rx = self.closed_ch_tx.subscribe();
+        tokio::spawn(async move {
+            let result = tokio::select! {
+                result = Self::connect(&socket_factory, local_addr, target, &writers) => result,
+                _ = closed_ch_rx.recv() => Ok(None),
+            };
+
+            let writer = match result {
+                Ok(Some(stream)) => Some(
+                    Self::add_stream(
+                        &agent_id,
+                        stream,
+                        target,
+                        read_buffer_size,
+                        &writers,
+                        &packets_tx,
+                        &closed_ch_rx,
+                    )
+                    .await,
+                ),
+                // The peer's connection was accepted while dialing, or the conn was closed
+                Ok(None) => {
+                    let writers = writers.lock().await;
+                    writers.get(&target).cloned()
+                }
+                Err(err) => {
+                    warn_event!(
+                        agent = agent_id,
+                        remote = target,
+                        local = local_addr,
+                        error = err;
+                        "failed to dial"
+                    );
+                    None
+                }
+            };
+
+            if let Some(writer) = writer {
+                let mut writer = writer.lock().await;
+                if let Err(err) = write_streaming_packet(&mut *writer, &first_packet).await {
+                    warn_event!(
+                        agent = agent_id,
+                        remote = target,
+                        error = err;
+                        "failed to write to simultaneous-open tcp conn"
+                    );
+                }
+            }
+
+            let mut dialing = dialing.lock().await;
+            dialing.remove(&target);
+        });
+    }
+
+    /// Connects to `target` from the local port until it succeeds, a stream to `target` is
+    /// accepted in the meantime, or `DIAL_TIMEOUT` elapses.
+    async fn connect(
+        socket_factory: &Arc<dyn SocketFactory + Send + Sync>,
+        local_addr: SocketAddr,
+        target: SocketAddr,
+        writers: &StreamWriters,
+    ) -> io::Result<Option<TcpStream>> {
+        let deadline = Instant::now() + DIAL_TIMEOUT;
+        let mut retry_interval = INITIAL_RETRY_INTERVAL;
+        loop {
+            if writers.lock().await.contains_key(&target) {
+                return Ok(None);
+            }
+
+            let socket = Self::new_socket(socket_factory, local_addr)?;
+            let err = match tokio::time::timeout_at(deadline, socket.connect(target)).await {
+                Ok(Ok(stream)) => return Ok(Some(stream)),
+                Ok(Err(err)) => err,
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("dialing {} timed out", target),
+                    ))
+                }
+            };
+
+            if Instant::now() + retry_interval >= deadline {
+                return Err(err);
+            }
+            log::trace!(
+                "retrying to dial {} in {:?}: {}",
+                target,
+                retry_interval,
+                err
+            );
+            tokio::time::sleep(retry_interval).await;
+            retry_interval = std::cmp::min(retry_interval * 2, MAX_RETRY_INTERVAL);
+        }
+    }
+}
+
+#[async_trait]
+impl Conn for SimultaneousOpenTcpConn {
+    async fn connect(&self, _addr: SocketAddr) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "Not applicable"))
+    }
+
+    async fn recv(&self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::Other, "Not applicable"))
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut packets_rx = self.packets_rx.lock().await;
+        if let Some((packet, src_addr)) = packets_rx.recv().await {
+            let n = std::cmp::min(buf.len(), packet.len());
+            buf[..n].copy_from_slice(&packet[..n]);
+            Ok((n, src_addr))
+        } else {
+            Err(io::Error::new(io::ErrorKind::Other, IceError::Closed))
+        }
+    }
+
+    async fn send(&self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::Other, "Not applicable"))
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        let writer = {
+            let writers = self.writers.lock().await;
+            writers.get(&target).cloned()
+        };
+
+        if let Some(writer) = writer {
+            let mut writer = writer.lock().await;
+            write_streaming_packet(&mut *writer, buf).await?;
+        } else {
+            self.dial(buf.to_vec(), target).await;
+        }
+
+        Ok(buf.len())
+    }
+
+    async fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
@@ -0,0 +1,258 @@
+use super::*;
+use crate::socket_factory::TokioSocketFactory;
+
+use stun::{agent::*, attributes::*, textattrs::*};
+use tokio::time::Duration;
+
+#[tokio::test]
+async fn test_tcp_mux_routes_conn_by_ufrag() -> Result<(), IceError> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let tcp_mux = TcpMuxDefault::new(TcpMuxParams {
+        listener,
+        read_buffer_size: 0,
+        first_packet_timeout: Duration::from_secs(0),
+    })?;
+    let mux_addr = tcp_mux.local_addr();
+
+    let conn = tcp_mux.get_conn_by_ufrag("myufrag", "agent").await?;
+    assert_eq!(mux_addr, conn.local_addr().await?, "should match");
+
+    let mut stream = TcpStream::connect(mux_addr).await?;
+    let stream_addr = stream.local_addr()?;
+
+    let mut m = Message::new();
+    m.build(&[
+        Box::new(BINDING_REQUEST),
+        Box::new(TransactionId::new()),
+        Box::new(Username::new(
+            ATTR_USERNAME,
+            "myufrag:otherufrag".to_owned(),
+        )),
+    ])?;
+    write_streaming_packet(&mut stream, &m.raw).await?;
+
+    let mut buf = vec![0_u8; DEFAULT_READ_BUFFER_SIZE];
+    let (n, src_addr) = tokio::time::timeout(Duration::from_secs(5), conn.recv_from(&mut buf))
+        .await
+        .expect("first packet should be routed by ufrag")?;
+    assert_eq!(&m.raw[..], &buf[..n], "should match");
+    assert_eq!(stream_addr, src_addr, "should match");
+
+    conn.send_to(b"hello", src_addr).await?;
+    let n = read_streaming_packet(&mut stream, &mut buf).await?;
+    assert_eq!(b"hello", &buf[..n], "should match");
+
+    write_streaming_packet(&mut stream, b"world").await?;
+    let (n, _) = tokio::time::timeout(Duration::from_secs(5), conn.recv_from(&mut buf))
+        .await
+        .expect("subsequent packets should be routed")?;
+    assert_eq!(b"world", &buf[..n], "should match");
+
+    tcp_mux.remove_conn_by_ufrag("myufrag").await;
+    assert!(
+        conn.send_to(b"hello", src_addr).await.is_err(),
+        "removed conn should not write"
+    );
+
+    tcp_mux.close().await?;
+    assert!(
+        tcp_mux.get_conn_by_ufrag("myufrag", "agent").await.is_err(),
+        "mux is closed"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tcp_mux_ignores_non_stun_first_packet() -> Result<(), IceError> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let tcp_mux = TcpMuxDefault::new(TcpMuxParams {
+        listener,
+        read_buffer_size: 0,
+        first_packet_timeout: Duration::from_secs(0),
+    })?;
+
+    let conn = tcp_mux.get_conn_by_ufrag("myufrag", "agent").await?;
+
+    let mut stream = TcpStream::connect(tcp_mux.local_addr()).await?;
+    write_streaming_packet(&mut stream, b"not a stun message").await?;
+
+    let mut buf = vec![0_u8; DEFAULT_READ_BUFFER_SIZE];
+    let result = tokio::time::timeout(Duration::from_millis(100), conn.recv_from(&mut buf)).await;
+    assert!(result.is_err(), "should not receive anything");
+
+    tcp_mux.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tcp_mux_drops_idle_stream() -> Result<(), IceError> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let tcp_mux = TcpMuxDefault::new(TcpMuxParams {
+        listener,
+        read_buffer_size: 0,
+        first_packet_timeout: Duration::from_millis(100),
+    })?;
+
+    let mut stream = TcpStream::connect(tcp_mux.local_addr()).await?;
+
+    // The mux closes the stream once the first packet is overdue
+    let mut buf = vec![0_u8; DEFAULT_READ_BUFFER_SIZE];
+    let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+        .await
+        .expect("idle stream should be dropped")?;
+    assert_eq!(0, n, "stream should be closed");
+
+    tcp_mux.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tcp_mux_conn_fails_after_close() -> Result<(), IceError> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let tcp_mux = TcpMuxDefault::new(TcpMuxParams {
+        listener,
+        read_buffer_size: 0,
+        first_packet_timeout: Duration::from_secs(0),
+    })?;
+
+    let conn_a = tcp_mux.get_conn_by_ufrag("ufragA", "agentA").await?;
+    let conn_b = tcp_mux.get_conn_by_ufrag("ufragB", "agentB").await?;
+
+    let mut buf = vec![0_u8; DEFAULT_READ_BUFFER_SIZE];
+    tcp_mux.remove_conn_by_ufrag("ufragA").await;
+    let result = tokio::time::timeout(Duration::from_secs(5), conn_a.recv_from(&mut buf))
+        .await
+        .expect("removed conn should stop reading");
+    assert!(result.is_err(), "removed conn should fail to read");
+
+    tcp_mux.close().await?;
+    let result = tokio::time::timeout(Duration::from_secs(5), conn_b.recv_from(&mut buf))
+        .await
+        .expect("closing the mux should stop reading");
+    assert!(result.is_err(), "conn should fail to read");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_active_tcp_conn_dials_tcp_mux() -> Result<(), IceError> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let tcp_mux = TcpMuxDefault::new(TcpMuxParams {
+        listener,
+        read_buffer_size: 0,
+        first_packet_timeout: Duration::from_secs(0),
+    })?;
+    let passive = tcp_mux.get_conn_by_ufrag("passive", "agent").await?;
+
+    let active = active_tcp_conn::ActiveTcpConn::new(
+        "agent".to_owned(),
+        "127.0.0.1".parse().unwrap(),
+        Arc::new(TokioSocketFactory::default()),
+        0,
+        0,
+    );
+
+    let mut m = Message::new();
+    m.build(&[
+        Box::new(BINDING_REQUEST),
+        Box::new(TransactionId::new()),
+        Box::new(Username::new(ATTR_USERNAME, "passive:active".to_owned())),
+    ])?;
+    active.send_to(&m.raw, tcp_mux.local_addr()).await?;
+
+    let mut buf = vec![0_u8; DEFAULT_READ_BUFFER_SIZE];
+    let (n, active_addr) =
+        tokio::time::timeout(Duration::from_secs(5), passive.recv_from(&mut buf))
+            .await
+            .expect("dialed conn should be routed to the passive side")?;
+    assert_eq!(&m.raw[..], &buf[..n], "should match");
+
+    passive.send_to(b"response", active_addr).await?;
+    let (n, src_addr) = tokio::time::timeout(Duration::from_secs(5), active.recv_from(&mut buf))
+        .await
+        .expect("active side should receive the response")?;
+    assert_eq!(b"response", &buf[..n], "should match");
+    assert_eq!(tcp_mux.local_addr(), src_addr, "should match");
+
+    tcp_mux.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_simultaneous_open_tcp_conns_connect() -> Result<(), IceError> {
+    let a = simultaneous_open_tcp_conn::SimultaneousOpenTcpConn::bind(
+        "agent".to_owned(),
+        "127.0.0.1".parse().unwrap(),
+        Arc::new(TokioSocketFactory::default()),
+        0,
+        0,
+    )
+    .await?;
+    let b = simultaneous_open_tcp_conn::SimultaneousOpenTcpConn::bind(
+        "agent".to_owned(),
+        "127.0.0.1".parse().unwrap(),
+        Arc::new(TokioSocketFactory::default()),
+        0,
+        0,
+    )
+    .await?;
+    let a_addr = a.local_addr().await?;
+    let b_addr = b.local_addr().await?;
+    assert_ne!(0, a_addr.port(), "should be bound to a port");
+
+    a.send_to(b"request", b_addr).await?;
+    let mut buf = vec![0_u8; DEFAULT_READ_BUFFER_SIZE];
+    let (n, src_addr) = tokio::time::timeout(Duration::from_secs(5), b.recv_from(&mut buf))
+        .await
+        .expect("b should receive the request")?;
+    assert_eq!(b"request", &buf[..n], "should match");
+    assert_eq!(a_addr, src_addr, "should be dialed from the bound port");
+
+    b.send_to(b"response", a_addr).await?;
+    let (n, src_addr) = tokio::time::timeout(Duration::from_secs(5), a.recv_from(&mut buf))
+        .await
+        .expect("a should receive the response")?;
+    assert_eq!(b"response", &buf[..n], "should match");
+    assert_eq!(b_addr, src_addr, "should match");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tcp_conns_bind_in_port_range() -> Result<(), IceError> {
+    let (port_min, port_max) = (44500, 44600);
+    let socket_factory: Arc<dyn SocketFactory + Send + Sync> =
+        Arc::new(TokioSocketFactory::default());
+
+    let a = simultaneous_open_tcp_conn::SimultaneousOpenTcpConn::bind(
+        "agent".to_owned(),
+        "127.0.0.1".parse().unwrap(),
+        Arc::clone(&socket_factory),
+        port_max,
+        port_min,
+    )
+    .await?;
+    let a_addr = a.local_addr().await?;
+    assert!((port_min..=port_max).contains(&a_addr.port()));
+
+    // The active side dials from the range as well
+    let active = active_tcp_conn::ActiveTcpConn::new(
+        "agent".to_owned(),
+        "127.0.0.1".parse().unwrap(),
+        socket_factory,
+        port_max,
+        port_min,
+    );
+    active.send_to(b"request", a_addr).await?;
+    let mut buf = vec![0_u8; DEFAULT_READ_BUFFER_SIZE];
+    let (_, src_addr) = tokio::time::timeout(Duration::from_secs(5), a.recv_from(&mut buf))
+        .await
+        .expect("the connection should be accepted")?;
+    assert!((port_min..=port_max).contains(&src_addr.port()));
+
+    Ok(())
+}
@@ -0,0 +1,36 @@
+use crate::errors::*;
+
+use async_trait::async_trait;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use util::Conn;
+
+/// A byte stream secured by TLS, e.g. a `tokio_rustls::client::TlsStream<TcpStream>`.
+pub trait TlsStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> TlsStream for T {}
+
+/// Secures the connections to `turns:` servers, so that the agent doesn't depend on a given TLS
+/// or DTLS implementation. It is required as soon as `AgentConfig::urls` holds a `turns:` URL.
+#[async_trait]
+pub trait TlsDialer {
+    /// Runs the TLS handshake with the TURN server `server_name` over `stream`, which is
+    /// connected to it, e.g. through the proxy of `AgentConfig::proxy_dialer`. Used for
+    /// `turns:` URLs with the TCP transport, the default.
+    async fn connect_tls(
+        &self,
+        server_name: &str,
+        stream: TcpStream,
+    ) -> Result<Box<dyn TlsStream>, IceError>;
+
+    /// Runs the DTLS handshake with the TURN server `server_name` over `conn`, which is
+    /// connected to it, and returns the connection the TURN messages are exchanged on. It is
+    /// addressed with `send_to` and `recv_from` like `conn`, the address being the one of the
+    /// server. Used for `turns:` URLs with `?transport=udp`.
+    async fn connect_dtls(
+        &self,
+        server_name: &str,
+        conn: Arc<dyn Conn + Send + Sync>,
+    ) -> Result<Arc<dyn Conn + Send + Sync>, IceError>;
+}
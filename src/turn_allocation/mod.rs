@@ -0,0 +1,382 @@
+#[cfg(test)]
+mod turn_allocation_test;
+
+use crate::errors::*;
+
+use stun::agent::TransactionId;
+use stun::attributes::*;
+use stun::fingerprint::FINGERPRINT;
+use stun::integrity::MessageIntegrity;
+use stun::message::*;
+use stun::textattrs::*;
+use turn::proto::lifetime::Lifetime;
+use util::Conn;
+
+use async_trait::async_trait;
+use rand::Rng;
+use std::collections::HashSet;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// How much longer than `AgentConfig::turn_allocation_lifetime` an allocation may ask for, so
+/// that the refreshes of the allocations made at once spread out.
+const LIFETIME_JITTER: f64 = 0.2;
+
+/// The code of the 438 (Stale Nonce) error responses.
+pub(crate) const STALE_NONCE: u16 = 438;
+
+#[derive(Default)]
+struct AllocationState {
+    /// The TURN server, once the client sent its Allocate request to it.
+    server_addr: Option<SocketAddr>,
+    username: String,
+    realm: String,
+    nonce: String,
+    /// The lifetime the Allocate requests ask for, picked once for the allocation.
+    requested_lifetime: Option<Duration>,
+    /// When the allocation ends, as granted by the server on the last allocation or refresh.
+    expires_at: Option<Instant>,
+    /// The refreshes sent by the client, waiting for their response.
+    refreshes: HashSet<TransactionId>,
+    /// The refreshes that failed, not reported yet.
+    failures: Vec<IceError>,
+    closed: bool,
+}
+
+/// Wraps the transport of a TURN client to manage the lifetime of its allocation, which the
+/// client doesn't expose: the Allocate request asks for a lifetime of
+/// `AgentConfig::turn_allocation_lifetime` with some jitter, and the lifetime granted by the
+/// server is read from the responses to it and to the refreshes of the client, whose failures
+/// are reported. The responses of the server are handed to the client untouched, as they are
+/// authenticated.
+pub(crate) struct AllocationConn {
+    conn: Arc<dyn Conn + Send + Sync>,
+    password: String,
+    requested_lifetime: Duration,
+    state: std::sync::Mutex<AllocationState>,
+    changed: Notify,
+}
+
+impl AllocationConn {
+    pub(crate) fn new(
+        conn: Arc<dyn Conn + Send + Sync>,
+        password: String,
+        requested_lifetime: Duration,
+    ) -> Self {
+        Self {
+            conn,
+            password,
+            requested_lifetime,
+            state: std::sync::Mutex::new(AllocationState::default()),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Returns the time left before the allocation expires unless it is refreshed, None until
+    /// it is granted and once it expired or was closed.
+    pub(crate) fn expires_in(&self) -> Option<Duration> {
+        self.state.lock().unwrap().expires_in()
+    }
+
+    /// Reports the refreshes of the allocation that fail until it is closed or expires, calling
+    /// `on_refresh_failed` with the error and the lifetime left each time. An allocation which
+    /// expires without being refreshed is reported with `IceError::Timeout`.
+    pub(crate) async fn watch_refreshes<F>(self: Arc<Self>, on_refresh_failed: F)
+    where
+        F: Fn(IceError, Option<Duration>) + Send + 'static,
+    {
+        loop {
+            // Created before the state is checked, so that a change made in between wakes it
+            let changed = self.changed.notified();
+            let (failures, expires_in) = {
+                let mut state = self.state.lock().unwrap();
+                if state.closed || state.expires_at.is_none() {
+                    return;
+                }
+                (std::mem::take(&mut state.failures), state.expires_in())
+            };
+
+            for err in failures {
+                warn_event!(error = err; "failed to refresh the TURN allocation");
+                on_refresh_failed(err, expires_in);
+            }
+
+            let expires_in = match expires_in {
+                Some(expires_in) => expires_in,
+                None => {
+                    on_refresh_failed(IceError::Timeout("refreshing the TURN allocation"), None);
+                    return;
+                }
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(expires_in) => {}
+                _ = changed => {}
+            }
+        }
+    }
+
+    /// Deletes the allocation with a refresh of zero lifetime, sent without waiting for its
+    /// response, and stops watching it.
+    pub(crate) async fn close(&self) {
+        let request = {
+            let mut state = self.state.lock().unwrap();
+            if state.closed {
+                return;
+            }
+            state.closed = true;
+            if state.expires_at.is_some() {
+                state
+                    .server_addr
+                    .zip(self.refresh_request(&state, Duration::from_secs(0)).ok())
+            } else {
+                None
+            }
+        };
+        self.changed.notify_one();
+
+        if let Some((server_addr, request)) = request {
+            if let Err(err) = self.conn.send_to(&request.raw, server_addr).await {
+                log::debug!("failed to delete the TURN allocation: {}", err);
+            }
+        }
+    }
+
+    fn refresh_request(
+        &self,
+        state: &AllocationState,
+        lifetime: Duration,
+    ) -> Result<Message, IceError> {
+        let integrity = MessageIntegrity::new_long_term_integrity(
+            state.username.clone(),
+            state.realm.clone(),
+            self.password.clone(),
+        );
+
+        let mut request = Message::new();
+        request
+            .build(&[
+                Box::new(TransactionId::new()),
+                Box::new(MessageType::new(METHOD_REFRESH, CLASS_REQUEST)),
+                Box::new(Lifetime(lifetime)),
+                Box::new(Username::new(ATTR_USERNAME, state.username.clone())),
+                Box::new(Realm::new(ATTR_REALM, state.realm.clone())),
+                Box::new(Nonce::new(ATTR_NONCE, state.nonce.clone())),
+                Box::new(integrity),
+                Box::new(FINGERPRINT),
+            ])
+            .map_err(IceError::Stun)?;
+
+        Ok(request)
+    }
+
+    /// Looks into a request of the client, keeping the credentials and nonce it is sent with for
+    /// deleting the allocation, and the refreshes to look into their response. Returns the
+    /// Allocate request asking for the requested lifetime, to be sent instead of the original
+    /// one.
+    pub(crate) fn on_request(&self, buf: &[u8], target: SocketAddr) -> Option<Vec<u8>> {
+        let request = decode(buf).filter(|request| request.typ.class == CLASS_REQUEST)?;
+
+        let (lifetime, integrity) = {
+            let mut state = self.state.lock().unwrap();
+            if let Ok(username) = Username::get_from_as(&request, ATTR_USERNAME) {
+                state.username = username.text;
+            }
+            if let Ok(realm) = Realm::get_from_as(&request, ATTR_REALM) {
+                state.realm = realm.text;
+            }
+            if let Ok(nonce) = Nonce::get_from_as(&request, ATTR_NONCE) {
+                state.nonce = nonce.text;
+            }
+            if request.typ.method == METHOD_REFRESH {
+                state.refreshes.insert(request.transaction_id);
+                return None;
+            }
+            if request.typ.method != METHOD_ALLOCATE {
+                return None;
+            }
+            state.server_addr = Some(target);
+
+            // The client refreshes the allocation halfway through the lifetime it is granted,
+            // so the jitter of the lifetime spreads the refreshes out
+            let requested_lifetime = self.requested_lifetime;
+            let lifetime = *state.requested_lifetime.get_or_insert_with(|| {
+                requested_lifetime.mul_f64(rand::thread_rng().gen_range(1.0..1.0 + LIFETIME_JITTER))
+            });
+            let integrity = MessageIntegrity::new_long_term_integrity(
+                state.username.clone(),
+                state.realm.clone(),
+                self.password.clone(),
+            );
+            (lifetime, integrity)
+        };
+
+        match with_lifetime(&request, lifetime, &integrity) {
+            Ok(request) => Some(request.raw),
+            Err(err) => {
+                warn_event!(error = err; "failed to request a TURN allocation lifetime");
+                None
+            }
+        }
+    }
+
+    /// Looks into a response of the server without changing it. The lifetime granted by the
+    /// responses to the Allocate request and the refreshes is kept, and the refreshes which
+    /// fail are queued for `watch_refreshes`, except those answered with 438 (Stale Nonce),
+    /// which the client retries with the new nonce.
+    pub(crate) fn on_response(&self, buf: &[u8]) {
+        let response = match decode(buf) {
+            Some(response)
+                if response.typ.class == CLASS_SUCCESS_RESPONSE
+                    || response.typ.class == CLASS_ERROR_RESPONSE =>
+            {
+                response
+            }
+            _ => return,
+        };
+
+        {
+            let mut state = self.state.lock().unwrap();
+            let is_refresh = state.refreshes.remove(&response.transaction_id);
+            if !is_refresh && response.typ.method != METHOD_ALLOCATE {
+                return;
+            }
+
+            if response.typ.class == CLASS_SUCCESS_RESPONSE {
+                let mut lifetime = Lifetime::default();
+                if lifetime.get_from(&response).is_err() {
+                    return;
+                }
+                log::debug!("TURN allocation granted for {}", lifetime);
+                state.granted(lifetime.0);
+            } else if is_refresh {
+                let (code, reason) = match error_code(&response) {
+                    Some((code, _)) if code == STALE_NONCE => return,
+                    Some(error_code) => error_code,
+                    None => return,
+                };
+                state
+                    .failures
+                    .push(IceError::TurnErrorResponse { code, reason });
+            } else {
+                return;
+            }
+        }
+        self.changed.notify_one();
+    }
+}
+
+impl AllocationState {
+    fn granted(&mut self, lifetime: Duration) {
+        // A lifetime of zero deletes the allocation
+        if lifetime == Duration::from_secs(0) {
+            self.closed = true;
+        }
+        self.expires_at = Some(Instant::now() + lifetime);
+    }
+
+    fn expires_in(&self) -> Option<Duration> {
+        if self.closed {
+            return None;
+        }
+        self.expires_at?
+            .checked_duration_since(Instant::now())
+            .filter(|&expires_in| expires_in > Duration::from_secs(0))
+    }
+}
+
+#[async_trait]
+impl Conn for AllocationConn {
+    async fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+        self.conn.connect(addr).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let (n, _) = self.recv_from(buf).await?;
+        Ok(n)
+    }
+
+    /// Every STUN message comes from the TURN server, as the client sends no request to any
+    /// other, and the transport to it may be a proxied stream whose address isn't the server's.
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (n, addr) = self.conn.recv_from(buf).await?;
+        if is_message(&buf[..n]) {
+            self.on_response(&buf[..n]);
+        }
+        Ok((n, addr))
+    }
+
+    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.conn.send(buf).await
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        if is_message(buf) {
+            if let Some(request) = self.on_request(buf, target) {
+                self.conn.send_to(&request, target).await?;
+                return Ok(buf.len());
+            }
+        }
+        self.conn.send_to(buf, target).await
+    }
+
+    async fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.conn.local_addr().await
+    }
+}
+
+fn decode(buf: &[u8]) -> Option<Message> {
+    let mut m = Message {
+        raw: buf.to_vec(),
+        ..Message::default()
+    };
+    m.decode().ok()?;
+    Some(m)
+}
+
+/// Returns the code and the reason of the ERROR-CODE attribute of `response`, whose code
+/// `ErrorCodeAttribute` doesn't expose.
+fn error_code(response: &Message) -> Option<(u16, String)> {
+    let value = response.get(ATTR_ERROR_CODE).ok()?;
+    if value.len() < 4 {
+        return None;
+    }
+    let code = u16::from(value[2] & 0x07) * 100 + u16::from(value[3]);
+    Some((code, String::from_utf8_lossy(&value[4..]).into_owned()))
+}
+
+/// Returns `request` with a LIFETIME attribute asking for `lifetime`. Its MESSAGE-INTEGRITY and
+/// FINGERPRINT attributes, if any, are computed anew, as they cover the attributes before them.
+pub(crate) fn with_lifetime(
+    request: &Message,
+    lifetime: Duration,
+    integrity: &MessageIntegrity,
+) -> Result<Message, IceError> {
+    let mut m = Message::new();
+    m.build(&[Box::new(request.transaction_id), Box::new(request.typ)])
+        .map_err(IceError::Stun)?;
+    for attr in &request.attributes.0 {
+        if attr.typ != ATTR_LIFETIME
+            && attr.typ != ATTR_MESSAGE_INTEGRITY
+            && attr.typ != ATTR_FINGERPRINT
+        {
+            m.add(attr.typ, &attr.value);
+        }
+    }
+
+    let mut setters: Vec<Box<dyn Setter>> = vec![Box::new(Lifetime(lifetime))];
+    if request.contains(ATTR_MESSAGE_INTEGRITY) {
+        setters.push(Box::new(integrity.clone()));
+    }
+    if request.contains(ATTR_FINGERPRINT) {
+        setters.push(Box::new(FINGERPRINT));
+    }
+    for setter in setters {
+        setter.add_to(&mut m).map_err(IceError::Stun)?;
+    }
+
+    Ok(m)
+}
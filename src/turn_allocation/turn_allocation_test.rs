@@ -0,0 +1,165 @@
+use super::*;
+use stun::error_code::*;
+
+fn allocate_request(integrity: &MessageIntegrity) -> Result<Message, IceError> {
+    let mut m = Message::new();
+    m.build(&[
+        Box::new(TransactionId::new()),
+        Box::new(MessageType::new(METHOD_ALLOCATE, CLASS_REQUEST)),
+        Box::new(Username::new(ATTR_USERNAME, "user".to_owned())),
+        Box::new(Realm::new(ATTR_REALM, "webrtc.rs".to_owned())),
+        Box::new(Nonce::new(ATTR_NONCE, "nonce".to_owned())),
+        Box::new(integrity.clone()),
+        Box::new(FINGERPRINT),
+    ])
+    .map_err(IceError::Stun)?;
+    Ok(m)
+}
+
+#[test]
+fn test_with_lifetime() -> Result<(), IceError> {
+    let integrity = MessageIntegrity::new_long_term_integrity(
+        "user".to_owned(),
+        "webrtc.rs".to_owned(),
+        "pass".to_owned(),
+    );
+    let request = allocate_request(&integrity)?;
+
+    let rewritten = with_lifetime(&request, Duration::from_secs(120), &integrity)?;
+    let mut decoded = decode(&rewritten.raw).expect("the request should decode");
+    assert_eq!(decoded.transaction_id, request.transaction_id);
+    assert_eq!(decoded.typ, request.typ);
+
+    let mut lifetime = Lifetime::default();
+    lifetime.get_from(&decoded).map_err(IceError::Stun)?;
+    assert_eq!(lifetime.0, Duration::from_secs(120));
+    let nonce = Nonce::get_from_as(&decoded, ATTR_NONCE).map_err(IceError::Stun)?;
+    assert_eq!(nonce.text, "nonce");
+
+    // The attributes protecting the message cover the added lifetime
+    FINGERPRINT.check(&decoded).map_err(IceError::Stun)?;
+    integrity.check(&mut decoded).map_err(IceError::Stun)?;
+
+    Ok(())
+}
+
+fn response(
+    request: &Message,
+    class: MessageClass,
+    setters: Vec<Box<dyn Setter>>,
+) -> Result<Message, IceError> {
+    let mut m = Message::new();
+    m.build(&[
+        Box::new(request.transaction_id),
+        Box::new(MessageType::new(request.typ.method, class)),
+    ])
+    .map_err(IceError::Stun)?;
+    for setter in setters {
+        setter.add_to(&mut m).map_err(IceError::Stun)?;
+    }
+    Ok(m)
+}
+
+fn refresh_request() -> Result<Message, IceError> {
+    let mut m = Message::new();
+    m.build(&[
+        Box::new(TransactionId::new()),
+        Box::new(MessageType::new(METHOD_REFRESH, CLASS_REQUEST)),
+        Box::new(Lifetime(Duration::from_secs(600))),
+    ])
+    .map_err(IceError::Stun)?;
+    Ok(m)
+}
+
+#[tokio::test]
+async fn test_allocation_tracks_the_granted_lifetime() -> Result<(), IceError> {
+    let conn = Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await?);
+    let allocation = AllocationConn::new(conn, "pass".to_owned(), Duration::from_secs(600));
+    let server: SocketAddr = "127.0.0.1:3478".parse()?;
+    let integrity = MessageIntegrity::new_long_term_integrity(
+        "user".to_owned(),
+        "webrtc.rs".to_owned(),
+        "pass".to_owned(),
+    );
+
+    // The Allocate request asks for the requested lifetime, with some jitter
+    let request = allocate_request(&integrity)?;
+    let rewritten = allocation
+        .on_request(&request.raw, server)
+        .expect("the lifetime should be requested");
+    let rewritten = decode(&rewritten).expect("the request should decode");
+    let mut lifetime = Lifetime::default();
+    lifetime.get_from(&rewritten).map_err(IceError::Stun)?;
+    assert!(lifetime.0 >= Duration::from_secs(600), "{}", lifetime);
+    assert!(lifetime.0 <= Duration::from_secs(720), "{}", lifetime);
+    assert_eq!(allocation.expires_in(), None, "not granted yet");
+
+    let granted = response(
+        &request,
+        CLASS_SUCCESS_RESPONSE,
+        vec![Box::new(Lifetime(Duration::from_secs(300)))],
+    )?;
+    allocation.on_response(&granted.raw);
+    let expires_in = allocation.expires_in().expect("the allocation is granted");
+    assert!(expires_in <= Duration::from_secs(300));
+    assert!(expires_in > Duration::from_secs(290));
+
+    // The refreshes of the client extend it
+    let refresh = refresh_request()?;
+    assert!(allocation.on_request(&refresh.raw, server).is_none());
+    let refreshed = response(
+        &refresh,
+        CLASS_SUCCESS_RESPONSE,
+        vec![Box::new(Lifetime(Duration::from_secs(600)))],
+    )?;
+    allocation.on_response(&refreshed.raw);
+    assert!(allocation.expires_in().unwrap() > Duration::from_secs(590));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_allocation_reports_failed_refreshes() -> Result<(), IceError> {
+    let conn = Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await?);
+    let allocation = AllocationConn::new(conn, "pass".to_owned(), Duration::from_secs(600));
+    let server: SocketAddr = "127.0.0.1:3478".parse()?;
+    let request = allocate_request(&MessageIntegrity::new_long_term_integrity(
+        "user".to_owned(),
+        "webrtc.rs".to_owned(),
+        "pass".to_owned(),
+    ))?;
+    allocation.on_request(&request.raw, server);
+    allocation.on_response(
+        &response(
+            &request,
+            CLASS_SUCCESS_RESPONSE,
+            vec![Box::new(Lifetime(Duration::from_secs(600)))],
+        )?
+        .raw,
+    );
+
+    // The client retries the refreshes answered with a stale nonce itself
+    for code in &[CODE_STALE_NONCE, CODE_FORBIDDEN] {
+        let refresh = refresh_request()?;
+        allocation.on_request(&refresh.raw, server);
+        let failed = response(
+            &refresh,
+            CLASS_ERROR_RESPONSE,
+            vec![Box::new(ErrorCodeAttribute {
+                code: *code,
+                reason: b"refused".to_vec(),
+            })],
+        )?;
+        allocation.on_response(&failed.raw);
+    }
+
+    let failures = std::mem::take(&mut allocation.state.lock().unwrap().failures);
+    assert_eq!(failures.len(), 1);
+    assert!(
+        matches!(failures[0], IceError::TurnErrorResponse { code: 403, .. }),
+        "{}",
+        failures[0]
+    );
+
+    Ok(())
+}
@@ -0,0 +1,16 @@
+use crate::errors::*;
+use crate::url::Url;
+
+use async_trait::async_trait;
+
+/// Provides the credentials of TURN servers when relay candidates are gathered, rather than
+/// those of their URL. Implement it to fetch time-limited credentials, e.g. from a TURN REST API,
+/// which would have expired if they had been set in `AgentConfig::urls` when the agent was
+/// created.
+#[async_trait]
+pub trait CredentialProvider {
+    /// Returns the username and password to allocate a relay on the TURN server of `url`. It is
+    /// called again if the server rejects them as unauthorized, in case they have just expired,
+    /// or rejects the nonce of the request as stale.
+    async fn credentials(&self, url: &Url) -> Result<(String, String), IceError>;
+}
@@ -0,0 +1,436 @@
+#[cfg(test)]
+mod udp_mux_test;
+
+use crate::errors::*;
+
+use stun::{agent::*, attributes::*, message::*, textattrs::*, xoraddr::*};
+use util::Conn;
+
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc, oneshot, Mutex};
+use tokio::time::Duration;
+
+/// The maximum size of a packet read from the shared socket.
+const RECEIVE_MTU: usize = 8192;
+
+/// The number of packets buffered per connection before new ones are dropped.
+const MAX_PENDING_PACKETS: usize = 128;
+
+/// Allows multiple ICE agents to share a single UDP socket.
+#[async_trait]
+pub trait UdpMux {
+    /// Closes the shared socket and stops demultiplexing. Reads from the connections handed out
+    /// by `get_conn` fail with `IceError::UdpMuxClosed` once their pending packets are drained.
+    async fn close(&self) -> Result<(), IceError>;
+
+    /// Returns the connection carrying the packets of the agent with the given local ufrag. The
+    /// warnings of the connection name the agent by `agent_id`, see `AgentConfig::agent_id`.
+    async fn get_conn(
+        &self,
+        ufrag: &str,
+        agent_id: &str,
+    ) -> Result<Arc<dyn Conn + Send + Sync>, IceError>;
+
+    /// Removes and closes the connection of the agent with the given local ufrag.
+    async fn remove_conn_by_ufrag(&self, ufrag: &str);
+}
+
+/// A `UdpMux` which can also learn the server reflexive address of its shared socket, so that
+/// server reflexive candidates report the shared local port.
+#[async_trait]
+pub trait UdpMuxSrflx: UdpMux {
+    /// Sends a STUN binding request to `server_addr` from the shared socket and returns the
+    /// mapped address of the response, waiting for it at most `deadline`.
+    async fn get_xor_mapped_addr(
+        &self,
+        server_addr: SocketAddr,
+        deadline: Duration,
+    ) -> Result<XorMappedAddress, IceError>;
+}
+
+type PendingBinds = Arc<Mutex<HashMap<[u8; TRANSACTION_ID_SIZE], oneshot::Sender<Message>>>>;
+
+/// The parameters used to create a `UdpMuxDefault`.
+pub struct UdpMuxParams {
+    pub conn: Arc<dyn Conn + Send + Sync>,
+}
+
+/// An implementation of `UdpMux` which demultiplexes inbound packets by the username fragment
+/// of STUN messages and, once a remote address is known, by that address.
+pub struct UdpMuxDefault {
+    // Dropped on close, the read loop drops its own reference once it stops
+    conn: std::sync::Mutex<Option<Arc<dyn Conn + Send + Sync>>>,
+    conns: Arc<Mutex<HashMap<String, Arc<UdpMuxConn>>>>,
+    address_map: Arc<Mutex<HashMap<SocketAddr, String>>>,
+    // Binding requests sent by get_xor_mapped_addr, by transaction ID
+    pending_binds: PendingBinds,
+    closed_ch_tx: Mutex<Option<broadcast::Sender<()>>>,
+}
+
+impl UdpMuxDefault {
+    /// Creates a new `UdpMuxDefault` and starts reading from the shared socket.
+    pub fn new(params: UdpMuxParams) -> Arc<Self> {
+        let (closed_ch_tx, closed_ch_rx) = broadcast::channel(1);
+
+        let conn = Arc::clone(&params.conn);
+        let m = Arc::new(Self {
+            conn: std::sync::Mutex::new(Some(params.conn)),
+            conns: Arc::new(Mutex::new(HashMap::new())),
+            address_map: Arc::new(Mutex::new(HashMap::new())),
+            pending_binds: Arc::new(Mutex::new(HashMap::new())),
+            closed_ch_tx: Mutex::new(Some(closed_ch_tx)),
+        });
+
+        let conns = Arc::clone(&m.conns);
+        let address_map = Arc::clone(&m.address_map);
+        let pending_binds = Arc::clone(&m.pending_binds);
+        tokio::spawn(async move {
+            Self::read_loop(conn, conns, address_map, pending_binds, closed_ch_rx).await;
+        });
+
+        m
+    }
+
+    /// Returns the local address of the shared socket.
+    pub async fn local_addr(&self) -> Result<SocketAddr, IceError> {
+        Ok(self.conn()?.local_addr().await?)
+    }
+
+    fn conn(&self) -> Result<Arc<dyn Conn + Send + Sync>, IceError> {
+        let conn = self.conn.lock().unwrap();
+        conn.clone().ok_or(IceError::UdpMuxClosed)
+    }
+
+    async fn read_loop(
+        conn: Arc<dyn Conn + Send + Sync>,
+        conns: Arc<Mutex<HashMap<String, Arc<UdpMuxConn>>>>,
+        address_map: Arc<Mutex<HashMap<SocketAddr, String>>>,
+        pending_binds: PendingBinds,
+        mut closed_ch_rx: broadcast::Receiver<()>,
+    ) {
+        let mut buffer = vec![0_u8; RECEIVE_MTU];
+        loop {
+            let (n, src_addr) = tokio::select! {
+                result = conn.recv_from(&mut buffer) => match result {
+                    Ok((n, src_addr)) => (n, src_addr),
+                    Err(err) => {
+                        warn_event!(error = err; "udp mux failed to read");
+                        return;
+                    }
+                },
+                _ = closed_ch_rx.recv() => return,
+            };
+
+            if let Some(response) = binding_response_from(&buffer[..n]) {
+                let waiter = {
+                    let mut pending_binds = pending_binds.lock().await;
+                    pending_binds.remove(&response.transaction_id.0)
+                };
+                if let Some(waiter) = waiter {
+                    let _ = waiter.send(response);
+                    continue;
+                }
+            }
+
+            let known_ufrag = {
+                let address_map = address_map.lock().await;
+                address_map.get(&src_addr).cloned()
+            };
+
+            let mux_conn = if let Some(ufrag) = &known_ufrag {
+                let conns = conns.lock().await;
+                conns.get(ufrag).cloned()
+            } else if is_message(&buffer[..n]) {
+                // Only remember the address once a conn owns the ufrag, so that unknown
+                // ufrags cannot grow the map
+                match ufrag_from_message(&buffer[..n]) {
+                    Some(ufrag) => {
+                        let mux_conn = {
+                            let conns = conns.lock().await;
+                            conns.get(&ufrag).cloned()
+                        };
+                        if mux_conn.is_some() {
+                            let mut address_map = address_map.lock().await;
+                            address_map.insert(src_addr, ufrag);
+                        }
+                        mux_conn
+                    }
+                    None => None,
+                }
+            } else {
+                None
+            };
+
+            if let Some(mux_conn) = mux_conn {
+                mux_conn.write_packet(&buffer[..n], src_addr);
+            } else {
+                log::trace!("udp mux: dropping packet from unknown source {}", src_addr);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl UdpMux for UdpMuxDefault {
+    async fn close(&self) -> Result<(), IceError> {
+        {
+            let mut closed_ch_tx = self.closed_ch_tx.lock().await;
+            if closed_ch_tx.take().is_none() {
+                return Err(IceError::UdpMuxClosed);
+            }
+        }
+
+        {
+            let mut conns = self.conns.lock().await;
+            for (_, conn) in conns.drain() {
+                conn.close();
+            }
+        }
+        {
+            let mut address_map = self.address_map.lock().await;
+            address_map.clear();
+        }
+        {
+            let mut conn = self.conn.lock().unwrap();
+            conn.take();
+        }
+
+        Ok(())
+    }
+
+    async fn get_conn(
+        &self,
+        ufrag: &str,
+        agent_id: &str,
+    ) -> Result<Arc<dyn Conn + Send + Sync>, IceError> {
+        {
+            let closed_ch_tx = self.closed_ch_tx.lock().await;
+            if closed_ch_tx.is_none() {
+                return Err(IceError::UdpMuxClosed);
+            }
+        }
+
+        let mut conns = self.conns.lock().await;
+        if let Some(conn) = conns.get(ufrag) {
+            return Ok(Arc::clone(conn) as Arc<dyn Conn + Send + Sync>);
+        }
+
+        let conn = Arc::new(UdpMuxConn::new(
+            agent_id.to_owned(),
+            ufrag.to_owned(),
+            self.conn()?,
+            Arc::clone(&self.address_map),
+        ));
+        conns.insert(ufrag.to_owned(), Arc::clone(&conn));
+
+        Ok(conn)
+    }
+
+    async fn remove_conn_by_ufrag(&self, ufrag: &str) {
+        let removed = {
+            let mut conns = self.conns.lock().await;
+            conns.remove(ufrag)
+        };
+        if let Some(conn) = removed {
+            conn.close();
+        }
+
+        let mut address_map = self.address_map.lock().await;
+        address_map.retain(|_, v| v != ufrag);
+    }
+}
+
+#[async_trait]
+impl UdpMuxSrflx for UdpMuxDefault {
+    async fn get_xor_mapped_addr(
+        &self,
+        server_addr: SocketAddr,
+        deadline: Duration,
+    ) -> Result<XorMappedAddress, IceError> {
+        let conn = self.conn()?;
+
+        let mut request = Message::new();
+        request
+            .build(&[Box::new(BINDING_REQUEST), Box::new(TransactionId::new())])
+            .map_err(IceError::Stun)?;
+
+        // The read loop hands the response over instead of routing it to an agent
+        let (response_tx, response_rx) = oneshot::channel();
+        {
+            let mut pending_binds = self.pending_binds.lock().await;
+            pending_binds.insert(request.transaction_id.0, response_tx);
+        }
+
+        let result = match conn.send_to(&request.raw, server_addr).await {
+            Ok(_) => match tokio::time::timeout(deadline, response_rx).await {
+                Ok(Ok(response)) => Ok(response),
+                Ok(Err(_)) => Err(IceError::UdpMuxClosed),
+                Err(_) => Err(IceError::Timeout("waiting for the STUN binding response")),
+            },
+            Err(err) => Err(err.into()),
+        };
+        let response = match result {
+            Ok(response) => response,
+            Err(err) => {
+                let mut pending_binds = self.pending_binds.lock().await;
+                pending_binds.remove(&request.transaction_id.0);
+                return Err(err);
+            }
+        };
+
+        let mut addr = XorMappedAddress::default();
+        addr.get_from(&response).map_err(IceError::Stun)?;
+        Ok(addr)
+    }
+}
+
+/// A connection handed out by `UdpMuxDefault` to a single agent.
+pub struct UdpMuxConn {
+    agent_id: String,
+    ufrag: String,
+    // Both are dropped on close, so that reads fail once the pending packets are drained
+    conn: std::sync::Mutex<Option<Arc<dyn Conn + Send + Sync>>>,
+    address_map: Arc<Mutex<HashMap<SocketAddr, String>>>,
+    packets_tx: std::sync::Mutex<Option<mpsc::Sender<(Vec<u8>, SocketAddr)>>>,
+    packets_rx: Mutex<mpsc::Receiver<(Vec<u8>, SocketAddr)>>,
+}
+
+impl UdpMuxConn {
+    fn new(
+        agent_id: String,
+        ufrag: String,
+        conn: Arc<dyn Conn + Send + Sync>,
+        address_map: Arc<Mutex<HashMap<SocketAddr, String>>>,
+    ) -> Self {
+        let (packets_tx, packets_rx) = mpsc::channel(MAX_PENDING_PACKETS);
+        Self {
+            agent_id,
+            ufrag,
+            conn: std::sync::Mutex::new(Some(conn)),
+            address_map,
+            packets_tx: std::sync::Mutex::new(Some(packets_tx)),
+            packets_rx: Mutex::new(packets_rx),
+        }
+    }
+
+    fn conn(&self) -> io::Result<Arc<dyn Conn + Send + Sync>> {
+        let conn = self.conn.lock().unwrap();
+        conn.clone()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, IceError::UdpMuxClosed))
+    }
+
+    fn close(&self) {
+        self.conn.lock().unwrap().take();
+        self.packets_tx.lock().unwrap().take();
+    }
+
+    fn write_packet(&self, buf: &[u8], src_addr: SocketAddr) {
+        let packets_tx = self.packets_tx.lock().unwrap();
+        let packets_tx = match &*packets_tx {
+            Some(packets_tx) => packets_tx,
+            None => return,
+        };
+        if packets_tx.try_send((buf.to_vec(), src_addr)).is_err() {
+            warn_event!(
+                agent = self.agent_id,
+                remote = src_addr,
+                ufrag = self.ufrag;
+                "udp mux: dropping packet, buffer is full"
+            );
+        }
+    }
+}
+
+#[async_trait]
+impl Conn for UdpMuxConn {
+    async fn connect(&self, _addr: SocketAddr) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "Not applicable"))
+    }
+
+    async fn recv(&self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::Other, "Not applicable"))
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut packets_rx = self.packets_rx.lock().await;
+        if let Some((packet, src_addr)) = packets_rx.recv().await {
+            let n = std::cmp::min(buf.len(), packet.len());
+            buf[..n].copy_from_slice(&packet[..n]);
+            Ok((n, src_addr))
+        } else {
+            Err(io::Error::new(io::ErrorKind::Other, IceError::UdpMuxClosed))
+        }
+    }
+
+    async fn send(&self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::Other, "Not applicable"))
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        let conn = self.conn()?;
+
+        {
+            // Responses from target carry no username, so remember who owns the address
+            let mut address_map = self.address_map.lock().await;
+            if !address_map.contains_key(&target) {
+                address_map.insert(target, self.ufrag.clone());
+            }
+        }
+
+        conn.send_to(buf, target).await
+    }
+
+    async fn local_addr(&self) -> io::Result<SocketAddr> {
+        let conn = self.conn()?;
+        conn.local_addr().await
+    }
+}
+
+/// Decodes a STUN binding response, which may answer a request sent by `get_xor_mapped_addr`.
+fn binding_response_from(buf: &[u8]) -> Option<Message> {
+    if !is_message(buf) {
+        return None;
+    }
+
+    let mut m = Message {
+        raw: buf.to_vec(),
+        ..Message::default()
+    };
+    if m.decode().is_err()
+        || m.typ.method != METHOD_BINDING
+        || !(m.typ.class == CLASS_SUCCESS_RESPONSE || m.typ.class == CLASS_ERROR_RESPONSE)
+    {
+        return None;
+    }
+
+    Some(m)
+}
+
+/// Extracts the local ufrag of the receiving agent from the USERNAME attribute of an inbound
+/// STUN message, which has the form "receiver_ufrag:sender_ufrag".
+pub(crate) fn ufrag_from_message(buf: &[u8]) -> Option<String> {
+    let mut m = Message {
+        raw: buf.to_vec(),
+        ..Message::default()
+    };
+    if let Err(err) = m.decode() {
+        log::trace!("udp mux: failed to decode STUN message: {}", err);
+        return None;
+    }
+
+    let mut username = Username::new(ATTR_USERNAME, String::new());
+    if username.get_from(&m).is_err() {
+        return None;
+    }
+
+    username
+        .to_string()
+        .split(':')
+        .next()
+        .filter(|ufrag| !ufrag.is_empty())
+        .map(ToOwned::to_owned)
+}
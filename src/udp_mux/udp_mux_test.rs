@@ -0,0 +1,179 @@
+use super::*;
+
+use stun::agent::*;
+use tokio::net::UdpSocket;
+use tokio::time::Duration;
+
+#[tokio::test]
+async fn test_udp_mux_demultiplexes_by_ufrag() -> Result<(), IceError> {
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let udp_mux = UdpMuxDefault::new(UdpMuxParams { conn });
+    let mux_addr = udp_mux.local_addr().await?;
+
+    let conn_a = udp_mux.get_conn("ufragA", "agentA").await?;
+    let conn_b = udp_mux.get_conn("ufragB", "agentB").await?;
+    assert_eq!(
+        mux_addr,
+        conn_a.local_addr().await?,
+        "should share the socket"
+    );
+
+    let remote = UdpSocket::bind("127.0.0.1:0").await?;
+    let remote_addr = remote.local_addr()?;
+
+    let mut m = Message::new();
+    m.build(&[
+        Box::new(BINDING_REQUEST),
+        Box::new(TransactionId::new()),
+        Box::new(Username::new(ATTR_USERNAME, "ufragB:remote".to_owned())),
+    ])?;
+    remote.send_to(&m.raw, mux_addr).await?;
+
+    let mut buf = vec![0_u8; RECEIVE_MTU];
+    let (n, src_addr) = tokio::time::timeout(Duration::from_secs(5), conn_b.recv_from(&mut buf))
+        .await
+        .expect("STUN message should be routed by ufrag")?;
+    assert_eq!(&m.raw[..], &buf[..n], "should match");
+    assert_eq!(remote_addr, src_addr, "should match");
+
+    // Once the address is known, non-STUN traffic follows it
+    remote.send_to(b"hello", mux_addr).await?;
+    let (n, _) = tokio::time::timeout(Duration::from_secs(5), conn_b.recv_from(&mut buf))
+        .await
+        .expect("packet should be routed by address")?;
+    assert_eq!(b"hello", &buf[..n], "should match");
+
+    let result = tokio::time::timeout(Duration::from_millis(100), conn_a.recv_from(&mut buf)).await;
+    assert!(result.is_err(), "other ufrag should not receive anything");
+
+    udp_mux.remove_conn_by_ufrag("ufragB").await;
+    udp_mux.close().await?;
+    assert!(
+        udp_mux.get_conn("ufragA", "agentA").await.is_err(),
+        "mux is closed"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_udp_mux_srflx_intercepts_binding_response() -> Result<(), IceError> {
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let udp_mux = UdpMuxDefault::new(UdpMuxParams { conn });
+    let mux_addr = udp_mux.local_addr().await?;
+    let agent_conn = udp_mux.get_conn("ufrag", "agent").await?;
+
+    // Answers a single binding request with the address it came from
+    let server = UdpSocket::bind("127.0.0.1:0").await?;
+    let server_addr = server.local_addr()?;
+    tokio::spawn(async move {
+        let mut buf = vec![0_u8; RECEIVE_MTU];
+        let (n, src_addr) = server.recv_from(&mut buf).await?;
+        let mut request = Message::new();
+        request.raw = buf[..n].to_vec();
+        request.decode()?;
+
+        let mut response = Message::new();
+        response.build(&[
+            Box::new(request),
+            Box::new(BINDING_SUCCESS),
+            Box::new(XorMappedAddress {
+                ip: src_addr.ip(),
+                port: src_addr.port(),
+            }),
+        ])?;
+        server.send_to(&response.raw, src_addr).await?;
+
+        Ok::<(), IceError>(())
+    });
+
+    let xoraddr = udp_mux
+        .get_xor_mapped_addr(server_addr, Duration::from_secs(5))
+        .await?;
+    assert_eq!(mux_addr.ip(), xoraddr.ip, "should match");
+    assert_eq!(
+        mux_addr.port(),
+        xoraddr.port,
+        "should report the shared port"
+    );
+
+    let mut buf = vec![0_u8; RECEIVE_MTU];
+    let result =
+        tokio::time::timeout(Duration::from_millis(100), agent_conn.recv_from(&mut buf)).await;
+    assert!(result.is_err(), "the response should not reach the agent");
+
+    let result = udp_mux
+        .get_xor_mapped_addr(server_addr, Duration::from_millis(100))
+        .await;
+    assert!(matches!(result, Err(IceError::Timeout(_))));
+
+    udp_mux.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_udp_mux_ignores_unknown_ufrag() -> Result<(), IceError> {
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let udp_mux = UdpMuxDefault::new(UdpMuxParams { conn });
+    let mux_addr = udp_mux.local_addr().await?;
+    let agent_conn = udp_mux.get_conn("ufrag", "agent").await?;
+
+    let remote = UdpSocket::bind("127.0.0.1:0").await?;
+    for i in 0..3 {
+        let mut m = Message::new();
+        m.build(&[
+            Box::new(BINDING_REQUEST),
+            Box::new(TransactionId::new()),
+            Box::new(Username::new(ATTR_USERNAME, format!("unknown{}:remote", i))),
+        ])?;
+        remote.send_to(&m.raw, mux_addr).await?;
+    }
+
+    let mut buf = vec![0_u8; RECEIVE_MTU];
+    let result =
+        tokio::time::timeout(Duration::from_millis(100), agent_conn.recv_from(&mut buf)).await;
+    assert!(result.is_err(), "should not receive anything");
+    assert!(
+        udp_mux.address_map.lock().await.is_empty(),
+        "unknown ufrags should not be mapped"
+    );
+
+    udp_mux.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_udp_mux_conn_fails_after_close() -> Result<(), IceError> {
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let udp_mux = UdpMuxDefault::new(UdpMuxParams { conn });
+    let remote_addr = UdpSocket::bind("127.0.0.1:0").await?.local_addr()?;
+
+    let conn_a = udp_mux.get_conn("ufragA", "agentA").await?;
+    let conn_b = udp_mux.get_conn("ufragB", "agentB").await?;
+
+    let mut buf = vec![0_u8; RECEIVE_MTU];
+    udp_mux.remove_conn_by_ufrag("ufragA").await;
+    let result = tokio::time::timeout(Duration::from_secs(5), conn_a.recv_from(&mut buf))
+        .await
+        .expect("removed conn should stop reading");
+    assert!(result.is_err(), "removed conn should fail to read");
+    assert!(
+        conn_a.send_to(b"hello", remote_addr).await.is_err(),
+        "removed conn should fail to write"
+    );
+
+    udp_mux.close().await?;
+    let result = tokio::time::timeout(Duration::from_secs(5), conn_b.recv_from(&mut buf))
+        .await
+        .expect("closing the mux should stop reading");
+    assert!(result.is_err(), "conn should fail to read");
+    assert!(
+        conn_b.send_to(b"hello", remote_addr).await.is_err(),
+        "conn should fail to write"
+    );
+    assert!(udp_mux.local_addr().await.is_err(), "mux is closed");
+
+    Ok(())
+}
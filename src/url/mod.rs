@@ -3,8 +3,6 @@ mod url_test;
 
 use crate::errors::*;
 
-use util::Error;
-
 use std::borrow::Cow;
 use std::convert::From;
 use std::fmt;
@@ -138,10 +136,10 @@ impl Url {
     /// Parses a STUN or TURN urls following the ABNF syntax described in
     /// [IETF rfc-7064](https://tools.ietf.org/html/rfc7064) and
     /// [IETF rfc-7065](https://tools.ietf.org/html/rfc7065) respectively.
-    pub fn parse_url(raw: &str) -> Result<Self, Error> {
+    pub fn parse_url(raw: &str) -> Result<Self, IceError> {
         // work around for url crate
         if raw.contains("//") {
-            return Err(ERR_INVALID_URL.to_owned());
+            return Err(IceError::InvalidUrl);
         }
 
         let mut s = raw.to_string();
@@ -149,10 +147,13 @@ impl Url {
         if let Some(p) = pos {
             s.replace_range(p..=p, "://");
         } else {
-            return Err(ERR_SCHEME_TYPE.to_owned());
+            return Err(IceError::SchemeType);
         }
 
-        let raw_parts = url::Url::parse(&s)?;
+        let raw_parts = url::Url::parse(&s).map_err(|err| match err {
+            url::ParseError::InvalidPort => IceError::Port,
+            err => IceError::Url(err),
+        })?;
 
         let scheme = raw_parts.scheme().into();
 
@@ -162,7 +163,7 @@ impl Url {
                 .trim_end_matches(']')
                 .to_owned()
         } else {
-            return Err(ERR_HOST.to_owned());
+            return Err(IceError::Host);
         };
 
         let port = if let Some(port) = raw_parts.port() {
@@ -173,58 +174,24 @@ impl Url {
             5349
         };
 
-        let mut q_args = raw_parts.query_pairs();
+        let q_args = raw_parts.query_pairs();
         let proto = match scheme {
             SchemeType::Stun => {
                 if q_args.count() > 0 {
-                    return Err(ERR_STUN_QUERY.to_owned());
+                    return Err(IceError::StunQuery);
                 }
                 ProtoType::Udp
             }
             SchemeType::Stuns => {
                 if q_args.count() > 0 {
-                    return Err(ERR_STUN_QUERY.to_owned());
+                    return Err(IceError::StunQuery);
                 }
                 ProtoType::Tcp
             }
-            SchemeType::Turn => {
-                if q_args.count() > 1 {
-                    return Err(ERR_INVALID_QUERY.to_owned());
-                }
-                if let Some((key, value)) = q_args.next() {
-                    if key == Cow::Borrowed("transport") {
-                        let proto: ProtoType = value.as_ref().into();
-                        if proto == ProtoType::Unknown {
-                            return Err(ERR_PROTO_TYPE.to_owned());
-                        }
-                        proto
-                    } else {
-                        return Err(ERR_INVALID_QUERY.to_owned());
-                    }
-                } else {
-                    ProtoType::Udp
-                }
-            }
-            SchemeType::Turns => {
-                if q_args.count() > 1 {
-                    return Err(ERR_INVALID_QUERY.to_owned());
-                }
-                if let Some((key, value)) = q_args.next() {
-                    if key == Cow::Borrowed("transport") {
-                        let proto: ProtoType = value.as_ref().into();
-                        if proto == ProtoType::Unknown {
-                            return Err(ERR_PROTO_TYPE.to_owned());
-                        }
-                        proto
-                    } else {
-                        return Err(ERR_INVALID_QUERY.to_owned());
-                    }
-                } else {
-                    ProtoType::Tcp
-                }
-            }
+            SchemeType::Turn => Self::parse_proto(q_args, ProtoType::Udp)?,
+            SchemeType::Turns => Self::parse_proto(q_args, ProtoType::Tcp)?,
             SchemeType::Unknown => {
-                return Err(ERR_SCHEME_TYPE.to_owned());
+                return Err(IceError::SchemeType);
             }
         };
 
@@ -238,27 +205,30 @@ impl Url {
         })
     }
 
-    /*
-    fn parse_proto(raw:&str) ->Result<ProtoType, Error> {
-        let qArgs= raw.split('=');
-        if qArgs.len() != 2 {
-            return Err(ERR_INVALID_QUERY.to_owned());
+    /// Parses the optional transport query of a TURN url, falling back to `default` when there
+    /// is none.
+    fn parse_proto(
+        mut q_args: url::form_urlencoded::Parse<'_>,
+        default: ProtoType,
+    ) -> Result<ProtoType, IceError> {
+        if q_args.count() > 1 {
+            return Err(IceError::InvalidQuery);
         }
 
-        var proto ProtoType
-        if rawProto := qArgs.Get("transport"); rawProto != "" {
-            if proto = NewProtoType(rawProto); proto == ProtoType(0) {
-                return ProtoType(Unknown), ErrProtoType
+        if let Some((key, value)) = q_args.next() {
+            if key == Cow::Borrowed("transport") {
+                let proto: ProtoType = value.as_ref().into();
+                if proto == ProtoType::Unknown {
+                    return Err(IceError::ProtoType);
+                }
+                Ok(proto)
+            } else {
+                Err(IceError::InvalidQuery)
             }
-            return proto, nil
-        }
-
-        if len(qArgs) > 0 {
-            return ProtoType(Unknown), ErrInvalidQuery
+        } else {
+            Ok(default)
         }
-
-        return proto, nil
-    }*/
+    }
 
     /// Returns whether the this URL's scheme describes secure scheme or not.
     #[must_use]
@@ -1,7 +1,7 @@
 use super::*;
 
 #[test]
-fn test_parse_url_success() -> Result<(), Error> {
+fn test_parse_url_success() -> Result<(), IceError> {
     let tests = vec![
         (
             "stun:google.de",
@@ -75,6 +75,24 @@ fn test_parse_url_success() -> Result<(), Error> {
             5349,
             ProtoType::Tcp,
         ),
+        (
+            "turn:google.de:1234?transport=tcp",
+            "turn:google.de:1234?transport=tcp",
+            SchemeType::Turn,
+            false,
+            "google.de",
+            1234,
+            ProtoType::Tcp,
+        ),
+        (
+            "turns:google.de?transport=udp",
+            "turns:google.de:5349?transport=udp",
+            SchemeType::Turns,
+            true,
+            "google.de",
+            5349,
+            ProtoType::Udp,
+        ),
     ];
 
     for (
@@ -106,30 +124,38 @@ fn test_parse_url_success() -> Result<(), Error> {
 }
 
 #[test]
-fn test_parse_url_failure() -> Result<(), Error> {
+fn test_parse_url_failure() -> Result<(), IceError> {
     let tests = vec![
-        ("", ERR_SCHEME_TYPE.to_owned()),
-        (":::", ERR_URL_PARSE_ERROR.to_owned()),
-        ("stun:[::1]:123:", ERR_PORT.to_owned()),
-        ("stun:[::1]:123a", ERR_PORT.to_owned()),
-        ("google.de", ERR_SCHEME_TYPE.to_owned()),
-        ("stun:", ERR_HOST.to_owned()),
-        ("stun:google.de:abc", ERR_PORT.to_owned()),
-        ("stun:google.de?transport=udp", ERR_STUN_QUERY.to_owned()),
-        ("stuns:google.de?transport=udp", ERR_STUN_QUERY.to_owned()),
-        ("turn:google.de?trans=udp", ERR_INVALID_QUERY.to_owned()),
-        ("turns:google.de?trans=udp", ERR_INVALID_QUERY.to_owned()),
+        ("", IceError::SchemeType),
+        (
+            ":::",
+            IceError::Url(url::ParseError::RelativeUrlWithoutBase),
+        ),
+        ("stun:[::1]:123:", IceError::Port),
+        ("stun:[::1]:123a", IceError::Port),
+        ("google.de", IceError::SchemeType),
+        ("stun:", IceError::Host),
+        ("stun:google.de:abc", IceError::Port),
+        ("stun:google.de?transport=udp", IceError::StunQuery),
+        ("stuns:google.de?transport=udp", IceError::StunQuery),
+        ("turn:google.de?trans=udp", IceError::InvalidQuery),
+        ("turns:google.de?trans=udp", IceError::InvalidQuery),
         (
             "turns:google.de?transport=udp&another=1",
-            ERR_INVALID_QUERY.to_owned(),
+            IceError::InvalidQuery,
         ),
-        ("turn:google.de?transport=ip", ERR_PROTO_TYPE.to_owned()),
+        ("turn:google.de?transport=ip", IceError::ProtoType),
     ];
 
     for (raw_url, expected_err) in tests {
         let result = Url::parse_url(raw_url);
         if let Err(err) = result {
-            assert_eq!(err, expected_err, "testCase:{}", raw_url);
+            assert_eq!(
+                err.to_string(),
+                expected_err.to_string(),
+                "testCase:{}",
+                raw_url
+            );
         } else {
             panic!("expected error, but got ok");
         }
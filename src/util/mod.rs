@@ -1,45 +1,123 @@
 #[cfg(test)]
 mod util_test;
 
-use crate::agent::agent_config::InterfaceFilterFn;
+use crate::agent::agent_config::{InterfaceFilterFn, IpFilterFn};
+use crate::batch_conn::BatchUdpConn;
+use crate::candidate::{
+    NETWORK_COST_HIGH, NETWORK_COST_LOW, NETWORK_COST_MIN, NETWORK_COST_UNKNOWN,
+};
 use crate::errors::*;
 use crate::network_type::*;
+use crate::socket_factory::{bind_udp, SocketFactory};
+use crate::wildcard_conn::WildcardUdpConn;
 
-use std::net::{IpAddr, SocketAddr};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::future::Future;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6};
 use stun::{agent::*, attributes::*, integrity::*, message::*, textattrs::*, xoraddr::*};
 
 use std::sync::Arc;
 use tokio::time::Duration;
-use util::{vnet::net::*, Conn, Error};
+use util::{vnet::net::*, Conn};
 
+/// Flags of /proc/net/if_inet6, see IFA_F_* in linux/if_addr.h.
+const IFA_F_DEPRECATED: u32 = 0x20;
+
+/// Which addresses of the interfaces are gathered besides the usual ones, see the `include_*`
+/// options of `AgentConfig`.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct LocalAddressPolicy {
+    pub(crate) include_loopback: bool,
+    pub(crate) include_link_local: bool,
+    pub(crate) include_deprecated_ipv6: bool,
+}
+
+/// What the OS tells about a local IPv6 address.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct Ipv6AddrInfo {
+    /// The index of the interface the address is configured on.
+    pub(crate) scope_id: u32,
+    pub(crate) deprecated: bool,
+}
+
+/// Returns the socket address of ip and port. A link-local IPv6 address carries the scope ID of
+/// the local interface it is configured on, as it can't be bound or reached without it. For a
+/// remote link-local address, the scope is given by the local socket it is reached from.
 pub fn create_addr(_network: NetworkType, ip: IpAddr, port: u16) -> SocketAddr {
     /*if network.is_tcp(){
         return &net.TCPAddr{IP: ip, Port: port}
     default:
         return &net.UDPAddr{IP: ip, Port: port}
     }*/
-    SocketAddr::new(ip, port)
+    match ip {
+        IpAddr::V6(ip6) if is_link_local_ipv6(&ip6) => {
+            let scope_id = read_ipv6_addr_info()
+                .get(&ip6)
+                .map_or(0, |info| info.scope_id);
+            SocketAddr::V6(SocketAddrV6::new(ip6, port, 0, scope_id))
+        }
+        _ => SocketAddr::new(ip, port),
+    }
+}
+
+/// Returns true for unicast link-local IPv6 addresses, i.e. fe80::/10.
+pub(crate) const fn is_link_local_ipv6(ip: &Ipv6Addr) -> bool {
+    (ip.segments()[0] & 0xffc0) == 0xfe80
+}
+
+/// Returns what the OS tells about the local IPv6 addresses. Only Linux tells it, through
+/// /proc/net/if_inet6, elsewhere nothing is known.
+pub(crate) fn read_ipv6_addr_info() -> HashMap<Ipv6Addr, Ipv6AddrInfo> {
+    std::fs::read_to_string("/proc/net/if_inet6")
+        .map(|content| parse_if_inet6(&content))
+        .unwrap_or_default()
+}
+
+/// Parses the lines of /proc/net/if_inet6, made of the address, the interface index, the prefix
+/// length, the scope, the flags and the interface name, all but the name in hex.
+pub(crate) fn parse_if_inet6(content: &str) -> HashMap<Ipv6Addr, Ipv6AddrInfo> {
+    let mut addrs = HashMap::new();
+    for line in content.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 6 {
+            continue;
+        }
+
+        if let (Ok(addr), Ok(scope_id), Ok(flags)) = (
+            u128::from_str_radix(fields[0], 16),
+            u32::from_str_radix(fields[1], 16),
+            u32::from_str_radix(fields[4], 16),
+        ) {
+            addrs.insert(
+                Ipv6Addr::from(addr),
+                Ipv6AddrInfo {
+                    scope_id,
+                    deprecated: flags & IFA_F_DEPRECATED != 0,
+                },
+            );
+        }
+    }
+    addrs
 }
 
-pub fn assert_inbound_username(m: &Message, expected_username: &str) -> Result<(), Error> {
+pub fn assert_inbound_username(m: &Message, expected_username: &str) -> Result<(), IceError> {
     let mut username = Username::new(ATTR_USERNAME, String::new());
-    username.get_from(m)?;
+    username.get_from(m).map_err(IceError::Stun)?;
 
     if username.to_string() != expected_username {
-        return Err(Error::new(format!(
-            "{} expected({}) actual({})",
-            ERR_MISMATCH_USERNAME.to_owned(),
-            expected_username,
-            username,
-        )));
+        return Err(IceError::MismatchUsername {
+            expected: expected_username.to_owned(),
+            actual: username.to_string(),
+        });
     }
 
     Ok(())
 }
 
-pub fn assert_inbound_message_integrity(m: &mut Message, key: &[u8]) -> Result<(), Error> {
+pub fn assert_inbound_message_integrity(m: &mut Message, key: &[u8]) -> Result<(), IceError> {
     let message_integrity_attr = MessageIntegrity(key.to_vec());
-    message_integrity_attr.check(m)
+    message_integrity_attr.check(m).map_err(IceError::Stun)
 }
 
 /// Initiates a stun requests to `server_addr` using conn, reads the response and returns the
@@ -49,10 +127,10 @@ pub async fn get_xormapped_addr(
     conn: &Arc<dyn Conn + Send + Sync>,
     server_addr: SocketAddr,
     deadline: Duration,
-) -> Result<XorMappedAddress, Error> {
+) -> Result<XorMappedAddress, IceError> {
     let resp = stun_request(conn, server_addr, deadline).await?;
     let mut addr = XorMappedAddress::default();
-    addr.get_from(&resp)?;
+    addr.get_from(&resp).map_err(IceError::Stun)?;
     Ok(addr)
 }
 
@@ -62,19 +140,18 @@ pub async fn stun_request(
     conn: &Arc<dyn Conn + Send + Sync>,
     server_addr: SocketAddr,
     deadline: Duration,
-) -> Result<Message, Error> {
+) -> Result<Message, IceError> {
     let mut request = Message::new();
-    request.build(&[Box::new(BINDING_REQUEST), Box::new(TransactionId::new())])?;
+    request
+        .build(&[Box::new(BINDING_REQUEST), Box::new(TransactionId::new())])
+        .map_err(IceError::Stun)?;
 
     conn.send_to(&request.raw, server_addr).await?;
     let mut bs = vec![0_u8; MAX_MESSAGE_SIZE];
     let (n, _) = if deadline > Duration::from_secs(0) {
         match tokio::time::timeout(deadline, conn.recv_from(&mut bs)).await {
-            Ok(result) => match result {
-                Ok((n, addr)) => (n, addr),
-                Err(err) => return Err(Error::new(err.to_string())),
-            },
-            Err(err) => return Err(Error::new(err.to_string())),
+            Ok(result) => result?,
+            Err(_) => return Err(IceError::Timeout("waiting for the STUN response")),
         }
     } else {
         conn.recv_from(&mut bs).await?
@@ -82,18 +159,31 @@ pub async fn stun_request(
 
     let mut res = Message::new();
     res.raw = bs[..n].to_vec();
-    res.decode()?;
+    res.decode().map_err(IceError::Stun)?;
 
     Ok(res)
 }
 
+/// Returns the position in `interfaces` of the first entry naming the interface `name` or the
+/// address `ip`, see `AgentConfig::interfaces`.
+pub(crate) fn interface_rank(interfaces: &[String], name: &str, ip: IpAddr) -> Option<usize> {
+    interfaces.iter().position(|entry| {
+        entry == name || entry.parse::<IpAddr>().map_or(false, |entry| entry == ip)
+    })
+}
+
+/// Returns the local addresses to gather from. When `interfaces` is not empty, only the
+/// addresses it names, or those of the interfaces it names, are returned, in its order.
 pub async fn local_interfaces(
     vnet: &Arc<Net>,
     interface_filter: &Option<InterfaceFilterFn>,
+    interfaces: &[String],
+    ip_filter: &Option<IpFilterFn>,
     network_types: &[NetworkType],
+    address_policy: LocalAddressPolicy,
 ) -> Vec<IpAddr> {
     let mut ips = vec![];
-    let interfaces = vnet.get_interfaces().await;
+    let vnet_interfaces = vnet.get_interfaces().await;
 
     let (mut ipv4requested, mut ipv6requested) = (false, false);
     for typ in network_types {
@@ -105,7 +195,14 @@ pub async fn local_interfaces(
         }
     }
 
-    for iface in interfaces {
+    // Virtual interfaces are unknown to the OS
+    let ipv6_addr_info = if ipv6requested && !vnet.is_virtual() {
+        read_ipv6_addr_info()
+    } else {
+        HashMap::new()
+    };
+
+    for iface in vnet_interfaces {
         if let Some(filter) = interface_filter {
             if !filter(iface.name()) {
                 continue;
@@ -114,37 +211,194 @@ pub async fn local_interfaces(
 
         for ipnet in iface.addrs() {
             let ipaddr = ipnet.addr();
-            if !ipaddr.is_loopback()
-                && ((ipv4requested && ipaddr.is_ipv4()) || (ipv6requested && ipaddr.is_ipv6()))
+            // An address shared by several interfaces, e.g. a bridge and its port or a VPN
+            // split setup, is gathered once
+            if ips.iter().any(|(_, ip)| *ip == ipaddr) {
+                continue;
+            }
+            let rank = if interfaces.is_empty() {
+                0
+            } else if let Some(rank) = interface_rank(interfaces, iface.name(), ipaddr) {
+                rank
+            } else {
+                continue;
+            };
+            if (!ipaddr.is_loopback() || address_policy.include_loopback)
+                && ((ipv4requested && ipaddr.is_ipv4())
+                    || (ipv6requested && is_ipv6_allowed(&ipaddr, address_policy, &ipv6_addr_info)))
+                && is_ip_allowed(ip_filter, ipaddr)
             {
-                ips.push(ipaddr);
+                ips.push((rank, ipaddr));
             }
         }
     }
 
-    ips
+    // The sort is stable, so the addresses of an interface keep the order of the OS
+    ips.sort_by_key(|(rank, _)| *rank);
+    ips.into_iter().map(|(_, ip)| ip).collect()
+}
+
+/// The network interface a local address is configured on, as its candidates report it.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct LocalNetwork {
+    pub(crate) id: u16,
+    pub(crate) cost: u16,
+    /// The position of the address in `AgentConfig::interfaces`, 0 being the most preferred.
+    pub(crate) rank: u16,
+    /// The local preference of the interface, from `AgentConfig::interface_preferences`.
+    pub(crate) preference: Option<u16>,
+}
+
+/// Returns the network of every local address. Interfaces are numbered from 1 in the order the
+/// OS lists them, and their cost is taken from `network_costs` by name, or guessed from the name.
+/// Their local preference is taken from `interface_preferences` by name. Addresses are ranked by
+/// their position in `interfaces`. An address shared by several interfaces is on the cheapest
+/// one.
+pub(crate) async fn local_networks(
+    vnet: &Arc<Net>,
+    network_costs: &HashMap<String, u16>,
+    interface_preferences: &HashMap<String, u16>,
+    interfaces: &[String],
+) -> HashMap<IpAddr, LocalNetwork> {
+    let mut networks = HashMap::new();
+    for (i, iface) in vnet.get_interfaces().await.iter().enumerate() {
+        let mut network = LocalNetwork {
+            id: u16::try_from(i + 1).unwrap_or(u16::MAX),
+            cost: network_costs
+                .get(iface.name())
+                .copied()
+                .unwrap_or_else(|| guess_network_cost(iface.name())),
+            rank: 0,
+            preference: interface_preferences.get(iface.name()).copied(),
+        };
+        for ipnet in iface.addrs() {
+            network.rank = interface_rank(interfaces, iface.name(), ipnet.addr())
+                .map_or(0, |rank| u16::try_from(rank).unwrap_or(u16::MAX));
+            let shared = networks.entry(ipnet.addr()).or_insert(network);
+            if network.cost < shared.cost {
+                *shared = network;
+            }
+        }
+    }
+    networks
+}
+
+/// Guesses the cost of an interface from the usual names of the OSes: wired and loopback
+/// interfaces are free, Wi-Fi is cheap and cellular is expensive.
+pub(crate) fn guess_network_cost(name: &str) -> u16 {
+    const WIRED: &[&str] = &["lo", "eth", "en", "em"];
+    const WIFI: &[&str] = &["wl", "wifi", "ath"];
+    const CELLULAR: &[&str] = &["rmnet", "ccmni", "pdp_ip", "wwan"];
+
+    let name = name.to_lowercase();
+    let is = |prefixes: &[&str]| prefixes.iter().any(|p| name.starts_with(p));
+    if is(CELLULAR) {
+        NETWORK_COST_HIGH
+    } else if is(WIFI) {
+        NETWORK_COST_LOW
+    } else if is(WIRED) {
+        NETWORK_COST_MIN
+    } else {
+        NETWORK_COST_UNKNOWN
+    }
+}
+
+/// Returns true if ip is an IPv6 address which the policy gathers.
+fn is_ipv6_allowed(
+    ip: &IpAddr,
+    policy: LocalAddressPolicy,
+    ipv6_addr_info: &HashMap<Ipv6Addr, Ipv6AddrInfo>,
+) -> bool {
+    let ip = match ip {
+        IpAddr::V6(ip) => ip,
+        IpAddr::V4(_) => return false,
+    };
+
+    if is_link_local_ipv6(ip) && !policy.include_link_local {
+        return false;
+    }
+    policy.include_deprecated_ipv6 || !ipv6_addr_info.get(ip).map_or(false, |info| info.deprecated)
+}
+
+/// Returns true if the address passes the optional IP filter.
+pub(crate) fn is_ip_allowed(ip_filter: &Option<IpFilterFn>, ip: IpAddr) -> bool {
+    ip_filter.as_ref().map_or(true, |filter| filter(ip))
 }
 
 pub async fn listen_udp_in_port_range(
     vnet: &Arc<Net>,
+    socket_factory: &Arc<dyn SocketFactory + Send + Sync>,
+    port_max: u16,
+    port_min: u16,
+    laddr: SocketAddr,
+) -> Result<Arc<dyn Conn + Send + Sync>, IceError> {
+    bind_in_port_range(port_max, port_min, laddr, |laddr| {
+        bind_udp(vnet, socket_factory, laddr)
+    })
+    .await
+}
+
+/// Binds a UDP socket of the host in the port range, which can send batches of packets.
+pub(crate) async fn listen_batch_udp_in_port_range(
+    socket_factory: &Arc<dyn SocketFactory + Send + Sync>,
+    port_max: u16,
+    port_min: u16,
+    laddr: SocketAddr,
+) -> Result<Arc<BatchUdpConn>, IceError> {
+    bind_in_port_range(port_max, port_min, laddr, |laddr| async move {
+        Ok(Arc::new(BatchUdpConn::new(
+            socket_factory.bind_udp(laddr).await?,
+        )))
+    })
+    .await
+}
+
+/// Binds a UDP socket of the host to the wildcard address `laddr` in the port range, shared by
+/// the host candidates of all the local addresses of its family. The IPv6 one only receives IPv6
+/// packets, the IPv4 ones being left to the IPv4 socket.
+pub(crate) async fn listen_wildcard_udp_in_port_range(
+    socket_factory: &Arc<dyn SocketFactory + Send + Sync>,
+    port_max: u16,
+    port_min: u16,
+    laddr: SocketAddr,
+) -> Result<Arc<WildcardUdpConn>, IceError> {
+    bind_in_port_range(port_max, port_min, laddr, |laddr| async move {
+        let socket = if laddr.is_ipv6() {
+            socket_factory.bind_udp_v6_only(laddr).await?
+        } else {
+            socket_factory.bind_udp(laddr).await?
+        };
+        Ok(WildcardUdpConn::new(socket)?)
+    })
+    .await
+}
+
+/// Binds with `bind` to a port of the range, trying them from a random one and wrapping around,
+/// or to the port of `laddr` if it is not 0 or if there is no range.
+pub(crate) async fn bind_in_port_range<T, F, Fut>(
     port_max: u16,
     port_min: u16,
     laddr: SocketAddr,
-) -> Result<Arc<dyn Conn + Send + Sync>, Error> {
+    bind: F,
+) -> Result<T, IceError>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = Result<T, IceError>>,
+{
     if laddr.port() != 0 || (port_min == 0 && port_max == 0) {
-        return vnet.bind(laddr).await;
+        return bind(laddr).await;
     }
     let i = if port_min == 0 { 1 } else { port_min };
     let j = if port_max == 0 { 0xFFFF } else { port_max };
     if i > j {
-        return Err(ERR_PORT.to_owned());
+        return Err(IceError::Port);
     }
 
     let port_start = rand::random::<u16>() % (j - i + 1) + i;
     let mut port_current = port_start;
     loop {
         let laddr = SocketAddr::new(laddr.ip(), port_current);
-        match vnet.bind(laddr).await {
+        match bind(laddr).await {
             Ok(c) => return Ok(c),
             Err(err) => log::debug!("failed to listen {}: {}", laddr, err),
         };
@@ -158,5 +412,5 @@ pub async fn listen_udp_in_port_range(
         }
     }
 
-    Err(ERR_PORT.to_owned())
+    Err(IceError::PortRangeExhausted)
 }
@@ -1,10 +1,185 @@
 use super::*;
 
 #[tokio::test]
-async fn test_local_interfaces() -> Result<(), Error> {
+async fn test_local_interfaces() -> Result<(), IceError> {
     let vnet = Arc::new(Net::new(None));
     let interfaces = vnet.get_interfaces().await;
-    let ips = local_interfaces(&vnet, &None, &[NetworkType::Udp4, NetworkType::Udp6]).await;
+    let ips = local_interfaces(
+        &vnet,
+        &None,
+        &[],
+        &None,
+        &[NetworkType::Udp4, NetworkType::Udp6],
+        LocalAddressPolicy::default(),
+    )
+    .await;
     log::info!("interfaces: {:?}, ips: {:?}", interfaces, ips);
     Ok(())
 }
+
+#[tokio::test]
+async fn test_local_interfaces_allowlist() -> Result<(), IceError> {
+    // A virtual network always has lo0 with 127.0.0.1 first
+    let vnet = Arc::new(Net::new(Some(NetConfig::default())));
+    let policy = LocalAddressPolicy {
+        include_loopback: true,
+        ..Default::default()
+    };
+    let ip: IpAddr = "127.0.0.1".parse().unwrap();
+
+    for interfaces in &[vec![], vec!["lo0".to_owned()], vec!["127.0.0.1".to_owned()]] {
+        let ips = local_interfaces(
+            &vnet,
+            &None,
+            interfaces,
+            &None,
+            &[NetworkType::Udp4],
+            policy,
+        )
+        .await;
+        assert_eq!(ips, vec![ip], "{:?} should allow lo0", interfaces);
+    }
+
+    let interfaces = vec!["eth0".to_owned()];
+    let ips = local_interfaces(
+        &vnet,
+        &None,
+        &interfaces,
+        &None,
+        &[NetworkType::Udp4],
+        policy,
+    )
+    .await;
+    assert!(ips.is_empty(), "interfaces not listed are skipped");
+
+    Ok(())
+}
+
+#[test]
+fn test_interface_rank() {
+    let interfaces = vec!["eth1".to_owned(), "10.0.0.1".to_owned(), "eth0".to_owned()];
+    let ip: IpAddr = "10.0.0.1".parse().unwrap();
+    assert_eq!(interface_rank(&interfaces, "eth0", ip), Some(1));
+    assert_eq!(
+        interface_rank(&interfaces, "eth0", "10.0.0.2".parse().unwrap()),
+        Some(2)
+    );
+    assert_eq!(interface_rank(&interfaces, "eth1", ip), Some(0));
+    assert_eq!(
+        interface_rank(&interfaces, "wlan0", "192.168.1.1".parse().unwrap()),
+        None
+    );
+}
+
+#[test]
+fn test_guess_network_cost() {
+    assert_eq!(guess_network_cost("eth0"), NETWORK_COST_MIN);
+    assert_eq!(guess_network_cost("lo"), NETWORK_COST_MIN);
+    assert_eq!(guess_network_cost("wlan0"), NETWORK_COST_LOW);
+    assert_eq!(guess_network_cost("wlp3s0"), NETWORK_COST_LOW);
+    assert_eq!(guess_network_cost("rmnet_data0"), NETWORK_COST_HIGH);
+    assert_eq!(guess_network_cost("pdp_ip0"), NETWORK_COST_HIGH);
+    assert_eq!(guess_network_cost("tun0"), NETWORK_COST_UNKNOWN);
+}
+
+#[tokio::test]
+async fn test_local_networks() -> Result<(), IceError> {
+    // A virtual network always has lo0 with 127.0.0.1 first
+    let vnet = Arc::new(Net::new(Some(NetConfig::default())));
+
+    let ip: IpAddr = "127.0.0.1".parse().unwrap();
+    let networks = local_networks(&vnet, &HashMap::new(), &HashMap::new(), &[]).await;
+    assert_eq!(
+        networks.get(&ip),
+        Some(&LocalNetwork {
+            id: 1,
+            cost: NETWORK_COST_MIN,
+            rank: 0,
+            preference: None,
+        })
+    );
+
+    let mut costs = HashMap::new();
+    costs.insert("lo0".to_owned(), NETWORK_COST_HIGH);
+    let networks = local_networks(&vnet, &costs, &HashMap::new(), &[]).await;
+    assert_eq!(networks.get(&ip).map(|n| n.cost), Some(NETWORK_COST_HIGH));
+
+    let mut preferences = HashMap::new();
+    preferences.insert("lo0".to_owned(), 30000);
+    let networks = local_networks(&vnet, &HashMap::new(), &preferences, &[]).await;
+    assert_eq!(networks.get(&ip).map(|n| n.preference), Some(Some(30000)));
+
+    let interfaces = vec!["eth0".to_owned(), "lo0".to_owned()];
+    let networks = local_networks(&vnet, &HashMap::new(), &HashMap::new(), &interfaces).await;
+    assert_eq!(networks.get(&ip).map(|n| n.rank), Some(1));
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_if_inet6() {
+    let content = "\
+fe800000000000000a0027fffe8d4b1c 02 40 20 80     eth0
+20010db8000000000000000000000001 02 40 00 80     eth0
+20010db8000000000000000000000003 02 40 00 a0     eth0
+20010db8000000000000000000000004 02 40
+00000000000000000000000000000001 01 80 10 80       lo
+";
+    let addrs = parse_if_inet6(content);
+
+    let link_local: Ipv6Addr = "fe80::a00:27ff:fe8d:4b1c".parse().unwrap();
+    assert_eq!(
+        addrs.get(&link_local),
+        Some(&Ipv6AddrInfo {
+            scope_id: 2,
+            deprecated: false,
+        })
+    );
+    let global: Ipv6Addr = "2001:db8::1".parse().unwrap();
+    assert_eq!(addrs.get(&global).map(|info| info.deprecated), Some(false));
+    let deprecated: Ipv6Addr = "2001:db8::3".parse().unwrap();
+    assert_eq!(
+        addrs.get(&deprecated).map(|info| info.deprecated),
+        Some(true)
+    );
+    assert_eq!(addrs.len(), 4, "malformed lines should be skipped");
+}
+
+#[test]
+fn test_is_ipv6_allowed() {
+    let link_local: IpAddr = "fe80::1".parse().unwrap();
+    let global: IpAddr = "2001:db8::1".parse().unwrap();
+    let deprecated: IpAddr = "2001:db8::2".parse().unwrap();
+
+    let mut info = HashMap::new();
+    info.insert(
+        "2001:db8::2".parse().unwrap(),
+        Ipv6AddrInfo {
+            scope_id: 2,
+            deprecated: true,
+        },
+    );
+
+    let default_policy = LocalAddressPolicy::default();
+    assert!(is_ipv6_allowed(&global, default_policy, &info));
+    assert!(!is_ipv6_allowed(&link_local, default_policy, &info));
+    assert!(!is_ipv6_allowed(&deprecated, default_policy, &info));
+
+    let permissive_policy = LocalAddressPolicy {
+        include_link_local: true,
+        include_deprecated_ipv6: true,
+        ..LocalAddressPolicy::default()
+    };
+    assert!(is_ipv6_allowed(&link_local, permissive_policy, &info));
+    assert!(is_ipv6_allowed(&deprecated, permissive_policy, &info));
+}
+
+#[test]
+fn test_create_addr_link_local() {
+    // A link-local address which isn't local gets no scope, the socket it is reached from gives it
+    let addr = create_addr(NetworkType::Udp6, "fe80::1234".parse().unwrap(), 5000);
+    assert!(matches!(addr, SocketAddr::V6(addr) if addr.scope_id() == 0 && addr.port() == 5000));
+
+    let addr = create_addr(NetworkType::Udp6, "2001:db8::1".parse().unwrap(), 5000);
+    assert_eq!(addr, "[2001:db8::1]:5000".parse().unwrap());
+}
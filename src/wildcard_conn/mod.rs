@@ -0,0 +1,509 @@
+#[cfg(all(test, any(target_os = "linux", target_os = "android")))]
+mod wildcard_conn_test;
+
+use crate::batch_conn::BatchConn;
+use crate::socket_factory::set_tos;
+use util::Conn;
+
+use async_trait::async_trait;
+use socket2::SockRef;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::Interest;
+use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, mpsc, Mutex};
+
+/// The maximum size of a packet read from the shared socket.
+const RECEIVE_MTU: usize = 8192;
+
+/// The number of packets buffered per local address before new ones are dropped.
+const MAX_PENDING_PACKETS: usize = 128;
+
+/// Whether the platform tells the destination address of the packets a socket receives and lets
+/// it choose their source address, which a socket bound to the wildcard address needs.
+pub(crate) const WILDCARD_UDP_SUPPORTED: bool =
+    cfg!(any(target_os = "linux", target_os = "android"));
+
+type PacketsTx = mpsc::Sender<(Vec<u8>, SocketAddr)>;
+
+/// A UDP socket of the host bound to the wildcard address, shared by the host candidates of all
+/// the local addresses of its family. A single read loop demultiplexes the packets by their
+/// destination address, read with `IP_PKTINFO` or `IPV6_PKTINFO`, and the packets of each
+/// candidate are sent from its own address rather than the one the routing table would pick.
+/// Once reading fails, the connections are closed, their `recv_from` failing.
+pub(crate) struct WildcardUdpConn {
+    socket: Arc<UdpSocket>,
+    conns: Arc<std::sync::Mutex<HashMap<IpAddr, PacketsTx>>>,
+    // Stops the read loop once dropped, along with the last connection
+    _closed_ch_tx: broadcast::Sender<()>,
+}
+
+impl WildcardUdpConn {
+    /// Enables the packet info of `socket`, which is bound to the wildcard address, and starts
+    /// reading from it.
+    pub(crate) fn new(socket: UdpSocket) -> io::Result<Arc<Self>> {
+        pktinfo::enable(&socket)?;
+
+        let (closed_ch_tx, closed_ch_rx) = broadcast::channel(1);
+        let socket = Arc::new(socket);
+        let conns = Arc::new(std::sync::Mutex::new(HashMap::new()));
+        tokio::spawn(Self::read_loop(
+            Arc::clone(&socket),
+            Arc::clone(&conns),
+            closed_ch_rx,
+        ));
+
+        Ok(Arc::new(Self {
+            socket,
+            conns,
+            _closed_ch_tx: closed_ch_tx,
+        }))
+    }
+
+    /// Returns the connection receiving the packets sent to `source`, and sending its packets
+    /// from it.
+    pub(crate) fn conn(self: &Arc<Self>, source: IpAddr) -> Arc<SourceUdpConn> {
+        let (packets_tx, packets_rx) = mpsc::channel(MAX_PENDING_PACKETS);
+        self.conns.lock().unwrap().insert(source, packets_tx);
+
+        Arc::new(SourceUdpConn {
+            wildcard: Arc::clone(self),
+            source,
+            packets_rx: Mutex::new(packets_rx),
+        })
+    }
+
+    async fn read_loop(
+        socket: Arc<UdpSocket>,
+        conns: Arc<std::sync::Mutex<HashMap<IpAddr, PacketsTx>>>,
+        mut closed_ch_rx: broadcast::Receiver<()>,
+    ) {
+        let mut buffer = vec![0_u8; RECEIVE_MTU];
+        loop {
+            let (n, src_addr, dst_ip) = tokio::select! {
+                result = socket.async_io(Interest::READABLE, || {
+                    pktinfo::recv_from(&socket, &mut buffer)
+                }) => match result {
+                    Ok(received) => received,
+                    Err(err) => {
+                        warn_event!(error = err; "wildcard udp conn failed to read");
+                        break;
+                    }
+                },
+                _ = closed_ch_rx.recv() => break,
+            };
+
+            let packets_tx = dst_ip.and_then(|ip| conns.lock().unwrap().get(&ip).cloned());
+            if let Some(packets_tx) = packets_tx {
+                if packets_tx
+                    .try_send((buffer[..n].to_vec(), src_addr))
+                    .is_err()
+                {
+                    warn_event!(
+                        remote = src_addr;
+                        "wildcard udp conn: dropping packet to {:?}, buffer is full", dst_ip
+                    );
+                }
+            } else {
+                log::trace!(
+                    "wildcard udp conn: dropping packet from {} to unknown address {:?}",
+                    src_addr,
+                    dst_ip
+                );
+            }
+        }
+
+        // Closes the connections, which would wait for packets forever otherwise
+        conns.lock().unwrap().clear();
+    }
+}
+
+/// The connection of a single local address handed out by `WildcardUdpConn`.
+pub(crate) struct SourceUdpConn {
+    wildcard: Arc<WildcardUdpConn>,
+    source: IpAddr,
+    packets_rx: Mutex<mpsc::Receiver<(Vec<u8>, SocketAddr)>>,
+}
+
+impl Drop for SourceUdpConn {
+    fn drop(&mut self) {
+        self.wildcard.conns.lock().unwrap().remove(&self.source);
+    }
+}
+
+#[async_trait]
+impl Conn for SourceUdpConn {
+    async fn connect(&self, _addr: SocketAddr) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "Not applicable"))
+    }
+
+    async fn recv(&self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::Other, "Not applicable"))
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut packets_rx = self.packets_rx.lock().await;
+        if let Some((packet, src_addr)) = packets_rx.recv().await {
+            let n = std::cmp::min(buf.len(), packet.len());
+            buf[..n].copy_from_slice(&packet[..n]);
+            Ok((n, src_addr))
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                "wildcard udp conn is closed",
+            ))
+        }
+    }
+
+    async fn send(&self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::Other, "Not applicable"))
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        let socket = &self.wildcard.socket;
+        socket
+            .async_io(Interest::WRITABLE, || {
+                pktinfo::send_to(socket, buf, self.source, target)
+            })
+            .await
+    }
+
+    async fn local_addr(&self) -> io::Result<SocketAddr> {
+        let port = self.wildcard.socket.local_addr()?.port();
+        Ok(SocketAddr::new(self.source, port))
+    }
+}
+
+#[async_trait]
+impl BatchConn for SourceUdpConn {
+    async fn send_batch_to(&self, bufs: &[&[u8]], target: SocketAddr) -> io::Result<usize> {
+        // The kernel may send only the first packets, the next call sends the others
+        let socket = &self.wildcard.socket;
+        let mut sent = 0;
+        while sent < bufs.len() {
+            let result = socket
+                .async_io(Interest::WRITABLE, || {
+                    pktinfo::send_mmsg(socket, &bufs[sent..], self.source, target)
+                })
+                .await;
+            match result {
+                Ok(n) => sent += n,
+                Err(err) if sent == 0 => return Err(err),
+                Err(_) => break,
+            }
+        }
+
+        Ok(sent)
+    }
+
+    fn set_tos(&self, tos: u8) -> io::Result<()> {
+        // The socket is shared by the candidates of the family, which are all marked alike, as
+        // only the selected pair of the component is marked
+        set_tos(
+            &SockRef::from(&*self.wildcard.socket),
+            self.source.is_ipv4(),
+            tos,
+        )
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod pktinfo {
+    use super::*;
+
+    use crate::batch_conn::mmsg::to_sockaddr;
+    use std::mem::{size_of, zeroed};
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::os::unix::io::AsRawFd;
+
+    /// Room for a single `IP_PKTINFO` or `IPV6_PKTINFO` control message, aligned as the headers
+    /// of control messages need.
+    #[repr(align(8))]
+    struct Control([u8; 64]);
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn set_option(socket: &UdpSocket, level: libc::c_int, name: libc::c_int) -> io::Result<()> {
+        let on: libc::c_int = 1;
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                (&on as *const libc::c_int).cast::<libc::c_void>(),
+                size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// Has the destination address of the packets `socket` receives be reported.
+    pub(super) fn enable(socket: &UdpSocket) -> io::Result<()> {
+        if socket.local_addr()?.is_ipv4() {
+            set_option(socket, libc::IPPROTO_IP, libc::IP_PKTINFO)
+        } else {
+            set_option(socket, libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO)
+        }
+    }
+
+    /// Converts the socket address filled in by `recvmsg`.
+    fn from_sockaddr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+        match libc::c_int::from(storage.ss_family) {
+            libc::AF_INET => {
+                let sin = unsafe {
+                    *(storage as *const libc::sockaddr_storage).cast::<libc::sockaddr_in>()
+                };
+                Some(SocketAddr::new(
+                    Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes()).into(),
+                    u16::from_be(sin.sin_port),
+                ))
+            }
+            libc::AF_INET6 => {
+                let sin6 = unsafe {
+                    *(storage as *const libc::sockaddr_storage).cast::<libc::sockaddr_in6>()
+                };
+                Some(SocketAddr::V6(std::net::SocketAddrV6::new(
+                    Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                    u16::from_be(sin6.sin6_port),
+                    sin6.sin6_flowinfo,
+                    sin6.sin6_scope_id,
+                )))
+            }
+            _ => None,
+        }
+    }
+
+    /// Receives a packet, and returns its size, its source address and its destination address.
+    /// Fails with `WouldBlock` when there is none.
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss,
+        clippy::cast_ptr_alignment
+    )]
+    pub(super) fn recv_from(
+        socket: &UdpSocket,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, Option<IpAddr>)> {
+        let mut addr: libc::sockaddr_storage = unsafe { zeroed() };
+        let mut iovec = libc::iovec {
+            iov_base: buf.as_mut_ptr().cast::<libc::c_void>(),
+            iov_len: buf.len(),
+        };
+        let mut control = Control([0; 64]);
+        let mut msg: libc::msghdr = unsafe { zeroed() };
+        msg.msg_name = (&mut addr as *mut libc::sockaddr_storage).cast::<libc::c_void>();
+        msg.msg_namelen = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_iov = &mut iovec;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.0.as_mut_ptr().cast::<libc::c_void>();
+        msg.msg_controllen = control.0.len() as _;
+
+        let n = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let src_addr = from_sockaddr(&addr)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unknown address family"))?;
+
+        let mut dst_ip = None;
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                    (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                        let info = std::ptr::read_unaligned(
+                            libc::CMSG_DATA(cmsg).cast::<libc::in_pktinfo>(),
+                        );
+                        dst_ip = Some(Ipv4Addr::from(info.ipi_addr.s_addr.to_ne_bytes()).into());
+                    }
+                    (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                        let info = std::ptr::read_unaligned(
+                            libc::CMSG_DATA(cmsg).cast::<libc::in6_pktinfo>(),
+                        );
+                        dst_ip = Some(Ipv6Addr::from(info.ipi6_addr.s6_addr).into());
+                    }
+                    _ => {}
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+
+        Ok((n as usize, src_addr, dst_ip))
+    }
+
+    /// Fills `control` with the `IP_PKTINFO` or `IPV6_PKTINFO` control message having the
+    /// packets of `msg` sent from `source`, and points `msg` at it.
+    #[allow(clippy::cast_possible_truncation, clippy::cast_ptr_alignment)]
+    fn set_source(msg: &mut libc::msghdr, control: &mut Control, source: IpAddr) {
+        msg.msg_control = control.0.as_mut_ptr().cast::<libc::c_void>();
+
+        unsafe {
+            match source {
+                IpAddr::V4(ip) => {
+                    let len = size_of::<libc::in_pktinfo>() as libc::c_uint;
+                    msg.msg_controllen = libc::CMSG_SPACE(len) as _;
+                    let cmsg = libc::CMSG_FIRSTHDR(&msg);
+                    (*cmsg).cmsg_level = libc::IPPROTO_IP;
+                    (*cmsg).cmsg_type = libc::IP_PKTINFO;
+                    (*cmsg).cmsg_len = libc::CMSG_LEN(len) as _;
+                    std::ptr::write_unaligned(
+                        libc::CMSG_DATA(cmsg).cast::<libc::in_pktinfo>(),
+                        libc::in_pktinfo {
+                            ipi_ifindex: 0,
+                            ipi_spec_dst: libc::in_addr {
+                                s_addr: u32::from_ne_bytes(ip.octets()),
+                            },
+                            ipi_addr: libc::in_addr { s_addr: 0 },
+                        },
+                    );
+                }
+                IpAddr::V6(ip) => {
+                    let len = size_of::<libc::in6_pktinfo>() as libc::c_uint;
+                    msg.msg_controllen = libc::CMSG_SPACE(len) as _;
+                    let cmsg = libc::CMSG_FIRSTHDR(&msg);
+                    (*cmsg).cmsg_level = libc::IPPROTO_IPV6;
+                    (*cmsg).cmsg_type = libc::IPV6_PKTINFO;
+                    (*cmsg).cmsg_len = libc::CMSG_LEN(len) as _;
+                    std::ptr::write_unaligned(
+                        libc::CMSG_DATA(cmsg).cast::<libc::in6_pktinfo>(),
+                        libc::in6_pktinfo {
+                            ipi6_addr: libc::in6_addr {
+                                s6_addr: ip.octets(),
+                            },
+                            ipi6_ifindex: 0,
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    /// Sends `buf` to `target` from `source`, and returns the number of bytes sent. Fails with
+    /// `WouldBlock` when the socket can't take it.
+    #[allow(clippy::cast_sign_loss)]
+    pub(super) fn send_to(
+        socket: &UdpSocket,
+        buf: &[u8],
+        source: IpAddr,
+        target: SocketAddr,
+    ) -> io::Result<usize> {
+        let (mut addr, addr_len) = to_sockaddr(target);
+        let mut iovec = libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut control = Control([0; 64]);
+        let mut msg: libc::msghdr = unsafe { zeroed() };
+        msg.msg_name = (&mut addr as *mut libc::sockaddr_storage).cast::<libc::c_void>();
+        msg.msg_namelen = addr_len;
+        msg.msg_iov = &mut iovec;
+        msg.msg_iovlen = 1;
+        set_source(&mut msg, &mut control, source);
+
+        let n = unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, 0) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(n as usize)
+    }
+
+    /// Sends as many buffers of `bufs` to `target` from `source` as the kernel accepts in one
+    /// `sendmmsg` call, and returns how many were sent. Fails with `WouldBlock` when none could
+    /// be sent.
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    pub(super) fn send_mmsg(
+        socket: &UdpSocket,
+        bufs: &[&[u8]],
+        source: IpAddr,
+        target: SocketAddr,
+    ) -> io::Result<usize> {
+        let (mut addr, addr_len) = to_sockaddr(target);
+        // The packets are all sent from the same address, they share the control message
+        let mut control = Control([0; 64]);
+        let mut source_msg: libc::msghdr = unsafe { zeroed() };
+        set_source(&mut source_msg, &mut control, source);
+
+        let mut iovecs: Vec<libc::iovec> = bufs
+            .iter()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            })
+            .collect();
+        let mut msgs: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .map(|iovec| {
+                let mut msg: libc::mmsghdr = unsafe { zeroed() };
+                msg.msg_hdr.msg_name =
+                    (&mut addr as *mut libc::sockaddr_storage).cast::<libc::c_void>();
+                msg.msg_hdr.msg_namelen = addr_len;
+                msg.msg_hdr.msg_iov = iovec;
+                msg.msg_hdr.msg_iovlen = 1;
+                msg.msg_hdr.msg_control = source_msg.msg_control;
+                msg.msg_hdr.msg_controllen = source_msg.msg_controllen;
+                msg
+            })
+            .collect();
+
+        let n = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                msgs.len() as libc::c_uint,
+                0,
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(n as usize)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+mod pktinfo {
+    use super::*;
+
+    fn unsupported() -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Other,
+            "packet info is not supported on this platform",
+        )
+    }
+
+    pub(super) fn enable(_socket: &UdpSocket) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    pub(super) fn recv_from(
+        _socket: &UdpSocket,
+        _buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, Option<IpAddr>)> {
+        Err(unsupported())
+    }
+
+    pub(super) fn send_to(
+        _socket: &UdpSocket,
+        _buf: &[u8],
+        _source: IpAddr,
+        _target: SocketAddr,
+    ) -> io::Result<usize> {
+        Err(unsupported())
+    }
+
+    pub(super) fn send_mmsg(
+        _socket: &UdpSocket,
+        _bufs: &[&[u8]],
+        _source: IpAddr,
+        _target: SocketAddr,
+    ) -> io::Result<usize> {
+        Err(unsupported())
+    }
+}
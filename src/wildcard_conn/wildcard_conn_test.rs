@@ -0,0 +1,118 @@
+use super::*;
+use crate::errors::*;
+
+use tokio::time::Duration;
+
+#[tokio::test]
+async fn test_wildcard_udp_conn_demultiplexes_by_destination() -> Result<(), IceError> {
+    let wildcard = WildcardUdpConn::new(UdpSocket::bind("0.0.0.0:0").await?)?;
+    let port = wildcard.socket.local_addr()?.port();
+
+    // The whole 127.0.0.0/8 block is bound to the loopback interface
+    let first: IpAddr = "127.0.0.1".parse()?;
+    let second: IpAddr = "127.0.0.2".parse()?;
+    let first_conn = wildcard.conn(first);
+    let second_conn = wildcard.conn(second);
+    assert_eq!(
+        SocketAddr::new(second, port),
+        second_conn.local_addr().await?
+    );
+
+    let remote = UdpSocket::bind("127.0.0.1:0").await?;
+    let remote_addr = remote.local_addr()?;
+    remote
+        .send_to(b"second", SocketAddr::new(second, port))
+        .await?;
+    remote
+        .send_to(b"first", SocketAddr::new(first, port))
+        .await?;
+
+    let mut buf = vec![0_u8; 1500];
+    for (conn, expected) in [(&first_conn, "first"), (&second_conn, "second")].iter() {
+        let (n, src_addr) = tokio::time::timeout(Duration::from_secs(5), conn.recv_from(&mut buf))
+            .await
+            .expect("packet should be received")?;
+        assert_eq!(expected.as_bytes(), &buf[..n]);
+        assert_eq!(remote_addr, src_addr);
+    }
+
+    // The answers come from the address the packets were sent to
+    second_conn.send_to(b"from second", remote_addr).await?;
+    let (n, src_addr) = tokio::time::timeout(Duration::from_secs(5), remote.recv_from(&mut buf))
+        .await
+        .expect("packet should be received")?;
+    assert_eq!(b"from second", &buf[..n]);
+    assert_eq!(SocketAddr::new(second, port), src_addr);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_wildcard_udp_conn_drops_packets_to_unknown_address() -> Result<(), IceError> {
+    let wildcard = WildcardUdpConn::new(UdpSocket::bind("0.0.0.0:0").await?)?;
+    let port = wildcard.socket.local_addr()?.port();
+    let conn = wildcard.conn("127.0.0.1".parse()?);
+
+    let remote = UdpSocket::bind("127.0.0.1:0").await?;
+    remote
+        .send_to(b"unknown", SocketAddr::new("127.0.0.3".parse()?, port))
+        .await?;
+
+    let mut buf = vec![0_u8; 1500];
+    assert!(
+        tokio::time::timeout(Duration::from_millis(200), conn.recv_from(&mut buf))
+            .await
+            .is_err(),
+        "packets to an address without a connection should be dropped"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_wildcard_udp_conn_sends_batches_from_its_address() -> Result<(), IceError> {
+    let wildcard = WildcardUdpConn::new(UdpSocket::bind("0.0.0.0:0").await?)?;
+    let port = wildcard.socket.local_addr()?.port();
+    let source: IpAddr = "127.0.0.2".parse()?;
+    let conn = wildcard.conn(source);
+    conn.set_tos(46 << 2)?;
+
+    let remote = UdpSocket::bind("127.0.0.1:0").await?;
+    let bufs: [&[u8]; 3] = [b"one", b"two", b"three"];
+    assert_eq!(3, conn.send_batch_to(&bufs, remote.local_addr()?).await?);
+
+    let mut buf = vec![0_u8; 1500];
+    for expected in &bufs {
+        let (n, src_addr) =
+            tokio::time::timeout(Duration::from_secs(5), remote.recv_from(&mut buf))
+                .await
+                .expect("packet should be received")?;
+        assert_eq!(*expected, &buf[..n]);
+        assert_eq!(SocketAddr::new(source, port), src_addr);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_wildcard_udp_conn_closes_conns_once_reading_stops() -> Result<(), IceError> {
+    let socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    let conns = Arc::new(std::sync::Mutex::new(HashMap::<IpAddr, PacketsTx>::new()));
+    let (packets_tx, mut packets_rx) = mpsc::channel(1);
+    conns
+        .lock()
+        .unwrap()
+        .insert("127.0.0.1".parse()?, packets_tx);
+
+    let (closed_ch_tx, closed_ch_rx) = broadcast::channel(1);
+    let _ = closed_ch_tx.send(());
+    WildcardUdpConn::read_loop(socket, Arc::clone(&conns), closed_ch_rx).await;
+
+    assert!(conns.lock().unwrap().is_empty());
+    assert!(
+        packets_rx.recv().await.is_none(),
+        "the connections should stop waiting for packets"
+    );
+
+    Ok(())
+}